@@ -0,0 +1,72 @@
+use bytes::BytesMut;
+use futures_util::{
+    SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
+};
+use miniprobe_proto::{DynamicMetrics, msg::IngressControl};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::Message};
+
+use crate::Error;
+
+type Ws = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub(crate) fn split(ws: Ws, batch: bool) -> (IngressSender, IngressControls) {
+    let (sink, stream) = ws.split();
+    (IngressSender { sink, batch }, IngressControls { stream })
+}
+
+/// Sending half of the ingress websocket of a session.
+#[derive(Debug)]
+pub struct IngressSender {
+    sink: SplitSink<Ws, Message>,
+    batch: bool,
+}
+
+impl IngressSender {
+    /// Send one sample, as a batch of one if connected with `batch`.
+    pub async fn send(&mut self, sample: &DynamicMetrics<'_>) -> Result<(), Error> {
+        if self.batch {
+            return self.send_batch(std::slice::from_ref(sample)).await;
+        }
+        self.send_encoded(sample).await
+    }
+
+    /// Send samples in one message, only accepted if connected with `batch`.
+    pub async fn send_batch(&mut self, samples: &[DynamicMetrics<'_>]) -> Result<(), Error> {
+        self.send_encoded(&samples).await
+    }
+
+    /// Close the websocket, the session stays open until it expires.
+    pub async fn close(mut self) -> Result<(), Error> {
+        Ok(self.sink.close().await?)
+    }
+
+    async fn send_encoded<T: serde::Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let buf = postcard::to_extend(value, BytesMut::new())?.freeze();
+        Ok(self.sink.send(Message::Binary(buf)).await?)
+    }
+}
+
+/// Receiving half of the ingress websocket, must be polled for pings to be
+/// answered.
+#[derive(Debug)]
+pub struct IngressControls {
+    stream: SplitStream<Ws>,
+}
+
+impl IngressControls {
+    /// The next control message, `None` once the server closed the websocket.
+    pub async fn next(&mut self) -> Option<Result<IngressControl, Error>> {
+        loop {
+            match self.stream.next().await? {
+                Ok(Message::Binary(bytes)) => {
+                    return Some(postcard::from_bytes(&bytes).map_err(Error::from));
+                }
+                Ok(Message::Close(_)) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
@@ -0,0 +1,534 @@
+//! Typed async client for the REST and websocket API of a miniprobe server.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), miniprobe_api::Error> {
+//! let client = miniprobe_api::Client::new("https://probe.example.com")?
+//!     .with_admin_token("secret");
+//! for c in client.list_clients().await? {
+//!     println!("{}: {:?}", c.name, c.status.state);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod ingress;
+mod replicate;
+mod types;
+
+use bytes::BytesMut;
+use miniprobe_proto::{
+    METRICS_SCHEMA_HASH,
+    limits::POSTCARD_CONTENT_TYPE,
+    msg::{CreateSessionReq, CreateSessionResp, SCHEMA_HEADER, ServerCapabilities, SessionToken},
+};
+use reqwest::{RequestBuilder, StatusCode, Url, header};
+use serde::de::DeserializeOwned;
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+pub use ingress::{IngressControls, IngressSender};
+pub use replicate::*;
+pub use types::*;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid server URL: {0}")]
+    InvalidUrl(String),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Server error: [{}] {message}", status.as_u16())]
+    Status { status: StatusCode, message: String },
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] Box<tungstenite::Error>),
+    #[error("Encoding error: {0}")]
+    Encoding(#[from] postcard::Error),
+    #[error("Decoding error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(e))
+    }
+}
+
+/// Connection to one server, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base: Url,
+    admin_token: Option<String>,
+}
+
+impl Client {
+    /// Client of the server at `base`, e.g. `http://127.0.0.1:8000`.
+    pub fn new(base: &str) -> Result<Self, Error> {
+        let base = Url::parse(base).map_err(|e| Error::InvalidUrl(e.to_string()))?;
+        if !matches!(base.scheme(), "http" | "https") || base.cannot_be_a_base() {
+            return Err(Error::InvalidUrl(format!("{base} is not an HTTP URL")));
+        }
+        Ok(Client {
+            http: reqwest::Client::new(),
+            base,
+            admin_token: None,
+        })
+    }
+
+    /// Send requests with this client, e.g. to set timeouts.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Token of the admin API, required for everything but the session and
+    /// ingress endpoints.
+    pub fn with_admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Features of the server, `None` if it predates the discovery endpoint.
+    pub async fn capabilities(&self) -> Result<Option<ServerCapabilities>, Error> {
+        let resp = self
+            .http
+            .get(self.url("http", "/.well-known/miniprobe"))
+            .send()
+            .await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(check_status(resp).await?.json().await?))
+    }
+
+    /// Open a session for the client owning `req.token`.
+    pub async fn create_session(&self, req: &CreateSessionReq) -> Result<CreateSessionResp, Error> {
+        let body = postcard::to_extend(req, BytesMut::new())?.freeze();
+        let resp = self
+            .http
+            .post(self.url("http", "/api/v1/sessions"))
+            .header(header::CONTENT_TYPE, POSTCARD_CONTENT_TYPE)
+            .header(SCHEMA_HEADER, schema_hash())
+            .body(body)
+            .send()
+            .await?;
+        let bytes = check_status(resp).await?.bytes().await?;
+        Ok(postcard::from_bytes(&bytes)?)
+    }
+
+    /// Connect to the ingress websocket of a session, with `batch` every
+    /// message carries a batch of samples.
+    pub async fn ingress(
+        &self,
+        session_token: &SessionToken,
+        batch: bool,
+    ) -> Result<(IngressSender, IngressControls), Error> {
+        let mut path = "/ws/v1/metrics/ingress".to_owned();
+        if batch {
+            path += "?batch=true";
+        }
+        let mut req = self.url("ws", &path).as_str().into_client_request()?;
+        req.headers_mut().insert(
+            header::AUTHORIZATION,
+            format!("Bearer {session_token}")
+                .parse()
+                .expect("session tokens are valid header values"),
+        );
+        req.headers_mut().insert(
+            SCHEMA_HEADER,
+            schema_hash().parse().expect("hex is a valid header value"),
+        );
+        let (ws, _) = tokio_tungstenite::connect_async(req).await?;
+        Ok(ingress::split(ws, batch))
+    }
+
+    /// Stream stored samples after `after`, or only newly ingested ones if
+    /// `None`, of one or every client.
+    pub async fn replicate(
+        &self,
+        after: Option<i64>,
+        client: Option<i64>,
+    ) -> Result<Replication, Error> {
+        let mut url = self.url("ws", "/ws/v1/metrics/replicate");
+        if let Some(after) = after {
+            url.query_pairs_mut()
+                .append_pair("after", &after.to_string());
+        }
+        if let Some(client) = client {
+            url.query_pairs_mut()
+                .append_pair("client", &client.to_string());
+        }
+        let mut req = url.as_str().into_client_request()?;
+        if let Some(token) = &self.admin_token {
+            req.headers_mut().insert(
+                header::AUTHORIZATION,
+                format!("Bearer {token}")
+                    .parse()
+                    .expect("admin tokens are valid header values"),
+            );
+        }
+        let (ws, _) = tokio_tungstenite::connect_async(req).await?;
+        Ok(Replication::new(ws))
+    }
+
+    /// Every client with its status.
+    pub async fn list_clients(&self) -> Result<Vec<ClientOverview>, Error> {
+        self.get_json(self.admin(self.http.get(self.url("http", "/api/v1/clients"))))
+            .await
+    }
+
+    /// The latest `limit` snapshots of the listening sockets of a client,
+    /// newest first.
+    pub async fn list_listeners(
+        &self,
+        client_id: i64,
+        limit: Option<u32>,
+    ) -> Result<Vec<ListenerSnapshot>, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", &format!("/api/v1/clients/{client_id}/listeners")));
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// The latest `limit` sessions of a client with how they were closed,
+    /// newest first.
+    pub async fn list_sessions(
+        &self,
+        client_id: i64,
+        limit: Option<u32>,
+    ) -> Result<Vec<SessionOverview>, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", &format!("/api/v1/clients/{client_id}/sessions")));
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// The latest `limit` changes of the addresses of the host of a client,
+    /// newest first.
+    pub async fn list_addresses(
+        &self,
+        client_id: i64,
+        limit: Option<u32>,
+    ) -> Result<Vec<AddressChange>, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", &format!("/api/v1/clients/{client_id}/addresses")));
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// The latest `limit` reboots of the host of a client, newest first.
+    pub async fn list_reboots(
+        &self,
+        client_id: i64,
+        limit: Option<u32>,
+    ) -> Result<Vec<Reboot>, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", &format!("/api/v1/clients/{client_id}/reboots")));
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// Averages of `metric` of a client in `points` buckets spanning the last
+    /// `range` seconds, 60 over an hour by default.
+    pub async fn sparkline(
+        &self,
+        client_id: i64,
+        metric: SparklineMetric,
+        points: Option<u32>,
+        range: Option<i64>,
+    ) -> Result<Sparkline, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", &format!("/api/v1/clients/{client_id}/sparkline")))
+            .query(&[("metric", metric)]);
+        if let Some(points) = points {
+            req = req.query(&[("points", points)]);
+        }
+        if let Some(range) = range {
+            req = req.query(&[("range", range)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// Send a Wake-on-LAN packet from the server to the MAC address of a
+    /// client.
+    pub async fn wake_client(&self, client_id: i64) -> Result<(), Error> {
+        let req = self
+            .http
+            .post(self.url("http", &format!("/api/v1/clients/{client_id}/wake")));
+        check_status(self.admin(req).send().await?).await?;
+        Ok(())
+    }
+
+    /// Ask a connected client to run the action `name` it offers, waits for
+    /// the result.
+    pub async fn run_action(&self, client_id: i64, name: &str) -> Result<ActionRun, Error> {
+        let req = self.http.post(self.url(
+            "http",
+            &format!("/api/v1/clients/{client_id}/actions/{name}"),
+        ));
+        self.get_json(self.admin(req)).await
+    }
+
+    /// The actions asked of a client, newest first.
+    pub async fn list_actions(
+        &self,
+        client_id: i64,
+        limit: Option<u32>,
+    ) -> Result<Vec<ActionRun>, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", &format!("/api/v1/clients/{client_id}/actions")));
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// The latest `limit` security events, newest first, only those of `kind`
+    /// or `client` if given.
+    pub async fn list_security_events(
+        &self,
+        kind: Option<SecurityEventKind>,
+        client: Option<i64>,
+        limit: Option<u32>,
+    ) -> Result<Vec<SecurityEvent>, Error> {
+        let mut req = self.http.get(self.url("http", "/api/v1/security/events"));
+        if let Some(kind) = kind {
+            req = req.query(&[("kind", kind)]);
+        }
+        if let Some(client) = client {
+            req = req.query(&[("client", client)]);
+        }
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// Evaluate an expression like `avg_over_time(cpu[5m])` for one or every
+    /// client, at `time` or now.
+    pub async fn query(
+        &self,
+        expr: &str,
+        client: Option<i64>,
+        time: Option<i64>,
+    ) -> Result<QueryResponse, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", "/api/v1/query"))
+            .query(&[("expr", expr)]);
+        if let Some(client) = client {
+            req = req.query(&[("client", client)]);
+        }
+        if let Some(time) = time {
+            req = req.query(&[("time", time)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// Evaluate an expression in buckets of `step` seconds from `start` to
+    /// `end` or now, for one or every client. With `compare_offset`, e.g.
+    /// `7d`, every bucket is evaluated that much earlier as well.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn query_range(
+        &self,
+        expr: &str,
+        client: Option<i64>,
+        start: i64,
+        end: Option<i64>,
+        step: i64,
+        fill: Fill,
+        compare_offset: Option<&str>,
+    ) -> Result<QueryRangeResponse, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", "/api/v1/query_range"))
+            .query(&[("expr", expr)])
+            .query(&[("start", start), ("step", step)])
+            .query(&[("fill", fill)]);
+        if let Some(client) = client {
+            req = req.query(&[("client", client)]);
+        }
+        if let Some(end) = end {
+            req = req.query(&[("end", end)]);
+        }
+        if let Some(compare_offset) = compare_offset {
+            req = req.query(&[("compare_offset", compare_offset)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// Evaluate an expression for every client `selector` matches, e.g.
+    /// `env=prod`, and aggregate the values, at `time` or now.
+    pub async fn fleet_query(
+        &self,
+        expr: &str,
+        selector: &str,
+        agg: Aggregation,
+        time: Option<i64>,
+    ) -> Result<FleetQueryResponse, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", "/api/v1/fleet/query"))
+            .query(&[("expr", expr), ("selector", selector)])
+            .query(&[("agg", agg)]);
+        if let Some(time) = time {
+            req = req.query(&[("time", time)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// Evaluate an expression in buckets of `step` seconds for every client
+    /// `selector` matches and aggregate each bucket.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn fleet_query_range(
+        &self,
+        expr: &str,
+        selector: &str,
+        agg: Aggregation,
+        start: i64,
+        end: Option<i64>,
+        step: i64,
+        fill: Fill,
+    ) -> Result<FleetQueryRangeResponse, Error> {
+        let mut req = self
+            .http
+            .get(self.url("http", "/api/v1/fleet/query_range"))
+            .query(&[("expr", expr), ("selector", selector)])
+            .query(&[("agg", agg)])
+            .query(&[("start", start), ("step", step)])
+            .query(&[("fill", fill)]);
+        if let Some(end) = end {
+            req = req.query(&[("end", end)]);
+        }
+        self.get_json(self.admin(req)).await
+    }
+
+    /// Alert rules stored on the server, without the rules of its config.
+    pub async fn list_alert_rules(&self) -> Result<Vec<StoredAlertRule>, Error> {
+        self.get_json(self.admin(self.http.get(self.url("http", "/api/v1/alerts/rules"))))
+            .await
+    }
+
+    /// Store a new alert rule, evaluated from the next evaluation on.
+    pub async fn create_alert_rule(&self, rule: &AlertRuleSpec) -> Result<StoredAlertRule, Error> {
+        let req = self
+            .http
+            .post(self.url("http", "/api/v1/alerts/rules"))
+            .json(rule);
+        self.get_json(self.admin(req)).await
+    }
+
+    /// Replace the stored alert rule `id`.
+    pub async fn update_alert_rule(
+        &self,
+        id: i64,
+        rule: &AlertRuleSpec,
+    ) -> Result<StoredAlertRule, Error> {
+        let req = self
+            .http
+            .put(self.url("http", &format!("/api/v1/alerts/rules/{id}")))
+            .json(rule);
+        self.get_json(self.admin(req)).await
+    }
+
+    pub async fn delete_alert_rule(&self, id: i64) -> Result<(), Error> {
+        let req = self
+            .http
+            .delete(self.url("http", &format!("/api/v1/alerts/rules/{id}")));
+        check_status(self.admin(req).send().await?).await?;
+        Ok(())
+    }
+
+    /// Version and database status of the server.
+    pub async fn server_info(&self) -> Result<ServerInfo, Error> {
+        self.get_json(self.admin(self.http.get(self.url("http", "/api/v1/server/info"))))
+            .await
+    }
+
+    /// `path` on the server, with the websocket counterpart of the base
+    /// scheme for `ws`.
+    fn url(&self, scheme: &str, path: &str) -> Url {
+        let mut url = self.base.clone();
+        if scheme == "ws" {
+            let ws = match self.base.scheme() {
+                "https" => "wss",
+                _ => "ws",
+            };
+            url.set_scheme(ws).expect("http URLs can be ws URLs");
+        }
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        // keep a path prefix of the base, e.g. behind a reverse proxy
+        let prefix = url.path().trim_end_matches('/').to_owned();
+        url.set_path(&format!("{prefix}{path}"));
+        url.set_query((!query.is_empty()).then_some(query));
+        url
+    }
+
+    fn admin(&self, req: RequestBuilder) -> RequestBuilder {
+        match &self.admin_token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<T, Error> {
+        Ok(check_status(req.send().await?).await?.json().await?)
+    }
+}
+
+/// Our [`METRICS_SCHEMA_HASH`] in the format of [`SCHEMA_HEADER`].
+fn schema_hash() -> String {
+    format!("{METRICS_SCHEMA_HASH:016x}")
+}
+
+/// Turn an error response into [`Error::Status`] with the message the server
+/// sent along.
+async fn check_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let message = resp.text().await.unwrap_or_default();
+    Err(Error::Status { status, message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        let client = Client::new("https://example.com/probe/").unwrap();
+        assert_eq!(
+            client.url("http", "/api/v1/clients").as_str(),
+            "https://example.com/probe/api/v1/clients"
+        );
+        assert_eq!(
+            client
+                .url("ws", "/ws/v1/metrics/ingress?batch=true")
+                .as_str(),
+            "wss://example.com/probe/ws/v1/metrics/ingress?batch=true"
+        );
+
+        let client = Client::new("http://127.0.0.1:8000").unwrap();
+        assert_eq!(
+            client.url("ws", "/ws/v1/metrics/ingress").as_str(),
+            "ws://127.0.0.1:8000/ws/v1/metrics/ingress"
+        );
+
+        assert!(Client::new("127.0.0.1:8000").is_err());
+        assert!(Client::new("ftp://example.com").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use futures_util::StreamExt;
+use miniprobe_proto::ListeningSocket;
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::Message};
+
+use crate::Error;
+
+type Ws = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Stored samples streamed by the replication websocket, in the order the
+/// server stored them.
+#[derive(Debug)]
+pub struct Replication {
+    ws: Ws,
+}
+
+impl Replication {
+    pub(crate) fn new(ws: Ws) -> Self {
+        Replication { ws }
+    }
+
+    /// The next sample, waits for new ones once caught up. `None` once the
+    /// server closed the websocket, e.g. when shutting down, reconnect with
+    /// the cursor of the last sample processed to continue after it.
+    pub async fn next(&mut self) -> Option<Result<ReplicatedSample, Error>> {
+        loop {
+            match self.ws.next().await? {
+                Ok(Message::Text(text)) => {
+                    return Some(serde_json::from_str(&text).map_err(Error::from));
+                }
+                Ok(Message::Close(_)) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+
+    pub async fn close(mut self) -> Result<(), Error> {
+        Ok(self.ws.close(None).await?)
+    }
+}
+
+/// A stored sample, sections that were not reported or stored are `None`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedSample {
+    /// Position of the sample in the stream
+    pub cursor: i64,
+    /// `None` if the session is gone with its client
+    pub client_id: Option<i64>,
+    pub session_id: i64,
+    pub seq: Option<i64>,
+    /// Client time in unix milliseconds
+    pub sample_time: i64,
+    /// Server time in unix milliseconds the sample arrived at, seconds
+    /// from servers predating that
+    pub received_at: Option<i64>,
+    pub cpu: Option<ReplicatedCpu>,
+    pub memory: Option<ReplicatedMemory>,
+    /// Every interface the client reported, missing from servers predating
+    /// per-interface network metrics
+    #[serde(default)]
+    pub networks: Vec<ReplicatedNetwork>,
+    /// Missing from servers predating disk metrics
+    #[serde(default)]
+    pub disks: Vec<ReplicatedDisk>,
+    pub sensors: Option<ReplicatedSensors>,
+    pub probe: Option<ReplicatedProbe>,
+    /// Missing from servers predating process counts
+    pub processes: Option<ReplicatedProcesses>,
+    /// Missing from servers predating file descriptor counts
+    pub fds: Option<ReplicatedFds>,
+    /// Missing from servers predating clock synchronization
+    pub clock: Option<ReplicatedClock>,
+    pub battery: Option<ReplicatedBattery>,
+    pub services: Vec<ReplicatedService>,
+    /// Only sent by clients when they changed
+    pub listeners: Option<Vec<ListeningSocket>>,
+}
+
+/// Usage in percent, of every core or folded by the client or server.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicatedCpu {
+    PerCore(Vec<f64>),
+    Aggregate { usage: f64, max_core: f64 },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedMemory {
+    pub total: i64,
+    pub used: i64,
+    pub swap_total: i64,
+    pub swap_used: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedNetwork {
+    pub ifname: String,
+    pub rx_bytes: Option<i64>,
+    pub tx_bytes: Option<i64>,
+    /// The counters are bytes since the previous sample of the session
+    /// rather than cumulative, never set by servers predating deltas
+    #[serde(default)]
+    pub delta: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedDisk {
+    pub mount_point: String,
+    /// Bytes
+    pub total: i64,
+    pub available: i64,
+    /// Bytes since boot
+    pub read_bytes: Option<i64>,
+    pub written_bytes: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedSensors {
+    /// Degrees Celsius
+    pub cpu_temperature: Option<f64>,
+    /// MHz
+    pub cpu_frequency: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedProbe {
+    /// Microseconds
+    pub collection_time: i64,
+    /// Percent of one core
+    pub cpu_usage: Option<f64>,
+    /// Bytes
+    pub rss: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedProcesses {
+    pub processes: i64,
+    /// Only counted on Linux
+    pub threads: Option<i64>,
+    pub zombies: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedFds {
+    pub open: i64,
+    pub max: i64,
+    pub tcp_established: Option<i64>,
+    pub tcp_time_wait: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedClock {
+    pub synchronized: bool,
+    /// Seconds ahead of the time source, negative if behind
+    pub offset: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedBattery {
+    /// Percent of the full charge
+    pub capacity: f64,
+    pub state: String,
+    /// Watts
+    pub power: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicatedService {
+    pub unit: String,
+    pub state: String,
+    pub restarts: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replicated_sample() {
+        let sample: ReplicatedSample = serde_json::from_str(
+            r#"{
+                "cursor": 42, "client_id": 1, "session_id": 3, "seq": 7,
+                "sample_time": 1700000000000, "received_at": 1700000001000,
+                "cpu": {"aggregate": {"usage": 12.5, "max_core": 80.0}},
+                "memory": null,
+                "networks": [{"ifname": "eth0", "rx_bytes": 1, "tx_bytes": null}],
+                "sensors": null, "probe": {"collection_time": 1200, "cpu_usage": null, "rss": null},
+                "battery": null,
+                "services": [{"unit": "nginx.service", "state": "active", "restarts": 0}],
+                "listeners": null
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(sample.cursor, 42);
+        assert_eq!(
+            sample.cpu,
+            Some(ReplicatedCpu::Aggregate {
+                usage: 12.5,
+                max_core: 80.0
+            })
+        );
+        assert_eq!(sample.networks[0].ifname, "eth0");
+        assert_eq!(sample.services[0].unit, "nginx.service");
+        // not sent by servers predating these sections
+        assert!(sample.processes.is_none() && sample.fds.is_none() && sample.clock.is_none());
+        assert!(sample.disks.is_empty());
+    }
+}
@@ -0,0 +1,492 @@
+//! Responses of the JSON endpoints, mirroring the server's.
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use miniprobe_proto::{ListeningSocket, msg::Compression};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientState {
+    /// Sent a sample within the last few scrape intervals
+    Up,
+    /// Sent samples before, but not recently
+    Stale,
+    /// Never sent a sample
+    Never,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientStatus {
+    pub state: ClientState,
+    /// Time of the latest sample as unix timestamp in seconds
+    pub last_sample: Option<i64>,
+    /// Seconds since the latest sample
+    pub staleness: Option<i64>,
+    /// Whether the client has a session active in the last 5 minutes
+    pub session_active: bool,
+    /// Average CPU usage over the last 24 hours in percent
+    pub cpu_avg_24h: Option<f64>,
+    /// Average used over total memory over the last 24 hours, `0..=1`
+    pub memory_avg_24h: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientOverview {
+    pub id: i64,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub timezone: Option<String>,
+    pub location: Option<String>,
+    /// Hostname sessions of the client must come from, any if `None`.
+    /// Missing from servers predating hostname binding
+    #[serde(default)]
+    pub expected_hostname: Option<String>,
+    /// Where Wake-on-LAN packets for the client are sent to
+    #[serde(default)]
+    pub mac_address: Option<String>,
+    /// Machine id the latest session reported, clients sharing one run on
+    /// the same host
+    #[serde(default)]
+    pub machine_id: Option<String>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// End of the active silence as unix timestamp in seconds, if silenced
+    pub silenced_until: Option<i64>,
+    /// Samples stored since the start of the day (UTC)
+    pub samples_today: i64,
+    /// Daily sample quota in effect, unlimited if `None`
+    pub samples_per_day: Option<i64>,
+    /// Labels selecting the client in fleet queries, e.g. `env=prod`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub status: ClientStatus,
+}
+
+/// Listening sockets reported by a client at one point in time.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerSnapshot {
+    /// Unix timestamp in seconds
+    pub sample_time: i64,
+    pub listeners: Vec<ListeningSocket>,
+    /// Sockets not in the previous snapshot, `None` for the first one
+    pub added: Option<Vec<ListeningSocket>>,
+    /// Sockets of the previous snapshot that are gone
+    pub removed: Option<Vec<ListeningSocket>>,
+}
+
+/// A session of a client.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionOverview {
+    pub id: i64,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// Unix timestamp in seconds of the latest message of the client
+    pub last_active: i64,
+    pub host_name: Option<String>,
+    pub os_version: Option<String>,
+    /// Id of the installation of the host, kept across hostname changes
+    #[serde(default)]
+    pub machine_id: Option<String>,
+    /// Id of the boot the session started in
+    #[serde(default)]
+    pub boot_id: Option<String>,
+    /// Created from another hostname than the client is bound to
+    #[serde(default)]
+    pub hostname_mismatch: bool,
+    /// How the latest ingress websocket was closed, `None` while it is open
+    /// or if the client never connected
+    pub close: Option<SessionClose>,
+    /// Missing from servers predating compression
+    #[serde(default)]
+    pub codec: SessionCodec,
+}
+
+/// Addresses the host of a client had from `changed_at` on.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddressChange {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    /// The session reporting the addresses
+    pub session_id: i64,
+    /// Unix timestamp in seconds of the sample reporting the addresses
+    pub changed_at: i64,
+}
+
+/// A reboot of the host of a client, noticed by its next session.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Reboot {
+    /// Unix timestamp in seconds the host booted at
+    pub boot_time: i64,
+    /// Unix timestamp in seconds the host booted at before
+    pub previous_boot_time: i64,
+    /// The first session after the reboot
+    pub session_id: i64,
+    /// Unix timestamp in seconds the session noticing the reboot was created
+    pub detected_at: i64,
+}
+
+/// How much the compression of the ingress websocket saved.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SessionCodec {
+    /// Compression of the latest connection, `None` if the client never
+    /// connected
+    pub compression: Option<Compression>,
+    /// `coarse` if the latest connection sent rounded values, `full`
+    /// otherwise. Missing from servers predating rounding
+    #[serde(default)]
+    pub precision: Option<String>,
+    /// Bytes of all messages of the session as received
+    pub wire_bytes: i64,
+    /// Bytes of those messages after decompression
+    pub decoded_bytes: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionClose {
+    /// Unix timestamp in seconds
+    pub closed_at: i64,
+    pub closed_by: ClosedBy,
+    /// Websocket close code, 1006 if the connection was lost without one
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Which side closed the ingress websocket first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClosedBy {
+    Client,
+    Server,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueryResponse {
+    pub time: i64,
+    pub results: Vec<QueryResult>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueryResult {
+    pub client_id: i64,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub timezone: Option<String>,
+    pub value: Option<f64>,
+    /// Client time of the latest sample at or before `time`
+    pub sample_time: Option<i64>,
+    /// Server time in unix milliseconds the latest sample arrived at
+    pub received_at: Option<i64>,
+    /// Boot times of the reboots within the lookback of the expression,
+    /// values across a reboot may jump. Missing from servers predating
+    /// reboot detection
+    #[serde(default)]
+    pub reboots: Vec<i64>,
+}
+
+/// Metrics a sparkline can show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SparklineMetric {
+    /// Average usage over all cores, `0..=1`
+    Cpu,
+    /// Used memory over total memory, `0..=1`
+    Memory,
+    /// Used swap over total swap, `0..=1`
+    Swap,
+    /// Hottest CPU sensor in degrees Celsius
+    CpuTemperature,
+}
+
+/// Averages of a metric in equal buckets, oldest first.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Sparkline {
+    /// Unix timestamp in seconds the first bucket starts at
+    pub start: i64,
+    /// Bucket width in seconds
+    pub step: i64,
+    /// Average of each bucket, `None` without samples
+    pub values: Vec<Option<f64>>,
+}
+
+/// An action asked of a client, as kept in the audit log of the server.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActionRun {
+    pub id: i64,
+    /// `None` once the client was removed
+    pub client_id: Option<i64>,
+    pub client_name: String,
+    pub session_id: Option<i64>,
+    pub action: String,
+    pub requested_at: i64,
+    /// When the result arrived or was given up on
+    pub finished_at: Option<i64>,
+    /// Exit code of the command, `None` if it did not exit by itself
+    pub exit_code: Option<i64>,
+    pub output: Option<String>,
+    /// Why the action did not run or finish
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventKind {
+    /// A client presented an unknown token
+    ClientAuthFailed,
+    /// A request of the admin API or the server info presented a wrong token
+    AdminAuthFailed,
+    /// An address was locked out after too many failed authentications
+    Lockout,
+    /// A client was refused for its network
+    AccessDenied,
+    /// A session was created, or refused, from another hostname than the
+    /// client is bound to
+    HostnameMismatch,
+    /// A client ran out of its daily sample quota
+    QuotaExceeded,
+    /// A client token was used from an address none of the earlier sessions
+    /// of its client came from
+    NewAddress,
+}
+
+/// A failed authentication, lockout or other security event.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityEvent {
+    pub id: i64,
+    /// Unix timestamp in seconds
+    pub time: i64,
+    pub kind: SecurityEventKind,
+    pub client_id: Option<i64>,
+    /// Name of the client at the time, kept when it is removed
+    pub client_name: Option<String>,
+    /// Peer address of the request, `None` on unix sockets
+    pub address: Option<IpAddr>,
+    pub detail: String,
+}
+
+/// Value of the buckets of a range query without samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fill {
+    #[default]
+    Null,
+    /// The value of the bucket before
+    Previous,
+    Zero,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueryRangeResponse {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+    /// Start of each bucket
+    pub buckets: Vec<i64>,
+    /// Seconds the compared buckets are earlier, if asked for
+    #[serde(default)]
+    pub compare_offset: Option<i64>,
+    pub results: Vec<QueryRangeResult>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueryRangeResult {
+    pub client_id: i64,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub timezone: Option<String>,
+    /// Value of each bucket, evaluated at its end
+    pub values: Vec<Option<f64>>,
+    /// Value of each bucket `compare_offset` earlier, aligned with `values`
+    #[serde(default)]
+    pub compare_values: Option<Vec<Option<f64>>>,
+    /// Boot times of the reboots between the first and the last bucket.
+    /// Missing from servers predating reboot detection
+    #[serde(default)]
+    pub reboots: Vec<i64>,
+}
+
+/// How a fleet query folds the values of the selected clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    /// Clients with a value
+    Count,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FleetQueryResponse {
+    pub time: i64,
+    pub selector: String,
+    pub agg: Aggregation,
+    /// `None` if no selected client has a value, except for `count`
+    pub value: Option<f64>,
+    /// Selected clients with a value
+    pub clients: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FleetQueryRangeResponse {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+    pub selector: String,
+    pub agg: Aggregation,
+    /// Start of each bucket
+    pub buckets: Vec<i64>,
+    /// Aggregated value of each bucket
+    pub values: Vec<Option<f64>>,
+    /// Clients with a value in each bucket
+    pub counts: Vec<usize>,
+    /// Selected clients with samples in range
+    pub clients: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+    /// Latest applied migration, `None` for a fresh database
+    pub schema_version: Option<i64>,
+    /// Latest migration known to the server
+    pub latest_schema_version: Option<i64>,
+    /// Latest applied migration of the samples database
+    pub samples_schema_version: Option<i64>,
+    /// Latest samples migration known to the server
+    pub latest_samples_schema_version: Option<i64>,
+    /// Versions of migrations not applied yet, of both databases
+    pub pending_migrations: Vec<i64>,
+    /// Size of both databases in bytes
+    pub db_size: i64,
+    /// Size cap of the database in bytes, ingest stops once reached
+    pub max_db_size: Option<i64>,
+    /// Latency of the samples of the latest minute samples arrived in
+    #[serde(default)]
+    pub ingest_latency: Option<LatencyRollup>,
+}
+
+/// Latency of the samples ingested in a minute.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LatencyRollup {
+    /// End of the minute as unix timestamp in seconds
+    pub time: i64,
+    pub samples: i64,
+    /// From the sample time of the client to the arrival at the server,
+    /// includes the offset of the client clock
+    pub transit: LatencyPercentiles,
+    /// From the arrival until the server stored the sample
+    pub store: LatencyPercentiles,
+}
+
+/// Latency percentiles in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct LatencyPercentiles {
+    pub p50: i64,
+    pub p95: i64,
+    pub p99: i64,
+    pub max: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    #[default]
+    Warn,
+    Crit,
+}
+
+/// An alert rule in the format of `alerts.rules` of the server config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertRuleSpec {
+    pub name: String,
+    /// Condition of the alert, e.g. `avg_over_time(cpu[5m]) > 0.9`
+    pub expr: String,
+    /// Seconds the condition must hold before the alert fires
+    #[serde(default, rename = "for")]
+    pub for_secs: u64,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Seconds between two notifications of a firing alert, notify once if unset
+    pub repeat_interval: Option<u64>,
+    /// Notify when the condition no longer holds
+    pub auto_resolve: bool,
+}
+
+/// An alert rule stored in the database of the server.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StoredAlertRule {
+    pub id: i64,
+    #[serde(flatten)]
+    pub spec: AlertRuleSpec,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// Unix timestamp in seconds
+    pub updated_at: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_overview() {
+        let client: ClientOverview = serde_json::from_str(
+            r#"{
+                "id": 1, "name": "web", "display_name": null, "timezone": null,
+                "location": null, "created_at": 1700000000, "silenced_until": null,
+                "samples_today": 42, "samples_per_day": null, "state": "stale",
+                "last_sample": 1700000100, "staleness": 600, "session_active": false,
+                "cpu_avg_24h": 12.5, "memory_avg_24h": null
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(client.status.state, ClientState::Stale);
+        assert_eq!(client.status.staleness, Some(600));
+    }
+
+    #[test]
+    fn session_overview() {
+        let session: SessionOverview = serde_json::from_str(
+            r#"{
+                "id": 7, "created_at": 1700000000, "last_active": 1700003600,
+                "host_name": "web", "os_version": null,
+                "close": {
+                    "closed_at": 1700003601, "closed_by": "server", "code": 4001,
+                    "reason": "session taken over by a newer connection"
+                },
+                "codec": {"compression": "zstd", "wire_bytes": 1200, "decoded_bytes": 4800}
+            }"#,
+        )
+        .unwrap();
+        let close = session.close.unwrap();
+        assert_eq!(close.closed_by, ClosedBy::Server);
+        assert_eq!(close.code, 4001);
+        assert_eq!(session.codec.compression, Some(Compression::Zstd));
+        assert_eq!(session.codec.decoded_bytes, 4800);
+    }
+
+    #[test]
+    fn alert_rule() {
+        let rule: StoredAlertRule = serde_json::from_str(
+            r#"{
+                "id": 3, "name": "cpu", "expr": "cpu > 0.9", "for": 60,
+                "severity": "crit", "repeat_interval": null, "auto_resolve": true,
+                "created_at": 1700000000, "updated_at": 1700000100
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(rule.spec.for_secs, 60);
+        assert_eq!(rule.spec.severity, Severity::Crit);
+
+        let spec = serde_json::to_value(&rule.spec).unwrap();
+        assert_eq!(spec["for"], 60);
+        assert_eq!(spec["severity"], "crit");
+    }
+}
@@ -0,0 +1,220 @@
+//! Actions the server may ask for, declared with `--action NAME=COMMAND`.
+//!
+//! Only declared actions are ever run, with the arguments they were declared
+//! with: the server sends a name and nothing else, and commands run without
+//! a shell. Actions run one at a time and are killed after a timeout.
+
+use std::{process::Stdio, str::FromStr, time::Duration};
+
+use miniprobe_proto::msg::{
+    ActionRequest, ActionResult, MAX_ACTION_OUTPUT, is_action_name, truncate_action_output,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    sync::Mutex,
+};
+
+/// `NAME=COMMAND`, e.g. `restart-app=systemctl restart myapp`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Action {
+    name: String,
+    program: String,
+    args: Vec<String>,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, command) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=COMMAND, got '{s}'"))?;
+        let name = name.trim();
+        if !is_action_name(name) {
+            return Err(format!(
+                "invalid action name '{name}', use lowercase letters, digits, '-' and '_'"
+            ));
+        }
+        let mut words = command.split_whitespace().map(str::to_owned);
+        let program = words
+            .next()
+            .ok_or_else(|| format!("action '{name}' has no command"))?;
+        Ok(Action {
+            name: name.to_owned(),
+            program,
+            args: words.collect(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Actions {
+    actions: Vec<Action>,
+    timeout: Duration,
+    /// Held while an action runs
+    running: Mutex<()>,
+}
+
+impl Actions {
+    pub fn new(actions: Vec<Action>, timeout: Duration) -> anyhow::Result<Self> {
+        for (i, action) in actions.iter().enumerate() {
+            if actions[..i].iter().any(|a| a.name == action.name) {
+                anyhow::bail!("action '{}' is declared twice", action.name);
+            }
+        }
+        Ok(Actions {
+            actions,
+            timeout,
+            running: Mutex::new(()),
+        })
+    }
+
+    /// Names of the actions separated by commas, as offered to the server.
+    pub fn names(&self) -> String {
+        self.actions
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Run the action asked for, after the one running finished.
+    pub async fn run(&self, request: ActionRequest) -> ActionResult {
+        let mut result = ActionResult {
+            id: request.id,
+            ..Default::default()
+        };
+        let Some(action) = self.actions.iter().find(|a| a.name == request.name) else {
+            log::warn!("Server asked for unknown action '{}'", request.name);
+            result.error = Some(format!("unknown action '{}'", request.name));
+            return result;
+        };
+
+        let _running = self.running.lock().await;
+        log::info!(
+            "Running action '{}' for the server: {} {}",
+            action.name,
+            action.program,
+            action.args.join(" ")
+        );
+        let child = Command::new(&action.program)
+            .args(&action.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                log::warn!("Action '{}' failed to start: {e}", action.name);
+                result.error = Some(format!("failed to start: {e}"));
+                return result;
+            }
+        };
+        let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+        let output =
+            async { tokio::try_join!(child.wait(), read_limited(stdout), read_limited(stderr)) };
+        // the child is killed when dropped on timeout
+        match tokio::time::timeout(self.timeout, output).await {
+            Ok(Ok((status, stdout, stderr))) => {
+                result.exit_code = status.code();
+                result.output = truncate_output(&stdout, &stderr);
+                log::info!(
+                    "Action '{}' exited with {:?}",
+                    action.name,
+                    result.exit_code
+                );
+            }
+            Ok(Err(e)) => {
+                log::warn!("Action '{}' failed: {e}", action.name);
+                result.error = Some(e.to_string());
+            }
+            Err(_) => {
+                log::warn!(
+                    "Action '{}' killed after {} seconds",
+                    action.name,
+                    self.timeout.as_secs()
+                );
+                result.error = Some(format!("killed after {} seconds", self.timeout.as_secs()));
+            }
+        }
+        result
+    }
+}
+
+/// The first `MAX_ACTION_OUTPUT` bytes of `pipe`, the rest is read and
+/// dropped so the command does not block on a full pipe.
+async fn read_limited(pipe: Option<impl AsyncRead + Unpin>) -> std::io::Result<Vec<u8>> {
+    let Some(mut pipe) = pipe else {
+        return Ok(Vec::new());
+    };
+    let mut output = Vec::new();
+    (&mut pipe)
+        .take(MAX_ACTION_OUTPUT as u64)
+        .read_to_end(&mut output)
+        .await?;
+    tokio::io::copy(&mut pipe, &mut tokio::io::sink()).await?;
+    Ok(output)
+}
+
+/// Standard output followed by standard error, cut to `MAX_ACTION_OUTPUT`
+/// bytes on a character boundary.
+fn truncate_output(stdout: &[u8], stderr: &[u8]) -> String {
+    let mut output = String::from_utf8_lossy(stdout).into_owned();
+    output.push_str(&String::from_utf8_lossy(stderr));
+    truncate_action_output(&mut output);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(
+            "restart-app=systemctl  restart myapp".parse(),
+            Ok(Action {
+                name: "restart-app".to_owned(),
+                program: "systemctl".to_owned(),
+                args: vec!["restart".to_owned(), "myapp".to_owned()],
+            })
+        );
+        assert!("restart-app".parse::<Action>().is_err());
+        assert!("restart-app= ".parse::<Action>().is_err());
+        assert!("Restart App=true".parse::<Action>().is_err());
+
+        let twice = vec!["a=true".parse().unwrap(), "a=false".parse().unwrap()];
+        assert!(Actions::new(twice, Duration::from_secs(1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_long_output() {
+        let long = "long=head -c 1000000 /dev/zero".parse().unwrap();
+        let actions = Actions::new(vec![long], Duration::from_secs(10)).unwrap();
+        let result = actions
+            .run(ActionRequest {
+                id: 1,
+                name: "long".to_owned(),
+            })
+            .await;
+        assert_eq!(result.exit_code, Some(0));
+        assert_eq!(result.output.len(), MAX_ACTION_OUTPUT);
+
+        let output = read_limited(Some(&[b'a'; MAX_ACTION_OUTPUT * 4][..]))
+            .await
+            .unwrap();
+        assert_eq!(output.len(), MAX_ACTION_OUTPUT);
+    }
+
+    #[test]
+    fn test_truncate_output() {
+        assert_eq!(truncate_output(b"out\n", b"err\n"), "out\nerr\n");
+
+        let long = "é".repeat(MAX_ACTION_OUTPUT);
+        let output = truncate_output(long.as_bytes(), b"");
+        assert_eq!(output.len(), MAX_ACTION_OUTPUT);
+    }
+}
@@ -0,0 +1,97 @@
+//! Primary IPv4 and IPv6 addresses of the host, those of the interface of
+//! the default route.
+//!
+//! Addresses rarely change, so they are looked up at most every
+//! [`CHECK_INTERVAL`] and only sent when they changed, for the server to keep
+//! a history of them.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+use miniprobe_proto::HostAddresses;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct AddressQuerent {
+    last_check: Option<Instant>,
+    last_sent: Option<HostAddresses>,
+}
+
+impl AddressQuerent {
+    /// The addresses if they changed since they were last returned, or
+    /// always if `force` is set, e.g. for the first sample of a session.
+    pub fn query(&mut self, force: bool) -> Option<HostAddresses> {
+        if !force
+            && self
+                .last_check
+                .is_some_and(|at| at.elapsed() < CHECK_INTERVAL)
+        {
+            return None;
+        }
+        self.last_check = Some(Instant::now());
+
+        let addresses = self.collect();
+        if !force && self.last_sent.as_ref() == Some(&addresses) {
+            return None;
+        }
+        self.last_sent = Some(addresses.clone());
+        Some(addresses)
+    }
+
+    /// A host without a default route has no addresses, which is a change
+    /// too.
+    fn collect(&self) -> HostAddresses {
+        let Ok(interface) = netdev::get_default_interface() else {
+            return HostAddresses {
+                ipv4: None,
+                ipv6: None,
+            };
+        };
+        HostAddresses {
+            ipv4: primary_ipv4(interface.ipv4.iter().map(|net| net.addr())),
+            ipv6: primary_ipv6(interface.ipv6.iter().map(|net| net.addr())),
+        }
+    }
+}
+
+fn primary_ipv4(addresses: impl IntoIterator<Item = Ipv4Addr>) -> Option<Ipv4Addr> {
+    addresses
+        .into_iter()
+        .find(|addr| !addr.is_loopback() && !addr.is_unspecified())
+}
+
+/// Link-local addresses are on every interface and say nothing about where
+/// the host is.
+fn primary_ipv6(addresses: impl IntoIterator<Item = Ipv6Addr>) -> Option<Ipv6Addr> {
+    addresses
+        .into_iter()
+        .find(|addr| !addr.is_loopback() && !addr.is_unspecified() && !addr.is_unicast_link_local())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_primary_addresses() {
+        let ipv4 = ["127.0.0.1", "192.0.2.7", "192.0.2.8"].map(|a| a.parse().unwrap());
+        assert_eq!(primary_ipv4(ipv4), Some("192.0.2.7".parse().unwrap()));
+        assert_eq!(primary_ipv4([]), None);
+
+        let ipv6 = ["fe80::1", "2001:db8::7"].map(|a| a.parse().unwrap());
+        assert_eq!(primary_ipv6(ipv6), Some("2001:db8::7".parse().unwrap()));
+        assert_eq!(primary_ipv6(["fe80::1".parse().unwrap()]), None);
+    }
+
+    #[test]
+    fn test_query_only_changes() {
+        let mut querent = AddressQuerent::default();
+        assert!(querent.query(true).is_some());
+        // not looked up again within the interval
+        assert!(querent.query(false).is_none());
+        assert!(querent.query(true).is_some());
+    }
+}
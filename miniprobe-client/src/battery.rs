@@ -0,0 +1,161 @@
+//! Battery collector, read from `/sys/class/power_supply` on Linux.
+//!
+//! Other platforms report no battery for now. Batteries of peripherals, such
+//! as wireless mice, are skipped.
+
+use miniprobe_proto::BatteryMetrics;
+#[cfg(any(target_os = "linux", test))]
+use miniprobe_proto::BatteryState;
+
+/// A single battery as found in sysfs.
+#[cfg(any(target_os = "linux", test))]
+#[derive(Debug, Clone, PartialEq)]
+struct Battery {
+    capacity: f32,
+    state: BatteryState,
+    /// Watts
+    power: Option<f32>,
+    /// Energy of a full battery in Wh, weighs the capacity of several batteries
+    energy_full: Option<f32>,
+}
+
+/// Combined state of every system battery, `None` without one.
+#[cfg(target_os = "linux")]
+pub fn query() -> Option<BatteryMetrics> {
+    let entries = match std::fs::read_dir("/sys/class/power_supply") {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::debug!("Failed to list power supplies: {e}");
+            return None;
+        }
+    };
+    let batteries = entries
+        .flatten()
+        .filter_map(|entry| read_battery(&entry.path()))
+        .collect::<Vec<_>>();
+    combine(&batteries)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn query() -> Option<BatteryMetrics> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn read_battery(dir: &std::path::Path) -> Option<Battery> {
+    let read = |name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|value| value.trim().to_owned())
+    };
+    // counters are in µW, µA, µV and µWh
+    let read_micro = |name: &str| read(name)?.parse::<f32>().ok().map(|v| v / 1e6);
+
+    if read("type")? != "Battery" || read("scope").as_deref() == Some("Device") {
+        return None;
+    }
+
+    let power = read_micro("power_now").or_else(|| {
+        let current = read_micro("current_now")?;
+        let voltage = read_micro("voltage_now")?;
+        Some(current * voltage)
+    });
+    Some(Battery {
+        capacity: read("capacity")?.parse().ok()?,
+        state: parse_state(&read("status").unwrap_or_default()),
+        // some drivers report a signed current, negative while discharging
+        power: power.map(f32::abs),
+        energy_full: read_micro("energy_full"),
+    })
+}
+
+/// Value of the sysfs `status` attribute.
+#[cfg(any(target_os = "linux", test))]
+fn parse_state(status: &str) -> BatteryState {
+    match status {
+        "Charging" => BatteryState::Charging,
+        "Discharging" => BatteryState::Discharging,
+        "Full" => BatteryState::Full,
+        "Not charging" => BatteryState::NotCharging,
+        _ => BatteryState::Unknown,
+    }
+}
+
+/// Fold several batteries into one, the capacity weighted by the energy they hold.
+#[cfg(any(target_os = "linux", test))]
+fn combine(batteries: &[Battery]) -> Option<BatteryMetrics> {
+    let first = batteries.first()?;
+
+    let weights = batteries
+        .iter()
+        .map(|battery| battery.energy_full.filter(|energy| *energy > 0.0))
+        .collect::<Option<Vec<_>>>()
+        .unwrap_or_else(|| vec![1.0; batteries.len()]);
+    let capacity = batteries
+        .iter()
+        .zip(&weights)
+        .map(|(battery, weight)| battery.capacity * weight)
+        .sum::<f32>()
+        / weights.iter().sum::<f32>();
+
+    // the batteries are drained or charged one after another, so any activity wins
+    let state = [BatteryState::Discharging, BatteryState::Charging]
+        .into_iter()
+        .find(|state| batteries.iter().any(|battery| battery.state == *state))
+        .unwrap_or(first.state);
+
+    let power = batteries
+        .iter()
+        .filter_map(|battery| battery.power)
+        .reduce(|a, b| a + b);
+
+    Some(BatteryMetrics {
+        capacity,
+        state,
+        power,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn battery(capacity: f32, state: BatteryState, energy_full: Option<f32>) -> Battery {
+        Battery {
+            capacity,
+            state,
+            power: Some(5.0),
+            energy_full,
+        }
+    }
+
+    #[test]
+    fn test_parse_state() {
+        assert_eq!(parse_state("Charging"), BatteryState::Charging);
+        assert_eq!(parse_state("Not charging"), BatteryState::NotCharging);
+        assert_eq!(parse_state(""), BatteryState::Unknown);
+    }
+
+    #[test]
+    fn test_combine() {
+        assert!(combine(&[]).is_none());
+
+        let metrics = combine(&[
+            battery(100.0, BatteryState::Full, Some(20.0)),
+            battery(40.0, BatteryState::Discharging, Some(60.0)),
+        ])
+        .unwrap();
+        assert_eq!(metrics.capacity, 55.0);
+        assert_eq!(metrics.state, BatteryState::Discharging);
+        assert_eq!(metrics.power, Some(10.0));
+
+        // without energy counters every battery weighs the same
+        let metrics = combine(&[
+            battery(100.0, BatteryState::Full, None),
+            battery(50.0, BatteryState::Full, Some(60.0)),
+        ])
+        .unwrap();
+        assert_eq!(metrics.capacity, 75.0);
+        assert_eq!(metrics.state, BatteryState::Full);
+    }
+}
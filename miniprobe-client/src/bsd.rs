@@ -0,0 +1,252 @@
+//! sysctl based collectors for the BSDs, used where sysinfo falls short.
+//!
+//! sysinfo does not support OpenBSD at all and may come back empty inside
+//! FreeBSD jails. Network counters are still read by netdev, which handles
+//! the BSDs through `getifaddrs`.
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+pub use querent::{BsdQuerent, uname};
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+mod querent {
+    use std::process::Command;
+
+    use miniprobe_proto::{CpuMetrics, MemoryMetrics};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    pub struct BsdQuerent {
+        /// Tick counters of the previous query, usage is computed from deltas
+        prev_ticks: Vec<Vec<u64>>,
+    }
+
+    impl BsdQuerent {
+        pub fn query_cpus(&mut self) -> anyhow::Result<Vec<CpuMetrics>> {
+            let ticks = cpu_ticks()?;
+            let cores = ticks
+                .iter()
+                .enumerate()
+                .map(|(i, ticks)| CpuMetrics {
+                    usage: self
+                        .prev_ticks
+                        .get(i)
+                        .map_or(0.0, |prev| cpu_usage(prev, ticks)),
+                })
+                .collect();
+            self.prev_ticks = ticks;
+            Ok(cores)
+        }
+
+        pub fn query_memory(&self) -> anyhow::Result<MemoryMetrics> {
+            let (total, used) = memory()?;
+            let (swap_total, swap_used) = parse_swapctl(&command("swapctl", &["-sk"])?)
+                .ok_or_else(|| anyhow::anyhow!("unexpected swapctl output"))?;
+            Ok(MemoryMetrics {
+                total,
+                used,
+                swap_total,
+                swap_used,
+            })
+        }
+    }
+
+    /// Output of `uname` with the given flag, for static metrics sysinfo can not read.
+    pub fn uname(flag: &str) -> Option<String> {
+        command("uname", &[flag])
+            .ok()
+            .map(|out| out.trim().to_owned())
+            .filter(|out| !out.is_empty())
+    }
+
+    #[cfg(target_os = "freebsd")]
+    fn cpu_ticks() -> anyhow::Result<Vec<Vec<u64>>> {
+        parse_cp_times(&command("sysctl", &["-n", "kern.cp_times"])?)
+            .ok_or_else(|| anyhow::anyhow!("unexpected kern.cp_times value"))
+    }
+
+    #[cfg(target_os = "openbsd")]
+    fn cpu_ticks() -> anyhow::Result<Vec<Vec<u64>>> {
+        let ncpu = command("sysctl", &["-n", "hw.ncpu"])?
+            .trim()
+            .parse::<usize>()?;
+        let names = (0..ncpu)
+            .map(|i| format!("kern.cp_time2.{i}"))
+            .collect::<Vec<_>>();
+        let mut args = vec!["-n"];
+        args.extend(names.iter().map(String::as_str));
+        parse_cp_time2(&command("sysctl", &args)?)
+            .ok_or_else(|| anyhow::anyhow!("unexpected kern.cp_time2 value"))
+    }
+
+    #[cfg(target_os = "freebsd")]
+    fn memory() -> anyhow::Result<(u64, u64)> {
+        let out = command(
+            "sysctl",
+            &[
+                "-n",
+                "hw.physmem",
+                "hw.pagesize",
+                "vm.stats.vm.v_free_count",
+            ],
+        )?;
+        let values = out
+            .lines()
+            .map(|v| v.trim().parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let [total, page_size, free_pages] = values[..] else {
+            anyhow::bail!("unexpected sysctl output: {out}");
+        };
+        Ok((total, total.saturating_sub(free_pages * page_size)))
+    }
+
+    #[cfg(target_os = "openbsd")]
+    fn memory() -> anyhow::Result<(u64, u64)> {
+        // the uvmexp struct is not printable with sysctl, vmstat summarizes it
+        let total = command("sysctl", &["-n", "hw.physmem"])?
+            .trim()
+            .parse::<u64>()?;
+        let used = parse_vmstat(&command("vmstat", &["-s"])?)
+            .ok_or_else(|| anyhow::anyhow!("unexpected vmstat output"))?;
+        Ok((total, used))
+    }
+
+    fn command(program: &str, args: &[&str]) -> anyhow::Result<String> {
+        let output = Command::new(program).args(args).output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "{program} exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
+
+/// Usage in percent between two tick counter snapshots, idle is the last state on every BSD.
+fn cpu_usage(prev: &[u64], ticks: &[u64]) -> f32 {
+    let total = ticks.iter().sum::<u64>().saturating_sub(prev.iter().sum());
+    let idle = ticks
+        .last()
+        .zip(prev.last())
+        .map_or(0, |(idle, prev)| idle.saturating_sub(*prev));
+    if total == 0 {
+        return 0.0;
+    }
+    (total - idle.min(total)) as f32 / total as f32 * 100.0
+}
+
+/// `kern.cp_times` on FreeBSD: five counters (user, nice, sys, intr, idle) per core.
+#[cfg(any(target_os = "freebsd", test))]
+fn parse_cp_times(out: &str) -> Option<Vec<Vec<u64>>> {
+    const CPUSTATES: usize = 5;
+    let ticks = out
+        .split_whitespace()
+        .map(|v| v.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if ticks.is_empty() || ticks.len() % CPUSTATES != 0 {
+        return None;
+    }
+    Some(ticks.chunks(CPUSTATES).map(<[u64]>::to_vec).collect())
+}
+
+/// `kern.cp_time2.N` on OpenBSD: one line of comma separated counters
+/// (user, nice, sys, spin, intr, idle) per core.
+#[cfg(any(target_os = "openbsd", test))]
+fn parse_cp_time2(out: &str) -> Option<Vec<Vec<u64>>> {
+    let cores = out
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            line.split(',')
+                .map(|v| v.trim().parse::<u64>().ok())
+                .collect::<Option<Vec<_>>>()
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!cores.is_empty()).then_some(cores)
+}
+
+/// Used bytes from `vmstat -s` on OpenBSD, which counts pages.
+#[cfg(any(target_os = "openbsd", test))]
+fn parse_vmstat(out: &str) -> Option<u64> {
+    let stat = |name: &str| {
+        out.lines().find_map(|line| {
+            let (value, key) = line.trim().split_once(' ')?;
+            (key.trim() == name).then(|| value.parse::<u64>().ok())?
+        })
+    };
+    let page_size = stat("bytes per page")?;
+    let managed = stat("pages managed")?;
+    let free = stat("pages free")?;
+    Some(managed.saturating_sub(free) * page_size)
+}
+
+/// Total and used swap in bytes from `swapctl -sk`, e.g.
+/// `total: 1048576 1K-blocks allocated, 0 used, 1048576 available` on OpenBSD
+/// or `Total:  2097152  0` on FreeBSD.
+fn parse_swapctl(out: &str) -> Option<(u64, u64)> {
+    let line = out
+        .lines()
+        .find(|line| line.trim_start().to_lowercase().starts_with("total"))?;
+    let mut values = line
+        .split_whitespace()
+        .filter_map(|v| v.trim_end_matches(',').parse::<u64>().ok());
+    let total = values.next()?;
+    let used = values.next()?;
+    Some((total * 1024, used * 1024))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cpu_usage() {
+        assert_eq!(cpu_usage(&[0, 0, 0, 0, 0], &[10, 0, 10, 0, 80]), 20.0);
+        assert_eq!(cpu_usage(&[10, 0, 10, 0, 80], &[10, 0, 10, 0, 80]), 0.0);
+    }
+
+    #[test]
+    fn test_parse_cp_times() {
+        let cores = parse_cp_times("1 2 3 4 5 6 7 8 9 10\n").unwrap();
+        assert_eq!(cores, vec![vec![1, 2, 3, 4, 5], vec![6, 7, 8, 9, 10]]);
+        assert!(parse_cp_times("1 2 3").is_none());
+        assert!(parse_cp_times("").is_none());
+    }
+
+    #[test]
+    fn test_parse_cp_time2() {
+        let cores = parse_cp_time2("1,2,3,4,5,6\n7,8,9,10,11,12\n").unwrap();
+        assert_eq!(
+            cores,
+            vec![vec![1, 2, 3, 4, 5, 6], vec![7, 8, 9, 10, 11, 12]]
+        );
+        assert!(parse_cp_time2("1,x,3").is_none());
+    }
+
+    #[test]
+    fn test_parse_vmstat() {
+        let out = "\
+     4096 bytes per page
+  2031118 pages managed
+  1813253 pages free
+    56421 pages active
+";
+        assert_eq!(parse_vmstat(out), Some((2031118 - 1813253) * 4096));
+        assert_eq!(parse_vmstat("4096 bytes per page"), None);
+    }
+
+    #[test]
+    fn test_parse_swapctl() {
+        assert_eq!(
+            parse_swapctl("total: 1048576 1K-blocks allocated, 512 used, 1048064 available\n"),
+            Some((1048576 * 1024, 512 * 1024))
+        );
+        assert_eq!(
+            parse_swapctl("Total:          2097152        0\n"),
+            Some((2097152 * 1024, 0))
+        );
+        assert_eq!(parse_swapctl("no swap device configured"), None);
+    }
+}
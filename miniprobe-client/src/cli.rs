@@ -0,0 +1,591 @@
+//! Command line of the client.
+//!
+//! `run` sends samples to a server and is what the client did before it had
+//! commands, so an invocation without one, e.g. `miniprobe-client TOKEN -a
+//! HOST:PORT`, still runs it. The other commands help setting up a host:
+//! `once` and `simulate` print samples without a server, `service install`
+//! writes a systemd unit and `buffer inspect` shows what `--buffer-dir` holds.
+
+use std::path::{Path, PathBuf};
+
+use argh::{EarlyExit, FromArgs};
+use miniprobe_proto::{CpuReportPolicy, limits::DEFAULT_SCRAPE_INTERVAL};
+
+use crate::{actions, http_util::IpVersion, offline, urgent};
+
+/// Variable holding the token when it is not an argument, kept out of the
+/// command line that other users can see.
+pub const TOKEN_ENV: &str = "MINIPROBE_TOKEN";
+
+/// Names of the commands, any other first argument is one of `run`.
+const COMMANDS: &[&str] = &["run", "once", "simulate", "service", "buffer", "help"];
+
+#[derive(FromArgs, Debug)]
+#[argh(description = "A lightweight system status probe client.")]
+pub struct Cli {
+    #[argh(subcommand)]
+    pub command: Command,
+}
+
+// parsed once, the size of `run` does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub enum Command {
+    Run(RunCommand),
+    Once(OnceCommand),
+    Simulate(SimulateCommand),
+    Service(ServiceCommand),
+    Buffer(BufferCommand),
+}
+
+/// Parse the command line, exiting on `--help` and errors.
+pub fn from_env() -> Cli {
+    let args = std::env::args().collect::<Vec<_>>();
+    let cmd = args
+        .first()
+        .and_then(|arg| Path::new(arg).file_name()?.to_str())
+        .unwrap_or("miniprobe-client")
+        .to_owned();
+    parse(&cmd, args.into_iter().skip(1).collect()).unwrap_or_else(|exit| match exit.status {
+        Ok(()) => {
+            println!("{}", exit.output);
+            std::process::exit(0)
+        }
+        Err(()) => {
+            eprintln!("{}\nRun {cmd} --help for more information.", exit.output);
+            std::process::exit(1)
+        }
+    })
+}
+
+fn parse(cmd: &str, mut args: Vec<String>) -> Result<Cli, EarlyExit> {
+    let command = args.first().map(String::as_str);
+    if !command.is_some_and(|arg| COMMANDS.contains(&arg) || arg == "--help" || arg == "-h") {
+        args.insert(0, "run".to_owned());
+    }
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    Cli::from_args(&[cmd], &args)
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(
+    subcommand,
+    name = "run",
+    description = "collect samples and send them to the server, also without a command"
+)]
+pub struct RunCommand {
+    #[argh(
+        positional,
+        description = "authentication token, $MINIPROBE_TOKEN if not given, not needed with --offline"
+    )]
+    pub token: Option<String>,
+    #[argh(
+        option,
+        short = 'a',
+        default = "\"127.0.0.1:8000\".to_string()",
+        description = "server address to connect to"
+    )]
+    pub server_addr: String,
+    #[argh(
+        option,
+        description = "look up the servers in the DNS SRV records of this name on every connect, e.g. _miniprobe._tcp.example.com, and try them by priority instead of --server-addr, Unix only and not with --socks5"
+    )]
+    pub server_srv: Option<String>,
+    #[argh(
+        switch,
+        short = 't',
+        description = "use TLS to connect to server (https/wss instead of http/ws)"
+    )]
+    pub tls: bool,
+    #[argh(
+        option,
+        default = "IpVersion::Auto",
+        description = "address family to reach the server with: auto (default), 4 or 6"
+    )]
+    pub ip_version: IpVersion,
+    #[argh(
+        option,
+        description = "connect to the server through the SOCKS5 proxy at HOST:PORT, e.g. an `ssh -D` dynamic forward, which also resolves the server name"
+    )]
+    pub socks5: Option<String>,
+    #[argh(
+        option,
+        description = "collect and connect from the named network namespace, e.g. a management VRF, Linux only and run through `ip netns exec`"
+    )]
+    pub netns: Option<String>,
+    #[argh(
+        option,
+        description = "network interfaces to report, comma separated or repeated, e.g. eth0,wg0, every physical one but loopback by default"
+    )]
+    pub interfaces: Vec<String>,
+    #[argh(
+        switch,
+        description = "report usage of every CPU core instead of the average and busiest core"
+    )]
+    pub per_core_cpu: bool,
+    #[argh(
+        switch,
+        description = "report charge, charging state and power draw of the battery"
+    )]
+    pub battery: bool,
+    #[argh(
+        option,
+        long = "unit",
+        description = "systemd unit to report the state of, may be repeated"
+    )]
+    pub units: Vec<String>,
+    #[argh(
+        switch,
+        description = "report listening TCP and UDP sockets and their processes when they change"
+    )]
+    pub listeners: bool,
+    #[argh(
+        option,
+        description = "send a sample right away when it crosses METRIC=VALUE, for cpu, memory and swap in percent or cpu_temperature in degrees Celsius, may be repeated"
+    )]
+    pub urgent: Vec<urgent::UrgentThreshold>,
+    #[argh(
+        option,
+        description = "send the collected samples in one message every this many seconds instead of after each scrape, needs a server accepting batches"
+    )]
+    pub send_interval: Option<u64>, // in seconds
+    #[argh(
+        option,
+        description = "compressions to offer the server, preferred first and separated by commas: zstd, deflate or identity, all of this build by default"
+    )]
+    pub compression: Option<String>,
+    #[argh(
+        switch,
+        description = "send network counters as bytes since the previous sample if the server accepts it, the buffer keeps them cumulative"
+    )]
+    pub delta_counters: bool,
+    #[argh(
+        switch,
+        description = "round CPU usages to 0.1% and byte counts to KiB before sending if the server accepts it, which compresses far better"
+    )]
+    pub coarse_precision: bool,
+    #[argh(
+        option,
+        long = "action",
+        description = "let the server run COMMAND by asking for NAME=COMMAND, without a shell and only if the server allows actions, may be repeated"
+    )]
+    pub actions: Vec<actions::Action>,
+    #[argh(
+        option,
+        default = "30",
+        description = "seconds an action may run before it is killed"
+    )]
+    pub action_timeout: u64, // in seconds
+    #[argh(
+        option,
+        default = "5",
+        description = "seconds a collector may take before its part of the sample is sent as missing"
+    )]
+    pub collect_timeout: u64, // in seconds
+    #[argh(
+        option,
+        default = "1",
+        description = "minimum interval between two connection retries in seconds"
+    )]
+    pub retry_minimum_interval: u64, // in seconds
+    #[argh(
+        option,
+        default = "300",
+        description = "maximum interval between two connection retries in seconds"
+    )]
+    pub retry_maximum_interval: u64, // in seconds
+    #[argh(
+        option,
+        default = "0.1",
+        description = "fraction the retry interval varies by at random so clients do not all reconnect at once, 0 disables"
+    )]
+    pub retry_jitter: f64,
+    #[argh(
+        option,
+        default = "120",
+        description = "exit when the client is stuck for this many seconds so a service manager can restart it, 0 disables"
+    )]
+    pub watchdog: u64, // in seconds
+    #[argh(
+        option,
+        description = "keep samples on disk in this directory until they are sent, so they survive lost connections and restarts"
+    )]
+    pub buffer_dir: Option<PathBuf>,
+    #[argh(
+        option,
+        default = "16",
+        description = "most disk space used by the sample buffer in MiB, the oldest samples are dropped first"
+    )]
+    pub buffer_size: u64, // in MiB
+    #[argh(
+        switch,
+        description = "write samples to files in --output instead of sending them to a server, for machines without network access"
+    )]
+    pub offline: bool,
+    #[argh(
+        option,
+        description = "directory the samples are written to with --offline"
+    )]
+    pub output: Option<PathBuf>,
+    #[argh(
+        option,
+        default = "offline::OutputFormat::Jsonl",
+        description = "format of the files written with --offline: jsonl (default) or postcard"
+    )]
+    pub output_format: offline::OutputFormat,
+    #[argh(
+        option,
+        default = "16",
+        description = "size in MiB after which --offline starts a new file"
+    )]
+    pub output_file_size: u64, // in MiB
+    #[argh(
+        option,
+        default = "DEFAULT_SCRAPE_INTERVAL.as_secs()",
+        description = "interval between two samples with --offline in seconds, the server decides otherwise"
+    )]
+    pub scrape_interval: u64, // in seconds
+}
+
+/// What the collector reports, as asked for on the command line.
+#[derive(Debug, Clone, Copy)]
+pub struct CollectOptions<'a> {
+    pub interfaces: &'a [String],
+    pub per_core_cpu: bool,
+    pub battery: bool,
+    pub units: &'a [String],
+    pub listeners: bool,
+    pub urgent: &'a [urgent::UrgentThreshold],
+    pub collect_timeout: u64, // in seconds
+}
+
+impl CollectOptions<'_> {
+    /// The CPU report shape asked for, the server may ask for another.
+    pub fn cpu_report(&self) -> CpuReportPolicy {
+        if self.per_core_cpu {
+            CpuReportPolicy::PerCore
+        } else {
+            CpuReportPolicy::Aggregate
+        }
+    }
+}
+
+impl RunCommand {
+    pub fn collect(&self) -> CollectOptions<'_> {
+        CollectOptions {
+            interfaces: &self.interfaces,
+            per_core_cpu: self.per_core_cpu,
+            battery: self.battery,
+            units: &self.units,
+            listeners: self.listeners,
+            urgent: &self.urgent,
+            collect_timeout: self.collect_timeout,
+        }
+    }
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(
+    subcommand,
+    name = "once",
+    description = "print the system information and a sample as JSON, without a server"
+)]
+pub struct OnceCommand {
+    #[argh(
+        option,
+        description = "network interfaces to report, comma separated or repeated, e.g. eth0,wg0, every physical one but loopback by default"
+    )]
+    pub interfaces: Vec<String>,
+    #[argh(
+        switch,
+        description = "report usage of every CPU core instead of the average and busiest core"
+    )]
+    pub per_core_cpu: bool,
+    #[argh(
+        switch,
+        description = "report charge, charging state and power draw of the battery"
+    )]
+    pub battery: bool,
+    #[argh(
+        option,
+        long = "unit",
+        description = "systemd unit to report the state of, may be repeated"
+    )]
+    pub units: Vec<String>,
+    #[argh(
+        switch,
+        description = "report listening TCP and UDP sockets and their processes"
+    )]
+    pub listeners: bool,
+    #[argh(
+        option,
+        default = "5",
+        description = "seconds a collector may take before its part of the sample is sent as missing"
+    )]
+    pub collect_timeout: u64, // in seconds
+}
+
+impl OnceCommand {
+    pub fn collect(&self) -> CollectOptions<'_> {
+        CollectOptions {
+            interfaces: &self.interfaces,
+            per_core_cpu: self.per_core_cpu,
+            battery: self.battery,
+            units: &self.units,
+            listeners: self.listeners,
+            urgent: &[],
+            collect_timeout: self.collect_timeout,
+        }
+    }
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(
+    subcommand,
+    name = "simulate",
+    description = "print the samples `run` would send as JSON lines, without a server"
+)]
+pub struct SimulateCommand {
+    #[argh(
+        option,
+        description = "network interfaces to report, comma separated or repeated, e.g. eth0,wg0, every physical one but loopback by default"
+    )]
+    pub interfaces: Vec<String>,
+    #[argh(
+        switch,
+        description = "report usage of every CPU core instead of the average and busiest core"
+    )]
+    pub per_core_cpu: bool,
+    #[argh(
+        switch,
+        description = "report charge, charging state and power draw of the battery"
+    )]
+    pub battery: bool,
+    #[argh(
+        option,
+        long = "unit",
+        description = "systemd unit to report the state of, may be repeated"
+    )]
+    pub units: Vec<String>,
+    #[argh(
+        switch,
+        description = "report listening TCP and UDP sockets and their processes when they change"
+    )]
+    pub listeners: bool,
+    #[argh(
+        option,
+        description = "mark samples crossing METRIC=VALUE as urgent, for cpu, memory and swap in percent or cpu_temperature in degrees Celsius, may be repeated"
+    )]
+    pub urgent: Vec<urgent::UrgentThreshold>,
+    #[argh(
+        option,
+        default = "5",
+        description = "seconds a collector may take before its part of the sample is sent as missing"
+    )]
+    pub collect_timeout: u64, // in seconds
+    #[argh(
+        option,
+        default = "DEFAULT_SCRAPE_INTERVAL.as_secs()",
+        description = "interval between two samples in seconds"
+    )]
+    pub scrape_interval: u64, // in seconds
+    #[argh(
+        option,
+        default = "0",
+        description = "number of samples to print, 0 prints until ctrl-c"
+    )]
+    pub count: u64,
+}
+
+impl SimulateCommand {
+    pub fn collect(&self) -> CollectOptions<'_> {
+        CollectOptions {
+            interfaces: &self.interfaces,
+            per_core_cpu: self.per_core_cpu,
+            battery: self.battery,
+            units: &self.units,
+            listeners: self.listeners,
+            urgent: &self.urgent,
+            collect_timeout: self.collect_timeout,
+        }
+    }
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(
+    subcommand,
+    name = "service",
+    description = "run the client as a service"
+)]
+pub struct ServiceCommand {
+    #[argh(subcommand)]
+    pub command: ServiceSubcommand,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub enum ServiceSubcommand {
+    Install(ServiceInstallCommand),
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(
+    subcommand,
+    name = "install",
+    description = "write a systemd unit running the client, e.g. `service install -- TOKEN -a HOST:PORT --tls`"
+)]
+pub struct ServiceInstallCommand {
+    #[argh(
+        option,
+        default = "PathBuf::from(\"/etc/systemd/system/miniprobe-client.service\")",
+        description = "path of the unit file, /etc/systemd/system/miniprobe-client.service by default"
+    )]
+    pub path: PathBuf,
+    #[argh(
+        option,
+        default = "PathBuf::from(\"/etc/miniprobe-client/env\")",
+        description = "path of the file passing the token to the unit, /etc/miniprobe-client/env by default"
+    )]
+    pub env_file: PathBuf,
+    #[argh(option, description = "user the client runs as, root by default")]
+    pub user: Option<String>,
+    #[argh(switch, description = "replace an existing unit and token file")]
+    pub force: bool,
+    #[argh(
+        positional,
+        greedy,
+        description = "arguments of `run`, after `--` if the first is an option"
+    )]
+    pub args: Vec<String>,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(
+    subcommand,
+    name = "buffer",
+    description = "look into the sample buffer of --buffer-dir"
+)]
+pub struct BufferCommand {
+    #[argh(subcommand)]
+    pub command: BufferSubcommand,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub enum BufferSubcommand {
+    Inspect(BufferInspectCommand),
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(
+    subcommand,
+    name = "inspect",
+    description = "show how many samples the buffer holds and how old they are, without changing it"
+)]
+pub struct BufferInspectCommand {
+    #[argh(positional, description = "directory given to --buffer-dir")]
+    pub dir: PathBuf,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Cli, EarlyExit> {
+        parse(
+            "miniprobe-client",
+            args.iter().map(|arg| arg.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn test_bare_invocation() {
+        let Command::Run(run) = parse_args(&["TOKEN", "-a", "example.com:8000", "--tls"])
+            .unwrap()
+            .command
+        else {
+            panic!("not run");
+        };
+        assert_eq!(run.token.as_deref(), Some("TOKEN"));
+        assert_eq!(run.server_addr, "example.com:8000");
+        assert!(run.tls);
+
+        let Command::Run(run) = parse_args(&["--offline", "--output", "/tmp"])
+            .unwrap()
+            .command
+        else {
+            panic!("not run");
+        };
+        assert!(run.offline);
+        assert_eq!(run.netns, None);
+
+        let Command::Run(run) = parse_args(&[
+            "TOKEN",
+            "--netns",
+            "mgmt",
+            "--server-srv",
+            "_miniprobe._tcp.example.com",
+        ])
+        .unwrap()
+        .command
+        else {
+            panic!("not run");
+        };
+        assert_eq!(run.netns.as_deref(), Some("mgmt"));
+        assert_eq!(
+            run.server_srv.as_deref(),
+            Some("_miniprobe._tcp.example.com")
+        );
+        assert!(matches!(
+            parse_args(&[]).unwrap().command,
+            Command::Run(RunCommand { token: None, .. })
+        ));
+        assert!(matches!(
+            parse_args(&["run", "TOKEN"]).unwrap().command,
+            Command::Run(_)
+        ));
+    }
+
+    #[test]
+    fn test_commands() {
+        assert!(matches!(
+            parse_args(&["once", "--battery"]).unwrap().command,
+            Command::Once(OnceCommand { battery: true, .. })
+        ));
+        assert!(matches!(
+            parse_args(&["simulate", "--count", "3"]).unwrap().command,
+            Command::Simulate(SimulateCommand { count: 3, .. })
+        ));
+        let Command::Once(once) =
+            parse_args(&["once", "--interfaces", "eth0,wg0", "--interfaces", "tun0"])
+                .unwrap()
+                .command
+        else {
+            panic!("not once");
+        };
+        assert_eq!(once.interfaces, ["eth0,wg0", "tun0"]);
+
+        let Command::Service(ServiceCommand {
+            command: ServiceSubcommand::Install(install),
+        }) = parse_args(&[
+            "service", "install", "--user", "probe", "--", "-a", "host:1", "TOKEN",
+        ])
+        .unwrap()
+        .command
+        else {
+            panic!("not service install");
+        };
+        assert_eq!(install.user.as_deref(), Some("probe"));
+        assert_eq!(install.args, ["-a", "host:1", "TOKEN"]);
+
+        assert!(matches!(
+            parse_args(&["buffer", "inspect", "/var/lib/miniprobe"])
+                .unwrap()
+                .command,
+            Command::Buffer(_)
+        ));
+        // the help of the commands, not that of `run`
+        assert_eq!(parse_args(&["--help"]).unwrap_err().status, Ok(()));
+    }
+}
@@ -0,0 +1,108 @@
+//! Synchronization of the system clock, asked from chrony or else
+//! systemd-timedated on Linux.
+//!
+//! Both are separate programs, so they are asked at most every
+//! [`CHECK_INTERVAL`] and the answer repeated in between. Other platforms
+//! report nothing for now.
+
+use std::time::{Duration, Instant};
+
+use miniprobe_proto::ClockMetrics;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct ClockQuerent {
+    last_check: Option<Instant>,
+    last: Option<ClockMetrics>,
+}
+
+impl ClockQuerent {
+    pub fn query(&mut self) -> Option<ClockMetrics> {
+        if self
+            .last_check
+            .is_none_or(|at| at.elapsed() >= CHECK_INTERVAL)
+        {
+            self.last_check = Some(Instant::now());
+            self.last = collect();
+        }
+        self.last.clone()
+    }
+}
+
+/// chrony knows the offset, timedated only whether the clock is synchronized
+/// by whichever daemon it manages.
+#[cfg(target_os = "linux")]
+fn collect() -> Option<ClockMetrics> {
+    if let Some(clock) = run("chronyc", &["-c", "tracking"]).and_then(|t| parse_chrony_tracking(&t))
+    {
+        return Some(clock);
+    }
+    let synchronized = run(
+        "timedatectl",
+        &["show", "--property=NTPSynchronized", "--value"],
+    )?;
+    Some(ClockMetrics {
+        synchronized: synchronized.trim() == "yes",
+        offset: None,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn collect() -> Option<ClockMetrics> {
+    None
+}
+
+/// Standard output of a program that exited successfully, `None` if it is
+/// not installed or failed, e.g. with its daemon not running.
+#[cfg(target_os = "linux")]
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .inspect_err(|e| log::debug!("Failed to run {program}: {e}"))
+        .ok()?;
+    if !output.status.success() {
+        log::debug!("{program} failed with {}", output.status);
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// The CSV of `chronyc -c tracking`: reference ID, name, stratum, reference
+/// time, the correction of the system time in seconds, positive while the
+/// clock is slow, and more, ending with the leap status.
+#[cfg(any(target_os = "linux", test))]
+fn parse_chrony_tracking(tracking: &str) -> Option<ClockMetrics> {
+    let fields = tracking.trim().split(',').collect::<Vec<_>>();
+    if fields.len() < 14 {
+        return None;
+    }
+    let correction = fields[4].parse::<f64>().ok()?;
+    Some(ClockMetrics {
+        synchronized: fields[13] != "Not synchronised",
+        offset: Some(-correction),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_chrony_tracking() {
+        let synced = "A9FEA9FE,169.254.169.254,3,1760000000.123456789,0.000012345,\
+            -0.000001,0.000020,-12.345,-0.001,0.020,0.000523,0.000123,64.2,Normal\n";
+        let clock = parse_chrony_tracking(synced).unwrap();
+        assert!(clock.synchronized);
+        assert_eq!(clock.offset, Some(-0.000012345));
+
+        let unsynced = "00000000,,0,0.000000000,0.000000000,0.000000000,0.000000000,\
+            0.000,0.000,0.000,1.000000000,1.000000000,0.0,Not synchronised\n";
+        assert!(!parse_chrony_tracking(unsynced).unwrap().synchronized);
+
+        assert!(parse_chrony_tracking("506 Cannot talk to daemon").is_none());
+    }
+}
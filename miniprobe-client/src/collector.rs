@@ -0,0 +1,108 @@
+//! Runs the [`MetricsQuerent`] on a thread of its own, so slow collectors do
+//! not hold up the websocket on the main runtime.
+//!
+//! The collector thread has its own single threaded runtime for the
+//! collectors that are async, such as the D-Bus queries of services. The
+//! main runtime talks to it through a bounded queue of requests, every
+//! request is answered on a oneshot channel.
+
+use std::thread;
+
+use miniprobe_proto::{CpuReportPolicy, DynamicMetrics, StaticMetrics};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{query::MetricsQuerent, supervisor::Watchdog};
+
+/// Requests waiting for the collector, more only happen if it falls behind
+const QUEUE_SIZE: usize = 4;
+
+enum Request {
+    Dynamic {
+        seq: u64,
+        reply: oneshot::Sender<DynamicMetrics<'static>>,
+    },
+    Static(oneshot::Sender<StaticMetrics>),
+    SetCpuReport(CpuReportPolicy),
+}
+
+/// Handle of the collector thread, which ends once the handle is dropped.
+///
+/// A panic while collecting ends the thread, the next request on the handle
+/// then panics as well, so the caller starts over with a fresh collector.
+#[derive(Debug)]
+pub struct Collector {
+    requests: mpsc::Sender<Request>,
+}
+
+impl Collector {
+    /// Move `querent` to a new collector thread, with its runtime watched by
+    /// `watchdog`.
+    pub fn spawn(mut querent: MetricsQuerent, watchdog: Option<Watchdog>) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let (requests, mut rx) = mpsc::channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("collector".to_owned())
+            .spawn(move || {
+                runtime.block_on(async {
+                    if let Some(watchdog) = watchdog {
+                        watchdog.watch_current_runtime();
+                    }
+                    while let Some(request) = rx.recv().await {
+                        match request {
+                            Request::Dynamic { seq, reply } => {
+                                let _ = reply.send(querent.query_dynamic(seq).await);
+                            }
+                            Request::Static(reply) => {
+                                let _ = reply.send(querent.query_static().await);
+                            }
+                            Request::SetCpuReport(policy) => querent.set_cpu_report(policy),
+                        }
+                    }
+                })
+            })?;
+        Ok(Collector { requests })
+    }
+
+    pub async fn query_dynamic(&self, seq: u64) -> DynamicMetrics<'static> {
+        let (reply, rx) = oneshot::channel();
+        self.request(Request::Dynamic { seq, reply }).await;
+        rx.await.expect("collector thread stopped")
+    }
+
+    pub async fn query_static(&self) -> StaticMetrics {
+        let (reply, rx) = oneshot::channel();
+        self.request(Request::Static(reply)).await;
+        rx.await.expect("collector thread stopped")
+    }
+
+    pub async fn set_cpu_report(&self, policy: CpuReportPolicy) {
+        self.request(Request::SetCpuReport(policy)).await;
+    }
+
+    async fn request(&self, request: Request) {
+        if self.requests.send(request).await.is_err() {
+            panic!("collector thread stopped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collector() {
+        let querent = MetricsQuerent::new();
+        let collector = Collector::spawn(querent, None).unwrap();
+        collector.set_cpu_report(CpuReportPolicy::PerCore).await;
+        let metrics = collector.query_dynamic(3).await;
+        assert_eq!(metrics.seq, 3);
+        assert!(matches!(
+            metrics.cpu,
+            miniprobe_proto::CpuReport::PerCore(_)
+        ));
+        collector.query_static().await;
+    }
+}
@@ -1,49 +1,380 @@
-use std::time::Duration;
+use std::{borrow::Cow, collections::HashMap, future::Future, io, sync::Arc, time::Duration};
 
 use bytes::BytesMut;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{Sink, SinkExt, StreamExt};
 use http::{HeaderValue, header};
-use log::{debug, warn};
-use miniprobe_proto::msg::SessionToken;
-use tokio::time::{Instant, sleep_until};
-use tokio_tungstenite::tungstenite::{Message, client::IntoClientRequest, protocol::CloseFrame};
+use log::{debug, info, warn};
+use miniprobe_proto::{
+    CpuReport, DynamicMetrics, METRICS_SCHEMA_HASH, MetricsBatch, Section, UnixMillis,
+    codec::Encoder,
+    metrics_math::{counter_delta, quantize_bytes, quantize_percent},
+    msg::{
+        ACTIONS_HEADER, ACTIONS_PARAM, CLOSE_MAINTENANCE, COARSE_PRECISION, COMPRESSION_HEADER,
+        COMPRESSION_PARAM, COUNTERS_HEADER, COUNTERS_PARAM, Compression, CreateSessionResp,
+        DELTA_COUNTERS, IngressControl, PRECISION_HEADER, PRECISION_PARAM, PROTOCOL_HEADER,
+        PROTOCOL_VERSION, SCHEMA_HEADER, SessionToken, parse_maintenance_close_reason,
+    },
+};
+use tokio::{
+    sync::{mpsc, watch},
+    time::{Instant, sleep_until},
+};
+use tokio_tungstenite::tungstenite::{
+    self, Message, client::IntoClientRequest, protocol::CloseFrame,
+};
 use tokio_util::sync::CancellationToken;
 
-use crate::{http_util::connect_tls, query::MetricsQuerent};
+use crate::{
+    actions::Actions,
+    collector::Collector,
+    http_util::{IpVersion, connect_tls, user_agent},
+    journal::Journal,
+    session::Maintenance,
+};
 
+/// How samples are collected into batches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchPolicy {
+    pub send_interval: Duration,
+    /// Most samples the server accepts in one batch
+    pub max_size: usize,
+    /// Most bytes of an encoded batch the server accepts, larger batches are
+    /// split
+    pub max_bytes: usize,
+}
+
+impl BatchPolicy {
+    /// Keep within the limits the server sent with the session.
+    pub fn limit(self, session: &CreateSessionResp) -> Self {
+        BatchPolicy {
+            max_size: self.max_size.min(session.max_batch_size.max(1) as usize),
+            max_bytes: self
+                .max_bytes
+                .min(usize::try_from(session.max_message_size).unwrap_or(usize::MAX)),
+            ..self
+        }
+    }
+}
+
+/// Turns the cumulative network counters of the samples sent on a connection
+/// into bytes since the previous one, see `DELTA_COUNTERS`.
+#[derive(Debug, Default)]
+struct CounterDeltas {
+    /// Counters of every interface in the previous sample with network
+    /// metrics
+    previous: HashMap<String, (Option<u64>, Option<u64>)>,
+}
+
+impl CounterDeltas {
+    fn apply(&mut self, metrics: &mut DynamicMetrics) {
+        if metrics.is_missing(Section::Network) {
+            return;
+        }
+        // interfaces gone from the sample are forgotten, counters of one
+        // coming back tell nothing
+        let mut previous = std::mem::take(&mut self.previous);
+        for network in &mut metrics.networks {
+            let counters = previous.remove(network.ifname.as_ref());
+            self.previous.insert(
+                network.ifname.to_string(),
+                (network.rx_bytes, network.tx_bytes),
+            );
+            let delta = |previous: Option<u64>, current: Option<u64>| {
+                Some(counter_delta(previous?, current?))
+            };
+            network.rx_bytes = delta(counters.and_then(|p| p.0), network.rx_bytes);
+            network.tx_bytes = delta(counters.and_then(|p| p.1), network.tx_bytes);
+        }
+    }
+}
+
+/// Most the server may stretch the scrape interval by, servers send at most 8.
+const MAX_SLOW_DOWN: f32 = 16.0;
+
+/// The factor to stretch the scrape interval by for a `SlowDown`, `None` for
+/// nonsense like NaN.
+fn slow_down_factor(factor: f32) -> Option<f32> {
+    factor.is_finite().then(|| factor.clamp(1.0, MAX_SLOW_DOWN))
+}
+
+/// Numbers samples for the server to drop the ones sent twice, e.g. resent
+/// from the journal after reconnecting. Numbers count up from the sample time
+/// in milliseconds, so they do not repeat for the client across sessions and
+/// restarts, and never go back when the clock does. The journal persists the
+/// last number for that.
+#[derive(Debug, Default)]
+pub struct SampleSeq {
+    last: Option<u64>,
+}
+
+impl SampleSeq {
+    /// Numbers after `last`, whatever the clock says.
+    pub fn after(last: Option<u64>) -> Self {
+        SampleSeq { last }
+    }
+
+    pub fn last(&self) -> Option<u64> {
+        self.last
+    }
+
+    pub fn next(&mut self, sample_time: UnixMillis) -> u64 {
+        let seq = match self.last {
+            Some(last) => sample_time.0.max(last + 1),
+            None => sample_time.0,
+        };
+        self.last = Some(seq);
+        seq
+    }
+}
+
+/// Round the CPU usages of a sample to a tenth of a percent and its byte
+/// counts to KiB, see `COARSE_PRECISION`. Done before the deltas, so they add
+/// up to the rounded counters.
+fn quantize(metrics: &mut DynamicMetrics) {
+    match &mut metrics.cpu {
+        CpuReport::PerCore(cores) => {
+            for core in cores {
+                core.usage = quantize_percent(core.usage);
+            }
+        }
+        CpuReport::Aggregate { usage, max_core } => {
+            *usage = quantize_percent(*usage);
+            *max_core = quantize_percent(*max_core);
+        }
+    }
+    let memory = &mut metrics.memory;
+    for bytes in [
+        &mut memory.total,
+        &mut memory.used,
+        &mut memory.swap_total,
+        &mut memory.swap_used,
+    ] {
+        *bytes = quantize_bytes(*bytes);
+    }
+    for network in &mut metrics.networks {
+        network.rx_bytes = network.rx_bytes.map(quantize_bytes);
+        network.tx_bytes = network.tx_bytes.map(quantize_bytes);
+    }
+    for disk in &mut metrics.disks {
+        disk.total = quantize_bytes(disk.total);
+        disk.available = quantize_bytes(disk.available);
+        disk.read_bytes = disk.read_bytes.map(quantize_bytes);
+        disk.written_bytes = disk.written_bytes.map(quantize_bytes);
+    }
+    metrics.probe.cpu_usage = metrics.probe.cpu_usage.map(quantize_percent);
+    metrics.probe.rss = metrics.probe.rss.map(quantize_bytes);
+}
+
+/// The compressions of a `--compression` list, all this build supports
+/// without one.
+pub fn offered_compressions(list: Option<&str>) -> anyhow::Result<Vec<Compression>> {
+    let Some(list) = list else {
+        return Ok(Compression::supported());
+    };
+    let supported = Compression::supported();
+    let mut offered = Vec::new();
+    for name in list.split(',').map(str::trim) {
+        let compression = name.parse::<Compression>().map_err(anyhow::Error::msg)?;
+        if !supported.contains(&compression) {
+            anyhow::bail!("{name} compression is not supported by this build");
+        }
+        if !offered.contains(&compression) {
+            offered.push(compression);
+        }
+    }
+    Ok(offered)
+}
+
+/// Scrape metrics and send them to the server until ctrl-c.
+///
+/// With a `batch` policy the samples are collected into a batch and sent
+/// together once the interval has passed or the batch is full.
+///
+/// With a `journal` every sample is stored in it first and sent from there,
+/// starting with the samples left over from before.
+///
+/// Messages are compressed with the first of `compression` the server
+/// supports, uncompressed if it supports none. With `delta_counters` network
+/// counters are sent as deltas if the server accepts them, with
+/// `coarse_precision` values are rounded if the server knows. A ping is sent
+/// when nothing else was for `heartbeat_interval`.
+///
+/// Samples sent without a journal are numbered by `sample_seq`, kept across
+/// connections.
+///
+/// `actions` are offered to the server, which may ask for them if it allows
+/// actions at all. Their results are sent between samples.
+#[allow(clippy::too_many_arguments)]
 pub async fn metrics_egress(
-    querent: &mut MetricsQuerent,
+    collector: &Collector,
+    mut journal: Option<&mut Journal>,
+    sample_seq: &mut SampleSeq,
     scrape_interval: Duration,
+    batch_policy: Option<BatchPolicy>,
+    heartbeat_interval: Duration,
+    compression: &[Compression],
+    delta_counters: bool,
+    coarse_precision: bool,
+    actions: Option<Arc<Actions>>,
     session_token: &SessionToken,
     server_addr: &str,
     tls: bool,
-    prefer_ipv6: bool,
+    ip_version: IpVersion,
+    socks5: Option<&str>,
 ) -> anyhow::Result<()> {
+    let offered = compression
+        .iter()
+        .map(|c| c.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    // ask for an ack of every message, to log what the server complains about
     let mut req = format!(
-        "{}://{server_addr}/ws/v1/metrics/ingress",
-        if tls { "wss" } else { "ws" }
+        "{}://{server_addr}/ws/v1/metrics/ingress?ack=1&{COMPRESSION_PARAM}={offered}{}{}{}{}",
+        if tls { "wss" } else { "ws" },
+        if batch_policy.is_some() {
+            "&batch=true"
+        } else {
+            ""
+        },
+        if delta_counters {
+            format!("&{COUNTERS_PARAM}={DELTA_COUNTERS}")
+        } else {
+            String::new()
+        },
+        if coarse_precision {
+            format!("&{PRECISION_PARAM}={COARSE_PRECISION}")
+        } else {
+            String::new()
+        },
+        match &actions {
+            Some(actions) => format!("&{ACTIONS_PARAM}={}", actions.names()),
+            None => String::new(),
+        }
     )
     .into_client_request()?;
     req.headers_mut().insert(
         header::AUTHORIZATION,
         HeaderValue::from_str(format!("Bearer {session_token}").as_str())?,
     );
+    req.headers_mut().insert(
+        SCHEMA_HEADER,
+        HeaderValue::from_str(&format!("{METRICS_SCHEMA_HASH:016x}"))?,
+    );
+    req.headers_mut()
+        .insert(PROTOCOL_HEADER, HeaderValue::from(PROTOCOL_VERSION));
+    req.headers_mut()
+        .insert(header::USER_AGENT, HeaderValue::from_str(&user_agent())?);
 
-    let stream = connect_tls(&req, tls, prefer_ipv6).await?;
+    let stream = connect_tls(&req, tls, ip_version, socks5).await?;
 
-    let (socket, _) = tokio_tungstenite::client_async(req, stream).await?;
+    let (socket, resp) = match tokio_tungstenite::client_async(req, stream).await {
+        Ok(connected) => connected,
+        Err(tungstenite::Error::Http(resp)) => {
+            return Err(match Maintenance::from_response(&resp) {
+                Some(maintenance) => maintenance.into(),
+                None => tungstenite::Error::Http(resp).into(),
+            });
+        }
+        Err(e) => return Err(e.into()),
+    };
+    // servers predating compression send no header
+    let compression = match resp.headers().get(COMPRESSION_HEADER) {
+        Some(value) => value
+            .to_str()?
+            .parse::<Compression>()
+            .map_err(anyhow::Error::msg)?,
+        None => Compression::Identity,
+    };
+    debug!("compressing messages with {}", compression.as_str());
+    let mut encoder = Encoder::new(compression)?;
+    // servers predating deltas, or relaying samples, keep counters cumulative
+    let mut deltas = resp
+        .headers()
+        .get(COUNTERS_HEADER)
+        .is_some_and(|value| value == DELTA_COUNTERS)
+        .then(CounterDeltas::default);
+    if delta_counters {
+        debug!("sending network counters as deltas: {}", deltas.is_some());
+    }
+    // servers predating rounding would take the values for exact ones
+    let coarse = resp
+        .headers()
+        .get(PRECISION_HEADER)
+        .is_some_and(|value| value == COARSE_PRECISION);
+    if coarse_precision {
+        debug!("rounding values: {coarse}");
+    }
+    // servers not allowing actions never ask for them
+    let actions = actions.filter(|_| resp.headers().contains_key(ACTIONS_HEADER));
+    debug!("server may ask for actions: {}", actions.is_some());
+    let (action_results_tx, mut action_results) = mpsc::channel(4);
+    let action_results_tx = actions.is_some().then_some(action_results_tx);
 
     let (mut write, mut read) = socket.split();
 
-    let read_task = tokio::spawn(async move {
+    // the server may ask us to stretch the interval while it catches up
+    let (slow_down_tx, slow_down_rx) = watch::channel(1.0f32);
+    // journaled samples are dropped once the server acknowledges them
+    let (acked_tx, mut acked_rx) = watch::channel(None);
+
+    // the server going into maintenance tells when to come back
+    let mut read_task = tokio::spawn(async move {
+        let mut maintenance = None;
         while let Some(Ok(msg)) = read.next().await {
             match msg {
+                Message::Binary(buf) => match postcard::from_bytes(&buf) {
+                    Ok(IngressControl::SlowDown { factor }) => {
+                        debug!("server requested slow down by factor {factor}");
+                        match slow_down_factor(factor) {
+                            Some(factor) => {
+                                let _ = slow_down_tx.send(factor);
+                            }
+                            None => warn!("Invalid slow down factor from server: {factor}"),
+                        }
+                    }
+                    Ok(IngressControl::Ack(ack)) => {
+                        debug!(
+                            "server stored {} samples up to seq {:?}",
+                            ack.stored, ack.last_seq
+                        );
+                        if ack.last_seq.is_some() {
+                            let _ = acked_tx.send(ack.last_seq);
+                        }
+                        for warning in ack.warnings {
+                            warn!("Server warning: {warning}");
+                        }
+                    }
+                    Ok(IngressControl::RunAction(request)) => {
+                        match (&actions, &action_results_tx) {
+                            (Some(actions), Some(results)) => {
+                                let actions = actions.clone();
+                                let results = results.clone();
+                                tokio::spawn(async move {
+                                    results.send(actions.run(request).await).await.ok();
+                                });
+                            }
+                            _ => warn!("Server asked for action '{}' not offered", request.name),
+                        }
+                    }
+                    Err(e) => warn!("Invalid control message from server: {e}"),
+                },
+                Message::Close(Some(CloseFrame { code, reason }))
+                    if u16::from(code) == CLOSE_MAINTENANCE =>
+                {
+                    info!("Server entered maintenance: {reason}");
+                    maintenance = Some(Maintenance {
+                        retry_after: parse_maintenance_close_reason(&reason)
+                            .map(Duration::from_secs),
+                    });
+                }
                 Message::Close(Some(CloseFrame { code, reason })) => {
                     warn!("WebSocket closed by server: code={code:?}, reason={reason}");
                 }
-                _ => {} // we dont care
+                // we dont care about other messages
+                _ => {}
             }
         }
+        maintenance
     });
 
     let shutdown_token = CancellationToken::new();
@@ -57,18 +388,114 @@ pub async fn metrics_egress(
         }
     });
 
+    // samples from the journal keep the numbers they were stored with, those
+    // sent on an earlier connection without an ack are sent again
+    if let Some(journal) = journal.as_deref_mut() {
+        journal.rewind();
+    }
+    if let Some(journal) = journal.as_deref_mut()
+        && let Err(e) = send_journal(
+            journal,
+            &mut write,
+            &mut encoder,
+            &mut deltas,
+            coarse,
+            batch_policy,
+        )
+        .await
+    {
+        let _ = tokio::join!(write.close(), read_task);
+        return Err(e);
+    }
+
+    let mut seq = 0;
+    let mut batch = MetricsBatch::new();
+    let mut unsent = 0;
+    let mut last_sent = Instant::now();
+    let mut last_message = last_sent;
     loop {
         let current_time = Instant::now();
-        let metrics = querent.query_dynamic();
-        let res: anyhow::Result<()> = async {
-            write
-                .send(Message::Binary(
-                    postcard::to_extend(&metrics, BytesMut::new())?.freeze(),
-                ))
+        let mut metrics = collector.query_dynamic(seq).await;
+        seq += 1;
+        // whether a message was sent
+        let res: anyhow::Result<bool> = async {
+            if let Some(journal) = journal.as_deref_mut() {
+                let urgent = metrics.urgent;
+                journal.append(&mut metrics)?;
+                unsent += 1;
+                if let Some(BatchPolicy {
+                    send_interval,
+                    max_size,
+                    ..
+                }) = batch_policy
+                    && !urgent
+                    && unsent < max_size
+                    && current_time < last_sent + send_interval
+                {
+                    return Ok(false);
+                }
+                send_journal(
+                    journal,
+                    &mut write,
+                    &mut encoder,
+                    &mut deltas,
+                    coarse,
+                    batch_policy,
+                )
                 .await?;
-            Ok(())
+                unsent = 0;
+                last_sent = current_time;
+                return Ok(true);
+            }
+
+            metrics.seq = sample_seq.next(metrics.sample_time);
+            if coarse {
+                quantize(&mut metrics);
+            }
+            if let Some(deltas) = &mut deltas {
+                deltas.apply(&mut metrics);
+            }
+            let bufs = match batch_policy {
+                Some(BatchPolicy {
+                    send_interval,
+                    max_size,
+                    max_bytes,
+                }) => {
+                    let urgent = metrics.urgent;
+                    batch.push(metrics);
+                    if !urgent && batch.len() < max_size && current_time < last_sent + send_interval
+                    {
+                        return Ok(false);
+                    }
+                    let bufs = encode_batches(&batch, max_bytes)?;
+                    batch.clear();
+                    last_sent = current_time;
+                    bufs
+                }
+                None => vec![postcard::to_extend(&metrics, BytesMut::new())?],
+            };
+            for buf in bufs {
+                write.send(binary(&mut encoder, buf)?).await?;
+            }
+            debug!("metrics egress sucessfully");
+            Ok(true)
         }
         .await;
+        let res = match res {
+            Ok(true) => {
+                last_message = current_time;
+                Ok(())
+            }
+            // keep the websocket from looking dead while samples are batched
+            Ok(false) if current_time >= last_message + heartbeat_interval => {
+                last_message = current_time;
+                write
+                    .send(Message::Ping(Default::default()))
+                    .await
+                    .map_err(Into::into)
+            }
+            res => res.map(drop),
+        };
 
         // delay error propagation
         if let Err(e) = res {
@@ -76,15 +503,371 @@ pub async fn metrics_egress(
             return Err(e);
         }
 
-        debug!("metrics egress sucessfully");
+        // wait scrape interval or ctrl-c, sending the results of actions meanwhile
+        let interval = scrape_interval.mul_f32(*slow_down_rx.borrow());
+        loop {
+            tokio::select! {
+               _ = shutdown_token.cancelled() => {
+                   // send what was collected so far, the journal keeps what is not
+                   if let Some(journal) = journal.as_deref_mut() {
+                       let _ = send_journal(journal, &mut write, &mut encoder, &mut deltas, coarse, batch_policy).await;
+                   } else if let Some(policy) = batch_policy
+                       && !batch.is_empty()
+                       && let Ok(bufs) = encode_batches(&batch, policy.max_bytes)
+                   {
+                       for buf in bufs {
+                           if let Ok(msg) = binary(&mut encoder, buf) {
+                               let _ = write.send(msg).await;
+                           }
+                       }
+                   }
+                   let _ = tokio::join!(write.close(), read_task);
+                   // the acks of the last samples arrive before the close
+                   let _ = acknowledge(journal.as_deref_mut(), &mut acked_rx);
+                   return Ok(());
+               }
+               _ = sleep_until(current_time + interval) => break,
+               Ok(()) = acked_rx.changed() => {
+                   if let Err(e) = acknowledge(journal.as_deref_mut(), &mut acked_rx) {
+                       warn!("Failed to drop acknowledged samples from the buffer: {e}");
+                   }
+               }
+               Some(result) = action_results.recv() => {
+                   let msg = Message::Text(serde_json::to_string(&result)?.into());
+                   if let Err(e) = write.send(msg).await {
+                       let _ = tokio::join!(write.close(), read_task);
+                       return Err(e.into());
+                   }
+               }
+               // the server closed the connection, e.g. when a quota is exceeded
+               res = &mut read_task => match res {
+                   Ok(Some(maintenance)) => return Err(maintenance.into()),
+                   Err(e) if e.is_panic() => anyhow::bail!("WebSocket reader failed: {e}"),
+                   _ => anyhow::bail!("WebSocket closed by server"),
+               }
+            }
+        }
+    }
+}
+
+/// Send every sample stored in the journal not sent yet, in batches if the
+/// server takes them, and mark them as sent. They stay in the journal until
+/// the server acknowledges them.
+async fn send_journal<S>(
+    journal: &mut Journal,
+    write: &mut S,
+    encoder: &mut Encoder,
+    deltas: &mut Option<CounterDeltas>,
+    coarse: bool,
+    batch_policy: Option<BatchPolicy>,
+) -> anyhow::Result<()>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let max_size = batch_policy.map_or(1, |policy| policy.max_size);
+    while !journal.is_empty() {
+        let (mut samples, position) = journal.peek(max_size)?;
+        if samples.is_empty() {
+            break;
+        }
+        for sample in &mut samples {
+            if coarse {
+                quantize(sample);
+            }
+            if let Some(deltas) = deltas {
+                deltas.apply(sample);
+            }
+        }
+        let bufs = match batch_policy {
+            Some(policy) => encode_batches(&samples, policy.max_bytes)?,
+            None => vec![postcard::to_extend(&samples[0], BytesMut::new())?],
+        };
+        for buf in bufs {
+            write.send(binary(encoder, buf)?).await?;
+        }
+        let last = samples.last().expect("samples are not empty");
+        journal.mark_sent(last.seq, position);
+        debug!("sent {} buffered samples", samples.len());
+    }
+    Ok(())
+}
+
+/// Drop the samples from the journal up to the latest the server
+/// acknowledged.
+fn acknowledge(
+    journal: Option<&mut Journal>,
+    acked: &mut watch::Receiver<Option<u64>>,
+) -> anyhow::Result<()> {
+    let seq = *acked.borrow_and_update();
+    match (journal, seq) {
+        (Some(journal), Some(seq)) => journal.acknowledge(seq),
+        _ => Ok(()),
+    }
+}
+
+/// `samples` encoded as batches of at most `max_bytes`, halved until they
+/// fit. A single sample larger than that is an error.
+fn encode_batches(samples: &[DynamicMetrics], max_bytes: usize) -> anyhow::Result<Vec<BytesMut>> {
+    let buf = postcard::to_extend(samples, BytesMut::new())?;
+    if buf.len() <= max_bytes {
+        return Ok(vec![buf]);
+    }
+    if samples.len() < 2 {
+        anyhow::bail!(
+            "sample of {} bytes is larger than the {max_bytes} bytes the server accepts",
+            buf.len()
+        );
+    }
+    let (first, second) = samples.split_at(samples.len() / 2);
+    let mut bufs = encode_batches(first, max_bytes)?;
+    bufs.extend(encode_batches(second, max_bytes)?);
+    Ok(bufs)
+}
+
+/// A binary message of `buf`, compressed as negotiated.
+fn binary(encoder: &mut Encoder, buf: BytesMut) -> io::Result<Message> {
+    Ok(Message::Binary(match encoder.encode(&buf)? {
+        Cow::Borrowed(_) => buf.freeze(),
+        Cow::Owned(compressed) => compressed.into(),
+    }))
+}
 
-        // wait scrape interval or ctrl-c
+/// Keep collecting samples into the journal until `wait` completes, so a
+/// lost connection leaves no gap. `next_scrape` carries over between waits.
+pub async fn collect_while(
+    collector: &Collector,
+    journal: &mut Journal,
+    scrape_interval: Duration,
+    next_scrape: &mut Instant,
+    wait: impl Future<Output = ()>,
+) {
+    tokio::pin!(wait);
+    // only the first sample carries the full inventory, later ones their
+    // changes like on a connection
+    let mut seq = 0;
+    loop {
         tokio::select! {
-           _ = shutdown_token.cancelled() => {
-               let _ = tokio::join!(write.close(), read_task);
-               return Ok(());
-           }
-           _ = sleep_until(current_time + scrape_interval) => { /* continue */ }
+            _ = &mut wait => return,
+            _ = sleep_until(*next_scrape) => {}
+        }
+        *next_scrape += scrape_interval;
+        if let Err(e) = journal.append(&mut collector.query_dynamic(seq).await) {
+            warn!("Failed to buffer sample: {e}");
         }
+        seq += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_offered_compressions() {
+        assert_eq!(
+            offered_compressions(None).unwrap(),
+            Compression::supported()
+        );
+        assert_eq!(
+            offered_compressions(Some("deflate, identity,deflate")).unwrap(),
+            [Compression::Deflate, Compression::Identity]
+        );
+        assert!(offered_compressions(Some("brotli")).is_err());
+        assert!(offered_compressions(Some("")).is_err());
+    }
+
+    fn sample() -> DynamicMetrics<'static> {
+        DynamicMetrics {
+            seq: 0,
+            sample_time: miniprobe_proto::UnixMillis(1_700_000_000_000),
+            cpu: miniprobe_proto::CpuReport::Aggregate {
+                usage: 12.5,
+                max_core: 50.0,
+            },
+            memory: miniprobe_proto::MemoryMetrics {
+                total: 8 << 30,
+                used: 2 << 30,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![miniprobe_proto::NetworkMetrics {
+                ifname: "eth0".into(),
+                rx_bytes: Some(1 << 20),
+                tx_bytes: Some(1 << 20),
+            }],
+            disks: Vec::new(),
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: None,
+            services: Vec::new(),
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_slow_down_factor() {
+        assert_eq!(slow_down_factor(2.0), Some(2.0));
+        assert_eq!(slow_down_factor(0.5), Some(1.0));
+        assert_eq!(slow_down_factor(1e30), Some(MAX_SLOW_DOWN));
+        assert_eq!(slow_down_factor(f32::INFINITY), None);
+        assert_eq!(slow_down_factor(f32::NEG_INFINITY), None);
+        assert_eq!(slow_down_factor(f32::NAN), None);
+        // the largest factor still makes a valid interval
+        assert!(Duration::from_secs(3600).mul_f32(MAX_SLOW_DOWN) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_sample_seq() {
+        let mut seq = SampleSeq::default();
+        assert_eq!(seq.next(UnixMillis(1000)), 1000);
+        assert_eq!(seq.next(UnixMillis(2000)), 2000);
+        // the clock went back or two samples in the same millisecond
+        assert_eq!(seq.next(UnixMillis(1500)), 2001);
+        assert_eq!(seq.next(UnixMillis(2001)), 2002);
+        assert_eq!(seq.next(UnixMillis(3000)), 3000);
+
+        // continuing from a number the journal stored
+        let mut seq = SampleSeq::after(seq.last());
+        assert_eq!(seq.next(UnixMillis(1000)), 3001);
+    }
+
+    #[tokio::test]
+    async fn test_collect_while() {
+        let dir =
+            std::env::temp_dir().join(format!("miniprobe-collect-while-{}", std::process::id()));
+        let mut journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        let collector = Collector::spawn(crate::query::MetricsQuerent::new(), None).unwrap();
+        let mut next_scrape = Instant::now();
+        collect_while(
+            &collector,
+            &mut journal,
+            Duration::from_millis(10),
+            &mut next_scrape,
+            tokio::time::sleep(Duration::from_secs(1)),
+        )
+        .await;
+
+        let (samples, _) = journal.peek(16).unwrap();
+        assert!(samples.len() >= 2, "{} samples", samples.len());
+        assert!(samples[0].addresses.is_some());
+        assert!(samples[1..].iter().all(|s| s.addresses.is_none()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_counter_deltas() {
+        let mut deltas = CounterDeltas::default();
+        let mut counters = |networks: &[(&'static str, Option<u64>, Option<u64>)], missing| {
+            let mut metrics = sample();
+            metrics.networks = networks
+                .iter()
+                .map(
+                    |&(ifname, rx_bytes, tx_bytes)| miniprobe_proto::NetworkMetrics {
+                        ifname: ifname.into(),
+                        rx_bytes,
+                        tx_bytes,
+                    },
+                )
+                .collect();
+            if missing {
+                metrics.missing_sections.push(Section::Network);
+            }
+            deltas.apply(&mut metrics);
+            metrics
+                .networks
+                .iter()
+                .map(|network| (network.rx_bytes, network.tx_bytes))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            counters(&[("eth0", Some(100), Some(10))], false),
+            [(None, None)]
+        );
+        assert_eq!(
+            counters(&[("eth0", Some(250), Some(40))], false),
+            [(Some(150), Some(30))]
+        );
+        assert_eq!(
+            counters(&[("eth0", Some(900), None)], true),
+            [(Some(900), None)]
+        );
+        assert_eq!(
+            counters(
+                &[
+                    ("eth0", Some(400), Some(50)),
+                    ("wlan0", Some(700), Some(70))
+                ],
+                false
+            ),
+            [(Some(150), Some(10)), (None, None)]
+        );
+        assert_eq!(
+            counters(&[("wlan0", Some(800), None)], false),
+            [(Some(100), None)]
+        );
+        // eth0 was missing in between
+        assert_eq!(
+            counters(
+                &[
+                    ("eth0", Some(500), Some(60)),
+                    ("wlan0", Some(900), Some(90))
+                ],
+                false
+            ),
+            [(None, None), (Some(100), None)]
+        );
+    }
+
+    #[test]
+    fn test_quantize() {
+        let mut metrics = sample();
+        metrics.cpu = CpuReport::Aggregate {
+            usage: 12.345,
+            max_core: 99.97,
+        };
+        metrics.memory.used = (2 << 30) + 700;
+        metrics.networks[0].rx_bytes = Some(1500);
+        metrics.networks[0].tx_bytes = None;
+        metrics.probe.cpu_usage = Some(0.04);
+        quantize(&mut metrics);
+
+        let CpuReport::Aggregate { usage, max_core } = metrics.cpu else {
+            unreachable!()
+        };
+        assert_eq!((usage, max_core), (12.3, 100.0));
+        assert_eq!(metrics.memory.used, (2 << 30) + 1024);
+        assert_eq!(metrics.memory.total, 8 << 30);
+        assert_eq!(
+            (metrics.networks[0].rx_bytes, metrics.networks[0].tx_bytes),
+            (Some(1024), None)
+        );
+        assert_eq!(metrics.probe.cpu_usage, Some(0.0));
+    }
+
+    #[test]
+    fn test_encode_batches() {
+        let samples = vec![sample(); 8];
+
+        let whole = encode_batches(&samples, usize::MAX).unwrap();
+        assert_eq!(whole.len(), 1);
+
+        // room for three samples, halved down to batches of two
+        let one = encode_batches(&samples[..1], usize::MAX).unwrap()[0].len();
+        let bufs = encode_batches(&samples, 3 * one).unwrap();
+        assert_eq!(bufs.len(), 4);
+        let mut decoded = 0;
+        for buf in &bufs {
+            assert!(buf.len() <= 3 * one);
+            decoded += postcard::from_bytes::<MetricsBatch>(buf).unwrap().len();
+        }
+        assert_eq!(decoded, samples.len());
+
+        assert!(encode_batches(&samples, one - 1).is_err());
     }
 }
@@ -0,0 +1,116 @@
+//! File descriptor and TCP connection counts, read from `/proc` on Linux.
+//!
+//! Only small fixed size files are read, never the socket tables, so the cost
+//! does not grow with the number of connections. Other platforms report none
+//! for now.
+
+use miniprobe_proto::FdMetrics;
+
+/// Open file descriptors of the system and TCP connections by state.
+#[cfg(target_os = "linux")]
+pub fn query() -> Option<FdMetrics> {
+    let read = |path: &str| match std::fs::read_to_string(path) {
+        Ok(content) => Some(content),
+        Err(e) => {
+            log::debug!("Failed to read {path}: {e}");
+            None
+        }
+    };
+    let (open, max) = parse_file_nr(&read("/proc/sys/fs/file-nr")?)?;
+    Some(FdMetrics {
+        open,
+        max,
+        // both files cover IPv4 and IPv6
+        tcp_established: read("/proc/net/snmp").and_then(|snmp| parse_established(&snmp)),
+        tcp_time_wait: read("/proc/net/sockstat").and_then(|sockstat| parse_time_wait(&sockstat)),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn query() -> Option<FdMetrics> {
+    None
+}
+
+/// Open and most file descriptors from `/proc/sys/fs/file-nr`, e.g.
+/// `1536 0 9223372036854775807`: allocated, allocated but unused and the
+/// limit.
+#[cfg(any(target_os = "linux", test))]
+fn parse_file_nr(file_nr: &str) -> Option<(u64, u64)> {
+    let fields = file_nr
+        .split_whitespace()
+        .map(|field| field.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match fields[..] {
+        [allocated, unused, max] => Some((allocated.saturating_sub(unused), max)),
+        _ => None,
+    }
+}
+
+/// `CurrEstab` of `/proc/net/snmp`, connections established or closing on
+/// the remote end. The `Tcp:` line with the field names precedes the one
+/// with the values.
+#[cfg(any(target_os = "linux", test))]
+fn parse_established(snmp: &str) -> Option<u32> {
+    let mut lines = snmp.lines().filter(|line| line.starts_with("Tcp:"));
+    let (names, values) = (lines.next()?, lines.next()?);
+    let position = names
+        .split_whitespace()
+        .position(|name| name == "CurrEstab")?;
+    values.split_whitespace().nth(position)?.parse().ok()
+}
+
+/// `tw` of the `TCP:` line of `/proc/net/sockstat`, e.g.
+/// `TCP: inuse 4 orphan 0 tw 12 alloc 6 mem 1`.
+#[cfg(any(target_os = "linux", test))]
+fn parse_time_wait(sockstat: &str) -> Option<u32> {
+    let line = sockstat
+        .lines()
+        .find_map(|line| line.strip_prefix("TCP:"))?;
+    let mut fields = line.split_whitespace();
+    while let Some(name) = fields.next() {
+        let value = fields.next()?;
+        if name == "tw" {
+            return value.parse().ok();
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_file_nr() {
+        assert_eq!(
+            parse_file_nr("1536\t0\t9223372036854775807\n"),
+            Some((1536, 9223372036854775807))
+        );
+        // kernels before 2.6 counted freed handles as unused
+        assert_eq!(parse_file_nr("2048 512 65536"), Some((1536, 65536)));
+        assert_eq!(parse_file_nr("2048 512"), None);
+        assert_eq!(parse_file_nr(""), None);
+    }
+
+    #[test]
+    fn test_parse_tcp_states() {
+        let snmp = "\
+Ip: Forwarding DefaultTTL InReceives
+Ip: 1 64 42
+Tcp: RtoAlgorithm RtoMin RtoMax MaxConn ActiveOpens PassiveOpens AttemptFails EstabResets CurrEstab InSegs
+Tcp: 1 200 120000 -1 606 515 68 172 27 42840
+Udp: InDatagrams NoPorts
+Udp: 10 0
+";
+        assert_eq!(parse_established(snmp), Some(27));
+        assert_eq!(parse_established("Ip: Forwarding\nIp: 1\n"), None);
+
+        let sockstat = "\
+sockets: used 18
+TCP: inuse 4 orphan 0 tw 12 alloc 6 mem 1
+UDP: inuse 0 mem 0
+";
+        assert_eq!(parse_time_wait(sockstat), Some(12));
+        assert_eq!(parse_time_wait("UDP: inuse 0 tw 3\n"), None);
+    }
+}
@@ -1,4 +1,9 @@
-use std::{pin::Pin, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    time::Duration,
+};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use http::{Method, Request, Response, Uri, header, request, response};
@@ -9,12 +14,49 @@ use tokio::{
     net::{TcpStream, ToSocketAddrs, lookup_host},
     task::JoinSet,
 };
+#[cfg(feature = "native-tls")]
 use tokio_native_tls::{TlsConnector as TokioTlsConnector, TlsStream, native_tls::TlsConnector};
 
 const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(150);
 
+/// `User-Agent` of every request, e.g. `miniprobe-client/0.1.0 (linux; x86_64)`.
+pub fn user_agent() -> String {
+    format!(
+        "miniprobe-client/{} ({}; {})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )
+}
+
+/// Address family used to reach the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpVersion {
+    /// Race both families, IPv4 first
+    #[default]
+    Auto,
+    /// Only connect over IPv4
+    V4,
+    /// Only connect over IPv6
+    V6,
+}
+
+impl FromStr for IpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(IpVersion::Auto),
+            "4" => Ok(IpVersion::V4),
+            "6" => Ok(IpVersion::V6),
+            _ => Err(format!("invalid IP version '{s}', expected auto, 4 or 6")),
+        }
+    }
+}
+
 pub enum MaybeTlsStream<S> {
     Plain(S),
+    #[cfg(feature = "native-tls")]
     Tls(TlsStream<S>),
 }
 
@@ -26,6 +68,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeTlsStream<S> {
     ) -> std::task::Poll<std::io::Result<()>> {
         match self.get_mut() {
             MaybeTlsStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "native-tls")]
             MaybeTlsStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
         }
     }
@@ -39,6 +82,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTlsStream<S> {
     ) -> std::task::Poll<std::io::Result<usize>> {
         match self.get_mut() {
             MaybeTlsStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "native-tls")]
             MaybeTlsStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
         }
     }
@@ -49,6 +93,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTlsStream<S> {
     ) -> std::task::Poll<std::io::Result<()>> {
         match self.get_mut() {
             MaybeTlsStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "native-tls")]
             MaybeTlsStream::Tls(s) => Pin::new(s).poll_flush(cx),
         }
     }
@@ -59,6 +104,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTlsStream<S> {
     ) -> std::task::Poll<std::io::Result<()>> {
         match self.get_mut() {
             MaybeTlsStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "native-tls")]
             MaybeTlsStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
         }
     }
@@ -84,6 +130,7 @@ pub fn basic_request_builder(uri: &str, method: Method) -> anyhow::Result<reques
         .header(header::HOST, host)
         .header(header::CONNECTION, "close")
         .header(header::ACCEPT_ENCODING, "identity")
+        .header(header::USER_AGENT, user_agent())
         .uri(&uri);
 
     Ok(req)
@@ -92,9 +139,10 @@ pub fn basic_request_builder(uri: &str, method: Method) -> anyhow::Result<reques
 pub async fn send_http_request<T: AsRef<[u8]>>(
     req: Request<T>,
     tls: bool,
-    prefer_ipv6: bool,
+    ip_version: IpVersion,
+    socks5: Option<&str>,
 ) -> anyhow::Result<Response<Bytes>> {
-    let stream = &mut connect_tls(&req, tls, prefer_ipv6).await?;
+    let stream = &mut connect_tls(&req, tls, ip_version, socks5).await?;
 
     stream.write_all(&assemble_http_request(req)?).await?;
     stream.flush().await?;
@@ -111,10 +159,13 @@ pub async fn send_http_request<T: AsRef<[u8]>>(
     Ok(resp)
 }
 
+/// Connect to the host of `req`, directly or through the SOCKS5 proxy at
+/// `socks5`. `ip_version` applies to whichever is connected to.
 pub async fn connect_tls<T>(
     req: &Request<T>,
     tls: bool,
-    prefer_ipv6: bool,
+    ip_version: IpVersion,
+    socks5: Option<&str>,
 ) -> anyhow::Result<MaybeTlsStream<TcpStream>> {
     let domain = req
         .uri()
@@ -122,8 +173,12 @@ pub async fn connect_tls<T>(
         .ok_or_else(|| anyhow::anyhow!("URL error: no host name"))?;
     let port = req.uri().port_u16().unwrap_or(if tls { 443 } else { 80 });
     trace!("connecting to ({domain}, {port})");
-    let stream = connect_happy_eyeballs((domain, port), prefer_ipv6).await?;
+    let stream = match socks5 {
+        Some(proxy) => connect_socks5(proxy, domain, port, ip_version).await?,
+        None => connect_happy_eyeballs((domain, port), ip_version).await?,
+    };
 
+    #[cfg(feature = "native-tls")]
     let stream = if tls {
         let connector = TokioTlsConnector::from(TlsConnector::new()?);
         let tls_stream = connector.connect(domain, stream).await?;
@@ -131,23 +186,24 @@ pub async fn connect_tls<T>(
     } else {
         MaybeTlsStream::Plain(stream)
     };
+    #[cfg(not(feature = "native-tls"))]
+    let stream = if tls {
+        anyhow::bail!("TLS error: built without TLS support, enable the `native-tls` feature");
+    } else {
+        MaybeTlsStream::Plain(stream)
+    };
 
     Ok(stream)
 }
 
 async fn connect_happy_eyeballs<A: ToSocketAddrs>(
     addr: A,
-    prefer_ipv6: bool,
+    ip_version: IpVersion,
 ) -> anyhow::Result<TcpStream> {
-    let addrs = {
-        let (v4, v6): (Vec<_>, Vec<_>) = lookup_host(addr).await?.partition(|a| a.is_ipv4());
-
-        let (first, second) = if prefer_ipv6 { (v6, v4) } else { (v4, v6) };
-        first
-            .into_iter()
-            .interleave(second.into_iter())
-            .collect::<Vec<_>>()
-    };
+    let addrs = order_addresses(lookup_host(addr).await?, ip_version);
+    if addrs.is_empty() {
+        anyhow::bail!("I/O error: server address has no {ip_version:?} address");
+    }
 
     let mut attempts = JoinSet::new();
     let handle_attempt_result = move |res: Result<Result<TcpStream, _>, _>| match res {
@@ -197,6 +253,103 @@ async fn connect_happy_eyeballs<A: ToSocketAddrs>(
     Err(anyhow::anyhow!("I/O error: all connection attempts failed"))
 }
 
+/// Connect to `(host, port)` through the SOCKS5 proxy at `proxy`, without
+/// authentication. Host names are resolved by the proxy, so an `ssh -D`
+/// forward reaches what the SSH server does.
+async fn connect_socks5(
+    proxy: &str,
+    host: &str,
+    port: u16,
+    ip_version: IpVersion,
+) -> anyhow::Result<TcpStream> {
+    let mut stream = connect_happy_eyeballs(proxy, ip_version).await?;
+
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    match choice {
+        [5, 0] => {}
+        [5, _] => anyhow::bail!("SOCKS5 error: proxy requires authentication"),
+        _ => anyhow::bail!("SOCKS5 error: {proxy} is not a SOCKS5 proxy"),
+    }
+
+    stream
+        .write_all(&socks5_connect_request(host, port)?)
+        .await?;
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        anyhow::bail!("SOCKS5 error: {proxy} is not a SOCKS5 proxy");
+    }
+    if reply[1] != 0 {
+        anyhow::bail!("SOCKS5 error: {}", socks5_reply_message(reply[1]));
+    }
+    // the address the proxy connected from is of no use, but has to be read
+    let addr_len = match reply[3] {
+        1 => 4,
+        3 => stream.read_u8().await? as usize,
+        4 => 16,
+        atyp => anyhow::bail!("SOCKS5 error: unknown address type {atyp}"),
+    };
+    let mut bound = vec![0; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    debug!("connected to ({host}, {port}) through SOCKS5 proxy {proxy}");
+    Ok(stream)
+}
+
+/// `CONNECT` request of RFC 1928, with the host as IP address if it is one.
+fn socks5_connect_request(host: &str, port: u16) -> anyhow::Result<Vec<u8>> {
+    let mut req = vec![5, 1, 0];
+    // IPv6 hosts of URLs are in brackets
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(1);
+            req.extend(ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(4);
+            req.extend(ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| anyhow::anyhow!("SOCKS5 error: host name too long"))?;
+            req.push(3);
+            req.push(len);
+            req.extend(host.as_bytes());
+        }
+    }
+    req.extend(port.to_be_bytes());
+    Ok(req)
+}
+
+fn socks5_reply_message(rep: u8) -> String {
+    match rep {
+        1 => "general SOCKS server failure".to_owned(),
+        2 => "connection not allowed by ruleset".to_owned(),
+        3 => "network unreachable".to_owned(),
+        4 => "host unreachable".to_owned(),
+        5 => "connection refused".to_owned(),
+        6 => "TTL expired".to_owned(),
+        7 => "command not supported".to_owned(),
+        8 => "address type not supported".to_owned(),
+        rep => format!("unknown reply {rep}"),
+    }
+}
+
+/// Connection attempt order, alternating between the families unless one is
+/// forced, in which case the addresses of the other are dropped.
+fn order_addresses(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    ip_version: IpVersion,
+) -> Vec<SocketAddr> {
+    let (v4, v6): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv4());
+    match ip_version {
+        IpVersion::Auto => v4.into_iter().interleave(v6).collect(),
+        IpVersion::V4 => v4,
+        IpVersion::V6 => v6,
+    }
+}
+
 fn assemble_http_request<T: AsRef<[u8]>>(req: Request<T>) -> anyhow::Result<Bytes> {
     let mut buffer = BytesMut::with_capacity(128);
 
@@ -259,3 +412,144 @@ fn parse_http_response(bytes: Bytes) -> anyhow::Result<http::Response<Bytes>> {
 
     Ok(response_builder.body(body)?)
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn v4(last: u8) -> SocketAddr {
+        (Ipv4Addr::new(192, 0, 2, last), 80).into()
+    }
+
+    fn v6(last: u16) -> SocketAddr {
+        (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last), 80).into()
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let agent = user_agent();
+        assert!(agent.starts_with(concat!(
+            "miniprobe-client/",
+            env!("CARGO_PKG_VERSION"),
+            " ("
+        )));
+        assert!(agent.ends_with(&format!("; {})", std::env::consts::ARCH)));
+        assert!(header::HeaderValue::from_str(&agent).is_ok());
+    }
+
+    #[test]
+    fn test_parse_ip_version() {
+        assert_eq!("auto".parse(), Ok(IpVersion::Auto));
+        assert_eq!("4".parse(), Ok(IpVersion::V4));
+        assert_eq!("6".parse(), Ok(IpVersion::V6));
+        assert!("ipv6".parse::<IpVersion>().is_err());
+    }
+
+    #[test]
+    fn test_order_dual_stack() {
+        let addrs = [v6(1), v6(2), v4(1), v4(2)];
+        assert_eq!(
+            order_addresses(addrs, IpVersion::Auto),
+            [v4(1), v6(1), v4(2), v6(2)]
+        );
+        assert_eq!(order_addresses(addrs, IpVersion::V4), [v4(1), v4(2)]);
+        assert_eq!(order_addresses(addrs, IpVersion::V6), [v6(1), v6(2)]);
+    }
+
+    #[test]
+    fn test_order_single_stack() {
+        let v6_only = [v6(1), v6(2)];
+        assert_eq!(order_addresses(v6_only, IpVersion::Auto), v6_only);
+        assert_eq!(order_addresses(v6_only, IpVersion::V6), v6_only);
+        assert!(order_addresses(v6_only, IpVersion::V4).is_empty());
+
+        let v4_only = [v4(1)];
+        assert_eq!(order_addresses(v4_only, IpVersion::Auto), v4_only);
+        assert!(order_addresses(v4_only, IpVersion::V6).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_forced_family() {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(
+            connect_happy_eyeballs((Ipv4Addr::LOCALHOST, port), IpVersion::Auto)
+                .await
+                .is_ok()
+        );
+        assert!(
+            connect_happy_eyeballs((Ipv4Addr::LOCALHOST, port), IpVersion::V4)
+                .await
+                .is_ok()
+        );
+        // an IPv4 address never falls back when IPv6 is forced
+        assert!(
+            connect_happy_eyeballs((Ipv4Addr::LOCALHOST, port), IpVersion::V6)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_socks5_connect_request() {
+        assert_eq!(
+            socks5_connect_request("example.com", 443).unwrap(),
+            b"\x05\x01\x00\x03\x0bexample.com\x01\xbb"
+        );
+        assert_eq!(
+            socks5_connect_request("192.0.2.1", 80).unwrap(),
+            [5, 1, 0, 1, 192, 0, 2, 1, 0, 80]
+        );
+        assert_eq!(socks5_connect_request("[::1]", 80).unwrap()[3..4], [4]);
+        assert!(socks5_connect_request(&"a".repeat(256), 80).is_err());
+    }
+
+    /// A proxy answering one `CONNECT` with `rep`, then echoing.
+    async fn socks5_proxy(rep: u8) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut req = [0; 5 + 11 + 2];
+            stream.read_exact(&mut req).await.unwrap();
+            assert_eq!(&req[5..16], b"example.com");
+            stream
+                .write_all(&[5, rep, 0, 1, 127, 0, 0, 1, 0x1f, 0x90])
+                .await
+                .unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_connect_socks5() {
+        let proxy = socks5_proxy(0).await.to_string();
+        let mut stream = connect_socks5(&proxy, "example.com", 80, IpVersion::Auto)
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let proxy = socks5_proxy(5).await.to_string();
+        let e = connect_socks5(&proxy, "example.com", 80, IpVersion::Auto)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "SOCKS5 error: connection refused");
+    }
+}
@@ -0,0 +1,171 @@
+//! Identifiers of the host sent with the system information: the boot id,
+//! new with every boot, and the machine id, kept across reboots and hostname
+//! changes.
+//!
+//! Linux has both as files. Elsewhere they are asked from `sysctl`, `ioreg`
+//! or the registry where the platform has them, `None` otherwise.
+
+/// Longest identifier sent, longer ones are not what we asked for.
+const MAX_ID_LENGTH: usize = 64;
+
+#[cfg(target_os = "linux")]
+pub fn boot_id() -> Option<String> {
+    read_id("/proc/sys/kernel/random/boot_id")
+}
+
+#[cfg(target_os = "macos")]
+pub fn boot_id() -> Option<String> {
+    run("sysctl", &["-n", "kern.bootsessionuuid"]).and_then(|out| parse_id(&out))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn boot_id() -> Option<String> {
+    None
+}
+
+/// systemd's machine id, D-Bus keeps a copy where systemd is not used.
+#[cfg(target_os = "linux")]
+pub fn machine_id() -> Option<String> {
+    read_id("/etc/machine-id").or_else(|| read_id("/var/lib/dbus/machine-id"))
+}
+
+#[cfg(target_os = "freebsd")]
+pub fn machine_id() -> Option<String> {
+    read_id("/etc/hostid")
+        .or_else(|| run("sysctl", &["-n", "kern.hostuuid"]).and_then(|out| parse_id(&out)))
+}
+
+#[cfg(target_os = "openbsd")]
+pub fn machine_id() -> Option<String> {
+    run("sysctl", &["-n", "hw.uuid"]).and_then(|out| parse_id(&out))
+}
+
+#[cfg(target_os = "macos")]
+pub fn machine_id() -> Option<String> {
+    run("ioreg", &["-rd1", "-c", "IOPlatformExpertDevice"]).and_then(|out| parse_ioreg(&out))
+}
+
+#[cfg(windows)]
+pub fn machine_id() -> Option<String> {
+    run(
+        "reg",
+        &[
+            "query",
+            r"HKLM\SOFTWARE\Microsoft\Cryptography",
+            "/v",
+            "MachineGuid",
+        ],
+    )
+    .and_then(|out| parse_reg_query(&out))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "macos",
+    windows
+)))]
+pub fn machine_id() -> Option<String> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn read_id(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|id| parse_id(&id))
+}
+
+/// Standard output of a program that exited successfully.
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "macos",
+    windows
+))]
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// An identifier as read, lowercase without surrounding whitespace. `None`
+/// if empty, implausibly long or not yet set up, like the `uninitialized`
+/// machine id of a system during its first boot.
+fn parse_id(id: &str) -> Option<String> {
+    let id = id.trim().to_ascii_lowercase();
+    let plausible = !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id != "uninitialized"
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    plausible.then_some(id)
+}
+
+/// `IOPlatformUUID` of `ioreg -rd1 -c IOPlatformExpertDevice`, a line like
+/// `"IOPlatformUUID" = "564D8A6B-..."`.
+#[cfg(any(target_os = "macos", test))]
+fn parse_ioreg(out: &str) -> Option<String> {
+    out.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "\"IOPlatformUUID\"").then(|| parse_id(value.trim().trim_matches('"')))?
+    })
+}
+
+/// `MachineGuid` of `reg query`, a line like
+/// `    MachineGuid    REG_SZ    8a3e...`.
+#[cfg(any(windows, test))]
+fn parse_reg_query(out: &str) -> Option<String> {
+    out.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        (fields.next()? == "MachineGuid" && fields.next()? == "REG_SZ")
+            .then(|| parse_id(fields.next()?))?
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(
+            parse_id("5E8F3C2A-1B4D-4C6E-9F0A-7D2B8E1C3F5A\n").as_deref(),
+            Some("5e8f3c2a-1b4d-4c6e-9f0a-7d2b8e1c3f5a")
+        );
+        assert_eq!(parse_id(" \n"), None);
+        assert_eq!(parse_id("uninitialized\n"), None);
+        assert_eq!(parse_id("not an id"), None);
+        assert_eq!(parse_id(&"a".repeat(65)), None);
+    }
+
+    #[test]
+    fn test_parse_ioreg() {
+        let out = r#"+-o MacBookPro18,3  <class IOPlatformExpertDevice, id 0x100000110>
+    {
+      "IOPlatformSerialNumber" = "C02XXXXXXXXX"
+      "IOPlatformUUID" = "564D8A6B-0C3E-4E6A-9B1F-2D7C5E8A9B0C"
+    }"#;
+        assert_eq!(
+            parse_ioreg(out).as_deref(),
+            Some("564d8a6b-0c3e-4e6a-9b1f-2d7c5e8a9b0c")
+        );
+        assert_eq!(parse_ioreg("\"IOPlatformSerialNumber\" = \"C02\""), None);
+    }
+
+    #[test]
+    fn test_parse_reg_query() {
+        let out = "\r\nHKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Cryptography\r\n    \
+            MachineGuid    REG_SZ    8a3e2c1d-5b6f-4a7e-9c0d-1e2f3a4b5c6d\r\n\r\n";
+        assert_eq!(
+            parse_reg_query(out).as_deref(),
+            Some("8a3e2c1d-5b6f-4a7e-9c0d-1e2f3a4b5c6d")
+        );
+        assert_eq!(parse_reg_query("ERROR: not found"), None);
+    }
+}
@@ -0,0 +1,612 @@
+//! On-disk buffer of samples not sent yet, enabled with `--buffer-dir`.
+//!
+//! Samples are appended to segment files `<index>.seg` as postcard records
+//! framed by [`miniprobe_proto::record`], and synced before they count as
+//! stored. A power loss leaves at most a torn record at the end of the newest
+//! segment, which fails its checksum and is cut off on the next start.
+//! Samples the server acknowledged are marked by the `cursor` file, replaced
+//! atomically, and segments behind it are deleted.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use miniprobe_proto::{
+    DynamicMetrics, UnixMillis,
+    record::{self, Records},
+};
+
+use crate::egress::SampleSeq;
+
+const HEADER_SIZE: u64 = record::HEADER_SIZE as u64;
+const MAX_SEGMENT_SIZE: u64 = 1024 * 1024;
+const CURSOR_FILE: &str = "cursor";
+
+/// Position in the journal, a segment index and byte offset in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    segment: u64,
+    offset: u64,
+}
+
+#[derive(Debug)]
+struct Segment {
+    index: u64,
+    size: u64,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    max_size: u64,
+    segment_size: u64,
+    /// Oldest first, the last one is appended to
+    segments: VecDeque<Segment>,
+    head: File,
+    /// First record the server did not acknowledge yet
+    cursor: Position,
+    /// First record not sent yet on the current connection, at or after
+    /// `cursor`
+    sent: Position,
+    /// `seq` of the last sample of every chunk sent but not acknowledged, and
+    /// the position after it, oldest first
+    in_flight: VecDeque<(u64, Position)>,
+    /// Numbers the samples as they are stored, so they keep their number
+    /// when sent again in another session. Continues after the numbers
+    /// stored before, also when the clock went back meanwhile
+    seq: SampleSeq,
+}
+
+impl Journal {
+    /// Open the journal in `dir`, creating it if needed, and repair what a
+    /// power loss may have left behind. It is kept under `max_size` bytes by
+    /// dropping the oldest samples.
+    pub fn open(dir: &Path, max_size: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut indices = fs::read_dir(dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                name.to_str()?.strip_suffix(".seg")?.parse::<u64>().ok()
+            })
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+
+        let (mut cursor, mut last_seq) = read_cursor(dir).unwrap_or((
+            Position {
+                segment: indices.first().copied().unwrap_or(0),
+                offset: 0,
+            },
+            None,
+        ));
+
+        let mut segments = VecDeque::new();
+        for index in indices {
+            let path = segment_path(dir, index);
+            if index < cursor.segment {
+                fs::remove_file(path)?;
+                continue;
+            }
+            let (size, seq) = repair(&path)?;
+            last_seq = last_seq.max(seq);
+            segments.push_back(Segment { index, size });
+        }
+
+        match segments.iter().find(|s| s.index == cursor.segment) {
+            Some(segment) => cursor.offset = cursor.offset.min(segment.size),
+            // the segment of the cursor is gone, start at the oldest one left
+            None => {
+                cursor = Position {
+                    segment: segments.front().map_or(cursor.segment, |s| s.index),
+                    offset: 0,
+                }
+            }
+        }
+        if segments.is_empty() {
+            File::create(segment_path(dir, cursor.segment))?;
+            sync_dir(dir);
+            segments.push_back(Segment {
+                index: cursor.segment,
+                size: 0,
+            });
+        }
+
+        let head = segments.back().expect("a segment exists");
+        let head = OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir, head.index))?;
+        Ok(Journal {
+            dir: dir.to_owned(),
+            max_size,
+            segment_size: (max_size / 4).clamp(HEADER_SIZE, MAX_SEGMENT_SIZE),
+            segments,
+            head,
+            cursor,
+            sent: cursor,
+            in_flight: VecDeque::new(),
+            seq: SampleSeq::after(last_seq),
+        })
+    }
+
+    /// Number and store a sample, it survives a crash once this returns.
+    pub fn append(&mut self, sample: &mut DynamicMetrics<'_>) -> anyhow::Result<()> {
+        sample.seq = self.seq.next(sample.sample_time);
+        let payload = postcard::to_extend(sample, Vec::new())?;
+        let mut record = Vec::new();
+        record::encode(&payload, &mut record);
+        let len = record.len() as u64;
+
+        let head = self.segments.back().expect("a segment exists");
+        if head.size > 0 && head.size + len > self.segment_size {
+            self.rotate()?;
+        }
+        self.enforce_max_size(len)?;
+
+        self.head.write_all(&record)?;
+        self.head.sync_data()?;
+        self.segments.back_mut().expect("a segment exists").size += len;
+        Ok(())
+    }
+
+    /// Whether every stored sample was sent on the current connection.
+    pub fn is_empty(&self) -> bool {
+        let head = self.segments.back().expect("a segment exists");
+        self.sent
+            >= (Position {
+                segment: head.index,
+                offset: head.size,
+            })
+    }
+
+    /// Up to `max` of the oldest unsent samples and the position after them,
+    /// to [`mark_sent`](Self::mark_sent) once they are sent.
+    pub fn peek(&self, max: usize) -> anyhow::Result<(Vec<DynamicMetrics<'static>>, Position)> {
+        let mut samples = Vec::new();
+        let mut position = self.sent;
+        let first = position.segment;
+        for segment in self.segments.iter().filter(|s| s.index >= first) {
+            if samples.len() >= max {
+                break;
+            }
+            if segment.index > position.segment {
+                position = Position {
+                    segment: segment.index,
+                    offset: 0,
+                };
+            }
+            let mut file = File::open(segment_path(&self.dir, segment.index))?;
+            file.seek(SeekFrom::Start(position.offset))?;
+            let mut bytes = Vec::new();
+            file.take(segment.size - position.offset)
+                .read_to_end(&mut bytes)?;
+
+            let mut records = Records(&bytes);
+            while samples.len() < max {
+                let Some(payload) = records.next() else {
+                    break;
+                };
+                samples.push(postcard::from_bytes::<DynamicMetrics>(payload)?.into_owned());
+                position.offset += HEADER_SIZE + payload.len() as u64;
+            }
+        }
+        Ok((samples, position))
+    }
+
+    /// Mark the samples before `position`, the last of them numbered `seq`,
+    /// as sent. They stay stored until the server acknowledges them.
+    pub fn mark_sent(&mut self, seq: u64, position: Position) {
+        self.sent = position;
+        self.in_flight.push_back((seq, position));
+    }
+
+    /// The server stored the sample numbered `seq`, drop the samples sent up
+    /// to it. Acks in the middle of what was sent at once are ignored, the
+    /// ack of its last sample follows.
+    pub fn acknowledge(&mut self, seq: u64) -> anyhow::Result<()> {
+        let Some(i) = self.in_flight.iter().position(|&(s, _)| s == seq) else {
+            return Ok(());
+        };
+        let (_, position) = self.in_flight[i];
+        self.in_flight.drain(..=i);
+        // the samples may have been dropped meanwhile to keep the size
+        if position > self.cursor {
+            self.consume(position)?;
+        }
+        Ok(())
+    }
+
+    /// Send the samples not acknowledged again, on a new connection.
+    pub fn rewind(&mut self) {
+        self.sent = self.cursor;
+        self.in_flight.clear();
+    }
+
+    /// Drop the samples before `position`.
+    fn consume(&mut self, position: Position) -> anyhow::Result<()> {
+        self.cursor = position;
+        self.sent = self.sent.max(position);
+        let tmp = self.dir.join(format!("{CURSOR_FILE}.tmp"));
+        let mut file = File::create(&tmp)?;
+        // the number of the last sample survives the segments holding it
+        write!(file, "{} {}", position.segment, position.offset)?;
+        if let Some(seq) = self.seq.last() {
+            write!(file, " {seq}")?;
+        }
+        file.sync_data()?;
+        fs::rename(tmp, self.dir.join(CURSOR_FILE))?;
+
+        while self.segments.len() > 1 && self.segments[0].index < position.segment {
+            let segment = self.segments.pop_front().expect("more than one segment");
+            fs::remove_file(segment_path(&self.dir, segment.index))?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let index = self.segments.back().expect("a segment exists").index + 1;
+        self.head = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(segment_path(&self.dir, index))?;
+        sync_dir(&self.dir);
+        self.segments.push_back(Segment { index, size: 0 });
+        Ok(())
+    }
+
+    /// Drop the oldest segments until `additional` bytes fit.
+    fn enforce_max_size(&mut self, additional: u64) -> io::Result<()> {
+        while self.segments.len() > 1
+            && self.segments.iter().map(|s| s.size).sum::<u64>() + additional > self.max_size
+        {
+            let segment = self.segments.pop_front().expect("more than one segment");
+            log::warn!("Sample buffer is full, dropping the oldest samples");
+            fs::remove_file(segment_path(&self.dir, segment.index))?;
+            let oldest = self.segments.front().expect("more than one segment").index;
+            let oldest = Position {
+                segment: oldest,
+                offset: 0,
+            };
+            self.cursor = self.cursor.max(oldest);
+            self.sent = self.sent.max(oldest);
+        }
+        Ok(())
+    }
+}
+
+/// What a journal holds, for `buffer inspect`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub segments: usize,
+    /// Bytes of the segments, sent samples not deleted yet included
+    pub bytes: u64,
+    pub unsent: u64,
+    /// Sample time of the oldest and newest unsent sample
+    pub oldest: Option<UnixMillis>,
+    pub newest: Option<UnixMillis>,
+    /// Bytes of damaged records, cut off when the client opens the journal
+    pub damaged: u64,
+}
+
+/// Read the journal in `dir` without repairing or changing it, so it can be
+/// looked at while the client runs.
+pub fn inspect(dir: &Path) -> anyhow::Result<Summary> {
+    if !dir.is_dir() {
+        anyhow::bail!("{} is not a directory", dir.display());
+    }
+    let mut indices = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name();
+            name.to_str()?.strip_suffix(".seg")?.parse::<u64>().ok()
+        })
+        .collect::<Vec<_>>();
+    indices.sort_unstable();
+    let cursor = read_cursor(dir).map(|(cursor, _)| cursor);
+
+    let mut summary = Summary::default();
+    for index in indices {
+        let bytes = fs::read(segment_path(dir, index))?;
+        summary.segments += 1;
+        summary.bytes += bytes.len() as u64;
+
+        let start = match cursor {
+            Some(cursor) if index < cursor.segment => continue,
+            Some(cursor) if index == cursor.segment => (cursor.offset as usize).min(bytes.len()),
+            _ => 0,
+        };
+        let mut records = Records(&bytes[start..]);
+        for payload in records.by_ref() {
+            let sample = postcard::from_bytes::<DynamicMetrics>(payload)?;
+            summary.unsent += 1;
+            summary.oldest.get_or_insert(sample.sample_time);
+            summary.newest = Some(sample.sample_time);
+        }
+        summary.damaged += records.0.len() as u64;
+    }
+    Ok(summary)
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Segments: {} ({} bytes)", self.segments, self.bytes)?;
+        write!(f, "Unsent samples: {}", self.unsent)?;
+        let now = UnixMillis::now();
+        for (label, time) in [("Oldest", self.oldest), ("Newest", self.newest)] {
+            if let Some(time) = time {
+                write!(
+                    f,
+                    "\n{label} unsent: unix time {}, {} seconds ago",
+                    time.as_secs(),
+                    now.saturating_duration_since(time).as_secs()
+                )?;
+            }
+        }
+        if self.damaged > 0 {
+            write!(f, "\nDamaged: {} bytes", self.damaged)?;
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{index:020}.seg"))
+}
+
+/// The position of the cursor and the number of the last sample stored when
+/// it was written, missing from journals of older clients.
+fn read_cursor(dir: &Path) -> Option<(Position, Option<u64>)> {
+    let cursor = fs::read_to_string(dir.join(CURSOR_FILE)).ok()?;
+    let mut fields = cursor.split_whitespace();
+    let position = Position {
+        segment: fields.next()?.parse().ok()?,
+        offset: fields.next()?.parse().ok()?,
+    };
+    Some((position, fields.next().and_then(|seq| seq.parse().ok())))
+}
+
+/// Cut a segment after its last intact record, returns the size left and the
+/// largest sample number in it.
+fn repair(path: &Path) -> io::Result<(u64, Option<u64>)> {
+    let bytes = fs::read(path)?;
+    let mut records = Records(&bytes);
+    let mut last_seq = None;
+    for payload in records.by_ref() {
+        // `seq` is the first field of a sample
+        if let Ok((seq, _)) = postcard::take_from_bytes::<u64>(payload) {
+            last_seq = last_seq.max(Some(seq));
+        }
+    }
+    let valid = (bytes.len() - records.0.len()) as u64;
+    if valid < bytes.len() as u64 {
+        log::warn!(
+            "Discarding {} bytes of a damaged record in {}",
+            bytes.len() as u64 - valid,
+            path.display()
+        );
+        OpenOptions::new().write(true).open(path)?.set_len(valid)?;
+    }
+    Ok((valid, last_seq))
+}
+
+/// Make a created file survive a power loss, best effort.
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) {
+        dir.sync_all().ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use miniprobe_proto::{CpuReport, MemoryMetrics, NetworkMetrics, UnixMillis};
+
+    use super::*;
+
+    fn sample(sample_time: u64) -> DynamicMetrics<'static> {
+        DynamicMetrics {
+            seq: 0,
+            sample_time: UnixMillis(sample_time),
+            cpu: CpuReport::Aggregate {
+                usage: 0.0,
+                max_core: 0.0,
+            },
+            memory: MemoryMetrics {
+                total: 0,
+                used: 0,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: "eth0".into(),
+                rx_bytes: None,
+                tx_bytes: None,
+            }],
+            disks: Vec::new(),
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: None,
+            services: Vec::new(),
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        }
+    }
+
+    fn times(samples: &[DynamicMetrics<'_>]) -> Vec<u64> {
+        samples.iter().map(|s| s.sample_time.0).collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("miniprobe-journal-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn test_append_consume_reopen() {
+        let dir = temp_dir("reopen");
+        let mut journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        assert!(journal.is_empty());
+        for t in 0..5 {
+            journal.append(&mut sample(t)).unwrap();
+        }
+
+        let (samples, position) = journal.peek(2).unwrap();
+        assert_eq!(times(&samples), [0, 1]);
+        journal.mark_sent(samples[1].seq, position);
+        journal.acknowledge(samples[1].seq).unwrap();
+        // sent, but the connection was lost before the ack
+        let (samples, position) = journal.peek(1).unwrap();
+        assert_eq!(times(&samples), [2]);
+        journal.mark_sent(samples[0].seq, position);
+        drop(journal);
+
+        let journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        let (samples, _) = journal.peek(10).unwrap();
+        assert_eq!(times(&samples), [2, 3, 4]);
+        // sent again with the numbers they were stored with
+        let seqs = samples.iter().map(|s| s.seq).collect::<Vec<_>>();
+        assert_eq!(seqs, [2, 3, 4]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_acknowledge() {
+        let dir = temp_dir("ack");
+        let mut journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        for t in 0..6 {
+            journal.append(&mut sample(t)).unwrap();
+        }
+        for _ in 0..3 {
+            let (samples, position) = journal.peek(2).unwrap();
+            journal.mark_sent(samples[1].seq, position);
+        }
+        assert!(journal.is_empty());
+
+        // an ack in the middle of a chunk drops nothing
+        journal.acknowledge(2).unwrap();
+        journal.rewind();
+        assert_eq!(times(&journal.peek(10).unwrap().0), [0, 1, 2, 3, 4, 5]);
+
+        let (samples, position) = journal.peek(4).unwrap();
+        journal.mark_sent(samples[3].seq, position);
+        journal.acknowledge(3).unwrap();
+        journal.rewind();
+        assert_eq!(times(&journal.peek(10).unwrap().0), [4, 5]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_seq_after_clock_went_back() {
+        let dir = temp_dir("seq");
+        let mut journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        for t in [5000, 6000] {
+            journal.append(&mut sample(t)).unwrap();
+        }
+        let (samples, position) = journal.peek(10).unwrap();
+        journal.mark_sent(6000, position);
+        journal.acknowledge(6000).unwrap();
+        assert_eq!(
+            samples.iter().map(|s| s.seq).collect::<Vec<_>>(),
+            [5000, 6000]
+        );
+        drop(journal);
+
+        // the clock went back while the client was stopped
+        let mut journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        let mut metrics = sample(1000);
+        journal.append(&mut metrics).unwrap();
+        assert_eq!(metrics.seq, 6001);
+
+        // also when the samples are gone and only the cursor knows
+        fs::remove_file(segment_path(&dir, 0)).unwrap();
+        fs::write(dir.join(CURSOR_FILE), "1 0 7000").unwrap();
+        let mut journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        let mut metrics = sample(1000);
+        journal.append(&mut metrics).unwrap();
+        assert_eq!(metrics.seq, 7001);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let dir = temp_dir("torn");
+        let mut journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        journal.append(&mut sample(0)).unwrap();
+        journal.append(&mut sample(1)).unwrap();
+        drop(journal);
+
+        // a power loss in the middle of the last record
+        let path = segment_path(&dir, 0);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        journal.append(&mut sample(2)).unwrap();
+        let (samples, _) = journal.peek(10).unwrap();
+        assert_eq!(times(&samples), [0, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_and_cap() {
+        let dir = temp_dir("cap");
+        let record =
+            HEADER_SIZE + postcard::to_extend(&sample(0), Vec::new()).unwrap().len() as u64;
+        // four segments of two records each
+        let mut journal = Journal::open(&dir, record * 8).unwrap();
+        for t in 0..12 {
+            journal.append(&mut sample(t)).unwrap();
+        }
+        assert!(journal.segments.len() <= 4);
+
+        let (samples, position) = journal.peek(100).unwrap();
+        assert_eq!(times(&samples), [4, 5, 6, 7, 8, 9, 10, 11]);
+        journal.mark_sent(11, position);
+        assert!(journal.is_empty());
+        journal.acknowledge(11).unwrap();
+        assert_eq!(journal.segments.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_inspect() {
+        let dir = temp_dir("inspect");
+        assert!(inspect(&dir).is_err());
+
+        let mut journal = Journal::open(&dir, 1024 * 1024).unwrap();
+        for t in 10..15 {
+            journal.append(&mut sample(t)).unwrap();
+        }
+        let (_, position) = journal.peek(2).unwrap();
+        journal.mark_sent(11, position);
+        journal.acknowledge(11).unwrap();
+
+        let size = fs::metadata(segment_path(&dir, 0)).unwrap().len();
+        let summary = inspect(&dir).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                segments: 1,
+                bytes: size,
+                unsent: 3,
+                oldest: Some(UnixMillis(12)),
+                newest: Some(UnixMillis(14)),
+                damaged: 0,
+            }
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+//! Inventory of listening TCP and UDP sockets, read from `/proc` on Linux.
+//!
+//! Listing the sockets and mapping them to processes walks every file
+//! descriptor of every process, so it runs at most every
+//! [`CHECK_INTERVAL`] and the result is only sent when it changed. Other
+//! platforms report no sockets for now.
+
+use std::time::{Duration, Instant};
+
+use miniprobe_proto::ListeningSocket;
+#[cfg(any(target_os = "linux", test))]
+use miniprobe_proto::SocketProtocol;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct ListenerQuerent {
+    enabled: bool,
+    last_check: Option<Instant>,
+    last_sent: Option<Vec<ListeningSocket>>,
+}
+
+impl ListenerQuerent {
+    pub fn new(enabled: bool) -> Self {
+        ListenerQuerent {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    /// The sockets if they changed since they were last returned, or always
+    /// if `force` is set, e.g. for the first sample of a session.
+    pub fn query(&mut self, force: bool) -> Option<Vec<ListeningSocket>> {
+        if !self.enabled {
+            return None;
+        }
+        if !force
+            && self
+                .last_check
+                .is_some_and(|at| at.elapsed() < CHECK_INTERVAL)
+        {
+            return None;
+        }
+        self.last_check = Some(Instant::now());
+
+        let listeners = match collect() {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                log::warn!("Failed to list listening sockets: {e}");
+                return None;
+            }
+        };
+        if !force && self.last_sent.as_ref() == Some(&listeners) {
+            return None;
+        }
+        self.last_sent = Some(listeners.clone());
+        Some(listeners)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn collect() -> anyhow::Result<Vec<ListeningSocket>> {
+    use std::collections::BTreeSet;
+
+    let mut sockets = Vec::new();
+    for (file, protocol) in [
+        ("tcp", SocketProtocol::Tcp),
+        ("tcp6", SocketProtocol::Tcp),
+        ("udp", SocketProtocol::Udp),
+        ("udp6", SocketProtocol::Udp),
+    ] {
+        // the file is missing if the protocol is disabled
+        if let Ok(table) = std::fs::read_to_string(format!("/proc/net/{file}")) {
+            sockets.extend(parse_net_table(&table, protocol));
+        }
+    }
+
+    let processes = socket_processes();
+    let listeners = sockets
+        .into_iter()
+        .map(|(mut socket, inode)| {
+            socket.process = processes.get(&inode).cloned();
+            socket
+        })
+        // forked workers share their sockets
+        .collect::<BTreeSet<_>>();
+    Ok(listeners.into_iter().collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn collect() -> anyhow::Result<Vec<ListeningSocket>> {
+    Ok(Vec::new())
+}
+
+/// Process names by socket inode, for the processes we may inspect.
+#[cfg(target_os = "linux")]
+fn socket_processes() -> std::collections::HashMap<u64, String> {
+    let mut processes = std::collections::HashMap::new();
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return processes;
+    };
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        let Ok(fds) = std::fs::read_dir(dir.join("fd")) else {
+            continue;
+        };
+        let Some(name) = std::fs::read_to_string(dir.join("comm"))
+            .ok()
+            .map(|comm| comm.trim().to_owned())
+        else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let inode = std::fs::read_link(fd.path()).ok().and_then(|link| {
+                link.to_str()?
+                    .strip_prefix("socket:[")?
+                    .strip_suffix(']')?
+                    .parse::<u64>()
+                    .ok()
+            });
+            if let Some(inode) = inode {
+                processes.entry(inode).or_insert_with(|| name.clone());
+            }
+        }
+    }
+    processes
+}
+
+/// Listening sockets and their inodes from `/proc/net/{tcp,udp}[6]`, e.g.
+/// `0: 0100007F:1F90 00000000:0000 0A ... 12345 ...`. TCP sockets listen in
+/// state `0A`, UDP sockets count if they are not connected.
+#[cfg(any(target_os = "linux", test))]
+fn parse_net_table(table: &str, protocol: SocketProtocol) -> Vec<(ListeningSocket, u64)> {
+    const TCP_LISTEN: &str = "0A";
+
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (local, remote, state, inode) = (
+                fields.get(1)?,
+                fields.get(2)?,
+                fields.get(3)?,
+                fields.get(9)?,
+            );
+            let listening = match protocol {
+                SocketProtocol::Tcp => *state == TCP_LISTEN,
+                SocketProtocol::Udp => parse_address(remote)?.1 == 0,
+            };
+            if !listening {
+                return None;
+            }
+            let (address, port) = parse_address(local)?;
+            let socket = ListeningSocket {
+                protocol,
+                address,
+                port,
+                process: None,
+            };
+            Some((socket, inode.parse().ok()?))
+        })
+        .collect()
+}
+
+/// `ADDR:PORT` in hex, the address as 32 bit words in host byte order.
+#[cfg(any(target_os = "linux", test))]
+fn parse_address(s: &str) -> Option<(std::net::IpAddr, u16)> {
+    let (address, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for i in (0..address.len()).step_by(8) {
+        let word = u32::from_str_radix(address.get(i..i + 8)?, 16).ok()?;
+        bytes.extend(word.to_ne_bytes());
+    }
+    let address = match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok()?.into(),
+        16 => <[u8; 16]>::try_from(bytes).ok()?.into(),
+        _ => return None,
+    };
+    Some((address, port))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_parse_address() {
+        assert_eq!(
+            parse_address("0100007F:1F90"),
+            Some((IpAddr::V4(Ipv4Addr::LOCALHOST), 8080))
+        );
+        assert_eq!(
+            parse_address("00000000000000000000000001000000:0035"),
+            Some((IpAddr::V6(Ipv6Addr::LOCALHOST), 53))
+        );
+        assert_eq!(parse_address("0100007F"), None);
+        assert_eq!(parse_address("01007F:0035"), None);
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_parse_net_table() {
+        let tcp = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 662 1 0 100 0 0 10 0
+   1: 0100007F:1F90 0100007F:C350 01 00000000:00000000 00:00000000 00000000  1000        0 926 1 0 20 4 30 10 -1
+";
+        let sockets = parse_net_table(tcp, SocketProtocol::Tcp);
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(sockets[0].0.port, 22);
+        assert_eq!(sockets[0].1, 662);
+
+        let udp = "\
+   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  100: 3500007F:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000   101        0 1234 2 0 0
+  101: 0100007F:A1B2 0100007F:0035 01 00000000:00000000 00:00000000 00000000  1000        0 1235 2 0 0
+";
+        let sockets = parse_net_table(udp, SocketProtocol::Udp);
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(
+            sockets[0].0.address,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 53))
+        );
+    }
+}
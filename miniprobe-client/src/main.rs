@@ -1,130 +1,259 @@
 #![forbid(unsafe_code)]
 
-use std::time::Duration;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use argh::FromArgs;
-use miniprobe_proto::msg::CreateSessionResp;
+use cli::{BufferSubcommand, CollectOptions, Command, RunCommand, ServiceSubcommand};
+use miniprobe_proto::msg::{CreateSessionResp, ServerCapabilities};
 use simple_logger::SimpleLogger;
 use tokio::time::sleep;
 
+mod actions;
+mod addresses;
+mod battery;
+#[cfg(any(target_os = "freebsd", target_os = "openbsd", test))]
+mod bsd;
+mod cli;
+mod clock;
+mod collector;
 mod egress;
+mod fds;
 mod http_util;
+mod identity;
+mod journal;
+mod listeners;
+mod netns;
+mod offline;
 mod query;
+mod reconnect;
+mod sensors;
+mod service;
+mod services;
 mod session;
-
-#[derive(FromArgs, Debug)]
-#[argh(description = "A lightweight system status probe client.")]
-struct ClientConfig {
-    #[argh(positional, description = "authentication token")]
-    pub token: String,
-    #[argh(
-        option,
-        short = 'a',
-        default = "\"127.0.0.1:8000\".to_string()",
-        description = "server address to connect to"
-    )]
-    pub server_addr: String,
-    #[argh(
-        switch,
-        short = 't',
-        description = "use TLS to connect to server (https/wss instead of http/ws)"
-    )]
-    pub tls: bool,
-    #[argh(
-        switch,
-        short = '6',
-        description = "prefer IPv6 when resolving server address"
-    )]
-    pub prefer_ipv6: bool,
-    #[argh(
-        option,
-        default = "1",
-        description = "minimum interval between two connection retries in seconds"
-    )]
-    pub retry_minimum_interval: u64, // in seconds
-    #[argh(
-        option,
-        default = "300",
-        description = "maximum interval between two connection retries in seconds"
-    )]
-    pub retry_maximum_interval: u64, // in seconds
-}
+mod simulate;
+mod srv;
+mod supervisor;
+mod timed;
+mod urgent;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     SimpleLogger::new().env().init()?;
 
-    let cfg: ClientConfig = argh::from_env();
+    match cli::from_env().command {
+        Command::Run(cfg) => run(cfg).await,
+        Command::Once(cmd) => simulate::once(&cmd).await,
+        Command::Simulate(cmd) => simulate::simulate(&cmd).await,
+        Command::Service(cmd) => match cmd.command {
+            ServiceSubcommand::Install(cmd) => service::install(&cmd),
+        },
+        Command::Buffer(cmd) => match cmd.command {
+            BufferSubcommand::Inspect(cmd) => {
+                println!("{}", journal::inspect(&cmd.dir)?);
+                Ok(())
+            }
+        },
+    }
+}
+
+async fn run(cfg: RunCommand) -> anyhow::Result<()> {
     log::debug!("Client config: {cfg:#?}");
+    if let Some(name) = &cfg.netns {
+        netns::enter(name)?;
+    }
+
+    supervisor::install_panic_hook();
+    let watchdog =
+        (cfg.watchdog > 0).then(|| supervisor::spawn_watchdog(Duration::from_secs(cfg.watchdog)));
 
-    let mut querent = query::MetricsQuerent::try_new(None)?;
-    let mut reconnect_timer = ReconnectTimer::new(
+    let mut collector = new_collector(&cfg.collect(), watchdog.clone())?;
+    if cfg.offline {
+        let Some(output) = &cfg.output else {
+            anyhow::bail!("--offline needs --output");
+        };
+        let mut files = offline::OutputFiles::open(
+            output,
+            cfg.output_format,
+            cfg.output_file_size * 1024 * 1024,
+        )?;
+        collector.set_cpu_report(cfg.collect().cpu_report()).await;
+        return offline::run(
+            &collector,
+            &mut files,
+            Duration::from_secs(cfg.scrape_interval),
+        )
+        .await;
+    }
+    // units written by `service install` pass it in the environment
+    let token = cfg
+        .token
+        .clone()
+        .or_else(|| std::env::var(cli::TOKEN_ENV).ok());
+    let Some(token) = &token else {
+        anyhow::bail!("an authentication token is required unless --offline is set");
+    };
+    if cfg.server_srv.is_some() && cfg.socks5.is_some() {
+        anyhow::bail!(
+            "--server-srv can not be used with --socks5, its lookup would bypass the proxy"
+        );
+    }
+    let compression = egress::offered_compressions(cfg.compression.as_deref())?;
+    let actions = if cfg.actions.is_empty() {
+        None
+    } else {
+        Some(Arc::new(actions::Actions::new(
+            cfg.actions.clone(),
+            Duration::from_secs(cfg.action_timeout),
+        )?))
+    };
+    let mut journal = cfg
+        .buffer_dir
+        .as_deref()
+        .map(|dir| journal::Journal::open(dir, cfg.buffer_size * 1024 * 1024))
+        .transpose()?;
+    // numbers of samples sent without a journal, which keeps its own
+    let mut sample_seq = egress::SampleSeq::default();
+    // samples are buffered while reconnecting once the interval is known
+    let mut last_scrape_interval = None;
+    let mut next_buffered_scrape = None;
+    let mut reconnect = reconnect::Reconnect::new(
         Duration::from_secs(cfg.retry_minimum_interval),
         Duration::from_secs(cfg.retry_maximum_interval),
+        cfg.retry_jitter,
     );
 
     loop {
-        let res: anyhow::Result<()> = async {
+        let started = Instant::now();
+        let res = supervisor::catch_panic("metrics egress", async {
+            let (server_addr, capabilities) = reach_server(&cfg).await?;
+            log::debug!("Server capabilities: {capabilities:?}");
+            let batch_policy = session::negotiate(
+                capabilities.as_ref(),
+                cfg.send_interval.map(Duration::from_secs),
+            )?;
+
+            let session = session::create_session(
+                token,
+                collector.query_static().await,
+                &server_addr,
+                cfg.tls,
+                cfg.ip_version,
+                cfg.socks5.as_deref(),
+            )
+            .await?;
+            let batch_policy = batch_policy.map(|policy| policy.limit(&session));
             let CreateSessionResp {
                 session_token,
                 scrape_interval,
-            } = session::create_session(&cfg.token, &cfg.server_addr, cfg.tls, cfg.prefer_ipv6)
-                .await?;
-            reconnect_timer.reset();
+                cpu_report,
+                heartbeat_interval,
+                ..
+            } = session;
+            reconnect.connected();
+            last_scrape_interval = Some(Duration::from_secs(scrape_interval));
+            next_buffered_scrape = None;
+
+            collector
+                .set_cpu_report(cpu_report.unwrap_or(cfg.collect().cpu_report()))
+                .await;
 
             egress::metrics_egress(
-                &mut querent,
+                &collector,
+                journal.as_mut(),
+                &mut sample_seq,
                 Duration::from_secs(scrape_interval),
+                batch_policy,
+                Duration::from_secs(heartbeat_interval.max(1)),
+                &compression,
+                cfg.delta_counters,
+                cfg.coarse_precision,
+                actions.clone(),
                 &session_token,
-                &cfg.server_addr,
+                &server_addr,
                 cfg.tls,
-                cfg.prefer_ipv6,
+                cfg.ip_version,
+                cfg.socks5.as_deref(),
             )
             .await?;
-            Ok(())
-        }
+            anyhow::Ok(())
+        })
         .await;
 
-        if let Err(e) = res {
-            log::warn!("Error occurred: {e}");
-            log::info!(
-                "Reconnecting in {} seconds...",
-                reconnect_timer.interval().as_secs()
-            );
-            reconnect_timer.wait().await;
-        } else {
-            return Ok(()); // means graceful shutdown
+        match res {
+            Ok(Ok(())) => return Ok(()), // means graceful shutdown
+            Ok(Err(e)) => {
+                let delay = reconnect.failed(&e);
+                log::info!("Reconnecting in {:.1} seconds...", delay.as_secs_f32());
+                match (journal.as_mut(), last_scrape_interval) {
+                    (Some(journal), Some(scrape_interval)) => {
+                        let next_scrape = next_buffered_scrape
+                            .get_or_insert_with(|| tokio::time::Instant::now() + scrape_interval);
+                        egress::collect_while(
+                            &collector,
+                            journal,
+                            scrape_interval,
+                            next_scrape,
+                            sleep(delay),
+                        )
+                        .await
+                    }
+                    _ => sleep(delay).await,
+                }
+            }
+            Err(panicked) => {
+                // the collector may be left half updated, start over with a fresh one
+                collector = new_collector(&cfg.collect(), watchdog.clone())?;
+                let delay = reconnect.panicked(started.elapsed());
+                log::error!(
+                    "{panicked}, restarting in {:.1} seconds...",
+                    delay.as_secs_f32()
+                );
+                sleep(delay).await;
+            }
         }
     }
 }
 
-struct ReconnectTimer {
-    minimal_interval: Duration,
-    maximal_interval: Duration,
-    curr_interval: Duration,
-}
-
-impl ReconnectTimer {
-    fn new(minimal_interval: Duration, maximal_interval: Duration) -> Self {
-        debug_assert!(minimal_interval <= maximal_interval);
-
-        Self {
-            minimal_interval,
-            maximal_interval,
-            curr_interval: minimal_interval,
+/// The server to connect to and what it advertises. With `--server-srv` its
+/// SRV records are looked up and their servers tried in order until one
+/// answers.
+async fn reach_server(cfg: &RunCommand) -> anyhow::Result<(String, Option<ServerCapabilities>)> {
+    let servers = match &cfg.server_srv {
+        Some(name) => srv::lookup(name).await?,
+        None => vec![cfg.server_addr.clone()],
+    };
+    let mut servers = servers.into_iter().peekable();
+    while let Some(server_addr) = servers.next() {
+        let res = session::server_capabilities(
+            &server_addr,
+            cfg.tls,
+            cfg.ip_version,
+            cfg.socks5.as_deref(),
+        )
+        .await;
+        match res {
+            Ok(capabilities) => return Ok((server_addr, capabilities)),
+            Err(e) if servers.peek().is_some() => {
+                log::warn!("Failed to reach server {server_addr}, trying the next one: {e:#}");
+            }
+            Err(e) => return Err(e),
         }
     }
+    unreachable!("there is a server to try")
+}
 
-    async fn wait(&mut self) {
-        sleep(self.curr_interval).await;
-        self.curr_interval = (self.curr_interval * 2).min(self.maximal_interval);
-    }
-
-    fn reset(&mut self) {
-        self.curr_interval = self.minimal_interval;
-    }
-
-    fn interval(&self) -> Duration {
-        self.curr_interval
-    }
+fn new_collector(
+    options: &CollectOptions<'_>,
+    watchdog: Option<supervisor::Watchdog>,
+) -> anyhow::Result<collector::Collector> {
+    let mut querent = query::MetricsQuerent::new();
+    querent.set_interfaces(options.interfaces);
+    querent.set_battery(options.battery);
+    querent.set_services(options.units);
+    querent.set_listeners(options.listeners);
+    querent.set_urgent_thresholds(options.urgent);
+    querent.set_collect_timeout(Duration::from_secs(options.collect_timeout));
+    collector::Collector::spawn(querent, watchdog)
 }
@@ -0,0 +1,66 @@
+//! `--netns`, running the client in a named network namespace on Linux,
+//! e.g. the management VRF of a router.
+//!
+//! The client runs itself again through `ip netns exec`, which besides
+//! entering the namespace mounts its own `/sys` and `/etc/netns/NAME`
+//! files, so the interfaces collected and the resolver and routes the server
+//! is reached with are all those of the namespace. Its arguments are kept,
+//! the second client finds itself in the namespace already and goes on.
+
+/// Run the client in the namespace `name` unless it already is, only
+/// returns if it is or the client could not be started again.
+#[cfg(target_os = "linux")]
+pub fn enter(name: &str) -> anyhow::Result<()> {
+    use std::os::unix::{fs::MetadataExt, process::CommandExt};
+
+    use anyhow::Context;
+
+    validate_name(name)?;
+    let path = format!("/run/netns/{name}");
+    let namespace = std::fs::metadata(&path)
+        .with_context(|| format!("network namespace {name} not found at {path}"))?;
+    let current = std::fs::metadata("/proc/self/ns/net")?;
+    if (namespace.dev(), namespace.ino()) == (current.dev(), current.ino()) {
+        log::info!("Running in network namespace {name}");
+        return Ok(());
+    }
+
+    let exe = std::env::current_exe()?;
+    log::debug!("Entering network namespace {name}");
+    let e = std::process::Command::new("ip")
+        .args(["netns", "exec", name])
+        .arg(exe)
+        .args(std::env::args_os().skip(1))
+        .exec();
+    Err(anyhow::Error::new(e).context(format!(
+        "failed to run `ip netns exec {name}`, is iproute2 installed and the client run as root?"
+    )))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enter(_name: &str) -> anyhow::Result<()> {
+    anyhow::bail!("--netns is only supported on Linux")
+}
+
+/// Names are file names in `/run/netns`, anything else would look elsewhere.
+#[cfg(any(target_os = "linux", test))]
+fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        anyhow::bail!("invalid network namespace name {name:?}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("mgmt").is_ok());
+        assert!(validate_name("vrf-mgmt.1").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("..").is_err());
+        assert!(validate_name("../../proc/1/ns/net").is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Offline mode, enabled with `--offline`, for machines that can not reach a
+//! server.
+//!
+//! Samples are appended to files `samples-<unix millis>.<format>` in the
+//! output directory, a new file is started once one would exceed its size.
+//! `jsonl` files hold one JSON sample per line, `postcard` files postcard
+//! samples framed by [`miniprobe_proto::record`]. The system information is
+//! written to `system.json` on start. The files are imported into a server
+//! later on.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use log::{info, warn};
+use miniprobe_proto::{DynamicMetrics, record};
+use tokio::time::{Instant, sleep_until};
+
+use crate::collector::Collector;
+
+const SYSTEM_FILE: &str = "system.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jsonl,
+    Postcard,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Postcard => "postcard",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "postcard" => Ok(OutputFormat::Postcard),
+            _ => Err(format!(
+                "invalid output format '{s}', expected jsonl or postcard"
+            )),
+        }
+    }
+}
+
+/// Rotating files samples are written to.
+#[derive(Debug)]
+pub struct OutputFiles {
+    dir: PathBuf,
+    format: OutputFormat,
+    max_file_size: u64,
+    /// The file appended to and its size
+    current: Option<(File, u64)>,
+}
+
+impl OutputFiles {
+    pub fn open(dir: &Path, format: OutputFormat, max_file_size: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(OutputFiles {
+            dir: dir.to_owned(),
+            format,
+            max_file_size,
+            current: None,
+        })
+    }
+
+    /// Store a sample, it survives a crash once this returns.
+    pub fn write(&mut self, sample: &DynamicMetrics<'_>) -> anyhow::Result<()> {
+        let bytes = match self.format {
+            OutputFormat::Jsonl => {
+                let mut line = serde_json::to_vec(sample)?;
+                line.push(b'\n');
+                line
+            }
+            OutputFormat::Postcard => {
+                let mut record = Vec::new();
+                record::encode(&postcard::to_extend(sample, Vec::new())?, &mut record);
+                record
+            }
+        };
+        let len = bytes.len() as u64;
+
+        if let Some((_, size)) = &self.current
+            && *size > 0
+            && size + len > self.max_file_size
+        {
+            self.current = None;
+        }
+        let (file, size) = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let path = self.dir.join(format!(
+                    "samples-{}.{}",
+                    sample.sample_time.0,
+                    self.format.extension()
+                ));
+                info!("Writing samples to {}", path.display());
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let size = file.metadata()?.len();
+                self.current.insert((file, size))
+            }
+        };
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        *size += len;
+        Ok(())
+    }
+}
+
+/// Collect samples every `scrape_interval` into `files` until ctrl-c.
+pub async fn run(
+    collector: &Collector,
+    files: &mut OutputFiles,
+    scrape_interval: Duration,
+) -> anyhow::Result<()> {
+    fs::write(
+        files.dir.join(SYSTEM_FILE),
+        serde_json::to_vec_pretty(&collector.query_static().await)?,
+    )?;
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut seq = 0;
+    let mut next_scrape = Instant::now();
+    loop {
+        let metrics = collector.query_dynamic(seq).await;
+        seq += 1;
+        if let Err(e) = files.write(&metrics) {
+            warn!("Failed to write sample: {e}");
+        }
+
+        next_scrape += scrape_interval;
+        tokio::select! {
+            _ = &mut ctrl_c => return Ok(()),
+            _ = sleep_until(next_scrape) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use miniprobe_proto::{CpuReport, MemoryMetrics, NetworkMetrics, UnixMillis, record::Records};
+
+    use super::*;
+
+    fn sample(sample_time: u64) -> DynamicMetrics<'static> {
+        DynamicMetrics {
+            seq: sample_time,
+            sample_time: UnixMillis(sample_time),
+            cpu: CpuReport::Aggregate {
+                usage: 0.0,
+                max_core: 0.0,
+            },
+            memory: MemoryMetrics {
+                total: 0,
+                used: 0,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: "eth0".into(),
+                rx_bytes: None,
+                tx_bytes: None,
+            }],
+            disks: Vec::new(),
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: None,
+            services: Vec::new(),
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("miniprobe-offline-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    /// Files in the directory by name with their content.
+    fn files(dir: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.file_name().into_string().unwrap(),
+                    fs::read(entry.path()).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_jsonl_rotation() {
+        let dir = temp_dir("jsonl");
+        let line = serde_json::to_vec(&sample(1000)).unwrap().len() as u64 + 1;
+        // two samples per file
+        let mut output = OutputFiles::open(&dir, OutputFormat::Jsonl, line * 2).unwrap();
+        for t in [1000, 2000, 3000] {
+            output.write(&sample(t)).unwrap();
+        }
+
+        let files = files(&dir);
+        let names = files
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["samples-1000.jsonl", "samples-3000.jsonl"]);
+        let lines = String::from_utf8(files[0].1.clone()).unwrap();
+        let times = lines
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<DynamicMetrics>(line)
+                    .unwrap()
+                    .sample_time
+                    .0
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(times, [1000, 2000]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_postcard_records() {
+        let dir = temp_dir("postcard");
+        let mut output = OutputFiles::open(&dir, OutputFormat::Postcard, 1024 * 1024).unwrap();
+        output.write(&sample(1000)).unwrap();
+        output.write(&sample(2000)).unwrap();
+
+        let files = files(&dir);
+        assert_eq!(files.len(), 1);
+        let times = Records(&files[0].1)
+            .map(|payload| {
+                postcard::from_bytes::<DynamicMetrics>(payload)
+                    .unwrap()
+                    .sample_time
+                    .0
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(times, [1000, 2000]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
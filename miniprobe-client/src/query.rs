@@ -1,128 +1,761 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use miniprobe_proto::{
-    CpuMetrics, DynamicMetrics, MemoryMetrics, NetworkMetrics, StaticMetrics, SystemInfo,
-};
-
-#[derive(Debug)]
-pub struct MetricsQuerent {
-    system: sysinfo::System,
-    net_interface: netdev::Interface,
-}
-
-impl MetricsQuerent {
-    pub fn try_new(if_name: Option<&str>) -> anyhow::Result<Self> {
-        let system = sysinfo::System::new_all();
-        let net_interface = match if_name {
-            Some(name) => {
-                let interface_list = netdev::get_interfaces();
-                interface_list
-                    .into_iter()
-                    .find(|iface| iface.name == name)
-                    .ok_or_else(|| anyhow::anyhow!("Network interface '{}' not found", name))?
-            }
-            None => netdev::get_default_interface()
-                .map_err(|e| anyhow::anyhow!("Unable to open default interface: {}", e))?,
-        };
-        Ok(Self {
-            system,
-            net_interface,
-        })
-    }
-
-    fn query_cpus(&mut self) -> Vec<CpuMetrics> {
-        self.system.refresh_cpu_all();
-        let usages = self.system.cpus().iter().map(|cpu| cpu.cpu_usage());
-        usages.map(|usage| CpuMetrics { usage }).collect()
-    }
-
-    fn query_memory(&mut self) -> MemoryMetrics {
-        self.system.refresh_memory();
-        MemoryMetrics {
-            total: self.system.total_memory(),
-            used: self.system.used_memory(),
-            swap_total: self.system.total_swap(),
-            swap_used: self.system.used_swap(),
-        }
-    }
-
-    fn query_network_status(&mut self) -> NetworkMetrics {
-        let _ = self.net_interface.update_stats();
-        NetworkMetrics {
-            ifname: self.net_interface.name.clone(),
-            rx_bytes: self
-                .net_interface
-                .stats
-                .as_ref()
-                .map(|stats| stats.rx_bytes),
-            tx_bytes: self
-                .net_interface
-                .stats
-                .as_ref()
-                .map(|stats| stats.tx_bytes),
-        }
-    }
-
-    pub fn query_dynamic(&mut self) -> DynamicMetrics {
-        DynamicMetrics {
-            sample_time: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            cpu: self.query_cpus(),
-            memory: self.query_memory(),
-            network: self.query_network_status(),
-        }
-    }
-
-    pub fn query_static() -> StaticMetrics {
-        let system_status = SystemInfo {
-            system_name: sysinfo::System::name(),
-            kernel_version: sysinfo::System::kernel_version(),
-            os_version: sysinfo::System::os_version(),
-            host_name: sysinfo::System::host_name(),
-            cpu_arch: sysinfo::System::cpu_arch(),
-        };
-        StaticMetrics {
-            system: system_status,
-        }
-    }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn test_query_cpus() {
-        let mut querent = MetricsQuerent::try_new(None).expect("Failed to create querent");
-        let _ = querent.query_cpus();
-        std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
-        let cpu_status = querent.query_cpus();
-
-        println!("{:?}", cpu_status);
-    }
-
-    #[test]
-    fn test_query_memory() {
-        let mut querent = MetricsQuerent::try_new(None).expect("Failed to create querent");
-        let memory_status = querent.query_memory();
-
-        println!("{:?}", memory_status);
-    }
-
-    #[test]
-    fn test_query_network_status() {
-        let mut querent = MetricsQuerent::try_new(None).expect("Failed to create querent");
-        let network_status = querent.query_network_status();
-
-        println!("{:?}", network_status);
-    }
-
-    #[test]
-    fn test_query_static() {
-        let static_status = MetricsQuerent::query_static();
-
-        println!("{:?}", static_status);
-    }
-}
+use std::time::{Duration, Instant};
+
+use miniprobe_proto::{
+    Capabilities, CpuMetrics, CpuReport, CpuReportPolicy, DiskMetrics, DynamicMetrics,
+    MemoryMetrics, NetworkMetrics, ProbeSelfMetrics, ProcessMetrics, Section, SensorMetrics,
+    StaticMetrics, SystemInfo, UnixMillis,
+};
+use sysinfo::{DiskRefreshKind, ProcessRefreshKind, ProcessStatus, ProcessesToUpdate};
+
+use crate::{
+    addresses::AddressQuerent,
+    battery,
+    clock::ClockQuerent,
+    fds, identity,
+    listeners::ListenerQuerent,
+    sensors::SensorFallback,
+    services::ServiceQuerent,
+    timed::Timed,
+    urgent::{UrgentThreshold, UrgentWatch},
+};
+
+/// Substrings of sensor labels belonging to the CPU, e.g. `coretemp Package id 0`
+/// or `k10temp Tctl`
+const CPU_SENSOR_LABELS: &[&str] = &["cpu", "core", "package", "tctl", "tdie", "soc"];
+
+/// Default of how long a collector may take before its section is missing
+const DEFAULT_COLLECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the network interfaces are listed again
+const INTERFACE_LIST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Collects samples, every section on its own with a timeout, see `timed`.
+#[derive(Debug)]
+pub struct MetricsQuerent {
+    /// CPU, memory and the probe process
+    system: Timed<SystemQuerent>,
+    network: Timed<NetworkQuerent>,
+    disks: Timed<DiskQuerent>,
+    sensors: Timed<SensorQuerent>,
+    processes: Timed<ProcessQuerent>,
+    fds: Timed<()>,
+    clock: Timed<ClockQuerent>,
+    battery: Timed<()>,
+    /// Collect `BatteryMetrics`, off by default
+    battery_enabled: bool,
+    services: ServiceQuerent,
+    listeners: Timed<ListenerQuerent>,
+    /// Listeners were missing from a sample, the next one is a full inventory
+    listeners_missed: bool,
+    addresses: Timed<AddressQuerent>,
+    /// Addresses were missing from a sample, the next one sends them anyway
+    addresses_missed: bool,
+    urgent: UrgentWatch,
+    cpu_report: CpuReportPolicy,
+    collect_timeout: Duration,
+}
+
+impl MetricsQuerent {
+    pub fn new() -> Self {
+        Self {
+            system: Timed::new("system", SystemQuerent::new()),
+            network: Timed::new("network", NetworkQuerent::new(&[])),
+            disks: Timed::new("disks", DiskQuerent::new()),
+            sensors: Timed::new("sensors", SensorQuerent::new()),
+            processes: Timed::new("processes", ProcessQuerent::default()),
+            fds: Timed::new("fds", ()),
+            clock: Timed::new("clock", ClockQuerent::default()),
+            battery: Timed::new("battery", ()),
+            battery_enabled: false,
+            services: ServiceQuerent::default(),
+            listeners: Timed::new("listeners", ListenerQuerent::default()),
+            listeners_missed: false,
+            addresses: Timed::new("addresses", AddressQuerent::default()),
+            addresses_missed: false,
+            urgent: UrgentWatch::default(),
+            cpu_report: CpuReportPolicy::default(),
+            collect_timeout: DEFAULT_COLLECT_TIMEOUT,
+        }
+    }
+
+    /// Only report the interfaces `names`, the physical ones if empty.
+    pub fn set_interfaces(&mut self, names: &[String]) {
+        self.network = Timed::new("network", NetworkQuerent::new(names));
+    }
+
+    pub fn set_cpu_report(&mut self, policy: CpuReportPolicy) {
+        self.cpu_report = policy;
+    }
+
+    pub fn set_battery(&mut self, enabled: bool) {
+        self.battery_enabled = enabled;
+    }
+
+    pub fn set_services(&mut self, units: &[String]) {
+        self.services = ServiceQuerent::new(units);
+    }
+
+    pub fn set_listeners(&mut self, enabled: bool) {
+        self.listeners = Timed::new("listeners", ListenerQuerent::new(enabled));
+    }
+
+    pub fn set_urgent_thresholds(&mut self, thresholds: &[UrgentThreshold]) {
+        self.urgent = UrgentWatch::new(thresholds);
+    }
+
+    pub fn set_collect_timeout(&mut self, timeout: Duration) {
+        self.collect_timeout = timeout;
+    }
+
+    /// A sample with every collector run at once. Sections whose collector
+    /// misses the timeout are listed in `missing_sections`.
+    pub async fn query_dynamic(&mut self, seq: u64) -> DynamicMetrics<'static> {
+        let started = Instant::now();
+        let (timeout, cpu_report) = (self.collect_timeout, self.cpu_report);
+        // a new session starts with a full inventory
+        let full_inventory = seq == 0 || self.listeners_missed;
+        let all_addresses = seq == 0 || self.addresses_missed;
+        let (
+            system,
+            network,
+            disks,
+            sensors,
+            processes,
+            fds,
+            clock,
+            battery,
+            services,
+            listeners,
+            addresses,
+        ) = tokio::join!(
+            self.system.run(timeout, move |system| {
+                (
+                    system.query_cpus(cpu_report),
+                    system.query_memory(),
+                    system.query_probe(),
+                )
+            }),
+            self.network.run(timeout, NetworkQuerent::query),
+            self.disks.run(timeout, DiskQuerent::query),
+            self.sensors.run(timeout, SensorQuerent::query),
+            self.processes.run(timeout, ProcessQuerent::query),
+            self.fds.run(timeout, |_| fds::query()),
+            self.clock.run(timeout, ClockQuerent::query),
+            async {
+                if !self.battery_enabled {
+                    return Some(None);
+                }
+                self.battery.run(timeout, |_| battery::query()).await
+            },
+            tokio::time::timeout(timeout, self.services.query()),
+            self.listeners
+                .run(timeout, move |listeners| listeners.query(full_inventory)),
+            self.addresses
+                .run(timeout, move |addresses| addresses.query(all_addresses)),
+        );
+
+        let mut missing_sections = Vec::new();
+        let (cpu, memory, (probe_cpu, probe_rss)) = system.unwrap_or_else(|| {
+            missing_sections.extend([Section::Cpu, Section::Memory]);
+            (
+                CpuReport::PerCore(Vec::new()),
+                MemoryMetrics {
+                    total: 0,
+                    used: 0,
+                    swap_total: 0,
+                    swap_used: 0,
+                },
+                (None, None),
+            )
+        });
+        let networks = network.unwrap_or_else(|| {
+            missing_sections.push(Section::Network);
+            Vec::new()
+        });
+        let disks = disks.unwrap_or_else(|| {
+            missing_sections.push(Section::Disks);
+            Vec::new()
+        });
+        let sensors = sensors.unwrap_or_else(|| {
+            missing_sections.push(Section::Sensors);
+            SensorMetrics::default()
+        });
+        let processes = processes.unwrap_or_else(|| {
+            missing_sections.push(Section::Processes);
+            None
+        });
+        let fds = fds.unwrap_or_else(|| {
+            missing_sections.push(Section::Fds);
+            None
+        });
+        let clock = clock.unwrap_or_else(|| {
+            missing_sections.push(Section::Clock);
+            None
+        });
+        let battery = battery.unwrap_or_else(|| {
+            missing_sections.push(Section::Battery);
+            None
+        });
+        let services = services.unwrap_or_else(|_| {
+            log::warn!("Querying services did not finish in {timeout:?}");
+            missing_sections.push(Section::Services);
+            Vec::new()
+        });
+        self.listeners_missed = listeners.is_none();
+        let listeners = listeners.unwrap_or_else(|| {
+            missing_sections.push(Section::Listeners);
+            None
+        });
+        self.addresses_missed = addresses.is_none();
+        let addresses = addresses.unwrap_or_else(|| {
+            missing_sections.push(Section::Addresses);
+            None
+        });
+
+        let mut metrics = DynamicMetrics {
+            seq,
+            sample_time: UnixMillis::now(),
+            cpu,
+            memory,
+            networks,
+            disks,
+            sensors,
+            probe: ProbeSelfMetrics {
+                collection_time: started.elapsed().as_micros() as u64,
+                cpu_usage: probe_cpu,
+                rss: probe_rss,
+            },
+            processes,
+            fds,
+            clock,
+            battery,
+            services,
+            listeners,
+            addresses,
+            urgent: false,
+            missing_sections,
+        };
+        metrics.urgent = self.urgent.check(&metrics);
+        metrics
+    }
+
+    /// Probe every collector once to see which work on this platform, those
+    /// that do not finish in time count as not working.
+    pub async fn capabilities(&mut self) -> Capabilities {
+        let timeout = self.collect_timeout;
+        let system = self
+            .system
+            .run(timeout, |system| (system.has_cpus(), system.query_memory()))
+            .await;
+        let network = self.network.run(timeout, NetworkQuerent::query).await;
+        let sensors = self
+            .sensors
+            .run(timeout, SensorQuerent::query)
+            .await
+            .unwrap_or_default();
+        let battery = match self.battery_enabled {
+            true => self
+                .battery
+                .run(timeout, |_| battery::query())
+                .await
+                .flatten(),
+            false => None,
+        };
+        Capabilities {
+            tls: cfg!(feature = "native-tls"),
+            cpu: system.as_ref().is_some_and(|(cpu, _)| *cpu),
+            memory: system.as_ref().is_some_and(|(_, memory)| memory.total > 0),
+            swap: system
+                .as_ref()
+                .is_some_and(|(_, memory)| memory.swap_total > 0),
+            network: network.is_some_and(|networks| {
+                networks
+                    .iter()
+                    .any(|network| network.rx_bytes.is_some() && network.tx_bytes.is_some())
+            }),
+            cpu_temperature: sensors.cpu_temperature.is_some(),
+            cpu_frequency: sensors.cpu_frequency.is_some(),
+            battery: battery.is_some(),
+        }
+    }
+
+    pub async fn query_static(&mut self) -> StaticMetrics {
+        #[allow(unused_mut)]
+        let mut system_status = SystemInfo {
+            system_name: sysinfo::System::name(),
+            kernel_version: sysinfo::System::kernel_version(),
+            os_version: sysinfo::System::os_version(),
+            host_name: sysinfo::System::host_name(),
+            cpu_arch: sysinfo::System::cpu_arch(),
+            // 0 where sysinfo can not tell
+            boot_time: Some(sysinfo::System::boot_time())
+                .filter(|&secs| secs > 0)
+                .map(|secs| UnixMillis(secs * 1000)),
+            boot_id: identity::boot_id(),
+            machine_id: identity::machine_id(),
+        };
+        #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+        {
+            use crate::bsd::uname;
+            let status = &mut system_status;
+            status.system_name = status.system_name.take().or_else(|| uname("-s"));
+            status.kernel_version = status.kernel_version.take().or_else(|| uname("-v"));
+            status.os_version = status.os_version.take().or_else(|| uname("-r"));
+            status.host_name = status.host_name.take().or_else(|| uname("-n"));
+        }
+        StaticMetrics {
+            system: system_status,
+            capabilities: self.capabilities().await,
+        }
+    }
+}
+
+/// CPU and memory usage of the system and the probe process.
+#[derive(Debug)]
+struct SystemQuerent {
+    system: sysinfo::System,
+    #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+    bsd: crate::bsd::BsdQuerent,
+}
+
+impl SystemQuerent {
+    fn new() -> Self {
+        SystemQuerent {
+            system: sysinfo::System::new_all(),
+            #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+            bsd: Default::default(),
+        }
+    }
+
+    fn has_cpus(&mut self) -> bool {
+        #[allow(unused_mut)]
+        let mut cpu = !self.system.cpus().is_empty();
+        #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+        {
+            cpu = cpu || self.bsd.query_cpus().is_ok_and(|cores| !cores.is_empty());
+        }
+        cpu
+    }
+
+    fn query_cpus(&mut self, policy: CpuReportPolicy) -> CpuReport {
+        self.system.refresh_cpu_all();
+        let usages = self.system.cpus().iter().map(|cpu| cpu.cpu_usage());
+        let cores = usages.map(|usage| CpuMetrics { usage }).collect::<Vec<_>>();
+        #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+        let cores = if cores.is_empty() {
+            self.bsd.query_cpus().unwrap_or_else(|e| {
+                log::warn!("Failed to query CPUs with sysctl: {e}");
+                Vec::new()
+            })
+        } else {
+            cores
+        };
+        match policy {
+            CpuReportPolicy::Aggregate => CpuReport::aggregate(&cores),
+            CpuReportPolicy::PerCore => CpuReport::PerCore(cores),
+        }
+    }
+
+    fn query_memory(&mut self) -> MemoryMetrics {
+        self.system.refresh_memory();
+        let memory = MemoryMetrics {
+            total: self.system.total_memory(),
+            used: self.system.used_memory(),
+            swap_total: self.system.total_swap(),
+            swap_used: self.system.used_swap(),
+        };
+        #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+        if memory.total == 0 {
+            match self.bsd.query_memory() {
+                Ok(memory) => return memory,
+                Err(e) => log::warn!("Failed to query memory with sysctl: {e}"),
+            }
+        }
+        memory
+    }
+
+    /// CPU usage and resident memory of the probe process, sysinfo can not
+    /// see it on OpenBSD.
+    fn query_probe(&mut self) -> (Option<f32>, Option<u64>) {
+        let process = sysinfo::get_current_pid().ok().and_then(|pid| {
+            self.system.refresh_processes_specifics(
+                ProcessesToUpdate::Some(&[pid]),
+                false,
+                ProcessRefreshKind::nothing().with_cpu().with_memory(),
+            );
+            self.system.process(pid)
+        });
+        (
+            process.map(|process| process.cpu_usage()),
+            process.map(|process| process.memory()),
+        )
+    }
+}
+
+/// Byte counters of the network interfaces.
+#[derive(Debug)]
+struct NetworkQuerent {
+    /// Only these interfaces, every physical one but loopback if empty.
+    /// Virtual interfaces like bridges and veths would count traffic twice
+    names: Vec<String>,
+    interfaces: Vec<netdev::Interface>,
+    /// When the interfaces were last listed
+    listed: Option<Instant>,
+    #[cfg(windows)]
+    networks: sysinfo::Networks,
+}
+
+impl NetworkQuerent {
+    /// `names` may list several interfaces separated by commas each.
+    fn new(names: &[String]) -> Self {
+        let names = names
+            .iter()
+            .flat_map(|names| names.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect();
+        NetworkQuerent {
+            names,
+            interfaces: Vec::new(),
+            listed: None,
+            #[cfg(windows)]
+            networks: sysinfo::Networks::new(),
+        }
+    }
+
+    /// Interfaces come and go, e.g. those of VPNs, so they are listed again
+    /// every `INTERFACE_LIST_INTERVAL`, only their counters in between.
+    fn query(&mut self) -> Vec<NetworkMetrics<'static>> {
+        if self
+            .listed
+            .is_none_or(|at| at.elapsed() >= INTERFACE_LIST_INTERVAL)
+        {
+            self.interfaces = match self.names.is_empty() {
+                true => physical_interfaces(),
+                false => netdev::get_interfaces()
+                    .into_iter()
+                    .filter(|iface| self.names.contains(&iface.name))
+                    .collect(),
+            };
+            self.interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+            self.listed = Some(Instant::now());
+        } else {
+            for interface in &mut self.interfaces {
+                let _ = interface.update_stats();
+            }
+        }
+        #[cfg(windows)]
+        if self.interfaces.iter().any(|iface| iface.stats.is_none()) {
+            self.networks.refresh(true);
+        }
+
+        let mut networks = Vec::with_capacity(self.interfaces.len());
+        for interface in &self.interfaces {
+            let stats = interface
+                .stats
+                .as_ref()
+                .map(|stats| (stats.rx_bytes, stats.tx_bytes));
+            #[cfg(windows)]
+            let stats = stats.or_else(|| self.query_windows(interface));
+            networks.push(NetworkMetrics {
+                ifname: interface.name.clone().into(),
+                rx_bytes: stats.map(|(rx, _)| rx),
+                tx_bytes: stats.map(|(_, tx)| tx),
+            });
+        }
+        networks
+    }
+
+    /// Byte counters of `GetIfTable2` through sysinfo, for when netdev has no
+    /// stats. sysinfo names interfaces by their alias, netdev's friendly name.
+    #[cfg(windows)]
+    fn query_windows(&self, interface: &netdev::Interface) -> Option<(u64, u64)> {
+        let alias = interface.friendly_name.as_deref()?;
+        let data = self.networks.get(alias)?;
+        Some((data.total_received(), data.total_transmitted()))
+    }
+}
+
+/// Every physical interface but loopback ones. Without any, e.g. in a
+/// container whose interface is one end of a veth, the one of the default
+/// route instead.
+fn physical_interfaces() -> Vec<netdev::Interface> {
+    let physical: Vec<_> = netdev::get_interfaces()
+        .into_iter()
+        .filter(|iface| !iface.is_loopback() && is_physical(iface))
+        .collect();
+    match physical.is_empty() {
+        true => default_interface().into_iter().collect(),
+        false => physical,
+    }
+}
+
+/// Whether `iface` belongs to a device, sysfs lists bridges, veths, VLANs,
+/// bonds and tunnels as virtual. netdev counts any interface with a carrier
+/// as physical on Linux, bridges included.
+#[cfg(target_os = "linux")]
+fn is_physical(iface: &netdev::Interface) -> bool {
+    std::fs::read_link(format!("/sys/class/net/{}", iface.name))
+        .is_ok_and(|device| !device.to_string_lossy().contains("/virtual/"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_physical(iface: &netdev::Interface) -> bool {
+    iface.is_physical()
+}
+
+/// The interface of the default route, or the first one up without one,
+/// which is common on routers and isolated boxes.
+fn default_interface() -> Option<netdev::Interface> {
+    netdev::get_default_interface()
+        .inspect_err(|e| {
+            log::debug!("No default interface ({e}), falling back to the first one up")
+        })
+        .ok()
+        .or_else(|| {
+            netdev::get_interfaces()
+                .into_iter()
+                .find(|iface| iface.is_up() && !iface.is_loopback())
+        })
+}
+
+/// Usage and I/O counters of the mounted filesystems.
+#[derive(Debug)]
+struct DiskQuerent {
+    disks: sysinfo::Disks,
+}
+
+impl DiskQuerent {
+    fn new() -> Self {
+        DiskQuerent {
+            disks: sysinfo::Disks::new_with_refreshed_list(),
+        }
+    }
+
+    /// The list is refreshed every time to pick up filesystems mounted
+    /// since. Filesystems without a size, e.g. pseudo filesystems, are left
+    /// out, and so are further mounts of a mount point, only the latest is
+    /// visible.
+    fn query(&mut self) -> Vec<DiskMetrics<'static>> {
+        self.disks.refresh_specifics(
+            true,
+            DiskRefreshKind::nothing().with_storage().with_io_usage(),
+        );
+        let mut disks = Vec::<DiskMetrics>::new();
+        for disk in self.disks.list().iter().rev() {
+            let mount_point = disk.mount_point().to_string_lossy();
+            if disk.total_space() == 0 || disks.iter().any(|d| d.mount_point == mount_point) {
+                continue;
+            }
+            // sysinfo leaves the counters at zero where it can not read them
+            let usage = disk.usage();
+            let io = usage.total_read_bytes > 0 || usage.total_written_bytes > 0;
+            disks.push(DiskMetrics {
+                mount_point: mount_point.into_owned().into(),
+                total: disk.total_space(),
+                available: disk.available_space(),
+                read_bytes: io.then_some(usage.total_read_bytes),
+                written_bytes: io.then_some(usage.total_written_bytes),
+            });
+        }
+        disks.reverse();
+        disks
+    }
+}
+
+/// Process and thread counts, with its own process list as `SystemQuerent`
+/// only refreshes the probe process.
+#[derive(Debug, Default)]
+struct ProcessQuerent {
+    system: sysinfo::System,
+}
+
+impl ProcessQuerent {
+    /// Only the state of every process is read, the cheapest refresh there
+    /// is. On Linux threads are listed as processes of their own.
+    fn query(&mut self) -> Option<ProcessMetrics> {
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::nothing(),
+        );
+        let mut metrics = ProcessMetrics {
+            processes: 0,
+            threads: None,
+            zombies: 0,
+        };
+        let mut threads = 0;
+        for process in self.system.processes().values() {
+            threads += 1;
+            if process.thread_kind().is_some() {
+                continue;
+            }
+            metrics.processes += 1;
+            if process.status() == ProcessStatus::Zombie {
+                metrics.zombies += 1;
+            }
+        }
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            metrics.threads = Some(threads);
+        }
+        // not even the probe itself, sysinfo can not list processes here
+        (metrics.processes > 0).then_some(metrics)
+    }
+}
+
+/// CPU temperature and frequency.
+#[derive(Debug)]
+struct SensorQuerent {
+    components: sysinfo::Components,
+    /// Only for the CPU frequencies
+    system: sysinfo::System,
+    fallback: SensorFallback,
+}
+
+impl SensorQuerent {
+    fn new() -> Self {
+        SensorQuerent {
+            components: sysinfo::Components::new_with_refreshed_list(),
+            system: sysinfo::System::new(),
+            fallback: SensorFallback::default(),
+        }
+    }
+
+    fn query(&mut self) -> SensorMetrics {
+        self.components.refresh(false);
+        let cpu_temperature = self
+            .components
+            .iter()
+            .filter(|component| {
+                let label = component.label().to_lowercase();
+                CPU_SENSOR_LABELS.iter().any(|cpu| label.contains(cpu))
+            })
+            .filter_map(|component| component.temperature())
+            .reduce(f32::max);
+
+        self.system.refresh_cpu_frequency();
+        let frequencies = self
+            .system
+            .cpus()
+            .iter()
+            .map(|cpu| cpu.frequency())
+            .filter(|&mhz| mhz > 0)
+            .collect::<Vec<_>>();
+        let cpu_frequency = (!frequencies.is_empty())
+            .then(|| frequencies.iter().sum::<u64>() / frequencies.len() as u64);
+
+        if cpu_temperature.is_some() && cpu_frequency.is_some() {
+            return SensorMetrics {
+                cpu_temperature,
+                cpu_frequency,
+            };
+        }
+        let fallback = self.fallback.query();
+        SensorMetrics {
+            cpu_temperature: cpu_temperature.or(fallback.cpu_temperature),
+            cpu_frequency: cpu_frequency.or(fallback.cpu_frequency),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_query_cpus() {
+        let mut querent = SystemQuerent::new();
+        let _ = querent.query_cpus(CpuReportPolicy::PerCore);
+        std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+        let cpu_status = querent.query_cpus(CpuReportPolicy::PerCore);
+
+        println!("{:?}", cpu_status);
+    }
+
+    #[test]
+    fn test_query_memory() {
+        let mut querent = SystemQuerent::new();
+        let memory_status = querent.query_memory();
+
+        println!("{:?}", memory_status);
+    }
+
+    #[test]
+    fn test_query_network_status() {
+        let mut querent = NetworkQuerent::new(&[]);
+        let network_status = querent.query();
+        assert!(network_status.iter().all(|network| network.ifname != "lo"));
+        // counters only, the list is kept
+        assert_eq!(querent.query().len(), network_status.len());
+
+        println!("{:?}", network_status);
+
+        let mut querent = NetworkQuerent::new(&["lo".to_owned()]);
+        assert!(querent.query().iter().all(|network| network.ifname == "lo"));
+    }
+
+    #[test]
+    fn test_physical_interfaces() {
+        let interfaces = physical_interfaces();
+        assert!(interfaces.iter().all(|iface| !iface.is_loopback()));
+        // only the default one stands in for virtual ones, e.g. in containers
+        if !interfaces.iter().all(is_physical) {
+            assert_eq!(interfaces.len(), 1);
+        }
+        #[cfg(target_os = "linux")]
+        assert!(
+            netdev::get_interfaces()
+                .iter()
+                .filter(|iface| iface.is_loopback())
+                .all(|iface| !is_physical(iface))
+        );
+    }
+
+    #[test]
+    fn test_query_disks() {
+        let mut querent = DiskQuerent::new();
+        let disks = querent.query();
+        for disk in &disks {
+            assert!(disk.available <= disk.total);
+        }
+
+        println!("{:?}", disks);
+    }
+
+    #[test]
+    fn test_query_processes() {
+        let mut querent = ProcessQuerent::default();
+        let processes = querent.query().expect("no processes listed");
+        assert!(processes.processes > 0);
+        if let Some(threads) = processes.threads {
+            assert!(threads >= processes.processes);
+        }
+    }
+
+    #[test]
+    fn test_query_sensors() {
+        let mut querent = SensorQuerent::new();
+        let sensors = querent.query();
+
+        println!("{:?}", sensors);
+    }
+
+    #[tokio::test]
+    async fn test_query_static() {
+        let mut querent = MetricsQuerent::new();
+        let static_status = querent.query_static().await;
+
+        println!("{:?}", static_status);
+    }
+
+    #[tokio::test]
+    async fn test_query_dynamic() {
+        let mut querent = MetricsQuerent::new();
+        querent.set_listeners(true);
+        let metrics = querent.query_dynamic(0).await;
+        assert!(metrics.missing_sections.is_empty());
+        assert!(metrics.listeners.is_some());
+        assert!(metrics.addresses.is_some());
+    }
+}
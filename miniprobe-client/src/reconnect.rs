@@ -0,0 +1,265 @@
+//! How long to wait before connecting again after a session ended.
+//!
+//! Failed sessions back off exponentially from `--retry-minimum-interval` up
+//! to `--retry-maximum-interval`, starting over once a session was created. A
+//! server in maintenance says how long to stay away, backing off from there.
+//! Panics back off on their own timer, which a new session does not reset
+//! since a panic may follow every connect.
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::Duration,
+};
+
+use crate::session::Maintenance;
+
+#[derive(Debug)]
+pub struct ReconnectTimer {
+    minimal_interval: Duration,
+    maximal_interval: Duration,
+    curr_interval: Duration,
+    /// Fraction the waits vary by at random, `0..=1`
+    jitter: f64,
+}
+
+impl ReconnectTimer {
+    pub fn new(minimal_interval: Duration, maximal_interval: Duration) -> Self {
+        debug_assert!(minimal_interval <= maximal_interval);
+
+        Self {
+            minimal_interval,
+            maximal_interval,
+            curr_interval: minimal_interval,
+            jitter: 0.0,
+        }
+    }
+
+    /// Vary each wait by up to `jitter` of the interval either way, so
+    /// clients losing the server at once do not all come back at once.
+    /// `NaN` and infinities mean no jitter, `mul_f64` would panic on them.
+    pub fn with_jitter(self, jitter: f64) -> Self {
+        let jitter = if jitter.is_finite() { jitter } else { 0.0 };
+        Self {
+            jitter: jitter.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// The wait before the next attempt, doubling the interval for the one
+    /// after.
+    pub fn next(&mut self) -> Duration {
+        let interval = self.curr_interval;
+        self.curr_interval = (self.curr_interval * 2).min(self.maximal_interval);
+        if self.jitter == 0.0 {
+            return interval;
+        }
+        interval.mul_f64(1.0 + self.jitter * (2.0 * random_fraction() - 1.0))
+    }
+
+    pub fn reset(&mut self) {
+        self.curr_interval = self.minimal_interval;
+    }
+
+    /// Wait `interval` next, e.g. as long as the server asked.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.curr_interval = interval.max(self.minimal_interval);
+    }
+}
+
+/// A fraction in `0..1`, random enough to spread reconnects without pulling
+/// in a random number generator.
+pub fn random_fraction() -> f64 {
+    // every `RandomState` is keyed differently
+    let bits = RandomState::new().hash_one(0u8);
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The timers of the reconnect loop.
+#[derive(Debug)]
+pub struct Reconnect {
+    session: ReconnectTimer,
+    restart: ReconnectTimer,
+}
+
+impl Reconnect {
+    pub fn new(minimal_interval: Duration, maximal_interval: Duration, jitter: f64) -> Self {
+        Reconnect {
+            session: ReconnectTimer::new(minimal_interval, maximal_interval).with_jitter(jitter),
+            restart: ReconnectTimer::new(minimal_interval, maximal_interval).with_jitter(jitter),
+        }
+    }
+
+    /// A session was created, the next failure waits the shortest again.
+    pub fn connected(&mut self) {
+        self.session.reset();
+    }
+
+    /// The wait before reconnecting after `e` ended a session.
+    pub fn failed(&mut self, e: &anyhow::Error) -> Duration {
+        // wait as long as the server asks, then back off from there
+        match e.downcast_ref::<Maintenance>() {
+            Some(Maintenance {
+                retry_after: Some(retry_after),
+            }) => {
+                log::info!("Server is in maintenance");
+                self.session.set_interval(*retry_after);
+            }
+            Some(_) => log::info!("Server is in maintenance"),
+            None => log::warn!("Error occurred: {e}"),
+        }
+        self.session.next()
+    }
+
+    /// The wait before starting over after a panic, once the previous run
+    /// lasted `ran_for`. Runs longer than the longest wait start the backoff
+    /// over.
+    pub fn panicked(&mut self, ran_for: Duration) -> Duration {
+        if ran_for > self.restart.maximal_interval {
+            self.restart.reset();
+        }
+        self.restart.next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::time::{Instant, sleep};
+
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    /// How a simulated session ends.
+    enum Outcome {
+        /// Refused before a session was created
+        Refused,
+        /// Created, then lost after running this long
+        Lost(Duration),
+        /// Closed for maintenance, asking to come back after this long
+        Maintenance(Option<Duration>),
+        Panicked(Duration),
+    }
+
+    /// Run the reconnect loop over `outcomes` in paused time, returns the
+    /// seconds from the start each attempt was made at.
+    async fn simulate(reconnect: &mut Reconnect, outcomes: Vec<Outcome>) -> Vec<u64> {
+        let start = Instant::now();
+        let mut attempts = Vec::new();
+        for outcome in outcomes {
+            attempts.push((Instant::now() - start).as_secs());
+            let delay = match outcome {
+                Outcome::Refused => reconnect.failed(&anyhow::anyhow!("connection refused")),
+                Outcome::Lost(ran_for) => {
+                    reconnect.connected();
+                    sleep(ran_for).await;
+                    reconnect.failed(&anyhow::anyhow!("connection reset"))
+                }
+                Outcome::Maintenance(retry_after) => {
+                    reconnect.connected();
+                    reconnect.failed(&Maintenance { retry_after }.into())
+                }
+                Outcome::Panicked(ran_for) => {
+                    sleep(ran_for).await;
+                    reconnect.panicked(ran_for)
+                }
+            };
+            sleep(delay).await;
+        }
+        attempts
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff_growth() {
+        let mut reconnect = Reconnect::new(SEC, 8 * SEC, 0.0);
+        let attempts = simulate(&mut reconnect, (0..6).map(|_| Outcome::Refused).collect()).await;
+        // waits of 1, 2, 4, 8 and 8 seconds
+        assert_eq!(attempts, [0, 1, 3, 7, 15, 23]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reset_on_success() {
+        let mut reconnect = Reconnect::new(SEC, 60 * SEC, 0.0);
+        let attempts = simulate(
+            &mut reconnect,
+            vec![
+                Outcome::Refused,
+                Outcome::Refused,
+                Outcome::Refused,
+                Outcome::Lost(10 * SEC),
+                Outcome::Refused,
+                Outcome::Refused,
+            ],
+        )
+        .await;
+        // 1, 2, 4, then 10 connected and 1 and 2 again
+        assert_eq!(attempts, [0, 1, 3, 7, 18, 20]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_maintenance_close() {
+        let mut reconnect = Reconnect::new(SEC, 300 * SEC, 0.0);
+        let attempts = simulate(
+            &mut reconnect,
+            vec![
+                Outcome::Maintenance(Some(60 * SEC)),
+                Outcome::Refused,
+                Outcome::Refused,
+                Outcome::Maintenance(None),
+                Outcome::Refused,
+            ],
+        )
+        .await;
+        // 60 as asked, backing off to 120 and 240 from there, then the
+        // shortest wait without a retry after
+        assert_eq!(attempts, [0, 60, 180, 420, 421]);
+
+        // never shorter than the shortest wait
+        let mut reconnect = Reconnect::new(5 * SEC, 300 * SEC, 0.0);
+        let attempts = simulate(
+            &mut reconnect,
+            vec![Outcome::Maintenance(Some(Duration::ZERO)), Outcome::Refused],
+        )
+        .await;
+        assert_eq!(attempts, [0, 5]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_panic_backoff() {
+        let mut reconnect = Reconnect::new(SEC, 8 * SEC, 0.0);
+        let attempts = simulate(
+            &mut reconnect,
+            vec![
+                Outcome::Panicked(Duration::ZERO),
+                Outcome::Panicked(Duration::ZERO),
+                // a session in between does not reset the panic backoff
+                Outcome::Lost(Duration::ZERO),
+                Outcome::Panicked(Duration::ZERO),
+                // a long run does
+                Outcome::Panicked(10 * SEC),
+                Outcome::Panicked(Duration::ZERO),
+            ],
+        )
+        .await;
+        assert_eq!(attempts, [0, 1, 3, 4, 8, 19]);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut timer = ReconnectTimer::new(10 * SEC, 80 * SEC).with_jitter(0.5);
+        for interval in [10, 20, 40, 80, 80] {
+            let wait = timer.next();
+            assert!(wait >= interval * SEC / 2, "{wait:?} for {interval}s");
+            assert!(wait <= interval * SEC * 3 / 2, "{wait:?} for {interval}s");
+        }
+
+        let waits = (0..32)
+            .map(|_| ReconnectTimer::new(SEC, SEC).with_jitter(1.0).next())
+            .collect::<Vec<_>>();
+        assert!(waits.iter().any(|w| *w != waits[0]));
+
+        for jitter in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let mut timer = ReconnectTimer::new(SEC, SEC).with_jitter(jitter);
+            assert_eq!(timer.next(), SEC);
+        }
+    }
+}
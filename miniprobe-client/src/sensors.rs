@@ -0,0 +1,208 @@
+//! Fallback collectors for sensors sysinfo can not read on some platforms.
+//!
+//! sysinfo reports no CPU temperature on Intel Macs and on most Windows
+//! machines without admin rights, and no frequency on some of them. The
+//! fallbacks shell out to the platform tools instead, which is too slow to do
+//! on every scrape, so their results are cached for [`FALLBACK_INTERVAL`].
+
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use miniprobe_proto::SensorMetrics;
+
+const FALLBACK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct SensorFallback {
+    cached: Option<(Instant, SensorMetrics)>,
+    disabled: bool,
+}
+
+impl SensorFallback {
+    /// Sensor readings of the platform fallback, possibly cached.
+    pub fn query(&mut self) -> SensorMetrics {
+        if self.disabled {
+            return SensorMetrics::default();
+        }
+        if let Some((at, metrics)) = &self.cached
+            && at.elapsed() < FALLBACK_INTERVAL
+        {
+            return metrics.clone();
+        }
+
+        match collect() {
+            Ok(metrics) => {
+                debug!("sensor fallback collected {metrics:?}");
+                self.cached = Some((Instant::now(), metrics.clone()));
+                metrics
+            }
+            Err(e) => {
+                // the tools will not start working later, so do not spawn them every scrape
+                warn!("Sensor fallback unavailable, disabling it: {e}");
+                self.disabled = true;
+                SensorMetrics::default()
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn collect() -> anyhow::Result<SensorMetrics> {
+    // powermetrics needs root, the frequency is still available through sysctl without it
+    let powermetrics = command(
+        "powermetrics",
+        &["--samplers", "smc,cpu_power", "-n", "1", "-i", "100"],
+    );
+    let mut metrics = match &powermetrics {
+        Ok(out) => parse_powermetrics(out),
+        Err(_) => SensorMetrics::default(),
+    };
+    if metrics.cpu_frequency.is_none() {
+        metrics.cpu_frequency = command("sysctl", &["-n", "hw.cpufrequency"])
+            .ok()
+            .and_then(|hz| hz.trim().parse::<u64>().ok())
+            .map(|hz| hz / 1_000_000);
+    }
+
+    match (powermetrics, &metrics) {
+        (
+            Err(e),
+            SensorMetrics {
+                cpu_temperature: None,
+                cpu_frequency: None,
+            },
+        ) => Err(e),
+        _ => Ok(metrics),
+    }
+}
+
+#[cfg(windows)]
+fn collect() -> anyhow::Result<SensorMetrics> {
+    // the performance counter class does not need admin rights, unlike `MSAcpi_ThermalZoneTemperature`
+    let out = command(
+        "powershell",
+        &[
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            "(Get-CimInstance Win32_PerfFormattedData_Counters_ThermalZoneInformation).Temperature; \
+             '---'; \
+             (Get-CimInstance Win32_Processor).CurrentClockSpeed",
+        ],
+    )?;
+    Ok(parse_wmi(&out))
+}
+
+/// sysinfo covers every sensor it can read on the remaining platforms
+#[cfg(not(any(target_os = "macos", windows)))]
+fn collect() -> anyhow::Result<SensorMetrics> {
+    Ok(SensorMetrics::default())
+}
+
+#[cfg(any(target_os = "macos", windows))]
+fn command(program: &str, args: &[&str]) -> anyhow::Result<String> {
+    let output = std::process::Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{program} exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Parse `CPU die temperature: 52.31 C` of the smc sampler and the
+/// `CPU 0 frequency: 1216 MHz` lines of the cpu_power sampler.
+#[cfg(any(target_os = "macos", test))]
+fn parse_powermetrics(out: &str) -> SensorMetrics {
+    let mut cpu_temperature = None;
+    let mut frequencies = Vec::new();
+    for line in out.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if key == "CPU die temperature" {
+            cpu_temperature = value
+                .strip_suffix('C')
+                .and_then(|v| v.trim().parse::<f32>().ok());
+        } else if key.starts_with("CPU ")
+            && key.ends_with(" frequency")
+            && let Some(mhz) = value.strip_suffix("MHz")
+            && let Ok(mhz) = mhz.trim().parse::<u64>()
+        {
+            frequencies.push(mhz);
+        }
+    }
+    SensorMetrics {
+        cpu_temperature,
+        cpu_frequency: average(&frequencies),
+    }
+}
+
+/// Parse thermal zone temperatures in Kelvin and processor clock speeds in
+/// MHz, separated by a `---` line.
+#[cfg(any(windows, test))]
+fn parse_wmi(out: &str) -> SensorMetrics {
+    let (temperatures, frequencies) = out.split_once("---").unwrap_or((out, ""));
+    let cpu_temperature = temperatures
+        .lines()
+        .filter_map(|kelvin| kelvin.trim().parse::<f32>().ok())
+        // zones without a sensor report 0 K
+        .filter(|kelvin| *kelvin > 0.0)
+        .map(|kelvin| kelvin - 273.15)
+        .reduce(f32::max);
+    let frequencies = frequencies
+        .lines()
+        .filter_map(|mhz| mhz.trim().parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    SensorMetrics {
+        cpu_temperature,
+        cpu_frequency: average(&frequencies),
+    }
+}
+
+#[cfg(any(target_os = "macos", windows, test))]
+fn average(values: &[u64]) -> Option<u64> {
+    (!values.is_empty()).then(|| values.iter().sum::<u64>() / values.len() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_powermetrics() {
+        let out = "\
+**** SMC sensors ****
+
+CPU Thermal level: 0
+CPU die temperature: 52.31 C
+
+**** Processor usage ****
+
+CPU 0 frequency: 1200 MHz
+CPU 0 active residency:  12.34% (600 MHz: 1.2%)
+CPU 1 frequency: 1800 MHz
+";
+        let metrics = parse_powermetrics(out);
+        assert_eq!(metrics.cpu_temperature, Some(52.31));
+        assert_eq!(metrics.cpu_frequency, Some(1500));
+
+        let metrics = parse_powermetrics("");
+        assert_eq!(metrics.cpu_temperature, None);
+        assert_eq!(metrics.cpu_frequency, None);
+    }
+
+    #[test]
+    fn test_parse_wmi() {
+        let metrics = parse_wmi("0\r\n318\r\n300\r\n---\r\n2904\r\n");
+        assert!((metrics.cpu_temperature.unwrap() - 44.85).abs() < 0.01);
+        assert_eq!(metrics.cpu_frequency, Some(2904));
+
+        let metrics = parse_wmi("---\n3600\n3200\n");
+        assert_eq!(metrics.cpu_temperature, None);
+        assert_eq!(metrics.cpu_frequency, Some(3400));
+    }
+}
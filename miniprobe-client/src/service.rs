@@ -0,0 +1,224 @@
+//! `service install`, writing a systemd unit that keeps `run` going with the
+//! arguments given.
+//!
+//! The unit restarts the client whenever it exits, which is what
+//! `--watchdog` relies on. The token is left out of `ExecStart=`, which every
+//! local user can read with `systemctl show`, and passed in the environment
+//! from a file only readable by its owner instead.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use argh::FromArgs;
+
+use crate::cli::{RunCommand, ServiceInstallCommand, TOKEN_ENV};
+
+pub fn install(cmd: &ServiceInstallCommand) -> anyhow::Result<()> {
+    // a typo would otherwise only show in the journal of the service
+    let run = parse_run(&cmd.args)?;
+    if run.token.is_none() && !run.offline {
+        anyhow::bail!("an authentication token is required unless --offline is set");
+    }
+    let args = match &run.token {
+        Some(token) => without_token(&cmd.args, token)?,
+        None => cmd.args.clone(),
+    };
+
+    let exe = std::env::current_exe()?;
+    let env_file = run.token.as_ref().map(|_| cmd.env_file.as_path());
+    let unit = unit_file(&exe, cmd.user.as_deref(), env_file, &args);
+    for path in [Some(cmd.path.as_path()), env_file].into_iter().flatten() {
+        if !cmd.force && path.exists() {
+            anyhow::bail!("{} exists, replace it with --force", path.display());
+        }
+    }
+    if let Some(token) = &run.token {
+        // the client reads it as is, systemd would unquote and expand it
+        if !token.chars().all(|c| c.is_ascii_alphanumeric()) {
+            anyhow::bail!("the token is not alphanumeric");
+        }
+        write_file(&cmd.env_file, &format!("{TOKEN_ENV}={token}\n"), 0o600)?;
+        println!("Wrote the token to {}", cmd.env_file.display());
+    }
+    write_file(&cmd.path, &unit, 0o644)?;
+
+    let name = cmd.path.file_name().map_or_else(
+        || "miniprobe-client.service".into(),
+        |n| n.to_string_lossy(),
+    );
+    println!("Wrote {}, start it with:", cmd.path.display());
+    println!("  systemctl daemon-reload && systemctl enable --now {name}");
+    Ok(())
+}
+
+fn parse_run(args: &[String]) -> anyhow::Result<RunCommand> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    RunCommand::from_args(&["run"], &args)
+        .map_err(|exit| anyhow::anyhow!("invalid arguments of `run`: {}", exit.output.trim()))
+}
+
+/// `args` without the positional `token`, told apart from an option value
+/// equal to it by parsing what is left.
+fn without_token(args: &[String], token: &str) -> anyhow::Result<Vec<String>> {
+    for (i, _) in args.iter().enumerate().filter(|(_, arg)| *arg == token) {
+        let mut rest = args.to_vec();
+        rest.remove(i);
+        if parse_run(&rest).is_ok_and(|run| run.token.is_none()) {
+            return Ok(rest);
+        }
+    }
+    anyhow::bail!("cannot tell the token apart from the other arguments")
+}
+
+/// Write `contents` to `path` with `mode` on unix, also when replacing a file
+/// with another mode.
+fn write_file(path: &Path, contents: &str, mode: u32) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    let mut file = options.open(path)?;
+    // the mode of `open` only applies to new files, and before the contents
+    // are written to a replaced one
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(mode))?;
+    #[cfg(not(unix))]
+    let _ = mode;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn unit_file(exe: &Path, user: Option<&str>, env_file: Option<&Path>, args: &[String]) -> String {
+    let mut exec_start = quote_exec_arg(&exe.to_string_lossy());
+    exec_start.push_str(" run");
+    for arg in args {
+        exec_start.push(' ');
+        exec_start.push_str(&quote_exec_arg(arg));
+    }
+    let user = user
+        .map(|user| format!("User={user}\n"))
+        .unwrap_or_default();
+    // read by systemd before dropping to `User=`, so it may stay root's
+    let env_file = env_file
+        .map(|path| {
+            format!(
+                "EnvironmentFile={}\n",
+                path.to_string_lossy().replace('%', "%%")
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "[Unit]
+Description=miniprobe client
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+{env_file}ExecStart={exec_start}
+{user}Restart=always
+RestartSec=5
+
+[Install]
+WantedBy=multi-user.target
+"
+    )
+}
+
+/// An argument of `ExecStart=`, quoted if systemd would split or expand it.
+fn quote_exec_arg(arg: &str) -> String {
+    // `%` starts a specifier and `$` a variable anywhere, quoted or not
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return arg;
+    }
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quote_exec_arg() {
+        assert_eq!(quote_exec_arg("--tls"), "--tls");
+        assert_eq!(quote_exec_arg("a b"), "\"a b\"");
+        assert_eq!(quote_exec_arg("x\"y\\"), "\"x\\\"y\\\\\"");
+        assert_eq!(quote_exec_arg("50%"), "50%%");
+        assert_eq!(quote_exec_arg("$HOME"), "$$HOME");
+        assert_eq!(quote_exec_arg(";"), "\";\"");
+        assert_eq!(quote_exec_arg(""), "\"\"");
+    }
+
+    #[test]
+    fn test_unit_file() {
+        let unit = unit_file(
+            Path::new("/usr/local/bin/miniprobe-client"),
+            Some("probe"),
+            Some(Path::new("/etc/miniprobe-client/env")),
+            &["--action".to_owned(), "up=uptime -p".to_owned()],
+        );
+        assert!(unit.contains(
+            "\nEnvironmentFile=/etc/miniprobe-client/env\nExecStart=/usr/local/bin/miniprobe-client run --action \"up=uptime -p\"\nUser=probe\n"
+        ));
+        let unit = unit_file(Path::new("/bin/c"), None, None, &[]);
+        assert!(!unit.contains("User="));
+        assert!(!unit.contains("EnvironmentFile="));
+    }
+
+    #[test]
+    fn test_without_token() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            without_token(&args(&["TOKEN", "-a", "host:1", "--tls"]), "TOKEN").unwrap(),
+            args(&["-a", "host:1", "--tls"])
+        );
+        // an option value equal to the token stays
+        assert_eq!(
+            without_token(&args(&["--netns", "mgmt", "mgmt"]), "mgmt").unwrap(),
+            args(&["--netns", "mgmt"])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_file_replaces_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("miniprobe-service-{}", std::process::id()));
+        let path = dir.join("env");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_file(&path, "new", 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(contents, "new");
+    }
+}
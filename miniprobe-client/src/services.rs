@@ -0,0 +1,193 @@
+//! State of systemd units, queried from the system bus.
+//!
+//! Only the units given on the command line are watched. Other platforms
+//! report every unit as unknown.
+
+use miniprobe_proto::{ServiceMetrics, ServiceState};
+
+#[derive(Debug, Default)]
+pub struct ServiceQuerent {
+    units: Vec<String>,
+    #[cfg(target_os = "linux")]
+    connection: Option<zbus::Connection>,
+    /// The last query failed, only the first failure in a row is a warning
+    #[cfg(target_os = "linux")]
+    failing: bool,
+}
+
+impl ServiceQuerent {
+    /// Watch `units`, a name without a suffix is taken as a `.service`.
+    pub fn new(units: &[String]) -> Self {
+        ServiceQuerent {
+            units: units
+                .iter()
+                .map(|unit| {
+                    if unit.contains('.') {
+                        unit.clone()
+                    } else {
+                        format!("{unit}.service")
+                    }
+                })
+                .collect(),
+            #[cfg(target_os = "linux")]
+            connection: None,
+            #[cfg(target_os = "linux")]
+            failing: false,
+        }
+    }
+
+    pub async fn query(&mut self) -> Vec<ServiceMetrics<'static>> {
+        if self.units.is_empty() {
+            return Vec::new();
+        }
+
+        #[cfg(target_os = "linux")]
+        let states = match self.query_systemd().await {
+            Ok(states) => {
+                self.failing = false;
+                return states;
+            }
+            Err(e) => {
+                if self.failing {
+                    log::debug!("Failed to query systemd units: {e}");
+                } else {
+                    log::warn!("Failed to query systemd units: {e}");
+                    self.failing = true;
+                }
+                // connect again next time, systemd may have been restarted
+                self.connection = None;
+                self.units.iter().map(|_| ServiceState::Unknown)
+            }
+        };
+        #[cfg(not(target_os = "linux"))]
+        let states = self.units.iter().map(|_| ServiceState::Unknown);
+
+        self.units
+            .iter()
+            .zip(states)
+            .map(|(name, state)| ServiceMetrics {
+                name: name.clone().into(),
+                state,
+                restarts: None,
+            })
+            .collect()
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn query_systemd(&mut self) -> zbus::Result<Vec<ServiceMetrics<'static>>> {
+        let connection = match &self.connection {
+            Some(connection) => connection,
+            None => self.connection.insert(zbus::Connection::system().await?),
+        };
+
+        let mut services = Vec::with_capacity(self.units.len());
+        for name in &self.units {
+            let (state, restarts) = match systemd::unit(connection, name).await {
+                Ok(unit) => unit,
+                // a missing unit is not a reason to give up on the others
+                Err(zbus::Error::MethodError(error, ..)) => {
+                    log::debug!("Failed to load unit {name}: {error}");
+                    (ServiceState::Unknown, None)
+                }
+                Err(e) => return Err(e),
+            };
+            services.push(ServiceMetrics {
+                name: name.clone().into(),
+                state,
+                restarts,
+            });
+        }
+        Ok(services)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod systemd {
+    use miniprobe_proto::ServiceState;
+    use zbus::{
+        Connection,
+        zvariant::{OwnedObjectPath, OwnedValue},
+    };
+
+    const DESTINATION: &str = "org.freedesktop.systemd1";
+
+    /// `ActiveState` and `NRestarts` of a unit, loading it if needed.
+    pub async fn unit(
+        connection: &Connection,
+        name: &str,
+    ) -> zbus::Result<(ServiceState, Option<u32>)> {
+        let path: OwnedObjectPath = connection
+            .call_method(
+                Some(DESTINATION),
+                "/org/freedesktop/systemd1",
+                Some("org.freedesktop.systemd1.Manager"),
+                "LoadUnit",
+                &(name,),
+            )
+            .await?
+            .body()
+            .deserialize()?;
+
+        let state: String = property(connection, &path, "Unit", "ActiveState").await?;
+        // only service units have a restart counter
+        let restarts = property(connection, &path, "Service", "NRestarts")
+            .await
+            .ok();
+        Ok((super::parse_state(&state), restarts))
+    }
+
+    async fn property<T>(
+        connection: &Connection,
+        path: &OwnedObjectPath,
+        interface: &str,
+        name: &str,
+    ) -> zbus::Result<T>
+    where
+        T: TryFrom<OwnedValue, Error = zbus::zvariant::Error>,
+    {
+        let value: OwnedValue = connection
+            .call_method(
+                Some(DESTINATION),
+                path,
+                Some("org.freedesktop.DBus.Properties"),
+                "Get",
+                &(format!("org.freedesktop.systemd1.{interface}"), name),
+            )
+            .await?
+            .body()
+            .deserialize()?;
+        Ok(T::try_from(value)?)
+    }
+}
+
+/// Value of the `ActiveState` property.
+#[cfg(any(target_os = "linux", test))]
+fn parse_state(state: &str) -> ServiceState {
+    match state {
+        "active" => ServiceState::Active,
+        "reloading" | "refreshing" => ServiceState::Reloading,
+        "inactive" | "maintenance" => ServiceState::Inactive,
+        "failed" => ServiceState::Failed,
+        "activating" => ServiceState::Activating,
+        "deactivating" => ServiceState::Deactivating,
+        _ => ServiceState::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unit_names() {
+        let querent = ServiceQuerent::new(&["nginx".to_owned(), "backup.timer".to_owned()]);
+        assert_eq!(querent.units, ["nginx.service", "backup.timer"]);
+    }
+
+    #[test]
+    fn test_parse_state() {
+        assert_eq!(parse_state("active"), ServiceState::Active);
+        assert_eq!(parse_state("failed"), ServiceState::Failed);
+        assert_eq!(parse_state("bogus"), ServiceState::Unknown);
+    }
+}
@@ -1,13 +1,132 @@
+use std::time::Duration;
+
 use bytes::BytesMut;
-use http::{Method, header};
-use miniprobe_proto::msg::{CreateSessionReq, CreateSessionResp};
+use http::{Method, StatusCode, header};
+use miniprobe_proto::{
+    METRICS_SCHEMA_HASH, StaticMetrics, UnixMillis,
+    limits::POSTCARD_CONTENT_TYPE,
+    msg::{
+        CreateSessionReq, CreateSessionResp, PROTOCOL_HEADER, PROTOCOL_VERSION, SCHEMA_HEADER,
+        ServerCapabilities,
+    },
+};
+
+use crate::{
+    egress::BatchPolicy,
+    http_util::{self, IpVersion},
+};
+
+/// Clock skew beyond which the client warns on session creation.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5);
+
+/// The server is in maintenance and refused the session or closed the
+/// websocket, asking to reconnect after `retry_after`.
+#[derive(Debug, thiserror::Error)]
+#[error("server is in maintenance")]
+pub struct Maintenance {
+    pub retry_after: Option<Duration>,
+}
+
+impl Maintenance {
+    /// From a `503` response, `None` for other statuses.
+    pub fn from_response<T>(resp: &http::Response<T>) -> Option<Self> {
+        if resp.status() != StatusCode::SERVICE_UNAVAILABLE {
+            return None;
+        }
+        let retry_after = resp
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+            .map(Duration::from_secs);
+        Some(Maintenance { retry_after })
+    }
+}
+
+/// Features advertised by the server, `None` for servers predating the
+/// discovery endpoint.
+pub async fn server_capabilities(
+    server_addr: &str,
+    tls: bool,
+    ip_version: IpVersion,
+    socks5: Option<&str>,
+) -> anyhow::Result<Option<ServerCapabilities>> {
+    let uri = format!(
+        "{}://{server_addr}/.well-known/miniprobe",
+        if tls { "https" } else { "http" }
+    );
+    let req = http_util::basic_request_builder(&uri, Method::GET)?
+        .header(header::ACCEPT, POSTCARD_CONTENT_TYPE)
+        .body(Vec::new())?;
+
+    let resp = http_util::send_http_request(req, tls, ip_version, socks5).await?;
+
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        anyhow::bail!(
+            "Discovery error: [{}]{}",
+            resp.status().as_u16(),
+            String::from_utf8_lossy(resp.body())
+        );
+    }
+
+    Ok(Some(postcard::from_bytes(resp.body())?))
+}
+
+/// Pick how to send samples from what the server supports, batching with
+/// `send_interval` only if the server accepts batches.
+pub fn negotiate(
+    capabilities: Option<&ServerCapabilities>,
+    send_interval: Option<Duration>,
+) -> anyhow::Result<Option<BatchPolicy>> {
+    let Some(send_interval) = send_interval else {
+        if let Some(capabilities) = capabilities {
+            check_protocol(capabilities)?;
+        }
+        return Ok(None);
+    };
+    let Some(capabilities) = capabilities else {
+        log::debug!("Server does not advertise its capabilities, assuming it accepts batches");
+        return Ok(Some(BatchPolicy {
+            send_interval,
+            max_size: usize::MAX,
+            max_bytes: usize::MAX,
+        }));
+    };
+    check_protocol(capabilities)?;
+
+    match capabilities.max_batch_size {
+        Some(max_size) => Ok(Some(BatchPolicy {
+            send_interval,
+            max_size: max_size.max(1) as usize,
+            max_bytes: usize::MAX,
+        })),
+        None => {
+            log::warn!("Server does not accept batches, sending every sample right away");
+            Ok(None)
+        }
+    }
+}
+
+fn check_protocol(capabilities: &ServerCapabilities) -> anyhow::Result<()> {
+    if !capabilities.protocol_versions.contains(&PROTOCOL_VERSION) {
+        anyhow::bail!(
+            "Server {} speaks protocol versions {:?}, this client speaks {PROTOCOL_VERSION}",
+            capabilities.version,
+            capabilities.protocol_versions
+        );
+    }
+    Ok(())
+}
 
-use crate::{http_util, query::MetricsQuerent};
 pub async fn create_session(
     token: &str,
+    system_info: StaticMetrics,
     server_addr: &str,
     tls: bool,
-    prefer_ipv6: bool,
+    ip_version: IpVersion,
+    socks5: Option<&str>,
 ) -> anyhow::Result<CreateSessionResp> {
     let uri = format!(
         "{}://{server_addr}/api/v1/sessions",
@@ -16,18 +135,25 @@ pub async fn create_session(
     let body = postcard::to_extend(
         &CreateSessionReq {
             token: token.to_owned(),
-            system_info: MetricsQuerent::query_static(),
+            system_info,
         },
         BytesMut::new(),
     )?
     .freeze();
     let req = http_util::basic_request_builder(&uri, Method::POST)?
-        .header(header::CONTENT_TYPE, "application/postcard")
+        .header(header::CONTENT_TYPE, POSTCARD_CONTENT_TYPE)
         .header(header::CONTENT_LENGTH, body.len())
+        .header(SCHEMA_HEADER, format!("{METRICS_SCHEMA_HASH:016x}"))
+        .header(PROTOCOL_HEADER, PROTOCOL_VERSION)
         .body(body)?;
 
-    let resp = http_util::send_http_request(req, tls, prefer_ipv6).await?;
+    let sent = UnixMillis::now();
+    let resp = http_util::send_http_request(req, tls, ip_version, socks5).await?;
+    let received = UnixMillis::now();
 
+    if let Some(maintenance) = Maintenance::from_response(&resp) {
+        return Err(maintenance.into());
+    }
     if !resp.status().is_success() {
         anyhow::bail!(
             "Auth error: [{}]{}",
@@ -38,5 +164,94 @@ pub async fn create_session(
 
     let auth_resp: CreateSessionResp = postcard::from_bytes(resp.body())?;
 
+    let skew = clock_skew(auth_resp.server_time, sent, received);
+    if skew.unsigned_abs() >= MAX_CLOCK_SKEW.as_millis() as u64 {
+        log::warn!(
+            "Clock is {:.1}s {} the server, sample times are off by as much",
+            skew.unsigned_abs() as f64 / 1000.0,
+            if skew > 0 { "behind" } else { "ahead of" }
+        );
+    } else {
+        log::debug!("Clock skew to the server: {skew}ms");
+    }
+
     Ok(auth_resp)
 }
+
+/// How far the clock of the server is ahead of ours in milliseconds, taking
+/// `server_time` to be halfway between sending the request and receiving the
+/// response.
+fn clock_skew(server_time: UnixMillis, sent: UnixMillis, received: UnixMillis) -> i64 {
+    server_time.0 as i64 - ((sent.0 + received.0) / 2) as i64
+}
+
+#[cfg(test)]
+mod test {
+    use miniprobe_proto::msg::{Compression, Encoding, Transport};
+
+    use super::*;
+
+    fn capabilities(protocol_version: u32, max_batch_size: Option<u32>) -> ServerCapabilities {
+        ServerCapabilities {
+            version: "0.1.0".to_owned(),
+            protocol_versions: vec![protocol_version],
+            transports: vec![Transport::Websocket],
+            encodings: vec![Encoding::Postcard],
+            compression: vec![Compression::Identity],
+            max_batch_size,
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let interval = Some(Duration::from_secs(60));
+
+        let batch = negotiate(Some(&capabilities(PROTOCOL_VERSION, Some(16))), interval).unwrap();
+        assert_eq!(batch.map(|batch| batch.max_size), Some(16));
+
+        // the server can not take batches
+        let batch = negotiate(Some(&capabilities(PROTOCOL_VERSION, None)), interval).unwrap();
+        assert_eq!(batch, None);
+
+        // an old server is trusted with the flags given
+        let batch = negotiate(None, interval).unwrap();
+        assert_eq!(batch.map(|batch| batch.max_size), Some(usize::MAX));
+        assert_eq!(negotiate(None, None).unwrap(), None);
+
+        assert!(negotiate(Some(&capabilities(PROTOCOL_VERSION + 1, Some(16))), None).is_err());
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let at = |ms: u64| UnixMillis(1_700_000_000_000 + ms);
+        assert_eq!(clock_skew(at(150), at(0), at(300)), 0);
+        // the server answered 2s past the midway point, we are behind
+        assert_eq!(clock_skew(at(2150), at(0), at(300)), 2000);
+        assert_eq!(clock_skew(at(0), at(1000), at(1000)), -1000);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let resp = |status: StatusCode, retry_after: Option<&str>| {
+            let mut resp = http::Response::builder().status(status);
+            if let Some(retry_after) = retry_after {
+                resp = resp.header(header::RETRY_AFTER, retry_after);
+            }
+            resp.body(()).unwrap()
+        };
+
+        let maintenance =
+            Maintenance::from_response(&resp(StatusCode::SERVICE_UNAVAILABLE, Some("30")));
+        assert_eq!(
+            maintenance.unwrap().retry_after,
+            Some(Duration::from_secs(30))
+        );
+        // an HTTP date is not understood, the client backs off as usual
+        let maintenance = Maintenance::from_response(&resp(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some("Wed, 21 Oct 2026 07:28:00 GMT"),
+        ));
+        assert_eq!(maintenance.unwrap().retry_after, None);
+        assert!(Maintenance::from_response(&resp(StatusCode::FORBIDDEN, Some("30"))).is_none());
+    }
+}
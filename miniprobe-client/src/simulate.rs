@@ -0,0 +1,69 @@
+//! `once` and `simulate`, collecting samples without a server to check what
+//! a host reports before pointing it at one.
+//!
+//! Samples are printed as JSON to standard output, the encoded size of each
+//! sample `simulate` prints is logged at debug level.
+
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+use tokio::time::{Instant, sleep, sleep_until};
+
+use crate::{
+    cli::{OnceCommand, SimulateCommand},
+    new_collector,
+};
+
+/// Print the system information and a sample taken a second after the
+/// first, so CPU usage and rates cover that second.
+pub async fn once(cmd: &OnceCommand) -> anyhow::Result<()> {
+    let options = cmd.collect();
+    let collector = new_collector(&options, None)?;
+    collector.set_cpu_report(options.cpu_report()).await;
+
+    let system = collector.query_static().await;
+    collector.query_dynamic(0).await;
+    sleep(Duration::from_secs(1)).await;
+    let sample = collector.query_dynamic(1).await;
+
+    let output = serde_json::json!({ "system": system, "sample": sample });
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
+
+/// Print a sample as a JSON line every scrape interval, `count` of them or
+/// until ctrl-c.
+pub async fn simulate(cmd: &SimulateCommand) -> anyhow::Result<()> {
+    let options = cmd.collect();
+    let collector = new_collector(&options, None)?;
+    collector.set_cpu_report(options.cpu_report()).await;
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let scrape_interval = Duration::from_secs(cmd.scrape_interval.max(1));
+    let mut next_scrape = Instant::now();
+    let mut stdout = io::stdout();
+    for seq in 0.. {
+        if cmd.count > 0 && seq >= cmd.count {
+            break;
+        }
+        if seq > 0 {
+            tokio::select! {
+                _ = &mut ctrl_c => break,
+                _ = sleep_until(next_scrape) => {}
+            }
+        }
+        let sample = collector.query_dynamic(seq).await;
+        log::debug!(
+            "Sample {seq} is {} bytes as postcard",
+            postcard::to_extend(&sample, Vec::new())?.len()
+        );
+        serde_json::to_writer(&mut stdout, &sample)?;
+        writeln!(stdout)?;
+        stdout.flush()?;
+        next_scrape += scrape_interval;
+    }
+    Ok(())
+}
@@ -0,0 +1,422 @@
+//! `--server-srv`, finding the servers to connect to in the DNS SRV records
+//! of a name, e.g. `_miniprobe._tcp.example.com`.
+//!
+//! The records are looked up again on every connect, so servers can move
+//! without touching the clients. They are asked from the nameservers of
+//! `/etc/resolv.conf` over UDP, and over TCP if the answer does not fit, so
+//! this is Unix only. Its `search` domains and `options` are not applied, the
+//! name is looked up as given. The queries go straight to the nameservers,
+//! which is why `--socks5` is refused along with it. The order to try the
+//! servers in follows RFC 2782: lowest priority first, shuffled by weight
+//! within a priority.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::reconnect::random_fraction;
+
+/// How long each nameserver may take to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Most compression pointers followed in one name, more are a loop.
+const MAX_POINTERS: usize = 16;
+
+/// A server of an SRV record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// Host name without the trailing dot
+    pub target: String,
+}
+
+/// The servers of the SRV records of `name` as `HOST:PORT`, in the order to
+/// try them.
+pub async fn lookup(name: &str) -> anyhow::Result<Vec<String>> {
+    if cfg!(not(unix)) {
+        anyhow::bail!("--server-srv is only supported on Unix");
+    }
+    let conf = std::fs::read_to_string(RESOLV_CONF)
+        .map_err(|e| anyhow::anyhow!("DNS error: failed to read {RESOLV_CONF}: {e}"))?;
+    let nameservers = parse_nameservers(&conf);
+    if nameservers.is_empty() {
+        anyhow::bail!("DNS error: no nameserver in {RESOLV_CONF}");
+    }
+
+    let id = (random_fraction() * 65536.0) as u16;
+    let query = encode_query(id, name)?;
+    let mut last_error = None;
+    for nameserver in nameservers {
+        match query_nameserver(SocketAddr::new(nameserver, 53), &query, id).await {
+            Ok(records) => {
+                let servers = order(records, random_fraction)
+                    .into_iter()
+                    .map(|record| format!("{}:{}", record.target, record.port))
+                    .collect::<Vec<_>>();
+                if servers.is_empty() {
+                    anyhow::bail!("DNS error: {name} has no SRV records");
+                }
+                log::debug!("Servers of {name}: {servers:?}");
+                return Ok(servers);
+            }
+            Err(e) => {
+                log::debug!("Nameserver {nameserver} failed to look up {name}: {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("there is a nameserver"))
+}
+
+async fn query_nameserver(
+    nameserver: SocketAddr,
+    query: &[u8],
+    id: u16,
+) -> anyhow::Result<Vec<SrvRecord>> {
+    let local: SocketAddr = if nameserver.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(nameserver).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; 1232];
+    let answer = loop {
+        let len = timeout(QUERY_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .map_err(|_| anyhow::anyhow!("DNS error: {nameserver} timed out"))??;
+        // an answer to someone else, or a spoofing attempt
+        if len >= 2 && buf[..2] == id.to_be_bytes() {
+            break &buf[..len];
+        }
+    };
+    match parse_response(answer, id)? {
+        Response::Records(records) => Ok(records),
+        Response::Truncated => {
+            let answer = timeout(QUERY_TIMEOUT, query_tcp(nameserver, query))
+                .await
+                .map_err(|_| anyhow::anyhow!("DNS error: {nameserver} timed out"))??;
+            match parse_response(&answer, id)? {
+                Response::Records(records) => Ok(records),
+                Response::Truncated => anyhow::bail!("DNS error: truncated answer over TCP"),
+            }
+        }
+    }
+}
+
+/// DNS over TCP, where messages are prefixed with their length.
+async fn query_tcp(nameserver: SocketAddr, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await?;
+    let len = stream.read_u16().await?;
+    let mut answer = vec![0; len as usize];
+    stream.read_exact(&mut answer).await?;
+    Ok(answer)
+}
+
+/// Addresses of the `nameserver` lines, in the order given.
+fn parse_nameservers(conf: &str) -> Vec<IpAddr> {
+    conf.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            (fields.next()? == "nameserver").then_some(())?;
+            // zone of link-local IPv6 addresses, which `IpAddr` can not hold
+            let addr = fields.next()?.split('%').next()?;
+            addr.parse().ok()
+        })
+        .collect()
+}
+
+/// A recursive query for the SRV records of `name`.
+fn encode_query(id: u16, name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut query = id.to_be_bytes().to_vec();
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("DNS error: invalid name {name:?}");
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    if query.len() > 12 + 255 {
+        anyhow::bail!("DNS error: name {name:?} is too long");
+    }
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Response {
+    Records(Vec<SrvRecord>),
+    /// The answer did not fit, ask again over TCP
+    Truncated,
+}
+
+/// The SRV records answered, without the `.` target which means the service
+/// is not offered.
+fn parse_response(msg: &[u8], id: u16) -> anyhow::Result<Response> {
+    let malformed = || anyhow::anyhow!("DNS error: malformed answer");
+    let u16_at = |pos: usize| -> anyhow::Result<u16> {
+        let bytes = msg.get(pos..pos + 2).ok_or_else(malformed)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    let flags = u16_at(2)?;
+    if u16_at(0)? != id || flags & 0x8000 == 0 {
+        anyhow::bail!("DNS error: not an answer to the query");
+    }
+    if flags & 0x0200 != 0 {
+        return Ok(Response::Truncated);
+    }
+    match flags & 0x000f {
+        0 => {}
+        2 => anyhow::bail!("DNS error: server failure"),
+        3 => anyhow::bail!("DNS error: no such name"),
+        5 => anyhow::bail!("DNS error: query refused"),
+        rcode => anyhow::bail!("DNS error: response code {rcode}"),
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let (rtype, class, rdlength) = (u16_at(pos)?, u16_at(pos + 2)?, u16_at(pos + 8)?);
+        let rdata = pos + 10;
+        pos = rdata + rdlength as usize;
+        if pos > msg.len() {
+            return Err(malformed());
+        }
+        // CNAMEs followed by the resolver come along
+        if rtype != TYPE_SRV || class != CLASS_IN {
+            continue;
+        }
+        let (target, _) = read_name(msg, rdata + 6)?;
+        if target.is_empty() {
+            continue;
+        }
+        records.push(SrvRecord {
+            priority: u16_at(rdata)?,
+            weight: u16_at(rdata + 2)?,
+            port: u16_at(rdata + 4)?,
+            target,
+        });
+    }
+    Ok(Response::Records(records))
+}
+
+/// The name at `pos` without the trailing dot, and the position after it.
+fn read_name(msg: &[u8], mut pos: usize) -> anyhow::Result<(String, usize)> {
+    let malformed = || anyhow::anyhow!("DNS error: malformed name");
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(pos).ok_or_else(malformed)? as usize;
+        match len {
+            0 => break,
+            len if len & 0xc0 == 0xc0 => {
+                let low = *msg.get(pos + 1).ok_or_else(malformed)? as usize;
+                end.get_or_insert(pos + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(malformed());
+                }
+                pos = ((len & 0x3f) << 8) | low;
+            }
+            len if len <= 63 => {
+                let label = msg.get(pos + 1..pos + 1 + len).ok_or_else(malformed)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(pos + 1)))
+}
+
+/// `records` in the order of RFC 2782: by priority, and within one by
+/// picking at random with a chance relative to the weight. `random` gives a
+/// fraction in `0..1`.
+fn order(mut records: Vec<SrvRecord>, mut random: impl FnMut() -> f64) -> Vec<SrvRecord> {
+    // records of weight 0 first, so they have a small chance to be picked
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+    let mut ordered = Vec::with_capacity(records.len());
+    let mut rest = records.as_slice();
+    while let Some(first) = rest.first() {
+        let same_priority = rest
+            .iter()
+            .take_while(|record| record.priority == first.priority)
+            .count();
+        let mut group = rest[..same_priority].to_vec();
+        rest = &rest[same_priority..];
+        while !group.is_empty() {
+            let total = group.iter().map(|r| r.weight as u64).sum::<u64>();
+            let pick = (random() * (total + 1) as f64) as u64;
+            let mut sum = 0;
+            let index = group
+                .iter()
+                .position(|record| {
+                    sum += record.weight as u64;
+                    sum >= pick
+                })
+                .unwrap_or(group.len() - 1);
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 8000,
+            target: target.to_owned(),
+        }
+    }
+
+    fn targets(records: &[SrvRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.target.as_str()).collect()
+    }
+
+    #[test]
+    fn test_parse_nameservers() {
+        let conf = "# generated\nsearch example.com\nnameserver 192.0.2.53\n\
+            nameserver fe80::1%eth0\nnameserver bogus\noptions edns0\n";
+        assert_eq!(
+            parse_nameservers(conf),
+            [
+                "192.0.2.53".parse::<IpAddr>().unwrap(),
+                "fe80::1".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query(0x1234, "_m._tcp.a.").unwrap();
+        assert_eq!(&query[..4], [0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&query[12..], b"\x02_m\x04_tcp\x01a\x00\x00\x21\x00\x01");
+        assert!(encode_query(0, "a..b").is_err());
+        assert!(encode_query(0, &"a".repeat(64)).is_err());
+    }
+
+    /// An answer to `encode_query(1, "_m._tcp.a")` with `answers` as the
+    /// SRV records, the targets pointing back at the question.
+    fn answer(flags: u16, answers: &[(u16, u16, u16, &[u8])]) -> Vec<u8> {
+        let mut msg = encode_query(1, "_m._tcp.a").unwrap();
+        msg[2..4].copy_from_slice(&flags.to_be_bytes());
+        msg[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for (priority, weight, port, target) in answers {
+            // name pointing at the question
+            msg.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 1, 0]);
+            msg.extend_from_slice(&(6 + target.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&priority.to_be_bytes());
+            msg.extend_from_slice(&weight.to_be_bytes());
+            msg.extend_from_slice(&port.to_be_bytes());
+            msg.extend_from_slice(target);
+        }
+        msg
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let msg = answer(
+            0x8180,
+            &[
+                (10, 5, 8000, b"\x03one\x07example\x00"),
+                // `b.` and a pointer to the `a` of the question
+                (20, 0, 8443, b"\x01b\xc0\x14"),
+                (30, 0, 0, b"\x00"),
+            ],
+        );
+        let Response::Records(records) = parse_response(&msg, 1).unwrap() else {
+            panic!("truncated");
+        };
+        assert_eq!(
+            records,
+            [
+                SrvRecord {
+                    priority: 10,
+                    weight: 5,
+                    port: 8000,
+                    target: "one.example".to_owned()
+                },
+                SrvRecord {
+                    priority: 20,
+                    weight: 0,
+                    port: 8443,
+                    target: "b.a".to_owned()
+                },
+            ]
+        );
+
+        assert_eq!(
+            parse_response(&answer(0x8380, &[]), 1).unwrap(),
+            Response::Truncated
+        );
+        assert!(parse_response(&answer(0x8183, &[]), 1).is_err());
+        assert!(parse_response(&answer(0x8180, &[]), 2).is_err());
+        // the query itself is no answer
+        assert!(parse_response(&encode_query(1, "a").unwrap(), 1).is_err());
+
+        let mut cut = msg.clone();
+        cut.truncate(msg.len() - 3);
+        assert!(parse_response(&cut, 1).is_err());
+        // a pointer to itself
+        let mut looped = answer(0x8180, &[(10, 5, 8000, b"\xc0\x00")]);
+        let pos = looped.len() - 2;
+        looped[pos + 1] = pos as u8;
+        assert!(parse_response(&looped, 1).is_err());
+    }
+
+    #[test]
+    fn test_order() {
+        let records = vec![
+            record(20, 0, "backup"),
+            record(10, 1, "light"),
+            record(10, 0, "zero"),
+            record(10, 9, "heavy"),
+        ];
+        // the lowest fraction picks the first record left
+        assert_eq!(
+            targets(&order(records.clone(), || 0.0)),
+            ["zero", "light", "heavy", "backup"]
+        );
+        assert_eq!(
+            targets(&order(records.clone(), || 0.99)),
+            ["heavy", "light", "zero", "backup"]
+        );
+
+        let heavy_first = (0..1000)
+            .filter(|_| order(records.clone(), random_fraction)[0].target == "heavy")
+            .count();
+        assert!((700..950).contains(&heavy_first), "{heavy_first}");
+        assert!(order(Vec::new(), || 0.5).is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+//! Keeps the client alive: panics are logged and the session restarted with
+//! a fresh collector instead of taking the process down, and a watchdog ends
+//! the process if a runtime stops making progress, e.g. on a hung system
+//! call, so a service manager can restart it.
+
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::FutureExt;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A component panicked, the message is the panic payload.
+#[derive(Debug, thiserror::Error)]
+#[error("{component} panicked: {message}")]
+pub struct Panicked {
+    component: &'static str,
+    message: String,
+}
+
+/// Log panics through the logger, with the thread and location, before they
+/// unwind into [`catch_panic`].
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        log::error!(
+            "Panic in thread '{}': {info}",
+            thread.name().unwrap_or("<unnamed>")
+        );
+    }));
+}
+
+/// Run `fut` to completion, turning a panic into [`Panicked`].
+pub async fn catch_panic<F: Future>(
+    component: &'static str,
+    fut: F,
+) -> Result<F::Output, Panicked> {
+    AssertUnwindSafe(fut)
+        .catch_unwind()
+        .await
+        .map_err(|payload| Panicked {
+            component,
+            message: panic_message(payload.as_ref()),
+        })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "<non-string payload>".to_owned()
+    }
+}
+
+/// Ends the process when a runtime it watches misses its heartbeat.
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    start: Instant,
+    beats: Arc<Mutex<Vec<Heartbeat>>>,
+}
+
+/// Heartbeat of a watched runtime.
+#[derive(Debug)]
+struct Heartbeat {
+    /// Name of the thread running the runtime
+    thread: String,
+    /// Seconds since `start` of the last beat, gone once the runtime is
+    /// dropped. 32 bits as targets like MIPS have no 64-bit atomics
+    last_beat: Weak<AtomicU32>,
+}
+
+impl Watchdog {
+    /// Watch the runtime this is called on as well.
+    pub fn watch_current_runtime(&self) {
+        let start = self.start;
+        let last_beat = Arc::new(AtomicU32::new(start.elapsed().as_secs() as u32));
+        let thread = std::thread::current()
+            .name()
+            .unwrap_or("<unnamed>")
+            .to_owned();
+        self.beats
+            .lock()
+            .expect("watchdog lock poisoned")
+            .push(Heartbeat {
+                thread,
+                last_beat: Arc::downgrade(&last_beat),
+            });
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                last_beat.store(start.elapsed().as_secs() as u32, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+/// End the process if the current runtime, or another one watched later,
+/// misses its heartbeat for `timeout`.
+///
+/// The heartbeat is a task on the runtime, the check runs on its own thread
+/// so it still fires while the runtime thread is blocked.
+pub fn spawn_watchdog(timeout: Duration) -> Watchdog {
+    let watchdog = Watchdog {
+        start: Instant::now(),
+        beats: Default::default(),
+    };
+    watchdog.watch_current_runtime();
+
+    let (start, beats) = (watchdog.start, watchdog.beats.clone());
+    std::thread::Builder::new()
+        .name("watchdog".to_owned())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(HEARTBEAT_INTERVAL);
+                let now = start.elapsed().as_secs();
+                let mut beats = beats.lock().expect("watchdog lock poisoned");
+                beats.retain(|beat| beat.last_beat.strong_count() > 0);
+                for beat in beats.iter() {
+                    let Some(last_beat) = beat.last_beat.upgrade() else {
+                        continue;
+                    };
+                    let stalled = now.saturating_sub(last_beat.load(Ordering::Relaxed).into());
+                    if stalled >= timeout.as_secs() {
+                        log::error!(
+                            "Runtime of thread '{}' stalled for {stalled} seconds, exiting",
+                            beat.thread
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+        })
+        .expect("failed to spawn the watchdog thread");
+    watchdog
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        let res = catch_panic("egress", async { 42 }).await;
+        assert_eq!(res.unwrap(), 42);
+
+        let res = catch_panic("egress", async {
+            panic!("sensor {} vanished", 3);
+        })
+        .await;
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "egress panicked: sensor 3 vanished"
+        );
+
+        let res = catch_panic("egress", async {
+            std::panic::panic_any(7);
+        })
+        .await;
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "egress panicked: <non-string payload>"
+        );
+    }
+}
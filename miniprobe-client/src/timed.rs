@@ -0,0 +1,134 @@
+//! Collectors that may hang, e.g. on a stuck mount or an unresponsive
+//! driver, run on the blocking pool with a timeout.
+//!
+//! A collector that misses its timeout keeps running in the background and
+//! its part of the sample is missing until it returns. It is not started
+//! again in the meantime, so a collector stuck for good holds one thread.
+
+use std::time::Duration;
+
+use tokio::{sync::oneshot, task::JoinHandle};
+
+/// State of one collector, moved to the blocking pool for every query.
+#[derive(Debug)]
+pub struct Timed<S> {
+    /// Collector name for the logs
+    name: &'static str,
+    state: TimedState<S>,
+}
+
+#[derive(Debug)]
+enum TimedState<S> {
+    Idle(S),
+    /// Still running after its timeout, the state comes back with it
+    Hung(JoinHandle<S>),
+    /// Only while a query runs
+    Running,
+}
+
+impl<S: Send + 'static> Timed<S> {
+    pub fn new(name: &'static str, state: S) -> Self {
+        Timed {
+            name,
+            state: TimedState::Idle(state),
+        }
+    }
+
+    /// Run `query` on the state, `None` if it takes longer than `timeout` or
+    /// an earlier query still hangs. A panic of `query` is passed on.
+    pub async fn run<T, F>(&mut self, timeout: Duration, query: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut S) -> T + Send + 'static,
+    {
+        let mut state = match std::mem::replace(&mut self.state, TimedState::Running) {
+            TimedState::Idle(state) => state,
+            TimedState::Hung(handle) if handle.is_finished() => {
+                log::info!("{} collector is back", self.name);
+                join(handle).await
+            }
+            TimedState::Hung(handle) => {
+                log::debug!("{} collector still hangs", self.name);
+                self.state = TimedState::Hung(handle);
+                return None;
+            }
+            TimedState::Running => unreachable!("{} collector queried twice", self.name),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::task::spawn_blocking(move || {
+            let _ = tx.send(query(&mut state));
+            state
+        });
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(value)) => {
+                self.state = TimedState::Idle(join(handle).await);
+                Some(value)
+            }
+            // dropped without a value, the query panicked
+            Ok(Err(_)) => {
+                join(handle).await;
+                unreachable!("{} collector ended without a value", self.name)
+            }
+            Err(_) => {
+                log::warn!(
+                    "{} collector did not finish in {timeout:?}, its metrics are missing until it does",
+                    self.name
+                );
+                self.state = TimedState::Hung(handle);
+                None
+            }
+        }
+    }
+}
+
+/// The state returned by a finished query, resuming its panic if it had one.
+async fn join<S>(handle: JoinHandle<S>) -> S {
+    match handle.await {
+        Ok(state) => state,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => panic!("collector task failed: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn test_timed() {
+        let mut timed = Timed::new("test", 0u32);
+        let value = timed
+            .run(TIMEOUT, |count| {
+                *count += 1;
+                *count
+            })
+            .await;
+        assert_eq!(value, Some(1));
+
+        // hangs past its timeout, then keeps the state it returns with
+        let value = timed
+            .run(TIMEOUT, |count| {
+                std::thread::sleep(TIMEOUT * 3);
+                *count += 1;
+                *count
+            })
+            .await;
+        assert_eq!(value, None);
+        assert_eq!(timed.run(TIMEOUT, |count| *count).await, None);
+
+        tokio::time::sleep(TIMEOUT * 4).await;
+        assert_eq!(timed.run(TIMEOUT, |count| *count).await, Some(2));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "collector broke")]
+    async fn test_timed_panic() {
+        let mut timed = Timed::new("test", ());
+        timed
+            .run(TIMEOUT, |_| -> () { panic!("collector broke") })
+            .await;
+    }
+}
@@ -0,0 +1,182 @@
+//! Thresholds that make a sample urgent, so it is sent right away instead
+//! of waiting for the next batch and the server evaluates alerts early.
+
+use std::str::FromStr;
+
+use miniprobe_proto::{DynamicMetrics, Section, metrics_math};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UrgentMetric {
+    /// Average CPU usage in percent
+    Cpu,
+    /// Used memory in percent
+    Memory,
+    /// Used swap in percent
+    Swap,
+    /// Degrees Celsius
+    CpuTemperature,
+}
+
+/// `METRIC=VALUE`, e.g. `cpu=95` or `cpu_temperature=90`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UrgentThreshold {
+    metric: UrgentMetric,
+    value: f32,
+}
+
+impl FromStr for UrgentThreshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (metric, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected METRIC=VALUE, got '{s}'"))?;
+        let metric = match metric.trim() {
+            "cpu" => UrgentMetric::Cpu,
+            "memory" => UrgentMetric::Memory,
+            "swap" => UrgentMetric::Swap,
+            "cpu_temperature" => UrgentMetric::CpuTemperature,
+            other => {
+                return Err(format!(
+                    "unknown metric '{other}', expected cpu, memory, swap or cpu_temperature"
+                ));
+            }
+        };
+        let value = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid threshold '{value}'"))?;
+        Ok(UrgentThreshold { metric, value })
+    }
+}
+
+impl UrgentThreshold {
+    /// The value of the metric, `None` if the sample has none.
+    fn observe(&self, metrics: &DynamicMetrics<'_>) -> Option<f32> {
+        let section = match self.metric {
+            UrgentMetric::Cpu => Section::Cpu,
+            UrgentMetric::Memory | UrgentMetric::Swap => Section::Memory,
+            UrgentMetric::CpuTemperature => Section::Sensors,
+        };
+        if metrics.is_missing(section) {
+            return None;
+        }
+        let percent =
+            |used: u64, total: u64| (total > 0).then(|| used as f32 / total as f32 * 100.0);
+        match self.metric {
+            UrgentMetric::Cpu => Some(metrics_math::narrow(metrics.cpu.usage())),
+            UrgentMetric::Memory => percent(metrics.memory.used, metrics.memory.total),
+            UrgentMetric::Swap => percent(metrics.memory.swap_used, metrics.memory.swap_total),
+            UrgentMetric::CpuTemperature => metrics.sensors.cpu_temperature,
+        }
+    }
+}
+
+/// Tracks which thresholds are exceeded, a sample is urgent when one is
+/// crossed upwards.
+#[derive(Debug, Default)]
+pub struct UrgentWatch {
+    thresholds: Vec<(UrgentThreshold, bool)>,
+}
+
+impl UrgentWatch {
+    pub fn new(thresholds: &[UrgentThreshold]) -> Self {
+        UrgentWatch {
+            thresholds: thresholds.iter().map(|t| (*t, false)).collect(),
+        }
+    }
+
+    pub fn check(&mut self, metrics: &DynamicMetrics<'_>) -> bool {
+        let mut urgent = false;
+        for (threshold, exceeded) in &mut self.thresholds {
+            let Some(value) = threshold.observe(metrics) else {
+                continue;
+            };
+            let now_exceeded = value >= threshold.value;
+            if now_exceeded && !*exceeded {
+                log::debug!(
+                    "{:?} crossed {} with {value}",
+                    threshold.metric,
+                    threshold.value
+                );
+                urgent = true;
+            }
+            *exceeded = now_exceeded;
+        }
+        urgent
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use miniprobe_proto::{CpuReport, MemoryMetrics, NetworkMetrics, UnixMillis};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!(
+            "cpu=95".parse(),
+            Ok(UrgentThreshold {
+                metric: UrgentMetric::Cpu,
+                value: 95.0
+            })
+        );
+        assert!("cpu".parse::<UrgentThreshold>().is_err());
+        assert!("disk=90".parse::<UrgentThreshold>().is_err());
+        assert!("memory=lots".parse::<UrgentThreshold>().is_err());
+    }
+
+    fn metrics(cpu_usage: f32) -> DynamicMetrics<'static> {
+        DynamicMetrics {
+            seq: 0,
+            sample_time: UnixMillis(0),
+            cpu: CpuReport::Aggregate {
+                usage: cpu_usage,
+                max_core: cpu_usage,
+            },
+            memory: MemoryMetrics {
+                total: 0,
+                used: 0,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: "eth0".into(),
+                rx_bytes: None,
+                tx_bytes: None,
+            }],
+            disks: Vec::new(),
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: None,
+            services: Vec::new(),
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_check_edges() {
+        let mut watch = UrgentWatch::new(&["cpu=90".parse().unwrap()]);
+        let mut check = |usage| watch.check(&metrics(usage));
+
+        assert!(!check(50.0));
+        assert!(check(95.0));
+        // only the crossing is urgent
+        assert!(!check(99.0));
+        assert!(!check(10.0));
+        assert!(check(90.0));
+
+        // a sample without CPU usage keeps the threshold exceeded
+        let mut missing = metrics(0.0);
+        missing.missing_sections.push(Section::Cpu);
+        assert!(!watch.check(&missing));
+        assert!(!watch.check(&metrics(95.0)));
+    }
+}
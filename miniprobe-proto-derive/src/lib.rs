@@ -0,0 +1,196 @@
+//! `#[derive(Validate)]` of `miniprobe_proto::validate::Validate`, checking
+//! the fields annotated with
+//!
+//! - `#[validate(range(0.0..=100.0))]`: the value is within the range, an
+//!   `Option` only if `Some`. `NaN` is within no range
+//! - `#[validate(nested)]`: the value, every item of a `Vec` or the value of
+//!   an `Option` is valid itself
+//!
+//! Fields without the attribute are not checked. On an enum variant, the
+//! attribute applies to every field of the variant.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    Attribute, Data, DeriveInput, Expr, Fields, Ident, Index, Member, Type, parse_macro_input,
+    spanned::Spanned,
+};
+
+#[proc_macro_derive(Validate, attributes(validate))]
+pub fn derive_validate(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Clone)]
+enum Rule {
+    Range(Expr),
+    Nested,
+}
+
+/// How a field holds its value.
+enum Shape {
+    Plain,
+    Option,
+    Vec,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, checks) = destructure(&data.fields, &[])?;
+            quote! {
+                let Self #pattern = self;
+                #(#checks)*
+            }
+        }
+        Data::Enum(data) => {
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let name = &variant.ident;
+                    let rules = rules(&variant.attrs)?;
+                    let (pattern, checks) = destructure(&variant.fields, &rules)?;
+                    Ok(quote! { Self::#name #pattern => { #(#checks)* } })
+                })
+                .collect::<syn::Result<Vec<_>>>()?;
+            quote! {
+                #[allow(unused_variables)]
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                input.span(),
+                "`Validate` can not be derived for unions",
+            ));
+        }
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::miniprobe_proto::validate::Validate for #name #ty_generics
+            #where_clause
+        {
+            #[allow(unused_variables)]
+            fn validate(&self) -> ::core::result::Result<(), ::miniprobe_proto::validate::ValidationError> {
+                #body
+                ::core::result::Result::Ok(())
+            }
+        }
+    })
+}
+
+/// A pattern binding every field and the checks of the annotated ones, every
+/// field is checked against `all` too.
+fn destructure(fields: &Fields, all: &[Rule]) -> syn::Result<(TokenStream2, Vec<TokenStream2>)> {
+    let mut bindings = Vec::new();
+    let mut checks = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let binding = match &field.ident {
+            Some(ident) => format_ident!("__{ident}"),
+            None => format_ident!("__{i}"),
+        };
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        };
+        bindings.push(quote! { #member: #binding });
+
+        // tuple fields are left out of the path, e.g. `cpu[2].usage`
+        let path = field
+            .ident
+            .as_ref()
+            .map_or(String::new(), |ident| ident.to_string());
+        for rule in all.iter().cloned().chain(rules(&field.attrs)?) {
+            checks.push(check(&binding, &path, shape(&field.ty), rule));
+        }
+    }
+    let pattern = match fields {
+        Fields::Unit => quote! {},
+        _ => quote! { { #(#bindings,)* .. } },
+    };
+    Ok((pattern, checks))
+}
+
+fn rules(attrs: &[Attribute]) -> syn::Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("validate")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("nested") {
+                rules.push(Rule::Nested);
+                Ok(())
+            } else if meta.path.is_ident("range") {
+                let content;
+                syn::parenthesized!(content in meta.input);
+                rules.push(Rule::Range(content.parse()?));
+                Ok(())
+            } else {
+                Err(meta.error("expected `range(..)` or `nested`"))
+            }
+        })?;
+    }
+    Ok(rules)
+}
+
+fn shape(ty: &Type) -> Shape {
+    let Type::Path(path) = ty else {
+        return Shape::Plain;
+    };
+    match path.path.segments.last() {
+        Some(segment) if segment.ident == "Option" => Shape::Option,
+        Some(segment) if segment.ident == "Vec" => Shape::Vec,
+        _ => Shape::Plain,
+    }
+}
+
+fn check(binding: &Ident, path: &str, shape: Shape, rule: Rule) -> TokenStream2 {
+    let validate = match rule {
+        Rule::Range(range) => {
+            let text = quote!(#range).to_string().replace(' ', "");
+            quote! {
+                if !(#range).contains(value) {
+                    return ::core::result::Result::Err(
+                        ::miniprobe_proto::validate::ValidationError::new(value, #text),
+                    );
+                }
+            }
+        }
+        Rule::Nested => quote! {
+            ::miniprobe_proto::validate::Validate::validate(value)?;
+        },
+    };
+    // the error of a value is moved under the path of its field
+    let within = quote! {
+        (|| -> ::core::result::Result<(), ::miniprobe_proto::validate::ValidationError> {
+            #validate
+            ::core::result::Result::Ok(())
+        })()
+    };
+    match shape {
+        Shape::Plain => quote! {
+            {
+                let value = #binding;
+                #within.map_err(|e| e.within(#path))?;
+            }
+        },
+        Shape::Option => quote! {
+            if let ::core::option::Option::Some(value) = #binding {
+                #within.map_err(|e| e.within(#path))?;
+            }
+        },
+        Shape::Vec => quote! {
+            for (i, value) in #binding.iter().enumerate() {
+                #within.map_err(|e| e.at(i).within(#path))?;
+            }
+        },
+    }
+}
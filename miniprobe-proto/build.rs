@@ -0,0 +1,85 @@
+//! Hash the serialized types of `src/lib.rs` and `src/msg.rs` into
+//! `METRICS_SCHEMA_HASH`.
+//!
+//! Only the items deriving `Serialize` or `Deserialize` count, and type
+//! aliases, parsed with syn and printed back as tokens, so formatting,
+//! documentation and `impl` blocks can change freely while any change of a
+//! field, variant or their order changes the hash. Attributes other than
+//! `#[serde(..)]` are left out, like `#[validate(..)]`, they do not change the
+//! encoding.
+
+use std::{env, fs, path::Path};
+
+use quote::ToTokens;
+use syn::{Attribute, Fields, Item};
+
+const SOURCES: &[&str] = &["src/lib.rs", "src/msg.rs"];
+
+fn main() {
+    let mut schema = String::new();
+    for path in SOURCES {
+        println!("cargo::rerun-if-changed={path}");
+        let source =
+            fs::read_to_string(path).unwrap_or_else(|e| panic!("failed to read {path}: {e}"));
+        let file =
+            syn::parse_file(&source).unwrap_or_else(|e| panic!("failed to parse {path}: {e}"));
+        schema.push_str(&self::schema(file.items));
+    }
+    let hash = fnv1a(schema.as_bytes());
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("schema_hash.rs");
+    fs::write(out, format!("{hash:#018x}")).expect("failed to write schema_hash.rs");
+}
+
+/// The serialized items, one per line.
+fn schema(items: Vec<Item>) -> String {
+    let mut schema = String::new();
+    for mut item in items {
+        match &mut item {
+            Item::Struct(item) if serialized(&item.attrs) => {
+                item.attrs.retain(is_serde);
+                strip_fields(&mut item.fields);
+            }
+            Item::Enum(item) if serialized(&item.attrs) => {
+                item.attrs.retain(is_serde);
+                for variant in &mut item.variants {
+                    variant.attrs.retain(is_serde);
+                    strip_fields(&mut variant.fields);
+                }
+            }
+            Item::Type(item) => item.attrs.retain(is_serde),
+            _ => continue,
+        }
+        schema.push_str(&item.to_token_stream().to_string());
+        schema.push('\n');
+    }
+    schema
+}
+
+/// Whether `attrs` derive `Serialize` or `Deserialize`.
+fn serialized(attrs: &[Attribute]) -> bool {
+    let mut serialized = false;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("derive")) {
+        let _ = attr.parse_nested_meta(|meta| {
+            serialized |= meta.path.is_ident("Serialize") || meta.path.is_ident("Deserialize");
+            Ok(())
+        });
+    }
+    serialized
+}
+
+fn is_serde(attr: &Attribute) -> bool {
+    attr.path().is_ident("serde")
+}
+
+fn strip_fields(fields: &mut Fields) {
+    for field in fields {
+        field.attrs.retain(is_serde);
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,48 @@
+//! Train the zstd dictionary of `Compression::Zstd` on samples recorded by
+//! clients with `--offline --output-format postcard`:
+//!
+//! ```sh
+//! cargo run -p miniprobe-proto --example train_dict -- dict/samples.zdict <dir>...
+//! ```
+//!
+//! Record samples of different machines and CPU report shapes, a few hundred
+//! of each. A new dictionary can not decode messages compressed with the old
+//! one, see `codec`.
+
+use std::{env, fs, path::Path};
+
+use miniprobe_proto::record::Records;
+
+/// Small enough to ship in every binary, large enough for the field layout
+/// and common strings.
+const MAX_DICT_SIZE: usize = 8 * 1024;
+
+fn main() -> std::io::Result<()> {
+    let mut args = env::args().skip(1);
+    let (Some(output), dirs) = (args.next(), args.collect::<Vec<_>>()) else {
+        eprintln!("usage: train_dict <output> <dir>...");
+        std::process::exit(2);
+    };
+
+    let mut samples = Vec::new();
+    for dir in &dirs {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("postcard") {
+                read_samples(&path, &mut samples)?;
+            }
+        }
+    }
+    println!("training on {} samples", samples.len());
+
+    let dict = zstd::dict::from_samples(&samples, MAX_DICT_SIZE)?;
+    fs::write(&output, &dict)?;
+    println!("wrote {} bytes to {output}", dict.len());
+    Ok(())
+}
+
+fn read_samples(path: &Path, samples: &mut Vec<Vec<u8>>) -> std::io::Result<()> {
+    let bytes = fs::read(path)?;
+    samples.extend(Records(&bytes).map(<[u8]>::to_vec));
+    Ok(())
+}
@@ -0,0 +1,283 @@
+//! Compression of the binary messages on the ingress websocket.
+//!
+//! The client lists the compressions it can send in the
+//! [`COMPRESSION_PARAM`](crate::msg::COMPRESSION_PARAM) query parameter, the
+//! server picks the first one it supports with [`negotiate`] and names it in
+//! the [`COMPRESSION_HEADER`](crate::msg::COMPRESSION_HEADER) of its upgrade
+//! response. Both sides fall back to [`Compression::Identity`] when the other
+//! one predates compression. Every message is compressed on its own, text
+//! frames and control messages are never compressed.
+//!
+//! Codecs other than identity are behind the `deflate` and `zstd` features.
+//! Single samples are too small for zstd to find repetitions in, so it starts
+//! from [`SAMPLES_DICT`], trained on samples recorded by clients with
+//! `--offline --output-format postcard` by `examples/train_dict.rs`. The
+//! dictionary is part of the wire format, changing it needs a new
+//! [`Compression`] variant.
+
+use std::{borrow::Cow, io};
+
+pub use crate::limits::MAX_DECODED_SIZE;
+use crate::msg::Compression;
+
+/// Zstandard dictionary of [`Compression::Zstd`].
+#[cfg(feature = "zstd")]
+pub static SAMPLES_DICT: &[u8] = include_bytes!("../dict/samples.zdict");
+
+#[cfg(feature = "deflate")]
+const DEFLATE_LEVEL: u8 = 6;
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    /// Compressions of this build, preferred first.
+    pub fn supported() -> Vec<Compression> {
+        let mut supported = Vec::new();
+        if cfg!(feature = "zstd") {
+            supported.push(Compression::Zstd);
+        }
+        if cfg!(feature = "deflate") {
+            supported.push(Compression::Deflate);
+        }
+        supported.push(Compression::Identity);
+        supported
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Compression::Identity => "identity",
+            Compression::Deflate => "deflate",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "identity" => Ok(Compression::Identity),
+            "deflate" => Ok(Compression::Deflate),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "invalid compression '{s}', expected identity, deflate or zstd"
+            )),
+        }
+    }
+}
+
+/// The first compression of the comma separated `offered` ones this build
+/// supports, unknown ones are skipped.
+pub fn negotiate(offered: &str) -> Compression {
+    let supported = Compression::supported();
+    offered
+        .split(',')
+        .filter_map(|name| name.trim().parse().ok())
+        .find(|compression| supported.contains(compression))
+        .unwrap_or(Compression::Identity)
+}
+
+/// Compresses the messages of one connection.
+pub struct Encoder {
+    compression: Compression,
+    #[cfg(feature = "zstd")]
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl Encoder {
+    /// Fails if this build does not support `compression`.
+    pub fn new(compression: Compression) -> io::Result<Self> {
+        check_supported(compression)?;
+        Ok(Encoder {
+            compression,
+            #[cfg(feature = "zstd")]
+            zstd: match compression {
+                Compression::Zstd => Some(zstd::bulk::Compressor::with_dictionary(
+                    ZSTD_LEVEL,
+                    SAMPLES_DICT,
+                )?),
+                _ => None,
+            },
+        })
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn encode<'a>(&mut self, message: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        match self.compression {
+            Compression::Identity => Ok(Cow::Borrowed(message)),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => Ok(Cow::Owned(miniz_oxide::deflate::compress_to_vec(
+                message,
+                DEFLATE_LEVEL,
+            ))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let zstd = self.zstd.as_mut().expect("created with the encoder");
+                Ok(Cow::Owned(zstd.compress(message)?))
+            }
+            #[allow(unreachable_patterns)]
+            compression => Err(unsupported(compression)),
+        }
+    }
+}
+
+/// Decompresses the messages of one connection.
+pub struct Decoder {
+    compression: Compression,
+    #[cfg(feature = "zstd")]
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+impl Decoder {
+    /// Fails if this build does not support `compression`.
+    pub fn new(compression: Compression) -> io::Result<Self> {
+        check_supported(compression)?;
+        Ok(Decoder {
+            compression,
+            #[cfg(feature = "zstd")]
+            zstd: match compression {
+                Compression::Zstd => Some(zstd::bulk::Decompressor::with_dictionary(SAMPLES_DICT)?),
+                _ => None,
+            },
+        })
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// The message as sent, at most [`MAX_DECODED_SIZE`] bytes. Uncompressed
+    /// messages are borrowed so samples can keep borrowing from them.
+    pub fn decode<'a>(&mut self, message: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        match self.compression {
+            Compression::Identity => Ok(Cow::Borrowed(message)),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => {
+                miniz_oxide::inflate::decompress_to_vec_with_limit(message, MAX_DECODED_SIZE)
+                    .map(Cow::Owned)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let zstd = self.zstd.as_mut().expect("created with the decoder");
+                Ok(Cow::Owned(zstd.decompress(message, MAX_DECODED_SIZE)?))
+            }
+            #[allow(unreachable_patterns)]
+            compression => Err(unsupported(compression)),
+        }
+    }
+}
+
+fn check_supported(compression: Compression) -> io::Result<()> {
+    if Compression::supported().contains(&compression) {
+        Ok(())
+    } else {
+        Err(unsupported(compression))
+    }
+}
+
+fn unsupported(compression: Compression) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} compression is not supported by this build",
+            compression.as_str()
+        ),
+    )
+}
+
+#[cfg(all(test, feature = "deflate", feature = "zstd"))]
+mod tests {
+    use super::*;
+    use crate::{CpuMetrics, CpuReport, DynamicMetrics, MemoryMetrics, NetworkMetrics, UnixMillis};
+
+    fn sample() -> Vec<u8> {
+        let sample = DynamicMetrics {
+            seq: 42,
+            sample_time: UnixMillis(1_760_000_000_000),
+            cpu: CpuReport::PerCore(vec![CpuMetrics { usage: 12.5 }; 8]),
+            memory: MemoryMetrics {
+                total: 16 * 1024 * 1024 * 1024,
+                used: 6 * 1024 * 1024 * 1024,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: "eth0".into(),
+                rx_bytes: Some(123_456_789),
+                tx_bytes: Some(98_765_432),
+            }],
+            disks: Vec::new(),
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: None,
+            services: Vec::new(),
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        };
+        postcard::to_extend(&sample, Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = sample();
+        for compression in [
+            Compression::Identity,
+            Compression::Deflate,
+            Compression::Zstd,
+        ] {
+            let mut encoder = Encoder::new(compression).unwrap();
+            let mut decoder = Decoder::new(compression).unwrap();
+            // contexts are reused between messages
+            for _ in 0..2 {
+                let encoded = encoder.encode(&message).unwrap();
+                assert_eq!(decoder.decode(&encoded).unwrap(), message);
+            }
+        }
+        let zstd = Encoder::new(Compression::Zstd)
+            .unwrap()
+            .encode(&message)
+            .unwrap()
+            .len();
+        assert!(zstd < message.len(), "{zstd} >= {}", message.len());
+    }
+
+    #[test]
+    fn decode_limit() {
+        let bomb = vec![0; MAX_DECODED_SIZE + 1];
+        for compression in [Compression::Deflate, Compression::Zstd] {
+            let encoded = Encoder::new(compression)
+                .unwrap()
+                .encode(&bomb)
+                .unwrap()
+                .into_owned();
+            assert!(Decoder::new(compression).unwrap().decode(&encoded).is_err());
+        }
+        assert!(
+            Decoder::new(Compression::Zstd)
+                .unwrap()
+                .decode(b"not zstd")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate("zstd,deflate"), Compression::Zstd);
+        assert_eq!(negotiate("brotli, deflate"), Compression::Deflate);
+        assert_eq!(negotiate(""), Compression::Identity);
+        assert_eq!(
+            "zstd".parse::<Compression>().unwrap().as_str(),
+            Compression::Zstd.as_str()
+        );
+    }
+}
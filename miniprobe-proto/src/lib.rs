@@ -1,20 +1,230 @@
+use std::{
+    borrow::Cow,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use serde::{Deserialize, Serialize};
 
+use crate::validate::Validate;
+
+// the derive of `Validate` names this crate by its path
+extern crate self as miniprobe_proto;
+
+pub mod codec;
+pub mod limits;
+pub mod metrics_math;
 pub mod msg;
+pub mod record;
+pub mod validate;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DynamicMetrics {
-    pub sample_time: u64,
-    pub cpu: Vec<CpuMetrics>,
+/// Hash of the serialized types of this module, generated by `build.rs`.
+/// Peers with different hashes can not decode each other's samples.
+pub const METRICS_SCHEMA_HASH: u64 = include!(concat!(env!("OUT_DIR"), "/schema_hash.rs"));
+
+/// A sample of a client.
+///
+/// Strings borrow from the message when decoded with `postcard::from_bytes`
+/// or `serde_json::from_str`, so the server does not allocate them for every
+/// sample. [`into_owned`](Self::into_owned) detaches the sample to keep it.
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct DynamicMetrics<'a> {
+    /// Number of the sample, unique for its client across sessions, so the
+    /// server can drop samples sent twice, e.g. resent after reconnecting.
+    /// Counts up from the sample time in milliseconds
+    pub seq: u64,
+    pub sample_time: UnixMillis,
+    #[validate(nested)]
+    pub cpu: CpuReport,
     pub memory: MemoryMetrics,
-    pub network: NetworkMetrics,
+    /// Every interface the client reports on, the physical ones but loopback
+    /// unless configured otherwise on the client
+    #[serde(borrow)]
+    pub networks: Vec<NetworkMetrics<'a>>,
+    /// Mounted filesystems, empty where sysinfo lists none
+    #[serde(borrow)]
+    pub disks: Vec<DiskMetrics<'a>>,
+    #[validate(nested)]
+    pub sensors: SensorMetrics,
+    #[validate(nested)]
+    pub probe: ProbeSelfMetrics,
+    /// `None` where the processes can not be listed, e.g. on OpenBSD
+    pub processes: Option<ProcessMetrics>,
+    /// Only collected on Linux, `None` elsewhere
+    pub fds: Option<FdMetrics>,
+    /// `None` if the client can not tell, e.g. without chrony or
+    /// systemd-timedated
+    #[validate(nested)]
+    pub clock: Option<ClockMetrics>,
+    /// Only collected when enabled on the client, `None` without a battery
+    #[validate(nested)]
+    pub battery: Option<BatteryMetrics>,
+    /// Watched systemd units, empty unless configured on the client
+    #[serde(borrow)]
+    pub services: Vec<ServiceMetrics<'a>>,
+    /// Listening sockets, only sent at the start of a session and when they
+    /// changed, `None` in between or if not enabled on the client
+    pub listeners: Option<Vec<ListeningSocket>>,
+    /// Primary addresses of the host, only sent at the start of a session and
+    /// when they changed, `None` in between
+    pub addresses: Option<HostAddresses>,
+    /// A configured threshold was crossed, the client sent the sample early
+    /// and the server evaluates alerts ahead of its interval, at most every
+    /// few seconds
+    pub urgent: bool,
+    /// Sections whose collectors did not finish in time, their fields hold
+    /// placeholders that are not stored
+    pub missing_sections: Vec<Section>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl DynamicMetrics<'_> {
+    pub fn is_missing(&self, section: Section) -> bool {
+        self.missing_sections.contains(&section)
+    }
+
+    pub fn into_owned(self) -> DynamicMetrics<'static> {
+        DynamicMetrics {
+            networks: self
+                .networks
+                .into_iter()
+                .map(NetworkMetrics::into_owned)
+                .collect(),
+            disks: self
+                .disks
+                .into_iter()
+                .map(DiskMetrics::into_owned)
+                .collect(),
+            services: self
+                .services
+                .into_iter()
+                .map(ServiceMetrics::into_owned)
+                .collect(),
+            ..self
+        }
+    }
+}
+
+/// Part of a sample filled by one collector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Section {
+    Cpu,
+    Memory,
+    Network,
+    Sensors,
+    Processes,
+    Fds,
+    Clock,
+    Battery,
+    Services,
+    Listeners,
+    Disks,
+    Addresses,
+}
+
+impl Section {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Section::Cpu => "cpu",
+            Section::Memory => "memory",
+            Section::Network => "network",
+            Section::Sensors => "sensors",
+            Section::Processes => "processes",
+            Section::Fds => "fds",
+            Section::Clock => "clock",
+            Section::Battery => "battery",
+            Section::Services => "services",
+            Section::Listeners => "listeners",
+            Section::Disks => "disks",
+            Section::Addresses => "addresses",
+        }
+    }
+}
+
+/// Unix time in milliseconds.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct UnixMillis(pub u64);
+
+impl UnixMillis {
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    pub fn from_secs(secs: u64) -> Self {
+        UnixMillis(secs * 1000)
+    }
+
+    /// Whole seconds, rounded down.
+    pub fn as_secs(self) -> u64 {
+        self.0 / 1000
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later.
+    pub fn saturating_duration_since(self, earlier: UnixMillis) -> Duration {
+        Duration::from_millis(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl From<SystemTime> for UnixMillis {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        UnixMillis(since_epoch.as_millis() as u64)
+    }
+}
+
+/// Samples sent together in one message, when connected to the ingress
+/// websocket with `?batch=true`.
+pub type MetricsBatch<'a> = Vec<DynamicMetrics<'a>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
 pub struct CpuMetrics {
+    /// Usage in percent
+    #[validate(range(0.0..=100.0))]
     pub usage: f32,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub enum CpuReport {
+    /// Usage of every core
+    PerCore(#[validate(nested)] Vec<CpuMetrics>),
+    /// Average usage over all cores and usage of the busiest core
+    #[validate(range(0.0..=100.0))]
+    Aggregate { usage: f32, max_core: f32 },
+}
+
+impl CpuReport {
+    /// Fold a per-core report into an aggregate one.
+    pub fn aggregate(cores: &[CpuMetrics]) -> Self {
+        let usages = || cores.iter().map(|cpu| cpu.usage);
+        CpuReport::Aggregate {
+            usage: metrics_math::narrow(metrics_math::mean(usages()).unwrap_or(0.0)),
+            max_core: metrics_math::narrow(metrics_math::max(usages()).unwrap_or(0.0)),
+        }
+    }
+
+    /// Average usage over all cores.
+    pub fn usage(&self) -> f64 {
+        match self {
+            CpuReport::Aggregate { usage, .. } => metrics_math::widen(*usage),
+            CpuReport::PerCore(cores) => {
+                metrics_math::mean(cores.iter().map(|cpu| cpu.usage)).unwrap_or(0.0)
+            }
+        }
+    }
+}
+
+/// Which shape of `CpuReport` to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CpuReportPolicy {
+    #[default]
+    Aggregate,
+    PerCore,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemoryMetrics {
     pub total: u64,
@@ -23,16 +233,242 @@ pub struct MemoryMetrics {
     pub swap_used: u64,
 }
 
+/// Byte counters of a network interface.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct NetworkMetrics {
-    pub ifname: String,
+pub struct NetworkMetrics<'a> {
+    #[serde(borrow)]
+    pub ifname: Cow<'a, str>,
     pub rx_bytes: Option<u64>,
     pub tx_bytes: Option<u64>,
 }
 
+impl NetworkMetrics<'_> {
+    pub fn into_owned(self) -> NetworkMetrics<'static> {
+        NetworkMetrics {
+            ifname: Cow::Owned(self.ifname.into_owned()),
+            rx_bytes: self.rx_bytes,
+            tx_bytes: self.tx_bytes,
+        }
+    }
+}
+
+/// Usage and I/O of a mounted filesystem.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskMetrics<'a> {
+    /// Where the filesystem is mounted, e.g. `/` or `C:\`
+    #[serde(borrow)]
+    pub mount_point: Cow<'a, str>,
+    /// Size in bytes
+    pub total: u64,
+    /// Bytes free for unprivileged users
+    pub available: u64,
+    /// Bytes read from the device since boot, `None` where the platform
+    /// does not count them
+    pub read_bytes: Option<u64>,
+    /// Bytes written to the device since boot
+    pub written_bytes: Option<u64>,
+}
+
+impl DiskMetrics<'_> {
+    pub fn into_owned(self) -> DiskMetrics<'static> {
+        DiskMetrics {
+            mount_point: Cow::Owned(self.mount_point.into_owned()),
+            total: self.total,
+            available: self.available,
+            read_bytes: self.read_bytes,
+            written_bytes: self.written_bytes,
+        }
+    }
+}
+
+/// Resource usage of the probe itself, to keep an eye on its overhead.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Validate)]
+pub struct ProbeSelfMetrics {
+    /// Time spent collecting the other metrics of the sample in microseconds
+    pub collection_time: u64,
+    /// CPU usage of the probe process in percent of one core
+    #[validate(range(0.0..=f32::MAX))]
+    pub cpu_usage: Option<f32>,
+    /// Resident memory of the probe process in bytes
+    pub rss: Option<u64>,
+}
+
+/// Process and thread counts of the system, to catch fork bombs and daemons
+/// leaking threads or children.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessMetrics {
+    pub processes: u32,
+    /// Threads of all processes, only counted on Linux
+    pub threads: Option<u32>,
+    /// Processes that exited without being reaped by their parent
+    pub zombies: u32,
+}
+
+/// Open files and TCP connections of the system, running out of file
+/// descriptors fails everything else quietly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FdMetrics {
+    /// File descriptors open by all processes
+    pub open: u64,
+    /// Most file descriptors the kernel hands out, `fs.file-max`
+    pub max: u64,
+    /// TCP connections established or closed by the remote end only
+    pub tcp_established: Option<u32>,
+    /// TCP connections closed, waiting out late packets
+    pub tcp_time_wait: Option<u32>,
+}
+
+/// Synchronization of the system clock, a clock drifting off shifts every
+/// sample of the client in time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Validate)]
+pub struct ClockMetrics {
+    /// The clock follows a time source, e.g. NTP servers
+    pub synchronized: bool,
+    /// Estimated seconds the clock is ahead of its time source, negative if
+    /// behind, not known to every time daemon
+    #[validate(range(f64::MIN..=f64::MAX))]
+    pub offset: Option<f64>,
+}
+
+/// All batteries of the client combined.
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct BatteryMetrics {
+    /// Charge in percent of the full capacity
+    #[validate(range(0.0..=100.0))]
+    pub capacity: f32,
+    pub state: BatteryState,
+    /// Power flowing into or out of the battery in watts
+    #[validate(range(f32::MIN..=f32::MAX))]
+    pub power: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryState {
+    Charging,
+    Discharging,
+    Full,
+    /// On external power without charging, e.g. held at a charge limit
+    NotCharging,
+    Unknown,
+}
+
+impl BatteryState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatteryState::Charging => "charging",
+            BatteryState::Discharging => "discharging",
+            BatteryState::Full => "full",
+            BatteryState::NotCharging => "not_charging",
+            BatteryState::Unknown => "unknown",
+        }
+    }
+}
+
+/// State of a systemd unit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceMetrics<'a> {
+    /// Unit name, e.g. `nginx.service`
+    #[serde(borrow)]
+    pub name: Cow<'a, str>,
+    pub state: ServiceState,
+    /// Automatic restarts since the unit was last started by hand, `None`
+    /// for units other than services
+    pub restarts: Option<u32>,
+}
+
+impl ServiceMetrics<'_> {
+    pub fn into_owned(self) -> ServiceMetrics<'static> {
+        ServiceMetrics {
+            name: Cow::Owned(self.name.into_owned()),
+            state: self.state,
+            restarts: self.restarts,
+        }
+    }
+}
+
+/// `ActiveState` of a systemd unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Active,
+    Reloading,
+    Inactive,
+    Failed,
+    Activating,
+    Deactivating,
+    /// The unit could not be queried
+    Unknown,
+}
+
+impl ServiceState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServiceState::Active => "active",
+            ServiceState::Reloading => "reloading",
+            ServiceState::Inactive => "inactive",
+            ServiceState::Failed => "failed",
+            ServiceState::Activating => "activating",
+            ServiceState::Deactivating => "deactivating",
+            ServiceState::Unknown => "unknown",
+        }
+    }
+}
+
+/// A socket accepting connections or datagrams.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ListeningSocket {
+    pub protocol: SocketProtocol,
+    pub address: IpAddr,
+    pub port: u16,
+    /// Name of the owning process, unknown without the rights to inspect it
+    pub process: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Addresses of the interface the client reports on, to find hosts with
+/// dynamic addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostAddresses {
+    pub ipv4: Option<Ipv4Addr>,
+    /// The first address that is not link-local
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Validate)]
+pub struct SensorMetrics {
+    /// Hottest CPU sensor in degrees Celsius
+    #[validate(range(-100.0..=200.0))]
+    pub cpu_temperature: Option<f32>,
+    /// Average current frequency over all cores in MHz
+    pub cpu_frequency: Option<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StaticMetrics {
     pub system: SystemInfo,
+    pub capabilities: Capabilities,
+}
+
+/// Which collectors work on the client platform, metrics of the others are
+/// missing or zero.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub tls: bool,
+    pub cpu: bool,
+    pub memory: bool,
+    pub swap: bool,
+    pub network: bool,
+    pub cpu_temperature: bool,
+    pub cpu_frequency: bool,
+    /// A battery was found, only checked when the battery collector is enabled
+    pub battery: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,4 +478,75 @@ pub struct SystemInfo {
     pub os_version: Option<String>,
     pub host_name: Option<String>,
     pub cpu_arch: String,
+    /// When the host booted, `None` where the client can not tell. Derived
+    /// from the uptime, so it moves by as much as the clock is adjusted
+    pub boot_time: Option<UnixMillis>,
+    /// Random id of the current boot, e.g. `/proc/sys/kernel/random/boot_id`
+    /// on Linux, `None` where the platform has none
+    pub boot_id: Option<String>,
+    /// Id of the installation that survives reboots and hostname changes,
+    /// e.g. `/etc/machine-id` on Linux
+    pub machine_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding_borrows_strings() {
+        let metrics = DynamicMetrics {
+            seq: 0,
+            sample_time: UnixMillis(1_760_000_000_000),
+            cpu: CpuReport::PerCore(vec![CpuMetrics { usage: 12.5 }]),
+            memory: MemoryMetrics {
+                total: 0,
+                used: 0,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: "eth0".into(),
+                rx_bytes: Some(1),
+                tx_bytes: None,
+            }],
+            disks: vec![DiskMetrics {
+                mount_point: "/".into(),
+                total: 1 << 30,
+                available: 1 << 29,
+                read_bytes: Some(4096),
+                written_bytes: None,
+            }],
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: None,
+            services: vec![ServiceMetrics {
+                name: "nginx.service".into(),
+                state: ServiceState::Active,
+                restarts: Some(0),
+            }],
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        };
+        let mut buf = [0; 128];
+        let bytes = postcard::to_slice(&metrics, &mut buf).unwrap();
+
+        let decoded: DynamicMetrics = postcard::from_bytes(bytes).unwrap();
+        assert!(matches!(decoded.networks[0].ifname, Cow::Borrowed("eth0")));
+        assert!(matches!(decoded.disks[0].mount_point, Cow::Borrowed("/")));
+        assert!(matches!(
+            decoded.services[0].name,
+            Cow::Borrowed("nginx.service")
+        ));
+
+        let owned = decoded.into_owned();
+        assert!(matches!(owned.networks[0].ifname, Cow::Owned(_)));
+        assert!(matches!(owned.disks[0].mount_point, Cow::Owned(_)));
+        assert_eq!(owned.services[0].name, "nginx.service");
+    }
 }
@@ -0,0 +1,27 @@
+//! Sizes, intervals and content types client and server have to agree on.
+//!
+//! The server advertises most of these in `ServerCapabilities` and
+//! `CreateSessionResp`, the values here are what it offers and what clients
+//! fall back to before a session tells them otherwise.
+
+use std::time::Duration;
+
+/// Content type of request and response bodies encoded with postcard.
+pub const POSTCARD_CONTENT_TYPE: &str = "application/postcard";
+
+/// Length of the tokens clients authenticate with, alphanumeric.
+pub const CLIENT_TOKEN_LENGTH: usize = 16;
+
+/// Interval between two samples, the server asks every client for it.
+pub const DEFAULT_SCRAPE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most samples accepted in one `MetricsBatch`.
+pub const MAX_BATCH_SIZE: u32 = 1024;
+
+/// How long an ingress websocket may stay silent, so clients batching
+/// samples for longer ping in between.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Most bytes a message may decompress to, to keep a hostile peer from
+/// exhausting memory with a small message.
+pub const MAX_DECODED_SIZE: usize = 16 * 1024 * 1024;
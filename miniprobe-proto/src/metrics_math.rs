@@ -0,0 +1,135 @@
+//! Arithmetic over metric values, shared by the client and the server.
+//!
+//! Precision policy: usage values like CPU usage in percent are `f32` on the
+//! wire, where single precision is plenty for a value in `0..=100`. Anything
+//! combining values, averages over cores, over time or in SQLite `REAL`
+//! columns, works in `f64`, so rounding errors do not pile up with the number
+//! of values. Values are [`widen`]ed when they leave a sample and only
+//! [`narrow`]ed again when a result is put back into one.
+
+/// A wire value as used for arithmetic and storage.
+pub fn widen(value: f32) -> f64 {
+    f64::from(value)
+}
+
+/// A computed value as sent on the wire.
+pub fn narrow(value: f64) -> f32 {
+    value as f32
+}
+
+/// Sum of `values` with Neumaier's compensated summation, exact enough that
+/// the order of the values does not matter in practice.
+pub fn sum<T: Into<f64>>(values: impl IntoIterator<Item = T>) -> f64 {
+    let (mut sum, mut compensation) = (0.0f64, 0.0f64);
+    for value in values {
+        let value = value.into();
+        let t = sum + value;
+        compensation += if sum.abs() >= value.abs() {
+            (sum - t) + value
+        } else {
+            (value - t) + sum
+        };
+        sum = t;
+    }
+    sum + compensation
+}
+
+/// Arithmetic mean of `values`, `None` without values.
+pub fn mean<T: Into<f64>>(values: impl IntoIterator<Item = T>) -> Option<f64> {
+    let mut count = 0usize;
+    let sum = sum(values.into_iter().inspect(|_| count += 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+/// Smallest of `values`, NaN is ignored.
+pub fn min<T: Into<f64>>(values: impl IntoIterator<Item = T>) -> Option<f64> {
+    values.into_iter().map(Into::into).reduce(f64::min)
+}
+
+/// Largest of `values`, NaN is ignored.
+pub fn max<T: Into<f64>>(values: impl IntoIterator<Item = T>) -> Option<f64> {
+    values.into_iter().map(Into::into).reduce(f64::max)
+}
+
+/// Increase of a counter from `previous` to `current`. A counter going down
+/// wrapped around if it was in the upper half of 32 bits and is in the lower
+/// half now, as on 32-bit kernels, and was reset otherwise, e.g. by the
+/// interface going down. A reset is not mistaken for a wrap adding up to
+/// 4 GiB.
+pub fn counter_delta(previous: u64, current: u64) -> u64 {
+    const HALF: u64 = u32::MAX as u64 / 2;
+    if current >= previous {
+        current - previous
+    } else if (HALF + 1..=u32::MAX as u64).contains(&previous) && current <= HALF {
+        current + (u32::MAX as u64 + 1 - previous)
+    } else {
+        current
+    }
+}
+
+/// A usage in percent rounded to a tenth of a percent.
+pub fn quantize_percent(value: f32) -> f32 {
+    narrow((widen(value) * 10.0).round() / 10.0)
+}
+
+/// A byte count rounded to the nearest KiB.
+pub fn quantize_bytes(bytes: u64) -> u64 {
+    bytes.saturating_add(512) / 1024 * 1024
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compensated_sum() {
+        // naive summation loses the small values next to the large ones
+        let values = [1e16, 1.0, -1e16, 1.0];
+        assert_eq!(values.iter().sum::<f64>(), 1.0);
+        assert_eq!(sum(values), 2.0);
+
+        // a day of one second samples at 33.3% drifts in f32
+        let usage = vec![33.3f32; 86_400];
+        let naive = usage.iter().sum::<f32>() / usage.len() as f32;
+        let exact = mean(usage.iter().copied()).unwrap();
+        assert!((naive - 33.3).abs() > 1e-3);
+        assert!((exact - widen(33.3)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empty_and_extremes() {
+        assert_eq!(mean(Vec::<f32>::new()), None);
+        assert_eq!(min(Vec::<f64>::new()), None);
+        assert_eq!(mean([1.0f32, 2.0, 4.0]), Some(7.0 / 3.0));
+        assert_eq!(min([3.0f32, f32::NAN, 1.0]), Some(1.0));
+        assert_eq!(max([3.0f64, 5.0, 1.0]), Some(5.0));
+        assert_eq!(narrow(widen(12.5)), 12.5);
+    }
+
+    #[test]
+    fn counter_deltas() {
+        assert_eq!(counter_delta(100, 250), 150);
+        assert_eq!(counter_delta(250, 250), 0);
+        // 32-bit wrap
+        assert_eq!(counter_delta(u32::MAX as u64 - 9, 20), 30);
+        // reset of a 64-bit counter
+        assert_eq!(counter_delta(1 << 40, 1000), 1000);
+        // reset below 4 GiB
+        assert_eq!(counter_delta(1_000_000, 10), 10);
+        assert_eq!(
+            counter_delta(u32::MAX as u64 - 9, u32::MAX as u64 - 20),
+            u32::MAX as u64 - 20
+        );
+    }
+
+    #[test]
+    fn quantized() {
+        assert_eq!(quantize_percent(33.3333), 33.3);
+        assert_eq!(quantize_percent(99.96), 100.0);
+        assert_eq!(quantize_percent(0.04), 0.0);
+        assert_eq!(quantize_bytes(1535), 1024);
+        assert_eq!(quantize_bytes(1536), 2048);
+        assert_eq!(quantize_bytes(100), 0);
+        assert_eq!(quantize_bytes(u64::MAX), u64::MAX / 1024 * 1024);
+    }
+}
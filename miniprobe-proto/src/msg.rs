@@ -1,8 +1,10 @@
 use std::str::FromStr;
 
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
 
-use crate::StaticMetrics;
+use crate::{CpuReportPolicy, StaticMetrics, UnixMillis};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateSessionReq {
@@ -14,20 +16,277 @@ pub struct CreateSessionReq {
 pub struct CreateSessionResp {
     pub session_token: SessionToken,
     pub scrape_interval: u64,
+    /// CPU report shape enforced by the server, the client decides if `None`
+    pub cpu_report: Option<CpuReportPolicy>,
+    /// Clock of the server when it answered, to estimate the skew of the
+    /// client clock sample times are taken with
+    pub server_time: UnixMillis,
+    /// Largest ingress message the server decodes in bytes, after
+    /// decompression
+    pub max_message_size: u64,
+    /// Most samples in one `MetricsBatch`
+    pub max_batch_size: u32,
+    /// Seconds an ingress websocket may stay silent, clients batching for
+    /// longer send a ping in between so proxies keep the connection
+    pub heartbeat_interval: u64,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+/// Version of the protocol spoken by this build, bumped on changes of the
+/// wire format that older peers can not decode.
+///
+/// 2: `CreateSessionResp` carries the server time and limits.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version servers of this build accept. Postcard has no
+/// field names nor lengths, so a message with appended fields does not decode
+/// with an older layout: raise it with every [`PROTOCOL_VERSION`] changing a
+/// message the server sends.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Header carrying the [`METRICS_SCHEMA_HASH`](crate::METRICS_SCHEMA_HASH)
+/// of a client in hex, on session creation and the ingress websocket, so the
+/// server can refuse clients it can not decode.
+pub const SCHEMA_HEADER: &str = "miniprobe-schema";
+
+/// Header carrying the [`PROTOCOL_VERSION`] of a client, on session creation
+/// and the ingress websocket, only logged by the server.
+pub const PROTOCOL_HEADER: &str = "x-miniprobe-protocol";
+
+/// Features of a server, served at `/.well-known/miniprobe` so clients can
+/// pick what to use instead of relying on flags matching the server build.
+///
+/// Served as JSON, or postcard if asked for with `Accept: application/postcard`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerCapabilities {
+    /// Version of the server package
+    pub version: String,
+    /// Protocol versions the server accepts, see [`PROTOCOL_VERSION`]
+    pub protocol_versions: Vec<u32>,
+    pub transports: Vec<Transport>,
+    /// Encodings of samples on the ingress websocket
+    pub encodings: Vec<Encoding>,
+    pub compression: Vec<Compression>,
+    /// Most samples in one `MetricsBatch`, `None` if batches are not supported
+    pub max_batch_size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// `/ws/v1/metrics/ingress`
+    Websocket,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// Binary frames
+    Postcard,
+    /// Text frames
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Uncompressed
+    Identity,
+    /// Raw deflate of every binary message
+    Deflate,
+    /// Zstandard of every binary message with the dictionary bundled in
+    /// [`codec`](crate::codec)
+    Zstd,
+}
+
+/// Query parameter of the ingress websocket listing the compressions a
+/// client can send, preferred first and separated by commas.
+pub const COMPRESSION_PARAM: &str = "compression";
+
+/// Header of the ingress websocket upgrade response carrying the compression
+/// the server picked, binary messages are uncompressed without it.
+pub const COMPRESSION_HEADER: &str = "miniprobe-compression";
+
+/// Query parameter of the ingress websocket a client sets to [`DELTA_COUNTERS`]
+/// to send the counters of `NetworkMetrics` as deltas.
+pub const COUNTERS_PARAM: &str = "counters";
+
+/// Header of the ingress websocket upgrade response, [`DELTA_COUNTERS`] if the
+/// server accepts deltas. Counters stay cumulative without it.
+pub const COUNTERS_HEADER: &str = "miniprobe-counters";
+
+/// Counters of `NetworkMetrics` hold the bytes since the previous sample sent
+/// on the connection, see [`counter_delta`](crate::metrics_math::counter_delta).
+/// The first sample of a connection, and the first after the interface
+/// changed, have no previous one and carry no counters.
+pub const DELTA_COUNTERS: &str = "delta";
+
+/// Query parameter of the ingress websocket a client sets to
+/// [`COARSE_PRECISION`] to round the values of its samples.
+pub const PRECISION_PARAM: &str = "precision";
+
+/// Header of the ingress websocket upgrade response, [`COARSE_PRECISION`] if
+/// the server knows the samples are rounded. The client sends values as
+/// collected without it.
+pub const PRECISION_HEADER: &str = "miniprobe-precision";
+
+/// CPU usages are rounded to a tenth of a percent and byte counts to whole
+/// KiB, see [`metrics_math::quantize_percent`](crate::metrics_math::quantize_percent)
+/// and [`metrics_math::quantize_bytes`](crate::metrics_math::quantize_bytes).
+/// Rounded samples compress far better.
+pub const COARSE_PRECISION: &str = "coarse";
+
+/// Values are sent as collected, the precision without [`COARSE_PRECISION`].
+pub const FULL_PRECISION: &str = "full";
+
+/// Query parameter of the ingress websocket listing the actions a client runs
+/// when asked to, by name and separated by commas. What they run stays on the
+/// client, see [`IngressControl::RunAction`].
+pub const ACTIONS_PARAM: &str = "actions";
+
+/// Header of the ingress websocket upgrade response, present if the server
+/// may ask for the actions offered. The client then sends the
+/// [`ActionResult`] of each as a JSON text frame, which leaves no room for
+/// JSON samples on the connection.
+pub const ACTIONS_HEADER: &str = "miniprobe-actions";
+
+/// Most bytes of output in an [`ActionResult`], the rest is cut off.
+pub const MAX_ACTION_OUTPUT: usize = 4096;
+
+/// Cut `output` to [`MAX_ACTION_OUTPUT`] bytes on a character boundary.
+pub fn truncate_action_output(output: &mut String) {
+    if output.len() > MAX_ACTION_OUTPUT {
+        let mut end = MAX_ACTION_OUTPUT;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+    }
+}
+
+/// Whether `name` can be offered as an action: lowercase letters, digits,
+/// `-` and `_`.
+pub fn is_action_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+/// Close code of the ingress websocket when a newer connection of the same
+/// session took over, from the range reserved for applications.
+pub const CLOSE_TAKEN_OVER: u16 = 4001;
+
+/// Close code of the ingress websocket when the server enters maintenance.
+/// The reason tells when to reconnect, see [`maintenance_close_reason`].
+pub const CLOSE_MAINTENANCE: u16 = 4002;
+
+/// Close reason of [`CLOSE_MAINTENANCE`], asking to reconnect after
+/// `retry_after` seconds like the `Retry-After` header of the `503` refusing
+/// new sessions meanwhile.
+pub fn maintenance_close_reason(retry_after: u64) -> String {
+    format!("maintenance, retry after {retry_after}s")
+}
+
+/// Seconds to wait before reconnecting from a [`CLOSE_MAINTENANCE`] reason.
+pub fn parse_maintenance_close_reason(reason: &str) -> Option<u64> {
+    reason
+        .strip_prefix("maintenance, retry after ")?
+        .strip_suffix('s')?
+        .parse()
+        .ok()
+}
+
+/// Control messages sent by the server over the metrics ingress websocket.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum IngressControl {
+    /// Stretch the scrape interval by `factor` until told otherwise, `1.0` restores it
+    SlowDown { factor: f32 },
+    /// Outcome of the messages since the previous ack, only sent to clients
+    /// connected with `?ack=n`
+    Ack(IngressAck),
+    /// Run one of the actions the client offered, only sent to clients
+    /// connected with [`ACTIONS_PARAM`]
+    RunAction(ActionRequest),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActionRequest {
+    /// Echoed in the [`ActionResult`]
+    pub id: u64,
+    /// Name of the action as offered by the client
+    pub name: String,
+}
+
+/// Outcome of an [`ActionRequest`], sent by the client as a JSON text frame.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ActionResult {
+    pub id: u64,
+    /// Exit code of the command, `None` if it did not exit by itself
+    pub exit_code: Option<i32>,
+    /// Standard output and error of the command, at most
+    /// [`MAX_ACTION_OUTPUT`] bytes
+    pub output: String,
+    /// Why the command did not run or finish, e.g. an unknown action or a
+    /// timeout
+    pub error: Option<String>,
+}
+
+/// What the server did with the samples of the messages it acknowledges.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IngressAck {
+    /// Samples handed to storage, including duplicates it drops
+    pub stored: u32,
+    /// `seq` of the latest stored sample
+    pub last_seq: Option<u64>,
+    /// `sample_time` of the latest stored sample
+    pub sample_time: Option<UnixMillis>,
+    /// Problems the server worked around, e.g. a per-core CPU report folded
+    /// into an aggregate
+    pub warnings: Vec<String>,
+}
+
+/// Opaque bearer token of a session: 256 random bits, written as unpadded
+/// base64url.
+///
+/// Servers before this format issued 32 alphanumeric characters, which are
+/// still written and parsed verbatim so new clients keep working with them.
+/// Support for them will be removed once those servers are gone.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SessionToken([u8; 32]);
 
+impl SessionToken {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Whether the token was issued by a server using the alphanumeric format.
+    fn is_legacy(&self) -> bool {
+        self.0.iter().all(u8::is_ascii_alphanumeric)
+    }
+}
+
+impl PartialEq for SessionToken {
+    /// Constant-time comparison, the running time does not reveal the common prefix.
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for SessionToken {}
+
 impl std::fmt::Debug for SessionToken {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "SessionToken({:?})", String::from_utf8_lossy(&self.0))
+        write!(f, "SessionToken({:?})", self.to_string())
     }
 }
 
 impl std::fmt::Display for SessionToken {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", String::from_utf8_lossy(&self.0))
+        if self.is_legacy() {
+            write!(f, "{}", String::from_utf8_lossy(&self.0))
+        } else {
+            write!(f, "{}", URL_SAFE_NO_PAD.encode(self.0))
+        }
     }
 }
 
@@ -35,13 +294,22 @@ impl FromStr for SessionToken {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let bytes = s.as_bytes();
-        if bytes.len() != 32 {
-            return Err("SessionToken must be 32 bytes long");
-        }
-
         let mut token_bytes = [0; 32];
-        token_bytes.copy_from_slice(&bytes[0..32]);
+        match s.len() {
+            // 32 bytes in unpadded base64
+            43 => {
+                let len = URL_SAFE_NO_PAD
+                    .decode_slice(s, &mut token_bytes)
+                    .map_err(|_| "SessionToken must be base64url encoded")?;
+                if len != 32 {
+                    return Err("SessionToken must be 32 bytes long");
+                }
+            }
+            32 if s.bytes().all(|b| b.is_ascii_alphanumeric()) => {
+                token_bytes.copy_from_slice(s.as_bytes());
+            }
+            _ => return Err("SessionToken must be 43 base64url characters long"),
+        }
 
         Ok(SessionToken(token_bytes))
     }
@@ -50,18 +318,102 @@ impl FromStr for SessionToken {
 #[cfg(feature = "rand")]
 impl SessionToken {
     pub fn random() -> Self {
-        use rand::{Rng, distr::Alphanumeric};
+        use rand::Rng;
 
         let mut token_bytes = [0; 32];
+        // an all alphanumeric token would be written in the legacy format,
+        // which is as likely as guessing the token but cheap to rule out
+        loop {
+            rand::rng().fill(&mut token_bytes);
+            let token = SessionToken(token_bytes);
+            if !token.is_legacy() {
+                return token;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_token_format() {
+        let token = SessionToken([0xfb; 32]);
+        let s = token.to_string();
+        assert_eq!(s.len(), 43);
+        assert!(
+            s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        );
+        assert_eq!(s.parse::<SessionToken>(), Ok(token));
+
+        let legacy = "AbCdEfGhIjKlMnOpQrStUvWxYz012345";
+        let token = legacy.parse::<SessionToken>().unwrap();
+        assert_eq!(token.to_string(), legacy);
+
+        assert!(
+            "AbCdEfGhIjKlMnOpQrStUvWxYz01234+"
+                .parse::<SessionToken>()
+                .is_err()
+        );
+        assert!("short".parse::<SessionToken>().is_err());
+        assert!("!".repeat(43).parse::<SessionToken>().is_err());
+    }
+
+    #[test]
+    fn maintenance_reason() {
+        let reason = maintenance_close_reason(90);
+        assert_eq!(reason, "maintenance, retry after 90s");
+        assert_eq!(parse_maintenance_close_reason(&reason), Some(90));
+        assert_eq!(parse_maintenance_close_reason("maintenance"), None);
+    }
+
+    #[test]
+    fn ingress_control_encoding() {
+        let mut buf = [0; 64];
+        // clients predating acks decode the first variant the same way
+        let bytes =
+            postcard::to_slice(&IngressControl::SlowDown { factor: 2.0 }, &mut buf).unwrap();
+        assert_eq!(bytes[0], 0);
+
+        let ack = IngressControl::Ack(IngressAck {
+            stored: 2,
+            last_seq: Some(7),
+            sample_time: Some(UnixMillis(1_700_000_000_250)),
+            warnings: vec!["clock ahead".to_owned()],
+        });
+        let bytes = postcard::to_slice(&ack, &mut buf).unwrap();
+        assert_eq!(postcard::from_bytes::<IngressControl>(bytes).unwrap(), ack);
+
+        let run = IngressControl::RunAction(ActionRequest {
+            id: 3,
+            name: "restart-app".to_owned(),
+        });
+        let bytes = postcard::to_slice(&run, &mut buf).unwrap();
+        assert_eq!(bytes[0], 2);
+        assert_eq!(postcard::from_bytes::<IngressControl>(bytes).unwrap(), run);
+    }
+
+    #[test]
+    fn action_names() {
+        assert!(is_action_name("restart-app"));
+        assert!(is_action_name("reload_nginx2"));
+        assert!(!is_action_name(""));
+        assert!(!is_action_name("Restart"));
+        assert!(!is_action_name("a,b"));
+        assert!(!is_action_name("rm -rf"));
+    }
 
-        rand::rng()
-            .sample_iter(&Alphanumeric)
-            .take(32)
-            .enumerate()
-            .for_each(|(i, c)| {
-                token_bytes[i] = c;
-            });
+    #[test]
+    fn action_output() {
+        let mut output = "é".repeat(MAX_ACTION_OUTPUT);
+        truncate_action_output(&mut output);
+        assert_eq!(output.len(), MAX_ACTION_OUTPUT);
+        assert!(output.chars().all(|c| c == 'é'));
 
-        SessionToken(token_bytes)
+        let mut short = "out\n".to_owned();
+        truncate_action_output(&mut short);
+        assert_eq!(short, "out\n");
     }
 }
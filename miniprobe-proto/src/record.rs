@@ -0,0 +1,54 @@
+//! Framing of samples stored in files, used by the client's journal and its
+//! offline output.
+//!
+//! Every record is `[length: u32][crc32: u32][payload]`, little endian, so a
+//! record torn by a power loss fails its checksum instead of being decoded.
+
+/// Size of the length and checksum in front of every payload.
+pub const HEADER_SIZE: usize = 8;
+
+/// Append `payload` framed as a record to `out`.
+pub fn encode(payload: &[u8], out: &mut Vec<u8>) {
+    out.reserve(HEADER_SIZE + payload.len());
+    out.extend((payload.len() as u32).to_le_bytes());
+    out.extend(crc32fast::hash(payload).to_le_bytes());
+    out.extend(payload);
+}
+
+/// Payloads of the intact records at the start of a buffer, the rest is left
+/// in `.0` after the first damaged or incomplete one.
+pub struct Records<'a>(pub &'a [u8]);
+
+impl<'a> Iterator for Records<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let header = self.0.get(..HEADER_SIZE)?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let payload = self.0.get(HEADER_SIZE..HEADER_SIZE + len)?;
+        if crc32fast::hash(payload) != crc {
+            return None;
+        }
+        self.0 = &self.0[HEADER_SIZE + len..];
+        Some(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torn_record() {
+        let mut buf = Vec::new();
+        encode(b"first", &mut buf);
+        encode(b"second", &mut buf);
+        buf.truncate(buf.len() - 1);
+
+        let mut records = Records(&buf);
+        assert_eq!(records.next(), Some(&b"first"[..]));
+        assert_eq!(records.next(), None);
+        assert_eq!(records.0.len(), HEADER_SIZE + b"second".len() - 1);
+    }
+}
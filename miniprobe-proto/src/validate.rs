@@ -0,0 +1,138 @@
+//! Plausible ranges of sample values, declared next to the fields with
+//! `#[validate(range(..))]` and checked by the server before storing a sample.
+//!
+//! [`Validate`] is derived by `miniprobe-proto-derive`, see there for the
+//! attributes. The attributes are not part of [`METRICS_SCHEMA_HASH`], they
+//! change what the server accepts but not what peers can decode.
+//!
+//! [`METRICS_SCHEMA_HASH`]: crate::METRICS_SCHEMA_HASH
+
+use std::fmt;
+
+pub use miniprobe_proto_derive::Validate;
+
+pub trait Validate {
+    /// The first value out of its range, if any.
+    fn validate(&self) -> Result<(), ValidationError>;
+}
+
+/// A value out of its range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// Path of the field, e.g. `cpu.usage` or `services[2].restarts`
+    pub field: String,
+    pub value: String,
+    /// The range as written in the attribute
+    pub range: &'static str,
+}
+
+impl ValidationError {
+    pub fn new(value: &impl fmt::Debug, range: &'static str) -> Self {
+        ValidationError {
+            field: String::new(),
+            value: format!("{value:?}"),
+            range,
+        }
+    }
+
+    /// Move the error under `field`, left as is if empty.
+    pub fn within(mut self, field: &str) -> Self {
+        self.field = match self.field.as_str() {
+            "" => field.to_owned(),
+            _ if field.is_empty() => self.field,
+            inner if inner.starts_with('[') => format!("{field}{inner}"),
+            inner => format!("{field}.{inner}"),
+        };
+        self
+    }
+
+    /// Move the error under the item `index` of a list.
+    pub fn at(self, index: usize) -> Self {
+        self.within(&format!("[{index}]"))
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` is {}, out of {}",
+            self.field, self.value, self.range
+        )
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::{
+        BatteryMetrics, BatteryState, CpuMetrics, CpuReport, DynamicMetrics, MemoryMetrics,
+        NetworkMetrics, UnixMillis,
+    };
+
+    fn sample() -> DynamicMetrics<'static> {
+        DynamicMetrics {
+            seq: 0,
+            sample_time: UnixMillis(1_700_000_000_000),
+            cpu: CpuReport::PerCore(vec![CpuMetrics { usage: 12.5 }, CpuMetrics { usage: 3.0 }]),
+            memory: MemoryMetrics {
+                total: 1024,
+                used: 512,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: Cow::Borrowed("eth0"),
+                rx_bytes: Some(1),
+                tx_bytes: Some(2),
+            }],
+            disks: Vec::new(),
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: Some(BatteryMetrics {
+                capacity: 80.0,
+                state: BatteryState::Discharging,
+                power: None,
+            }),
+            services: Vec::new(),
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(sample().validate(), Ok(()));
+
+        let mut metrics = sample();
+        metrics.cpu =
+            CpuReport::PerCore(vec![CpuMetrics { usage: 1.0 }, CpuMetrics { usage: 250.0 }]);
+        let e = metrics.validate().unwrap_err();
+        assert_eq!(e.field, "cpu[1].usage");
+        assert_eq!(e.value, "250.0");
+        assert_eq!(e.range, "0.0..=100.0");
+
+        let mut metrics = sample();
+        metrics.cpu = CpuReport::Aggregate {
+            usage: f32::NAN,
+            max_core: 0.0,
+        };
+        assert_eq!(metrics.validate().unwrap_err().field, "cpu.usage");
+
+        let mut metrics = sample();
+        metrics.battery.as_mut().unwrap().capacity = -1.0;
+        assert_eq!(
+            metrics.validate().unwrap_err().to_string(),
+            "`battery.capacity` is -1.0, out of 0.0..=100.0"
+        );
+    }
+}
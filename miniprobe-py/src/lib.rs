@@ -0,0 +1,230 @@
+//! Python bindings for the query API of a miniprobe server, built with
+//! `maturin build --release` in this directory.
+//!
+//! Results are returned as dicts of columns, ready for `pandas.DataFrame`:
+//!
+//! ```python
+//! import pandas as pd
+//! from miniprobe_py import Client
+//!
+//! client = Client("http://127.0.0.1:8000", admin_token="secret")
+//! cpu = pd.DataFrame(client.query("avg_over_time(cpu[1h])"))
+//! clients = pd.DataFrame(client.clients()).set_index("id")
+//! hourly = pd.DataFrame(client.query_range("avg_over_time(cpu[1h])", start, 3600))
+//! rx = pd.DataFrame(client.fleet_query_range("rate(rx_bytes[5m])", "sum", start, 300, selector="env=prod"))
+//! ```
+//!
+//! Times are unix timestamps in seconds, missing values are `None` and become
+//! `NaN` in pandas.
+
+use std::future::Future;
+
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyValueError},
+    prelude::*,
+    types::PyDict,
+};
+use tokio::runtime::Runtime;
+
+create_exception!(miniprobe_py, MiniprobeError, PyException);
+
+/// Blocking client of one server.
+#[pyclass(frozen)]
+struct Client {
+    api: miniprobe_api::Client,
+    runtime: Runtime,
+}
+
+#[pymethods]
+impl Client {
+    #[new]
+    #[pyo3(signature = (url, admin_token = None))]
+    fn new(url: &str, admin_token: Option<String>) -> PyResult<Self> {
+        let mut api = miniprobe_api::Client::new(url).map_err(to_py_err)?;
+        if let Some(token) = admin_token {
+            api = api.with_admin_token(token);
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Client { api, runtime })
+    }
+
+    /// Evaluate `expr` for one or every client at `time`, one row per client.
+    #[pyo3(signature = (expr, client = None, time = None))]
+    fn query<'py>(
+        &self,
+        py: Python<'py>,
+        expr: &str,
+        client: Option<i64>,
+        time: Option<i64>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let resp = self.block_on(py, self.api.query(expr, client, time))?;
+        let rows = &resp.results;
+
+        let columns = PyDict::new(py);
+        columns.set_item("time", vec![resp.time; rows.len()])?;
+        columns.set_item("client_id", column(rows, |r| r.client_id))?;
+        columns.set_item("name", column(rows, |r| r.name.clone()))?;
+        columns.set_item("display_name", column(rows, |r| r.display_name.clone()))?;
+        columns.set_item("value", column(rows, |r| r.value))?;
+        columns.set_item("sample_time", column(rows, |r| r.sample_time))?;
+        columns.set_item("received_at", column(rows, |r| r.received_at))?;
+        Ok(columns)
+    }
+
+    /// Evaluate `expr` in buckets of `step` seconds from `start` to `end` or
+    /// now, one row per client and bucket. `fill` is `"null"`, `"previous"`
+    /// or `"zero"`. With `compare_offset`, e.g. `"7d"`, the value of each
+    /// bucket that much earlier is in a `compare_value` column.
+    #[pyo3(signature = (expr, start, step, end = None, client = None, fill = "null", compare_offset = None))]
+    #[allow(clippy::too_many_arguments)]
+    fn query_range<'py>(
+        &self,
+        py: Python<'py>,
+        expr: &str,
+        start: i64,
+        step: i64,
+        end: Option<i64>,
+        client: Option<i64>,
+        fill: &str,
+        compare_offset: Option<&str>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let fill = parse_fill(fill)?;
+        let resp = self.block_on(
+            py,
+            self.api
+                .query_range(expr, client, start, end, step, fill, compare_offset),
+        )?;
+        let rows: Vec<_> = resp
+            .results
+            .iter()
+            .flat_map(|r| {
+                let compared = r.compare_values.iter().flatten().copied();
+                resp.buckets
+                    .iter()
+                    .zip(&r.values)
+                    .zip(compared.map(Some).chain(std::iter::repeat(None)))
+                    .map(move |((t, v), c)| (r, *t, *v, c.flatten()))
+            })
+            .collect();
+
+        let columns = PyDict::new(py);
+        columns.set_item("time", column(&rows, |(_, t, ..)| *t))?;
+        columns.set_item("client_id", column(&rows, |(r, ..)| r.client_id))?;
+        columns.set_item("name", column(&rows, |(r, ..)| r.name.clone()))?;
+        columns.set_item(
+            "display_name",
+            column(&rows, |(r, ..)| r.display_name.clone()),
+        )?;
+        columns.set_item("value", column(&rows, |(_, _, v, _)| *v))?;
+        if resp.compare_offset.is_some() {
+            columns.set_item("compare_value", column(&rows, |(.., c)| *c))?;
+        }
+        Ok(columns)
+    }
+
+    /// Evaluate `expr` in buckets of `step` seconds for every client matching
+    /// `selector`, e.g. `"env=prod"`, folded by `agg`: `"sum"`, `"avg"`,
+    /// `"min"`, `"max"` or `"count"`. One row per bucket, with the number of
+    /// clients that had a value.
+    #[pyo3(signature = (expr, agg, start, step, end = None, selector = "", fill = "null"))]
+    #[allow(clippy::too_many_arguments)]
+    fn fleet_query_range<'py>(
+        &self,
+        py: Python<'py>,
+        expr: &str,
+        agg: &str,
+        start: i64,
+        step: i64,
+        end: Option<i64>,
+        selector: &str,
+        fill: &str,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let agg = match agg {
+            "sum" => miniprobe_api::Aggregation::Sum,
+            "avg" => miniprobe_api::Aggregation::Avg,
+            "min" => miniprobe_api::Aggregation::Min,
+            "max" => miniprobe_api::Aggregation::Max,
+            "count" => miniprobe_api::Aggregation::Count,
+            _ => return Err(PyValueError::new_err(format!("invalid agg {agg:?}"))),
+        };
+        let fill = parse_fill(fill)?;
+        let resp = self.block_on(
+            py,
+            self.api
+                .fleet_query_range(expr, selector, agg, start, end, step, fill),
+        )?;
+
+        let columns = PyDict::new(py);
+        columns.set_item("time", &resp.buckets)?;
+        columns.set_item("value", &resp.values)?;
+        columns.set_item("clients", &resp.counts)?;
+        Ok(columns)
+    }
+
+    /// Every client with its status, one row per client.
+    fn clients<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let rows = &self.block_on(py, self.api.list_clients())?;
+
+        let columns = PyDict::new(py);
+        columns.set_item("id", column(rows, |c| c.id))?;
+        columns.set_item("name", column(rows, |c| c.name.clone()))?;
+        columns.set_item("display_name", column(rows, |c| c.display_name.clone()))?;
+        columns.set_item("timezone", column(rows, |c| c.timezone.clone()))?;
+        columns.set_item("location", column(rows, |c| c.location.clone()))?;
+        columns.set_item("created_at", column(rows, |c| c.created_at))?;
+        columns.set_item("state", column(rows, |c| state_name(c.status.state)))?;
+        columns.set_item("last_sample", column(rows, |c| c.status.last_sample))?;
+        columns.set_item("session_active", column(rows, |c| c.status.session_active))?;
+        columns.set_item("cpu_avg_24h", column(rows, |c| c.status.cpu_avg_24h))?;
+        columns.set_item("memory_avg_24h", column(rows, |c| c.status.memory_avg_24h))?;
+        columns.set_item("samples_today", column(rows, |c| c.samples_today))?;
+        Ok(columns)
+    }
+}
+
+impl Client {
+    /// Run a request without holding the GIL.
+    fn block_on<T: Send>(
+        &self,
+        py: Python<'_>,
+        request: impl Future<Output = Result<T, miniprobe_api::Error>> + Send,
+    ) -> PyResult<T> {
+        py.detach(|| self.runtime.block_on(request))
+            .map_err(to_py_err)
+    }
+}
+
+fn parse_fill(fill: &str) -> PyResult<miniprobe_api::Fill> {
+    Ok(match fill {
+        "null" => miniprobe_api::Fill::Null,
+        "previous" => miniprobe_api::Fill::Previous,
+        "zero" => miniprobe_api::Fill::Zero,
+        _ => return Err(PyValueError::new_err(format!("invalid fill {fill:?}"))),
+    })
+}
+
+fn column<R, T>(rows: &[R], f: impl Fn(&R) -> T) -> Vec<T> {
+    rows.iter().map(f).collect()
+}
+
+fn state_name(state: miniprobe_api::ClientState) -> &'static str {
+    match state {
+        miniprobe_api::ClientState::Up => "up",
+        miniprobe_api::ClientState::Stale => "stale",
+        miniprobe_api::ClientState::Never => "never",
+    }
+}
+
+fn to_py_err(e: miniprobe_api::Error) -> PyErr {
+    MiniprobeError::new_err(e.to_string())
+}
+
+#[pymodule]
+fn miniprobe_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Client>()?;
+    m.add("MiniprobeError", m.py().get_type::<MiniprobeError>())?;
+    Ok(())
+}
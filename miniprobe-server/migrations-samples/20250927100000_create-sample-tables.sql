@@ -0,0 +1,82 @@
+-- Add migration script here
+-- samples live in their own database, attached as `samples` to connections of
+-- the main database. Sessions are in the main database, so `session_id` can
+-- not be a foreign key.
+CREATE TABLE session_data (
+    id INTEGER PRIMARY KEY NOT NULL,
+    session_id INTEGER NOT NULL,
+    sample_time INTEGER NOT NULL,
+    seq INTEGER
+);
+
+CREATE INDEX session_data_session_id_sample_time ON session_data(session_id, sample_time);
+CREATE UNIQUE INDEX session_data_session_id_seq ON session_data(session_id, seq);
+
+CREATE TABLE session_data_cpu (
+    id INTEGER PRIMARY KEY NOT NULL,
+    session_data_id INTEGER NOT NULL,
+    cpu_id INTEGER NOT NULL,
+    cpu_usage REAL NOT NULL,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+);
+
+CREATE TABLE session_data_cpu_aggregate (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    cpu_usage REAL NOT NULL,
+    max_core_usage REAL NOT NULL,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
+
+CREATE TABLE session_data_memory (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    total INTEGER NOT NULL,
+    used INTEGER NOT NULL,
+    swap_total INTEGER NOT NULL,
+    swap_used INTEGER NOT NULL,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
+
+-- dictionary of strings repeated across samples (interface names etc.)
+CREATE TABLE strings (
+    id INTEGER PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE session_data_network (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    ifname_id INTEGER NOT NULL,
+    rx_bytes INTEGER,
+    tx_bytes INTEGER,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE,
+    FOREIGN KEY (ifname_id) REFERENCES strings(id)
+) WITHOUT ROWID;
+
+-- read path helper resolving interned names
+CREATE VIEW session_data_network_named AS
+SELECT n.session_data_id, s.value AS ifname, n.rx_bytes, n.tx_bytes
+FROM session_data_network n
+JOIN strings s ON s.id = n.ifname_id;
+
+CREATE TABLE session_data_sensors (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    -- degrees Celsius
+    cpu_temperature REAL,
+    -- MHz
+    cpu_frequency INTEGER,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
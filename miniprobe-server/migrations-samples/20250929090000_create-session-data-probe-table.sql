@@ -0,0 +1,15 @@
+-- Add migration script here
+-- resource usage of the probe itself
+CREATE TABLE session_data_probe (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    -- microseconds
+    collection_time INTEGER NOT NULL,
+    -- percent of one core
+    cpu_usage REAL,
+    -- bytes
+    rss INTEGER,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- server time the sample arrived at, NULL for samples stored before
+ALTER TABLE session_data ADD COLUMN received_at INTEGER;
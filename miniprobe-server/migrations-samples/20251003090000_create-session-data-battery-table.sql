@@ -0,0 +1,15 @@
+-- Add migration script here
+-- combined state of the batteries of a client, only if it reports one
+CREATE TABLE session_data_battery (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    -- percent of the full charge
+    capacity REAL NOT NULL,
+    -- charging, discharging, full, not_charging or unknown
+    state TEXT NOT NULL,
+    -- watts
+    power REAL,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
@@ -0,0 +1,21 @@
+-- Add migration script here
+-- state of the systemd units a client watches
+CREATE TABLE session_data_service (
+    session_data_id INTEGER NOT NULL,
+    unit_id INTEGER NOT NULL,
+    -- active, reloading, inactive, failed, activating, deactivating or unknown
+    state TEXT NOT NULL,
+    restarts INTEGER,
+
+    PRIMARY KEY (session_data_id, unit_id),
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE,
+    FOREIGN KEY (unit_id) REFERENCES strings(id)
+) WITHOUT ROWID;
+
+-- read path helper resolving interned names
+CREATE VIEW session_data_service_named AS
+SELECT v.session_data_id, s.value AS unit, v.state, v.restarts
+FROM session_data_service v
+JOIN strings s ON s.id = v.unit_id;
@@ -0,0 +1,11 @@
+-- Add migration script here
+-- listening sockets of a client, only stored when they changed
+CREATE TABLE session_data_listeners (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    -- JSON array of {protocol, address, port, process}
+    listeners TEXT NOT NULL,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- sample times are unix milliseconds from now on, they were seconds
+UPDATE session_data SET sample_time = sample_time * 1000;
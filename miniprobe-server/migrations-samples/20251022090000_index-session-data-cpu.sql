@@ -0,0 +1,4 @@
+-- Add migration script here
+-- per-core rows are looked up by sample, e.g. by replication and the CPU
+-- fallback of expressions
+CREATE INDEX session_data_cpu_session_data_id ON session_data_cpu(session_data_id, cpu_id);
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- rx_bytes and tx_bytes are the bytes since the previous sample rather than
+-- cumulative counters, sent by clients negotiating delta counters
+ALTER TABLE session_data_network ADD COLUMN delta BOOLEAN NOT NULL DEFAULT FALSE;
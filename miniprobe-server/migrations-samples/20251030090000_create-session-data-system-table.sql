@@ -0,0 +1,14 @@
+-- Add migration script here
+-- system-wide gauges, only if the client can list processes
+CREATE TABLE session_data_system (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    processes INTEGER NOT NULL,
+    -- threads of all processes, only counted on Linux
+    threads INTEGER,
+    -- exited processes not reaped by their parent
+    zombies INTEGER NOT NULL,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
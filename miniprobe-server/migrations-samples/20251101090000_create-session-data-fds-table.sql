@@ -0,0 +1,15 @@
+-- Add migration script here
+-- open files and TCP connections of the system, only reported by Linux clients
+CREATE TABLE session_data_fds (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    -- file descriptors open by all processes
+    open INTEGER NOT NULL,
+    -- fs.file-max, clamped to the largest INTEGER
+    max INTEGER NOT NULL,
+    tcp_established INTEGER,
+    tcp_time_wait INTEGER,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
@@ -0,0 +1,12 @@
+-- Add migration script here
+-- synchronization of the client clock, only if the client could tell
+CREATE TABLE session_data_clock (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    synchronized BOOLEAN NOT NULL,
+    -- seconds ahead of the time source, negative if behind
+    offset REAL,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
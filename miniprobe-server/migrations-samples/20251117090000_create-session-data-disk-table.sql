@@ -0,0 +1,25 @@
+-- Add migration script here
+-- usage and I/O of the filesystems mounted on a client
+CREATE TABLE session_data_disk (
+    session_data_id INTEGER NOT NULL,
+    mount_point_id INTEGER NOT NULL,
+    total INTEGER NOT NULL,
+    available INTEGER NOT NULL,
+    -- bytes read from and written to the device since boot, NULL where the
+    -- client platform does not count them
+    read_bytes INTEGER,
+    written_bytes INTEGER,
+
+    PRIMARY KEY (session_data_id, mount_point_id),
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE,
+    FOREIGN KEY (mount_point_id) REFERENCES strings(id)
+) WITHOUT ROWID;
+
+-- read path helper resolving interned mount points
+CREATE VIEW session_data_disk_named AS
+SELECT d.session_data_id, s.value AS mount_point, d.total, d.available,
+    d.read_bytes, d.written_bytes
+FROM session_data_disk d
+JOIN strings s ON s.id = d.mount_point_id;
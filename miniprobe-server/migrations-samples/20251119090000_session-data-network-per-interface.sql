@@ -0,0 +1,31 @@
+-- Add migration script here
+-- clients report every interface rather than a single one, one row per
+-- interface per sample
+CREATE TABLE session_data_network_per_interface (
+    session_data_id INTEGER NOT NULL,
+    ifname_id INTEGER NOT NULL,
+    rx_bytes INTEGER,
+    tx_bytes INTEGER,
+    delta BOOLEAN NOT NULL DEFAULT FALSE,
+
+    PRIMARY KEY (session_data_id, ifname_id),
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE,
+    FOREIGN KEY (ifname_id) REFERENCES strings(id)
+) WITHOUT ROWID;
+
+INSERT INTO session_data_network_per_interface
+    (session_data_id, ifname_id, rx_bytes, tx_bytes, delta)
+SELECT session_data_id, ifname_id, rx_bytes, tx_bytes, delta
+FROM session_data_network;
+
+DROP VIEW session_data_network_named;
+DROP TABLE session_data_network;
+ALTER TABLE session_data_network_per_interface RENAME TO session_data_network;
+
+-- read path helper resolving interned names
+CREATE VIEW session_data_network_named AS
+SELECT n.session_data_id, s.value AS ifname, n.rx_bytes, n.tx_bytes, n.delta
+FROM session_data_network n
+JOIN strings s ON s.id = n.ifname_id;
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- clients resend samples they are not sure were stored in the session after
+-- reconnecting, so sequence numbers are unique per client rather than per
+-- session. Samples stored before keep a NULL client, which never conflicts
+ALTER TABLE session_data ADD COLUMN client_id INTEGER;
+
+DROP INDEX session_data_session_id_seq;
+CREATE UNIQUE INDEX session_data_client_id_seq ON session_data(client_id, seq);
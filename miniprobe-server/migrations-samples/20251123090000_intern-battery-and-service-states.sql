@@ -0,0 +1,60 @@
+-- Add migration script here
+-- battery and service states repeat in every sample, interned like the names
+INSERT OR IGNORE INTO strings (value)
+SELECT state FROM session_data_battery
+UNION
+SELECT state FROM session_data_service;
+
+CREATE TABLE session_data_battery_interned (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    -- percent of the full charge
+    capacity REAL NOT NULL,
+    -- charging, discharging, full, not_charging or unknown
+    state_id INTEGER NOT NULL,
+    -- watts
+    power REAL,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE,
+    FOREIGN KEY (state_id) REFERENCES strings(id)
+) WITHOUT ROWID;
+
+INSERT INTO session_data_battery_interned (session_data_id, capacity, state_id, power)
+SELECT b.session_data_id, b.capacity, s.id, b.power
+FROM session_data_battery b
+JOIN strings s ON s.value = b.state;
+
+DROP TABLE session_data_battery;
+ALTER TABLE session_data_battery_interned RENAME TO session_data_battery;
+
+CREATE TABLE session_data_service_interned (
+    session_data_id INTEGER NOT NULL,
+    unit_id INTEGER NOT NULL,
+    -- active, reloading, inactive, failed, activating, deactivating or unknown
+    state_id INTEGER NOT NULL,
+    restarts INTEGER,
+
+    PRIMARY KEY (session_data_id, unit_id),
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE,
+    FOREIGN KEY (unit_id) REFERENCES strings(id),
+    FOREIGN KEY (state_id) REFERENCES strings(id)
+) WITHOUT ROWID;
+
+INSERT INTO session_data_service_interned (session_data_id, unit_id, state_id, restarts)
+SELECT v.session_data_id, v.unit_id, s.id, v.restarts
+FROM session_data_service v
+JOIN strings s ON s.value = v.state;
+
+DROP VIEW session_data_service_named;
+DROP TABLE session_data_service;
+ALTER TABLE session_data_service_interned RENAME TO session_data_service;
+
+-- read path helper resolving interned names
+CREATE VIEW session_data_service_named AS
+SELECT v.session_data_id, u.value AS unit, s.value AS state, v.restarts
+FROM session_data_service v
+JOIN strings u ON u.id = v.unit_id
+JOIN strings s ON s.id = v.state_id;
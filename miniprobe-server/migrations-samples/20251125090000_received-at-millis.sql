@@ -0,0 +1,4 @@
+-- Add migration script here
+-- receive times are unix milliseconds from now on like sample times, they
+-- were seconds
+UPDATE session_data SET received_at = received_at * 1000 WHERE received_at IS NOT NULL;
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- optional, human-facing metadata used by dashboards
+ALTER TABLE clients ADD COLUMN display_name TEXT;
+ALTER TABLE clients ADD COLUMN timezone TEXT;
+ALTER TABLE clients ADD COLUMN location TEXT;
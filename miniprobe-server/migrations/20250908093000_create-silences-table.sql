@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE silences (
+    id INTEGER PRIMARY KEY NOT NULL,
+    client_id INTEGER, -- NULL silences every client
+    starts_at INTEGER DEFAULT (unixepoch()) NOT NULL,
+    ends_at INTEGER NOT NULL,
+    reason TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (client_id) REFERENCES clients(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+);
+
+CREATE INDEX silences_ends_at ON silences(ends_at);
@@ -0,0 +1,2 @@
+-- Add migration script here
+CREATE INDEX session_data_session_id_sample_time ON session_data(session_id, sample_time);
@@ -0,0 +1,11 @@
+-- Add migration script here
+-- aggregate cpu reports, per-core reports keep using `session_data_cpu`
+CREATE TABLE session_data_cpu_aggregate (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    cpu_usage REAL NOT NULL,
+    max_core_usage REAL NOT NULL,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
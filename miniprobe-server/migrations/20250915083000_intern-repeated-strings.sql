@@ -0,0 +1,35 @@
+-- Add migration script here
+-- dictionary of strings repeated across samples (interface names etc.)
+CREATE TABLE strings (
+    id INTEGER PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL UNIQUE
+);
+
+INSERT OR IGNORE INTO strings (value)
+SELECT DISTINCT ifname FROM session_data_network;
+
+CREATE TABLE session_data_network_interned (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    ifname_id INTEGER NOT NULL,
+    rx_bytes INTEGER,
+    tx_bytes INTEGER,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE,
+    FOREIGN KEY (ifname_id) REFERENCES strings(id)
+) WITHOUT ROWID;
+
+INSERT INTO session_data_network_interned (session_data_id, ifname_id, rx_bytes, tx_bytes)
+SELECT n.session_data_id, s.id, n.rx_bytes, n.tx_bytes
+FROM session_data_network n
+JOIN strings s ON s.value = n.ifname;
+
+DROP TABLE session_data_network;
+ALTER TABLE session_data_network_interned RENAME TO session_data_network;
+
+-- read path helper resolving interned names
+CREATE VIEW session_data_network_named AS
+SELECT n.session_data_id, s.value AS ifname, n.rx_bytes, n.tx_bytes
+FROM session_data_network n
+JOIN strings s ON s.id = n.ifname_id;
@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE session_data_sensors (
+    session_data_id INTEGER PRIMARY KEY NOT NULL,
+    -- degrees Celsius
+    cpu_temperature REAL,
+    -- MHz
+    cpu_frequency INTEGER,
+
+    FOREIGN KEY (session_data_id) REFERENCES session_data(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+) WITHOUT ROWID;
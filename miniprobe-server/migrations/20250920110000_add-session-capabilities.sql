@@ -0,0 +1,3 @@
+-- Add migration script here
+-- collectors working on the client platform, as JSON
+ALTER TABLE sessions ADD COLUMN capabilities TEXT;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- per-client override of the daily sample quota, `NULL` uses the configured default
+ALTER TABLE clients ADD COLUMN samples_per_day INTEGER;
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- samples stored before sequence numbers existed keep NULL, which never conflicts
+ALTER TABLE session_data ADD COLUMN seq INTEGER;
+
+CREATE UNIQUE INDEX session_data_session_id_seq ON session_data(session_id, seq);
@@ -0,0 +1,27 @@
+-- Add migration script here
+-- names were not unique before, the later duplicates get their id appended.
+-- A name taken that way already gets a counter before the id, `<name>-<n>-<id>`.
+-- Every new name ends in the id of its client, so they never collide with
+-- each other, only with names kept
+CREATE TEMP TABLE renamed_clients AS
+WITH RECURSIVE
+    duplicates(id, name) AS (
+        SELECT id, name FROM clients
+        WHERE EXISTS (SELECT 1 FROM clients c WHERE c.name = clients.name AND c.id < clients.id)
+    ),
+    attempts(id, n, name) AS (
+        SELECT id, 0, name || '-' || id FROM duplicates
+        UNION ALL
+        SELECT a.id, a.n + 1, d.name || '-' || (a.n + 1) || '-' || a.id
+        FROM attempts a JOIN duplicates d ON d.id = a.id
+        WHERE a.name IN (SELECT name FROM clients WHERE id NOT IN (SELECT id FROM duplicates))
+    )
+SELECT id, name FROM attempts a
+WHERE n = (SELECT MAX(n) FROM attempts WHERE id = a.id);
+
+UPDATE clients SET name = (SELECT name FROM renamed_clients r WHERE r.id = clients.id)
+WHERE id IN (SELECT id FROM renamed_clients);
+
+DROP TABLE renamed_clients;
+
+CREATE UNIQUE INDEX clients_name ON clients(name);
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- how the latest ingress websocket of a session was closed, NULL while open
+-- or if it never connected
+ALTER TABLE sessions ADD COLUMN closed_at INTEGER;
+-- 'client' or 'server', whichever side closed first
+ALTER TABLE sessions ADD COLUMN closed_by TEXT;
+ALTER TABLE sessions ADD COLUMN close_code INTEGER;
+ALTER TABLE sessions ADD COLUMN close_reason TEXT;
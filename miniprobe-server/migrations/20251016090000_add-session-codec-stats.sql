@@ -0,0 +1,8 @@
+-- Add migration script here
+-- compression of the latest ingress websocket of a session, NULL if it never
+-- connected
+ALTER TABLE sessions ADD COLUMN compression TEXT;
+-- bytes of the binary and text messages of all connections of the session as
+-- received and after decompression
+ALTER TABLE sessions ADD COLUMN wire_bytes INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN decoded_bytes INTEGER NOT NULL DEFAULT 0;
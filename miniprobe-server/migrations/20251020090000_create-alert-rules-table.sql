@@ -0,0 +1,15 @@
+-- Add migration script here
+-- alert rules managed through the admin API, evaluated next to the rules of
+-- the config file
+CREATE TABLE alert_rules (
+    id INTEGER PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL UNIQUE,
+    -- as written, parsed again by the evaluator
+    expr TEXT NOT NULL,
+    for_secs INTEGER NOT NULL DEFAULT 0,
+    severity TEXT NOT NULL,
+    repeat_interval INTEGER,
+    auto_resolve BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at INTEGER DEFAULT (unixepoch()) NOT NULL,
+    updated_at INTEGER DEFAULT (unixepoch()) NOT NULL
+);
@@ -0,0 +1,19 @@
+-- Add migration script here
+-- latency percentiles of the samples ingested in a minute, in milliseconds:
+-- transit from the sample time of the client to its arrival at the server,
+-- store from its arrival until the sink wrote it
+CREATE TABLE ingest_latency (
+    id INTEGER PRIMARY KEY NOT NULL,
+    -- end of the minute, unix timestamp in seconds
+    time INTEGER NOT NULL,
+    samples INTEGER NOT NULL,
+    transit_p50 INTEGER NOT NULL,
+    transit_p95 INTEGER NOT NULL,
+    transit_p99 INTEGER NOT NULL,
+    transit_max INTEGER NOT NULL,
+    store_p50 INTEGER NOT NULL,
+    store_p95 INTEGER NOT NULL,
+    store_p99 INTEGER NOT NULL,
+    store_max INTEGER NOT NULL
+);
+CREATE INDEX ingest_latency_time ON ingest_latency(time);
@@ -0,0 +1,12 @@
+-- Add migration script here
+-- labels of a client, e.g. `env=prod`, to select groups of clients for fleet
+-- wide queries
+CREATE TABLE client_labels (
+    client_id INTEGER NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (client_id, key),
+    FOREIGN KEY (client_id) REFERENCES clients(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+);
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- hostname the sessions of a client are expected from, `NULL` accepts any
+ALTER TABLE clients ADD COLUMN expected_hostname TEXT;
+-- the session came from another hostname than expected and was let in
+ALTER TABLE sessions ADD COLUMN hostname_mismatch BOOLEAN NOT NULL DEFAULT FALSE;
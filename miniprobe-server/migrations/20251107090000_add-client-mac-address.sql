@@ -0,0 +1,3 @@
+-- Add migration script here
+-- MAC address Wake-on-LAN packets for the client are sent to, e.g. `00:11:22:aa:bb:cc`
+ALTER TABLE clients ADD COLUMN mac_address TEXT;
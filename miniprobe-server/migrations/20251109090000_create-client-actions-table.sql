@@ -0,0 +1,20 @@
+-- Add migration script here
+-- audit log of the actions asked of clients, kept when the client is removed
+CREATE TABLE client_actions (
+    id INTEGER PRIMARY KEY NOT NULL,
+    client_id INTEGER,
+    client_name TEXT NOT NULL,
+    session_id INTEGER,
+    action TEXT NOT NULL,
+    requested_at INTEGER DEFAULT (unixepoch()) NOT NULL,
+    finished_at INTEGER,
+    exit_code INTEGER,
+    output TEXT,
+    error TEXT,
+
+    FOREIGN KEY (client_id) REFERENCES clients(id)
+        ON DELETE SET NULL
+        ON UPDATE CASCADE
+);
+
+CREATE INDEX client_actions_client_id ON client_actions(client_id, id);
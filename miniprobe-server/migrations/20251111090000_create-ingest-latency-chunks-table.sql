@@ -0,0 +1,12 @@
+-- Add migration script here
+-- rows of ingest_latency of a finished hour compressed into one chunk, see
+-- the `gorilla` module of the server for the encoding
+CREATE TABLE ingest_latency_chunks (
+    id INTEGER PRIMARY KEY NOT NULL,
+    -- time of the first and last row, unix timestamps in seconds
+    start_time INTEGER NOT NULL,
+    end_time INTEGER NOT NULL,
+    rows INTEGER NOT NULL,
+    data BLOB NOT NULL
+);
+CREATE INDEX ingest_latency_chunks_end_time ON ingest_latency_chunks(end_time);
@@ -0,0 +1,20 @@
+-- Add migration script here
+-- failed authentications, lockouts and other security relevant events, the
+-- client is kept by name when it is removed
+CREATE TABLE security_events (
+    id INTEGER PRIMARY KEY NOT NULL,
+    -- unix timestamp in seconds
+    time INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    client_id INTEGER,
+    client_name TEXT,
+    -- peer address of the request, NULL on unix sockets
+    address TEXT,
+    detail TEXT NOT NULL,
+
+    FOREIGN KEY (client_id) REFERENCES clients(id)
+        ON DELETE SET NULL
+        ON UPDATE CASCADE
+);
+
+CREATE INDEX security_events_time ON security_events(time);
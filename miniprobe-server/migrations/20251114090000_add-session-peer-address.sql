@@ -0,0 +1,4 @@
+-- Add migration script here
+-- address the session was created from, NULL on unix sockets and for
+-- sessions created before it was recorded
+ALTER TABLE sessions ADD COLUMN peer_address TEXT;
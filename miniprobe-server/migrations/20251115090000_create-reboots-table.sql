@@ -0,0 +1,22 @@
+-- Add migration script here
+-- boot time the client reported with the session, unix timestamp in seconds
+ALTER TABLE sessions ADD COLUMN boot_time INTEGER;
+
+-- reboots of the host of a client, noticed when a session reports a later
+-- boot time than the previous session did
+CREATE TABLE reboots (
+    id INTEGER PRIMARY KEY NOT NULL,
+    client_id INTEGER NOT NULL,
+    -- the first session after the reboot
+    session_id INTEGER NOT NULL,
+    -- unix timestamps in seconds
+    boot_time INTEGER NOT NULL,
+    previous_boot_time INTEGER NOT NULL,
+    detected_at INTEGER DEFAULT (unixepoch()) NOT NULL,
+
+    FOREIGN KEY (client_id) REFERENCES clients(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+);
+
+CREATE INDEX reboots_client_id ON reboots(client_id, boot_time);
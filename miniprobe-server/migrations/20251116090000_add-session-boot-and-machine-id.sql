@@ -0,0 +1,6 @@
+-- Add migration script here
+-- random id of the boot the session started in, tells reboots apart from
+-- clock adjustments
+ALTER TABLE sessions ADD COLUMN boot_id TEXT;
+-- id of the installation of the host, kept across reboots and hostname changes
+ALTER TABLE sessions ADD COLUMN machine_id TEXT;
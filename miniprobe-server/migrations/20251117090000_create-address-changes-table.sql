@@ -0,0 +1,19 @@
+-- Add migration script here
+-- primary addresses of the host of a client, a row whenever a sample reports
+-- other addresses than the latest row of the client
+CREATE TABLE address_changes (
+    id INTEGER PRIMARY KEY NOT NULL,
+    client_id INTEGER NOT NULL,
+    -- the session reporting the addresses
+    session_id INTEGER NOT NULL,
+    -- sample time, unix timestamp in seconds
+    changed_at INTEGER NOT NULL,
+    ipv4 TEXT,
+    ipv6 TEXT,
+
+    FOREIGN KEY (client_id) REFERENCES clients(id)
+        ON DELETE CASCADE
+        ON UPDATE CASCADE
+);
+
+CREATE INDEX address_changes_client_id ON address_changes(client_id, id);
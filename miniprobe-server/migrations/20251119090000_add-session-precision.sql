@@ -0,0 +1,4 @@
+-- Add migration script here
+-- precision of the samples of the latest ingress websocket of a session,
+-- `full` or `coarse`, NULL if it never connected
+ALTER TABLE sessions ADD COLUMN precision TEXT;
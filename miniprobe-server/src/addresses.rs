@@ -0,0 +1,85 @@
+//! History of the primary addresses of the hosts of clients, to find hosts
+//! with dynamic addresses and tell how often their leases change.
+//!
+//! Clients send their addresses with the first sample of a session and when
+//! they changed, a row is only added when they differ from the latest row of
+//! the client, so reconnecting clients do not fill the history.
+
+use miniprobe_proto::HostAddresses;
+use sqlx::SqlitePool;
+
+/// Add `addresses` to the history of `client_id` if they changed, returns
+/// whether they did.
+pub async fn record(
+    db: &SqlitePool,
+    client_id: i64,
+    session_id: i64,
+    changed_at: i64,
+    addresses: &HostAddresses,
+) -> sqlx::Result<bool> {
+    let ipv4 = addresses.ipv4.map(|addr| addr.to_string());
+    let ipv6 = addresses.ipv6.map(|addr| addr.to_string());
+    let inserted = sqlx::query!(
+        "INSERT INTO address_changes (client_id, session_id, changed_at, ipv4, ipv6) \
+            SELECT ?1, ?2, ?3, ?4, ?5 \
+            WHERE NOT EXISTS ( \
+                SELECT 1 FROM address_changes \
+                WHERE id = (SELECT MAX(id) FROM address_changes WHERE client_id = ?1) \
+                    AND ipv4 IS ?4 AND ipv6 IS ?5 \
+            )",
+        client_id,
+        session_id,
+        changed_at,
+        ipv4,
+        ipv6
+    )
+    .execute(db)
+    .await?;
+    Ok(inserted.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    #[tokio::test]
+    async fn only_changes_recorded() {
+        let db = Db::connect(
+            "sqlite:file:addresses-only-changes?mode=memory&cache=shared",
+            "sqlite:file:addresses-only-changes-samples?mode=memory&cache=shared",
+            1,
+            None,
+        )
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        sqlx::query(
+            "INSERT INTO clients (id, name, token_idx, token_hash) VALUES (1, 'web-1', 0, '')",
+        )
+        .execute(&db.writer)
+        .await
+        .unwrap();
+
+        let home = HostAddresses {
+            ipv4: Some("192.0.2.7".parse().unwrap()),
+            ipv6: None,
+        };
+        let moved = HostAddresses {
+            ipv4: Some("198.51.100.3".parse().unwrap()),
+            ipv6: Some("2001:db8::7".parse().unwrap()),
+        };
+        let db = &db.writer;
+        assert!(record(db, 1, 1, 100, &home).await.unwrap());
+        assert!(!record(db, 1, 2, 200, &home).await.unwrap());
+        assert!(record(db, 1, 2, 300, &moved).await.unwrap());
+        assert!(record(db, 1, 3, 400, &home).await.unwrap());
+
+        let changes: Vec<i64> =
+            sqlx::query_scalar("SELECT changed_at FROM address_changes ORDER BY id")
+                .fetch_all(db)
+                .await
+                .unwrap();
+        assert_eq!(changes, [100, 300, 400]);
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Subcommand;
+use serde::{Deserialize, de::DeserializeOwned};
+use sqlx::{Pool, Sqlite, types::time::OffsetDateTime};
+
+use super::{format_local_time, parse_time};
+use crate::{
+    alert::{
+        AlertRule, Transition, replay,
+        rules::{self, RuleError, RuleSpec},
+        templates::{self, TEMPLATES},
+    },
+    expr::fetch_samples,
+};
+
+#[derive(Debug, Subcommand)]
+pub enum AlertsCommands {
+    /// List the alert rules stored in the database
+    #[clap(visible_alias("ls"))]
+    List,
+    /// Store alert rules, evaluated next to the rules of the config
+    #[clap(visible_alias("a"))]
+    Add {
+        /// TOML file with a single rule, or a `[[rules]]` list, in the format of `alerts.rules`
+        #[arg(long)]
+        rule: PathBuf,
+    },
+    /// Replace a stored alert rule
+    Update {
+        id: i64,
+        /// TOML file with a single rule in the format of `alerts.rules`
+        #[arg(long)]
+        rule: PathBuf,
+    },
+    /// Remove a stored alert rule
+    #[clap(visible_alias("rm"))]
+    Remove { id: i64 },
+    /// Replay stored samples through alert rules and report when they would have fired
+    Test {
+        /// TOML file with a single rule, or a `[[rules]]` list, in the format of `alerts.rules`
+        #[arg(long)]
+        rule: PathBuf,
+        /// Start of the replay: unix timestamp, RFC 3339 time or a duration ago (e.g. `7d`)
+        #[arg(long, default_value = "1d", value_parser = parse_time)]
+        from: i64,
+        /// End of the replay in the same formats as `--from`, now if omitted
+        #[arg(long, value_parser = parse_time)]
+        to: Option<i64>,
+        /// Interval between two evaluations
+        #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+        step: Duration,
+        /// Only replay samples of this client
+        #[arg(long)]
+        client: Option<i64>,
+    },
+    /// Print a built-in rule to store with `add`, list them without a name
+    Template { name: Option<String> },
+}
+
+pub async fn alerts(
+    command: AlertsCommands,
+    pool: &Pool<Sqlite>,
+    conf_rules: &[AlertRule],
+) -> anyhow::Result<()> {
+    match command {
+        AlertsCommands::List => list_rules(pool).await,
+        AlertsCommands::Add { rule } => add_rules(pool, rule, conf_rules).await,
+        AlertsCommands::Update { id, rule } => update_rule(pool, id, rule, conf_rules).await,
+        AlertsCommands::Remove { id } => remove_rule(pool, id).await,
+        AlertsCommands::Test {
+            rule,
+            from,
+            to,
+            step,
+            client,
+        } => test_rules(pool, rule, from, to, step, client).await,
+        AlertsCommands::Template { name } => template(name.as_deref()),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+struct RuleFile<T> {
+    rules: Vec<T>,
+}
+
+/// Parse a file with a single rule or a `[[rules]]` list.
+fn parse_rules<T: DeserializeOwned>(content: &str) -> Result<Vec<T>, toml::de::Error> {
+    let table: toml::Table = toml::from_str(content)?;
+    if table.contains_key("rules") {
+        Ok(toml::from_str::<RuleFile<T>>(content)?.rules)
+    } else {
+        Ok(vec![toml::from_str::<T>(content)?])
+    }
+}
+
+fn read_rules<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Vec<T>> {
+    parse_rules(&std::fs::read_to_string(path)?)
+        .map_err(|e| anyhow::anyhow!("invalid rule file {}: {e}", path.display()))
+}
+
+fn template(name: Option<&str>) -> anyhow::Result<()> {
+    let Some(name) = name else {
+        for template in TEMPLATES {
+            println!("{:<20} {}", template.name, template.description);
+        }
+        return Ok(());
+    };
+    let template =
+        templates::find(name).ok_or_else(|| anyhow::anyhow!("No rule template named '{name}'."))?;
+    print!("{}", template.rule);
+    Ok(())
+}
+
+async fn list_rules(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    let rules = rules::list(pool).await?;
+    if rules.is_empty() {
+        println!("No alert rules stored.");
+    }
+
+    for rule in rules {
+        let spec = rule.spec;
+        println!(
+            "[{}] {} ({}): {}{}{}{}, updated {}",
+            rule.id,
+            spec.name,
+            spec.severity.as_str(),
+            spec.expr,
+            if spec.for_secs > 0 {
+                format!(" for {}s", spec.for_secs)
+            } else {
+                String::new()
+            },
+            spec.repeat_interval
+                .map(|interval| format!(", repeat every {interval}s"))
+                .unwrap_or_default(),
+            if spec.auto_resolve {
+                ""
+            } else {
+                ", no auto resolve"
+            },
+            format_local_time(OffsetDateTime::from_unix_timestamp(rule.updated_at)?)
+        );
+    }
+
+    Ok(())
+}
+
+async fn add_rules(
+    pool: &Pool<Sqlite>,
+    path: PathBuf,
+    conf_rules: &[AlertRule],
+) -> anyhow::Result<()> {
+    let specs = read_rules::<RuleSpec>(&path)?;
+    // validate every rule before storing any
+    for spec in &specs {
+        rules::check(spec, conf_rules).map_err(|e| anyhow::anyhow!("rule '{}': {e}", spec.name))?;
+    }
+
+    for spec in specs {
+        let rule = rules::create(pool, &spec, conf_rules)
+            .await
+            .map_err(|e| anyhow::anyhow!("rule '{}': {e}", spec.name))?;
+        println!("Alert rule [{}] '{}' added.", rule.id, rule.spec.name);
+    }
+    Ok(())
+}
+
+async fn update_rule(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    path: PathBuf,
+    conf_rules: &[AlertRule],
+) -> anyhow::Result<()> {
+    let [spec] = <[RuleSpec; 1]>::try_from(read_rules::<RuleSpec>(&path)?)
+        .map_err(|_| anyhow::anyhow!("{} must contain a single rule", path.display()))?;
+    match rules::update(pool, id, &spec, conf_rules).await {
+        Ok(rule) => println!("Alert rule [{id}] '{}' updated.", rule.spec.name),
+        Err(RuleError::NotFound(_)) => println!("No alert rule found with ID {id}."),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+async fn remove_rule(pool: &Pool<Sqlite>, id: i64) -> anyhow::Result<()> {
+    match rules::delete(pool, id).await {
+        Ok(()) => println!("Alert rule with ID {id} removed successfully."),
+        Err(RuleError::NotFound(_)) => println!("No alert rule found with ID {id}."),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+async fn test_rules(
+    pool: &Pool<Sqlite>,
+    path: PathBuf,
+    from: i64,
+    to: Option<i64>,
+    step: Duration,
+    client: Option<i64>,
+) -> anyhow::Result<()> {
+    let rules = read_rules::<AlertRule>(&path)?;
+    let to = match to {
+        Some(to) => to,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+    };
+    if from > to {
+        anyhow::bail!("--from is after --to");
+    }
+
+    let clients = sqlx::query!(
+        "SELECT id, name FROM clients WHERE $1 IS NULL OR id = $1 ORDER BY id",
+        client
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let lookback = rules.iter().map(|r| r.expr.lookback()).max().unwrap_or(0);
+    let mut fired = vec![0; rules.len()];
+    for client in clients {
+        let samples =
+            fetch_samples(&mut *pool.acquire().await?, client.id, from - lookback, to).await?;
+        if samples.is_empty() {
+            continue;
+        }
+
+        for (rule, fired) in rules.iter().zip(&mut fired) {
+            for event in replay(rule, &samples, from, to, step.as_secs() as i64) {
+                let status = match event.transition {
+                    Transition::Firing => {
+                        *fired += 1;
+                        "firing"
+                    }
+                    Transition::Repeat => "repeat",
+                    Transition::Resolved => "resolved",
+                };
+                println!(
+                    "{} {} [{}] {}: {status}{}",
+                    format_local_time(OffsetDateTime::from_unix_timestamp(event.time)?),
+                    client.name,
+                    client.id,
+                    rule.name,
+                    event
+                        .value
+                        .map(|value| format!(" (value: {value})"))
+                        .unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    for (rule, fired) in rules.iter().zip(fired) {
+        println!("rule '{}' would have fired {fired} time(s)", rule.name);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("1700000000"), Ok(1700000000));
+        assert_eq!(parse_time("2023-11-14T22:13:20Z"), Ok(1700000000));
+        assert_eq!(parse_time("2023-11-14 22:13:20"), Ok(1700000000));
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let day_ago = parse_time("1d").unwrap();
+        assert!((now - 86400 - day_ago).abs() <= 1);
+
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn rule_files() {
+        let one = parse_rules::<AlertRule>("name = \"cpu\"\nexpr = \"cpu > 0.9\"\n").unwrap();
+        assert_eq!(one.len(), 1);
+        assert_eq!(one[0].name, "cpu");
+
+        let many = parse_rules::<AlertRule>(
+            "[[rules]]\nname = \"cpu\"\nexpr = \"cpu > 0.9\"\n\n\
+             [[rules]]\nname = \"mem\"\nexpr = \"memory > 0.9\"\nfor = 300\n",
+        )
+        .unwrap();
+        assert_eq!(many.len(), 2);
+        assert_eq!(many[1].for_secs, 300);
+
+        let err = parse_rules::<AlertRule>("name = \"cpu\"\nexpr = \"cpu >\"\n").unwrap_err();
+        assert!(err.to_string().contains("expected"));
+    }
+}
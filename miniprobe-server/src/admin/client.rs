@@ -0,0 +1,591 @@
+use clap::Subcommand;
+use miniprobe_proto::limits::CLIENT_TOKEN_LENGTH;
+use rand::{Rng, distr::Alphanumeric};
+use sqlx::{Pool, Sqlite, Transaction, types::time::OffsetDateTime};
+
+use super::format_local_time;
+use crate::{
+    index_client_token,
+    labels::{self, parse_label},
+    overview::{self, ClientState, ClientStatus},
+    wol,
+};
+
+#[derive(Debug, Subcommand)]
+pub enum ClientCommands {
+    /// List all clients
+    #[clap(visible_alias("ls"))]
+    List,
+    /// Add a new client
+    #[clap(visible_alias("a"))]
+    Add {
+        username: String,
+        /// Take the name from the client holding it, which is renamed to `<name>-<id>`
+        #[arg(long)]
+        force: bool,
+    },
+    /// Remove a client
+    #[clap(visible_alias("rm"))]
+    Remove { id: i64 },
+    /// Rename a client
+    Rename {
+        id: i64,
+        new_username: String,
+        /// Take the name from the client holding it, which is renamed to `<name>-<id>`
+        #[arg(long)]
+        force: bool,
+    },
+    /// Set display metadata of a client, an empty value clears the field
+    Meta {
+        id: i64,
+        /// Human friendly name shown by dashboards
+        #[arg(long)]
+        display_name: Option<String>,
+        /// IANA time zone (e.g. `Asia/Shanghai`), `UTC` or a fixed offset (e.g. `+08:00`)
+        #[arg(long)]
+        timezone: Option<String>,
+        /// Free-form location of the host
+        #[arg(long)]
+        location: Option<String>,
+    },
+    /// Set labels of a client used by fleet queries, `key=` removes a label
+    Label {
+        id: i64,
+        /// Labels as `key=value`, e.g. `env=prod`
+        #[arg(required = true, value_parser = parse_label)]
+        labels: Vec<(String, Option<String>)>,
+    },
+    /// Bind the token of a client to the hostname of its machine, omit the
+    /// hostname to accept any
+    Hostname { id: i64, hostname: Option<String> },
+    /// Set the MAC address `POST /api/v1/clients/{id}/wake` sends Wake-on-LAN
+    /// packets to, e.g. `00:11:22:aa:bb:cc`, omit it to clear
+    Mac {
+        id: i64,
+        #[arg(value_parser = parse_mac)]
+        mac_address: Option<String>,
+    },
+    /// Override the daily sample quota of a client, omit it to use the configured default
+    Quota {
+        id: i64,
+        samples_per_day: Option<u64>,
+    },
+}
+
+pub async fn client(command: ClientCommands, pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    match command {
+        ClientCommands::List => list_clients(pool).await,
+        ClientCommands::Add { username, force } => add_client(pool, username, force).await,
+        ClientCommands::Rename {
+            id,
+            new_username,
+            force,
+        } => rename_client(pool, id, new_username, force).await,
+        ClientCommands::Remove { id } => remove_client(pool, id).await,
+        ClientCommands::Meta {
+            id,
+            display_name,
+            timezone,
+            location,
+        } => set_client_meta(pool, id, display_name, timezone, location).await,
+        ClientCommands::Label { id, labels } => set_client_labels(pool, id, labels).await,
+        ClientCommands::Hostname { id, hostname } => set_client_hostname(pool, id, hostname).await,
+        ClientCommands::Mac { id, mac_address } => set_client_mac(pool, id, mac_address).await,
+        ClientCommands::Quota {
+            id,
+            samples_per_day,
+        } => set_client_quota(pool, id, samples_per_day).await,
+    }
+}
+
+async fn list_clients(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    let clients = sqlx::query!(
+        r#"
+        SELECT c.id, c.name, c.created_at, c.display_name, c.timezone, c.location,
+            c.expected_hostname, c.mac_address, c.samples_per_day,
+            (
+                SELECT COUNT(*) FROM session_data d
+                JOIN sessions s ON s.id = d.session_id
+                WHERE s.client_id = c.id AND d.sample_time >= unixepoch('now', 'start of day') * 1000
+            ) AS "samples_today!: i64"
+        FROM clients c
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut statuses = overview::clients_status(pool).await?;
+    let mut labels = labels::load(pool).await?;
+
+    for client in clients {
+        println!(
+            "[{}] {} (created at: {})",
+            client.id,
+            client.name,
+            format_local_time(client.created_at)
+        );
+
+        let meta = [
+            ("display name", client.display_name),
+            ("timezone", client.timezone),
+            ("location", client.location),
+            ("expected hostname", client.expected_hostname),
+            ("mac address", client.mac_address),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| format!("{key}: {value}")))
+        .collect::<Vec<_>>();
+        if !meta.is_empty() {
+            println!("    {}", meta.join(", "));
+        }
+        if let Some(labels) = labels.remove(&client.id) {
+            let labels = labels
+                .into_iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>();
+            println!("    labels: {}", labels.join(", "));
+        }
+        match client.samples_per_day {
+            Some(quota) => println!("    samples today: {}/{quota}", client.samples_today),
+            None => println!("    samples today: {}", client.samples_today),
+        }
+        if let Some(status) = statuses.remove(&client.id) {
+            print_status(&status)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print_status(status: &ClientStatus) -> anyhow::Result<()> {
+    let Some(last_sample) = status.last_sample else {
+        println!("    never seen");
+        return Ok(());
+    };
+    println!(
+        "    last seen: {} ({}s ago{})",
+        format_local_time(OffsetDateTime::from_unix_timestamp(last_sample)?),
+        status.staleness.unwrap_or_default(),
+        if status.state == ClientState::Stale {
+            ", stale"
+        } else {
+            ""
+        }
+    );
+    if let (Some(cpu), Some(memory)) = (status.cpu_avg_24h, status.memory_avg_24h) {
+        println!(
+            "    24h average: cpu {cpu:.1}%, memory {:.1}%",
+            memory * 100.0
+        );
+    }
+    Ok(())
+}
+
+async fn add_client(pool: &Pool<Sqlite>, username: String, force: bool) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    claim_name(&mut tx, &username, None, force).await?;
+    let (id, token) = insert_client(&mut tx, &username).await?;
+    tx.commit().await?;
+
+    println!("Client '{username}' [{id}] added successfully.");
+    println!("Token: {token}");
+    Ok(())
+}
+
+/// Insert a client with a new token, returns its ID and the token. The name
+/// must be free, see [`claim_name`].
+pub(super) async fn insert_client(
+    tx: &mut Transaction<'_, Sqlite>,
+    username: &str,
+) -> anyhow::Result<(i64, String)> {
+    // Ensure the token is unique
+    let (token, token_idx, token_hash) = loop {
+        let token: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(CLIENT_TOKEN_LENGTH)
+            .map(char::from)
+            .collect();
+
+        let token_idx = index_client_token(&token);
+        let token_hash = password_auth::generate_hash(&token);
+
+        if sqlx::query!("SELECT id FROM clients WHERE token_hash = ?", token_hash)
+            .fetch_optional(&mut **tx)
+            .await?
+            .is_none()
+        {
+            break (token, token_idx, token_hash);
+        }
+    };
+
+    let record = sqlx::query!(
+        "INSERT INTO clients (name, token_idx, token_hash) VALUES (?, ?, ?) RETURNING id",
+        username,
+        token_idx,
+        token_hash
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok((record.id, token))
+}
+
+async fn remove_client(pool: &Pool<Sqlite>, id: i64) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    // samples are in the attached database, out of reach of foreign keys
+    // from the sessions; the rows of the metric tables go with their sample
+    sqlx::query!(
+        "DELETE FROM session_data WHERE session_id IN (SELECT id FROM sessions WHERE client_id = ?)",
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM sessions WHERE client_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    let rows_affected = sqlx::query!("DELETE FROM clients WHERE id = ?", id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+
+    if rows_affected == 0 {
+        println!("No client found with ID {id}.");
+    } else {
+        println!("Client with ID {id} removed successfully.");
+    }
+
+    Ok(())
+}
+
+async fn rename_client(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    new_username: String,
+    force: bool,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    if sqlx::query!("SELECT id FROM clients WHERE id = ?", id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_none()
+    {
+        println!("No client found with ID {id}.");
+        return Ok(());
+    }
+    claim_name(&mut tx, &new_username, Some(id), force).await?;
+
+    sqlx::query!("UPDATE clients SET name = ? WHERE id = ?", new_username, id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    println!("Client with ID {id} renamed successfully.");
+    Ok(())
+}
+
+/// Make sure `name` is free for client `id`, or a new client if `None`.
+///
+/// A name held by another client is an error, unless `force` renames that
+/// client to `<name>-<id>`.
+async fn claim_name(
+    tx: &mut Transaction<'_, Sqlite>,
+    name: &str,
+    id: Option<i64>,
+    force: bool,
+) -> anyhow::Result<()> {
+    let Some(holder) =
+        sqlx::query_scalar!(r#"SELECT id AS "id!" FROM clients WHERE name = ?"#, name)
+            .fetch_optional(&mut **tx)
+            .await?
+    else {
+        return Ok(());
+    };
+    if Some(holder) == id {
+        return Ok(());
+    }
+    if !force {
+        anyhow::bail!(
+            "client name '{name}' is already taken by client [{holder}], \
+             rename that client first or pass --force to take the name from it"
+        );
+    }
+
+    let released = format!("{name}-{holder}");
+    sqlx::query!("UPDATE clients SET name = ? WHERE id = ?", released, holder)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                anyhow::anyhow!(
+                    "can not rename client [{holder}] to '{released}', the name is taken"
+                )
+            }
+            e => e.into(),
+        })?;
+    println!("Client [{holder}] renamed to '{released}'.");
+    Ok(())
+}
+
+async fn set_client_meta(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    display_name: Option<String>,
+    timezone: Option<String>,
+    location: Option<String>,
+) -> anyhow::Result<()> {
+    if let Some(timezone) = timezone.as_deref().filter(|tz| !tz.is_empty()) {
+        validate_timezone(timezone)?;
+    }
+
+    // `NULL` keeps the current value, an empty string clears it
+    let rows_affected = sqlx::query!(
+        "UPDATE clients SET \
+            display_name = CASE WHEN $1 IS NULL THEN display_name ELSE NULLIF($1, '') END, \
+            timezone = CASE WHEN $2 IS NULL THEN timezone ELSE NULLIF($2, '') END, \
+            location = CASE WHEN $3 IS NULL THEN location ELSE NULLIF($3, '') END \
+            WHERE id = $4",
+        display_name,
+        timezone,
+        location,
+        id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        println!("No client found with ID {id}.");
+    } else {
+        println!("Client with ID {id} updated successfully.");
+    }
+
+    Ok(())
+}
+
+async fn set_client_labels(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    labels: Vec<(String, Option<String>)>,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    if sqlx::query!("SELECT id FROM clients WHERE id = ?", id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_none()
+    {
+        println!("No client found with ID {id}.");
+        return Ok(());
+    }
+    for (key, value) in labels {
+        match value {
+            Some(value) => {
+                sqlx::query!(
+                    "INSERT INTO client_labels (client_id, key, value) VALUES (?, ?, ?) \
+                    ON CONFLICT (client_id, key) DO UPDATE SET value = excluded.value",
+                    id,
+                    key,
+                    value
+                )
+                .execute(&mut *tx)
+                .await?
+            }
+            None => {
+                sqlx::query!(
+                    "DELETE FROM client_labels WHERE client_id = ? AND key = ?",
+                    id,
+                    key
+                )
+                .execute(&mut *tx)
+                .await?
+            }
+        };
+    }
+    tx.commit().await?;
+
+    println!("Labels of client with ID {id} updated successfully.");
+    Ok(())
+}
+
+async fn set_client_hostname(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    hostname: Option<String>,
+) -> anyhow::Result<()> {
+    let rows_affected = sqlx::query!(
+        "UPDATE clients SET expected_hostname = ? WHERE id = ?",
+        hostname,
+        id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        println!("No client found with ID {id}.");
+    } else if let Some(hostname) = hostname {
+        println!("Client with ID {id} bound to hostname '{hostname}'.");
+    } else {
+        println!("Client with ID {id} accepts any hostname.");
+    }
+
+    Ok(())
+}
+
+/// A MAC address in the form it is stored in, lowercase and separated by `:`.
+fn parse_mac(text: &str) -> Result<String, String> {
+    let mac = wol::parse_mac(text).ok_or("expected a MAC address like 00:11:22:aa:bb:cc")?;
+    Ok(mac.map(|byte| format!("{byte:02x}")).join(":"))
+}
+
+async fn set_client_mac(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    mac_address: Option<String>,
+) -> anyhow::Result<()> {
+    let rows_affected = sqlx::query!(
+        "UPDATE clients SET mac_address = ? WHERE id = ?",
+        mac_address,
+        id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        println!("No client found with ID {id}.");
+    } else {
+        println!("MAC address of client with ID {id} updated successfully.");
+    }
+
+    Ok(())
+}
+
+async fn set_client_quota(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    samples_per_day: Option<u64>,
+) -> anyhow::Result<()> {
+    let samples_per_day = samples_per_day.map(|n| n as i64);
+    let rows_affected = sqlx::query!(
+        "UPDATE clients SET samples_per_day = ? WHERE id = ?",
+        samples_per_day,
+        id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        println!("No client found with ID {id}.");
+    } else {
+        println!("Quota of client with ID {id} updated successfully.");
+    }
+
+    Ok(())
+}
+
+/// Accept `UTC`, fixed offsets like `+08:00` and IANA names like `America/New_York`.
+///
+/// The server has no time zone database, so IANA names are only checked for shape.
+fn validate_timezone(tz: &str) -> anyhow::Result<()> {
+    let is_offset = |tz: &str| {
+        let bytes = tz.as_bytes();
+        bytes.len() == 6
+            && matches!(bytes[0], b'+' | b'-')
+            && bytes[3] == b':'
+            && [1, 2, 4, 5].iter().all(|&i| bytes[i].is_ascii_digit())
+    };
+    let is_iana = |tz: &str| {
+        tz.split('/').count() >= 2
+            && tz.split('/').all(|part| {
+                !part.is_empty()
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+            })
+    };
+
+    if tz == "UTC" || is_offset(tz) || is_iana(tz) {
+        Ok(())
+    } else {
+        anyhow::bail!("invalid timezone '{tz}', expected `UTC`, `+HH:MM` or an IANA name")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    #[tokio::test]
+    async fn removed_with_sessions_and_samples() {
+        let db = Db::connect(
+            "sqlite:file:remove-client?mode=memory&cache=shared",
+            "sqlite:file:remove-client-samples?mode=memory&cache=shared",
+            1,
+            None,
+        )
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        for (client, name) in [(1, "gone"), (2, "kept")] {
+            sqlx::query(
+                "INSERT INTO clients (id, name, token_idx, token_hash) VALUES (?, ?, ?, ?)",
+            )
+            .bind(client)
+            .bind(name)
+            .bind(name)
+            .bind(name)
+            .execute(&db.writer)
+            .await
+            .unwrap();
+            let session: i64 = sqlx::query_scalar(
+                "INSERT INTO sessions (client_id, cpu_arch) VALUES (?, 'x86_64') RETURNING id",
+            )
+            .bind(client)
+            .fetch_one(&db.writer)
+            .await
+            .unwrap();
+            let sample: i64 = sqlx::query_scalar(
+                "INSERT INTO samples.session_data (session_id, sample_time) VALUES (?, 0) RETURNING id",
+            )
+            .bind(session)
+            .fetch_one(&db.writer)
+            .await
+            .unwrap();
+            sqlx::query(
+                "INSERT INTO samples.session_data_cpu (session_data_id, cpu_id, cpu_usage) VALUES (?, 0, 0.5)",
+            )
+            .bind(sample)
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        }
+
+        remove_client(&db.writer, 1).await.unwrap();
+
+        for table in [
+            "clients",
+            "sessions",
+            "samples.session_data",
+            "samples.session_data_cpu",
+        ] {
+            let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+                .fetch_one(&db.writer)
+                .await
+                .unwrap();
+            assert_eq!(count, 1, "{table}");
+        }
+    }
+
+    #[test]
+    fn timezones() {
+        assert!(validate_timezone("UTC").is_ok());
+        assert!(validate_timezone("+08:00").is_ok());
+        assert!(validate_timezone("-03:30").is_ok());
+        assert!(validate_timezone("Asia/Shanghai").is_ok());
+        assert!(validate_timezone("America/Argentina/Buenos_Aires").is_ok());
+        assert!(validate_timezone("Shanghai").is_err());
+        assert!(validate_timezone("+8").is_err());
+        assert!(validate_timezone("Asia//Shanghai").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::{self, BufRead, Write};
+
+use clap::{Args, Subcommand};
+use sqlx::{Pool, Sqlite, types::time::OffsetDateTime};
+
+use super::{format_local_time, parse_time, usage};
+
+#[derive(Debug, Subcommand)]
+pub enum DataCommands {
+    /// Delete stored samples of a client or session
+    Purge {
+        #[command(flatten)]
+        target: PurgeTarget,
+        /// Only delete samples taken before this time: unix timestamp, RFC 3339 time or a
+        /// duration ago (e.g. `90d`)
+        #[arg(long, value_parser = parse_time)]
+        before: Option<i64>,
+        /// Also delete the sessions left without samples, except active ones
+        #[arg(long)]
+        sessions: bool,
+        /// Do not ask for confirmation
+        #[arg(long, short)]
+        yes: bool,
+    },
+    /// Show the storage taken by each table, metric family and client
+    Usage {
+        /// Only break down the metric families of this client
+        #[arg(long)]
+        client: Option<i64>,
+    },
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+pub struct PurgeTarget {
+    /// Purge every session of this client
+    #[arg(long)]
+    client: Option<i64>,
+    /// Purge a single session
+    #[arg(long)]
+    session: Option<i64>,
+}
+
+pub async fn data(command: DataCommands, pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    match command {
+        DataCommands::Purge {
+            target,
+            before,
+            sessions,
+            yes,
+        } => purge(pool, target, before, sessions, yes).await,
+        DataCommands::Usage { client } => usage::usage(pool, client).await,
+    }
+}
+
+async fn purge(
+    pool: &Pool<Sqlite>,
+    target: PurgeTarget,
+    before: Option<i64>,
+    sessions: bool,
+    yes: bool,
+) -> anyhow::Result<()> {
+    let PurgeTarget { client, session } = target;
+    let description = match (client, session) {
+        (Some(id), _) => {
+            let Some(name) = sqlx::query_scalar!("SELECT name FROM clients WHERE id = ?", id)
+                .fetch_optional(pool)
+                .await?
+            else {
+                println!("No client found with ID {id}.");
+                return Ok(());
+            };
+            format!("client '{name}' [{id}]")
+        }
+        (None, Some(id)) => format!("session {id}"),
+        (None, None) => unreachable!("clap requires a target"),
+    };
+
+    // samples of removed clients keep their session, so a session is matched
+    // by id alone
+    let samples = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!: i64" FROM session_data d
+        WHERE (d.session_id = $2 OR d.session_id IN (SELECT id FROM sessions WHERE client_id = $1))
+            AND ($3 IS NULL OR d.sample_time < $3 * 1000)
+        "#,
+        client,
+        session,
+        before
+    )
+    .fetch_one(pool)
+    .await?;
+    let empty_sessions = if sessions {
+        sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!: i64" FROM sessions s
+            WHERE (s.id = $2 OR s.client_id = $1)
+                AND s.id NOT IN (SELECT id FROM non_expired_sessions)
+                AND NOT EXISTS (
+                    SELECT 1 FROM session_data d
+                    WHERE d.session_id = s.id AND $3 IS NOT NULL AND d.sample_time >= $3 * 1000
+                )
+            "#,
+            client,
+            session,
+            before
+        )
+        .fetch_one(pool)
+        .await?
+    } else {
+        0
+    };
+
+    if samples == 0 && empty_sessions == 0 {
+        println!("Nothing to purge for {description}.");
+        return Ok(());
+    }
+
+    let mut summary = format!("{samples} sample(s)");
+    if let Some(before) = before {
+        summary += &format!(
+            " taken before {}",
+            format_local_time(OffsetDateTime::from_unix_timestamp(before)?)
+        );
+    }
+    if sessions {
+        summary += &format!(" and {empty_sessions} session(s)");
+    }
+    if !yes && !confirm(&format!("Delete {summary} of {description}?"))? {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let mut tx = pool.begin().await?;
+    // the rows of the metric tables go with their sample
+    let samples = sqlx::query!(
+        r#"
+        DELETE FROM session_data
+        WHERE (session_id = $2 OR session_id IN (SELECT id FROM sessions WHERE client_id = $1))
+            AND ($3 IS NULL OR sample_time < $3 * 1000)
+        "#,
+        client,
+        session,
+        before
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    let empty_sessions = if sessions {
+        sqlx::query!(
+            r#"
+            DELETE FROM sessions
+            WHERE (id = $2 OR client_id = $1)
+                AND id NOT IN (SELECT id FROM non_expired_sessions)
+                AND NOT EXISTS (SELECT 1 FROM session_data d WHERE d.session_id = sessions.id)
+            "#,
+            client,
+            session
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+    } else {
+        0
+    };
+    tx.commit().await?;
+
+    println!("Deleted {samples} sample(s) and {empty_sessions} session(s) of {description}.");
+    Ok(())
+}
+
+/// Ask a yes/no question on the terminal, anything but yes declines.
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
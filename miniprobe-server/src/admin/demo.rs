@@ -0,0 +1,424 @@
+//! Synthetic history for `admin demo seed`, so dashboards and queries can be
+//! developed against days of samples without running probes for days.
+//!
+//! Every host gets a role deciding its load, a daily cycle shifted by a
+//! random offset, quieter weekends, noise and the occasional incident: a CPU
+//! spike, a failed unit restarted a few minutes later, or memory dropping
+//! after a restart of the application.
+
+use std::{borrow::Cow, f64::consts::TAU};
+
+use clap::Subcommand;
+use miniprobe_proto::{
+    ClockMetrics, CpuReport, DiskMetrics, DynamicMetrics, FdMetrics, MemoryMetrics, NetworkMetrics,
+    ProbeSelfMetrics, ProcessMetrics, SensorMetrics, ServiceMetrics, ServiceState, UnixMillis,
+};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use sqlx::{Pool, Sqlite};
+
+use super::client::insert_client;
+use crate::sink::{Ingested, MetricsSink, SqliteSink};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Subcommand)]
+pub enum DemoCommands {
+    /// Add clients named `demo-01`, `demo-02`, ... with generated samples up to now
+    Seed {
+        /// Number of hosts to add
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=99))]
+        hosts: u32,
+        /// Days of history to generate
+        #[arg(long, default_value_t = 7, value_parser = clap::value_parser!(u32).range(1..=366))]
+        days: u32,
+        /// Seconds between two samples of a host
+        #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        /// Seed of the generator, the same seed generates the same samples
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+}
+
+pub async fn demo(command: DemoCommands, pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    match command {
+        DemoCommands::Seed {
+            hosts,
+            days,
+            interval,
+            seed,
+        } => seed_demo(pool, hosts, days, interval, seed).await,
+    }
+}
+
+async fn seed_demo(
+    pool: &Pool<Sqlite>,
+    hosts: u32,
+    days: u32,
+    interval: u64,
+    seed: Option<u64>,
+) -> anyhow::Result<()> {
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_os_rng(),
+    };
+    let hosts = (0..hosts)
+        .map(|index| Host::new(index as usize, &mut rng))
+        .collect::<Vec<_>>();
+
+    let mut tx = pool.begin().await?;
+    let mut clients = Vec::with_capacity(hosts.len());
+    for host in &hosts {
+        if let Some(id) = sqlx::query_scalar!(
+            r#"SELECT id AS "id!" FROM clients WHERE name = ?"#,
+            host.name
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        {
+            anyhow::bail!(
+                "client name '{}' is already taken by client [{id}], remove the demo clients \
+                 before seeding again",
+                host.name
+            );
+        }
+        let (id, _) = insert_client(&mut tx, &host.name).await?;
+        for (key, value) in [("env", host.env), ("role", host.role.name())] {
+            sqlx::query!(
+                "INSERT INTO client_labels (client_id, key, value) VALUES (?, ?, ?)",
+                id,
+                key,
+                value
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        clients.push(id);
+    }
+    tx.commit().await?;
+
+    let now = UnixMillis::now().as_secs();
+    let start = now - u64::from(days) * DAY_SECS;
+    let mut sink = SqliteSink::new(pool.clone());
+    for (mut host, client_id) in hosts.into_iter().zip(clients) {
+        let (first, last) = (start as i64, now as i64);
+        let session_id = sqlx::query_scalar!(
+            "INSERT INTO sessions \
+                (client_id, created_at, last_active, system_name, kernel_version, os_version, \
+                host_name, cpu_arch, capabilities) \
+                VALUES (?, datetime(?, 'unixepoch'), ?, 'Linux', '6.8.0', 'Ubuntu 24.04', ?, \
+                'x86_64', NULL) \
+                RETURNING id",
+            client_id,
+            first,
+            last,
+            host.name,
+        )
+        .fetch_one(pool)
+        .await?;
+
+        let mut count = 0;
+        for time in (start..=now).step_by(interval as usize) {
+            sink.write(Ingested {
+                client_id,
+                session_id,
+                received_at: (time as i64 + 1) * 1000,
+                network_delta: false,
+                metrics: host.sample(time, interval, &mut rng),
+            })
+            .await?;
+            count += 1;
+        }
+        println!(
+            "Client '{}' [{client_id}] ({}, {}): {count} samples.",
+            host.name,
+            host.env,
+            host.role.name()
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Web,
+    Db,
+    Cache,
+    Worker,
+}
+
+impl Role {
+    const ALL: [Role; 4] = [Role::Web, Role::Db, Role::Cache, Role::Worker];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Role::Web => "web",
+            Role::Db => "db",
+            Role::Cache => "cache",
+            Role::Worker => "worker",
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        match self {
+            Role::Web => "nginx.service",
+            Role::Db => "postgresql.service",
+            Role::Cache => "redis-server.service",
+            Role::Worker => "app-worker.service",
+        }
+    }
+
+    /// CPU usage in percent at the daily peak, before noise.
+    fn peak_cpu(&self) -> f64 {
+        match self {
+            Role::Web => 45.0,
+            Role::Db => 60.0,
+            Role::Cache => 20.0,
+            Role::Worker => 75.0,
+        }
+    }
+
+    /// Received and sent bytes per second at the daily peak.
+    fn peak_traffic(&self) -> (f64, f64) {
+        match self {
+            Role::Web => (2.0e6, 8.0e6),
+            Role::Db => (4.0e6, 3.0e6),
+            Role::Cache => (6.0e6, 6.0e6),
+            Role::Worker => (1.0e6, 0.5e6),
+        }
+    }
+}
+
+/// A generated host and the state carried from one of its samples to the
+/// next.
+struct Host {
+    name: String,
+    env: &'static str,
+    role: Role,
+    memory_total: u64,
+    swap_total: u64,
+    /// Shift of the daily cycle in seconds, hosts do not all peak at once
+    phase: u64,
+    /// Share of the memory used right after a restart of the application
+    memory_base: f64,
+    /// Share of the memory leaked since the last restart
+    leaked: f64,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    disk_total: u64,
+    /// Bytes used on the disk, growing with the logs until they are cleaned up
+    disk_used: u64,
+    read_bytes: u64,
+    written_bytes: u64,
+    restarts: u32,
+    /// Samples left until an incident is over
+    spike_left: u32,
+    failed_left: u32,
+}
+
+impl Host {
+    fn new(index: usize, rng: &mut impl Rng) -> Self {
+        let role = Role::ALL[index % Role::ALL.len()];
+        let memory_total = [4, 8, 16, 32][rng.random_range(0..4)] * GIB;
+        let disk_total = [40, 80, 160][rng.random_range(0..3)] * GIB;
+        Host {
+            name: format!("demo-{:02}", index + 1),
+            // every fifth host is a staging one
+            env: if index % 5 == 4 { "staging" } else { "prod" },
+            role,
+            memory_total,
+            swap_total: memory_total / 4,
+            phase: rng.random_range(0..3 * 60 * 60),
+            memory_base: rng.random_range(0.25..0.45),
+            leaked: 0.0,
+            rx_bytes: rng.random_range(0..GIB),
+            tx_bytes: rng.random_range(0..GIB),
+            disk_total,
+            disk_used: (disk_total as f64 * rng.random_range(0.2..0.5)) as u64,
+            read_bytes: rng.random_range(0..GIB),
+            written_bytes: rng.random_range(0..GIB),
+            restarts: 0,
+            spike_left: 0,
+            failed_left: 0,
+        }
+    }
+
+    /// The sample taken at `time` in unix seconds, `interval` seconds after
+    /// the previous one. Numbered by its sample time in milliseconds like
+    /// the client does, which grows as the times are seconds apart.
+    fn sample(&mut self, time: u64, interval: u64, rng: &mut impl Rng) -> DynamicMetrics<'static> {
+        // a share of the daily peak, lowest at 04:00 and highest at 16:00
+        let day = ((time + self.phase) % DAY_SECS) as f64 / DAY_SECS as f64;
+        // from 0 on Mondays, the epoch was a Thursday
+        let weekday = (time / DAY_SECS + 3) % 7;
+        let mut load = 0.55 - 0.45 * (TAU * (day - 4.0 / 24.0)).cos();
+        if weekday >= 5 {
+            load *= 0.6;
+        }
+        let samples_per_hour = (3600 / interval).max(1) as f64;
+
+        // about one spike a day, lasting 5 to 20 minutes
+        if self.spike_left == 0 && rng.random_bool((1.0 / (24.0 * samples_per_hour)).min(1.0)) {
+            self.spike_left = (rng.random_range(300..1200) / interval).max(1) as u32;
+        }
+        let spike = if self.spike_left > 0 {
+            self.spike_left -= 1;
+            rng.random_range(30.0..50.0)
+        } else {
+            0.0
+        };
+        let usage = (self.role.peak_cpu() * load + 3.0 + spike + rng.random_range(-1.0..1.0) * 4.0)
+            .clamp(0.5, 100.0);
+        let max_core = (usage * rng.random_range(1.1..1.6)).min(100.0);
+
+        // the application leaks until it is restarted, about every other day
+        self.leaked += rng.random_range(0.0..0.3) / (24.0 * samples_per_hour);
+        if self.leaked > 0.3 || rng.random_bool((0.5 / (24.0 * samples_per_hour)).min(1.0)) {
+            self.leaked = 0.0;
+        }
+        let used_share =
+            (self.memory_base + self.leaked + 0.1 * load + rng.random_range(0.0..0.02)).min(0.97);
+        let swap_share = (used_share - 0.8).max(0.0);
+
+        let (rx_peak, tx_peak) = self.role.peak_traffic();
+        let traffic = (load + spike / 100.0) * interval as f64;
+        self.rx_bytes += (rx_peak * traffic * rng.random_range(0.8..1.2)) as u64;
+        self.tx_bytes += (tx_peak * traffic * rng.random_range(0.8..1.2)) as u64;
+
+        // writes follow the traffic, a tenth of them stays as logs until
+        // they are cleaned up at 85% full
+        let written = ((rx_peak + tx_peak) * 0.05 * traffic * rng.random_range(0.8..1.2)) as u64;
+        self.written_bytes += written;
+        self.read_bytes += (written as f64 * rng.random_range(0.2..0.6)) as u64;
+        self.disk_used += written / 10;
+        if self.disk_used > self.disk_total / 100 * 85 {
+            self.disk_used = self.disk_total / 100 * 40;
+        }
+
+        // about one failure of the unit in three days, restarted after a few minutes
+        if self.failed_left == 0 && rng.random_bool((1.0 / (72.0 * samples_per_hour)).min(1.0)) {
+            self.failed_left = (rng.random_range(60..600) / interval).max(1) as u32;
+            self.restarts += 1;
+        }
+        let state = if self.failed_left > 0 {
+            self.failed_left -= 1;
+            ServiceState::Failed
+        } else {
+            ServiceState::Active
+        };
+
+        let sample_time = UnixMillis(time * 1000 + rng.random_range(0..200));
+        DynamicMetrics {
+            seq: sample_time.0,
+            sample_time,
+            cpu: CpuReport::Aggregate {
+                usage: usage as f32,
+                max_core: max_core as f32,
+            },
+            memory: MemoryMetrics {
+                total: self.memory_total,
+                used: (self.memory_total as f64 * used_share) as u64,
+                swap_total: self.swap_total,
+                swap_used: (self.swap_total as f64 * swap_share) as u64,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: Cow::Borrowed("eth0"),
+                rx_bytes: Some(self.rx_bytes),
+                tx_bytes: Some(self.tx_bytes),
+            }],
+            disks: vec![DiskMetrics {
+                mount_point: Cow::Borrowed("/"),
+                total: self.disk_total,
+                available: self.disk_total - self.disk_used,
+                read_bytes: Some(self.read_bytes),
+                written_bytes: Some(self.written_bytes),
+            }],
+            sensors: SensorMetrics {
+                cpu_temperature: Some((38.0 + usage * 0.4 + rng.random_range(-1.5..1.5)) as f32),
+                cpu_frequency: Some((1200.0 + usage * 24.0) as u64),
+            },
+            probe: ProbeSelfMetrics {
+                collection_time: rng.random_range(800..3000),
+                cpu_usage: Some(rng.random_range(0.1..0.6)),
+                rss: Some(rng.random_range(11..14) * 1024 * 1024),
+            },
+            processes: {
+                let processes = 160 + (load * 60.0) as u32 + rng.random_range(0..10);
+                Some(ProcessMetrics {
+                    processes,
+                    threads: Some(processes * 3 + (load * 400.0) as u32),
+                    // a zombie now and then until its parent gets to it
+                    zombies: rng.random_bool(0.02) as u32,
+                })
+            },
+            fds: {
+                let established = 20 + (load * 200.0 * rng.random_range(0.8..1.2)) as u32;
+                Some(FdMetrics {
+                    open: 1500 + established as u64 * 2,
+                    max: i64::MAX as u64,
+                    tcp_established: Some(established),
+                    tcp_time_wait: Some((load * 80.0 * rng.random_range(0.5..1.5)) as u32),
+                })
+            },
+            clock: Some(ClockMetrics {
+                synchronized: true,
+                offset: Some(rng.random_range(-0.005..0.005)),
+            }),
+            battery: None,
+            services: vec![
+                ServiceMetrics {
+                    name: Cow::Borrowed("ssh.service"),
+                    state: ServiceState::Active,
+                    restarts: Some(0),
+                },
+                ServiceMetrics {
+                    name: Cow::Borrowed(self.role.unit()),
+                    state,
+                    restarts: Some(self.restarts),
+                },
+            ],
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plausible_samples() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut host = Host::new(1, &mut rng);
+        assert_eq!(host.name, "demo-02");
+        assert_eq!(host.role, Role::Db);
+
+        let (mut rx, mut tx) = (0, 0);
+        let mut failed = false;
+        for time in (0..30 * DAY_SECS).step_by(60) {
+            let sample = host.sample(time, 60, &mut rng);
+            assert_eq!(sample.seq, sample.sample_time.0);
+            assert_eq!(sample.sample_time.as_secs(), time);
+            let CpuReport::Aggregate { usage, max_core } = sample.cpu else {
+                panic!("expected an aggregate report");
+            };
+            assert!((0.0..=100.0).contains(&usage) && usage <= max_core && max_core <= 100.0);
+            assert!(sample.memory.used <= sample.memory.total);
+            assert!(sample.memory.swap_used <= sample.memory.swap_total);
+            let (next_rx, next_tx) = (
+                sample.networks[0].rx_bytes.unwrap(),
+                sample.networks[0].tx_bytes.unwrap(),
+            );
+            assert!(next_rx >= rx && next_tx >= tx);
+            (rx, tx) = (next_rx, next_tx);
+            let disk = &sample.disks[0];
+            assert!(disk.available <= disk.total);
+            failed |= sample.services[1].state == ServiceState::Failed;
+        }
+        assert!(failed, "no incident in a month");
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use miniprobe_proto::{
+    DynamicMetrics, StaticMetrics, SystemInfo, UnixMillis, record::Records, validate::Validate,
+};
+use sqlx::{Pool, Sqlite};
+
+use crate::sink::{Ingested, MetricsSink, SqliteSink};
+
+/// How far a sample may be ahead of the clock of the server.
+const MAX_CLOCK_AHEAD_MS: u64 = 60_000;
+/// Earlier sample times are in seconds or otherwise bogus, 1973 in milliseconds.
+const MIN_SAMPLE_TIME_MS: u64 = 100_000_000_000;
+/// System information written next to the samples by the client.
+const SYSTEM_FILE: &str = "system.json";
+
+/// What the files of an import held.
+#[derive(Debug, Default)]
+struct Loaded {
+    samples: Vec<DynamicMetrics<'static>>,
+    /// Lines or records that could not be decoded
+    damaged: usize,
+}
+
+/// Import the samples a client wrote with `--offline` into a new session of
+/// `client_id`. Samples with a bad time and samples of times the client
+/// already has samples at are skipped, so importing twice is harmless.
+pub async fn import(pool: &Pool<Sqlite>, path: &Path, client_id: i64) -> anyhow::Result<()> {
+    let Some(name) = sqlx::query_scalar!("SELECT name FROM clients WHERE id = ?", client_id)
+        .fetch_optional(pool)
+        .await?
+    else {
+        anyhow::bail!("client {client_id} not found");
+    };
+
+    let (files, dir) = if path.is_dir() {
+        (sample_files(path)?, path)
+    } else {
+        (
+            vec![path.to_owned()],
+            path.parent().unwrap_or(Path::new(".")),
+        )
+    };
+    if files.is_empty() {
+        anyhow::bail!("no sample files in {}", path.display());
+    }
+    let mut loaded = Loaded::default();
+    for file in &files {
+        load(file, &mut loaded)?;
+    }
+    if loaded.damaged > 0 {
+        eprintln!("Skipped {} damaged samples.", loaded.damaged);
+    }
+
+    let (mut samples, invalid) = validate(loaded.samples, UnixMillis::now());
+    if invalid > 0 {
+        eprintln!("Skipped {invalid} samples with an implausible time or values.");
+    }
+    let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+        anyhow::bail!("no samples to import");
+    };
+    let (first, last) = (first.sample_time.0 as i64, last.sample_time.0 as i64);
+    let stored = sqlx::query_scalar!(
+        "SELECT d.sample_time FROM session_data d JOIN sessions s ON s.id = d.session_id \
+            WHERE s.client_id = ? AND d.sample_time BETWEEN ? AND ?",
+        client_id,
+        first,
+        last
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect::<HashSet<_>>();
+    let before = samples.len();
+    samples.retain(|s| !stored.contains(&(s.sample_time.0 as i64)));
+    if samples.len() < before {
+        eprintln!("Skipped {} samples already stored.", before - samples.len());
+    }
+    if samples.is_empty() {
+        println!("Nothing to import for {name}.");
+        return Ok(());
+    }
+
+    let system = match fs::read(dir.join(SYSTEM_FILE)) {
+        Ok(bytes) => Some(serde_json::from_slice::<StaticMetrics>(&bytes)?),
+        Err(_) => None,
+    };
+    let capabilities = system
+        .as_ref()
+        .map(|s| serde_json::to_string(&s.capabilities))
+        .transpose()?;
+    let system = system.map(|s| s.system).unwrap_or(SystemInfo {
+        system_name: None,
+        kernel_version: None,
+        os_version: None,
+        host_name: None,
+        cpu_arch: "unknown".to_owned(),
+        boot_time: None,
+        boot_id: None,
+        machine_id: None,
+    });
+    let (first, last) = (
+        samples[0].sample_time.as_secs() as i64,
+        samples[samples.len() - 1].sample_time.as_secs() as i64,
+    );
+    let session_id = sqlx::query_scalar!(
+        "INSERT INTO sessions \
+            (client_id, created_at, last_active, system_name, kernel_version, os_version, \
+            host_name, cpu_arch, capabilities) \
+            VALUES (?, datetime(?, 'unixepoch'), ?, ?, ?, ?, ?, ?, ?) \
+            RETURNING id",
+        client_id,
+        first,
+        last,
+        system.system_name,
+        system.kernel_version,
+        system.os_version,
+        system.host_name,
+        system.cpu_arch,
+        capabilities,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let received_at = UnixMillis::now().0 as i64;
+    let mut sink = SqliteSink::new(pool.clone());
+    let count = samples.len();
+    for metrics in samples {
+        // numbered like the client does, importing the files again adds nothing
+        sink.write(Ingested {
+            client_id,
+            session_id,
+            received_at,
+            network_delta: false,
+            metrics: DynamicMetrics {
+                seq: metrics.sample_time.0,
+                ..metrics
+            },
+        })
+        .await?;
+    }
+
+    println!(
+        "Imported {count} samples from {} files into session {session_id} of {name}.",
+        files.len()
+    );
+    Ok(())
+}
+
+/// The sample files written by the client in `dir`, oldest first.
+fn sample_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                name.starts_with("samples-")
+                    && (name.ends_with(".jsonl") || name.ends_with(".postcard"))
+            })
+    });
+    files.sort();
+    Ok(files)
+}
+
+/// Decode the samples of a `.jsonl` or `.postcard` file.
+fn load(path: &Path, loaded: &mut Loaded) -> anyhow::Result<()> {
+    let bytes = fs::read(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("jsonl") => {
+            for line in bytes.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+                match serde_json::from_slice::<DynamicMetrics>(line) {
+                    Ok(sample) => loaded.samples.push(sample.into_owned()),
+                    Err(_) => loaded.damaged += 1,
+                }
+            }
+        }
+        Some("postcard") => {
+            let mut records = Records(&bytes);
+            for payload in records.by_ref() {
+                match postcard::from_bytes::<DynamicMetrics>(payload) {
+                    Ok(sample) => loaded.samples.push(sample.into_owned()),
+                    Err(_) => loaded.damaged += 1,
+                }
+            }
+            // a torn record at the end
+            if !records.0.is_empty() {
+                loaded.damaged += 1;
+            }
+        }
+        _ => anyhow::bail!(
+            "{} is neither a .jsonl nor a .postcard file",
+            path.display()
+        ),
+    }
+    Ok(())
+}
+
+/// Sort samples by time, dropping those with the time of an earlier one and
+/// those with implausible times or values out of their ranges. Returns the
+/// samples and how many were implausible.
+fn validate(
+    mut samples: Vec<DynamicMetrics<'static>>,
+    now: UnixMillis,
+) -> (Vec<DynamicMetrics<'static>>, usize) {
+    let before = samples.len();
+    samples.retain(|s| {
+        (MIN_SAMPLE_TIME_MS..=now.0 + MAX_CLOCK_AHEAD_MS).contains(&s.sample_time.0)
+            && s.validate().is_ok()
+    });
+    let invalid = before - samples.len();
+    samples.sort_by_key(|s| s.sample_time);
+    samples.dedup_by_key(|s| s.sample_time);
+    (samples, invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use miniprobe_proto::{CpuReport, MemoryMetrics, NetworkMetrics};
+
+    use super::*;
+
+    fn sample(sample_time: u64) -> DynamicMetrics<'static> {
+        DynamicMetrics {
+            seq: 0,
+            sample_time: UnixMillis(sample_time),
+            cpu: CpuReport::Aggregate {
+                usage: 0.0,
+                max_core: 0.0,
+            },
+            memory: MemoryMetrics {
+                total: 0,
+                used: 0,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: "eth0".into(),
+                rx_bytes: None,
+                tx_bytes: None,
+            }],
+            disks: Vec::new(),
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: None,
+            services: Vec::new(),
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn validate_samples() {
+        let now = UnixMillis(1_800_000_000_000);
+        let samples = [
+            1_700_000_010_000,
+            1_700_000_000_000,
+            1_700_000_010_000,
+            // in seconds
+            1_700_000_000,
+            // far ahead
+            1_800_000_100_000,
+        ]
+        .map(sample)
+        .to_vec();
+        let mut overloaded = sample(1_700_000_020_000);
+        overloaded.cpu = CpuReport::Aggregate {
+            usage: 250.0,
+            max_core: 100.0,
+        };
+        let (samples, invalid) = validate([samples, vec![overloaded]].concat(), now);
+        let times = samples.iter().map(|s| s.sample_time.0).collect::<Vec<_>>();
+        assert_eq!(times, [1_700_000_000_000, 1_700_000_010_000]);
+        assert_eq!(invalid, 3);
+    }
+
+    #[test]
+    fn load_damaged_files() {
+        let dir = std::env::temp_dir().join(format!("miniprobe-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut jsonl = serde_json::to_vec(&sample(1_700_000_000_000)).unwrap();
+        jsonl.extend(b"\n{\"seq\":");
+        fs::write(dir.join("samples-1.jsonl"), jsonl).unwrap();
+        let mut postcard = Vec::new();
+        let payload = postcard::to_extend(&sample(1_700_000_005_000), Vec::new()).unwrap();
+        miniprobe_proto::record::encode(&payload, &mut postcard);
+        postcard.extend(&[1, 2, 3]);
+        fs::write(dir.join("samples-2.postcard"), postcard).unwrap();
+        fs::write(dir.join("system.json"), "{}").unwrap();
+
+        let files = sample_files(&dir).unwrap();
+        assert_eq!(files.len(), 2);
+        let mut loaded = Loaded::default();
+        for file in &files {
+            load(file, &mut loaded).unwrap();
+        }
+        assert_eq!(loaded.samples.len(), 2);
+        assert_eq!(loaded.damaged, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::Subcommand;
+use sqlx::{
+    Pool, Sqlite,
+    types::time::{OffsetDateTime, UtcOffset},
+};
+use time::macros::format_description;
+
+use crate::Conf;
+
+mod alerts;
+mod client;
+mod data;
+mod demo;
+mod import;
+mod silence;
+mod usage;
+
+#[derive(Debug, Subcommand)]
+pub enum AdminCommands {
+    /// User related commands
+    #[command(subcommand)]
+    Client(client::ClientCommands),
+    /// Silence alerts and down detection for a while
+    #[command(subcommand)]
+    Silence(silence::SilenceCommands),
+    /// Manage stored alert rules and test rules against stored samples
+    #[command(subcommand)]
+    Alerts(alerts::AlertsCommands),
+    /// Stored sample maintenance
+    #[command(subcommand, visible_alias("db"))]
+    Data(data::DataCommands),
+    /// Generate demo clients with synthetic history
+    #[command(subcommand)]
+    Demo(demo::DemoCommands),
+    /// Import the sample files a client wrote with `--offline` as a new
+    /// session of the client
+    Import {
+        /// A sample file or the output directory of the client
+        path: PathBuf,
+        /// ID of the client the samples belong to
+        #[arg(long)]
+        client: i64,
+    },
+}
+
+pub async fn admin(command: AdminCommands, pool: Pool<Sqlite>, conf: &Conf) -> anyhow::Result<()> {
+    match command {
+        AdminCommands::Client(command) => client::client(command, &pool).await,
+        AdminCommands::Silence(command) => silence::silence(command, &pool).await,
+        AdminCommands::Alerts(command) => alerts::alerts(command, &pool, &conf.alerts.rules).await,
+        AdminCommands::Data(command) => data::data(command, &pool).await,
+        AdminCommands::Demo(command) => demo::demo(command, &pool).await,
+        AdminCommands::Import { path, client } => import::import(&pool, &path, client).await,
+    }
+}
+
+fn format_local_time(time: OffsetDateTime) -> String {
+    time.to_offset(UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC))
+        .format(format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second]"
+        ))
+        .unwrap()
+}
+
+/// Parse a unix timestamp, an RFC 3339 time or a duration before now.
+fn parse_time(s: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = s.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    let time = match humantime::parse_rfc3339_weak(s) {
+        Ok(time) => time,
+        Err(_) => {
+            let ago = humantime::parse_duration(s).map_err(|_| {
+                format!("invalid time '{s}', expected a unix timestamp, RFC 3339 time or duration")
+            })?;
+            SystemTime::now()
+                .checked_sub(ago)
+                .ok_or_else(|| format!("'{s}' is too long ago"))?
+        }
+    };
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .map_err(|_| format!("'{s}' is before the unix epoch"))
+}
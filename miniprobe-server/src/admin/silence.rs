@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use clap::Subcommand;
+use sqlx::{Pool, Sqlite, types::time::OffsetDateTime};
+
+use super::format_local_time;
+
+#[derive(Debug, Subcommand)]
+pub enum SilenceCommands {
+    /// List active silences
+    #[clap(visible_alias("ls"))]
+    List {
+        /// Include expired silences
+        #[arg(long)]
+        all: bool,
+    },
+    /// Add a new silence
+    #[clap(visible_alias("a"))]
+    Add {
+        /// Client to silence, every client is silenced if omitted
+        #[arg(long)]
+        client: Option<i64>,
+        /// How long the silence lasts (e.g. `2h`, `30m`, `1h 30m`)
+        #[arg(long, value_parser = humantime::parse_duration)]
+        duration: Duration,
+        /// Why alerts are silenced (e.g. `maintenance`)
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Expire a silence now
+    #[clap(visible_alias("rm"))]
+    Remove { id: i64 },
+}
+
+pub async fn silence(command: SilenceCommands, pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    match command {
+        SilenceCommands::List { all } => list_silences(pool, all).await,
+        SilenceCommands::Add {
+            client,
+            duration,
+            reason,
+        } => add_silence(pool, client, duration, reason).await,
+        SilenceCommands::Remove { id } => remove_silence(pool, id).await,
+    }
+}
+
+async fn list_silences(pool: &Pool<Sqlite>, all: bool) -> anyhow::Result<()> {
+    let silences = sqlx::query!(
+        "SELECT s.id, s.client_id, c.name AS \"client_name?\", s.starts_at, s.ends_at, s.reason \
+            FROM silences s LEFT JOIN clients c ON c.id = s.client_id \
+            WHERE $1 OR s.ends_at > unixepoch() \
+            ORDER BY s.ends_at",
+        all
+    )
+    .fetch_all(pool)
+    .await?;
+
+    if silences.is_empty() {
+        println!("No silences found.");
+    }
+
+    for silence in silences {
+        let target = match (silence.client_id, silence.client_name) {
+            (Some(id), Some(name)) => format!("{name} [{id}]"),
+            (Some(id), None) => format!("[{id}]"),
+            (None, _) => "all clients".to_owned(),
+        };
+        println!(
+            "[{}] {target}: {} ~ {}{}",
+            silence.id,
+            format_local_time(OffsetDateTime::from_unix_timestamp(silence.starts_at)?),
+            format_local_time(OffsetDateTime::from_unix_timestamp(silence.ends_at)?),
+            silence
+                .reason
+                .map(|reason| format!(" ({reason})"))
+                .unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+async fn add_silence(
+    pool: &Pool<Sqlite>,
+    client_id: Option<i64>,
+    duration: Duration,
+    reason: Option<String>,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+
+    if let Some(client_id) = client_id
+        && sqlx::query!("SELECT id FROM clients WHERE id = ?", client_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_none()
+    {
+        anyhow::bail!("No client found with ID {client_id}.");
+    }
+
+    let duration = i64::try_from(duration.as_secs())?;
+    let record = sqlx::query!(
+        "INSERT INTO silences (client_id, ends_at, reason) \
+            VALUES (?, unixepoch() + ?, ?) \
+            RETURNING id, ends_at",
+        client_id,
+        duration,
+        reason
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    println!(
+        "Silence [{}] added, active until {}.",
+        record.id,
+        format_local_time(OffsetDateTime::from_unix_timestamp(record.ends_at)?)
+    );
+    Ok(())
+}
+
+async fn remove_silence(pool: &Pool<Sqlite>, id: i64) -> anyhow::Result<()> {
+    // keep the row around as history, just let it expire
+    let rows_affected = sqlx::query!(
+        "UPDATE silences SET ends_at = unixepoch() WHERE id = ? AND ends_at > unixepoch()",
+        id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        println!("No active silence found with ID {id}.");
+    } else {
+        println!("Silence with ID {id} expired successfully.");
+    }
+
+    Ok(())
+}
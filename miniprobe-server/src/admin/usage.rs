@@ -0,0 +1,245 @@
+//! Storage report of `admin data usage`: what the tables of both databases
+//! take on disk, and how that splits over metric families and clients.
+//!
+//! Table sizes are exact, from the `dbstat` virtual table and including the
+//! indexes of a table. The sizes of families and clients are estimated from
+//! their share of the rows of each table, every row of a table is taken to be
+//! of the same size.
+
+use std::collections::HashMap;
+
+use sqlx::{Pool, Row, Sqlite};
+
+/// Metric families and the sample tables storing them, all of them hang off
+/// `session_data`.
+const FAMILIES: &[(&str, &[&str])] = &[
+    ("samples", &["session_data"]),
+    ("cpu", &["session_data_cpu", "session_data_cpu_aggregate"]),
+    ("memory", &["session_data_memory"]),
+    ("network", &["session_data_network"]),
+    ("disks", &["session_data_disk"]),
+    ("sensors", &["session_data_sensors"]),
+    ("probe", &["session_data_probe"]),
+    ("processes", &["session_data_system"]),
+    ("fds", &["session_data_fds"]),
+    ("clock", &["session_data_clock"]),
+    ("battery", &["session_data_battery"]),
+    ("services", &["session_data_service"]),
+    ("listeners", &["session_data_listeners"]),
+];
+
+struct TableUsage {
+    schema: &'static str,
+    name: String,
+    rows: i64,
+    bytes: i64,
+}
+
+impl TableUsage {
+    /// Estimated bytes of `rows` of the table.
+    fn share(&self, rows: i64) -> i64 {
+        if self.rows == 0 {
+            return 0;
+        }
+        (self.bytes as f64 * rows as f64 / self.rows as f64).round() as i64
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Usage {
+    rows: i64,
+    bytes: i64,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.rows += other.rows;
+        self.bytes += other.bytes;
+    }
+}
+
+pub async fn usage(pool: &Pool<Sqlite>, client: Option<i64>) -> anyhow::Result<()> {
+    let mut tables = table_usage(pool, "main").await?;
+    tables.extend(table_usage(pool, "samples").await?);
+
+    println!("Tables:");
+    println!(
+        "  {:<8} {:<32} {:>12} {:>10}",
+        "DATABASE", "TABLE", "ROWS", "SIZE"
+    );
+    for table in &tables {
+        println!(
+            "  {:<8} {:<32} {:>12} {:>10}",
+            table.schema,
+            table.name,
+            table.rows,
+            format_size(table.bytes)
+        );
+    }
+    println!(
+        "  {:<41} {:>12} {:>10}",
+        "total",
+        tables.iter().map(|t| t.rows).sum::<i64>(),
+        format_size(tables.iter().map(|t| t.bytes).sum())
+    );
+
+    let names = sqlx::query!(r#"SELECT id AS "id!", name FROM clients"#)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| (r.id, r.name))
+        .collect::<HashMap<_, _>>();
+    if let Some(id) = client
+        && !names.contains_key(&id)
+    {
+        anyhow::bail!("No client found with ID {id}.");
+    }
+
+    // rows of every family by client, `None` for samples of removed clients
+    let mut families = Vec::new();
+    let mut clients = HashMap::<Option<i64>, Usage>::new();
+    for (family, family_tables) in FAMILIES {
+        let mut family_usage = Usage::default();
+        for table in tables
+            .iter()
+            .filter(|t| t.schema == "samples" && family_tables.contains(&t.name.as_str()))
+        {
+            for (client_id, rows) in rows_by_client(pool, &table.name).await? {
+                let usage = Usage {
+                    rows,
+                    bytes: table.share(rows),
+                };
+                *clients.entry(client_id).or_default() += usage;
+                if client.is_none() || client_id == client {
+                    family_usage += usage;
+                }
+            }
+        }
+        families.push((*family, family_usage));
+    }
+
+    match client {
+        Some(id) => println!("\nMetric families of client '{}' [{id}]:", names[&id]),
+        None => println!("\nMetric families:"),
+    }
+    println!("  {:<41} {:>12} {:>10}", "FAMILY", "ROWS", "~SIZE");
+    for (family, usage) in &families {
+        println!(
+            "  {family:<41} {:>12} {:>10}",
+            usage.rows,
+            format_size(usage.bytes)
+        );
+    }
+
+    if client.is_some() {
+        return Ok(());
+    }
+    let mut clients = clients.into_iter().collect::<Vec<_>>();
+    clients.sort_by_key(|(_, usage)| std::cmp::Reverse(usage.bytes));
+    println!("\nClients:");
+    println!("  {:<41} {:>12} {:>10}", "CLIENT", "ROWS", "~SIZE");
+    for (client_id, usage) in clients {
+        let client = match client_id {
+            Some(id) => match names.get(&id) {
+                Some(name) => format!("{name} [{id}]"),
+                None => format!("[{id}]"),
+            },
+            None => "(removed sessions)".to_owned(),
+        };
+        println!(
+            "  {client:<41} {:>12} {:>10}",
+            usage.rows,
+            format_size(usage.bytes)
+        );
+    }
+
+    Ok(())
+}
+
+/// Rows and bytes of the tables of `schema`, their indexes included, largest
+/// first.
+async fn table_usage(pool: &Pool<Sqlite>, schema: &'static str) -> anyhow::Result<Vec<TableUsage>> {
+    // not checked at compile time, the samples schema only exists when attached
+    let sizes = sqlx::query(&format!(
+        "SELECT m.tbl_name AS name, SUM(s.pgsize) AS bytes \
+            FROM dbstat('{schema}') s \
+            JOIN {schema}.sqlite_schema m ON m.name = s.name \
+            WHERE m.tbl_name NOT LIKE 'sqlite_%' AND m.tbl_name NOT LIKE '_sqlx_%' \
+            GROUP BY m.tbl_name"
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    let mut tables = Vec::new();
+    for row in sizes {
+        let name: String = row.try_get("name")?;
+        let rows = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {schema}.\"{name}\""))
+            .fetch_one(pool)
+            .await?;
+        tables.push(TableUsage {
+            schema,
+            name,
+            rows,
+            bytes: row.try_get("bytes")?,
+        });
+    }
+    tables.sort_by_key(|t| std::cmp::Reverse(t.bytes));
+    Ok(tables)
+}
+
+/// Rows of a sample table by the client they belong to.
+async fn rows_by_client(
+    pool: &Pool<Sqlite>,
+    table: &str,
+) -> anyhow::Result<Vec<(Option<i64>, i64)>> {
+    let join = match table {
+        "session_data" => String::new(),
+        _ => format!("JOIN {table} t ON t.session_data_id = d.id"),
+    };
+    // the session of a sample may be gone with its client
+    Ok(sqlx::query_as(&format!(
+        "SELECT s.client_id, COUNT(*) FROM session_data d {join} \
+            LEFT JOIN sessions s ON s.id = d.session_id \
+            GROUP BY s.client_id"
+    ))
+    .fetch_all(pool)
+    .await?)
+}
+
+fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{size:.1} {unit}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(10 * 1024 * 1024), "10.0 MiB");
+
+        let table = TableUsage {
+            schema: "samples",
+            name: "session_data".to_owned(),
+            rows: 4,
+            bytes: 4096,
+        };
+        assert_eq!(table.share(1), 1024);
+        assert_eq!(TableUsage { rows: 0, ..table }.share(0), 0);
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use confique::Config;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::{
+    alert::{
+        notify::{Channel, Notification},
+        state::AlertTracker,
+    },
+    events::EventSender,
+    expr::{Expr, Sample, fetch_samples},
+};
+
+pub use notify::{AlertStatus, Notifier};
+pub use state::Transition;
+
+mod notify;
+pub mod rules;
+mod state;
+pub mod templates;
+
+#[derive(Config, Debug)]
+pub struct AlertConf {
+    /// Interval between two alert evaluations in seconds
+    #[config(default = 30)]
+    pub evaluation_interval: u64,
+
+    /// Alert rules, see `AlertRule`. More rules can be added at runtime
+    /// through the admin API and `admin alerts`
+    #[config(default = [])]
+    pub rules: Vec<AlertRule>,
+
+    /// Notification channels, see `Channel`
+    #[config(default = [])]
+    pub channels: Vec<Channel>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    #[default]
+    #[serde(alias = "warning")]
+    Warn,
+    #[serde(alias = "critical")]
+    Crit,
+}
+
+impl Severity {
+    fn all() -> Vec<Severity> {
+        vec![Severity::Info, Severity::Warn, Severity::Crit]
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warn => "warn",
+            Severity::Crit => "crit",
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "info" => Ok(Severity::Info),
+            "warn" | "warning" => Ok(Severity::Warn),
+            "crit" | "critical" => Ok(Severity::Crit),
+            _ => Err(format!(
+                "invalid severity '{s}', expected info, warn or crit"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    pub name: String,
+    /// Condition of the alert, e.g. `avg_over_time(cpu[5m]) > 0.9`
+    pub expr: Expr,
+    /// Seconds the condition must hold before the alert fires
+    #[serde(default, rename = "for")]
+    pub for_secs: u64,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Seconds between two notifications of a firing alert, notify once if unset
+    pub repeat_interval: Option<u64>,
+    /// Notify when the condition no longer holds, otherwise the alert clears silently
+    #[serde(default = "default_auto_resolve")]
+    pub auto_resolve: bool,
+}
+
+fn default_auto_resolve() -> bool {
+    true
+}
+
+/// Transition found by replaying stored samples through a rule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayEvent {
+    pub time: i64,
+    pub transition: Transition,
+    pub value: Option<f64>,
+}
+
+/// Evaluate `rule` every `step` seconds from `from` to `to` over the samples
+/// of a single client, as the evaluator would have done.
+pub fn replay(
+    rule: &AlertRule,
+    samples: &[Sample],
+    from: i64,
+    to: i64,
+    step: i64,
+) -> Vec<ReplayEvent> {
+    let mut tracker = AlertTracker::default();
+    let lookback = rule.expr.lookback();
+    let mut events = Vec::new();
+
+    let mut now = from;
+    while now <= to {
+        // only hand the samples the expression can see to keep long replays linear
+        let start = samples.partition_point(|s| {
+            s.sample_time <= now.saturating_sub(lookback).saturating_mul(1000)
+        });
+        let end = samples.partition_point(|s| s.sample_time <= now.saturating_mul(1000));
+        let window = &samples[start..end.max(start)];
+
+        // without samples to tell, the alert stays as it is
+        let met = rule.expr.holds(window, now);
+        if let Some(transition) = met.and_then(|met| tracker.observe(rule, 0, met, now)) {
+            events.push(ReplayEvent {
+                time: now,
+                transition,
+                value: rule.expr.observed(window, now),
+            });
+        }
+        let Some(next) = now.checked_add(step.max(1)) else {
+            break;
+        };
+        now = next;
+    }
+
+    events
+}
+
+/// Least time between two evaluations for urgent samples, any client can
+/// flag its samples urgent.
+const MIN_URGENT_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct AlertEvaluator {
+    pool: SqlitePool,
+    /// Rules of the config, the stored ones are loaded for every evaluation
+    rules: Vec<AlertRule>,
+    interval: Duration,
+    tracker: AlertTracker,
+    notifier: Notifier,
+    events: EventSender,
+    trigger: Arc<Notify>,
+}
+
+impl AlertEvaluator {
+    /// `trigger` evaluates the rules ahead of the interval, at most every
+    /// `MIN_URGENT_INTERVAL`.
+    pub fn new(
+        pool: SqlitePool,
+        conf: &AlertConf,
+        events: EventSender,
+        trigger: Arc<Notify>,
+    ) -> Self {
+        AlertEvaluator {
+            pool,
+            rules: conf.rules.clone(),
+            interval: Duration::from_secs(conf.evaluation_interval),
+            tracker: AlertTracker::default(),
+            notifier: Notifier::new(conf.channels.clone()),
+            events,
+            trigger,
+        }
+    }
+
+    /// Evaluate rules periodically until cancelled.
+    pub async fn run(mut self, cancellation_token: CancellationToken) {
+        let mut interval = tokio::time::interval(self.interval);
+        let trigger = Arc::clone(&self.trigger);
+        let mut next_urgent = tokio::time::Instant::now();
+        loop {
+            // triggers in between are kept as one by `Notify`
+            let urgent = async {
+                tokio::time::sleep_until(next_urgent).await;
+                trigger.notified().await
+            };
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = self.evaluate().await {
+                        warn!("alert evaluation failed: {e}");
+                    }
+                }
+                _ = urgent => {
+                    debug!("urgent sample, evaluating alert rules");
+                    if let Err(e) = self.evaluate().await {
+                        warn!("alert evaluation failed: {e}");
+                    }
+                    next_urgent = tokio::time::Instant::now() + MIN_URGENT_INTERVAL;
+                }
+                _ = cancellation_token.cancelled() => return,
+            }
+        }
+    }
+
+    async fn evaluate(&mut self) -> anyhow::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        let mut rules = self.rules.clone();
+        rules.extend(rules::load(&self.pool, &self.rules).await?);
+        // alerts of removed rules are dropped silently
+        self.tracker.retain_rules(&rules);
+        if rules.is_empty() {
+            return Ok(());
+        }
+
+        // every client with a non-expired session
+        let clients = sqlx::query!(
+            r#"
+            SELECT DISTINCT s.client_id AS "client_id!", c.name AS "client_name?"
+            FROM non_expired_sessions s
+            LEFT JOIN clients c ON c.id = s.client_id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let lookback = rules.iter().map(|r| r.expr.lookback()).max();
+        let mut samples = HashMap::new();
+        for client in &clients {
+            let client_samples = fetch_samples(
+                &mut *self.pool.acquire().await?,
+                client.client_id,
+                now - lookback.unwrap_or_default(),
+                now,
+            )
+            .await?;
+            samples.insert(client.client_id, client_samples);
+        }
+
+        let silenced = sqlx::query_scalar!(
+            "SELECT client_id FROM silences WHERE starts_at <= ? AND ends_at > ?",
+            now,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let silence_all = silenced.contains(&None);
+        let silenced = silenced.into_iter().flatten().collect::<HashSet<_>>();
+
+        for rule in &rules {
+            for client in &clients {
+                let (client_id, client_name) = (client.client_id, client.client_name.as_deref());
+                let client_samples = samples.get(&client_id).map(Vec::as_slice).unwrap_or(&[]);
+                // without samples to tell, e.g. of a client that stopped
+                // reporting, its alert stays as it is
+                let Some(met) = rule.expr.holds(client_samples, now) else {
+                    continue;
+                };
+                let since = self.tracker.since(rule, client_id);
+                let is_silenced = silence_all || silenced.contains(&client_id);
+
+                // an alert which started firing during a silence is notified
+                // once the silence is over
+                let withheld = !is_silenced && self.tracker.release(rule, client_id);
+                let transition = match self.tracker.observe(rule, client_id, met, now) {
+                    None | Some(Transition::Repeat) if withheld => Transition::Firing,
+                    Some(transition) => transition,
+                    None => continue,
+                };
+
+                let notification = Notification {
+                    status: match transition {
+                        Transition::Firing | Transition::Repeat => AlertStatus::Firing,
+                        Transition::Resolved => AlertStatus::Resolved,
+                    },
+                    rule: &rule.name,
+                    severity: rule.severity,
+                    client_id,
+                    client_name,
+                    value: rule.expr.observed(client_samples, now),
+                    since: self.tracker.since(rule, client_id).or(since),
+                };
+
+                // sending only fails without subscribers
+                self.events
+                    .send(notification.to_event(now, is_silenced))
+                    .ok();
+
+                if is_silenced {
+                    if transition == Transition::Firing {
+                        self.tracker.withhold(rule, client_id);
+                    }
+                    debug!(
+                        rule = rule.name,
+                        client_id,
+                        ?transition,
+                        "alert notification silenced"
+                    );
+                    continue;
+                }
+                // resolved before its firing was ever notified
+                if withheld && transition == Transition::Resolved {
+                    continue;
+                }
+                self.notifier.notify(&notification).await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(expr: &str, for_secs: u64) -> AlertRule {
+        AlertRule {
+            name: "test".to_owned(),
+            expr: expr.parse().unwrap(),
+            for_secs,
+            severity: Severity::Warn,
+            repeat_interval: None,
+            auto_resolve: true,
+        }
+    }
+
+    fn samples(cpu: &[(i64, f64)]) -> Vec<Sample> {
+        cpu.iter()
+            .map(|&(secs, cpu)| Sample {
+                sample_time: secs * 1000,
+                cpu: Some(cpu),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_fires_and_resolves() {
+        let samples = samples(&[(10, 10.0), (20, 95.0), (30, 95.0), (40, 95.0), (50, 10.0)]);
+        let events = replay(&rule("cpu > 0.9", 10), &samples, 10, 60, 10);
+
+        assert_eq!(
+            events,
+            vec![
+                ReplayEvent {
+                    time: 30,
+                    transition: Transition::Firing,
+                    value: Some(0.95),
+                },
+                ReplayEvent {
+                    time: 50,
+                    transition: Transition::Resolved,
+                    value: Some(0.1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_respects_lookback() {
+        // a range function only sees the samples inside its range
+        let samples = samples(&[(0, 100.0), (100, 0.0), (110, 0.0)]);
+        let events = replay(
+            &rule("max_over_time(cpu[30s]) > 0.5", 0),
+            &samples,
+            0,
+            120,
+            10,
+        );
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].transition, Transition::Firing);
+        // without samples in its range from 30s on, the alert fires until
+        // the next sample tells otherwise
+        assert_eq!(events[1].transition, Transition::Resolved);
+        assert_eq!(events[1].time, 100);
+    }
+}
@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use super::Severity;
+use crate::events::Event;
+
+/// Where notifications of a severity are delivered.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Channel {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: ChannelKind,
+    /// Severities routed to this channel, all severities if omitted
+    #[serde(default = "Severity::all")]
+    pub severities: Vec<Severity>,
+    /// Deliver security events as well, e.g. failed authentications
+    #[serde(default)]
+    pub security_events: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ChannelKind {
+    /// Write notifications into the server log
+    Log,
+    /// POST notifications as JSON to an URL
+    Webhook { url: String },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification<'a> {
+    pub status: AlertStatus,
+    pub rule: &'a str,
+    pub severity: Severity,
+    pub client_id: i64,
+    pub client_name: Option<&'a str>,
+    /// Value of the left-hand side of the condition
+    pub value: Option<f64>,
+    /// Unix timestamp in seconds since when the condition holds
+    pub since: Option<i64>,
+}
+
+impl Notification<'_> {
+    /// Event published to `/ws/v1/events` for this notification.
+    pub fn to_event(&self, time: i64, silenced: bool) -> Event {
+        Event::Alert {
+            status: self.status,
+            rule: self.rule.to_owned(),
+            severity: self.severity,
+            client_id: self.client_id,
+            client_name: self.client_name.map(str::to_owned),
+            value: self.value,
+            since: self.since,
+            time,
+            silenced,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Notifier {
+    channels: Vec<Channel>,
+    http: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(channels: Vec<Channel>) -> Self {
+        Notifier {
+            channels,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn notify(&self, notification: &Notification<'_>) {
+        let routed = self
+            .channels
+            .iter()
+            .filter(|channel| channel.severities.contains(&notification.severity));
+
+        for channel in routed {
+            match &channel.kind {
+                ChannelKind::Log => log_notification(notification),
+                ChannelKind::Webhook { url } => {
+                    let res = self
+                        .http
+                        .post(url)
+                        .json(notification)
+                        .send()
+                        .await
+                        .and_then(|resp| resp.error_for_status());
+                    if let Err(e) = res {
+                        warn!(
+                            channel = channel.name,
+                            "failed to deliver notification: {e}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Notifier {
+    pub fn wants_security_events(&self) -> bool {
+        self.channels.iter().any(|channel| channel.security_events)
+    }
+
+    /// Deliver an `Event::Security` to the channels asking for them.
+    pub async fn notify_security(&self, event: &Event) {
+        for channel in self.channels.iter().filter(|c| c.security_events) {
+            match &channel.kind {
+                // the security log records them already
+                ChannelKind::Log => {}
+                ChannelKind::Webhook { url } => {
+                    let res = self
+                        .http
+                        .post(url)
+                        .json(event)
+                        .send()
+                        .await
+                        .and_then(|resp| resp.error_for_status());
+                    if let Err(e) = res {
+                        warn!(
+                            channel = channel.name,
+                            "failed to deliver security event: {e}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn log_notification(n: &Notification<'_>) {
+    let client = n.client_name.unwrap_or("<unknown>");
+    match (n.status, n.severity) {
+        (AlertStatus::Resolved, _) => info!(rule = n.rule, client, "alert resolved"),
+        (AlertStatus::Firing, Severity::Info) => {
+            info!(rule = n.rule, client, value = n.value, "alert firing")
+        }
+        (AlertStatus::Firing, Severity::Warn) => {
+            warn!(rule = n.rule, client, value = n.value, "alert firing")
+        }
+        (AlertStatus::Firing, Severity::Crit) => {
+            error!(rule = n.rule, client, value = n.value, "alert firing")
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Alert rules stored in the database, managed at runtime through the admin
+//! API and `admin alerts` next to the fixed `alerts.rules` of the config.
+//!
+//! The evaluator loads them again for every evaluation, so changes apply from
+//! the next one. Rule names are unique across the config and the database, as
+//! the state of an alert is tracked by the name of its rule.
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tracing::warn;
+
+use super::{AlertRule, Severity, default_auto_resolve};
+use crate::expr::ParseError;
+
+/// A rule in the format of `alerts.rules`, with its expression as written.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSpec {
+    pub name: String,
+    /// Condition of the alert, e.g. `avg_over_time(cpu[5m]) > 0.9`
+    pub expr: String,
+    /// Seconds the condition must hold before the alert fires
+    #[serde(default, rename = "for")]
+    pub for_secs: u64,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Seconds between two notifications of a firing alert, notify once if unset
+    pub repeat_interval: Option<u64>,
+    /// Notify when the condition no longer holds, otherwise the alert clears silently
+    #[serde(default = "default_auto_resolve")]
+    pub auto_resolve: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredRule {
+    pub id: i64,
+    #[serde(flatten)]
+    pub spec: RuleSpec,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// Unix timestamp in seconds
+    pub updated_at: i64,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RuleError {
+    #[error("Invalid rule: {0}")]
+    Invalid(&'static str),
+    #[error("Invalid expression: {0}")]
+    InvalidExpr(#[from] ParseError),
+    #[error("An alert rule named '{0}' already exists")]
+    NameTaken(String),
+    #[error("An alert rule named '{0}' is defined in the config")]
+    ConfigRule(String),
+    #[error("No alert rule found with ID {0}")]
+    NotFound(i64),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl RuleSpec {
+    /// The rule as evaluated, if the spec is valid.
+    pub fn validate(&self) -> Result<AlertRule, RuleError> {
+        if self.name.trim().is_empty() {
+            return Err(RuleError::Invalid("`name` must not be empty"));
+        }
+        // stored as SQLite integers
+        if i64::try_from(self.for_secs).is_err() {
+            return Err(RuleError::Invalid("`for` is too large"));
+        }
+        match self.repeat_interval {
+            Some(0) => return Err(RuleError::Invalid("`repeat_interval` must be positive")),
+            Some(interval) if i64::try_from(interval).is_err() => {
+                return Err(RuleError::Invalid("`repeat_interval` is too large"));
+            }
+            _ => {}
+        }
+        Ok(AlertRule {
+            name: self.name.clone(),
+            expr: self.expr.parse()?,
+            for_secs: self.for_secs,
+            severity: self.severity,
+            repeat_interval: self.repeat_interval,
+            auto_resolve: self.auto_resolve,
+        })
+    }
+}
+
+/// A row of `alert_rules`.
+struct RuleRow {
+    id: i64,
+    name: String,
+    expr: String,
+    for_secs: i64,
+    severity: String,
+    repeat_interval: Option<i64>,
+    auto_resolve: bool,
+    created_at: i64,
+    updated_at: i64,
+}
+
+impl From<RuleRow> for StoredRule {
+    fn from(r: RuleRow) -> Self {
+        StoredRule {
+            id: r.id,
+            spec: RuleSpec {
+                name: r.name,
+                expr: r.expr,
+                for_secs: r.for_secs as u64,
+                severity: r.severity.parse().unwrap_or_default(),
+                repeat_interval: r.repeat_interval.map(|interval| interval as u64),
+                auto_resolve: r.auto_resolve,
+            },
+            created_at: r.created_at,
+            updated_at: r.updated_at,
+        }
+    }
+}
+
+/// Every stored rule by ID.
+pub async fn list(pool: &SqlitePool) -> Result<Vec<StoredRule>, RuleError> {
+    let rows = sqlx::query_as!(
+        RuleRow,
+        "SELECT id, name, expr, for_secs, severity, repeat_interval, auto_resolve, \
+            created_at, updated_at \
+            FROM alert_rules ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(StoredRule::from).collect())
+}
+
+pub async fn get(pool: &SqlitePool, id: i64) -> Result<StoredRule, RuleError> {
+    sqlx::query_as!(
+        RuleRow,
+        "SELECT id, name, expr, for_secs, severity, repeat_interval, auto_resolve, \
+            created_at, updated_at \
+            FROM alert_rules WHERE id = ?",
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(StoredRule::from)
+    .ok_or(RuleError::NotFound(id))
+}
+
+/// Store a new rule, `conf_rules` are the rules of the config whose names are
+/// taken.
+pub async fn create(
+    pool: &SqlitePool,
+    spec: &RuleSpec,
+    conf_rules: &[AlertRule],
+) -> Result<StoredRule, RuleError> {
+    check(spec, conf_rules)?;
+    let severity = spec.severity.as_str();
+    let for_secs = spec.for_secs as i64;
+    let repeat_interval = spec.repeat_interval.map(|interval| interval as i64);
+    let id = sqlx::query_scalar!(
+        "INSERT INTO alert_rules (name, expr, for_secs, severity, repeat_interval, auto_resolve) \
+            VALUES (?, ?, ?, ?, ?, ?) \
+            RETURNING id",
+        spec.name,
+        spec.expr,
+        for_secs,
+        severity,
+        repeat_interval,
+        spec.auto_resolve
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| name_taken(e, &spec.name))?;
+    get(pool, id).await
+}
+
+/// Replace the stored rule `id` with `spec`.
+pub async fn update(
+    pool: &SqlitePool,
+    id: i64,
+    spec: &RuleSpec,
+    conf_rules: &[AlertRule],
+) -> Result<StoredRule, RuleError> {
+    check(spec, conf_rules)?;
+    let severity = spec.severity.as_str();
+    let for_secs = spec.for_secs as i64;
+    let repeat_interval = spec.repeat_interval.map(|interval| interval as i64);
+    let rows_affected = sqlx::query!(
+        "UPDATE alert_rules \
+            SET name = ?, expr = ?, for_secs = ?, severity = ?, repeat_interval = ?, \
+                auto_resolve = ?, updated_at = unixepoch() \
+            WHERE id = ?",
+        spec.name,
+        spec.expr,
+        for_secs,
+        severity,
+        repeat_interval,
+        spec.auto_resolve,
+        id
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| name_taken(e, &spec.name))?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        return Err(RuleError::NotFound(id));
+    }
+    get(pool, id).await
+}
+
+pub async fn delete(pool: &SqlitePool, id: i64) -> Result<(), RuleError> {
+    let rows_affected = sqlx::query!("DELETE FROM alert_rules WHERE id = ?", id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if rows_affected == 0 {
+        return Err(RuleError::NotFound(id));
+    }
+    Ok(())
+}
+
+/// The stored rules to evaluate next to `conf_rules`. Rules that no longer
+/// validate, e.g. after a change of the expression language, or that clash
+/// with a rule of the config are skipped.
+pub async fn load(
+    pool: &SqlitePool,
+    conf_rules: &[AlertRule],
+) -> Result<Vec<AlertRule>, RuleError> {
+    Ok(list(pool)
+        .await?
+        .into_iter()
+        .filter_map(|stored| match check(&stored.spec, conf_rules) {
+            Ok(rule) => Some(rule),
+            Err(e) => {
+                warn!(id = stored.id, "skipping stored alert rule: {e}");
+                None
+            }
+        })
+        .collect())
+}
+
+/// Validate `spec` as a rule to store next to `conf_rules`.
+pub fn check(spec: &RuleSpec, conf_rules: &[AlertRule]) -> Result<AlertRule, RuleError> {
+    let rule = spec.validate()?;
+    if conf_rules.iter().any(|r| r.name == rule.name) {
+        return Err(RuleError::ConfigRule(rule.name));
+    }
+    Ok(rule)
+}
+
+fn name_taken(e: sqlx::Error, name: &str) -> RuleError {
+    match e {
+        sqlx::Error::Database(e) if e.is_unique_violation() => {
+            RuleError::NameTaken(name.to_owned())
+        }
+        e => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(toml: &str) -> RuleSpec {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn validation() {
+        let rule = spec("name = \"cpu\"\nexpr = \"cpu > 0.9\"\nfor = 60\n")
+            .validate()
+            .unwrap();
+        assert_eq!(rule.for_secs, 60);
+        assert_eq!(rule.severity, Severity::Warn);
+        assert!(rule.auto_resolve);
+
+        let err = |toml: &str| spec(toml).validate().unwrap_err().to_string();
+        assert!(err("name = \" \"\nexpr = \"cpu > 0.9\"\n").contains("name"));
+        assert!(err("name = \"cpu\"\nexpr = \"cpu >\"\n").starts_with("Invalid expression"));
+        assert!(
+            err("name = \"cpu\"\nexpr = \"cpu > 0.9\"\nrepeat_interval = 0\n")
+                .contains("repeat_interval")
+        );
+        assert!(err("name = \"cpu\"\nexpr = \"cpu\"\nfor = 9223372036854775808\n").contains("for"));
+
+        let conf_rules = vec![rule];
+        assert!(matches!(
+            check(&spec("name = \"cpu\"\nexpr = \"cpu > 0.5\"\n"), &conf_rules),
+            Err(RuleError::ConfigRule(_))
+        ));
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use super::AlertRule;
+
+/// What happened to an alert after observing a new value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// The condition held for long enough, the alert starts firing
+    Firing,
+    /// The alert is still firing and `repeat_interval` has elapsed
+    Repeat,
+    /// The condition no longer holds
+    Resolved,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AlertState {
+    pending_since: i64,
+    firing: bool,
+    last_notified: i64,
+    /// Firing during a silence, not notified yet
+    withheld: bool,
+}
+
+/// Tracks pending/firing alerts per (rule, client).
+#[derive(Debug, Default)]
+pub struct AlertTracker {
+    states: HashMap<(String, i64), AlertState>,
+}
+
+impl AlertTracker {
+    /// Feed whether the rule condition is met for a client at `now` (unix seconds).
+    pub fn observe(
+        &mut self,
+        rule: &AlertRule,
+        client_id: i64,
+        met: bool,
+        now: i64,
+    ) -> Option<Transition> {
+        let key = (rule.name.clone(), client_id);
+
+        if !met {
+            let state = self.states.remove(&key)?;
+            return (state.firing && rule.auto_resolve).then_some(Transition::Resolved);
+        }
+
+        let state = self.states.entry(key).or_insert(AlertState {
+            pending_since: now,
+            firing: false,
+            last_notified: now,
+            withheld: false,
+        });
+
+        if !state.firing {
+            if now - state.pending_since < rule.for_secs as i64 {
+                return None;
+            }
+            state.firing = true;
+            state.last_notified = now;
+            return Some(Transition::Firing);
+        }
+
+        match rule.repeat_interval {
+            Some(interval) if now - state.last_notified >= interval as i64 => {
+                state.last_notified = now;
+                Some(Transition::Repeat)
+            }
+            _ => None,
+        }
+    }
+
+    /// Forget the alerts of rules other than `rules`.
+    pub fn retain_rules(&mut self, rules: &[AlertRule]) {
+        self.states
+            .retain(|(name, _), _| rules.iter().any(|rule| rule.name == *name));
+    }
+
+    /// Hold back the notification of the firing alert of a client, it
+    /// started firing during a silence.
+    pub fn withhold(&mut self, rule: &AlertRule, client_id: i64) {
+        if let Some(state) = self.states.get_mut(&(rule.name.clone(), client_id)) {
+            state.withheld = true;
+        }
+    }
+
+    /// Whether the notification of the alert of a client was held back, it
+    /// no longer is.
+    pub fn release(&mut self, rule: &AlertRule, client_id: i64) -> bool {
+        self.states
+            .get_mut(&(rule.name.clone(), client_id))
+            .is_some_and(|state| std::mem::take(&mut state.withheld))
+    }
+
+    /// Unix timestamp since when the condition holds, if it does.
+    pub fn since(&self, rule: &AlertRule, client_id: i64) -> Option<i64> {
+        self.states
+            .get(&(rule.name.clone(), client_id))
+            .map(|state| state.pending_since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alert::Severity;
+
+    fn rule(for_secs: u64, repeat_interval: Option<u64>, auto_resolve: bool) -> AlertRule {
+        AlertRule {
+            name: "high-cpu".to_owned(),
+            expr: "cpu > 0.9".parse().unwrap(),
+            for_secs,
+            severity: Severity::Crit,
+            repeat_interval,
+            auto_resolve,
+        }
+    }
+
+    #[test]
+    fn fires_after_for_duration() {
+        let rule = rule(60, None, true);
+        let mut tracker = AlertTracker::default();
+
+        assert_eq!(tracker.observe(&rule, 1, true, 0), None);
+        assert_eq!(tracker.observe(&rule, 1, true, 30), None);
+        assert_eq!(
+            tracker.observe(&rule, 1, true, 60),
+            Some(Transition::Firing)
+        );
+        assert_eq!(tracker.observe(&rule, 1, true, 90), None);
+        assert_eq!(tracker.since(&rule, 1), Some(0));
+    }
+
+    #[test]
+    fn pending_alert_resets_silently() {
+        let rule = rule(60, None, true);
+        let mut tracker = AlertTracker::default();
+
+        assert_eq!(tracker.observe(&rule, 1, true, 0), None);
+        assert_eq!(tracker.observe(&rule, 1, false, 30), None);
+        assert_eq!(tracker.observe(&rule, 1, true, 60), None);
+        assert_eq!(
+            tracker.observe(&rule, 1, true, 120),
+            Some(Transition::Firing)
+        );
+    }
+
+    #[test]
+    fn repeats_firing_alert() {
+        let rule = rule(0, Some(100), true);
+        let mut tracker = AlertTracker::default();
+
+        assert_eq!(tracker.observe(&rule, 1, true, 0), Some(Transition::Firing));
+        assert_eq!(tracker.observe(&rule, 1, true, 50), None);
+        assert_eq!(
+            tracker.observe(&rule, 1, true, 100),
+            Some(Transition::Repeat)
+        );
+        assert_eq!(tracker.observe(&rule, 1, true, 150), None);
+        assert_eq!(
+            tracker.observe(&rule, 1, true, 200),
+            Some(Transition::Repeat)
+        );
+    }
+
+    #[test]
+    fn auto_resolve() {
+        let mut tracker = AlertTracker::default();
+
+        let resolving = rule(0, None, true);
+        assert_eq!(
+            tracker.observe(&resolving, 1, true, 0),
+            Some(Transition::Firing)
+        );
+        assert_eq!(
+            tracker.observe(&resolving, 1, false, 10),
+            Some(Transition::Resolved)
+        );
+
+        let latching = rule(0, None, false);
+        assert_eq!(
+            tracker.observe(&latching, 2, true, 0),
+            Some(Transition::Firing)
+        );
+        assert_eq!(tracker.observe(&latching, 2, false, 10), None);
+        assert_eq!(tracker.since(&latching, 2), None);
+    }
+
+    #[test]
+    fn clients_are_independent() {
+        let rule = rule(0, None, true);
+        let mut tracker = AlertTracker::default();
+
+        assert_eq!(tracker.observe(&rule, 1, true, 0), Some(Transition::Firing));
+        assert_eq!(tracker.observe(&rule, 2, true, 0), Some(Transition::Firing));
+        assert_eq!(
+            tracker.observe(&rule, 1, false, 10),
+            Some(Transition::Resolved)
+        );
+        assert_eq!(tracker.observe(&rule, 2, true, 10), None);
+    }
+
+    #[test]
+    fn forgets_removed_rules() {
+        let rule = rule(0, None, true);
+        let mut tracker = AlertTracker::default();
+
+        assert_eq!(tracker.observe(&rule, 1, true, 0), Some(Transition::Firing));
+        tracker.retain_rules(std::slice::from_ref(&rule));
+        assert_eq!(tracker.since(&rule, 1), Some(0));
+        tracker.retain_rules(&[]);
+        assert_eq!(tracker.since(&rule, 1), None);
+    }
+
+    #[test]
+    fn withheld_until_released() {
+        let rule = rule(0, None, true);
+        let mut tracker = AlertTracker::default();
+
+        assert!(!tracker.release(&rule, 1));
+        assert_eq!(tracker.observe(&rule, 1, true, 0), Some(Transition::Firing));
+        tracker.withhold(&rule, 1);
+        assert_eq!(tracker.observe(&rule, 1, true, 10), None);
+        assert!(tracker.release(&rule, 1));
+        assert!(!tracker.release(&rule, 1));
+
+        // gone with the alert
+        tracker.withhold(&rule, 1);
+        assert_eq!(
+            tracker.observe(&rule, 1, false, 20),
+            Some(Transition::Resolved)
+        );
+        assert!(!tracker.release(&rule, 1));
+    }
+}
@@ -0,0 +1,51 @@
+//! Built-in alert rules to start from, printed by `admin alerts template`
+//! in the format of `alerts.rules`.
+
+pub struct Template {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub rule: &'static str,
+}
+
+pub const TEMPLATES: &[Template] = &[
+    Template {
+        name: "clock-unsynced",
+        description: "The clock of a client lost its time source",
+        rule: r#"name = "clock-unsynced"
+# samples of the client drift off in time from here on
+expr = "clock_synced < 1"
+for = 600
+severity = "warn"
+"#,
+    },
+    Template {
+        name: "clock-offset",
+        description: "The clock of a client is off by more than a second",
+        rule: r#"name = "clock-offset"
+# only reported by clients running chrony
+expr = "clock_offset > 1"
+for = 300
+severity = "crit"
+"#,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Template> {
+    TEMPLATES.iter().find(|template| template.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alert::AlertRule;
+
+    #[test]
+    fn templates_parse() {
+        for template in TEMPLATES {
+            let rule: AlertRule = toml::from_str(template.rule).unwrap();
+            assert_eq!(rule.name, template.name);
+        }
+        assert!(find("clock-offset").is_some());
+        assert!(find("clock").is_none());
+    }
+}
@@ -0,0 +1,125 @@
+use std::ops::Range;
+
+use clap::Subcommand;
+use confique::{
+    Config,
+    meta::{FieldKind, Meta},
+    toml::FormatOptions,
+};
+use toml::de::{DeTable, DeValue};
+
+use crate::Conf;
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommands {
+    /// Validate the config file, environment variables included, and report
+    /// keys the server does not know
+    Check,
+    /// Print a config file with every option commented out at its default
+    PrintDefault,
+}
+
+pub fn config(command: ConfigCommands, path: &str) -> anyhow::Result<()> {
+    match command {
+        ConfigCommands::Check => check(path),
+        ConfigCommands::PrintDefault => {
+            print!(
+                "{}",
+                confique::toml::template::<Conf>(FormatOptions::default())
+            );
+            Ok(())
+        }
+    }
+}
+
+fn check(path: &str) -> anyhow::Result<()> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read config file {path}: {e}"))?;
+    crate::config(path)?;
+
+    let table = DeTable::parse(&content)?;
+    let mut unknown = Vec::new();
+    unknown_keys(table.get_ref(), &Conf::META, "", &mut unknown);
+    for (key, span) in &unknown {
+        let (line, column) = line_column(&content, span.start);
+        eprintln!("{path}:{line}:{column}: unknown key `{key}`");
+    }
+    if !unknown.is_empty() {
+        anyhow::bail!("{path} has {} unknown keys", unknown.len());
+    }
+
+    println!("{path} is valid");
+    Ok(())
+}
+
+/// Collect the dotted paths and spans of keys in `table` that are not fields
+/// of `meta`, descending into nested configs but not into values.
+fn unknown_keys(
+    table: &DeTable<'_>,
+    meta: &Meta,
+    prefix: &str,
+    unknown: &mut Vec<(String, Range<usize>)>,
+) {
+    for (key, value) in table {
+        let name: &str = key.get_ref();
+        match meta.fields.iter().find(|f| f.name == name) {
+            None => unknown.push((format!("{prefix}{name}"), key.span())),
+            Some(field) => {
+                if let (FieldKind::Nested { meta }, DeValue::Table(table)) =
+                    (field.kind, value.get_ref())
+                {
+                    unknown_keys(table, meta, &format!("{prefix}{name}."), unknown);
+                }
+            }
+        }
+    }
+}
+
+/// 1-based line and column of a byte offset.
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unknown(content: &str) -> Vec<(String, (usize, usize))> {
+        let table = DeTable::parse(content).unwrap();
+        let mut unknown = Vec::new();
+        unknown_keys(table.get_ref(), &Conf::META, "", &mut unknown);
+        unknown
+            .into_iter()
+            .map(|(key, span)| (key, line_column(content, span.start)))
+            .collect()
+    }
+
+    #[test]
+    fn reports_unknown_keys() {
+        let content = "port = 8000\nprot = 8001\n\n[relay]\nupstrem = \"x\"\n\n[alerts]\n";
+        assert_eq!(
+            unknown(content),
+            [
+                ("prot".to_owned(), (2, 1)),
+                ("relay.upstrem".to_owned(), (5, 1))
+            ]
+        );
+        assert!(unknown("[quotas]\n").is_empty());
+    }
+
+    #[test]
+    fn default_template_is_valid() {
+        let template = confique::toml::template::<Conf>(FormatOptions::default());
+        assert!(unknown(&template).is_empty());
+        // every option is commented out
+        let table: toml::Table = toml::from_str(&template).unwrap();
+        assert!(
+            table
+                .values()
+                .all(|v| v.as_table().is_some_and(|t| t.is_empty()))
+        );
+    }
+}
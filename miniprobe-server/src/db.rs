@@ -0,0 +1,501 @@
+use std::{
+    ops::{Deref, DerefMut},
+    path::Path,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use sqlx::{
+    Executor, SqlitePool,
+    migrate::{Migrate, MigrateError, Migrator},
+    pool::PoolConnection,
+    sqlite::{
+        Sqlite, SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePoolOptions,
+    },
+};
+use tracing::info;
+
+/// Migrations embedded in the binary
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Migrations of the samples database
+pub static SAMPLES_MIGRATOR: Migrator = sqlx::migrate!("./migrations-samples");
+
+/// Sample tables created in the main database by migrations before the split,
+/// parents first.
+const LEGACY_SAMPLE_TABLES: &[&str] = &[
+    "strings",
+    "session_data",
+    "session_data_cpu",
+    "session_data_cpu_aggregate",
+    "session_data_memory",
+    "session_data_network",
+    "session_data_sensors",
+];
+
+/// SQLite pools split by access: a single writer connection serializing all
+/// writes, and read-only connections for queries.
+///
+/// Readers never wait for the write lock in WAL mode, so dashboards querying
+/// during heavy ingest do not run into `database is locked`.
+///
+/// Samples live in a second database attached as `samples` to every
+/// connection, queries name their tables unqualified and work across both.
+/// The main database with clients and sessions stays small to back up, and
+/// deleting the samples database while the server is stopped drops every
+/// sample without touching the configuration.
+#[derive(Clone, Debug)]
+pub struct Db {
+    pub writer: SqlitePool,
+    pub reader: SqlitePool,
+    /// Connection to the samples database alone, for its migrations
+    pub samples: SqlitePool,
+}
+
+impl Db {
+    /// Open both databases, encrypted with `key` if given, creating them if
+    /// missing.
+    pub async fn connect(
+        url: &str,
+        samples_url: &str,
+        read_connections: u32,
+        key: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        Self::open(url, samples_url, read_connections, key, true).await
+    }
+
+    /// Open both databases like [`connect`](Self::connect), failing if either
+    /// does not exist rather than creating it.
+    pub async fn connect_existing(
+        url: &str,
+        samples_url: &str,
+        read_connections: u32,
+        key: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        Self::open(url, samples_url, read_connections, key, false).await
+    }
+
+    async fn open(
+        url: &str,
+        samples_url: &str,
+        read_connections: u32,
+        key: Option<&str>,
+        create: bool,
+    ) -> anyhow::Result<Self> {
+        let with_key = |opts: SqliteConnectOptions| match key {
+            Some(key) => opts.pragma("key", quote_key(key)),
+            None => opts,
+        };
+        let samples_opts = with_key(SqliteConnectOptions::from_str(samples_url)?)
+            .create_if_missing(create)
+            .journal_mode(SqliteJournalMode::Wal);
+        let samples_path = samples_opts.get_filename().to_string_lossy().into_owned();
+
+        // create the samples database before it is attached
+        let samples = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(samples_opts)
+            .await?;
+
+        let opts = with_key(SqliteConnectOptions::from_str(url)?)
+            .create_if_missing(create)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool_opts = || {
+            let samples_path = samples_path.clone();
+            let key = key.map(str::to_owned);
+            SqlitePoolOptions::new().after_connect(move |conn, _| {
+                let samples_path = samples_path.clone();
+                let key = key.clone();
+                Box::pin(async move {
+                    // without a key SQLCipher would reuse the one derived for
+                    // the main database, with the salt of the main database
+                    let attach = match key {
+                        Some(key) => sqlx::query("ATTACH DATABASE ? AS samples KEY ?")
+                            .bind(samples_path)
+                            .bind(key),
+                        None => sqlx::query("ATTACH DATABASE ? AS samples").bind(samples_path),
+                    };
+                    conn.execute(attach).await?;
+                    Ok(())
+                })
+            })
+        };
+
+        // the writer has to create the database before readers can open it
+        let writer = pool_opts()
+            .max_connections(1)
+            .connect_with(opts.clone())
+            .await?;
+        let reader = pool_opts()
+            .max_connections(read_connections.max(1))
+            // installed by `Db::interruptible_reader`
+            .after_release(|conn, _| {
+                Box::pin(async move {
+                    conn.lock_handle().await?.remove_progress_handler();
+                    Ok(true)
+                })
+            })
+            .connect_with(opts.read_only(true))
+            .await?;
+
+        Ok(Db {
+            writer,
+            reader,
+            samples,
+        })
+    }
+
+    /// A read-only connection whose statements are interrupted once it is
+    /// dropped, so a query API request given up on, by its client or by a
+    /// timeout, does not keep a reader busy with a huge time range.
+    pub async fn interruptible_reader(&self) -> sqlx::Result<InterruptibleConnection> {
+        let mut conn = self.reader.acquire().await?;
+        let dropped = Arc::new(AtomicBool::new(false));
+        let handler_dropped = dropped.clone();
+        conn.lock_handle()
+            .await?
+            .set_progress_handler(INTERRUPT_CHECK_OPS, move || {
+                !handler_dropped.load(Ordering::Relaxed)
+            });
+        Ok(InterruptibleConnection { conn, dropped })
+    }
+
+    /// Apply pending migrations of both databases.
+    pub async fn migrate(&self) -> Result<(), MigrateError> {
+        MIGRATOR.run(&self.writer).await?;
+        SAMPLES_MIGRATOR.run(&self.samples).await?;
+        self.move_legacy_samples().await?;
+        Ok(())
+    }
+
+    /// Move samples stored in the main database before the split over to the
+    /// samples database. Fresh databases go through this too, the old
+    /// migrations still create empty sample tables in the main database.
+    async fn move_legacy_samples(&self) -> sqlx::Result<()> {
+        let mut tx = self.writer.begin().await?;
+
+        let legacy: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM main.sqlite_master WHERE type = 'table' AND name = 'session_data')",
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if !legacy {
+            return Ok(());
+        }
+
+        tx.execute("DROP VIEW IF EXISTS main.session_data_network_named")
+            .await?;
+        for table in LEGACY_SAMPLE_TABLES {
+            // the samples schema may have gained columns since, copy the old ones
+            let columns =
+                sqlx::query_scalar::<_, String>("SELECT name FROM pragma_table_info(?, 'main')")
+                    .bind(table)
+                    .fetch_all(&mut *tx)
+                    .await?
+                    .join(", ");
+            tx.execute(
+                format!(
+                    "INSERT INTO samples.{table} ({columns}) SELECT {columns} FROM main.{table}"
+                )
+                .as_str(),
+            )
+            .await?;
+        }
+        // the main database predates millisecond sample times
+        tx.execute(
+            "UPDATE samples.session_data SET sample_time = sample_time * 1000 \
+                WHERE id IN (SELECT id FROM main.session_data)",
+        )
+        .await?;
+        // children first, the implicit delete would cascade otherwise
+        for table in LEGACY_SAMPLE_TABLES.iter().rev() {
+            tx.execute(format!("DROP TABLE main.{table}").as_str())
+                .await?;
+        }
+        tx.commit().await?;
+
+        // give the space back, that is the point of the split after all
+        self.writer.execute("VACUUM main").await?;
+        info!("moved samples to the samples database");
+        Ok(())
+    }
+
+    pub async fn close(&self) {
+        tokio::join!(
+            self.writer.close(),
+            self.reader.close(),
+            self.samples.close()
+        );
+    }
+}
+
+/// The database key from `file`, or else from the environment variable
+/// `env`, without surrounding whitespace. `None` if neither is given.
+pub fn load_key(file: Option<&Path>, env: Option<&str>) -> anyhow::Result<Option<String>> {
+    let key = match (file, env) {
+        (Some(file), _) => std::fs::read_to_string(file).map_err(|e| {
+            anyhow::anyhow!("failed to read database key file {}: {e}", file.display())
+        })?,
+        (None, Some(env)) => std::env::var(env)
+            .map_err(|e| anyhow::anyhow!("failed to read database key from ${env}: {e}"))?,
+        (None, None) => return Ok(None),
+    };
+    let key = key.trim();
+    if key.is_empty() {
+        anyhow::bail!("the database key is empty");
+    }
+    Ok(Some(key.to_owned()))
+}
+
+/// The `key` pragma value of a passphrase, as an SQL string literal.
+fn quote_key(key: &str) -> String {
+    format!("'{}'", key.replace('\'', "''"))
+}
+
+/// Virtual machine instructions SQLite runs between checks whether an
+/// `InterruptibleConnection` was dropped.
+const INTERRUPT_CHECK_OPS: i32 = 10_000;
+
+/// See `Db::interruptible_reader`.
+#[derive(Debug)]
+pub struct InterruptibleConnection {
+    conn: PoolConnection<Sqlite>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl Deref for InterruptibleConnection {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn
+    }
+}
+
+impl DerefMut for InterruptibleConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.conn
+    }
+}
+
+impl Drop for InterruptibleConnection {
+    fn drop(&mut self) {
+        // the statement running on the worker thread of the connection stops
+        // at the next check, before the pool gets the connection back
+        self.dropped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Migration state of a database compared to the migrations of this binary.
+#[derive(Debug)]
+pub struct MigrationStatus {
+    /// Latest applied migration, `None` for a fresh database
+    pub current: Option<i64>,
+    /// Latest migration known to this binary
+    pub latest: Option<i64>,
+    /// Migrations not applied yet, as `(version, description)`
+    pub pending: Vec<(i64, String)>,
+}
+
+impl MigrationStatus {
+    /// Inspect the applied migrations without changing the database.
+    ///
+    /// Fails the same way applying the migrations would when the database is
+    /// dirty, or has migrations that were modified or are unknown to this
+    /// binary.
+    pub async fn check(pool: &SqlitePool, migrator: &Migrator) -> Result<Self, MigrateError> {
+        let mut conn = pool.acquire().await?;
+
+        let initialized: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let applied = if initialized {
+            if let Some(version) = conn.dirty_version().await? {
+                return Err(MigrateError::Dirty(version));
+            }
+            conn.list_applied_migrations().await?
+        } else {
+            Vec::new()
+        };
+
+        for migration in &applied {
+            match migrator.iter().find(|m| m.version == migration.version) {
+                Some(m) if m.checksum != migration.checksum => {
+                    return Err(MigrateError::VersionMismatch(migration.version));
+                }
+                Some(_) => {}
+                None => return Err(MigrateError::VersionMissing(migration.version)),
+            }
+        }
+
+        let pending = migrator
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration())
+            .filter(|m| !applied.iter().any(|a| a.version == m.version))
+            .map(|m| (m.version, m.description.to_string()))
+            .collect();
+
+        Ok(MigrationStatus {
+            current: applied.iter().map(|m| m.version).max(),
+            latest: migrator.iter().map(|m| m.version).max(),
+            pending,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Rows counted by a statement running for far longer than any test.
+    const ENDLESS: &str = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n) \
+        SELECT COUNT(*) FROM n";
+
+    #[test]
+    fn database_key() {
+        assert_eq!(load_key(None, None).unwrap(), None);
+        let path = std::env::temp_dir().join(format!("miniprobe-key-{}", std::process::id()));
+        std::fs::write(&path, "it's secret\n").unwrap();
+        let key = load_key(Some(&path), Some("MINIPROBE_UNSET_KEY")).unwrap();
+        assert_eq!(key.as_deref(), Some("it's secret"));
+        assert_eq!(quote_key(&key.unwrap()), "'it''s secret'");
+        std::fs::write(&path, " \n").unwrap();
+        assert!(load_key(Some(&path), None).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_key(Some(&path), None).is_err());
+        assert!(load_key(None, Some("MINIPROBE_UNSET_KEY")).is_err());
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[tokio::test]
+    async fn encrypted() {
+        let dir =
+            std::env::temp_dir().join(format!("miniprobe-db-encrypted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (url, samples_url) = (
+            format!("sqlite://{}", dir.join("db.sqlite").display()),
+            format!("sqlite://{}", dir.join("samples.sqlite").display()),
+        );
+        let connect = async |key| Db::connect(&url, &samples_url, 1, key).await;
+
+        let db = connect(Some("secret")).await.unwrap();
+        db.migrate().await.unwrap();
+        db.close().await;
+        assert!(connect(None).await.is_err());
+        assert!(connect(Some("wrong")).await.is_err());
+        let db = connect(Some("secret")).await.unwrap();
+        let status = MigrationStatus::check(&db.reader, &MIGRATOR).await.unwrap();
+        assert!(status.pending.is_empty());
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn existing_not_created() {
+        let dir =
+            std::env::temp_dir().join(format!("miniprobe-db-existing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (url, samples_url) = (
+            format!("sqlite://{}", dir.join("db.sqlite").display()),
+            format!("sqlite://{}", dir.join("samples.sqlite").display()),
+        );
+        assert!(
+            Db::connect_existing(&url, &samples_url, 1, None)
+                .await
+                .is_err()
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        Db::connect(&url, &samples_url, 1, None)
+            .await
+            .unwrap()
+            .close()
+            .await;
+        let db = Db::connect_existing(&url, &samples_url, 1, None)
+            .await
+            .unwrap();
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn duplicate_client_names_renamed() {
+        const UNIQUE_CLIENT_NAMES: i64 = 20251009090000;
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let migrate = async |migration: &sqlx::migrate::Migration| {
+            sqlx::raw_sql(&migration.sql).execute(&pool).await.unwrap();
+        };
+        for migration in MIGRATOR.iter().filter(|m| m.version < UNIQUE_CLIENT_NAMES) {
+            migrate(migration).await;
+        }
+        for (id, name) in [
+            (1, "web"),
+            (2, "web"),
+            (3, "web-2"),
+            (4, "web"),
+            (5, "web-1-2"),
+            (6, "db"),
+            (7, "db"),
+        ] {
+            sqlx::query(
+                "INSERT INTO clients (id, name, token_idx, token_hash) VALUES (?, ?, 0, ?)",
+            )
+            .bind(id)
+            .bind(name)
+            .bind(id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let unique_names = MIGRATOR.iter().find(|m| m.version == UNIQUE_CLIENT_NAMES);
+        migrate(unique_names.unwrap()).await;
+        let names = sqlx::query_scalar::<_, String>("SELECT name FROM clients ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        // `web-2` and `web-1-2` were taken
+        assert_eq!(
+            names,
+            ["web", "web-2-2", "web-2", "web-4", "web-1-2", "db", "db-7"]
+        );
+    }
+
+    #[tokio::test]
+    async fn interrupted_when_dropped() {
+        let db = Db::connect(
+            "sqlite:file:interrupt?mode=memory&cache=shared",
+            "sqlite:file:interrupt-samples?mode=memory&cache=shared",
+            1,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let mut conn = db.interruptible_reader().await.unwrap();
+        let endless = sqlx::query_scalar::<_, i64>(ENDLESS).fetch_one(&mut *conn);
+        let timed_out = tokio::time::timeout(Duration::from_millis(100), endless).await;
+        assert!(timed_out.is_err());
+        drop(conn);
+
+        // the only reader is back, without the handler of the dropped one
+        let count = sqlx::query_scalar::<_, i64>(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100000) \
+                SELECT COUNT(*) FROM n",
+        )
+        .fetch_one(&db.reader);
+        let count = tokio::time::timeout(Duration::from_secs(5), count).await;
+        assert_eq!(count.unwrap().unwrap(), 100_000);
+    }
+}
@@ -0,0 +1,217 @@
+//! Alert, host and session state changes, reboots and security events,
+//! broadcast to subscribers of `/ws/v1/events` and to the hooks.
+
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::{
+    alert::{AlertStatus, Severity},
+    overview::ClientState,
+    security::SecurityEventKind,
+};
+
+/// Events buffered per subscriber before a slow one starts missing events.
+const EVENT_CAPACITY: usize = 256;
+
+pub type EventSender = broadcast::Sender<Event>;
+
+pub fn channel() -> EventSender {
+    broadcast::channel(EVENT_CAPACITY).0
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// An alert started firing, fired again or resolved
+    Alert {
+        status: AlertStatus,
+        rule: String,
+        severity: Severity,
+        client_id: i64,
+        client_name: Option<String>,
+        /// Value of the left-hand side of the condition
+        value: Option<f64>,
+        /// Unix timestamp in seconds since when the condition holds
+        since: Option<i64>,
+        /// Unix timestamp in seconds of the evaluation
+        time: i64,
+        /// Notifications of the alert are silenced
+        silenced: bool,
+    },
+    /// A client started or stopped sending samples
+    Host {
+        state: HostState,
+        client_id: i64,
+        client_name: String,
+        /// Unix timestamp in seconds of the latest sample
+        last_sample: Option<i64>,
+        /// Unix timestamp in seconds the change was noticed at
+        time: i64,
+        /// The client is silenced
+        silenced: bool,
+    },
+    /// A session was created, or its ingress websocket connected again or
+    /// closed
+    Session {
+        state: SessionState,
+        session_id: i64,
+        client_id: i64,
+        client_name: String,
+        /// `client` or `server`, whichever side closed first, only when ended
+        closed_by: Option<String>,
+        /// Websocket close code, only when ended
+        close_code: Option<u16>,
+        close_reason: Option<String>,
+        /// Unix timestamp in seconds
+        time: i64,
+    },
+    /// The host of a client booted again since its previous session
+    Reboot {
+        client_id: i64,
+        client_name: String,
+        /// The first session after the reboot
+        session_id: i64,
+        /// Unix timestamps in seconds
+        boot_time: i64,
+        previous_boot_time: i64,
+        /// Unix timestamp in seconds the reboot was noticed at
+        time: i64,
+    },
+    /// Something `security_events` records, e.g. a failed authentication
+    Security {
+        kind: SecurityEventKind,
+        client_id: Option<i64>,
+        client_name: Option<String>,
+        /// Peer address of the request, unknown on unix sockets
+        address: Option<IpAddr>,
+        detail: String,
+        /// Unix timestamp in seconds
+        time: i64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostState {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionState {
+    Created,
+    Resumed,
+    Ended,
+}
+
+/// Host state change between two observations of a client, if any.
+fn host_transition(prev: Option<ClientState>, next: ClientState) -> Option<HostState> {
+    match (prev?, next) {
+        (ClientState::Stale | ClientState::Never, ClientState::Up) => Some(HostState::Up),
+        (ClientState::Up, ClientState::Stale) => Some(HostState::Down),
+        _ => None,
+    }
+}
+
+/// Watches the staleness of clients and publishes `Event::Host` on changes.
+pub struct HostWatcher {
+    pool: SqlitePool,
+    events: EventSender,
+    interval: Duration,
+    states: HashMap<i64, ClientState>,
+}
+
+impl HostWatcher {
+    pub fn new(pool: SqlitePool, events: EventSender, interval: Duration) -> Self {
+        HostWatcher {
+            pool,
+            events,
+            interval,
+            states: HashMap::new(),
+        }
+    }
+
+    /// Check clients periodically until cancelled.
+    pub async fn run(mut self, cancellation_token: CancellationToken) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = self.check().await {
+                        warn!("host state check failed: {e}");
+                    }
+                }
+                _ = cancellation_token.cancelled() => return,
+            }
+        }
+    }
+
+    async fn check(&mut self) -> sqlx::Result<()> {
+        let clients = sqlx::query!(
+            r#"
+            SELECT c.id AS "id!", c.name, unixepoch() AS "now!: i64",
+                (
+                    SELECT MAX(d.sample_time) / 1000 FROM sessions s
+                    JOIN session_data d ON d.session_id = s.id
+                    WHERE s.client_id = c.id
+                ) AS "last_sample: i64",
+                EXISTS(
+                    SELECT 1 FROM silences s
+                    WHERE (s.client_id = c.id OR s.client_id IS NULL)
+                        AND s.starts_at <= unixepoch() AND s.ends_at > unixepoch()
+                ) AS "silenced!: bool"
+            FROM clients c
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut states = HashMap::with_capacity(clients.len());
+        for client in clients {
+            let state = ClientState::of(client.now, client.last_sample);
+            // the first check only learns the current states
+            if let Some(host_state) = host_transition(self.states.get(&client.id).copied(), state) {
+                debug!(client = client.name, ?host_state, "host state changed");
+                // sending only fails without subscribers
+                self.events
+                    .send(Event::Host {
+                        state: host_state,
+                        client_id: client.id,
+                        client_name: client.name,
+                        last_sample: client.last_sample,
+                        time: client.now,
+                        silenced: client.silenced,
+                    })
+                    .ok();
+            }
+            states.insert(client.id, state);
+        }
+        self.states = states;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_transitions() {
+        use ClientState::*;
+
+        assert_eq!(host_transition(None, Up), None);
+        assert_eq!(host_transition(Some(Up), Up), None);
+        assert_eq!(host_transition(Some(Up), Stale), Some(HostState::Down));
+        assert_eq!(host_transition(Some(Stale), Stale), None);
+        assert_eq!(host_transition(Some(Stale), Up), Some(HostState::Up));
+        assert_eq!(host_transition(Some(Never), Up), Some(HostState::Up));
+        assert_eq!(host_transition(Some(Never), Never), None);
+    }
+}
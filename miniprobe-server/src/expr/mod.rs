@@ -0,0 +1,399 @@
+//! A tiny expression language over client metrics, shared by the query API
+//! and alert rules, e.g. `avg_over_time(cpu[5m]) > 0.9`.
+
+use std::str::FromStr;
+
+use miniprobe_proto::metrics_math;
+use serde::Deserialize;
+
+pub use parser::{ParseError, parse_range};
+pub use samples::{Sample, fetch_samples};
+
+mod parser;
+mod samples;
+
+/// How far an instant selector looks back for the latest sample, in seconds.
+const INSTANT_LOOKBACK: i64 = 5 * 60;
+
+/// Longest range of a range selector, in seconds. Windows stay far from
+/// overflowing once in milliseconds.
+pub const MAX_RANGE: i64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Expr {
+    Number(f64),
+    /// Latest value of a metric
+    Metric(Metric),
+    /// Function over a metric in the last given seconds
+    Range(RangeFunc, Metric, i64),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    /// Evaluates to `1` if the comparison holds and `0` otherwise
+    Compare(CmpOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Average usage over all cores, `0..=1`
+    Cpu,
+    /// Usage of the busiest core, `0..=1`
+    CpuMaxCore,
+    /// Used memory over total memory, `0..=1`
+    Memory,
+    /// Used swap over total swap, `0..=1`
+    Swap,
+    MemoryUsed,
+    MemoryTotal,
+    SwapUsed,
+    SwapTotal,
+    /// Received bytes counter
+    RxBytes,
+    /// Transmitted bytes counter
+    TxBytes,
+    /// Hottest CPU sensor in degrees Celsius
+    CpuTemperature,
+    /// Average CPU frequency in MHz
+    CpuFrequency,
+    /// Seconds the probe took to collect a sample
+    ProbeCollectionTime,
+    /// CPU usage of the probe process, `1` is one full core
+    ProbeCpu,
+    /// Resident memory of the probe process in bytes
+    ProbeRss,
+    /// Processes of the system
+    Processes,
+    /// Threads of all processes, only reported by Linux clients
+    Threads,
+    /// Processes that exited without being reaped by their parent
+    Zombies,
+    /// Open file descriptors over the limit of the system, `0..=1`
+    Fds,
+    FdsOpen,
+    FdsMax,
+    /// TCP connections established
+    TcpEstablished,
+    /// TCP connections in `TIME_WAIT`
+    TcpTimeWait,
+    /// `1` while the clock follows its time source, `0` otherwise
+    ClockSynced,
+    /// Seconds the clock is off its time source, ahead or behind
+    ClockOffset,
+    /// Battery charge, `0..=1`
+    Battery,
+    /// Power flowing into or out of the battery in watts
+    BatteryPower,
+    /// `1` while running on battery, `0` otherwise
+    BatteryDischarging,
+    /// Watched systemd units that failed
+    ServicesFailed,
+    /// Watched systemd units that are not running
+    ServicesDown,
+    /// Automatic restarts of watched services, a counter
+    ServiceRestarts,
+    /// Seconds between taking a sample and its arrival at the server,
+    /// negative if the client clock is ahead
+    IngestDelay,
+}
+
+impl Metric {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "cpu" => Metric::Cpu,
+            "cpu_max_core" => Metric::CpuMaxCore,
+            "memory" => Metric::Memory,
+            "swap" => Metric::Swap,
+            "memory_used" => Metric::MemoryUsed,
+            "memory_total" => Metric::MemoryTotal,
+            "swap_used" => Metric::SwapUsed,
+            "swap_total" => Metric::SwapTotal,
+            "rx_bytes" => Metric::RxBytes,
+            "tx_bytes" => Metric::TxBytes,
+            "cpu_temperature" => Metric::CpuTemperature,
+            "cpu_frequency" => Metric::CpuFrequency,
+            "probe_collection_time" => Metric::ProbeCollectionTime,
+            "probe_cpu" => Metric::ProbeCpu,
+            "probe_rss" => Metric::ProbeRss,
+            "processes" => Metric::Processes,
+            "threads" => Metric::Threads,
+            "zombies" => Metric::Zombies,
+            "fds" => Metric::Fds,
+            "fds_open" => Metric::FdsOpen,
+            "fds_max" => Metric::FdsMax,
+            "tcp_established" => Metric::TcpEstablished,
+            "tcp_time_wait" => Metric::TcpTimeWait,
+            "clock_synced" => Metric::ClockSynced,
+            "clock_offset" => Metric::ClockOffset,
+            "battery" => Metric::Battery,
+            "battery_power" => Metric::BatteryPower,
+            "battery_discharging" => Metric::BatteryDischarging,
+            "services_failed" => Metric::ServicesFailed,
+            "services_down" => Metric::ServicesDown,
+            "service_restarts" => Metric::ServiceRestarts,
+            "ingest_delay" => Metric::IngestDelay,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeFunc {
+    Avg,
+    Min,
+    Max,
+    /// Per-second increase of a counter, tolerating counter resets
+    Rate,
+}
+
+impl RangeFunc {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "avg_over_time" => RangeFunc::Avg,
+            "min_over_time" => RangeFunc::Min,
+            "max_over_time" => RangeFunc::Max,
+            "rate" => RangeFunc::Rate,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+impl FromStr for Expr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parser::parse(s)
+    }
+}
+
+impl TryFrom<String> for Expr {
+    type Error = ParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Expr {
+    /// Seconds of history needed to evaluate the expression.
+    pub fn lookback(&self) -> i64 {
+        match self {
+            Expr::Number(_) => 0,
+            Expr::Metric(_) => INSTANT_LOOKBACK,
+            Expr::Range(_, _, range) => *range,
+            Expr::Binary(_, lhs, rhs) | Expr::Compare(_, lhs, rhs) => {
+                lhs.lookback().max(rhs.lookback())
+            }
+        }
+    }
+
+    /// Evaluate at `now`, in unix seconds, over samples sorted by `sample_time`.
+    pub fn eval(&self, samples: &[Sample], now: i64) -> Option<f64> {
+        self.eval_with_lookback(samples, now, INSTANT_LOOKBACK)
+    }
+
+    /// Evaluate at the end of the bucket `(now - step, now]`, metrics only
+    /// take samples of the bucket so a bucket without samples has no value.
+    pub fn eval_bucket(&self, samples: &[Sample], now: i64, step: i64) -> Option<f64> {
+        self.eval_with_lookback(samples, now, step)
+    }
+
+    fn eval_with_lookback(&self, samples: &[Sample], now: i64, lookback: i64) -> Option<f64> {
+        // sample times are in milliseconds, a time past the year 292 million
+        // is as good as the end of time
+        let (now_ms, lookback_ms) = (now.saturating_mul(1000), lookback.saturating_mul(1000));
+        match self {
+            Expr::Number(number) => Some(*number),
+            Expr::Metric(metric) => samples
+                .iter()
+                .rev()
+                .skip_while(|s| s.sample_time > now_ms)
+                .take_while(|s| s.sample_time > now_ms.saturating_sub(lookback_ms))
+                .find_map(|s| s.get(*metric)),
+            Expr::Range(func, metric, range) => {
+                let points = samples
+                    .iter()
+                    .filter(|s| {
+                        s.sample_time > now_ms.saturating_sub(range.saturating_mul(1000))
+                            && s.sample_time <= now_ms
+                    })
+                    .filter_map(|s| s.get(*metric).map(|v| (s.sample_time, v)))
+                    .collect::<Vec<_>>();
+                func.apply(&points)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (
+                    lhs.eval_with_lookback(samples, now, lookback)?,
+                    rhs.eval_with_lookback(samples, now, lookback)?,
+                );
+                let value = match op {
+                    BinOp::Add => lhs + rhs,
+                    BinOp::Sub => lhs - rhs,
+                    BinOp::Mul => lhs * rhs,
+                    BinOp::Div if rhs == 0.0 => return None,
+                    BinOp::Div => lhs / rhs,
+                };
+                Some(value)
+            }
+            Expr::Compare(op, lhs, rhs) => {
+                let (lhs, rhs) = (
+                    lhs.eval_with_lookback(samples, now, lookback)?,
+                    rhs.eval_with_lookback(samples, now, lookback)?,
+                );
+                let holds = match op {
+                    CmpOp::Gt => lhs > rhs,
+                    CmpOp::Ge => lhs >= rhs,
+                    CmpOp::Lt => lhs < rhs,
+                    CmpOp::Le => lhs <= rhs,
+                    CmpOp::Eq => lhs == rhs,
+                    CmpOp::Ne => lhs != rhs,
+                };
+                Some(if holds { 1.0 } else { 0.0 })
+            }
+        }
+    }
+
+    /// Whether the expression evaluates to a non-zero value, unknown when it
+    /// can not be evaluated, e.g. without samples in its range.
+    pub fn holds(&self, samples: &[Sample], now: i64) -> Option<bool> {
+        self.eval(samples, now).map(|value| value != 0.0)
+    }
+
+    /// The value worth reporting: the left-hand side of a comparison, or the result.
+    pub fn observed(&self, samples: &[Sample], now: i64) -> Option<f64> {
+        match self {
+            Expr::Compare(_, lhs, _) => lhs.eval(samples, now),
+            expr => expr.eval(samples, now),
+        }
+    }
+}
+
+impl RangeFunc {
+    fn apply(self, points: &[(i64, f64)]) -> Option<f64> {
+        let values = points.iter().map(|(_, v)| *v);
+        match self {
+            RangeFunc::Avg => metrics_math::mean(values),
+            RangeFunc::Min => metrics_math::min(values),
+            RangeFunc::Max => metrics_math::max(values),
+            RangeFunc::Rate => {
+                let (first, last) = (points.first()?, points.last()?);
+                if last.0 <= first.0 {
+                    return None;
+                }
+                let increase = points
+                    .windows(2)
+                    .map(|w| {
+                        let (prev, curr) = (w[0].1, w[1].1);
+                        // a decreasing counter has been reset
+                        if curr >= prev { curr - prev } else { curr }
+                    })
+                    .sum::<f64>();
+                // per second, the times are in milliseconds
+                Some(increase * 1000.0 / (last.0 - first.0) as f64)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(secs: i64, cpu: f64, rx_bytes: i64) -> Sample {
+        Sample {
+            sample_time: secs * 1000,
+            cpu: Some(cpu),
+            rx_bytes: Some(rx_bytes),
+            ..Default::default()
+        }
+    }
+
+    fn eval(expr: &str, samples: &[Sample], now: i64) -> Option<f64> {
+        expr.parse::<Expr>().unwrap().eval(samples, now)
+    }
+
+    #[test]
+    fn instant_selector() {
+        let samples = [sample(100, 10.0, 0), sample(110, 20.0, 0)];
+
+        assert_eq!(eval("cpu", &samples, 110), Some(0.2));
+        assert_eq!(eval("cpu", &samples, 105), Some(0.1));
+        assert_eq!(eval("cpu * 100", &samples, 110), Some(20.0));
+        assert_eq!(eval("cpu", &samples, 110 + INSTANT_LOOKBACK), None);
+        assert_eq!(eval("memory", &samples, 110), None);
+    }
+
+    #[test]
+    fn bucket_evaluation() {
+        let samples = [sample(100, 10.0, 0), sample(110, 20.0, 0)];
+        let expr = "cpu".parse::<Expr>().unwrap();
+
+        assert_eq!(expr.eval_bucket(&samples, 110, 10), Some(0.2));
+        assert_eq!(expr.eval_bucket(&samples, 100, 10), Some(0.1));
+        // the sample at 110 belongs to the previous bucket
+        assert_eq!(expr.eval_bucket(&samples, 120, 10), None);
+        assert_eq!(expr.eval(&samples, 120), Some(0.2));
+    }
+
+    #[test]
+    fn range_functions() {
+        let samples = [
+            sample(0, 10.0, 100),
+            sample(10, 20.0, 200),
+            sample(20, 90.0, 50), // counter reset
+            sample(30, 40.0, 150),
+        ];
+
+        assert_eq!(eval("avg_over_time(cpu[25s])", &samples, 30), Some(0.5));
+        assert_eq!(eval("max_over_time(cpu[1m])", &samples, 30), Some(0.9));
+        assert_eq!(eval("min_over_time(cpu[1m])", &samples, 30), Some(0.1));
+        assert_eq!(eval("rate(rx_bytes[1m])", &samples, 30), Some(250.0 / 30.0));
+        assert_eq!(eval("rate(rx_bytes[5s])", &samples, 30), None);
+    }
+
+    #[test]
+    fn sub_second_rate() {
+        let samples = [0, 250, 500].map(|ms| Sample {
+            sample_time: 10_000 + ms,
+            rx_bytes: Some(ms * 2),
+            ..Default::default()
+        });
+
+        assert_eq!(eval("rate(rx_bytes[1m])", &samples, 11), Some(2000.0));
+    }
+
+    #[test]
+    fn comparisons() {
+        let samples = [sample(0, 95.0, 0)];
+        let expr = "avg_over_time(cpu[5m]) > 0.9".parse::<Expr>().unwrap();
+
+        assert_eq!(expr.holds(&samples, 0), Some(true));
+        assert_eq!(expr.observed(&samples, 0), Some(0.95));
+        assert_eq!(expr.holds(&samples, 1000), None);
+        assert_eq!(
+            "avg_over_time(cpu[5m]) > 0.99"
+                .parse::<Expr>()
+                .unwrap()
+                .holds(&samples, 0),
+            Some(false)
+        );
+        assert_eq!(expr.lookback(), 300);
+        assert_eq!(eval("1 / 0", &samples, 0), None);
+    }
+}
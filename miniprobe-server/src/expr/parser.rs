@@ -0,0 +1,354 @@
+use super::{BinOp, CmpOp, Expr, MAX_RANGE, Metric, RangeFunc};
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+#[error("{message} at position {position}")]
+pub struct ParseError {
+    message: String,
+    position: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    /// `[5m]`, in seconds
+    Range(i64),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Lexer<'_> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            message: message.into(),
+            position: self.pos,
+        })
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        let len = self.src[start..]
+            .find(|c| !f(c))
+            .unwrap_or(self.src.len() - start);
+        self.pos += len;
+        &self.src[start..self.pos]
+    }
+
+    fn tokenize(mut self) -> Result<Vec<(usize, Token)>, ParseError> {
+        const OPS: [&str; 10] = [">=", "<=", "==", "!=", ">", "<", "+", "-", "*", "/"];
+
+        let mut tokens = Vec::new();
+        while let Some(c) = self.src[self.pos..].chars().next() {
+            let start = self.pos;
+            let token = if c.is_whitespace() {
+                self.pos += c.len_utf8();
+                continue;
+            } else if c.is_ascii_digit() || c == '.' {
+                let number = self
+                    .take_while(|c| c.is_ascii_digit() || c == '.')
+                    .to_owned();
+                match number.parse() {
+                    Ok(number) => Token::Number(number),
+                    Err(_) => return self.error(format!("invalid number `{number}`")),
+                }
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                Token::Ident(
+                    self.take_while(|c| c.is_ascii_alphanumeric() || c == '_')
+                        .to_owned(),
+                )
+            } else if c == '[' {
+                self.pos += 1;
+                let range = self.take_while(|c| c != ']').trim().to_owned();
+                if !self.src[self.pos..].starts_with(']') {
+                    return self.error("unclosed `[`");
+                }
+                self.pos += 1;
+                match parse_range(&range) {
+                    Some(secs) => Token::Range(secs),
+                    None => return self.error(format!("invalid range `{range}`")),
+                }
+            } else if c == '(' {
+                self.pos += 1;
+                Token::LParen
+            } else if c == ')' {
+                self.pos += 1;
+                Token::RParen
+            } else if let Some(op) = OPS.iter().find(|op| self.src[self.pos..].starts_with(**op)) {
+                self.pos += op.len();
+                Token::Op(op)
+            } else {
+                return self.error(format!("unexpected character `{c}`"));
+            };
+            tokens.push((start, token));
+        }
+        Ok(tokens)
+    }
+}
+
+/// Parse `30s`, `5m`, `1h` or `7d` into seconds, `None` past [`MAX_RANGE`].
+pub fn parse_range(range: &str) -> Option<i64> {
+    let unit = match range.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let value: i64 = range[..range.len() - 1].parse().ok()?;
+    value
+        .checked_mul(unit)
+        .filter(|secs| (1..=MAX_RANGE).contains(secs))
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    idx: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.idx).map(|(_, t)| t)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.idx).map(|(_, t)| t.clone());
+        self.idx += 1;
+        token
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            message: message.into(),
+            position: self
+                .tokens
+                .get(self.idx)
+                .map(|(pos, _)| *pos)
+                .unwrap_or(self.end),
+        })
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        if self.peek() == Some(&expected) {
+            self.idx += 1;
+            Ok(())
+        } else {
+            self.error(format!("expected {expected:?}"))
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        let lhs = self.parse_additive()?;
+        let op = match self.peek() {
+            Some(Token::Op(">")) => CmpOp::Gt,
+            Some(Token::Op(">=")) => CmpOp::Ge,
+            Some(Token::Op("<")) => CmpOp::Lt,
+            Some(Token::Op("<=")) => CmpOp::Le,
+            Some(Token::Op("==")) => CmpOp::Eq,
+            Some(Token::Op("!=")) => CmpOp::Ne,
+            _ => return Ok(lhs),
+        };
+        self.idx += 1;
+        let rhs = self.parse_additive()?;
+        Ok(Expr::Compare(op, Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("+")) => BinOp::Add,
+                Some(Token::Op("-")) => BinOp::Sub,
+                _ => return Ok(lhs),
+            };
+            self.idx += 1;
+            let rhs = self.parse_multiplicative()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("*")) => BinOp::Mul,
+                Some(Token::Op("/")) => BinOp::Div,
+                _ => return Ok(lhs),
+            };
+            self.idx += 1;
+            let rhs = self.parse_unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        if self.peek() == Some(&Token::Op("-")) {
+            self.idx += 1;
+            let operand = self.parse_unary()?;
+            return Ok(Expr::Binary(
+                BinOp::Sub,
+                Box::new(Expr::Number(0.0)),
+                Box::new(operand),
+            ));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return self.error("unexpected end of expression"),
+        };
+        match token {
+            Token::Number(number) => {
+                self.idx += 1;
+                Ok(Expr::Number(number))
+            }
+            Token::LParen => {
+                self.idx += 1;
+                let expr = self.parse_expr()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Token::Ident(name)
+                if self.tokens.get(self.idx + 1).map(|(_, t)| t) == Some(&Token::LParen) =>
+            {
+                let Some(func) = RangeFunc::from_name(&name) else {
+                    return self.error(format!("unknown function `{name}`"));
+                };
+                self.idx += 2;
+                let metric = match self.next() {
+                    Some(Token::Ident(metric)) => metric,
+                    _ => {
+                        self.idx -= 1;
+                        return self.error("expected metric name");
+                    }
+                };
+                let Some(metric) = Metric::from_name(&metric) else {
+                    self.idx -= 1;
+                    return self.error(format!("unknown metric `{metric}`"));
+                };
+                let range = match self.next() {
+                    Some(Token::Range(range)) => range,
+                    _ => {
+                        self.idx -= 1;
+                        return self.error("expected range like `[5m]`");
+                    }
+                };
+                self.expect(Token::RParen)?;
+                Ok(Expr::Range(func, metric, range))
+            }
+            Token::Ident(name) => match Metric::from_name(&name) {
+                Some(metric) => {
+                    self.idx += 1;
+                    Ok(Expr::Metric(metric))
+                }
+                None => self.error(format!("unknown metric `{name}`")),
+            },
+            token => self.error(format!("unexpected {token:?}")),
+        }
+    }
+}
+
+pub fn parse(src: &str) -> Result<Expr, ParseError> {
+    let tokens = Lexer { src, pos: 0 }.tokenize()?;
+    let mut parser = Parser {
+        tokens,
+        idx: 0,
+        end: src.len(),
+    };
+    let expr = parser.parse_expr()?;
+    if parser.peek().is_some() {
+        return parser.error("unexpected trailing input");
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            parse("1 + 2 * 3").unwrap(),
+            Expr::Binary(
+                BinOp::Add,
+                Box::new(Expr::Number(1.0)),
+                Box::new(Expr::Binary(
+                    BinOp::Mul,
+                    Box::new(Expr::Number(2.0)),
+                    Box::new(Expr::Number(3.0))
+                ))
+            )
+        );
+        assert_eq!(
+            parse("(1 + 2) * 3 > memory").unwrap(),
+            Expr::Compare(
+                CmpOp::Gt,
+                Box::new(Expr::Binary(
+                    BinOp::Mul,
+                    Box::new(Expr::Binary(
+                        BinOp::Add,
+                        Box::new(Expr::Number(1.0)),
+                        Box::new(Expr::Number(2.0))
+                    )),
+                    Box::new(Expr::Number(3.0))
+                )),
+                Box::new(Expr::Metric(Metric::Memory))
+            )
+        );
+    }
+
+    #[test]
+    fn range_functions() {
+        assert_eq!(
+            parse("avg_over_time(cpu[5m]) > 0.9").unwrap(),
+            Expr::Compare(
+                CmpOp::Gt,
+                Box::new(Expr::Range(RangeFunc::Avg, Metric::Cpu, 300)),
+                Box::new(Expr::Number(0.9))
+            )
+        );
+        assert_eq!(
+            parse("rate(rx_bytes[1h])").unwrap(),
+            Expr::Range(RangeFunc::Rate, Metric::RxBytes, 3600)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            parse("avg_over_time(cpu)").unwrap_err().to_string(),
+            "expected range like `[5m]` at position 17"
+        );
+        assert_eq!(
+            parse("cpu >").unwrap_err().to_string(),
+            "unexpected end of expression at position 5"
+        );
+        assert_eq!(
+            parse("disk > 1").unwrap_err().to_string(),
+            "unknown metric `disk` at position 0"
+        );
+        assert_eq!(
+            parse("cpu[5x]").unwrap_err().to_string(),
+            "invalid range `5x` at position 7"
+        );
+        assert_eq!(
+            parse("avg_over_time(cpu[999999999999999d])")
+                .unwrap_err()
+                .to_string(),
+            "invalid range `999999999999999d` at position 35"
+        );
+        assert!(parse("cpu[366d]").is_err());
+        assert!(parse("avg_over_time(cpu[365d])").is_ok());
+        assert!(parse("cpu > 1 > 2").is_err());
+        assert!(parse("median(cpu[5m])").is_err());
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+
+use sqlx::SqliteConnection;
+
+use super::Metric;
+
+/// A sample flattened into the values expressions can select.
+#[derive(Debug, Clone, Default)]
+pub struct Sample {
+    /// Row of the sample in `session_data`
+    pub id: i64,
+    /// Client time in unix milliseconds
+    pub sample_time: i64,
+    /// Server time in unix milliseconds the sample arrived at
+    pub received_at: Option<i64>,
+    /// Average usage over all cores in percent
+    pub cpu: Option<f64>,
+    /// Usage of the busiest core in percent
+    pub cpu_max_core: Option<f64>,
+    pub memory_used: Option<i64>,
+    pub memory_total: Option<i64>,
+    pub swap_used: Option<i64>,
+    pub swap_total: Option<i64>,
+    /// A counter of the bytes of all interfaces, increasing by what the
+    /// counters of the interfaces did since the previous sample, see
+    /// `sum_interface_counters`
+    pub rx_bytes: Option<i64>,
+    pub tx_bytes: Option<i64>,
+    /// Degrees Celsius
+    pub cpu_temperature: Option<f64>,
+    /// MHz
+    pub cpu_frequency: Option<i64>,
+    /// Microseconds the probe took to collect the sample
+    pub probe_collection_time: Option<i64>,
+    /// CPU usage of the probe in percent of one core
+    pub probe_cpu: Option<f64>,
+    /// Resident memory of the probe in bytes
+    pub probe_rss: Option<i64>,
+    pub processes: Option<i64>,
+    pub threads: Option<i64>,
+    pub zombies: Option<i64>,
+    pub fds_open: Option<i64>,
+    pub fds_max: Option<i64>,
+    pub tcp_established: Option<i64>,
+    pub tcp_time_wait: Option<i64>,
+    pub clock_synced: Option<bool>,
+    /// Seconds the clock is ahead of its time source, negative if behind
+    pub clock_offset: Option<f64>,
+    /// Battery charge in percent
+    pub battery_capacity: Option<f64>,
+    /// `BatteryState` as written by `BatteryState::as_str`
+    pub battery_state: Option<String>,
+    /// Watts
+    pub battery_power: Option<f64>,
+    /// Watched systemd units in the failed state
+    pub services_failed: Option<i64>,
+    /// Watched systemd units neither active nor reloading
+    pub services_down: Option<i64>,
+    /// Automatic restarts of all watched services
+    pub service_restarts: Option<i64>,
+}
+
+impl Sample {
+    pub fn get(&self, metric: Metric) -> Option<f64> {
+        let ratio = |used: Option<i64>, total: Option<i64>| match (used, total) {
+            (Some(used), Some(total)) if total > 0 => Some(used as f64 / total as f64),
+            _ => None,
+        };
+        match metric {
+            Metric::Cpu => self.cpu.map(|cpu| cpu / 100.0),
+            Metric::CpuMaxCore => self.cpu_max_core.map(|cpu| cpu / 100.0),
+            Metric::Memory => ratio(self.memory_used, self.memory_total),
+            Metric::Swap => ratio(self.swap_used, self.swap_total),
+            Metric::MemoryUsed => self.memory_used.map(|v| v as f64),
+            Metric::MemoryTotal => self.memory_total.map(|v| v as f64),
+            Metric::SwapUsed => self.swap_used.map(|v| v as f64),
+            Metric::SwapTotal => self.swap_total.map(|v| v as f64),
+            Metric::RxBytes => self.rx_bytes.map(|v| v as f64),
+            Metric::TxBytes => self.tx_bytes.map(|v| v as f64),
+            Metric::CpuTemperature => self.cpu_temperature,
+            Metric::CpuFrequency => self.cpu_frequency.map(|v| v as f64),
+            Metric::ProbeCollectionTime => self.probe_collection_time.map(|us| us as f64 / 1e6),
+            Metric::ProbeCpu => self.probe_cpu.map(|cpu| cpu / 100.0),
+            Metric::ProbeRss => self.probe_rss.map(|v| v as f64),
+            Metric::Processes => self.processes.map(|v| v as f64),
+            Metric::Threads => self.threads.map(|v| v as f64),
+            Metric::Zombies => self.zombies.map(|v| v as f64),
+            Metric::Fds => ratio(self.fds_open, self.fds_max),
+            Metric::FdsOpen => self.fds_open.map(|v| v as f64),
+            Metric::FdsMax => self.fds_max.map(|v| v as f64),
+            Metric::TcpEstablished => self.tcp_established.map(|v| v as f64),
+            Metric::TcpTimeWait => self.tcp_time_wait.map(|v| v as f64),
+            Metric::ClockSynced => self
+                .clock_synced
+                .map(|synced| if synced { 1.0 } else { 0.0 }),
+            Metric::ClockOffset => self.clock_offset.map(f64::abs),
+            Metric::Battery => self.battery_capacity.map(|capacity| capacity / 100.0),
+            Metric::BatteryPower => self.battery_power,
+            Metric::BatteryDischarging => self
+                .battery_state
+                .as_deref()
+                .map(|state| if state == "discharging" { 1.0 } else { 0.0 }),
+            Metric::ServicesFailed => self.services_failed.map(|v| v as f64),
+            Metric::ServicesDown => self.services_down.map(|v| v as f64),
+            Metric::ServiceRestarts => self.service_restarts.map(|v| v as f64),
+            Metric::IngestDelay => self
+                .received_at
+                .map(|received_at| (received_at - self.sample_time) as f64 / 1000.0),
+        }
+    }
+}
+
+/// Samples of a client taken in the seconds `from < t <= to`, sorted by time.
+pub async fn fetch_samples(
+    conn: &mut SqliteConnection,
+    client_id: i64,
+    from: i64,
+    to: i64,
+) -> sqlx::Result<Vec<Sample>> {
+    let mut samples = sqlx::query_as!(
+        Sample,
+        r#"
+        SELECT d.id, d.sample_time, d.received_at,
+            COALESCE(
+                a.cpu_usage,
+                (SELECT AVG(cpu_usage) FROM session_data_cpu WHERE session_data_id = d.id)
+            ) AS "cpu: f64",
+            COALESCE(
+                a.max_core_usage,
+                (SELECT MAX(cpu_usage) FROM session_data_cpu WHERE session_data_id = d.id)
+            ) AS "cpu_max_core: f64",
+            m.used AS "memory_used?", m.total AS "memory_total?",
+            m.swap_used AS "swap_used?", m.swap_total AS "swap_total?",
+            NULL AS "rx_bytes: i64", NULL AS "tx_bytes: i64",
+            t.cpu_temperature, t.cpu_frequency,
+            p.collection_time AS "probe_collection_time?",
+            p.cpu_usage AS probe_cpu, p.rss AS probe_rss,
+            y.processes AS "processes?", y.threads, y.zombies AS "zombies?",
+            f.open AS "fds_open?", f.max AS "fds_max?", f.tcp_established, f.tcp_time_wait,
+            k.synchronized AS "clock_synced?: bool", k.offset AS clock_offset,
+            b.capacity AS "battery_capacity?", bs.value AS "battery_state?",
+            b.power AS battery_power,
+            (
+                SELECT SUM(v.state = 'failed') FROM session_data_service_named v
+                WHERE v.session_data_id = d.id
+            ) AS "services_failed: i64",
+            (
+                SELECT SUM(v.state NOT IN ('active', 'reloading')) FROM session_data_service_named v
+                WHERE v.session_data_id = d.id
+            ) AS "services_down: i64",
+            (
+                SELECT SUM(v.restarts) FROM session_data_service v
+                WHERE v.session_data_id = d.id
+            ) AS "service_restarts: i64"
+        FROM session_data d
+        JOIN sessions s ON s.id = d.session_id
+        LEFT JOIN session_data_cpu_aggregate a ON a.session_data_id = d.id
+        LEFT JOIN session_data_memory m ON m.session_data_id = d.id
+        LEFT JOIN session_data_sensors t ON t.session_data_id = d.id
+        LEFT JOIN session_data_probe p ON p.session_data_id = d.id
+        LEFT JOIN session_data_system y ON y.session_data_id = d.id
+        LEFT JOIN session_data_fds f ON f.session_data_id = d.id
+        LEFT JOIN session_data_clock k ON k.session_data_id = d.id
+        LEFT JOIN session_data_battery b ON b.session_data_id = d.id
+        LEFT JOIN strings bs ON bs.id = b.state_id
+        WHERE s.client_id = ? AND d.sample_time > ? * 1000 AND d.sample_time <= ? * 1000
+        ORDER BY d.sample_time
+        "#,
+        client_id,
+        from,
+        to
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let counters = sqlx::query_as!(
+        InterfaceCounters,
+        r#"
+        SELECT n.session_data_id, n.ifname_id, n.rx_bytes, n.tx_bytes, n.delta
+        FROM session_data_network n
+        JOIN session_data d ON d.id = n.session_data_id
+        JOIN sessions s ON s.id = d.session_id
+        WHERE s.client_id = ? AND d.sample_time > ? * 1000 AND d.sample_time <= ? * 1000
+        "#,
+        client_id,
+        from,
+        to
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    sum_interface_counters(&mut samples, &counters);
+    Ok(samples)
+}
+
+/// The counters of one interface in one sample.
+#[derive(Debug, Clone, Copy)]
+struct InterfaceCounters {
+    session_data_id: i64,
+    ifname_id: i64,
+    rx_bytes: Option<i64>,
+    tx_bytes: Option<i64>,
+    /// The client sent the bytes since its previous sample instead of the
+    /// counters
+    delta: bool,
+}
+
+/// Set the network counters of `samples` to a counter per client, which
+/// increases by what the counters of every interface did since the previous
+/// sample. Summing the counters themselves would make `rate` jump whenever
+/// an interface comes or goes. Counters sent as deltas add up the same way,
+/// a delta sample without counters starts a connection, the bytes since the
+/// previous connection are unknown.
+fn sum_interface_counters(samples: &mut [Sample], counters: &[InterfaceCounters]) {
+    let mut by_sample = HashMap::<i64, Vec<&InterfaceCounters>>::new();
+    for counters in counters {
+        by_sample
+            .entry(counters.session_data_id)
+            .or_default()
+            .push(counters);
+    }
+    sum_counter(samples, &by_sample, |c| c.rx_bytes, |s| &mut s.rx_bytes);
+    sum_counter(samples, &by_sample, |c| c.tx_bytes, |s| &mut s.tx_bytes);
+}
+
+fn sum_counter(
+    samples: &mut [Sample],
+    by_sample: &HashMap<i64, Vec<&InterfaceCounters>>,
+    counter: fn(&InterfaceCounters) -> Option<i64>,
+    value: fn(&mut Sample) -> &mut Option<i64>,
+) {
+    // the last counter of every interface, kept while it is missing
+    let mut last = HashMap::new();
+    let mut total = None::<i64>;
+    for sample in samples {
+        let mut increase = None::<i64>;
+        for interface in by_sample.get(&sample.id).into_iter().flatten() {
+            let Some(bytes) = counter(interface) else {
+                continue;
+            };
+            let previous = last.get(&interface.ifname_id).copied();
+            let (delta, current) = match (interface.delta, previous) {
+                (true, previous) => (bytes, previous.map(|p: i64| p.saturating_add(bytes))),
+                // a decreasing counter has been reset
+                (false, Some(previous)) if bytes >= previous => (bytes - previous, Some(bytes)),
+                (false, Some(_)) => (bytes, Some(bytes)),
+                // the first counter of an interface counts from there on,
+                // unless it is the first of all
+                (false, None) if total.is_none() => (bytes, Some(bytes)),
+                (false, None) => (0, Some(bytes)),
+            };
+            match current {
+                Some(current) => last.insert(interface.ifname_id, current),
+                None => last.remove(&interface.ifname_id),
+            };
+            increase = Some(increase.unwrap_or(0).saturating_add(delta));
+        }
+        if let Some(increase) = increase {
+            total = Some(total.unwrap_or(0).saturating_add(increase));
+            *value(sample) = total;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counters(id: i64, ifname_id: i64, rx_bytes: Option<i64>, delta: bool) -> InterfaceCounters {
+        InterfaceCounters {
+            session_data_id: id,
+            ifname_id,
+            rx_bytes,
+            tx_bytes: None,
+            delta,
+        }
+    }
+
+    fn rx_bytes(counters: &[InterfaceCounters]) -> Vec<Option<i64>> {
+        let mut samples = (0..=counters.iter().map(|c| c.session_data_id).max().unwrap())
+            .map(|id| Sample {
+                id,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        sum_interface_counters(&mut samples, counters);
+        samples.iter().map(|s| s.rx_bytes).collect()
+    }
+
+    #[test]
+    fn network_deltas() {
+        let rx_bytes = rx_bytes(&[
+            counters(0, 1, Some(1000), false),
+            counters(1, 1, Some(1500), false),
+            // a client reconnecting with deltas
+            counters(2, 1, None, true),
+            counters(3, 1, Some(200), true),
+            counters(4, 1, Some(300), true),
+            // and with cumulative counters again
+            counters(5, 1, Some(2100), false),
+        ]);
+        assert_eq!(
+            rx_bytes,
+            [
+                Some(1000),
+                Some(1500),
+                None,
+                Some(1700),
+                Some(2000),
+                Some(2100)
+            ]
+        );
+    }
+
+    #[test]
+    fn interfaces_coming_and_going() {
+        let rx_bytes = rx_bytes(&[
+            counters(0, 1, Some(1000), false),
+            counters(1, 1, Some(1100), false),
+            // a VPN coming up with a large counter adds nothing yet
+            counters(1, 2, Some(50_000), false),
+            counters(2, 1, Some(1200), false),
+            counters(2, 2, Some(50_010), false),
+            // and going away again takes nothing away
+            counters(3, 1, Some(1300), false),
+            // a reset interface counts from zero
+            counters(4, 1, Some(40), false),
+        ]);
+        assert_eq!(
+            rx_bytes,
+            [Some(1000), Some(1100), Some(1210), Some(1310), Some(1350)]
+        );
+    }
+}
@@ -0,0 +1,360 @@
+//! Compression of regular time series in the style of Facebook's Gorilla,
+//! for rollups kept long enough that their size matters.
+//!
+//! A chunk holds rows of a timestamp and a fixed number of values.
+//! Timestamps are stored as the difference between consecutive deltas, a
+//! single bit while rows arrive at a steady interval. Every value is XORed
+//! with the one before it in its column, a single bit while it does not
+//! change and otherwise only the bits between the leading and trailing zeros
+//! of the XOR. Integers up to 2^53 survive as `f64` exactly.
+//!
+//! A chunk starts with the number of columns as a byte and the number of
+//! rows as a little endian `u32`, the bits of the rows follow.
+
+/// Sizes of the delta of delta ranges, as `(control bits, control bit
+/// count, value bits)`, larger ones store the full 64 bits.
+const DOD_RANGES: [(u64, u32, u32); 3] = [(0b10, 2, 7), (0b110, 3, 9), (0b1110, 4, 12)];
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("chunk ends early")]
+    Truncated,
+    #[error("chunk has {0} columns, expected {1}")]
+    Columns(usize, usize),
+}
+
+#[derive(Debug, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used of the last byte, 0 when it is full or there is none
+    used: u32,
+}
+
+impl BitWriter {
+    /// Append the lowest `count` bits of `bits`, highest first.
+    fn write(&mut self, bits: u64, count: u32) {
+        for i in (0..count).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let bit = (bits >> i) as u8 & 1;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// Position in bits
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: u32) -> Result<u64, DecodeError> {
+        let mut bits = 0;
+        for _ in 0..count {
+            let byte = self.bytes.get(self.pos / 8).ok_or(DecodeError::Truncated)?;
+            bits = bits << 1 | u64::from(byte >> (7 - self.pos % 8) & 1);
+            self.pos += 1;
+        }
+        Ok(bits)
+    }
+
+    fn bit(&mut self) -> Result<bool, DecodeError> {
+        Ok(self.read(1)? == 1)
+    }
+}
+
+/// The previous value of a column and the window of meaningful bits of its
+/// last XOR.
+#[derive(Debug, Default, Clone, Copy)]
+struct XorState {
+    prev: u64,
+    leading: u32,
+    trailing: u32,
+}
+
+impl XorState {
+    fn encode(&mut self, out: &mut BitWriter, value: f64) {
+        let value = value.to_bits();
+        let xor = value ^ self.prev;
+        self.prev = value;
+        if xor == 0 {
+            out.write(0, 1);
+            return;
+        }
+        // 5 bits hold the leading zeros
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        // the previous window is 0 wide before the first XOR
+        let window = 64 - self.leading - self.trailing;
+        if window < 64 && leading >= self.leading && trailing >= self.trailing {
+            out.write(0b10, 2);
+            out.write(xor >> self.trailing, window);
+        } else {
+            let meaningful = 64 - leading - trailing;
+            out.write(0b11, 2);
+            out.write(u64::from(leading), 5);
+            // 64 meaningful bits are written as 0
+            out.write(u64::from(meaningful % 64), 6);
+            out.write(xor >> trailing, meaningful);
+            self.leading = leading;
+            self.trailing = trailing;
+        }
+    }
+
+    fn decode(&mut self, input: &mut BitReader) -> Result<f64, DecodeError> {
+        if input.bit()? {
+            let xor = if input.bit()? {
+                let leading = input.read(5)? as u32;
+                let meaningful = match input.read(6)? as u32 {
+                    0 => 64,
+                    meaningful => meaningful,
+                };
+                let trailing = 64u32.saturating_sub(leading + meaningful);
+                self.leading = leading;
+                self.trailing = trailing;
+                input.read(meaningful)? << trailing
+            } else {
+                input.read(64 - self.leading - self.trailing)? << self.trailing
+            };
+            self.prev ^= xor;
+        }
+        Ok(f64::from_bits(self.prev))
+    }
+}
+
+/// Builds a chunk row by row.
+#[derive(Debug)]
+pub struct ChunkEncoder {
+    out: BitWriter,
+    rows: u32,
+    time: i64,
+    delta: i64,
+    columns: Vec<XorState>,
+}
+
+impl ChunkEncoder {
+    pub fn new(columns: u8) -> Self {
+        ChunkEncoder {
+            out: BitWriter::default(),
+            rows: 0,
+            time: 0,
+            delta: 0,
+            columns: vec![XorState::default(); columns.into()],
+        }
+    }
+
+    /// Append a row, `values` has one value per column.
+    pub fn push(&mut self, time: i64, values: &[f64]) {
+        assert_eq!(values.len(), self.columns.len(), "values of every column");
+        if self.rows == 0 {
+            self.out.write(time as u64, 64);
+        } else {
+            let delta = time.wrapping_sub(self.time);
+            self.encode_dod(delta.wrapping_sub(self.delta));
+            self.delta = delta;
+        }
+        self.time = time;
+        for (column, &value) in self.columns.iter_mut().zip(values) {
+            column.encode(&mut self.out, value);
+        }
+        self.rows += 1;
+    }
+
+    fn encode_dod(&mut self, dod: i64) {
+        if dod == 0 {
+            self.out.write(0, 1);
+            return;
+        }
+        for (control, control_bits, bits) in DOD_RANGES {
+            let half = 1i64 << (bits - 1);
+            if (-half..half).contains(&dod) {
+                self.out.write(control, control_bits);
+                // two's complement cut to `bits`, decoded by sign extension
+                self.out.write(dod as u64, bits);
+                return;
+            }
+        }
+        self.out.write(0b1111, 4);
+        self.out.write(dod as u64, 64);
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut chunk = Vec::with_capacity(5 + self.out.bytes.len());
+        chunk.push(self.columns.len() as u8);
+        chunk.extend_from_slice(&self.rows.to_le_bytes());
+        chunk.extend_from_slice(&self.out.bytes);
+        chunk
+    }
+}
+
+/// The rows of a chunk of `columns` columns, as `(time, values)`.
+pub fn decode(chunk: &[u8], columns: usize) -> Result<Vec<(i64, Vec<f64>)>, DecodeError> {
+    let [chunk_columns, r0, r1, r2, r3, bytes @ ..] = chunk else {
+        return Err(DecodeError::Truncated);
+    };
+    if usize::from(*chunk_columns) != columns {
+        return Err(DecodeError::Columns((*chunk_columns).into(), columns));
+    }
+    let count = u32::from_le_bytes([*r0, *r1, *r2, *r3]);
+
+    let mut input = BitReader { bytes, pos: 0 };
+    let mut states = vec![XorState::default(); columns];
+    // not trusted for the capacity, a damaged chunk could claim any count
+    let mut rows = Vec::new();
+    let (mut time, mut delta) = (0i64, 0i64);
+    for row in 0..count {
+        if row == 0 {
+            time = input.read(64)? as i64;
+        } else {
+            delta = delta.wrapping_add(decode_dod(&mut input)?);
+            time = time.wrapping_add(delta);
+        }
+        let values = states
+            .iter_mut()
+            .map(|state| state.decode(&mut input))
+            .collect::<Result<_, _>>()?;
+        rows.push((time, values));
+    }
+    Ok(rows)
+}
+
+fn decode_dod(input: &mut BitReader) -> Result<i64, DecodeError> {
+    if !input.bit()? {
+        return Ok(0);
+    }
+    // the control bits are ones up to the first zero
+    for (_, _, bits) in DOD_RANGES {
+        if !input.bit()? {
+            return read_signed(input, bits);
+        }
+    }
+    Ok(input.read(64)? as i64)
+}
+
+/// A value written as its lowest `bits` bits.
+fn read_signed(input: &mut BitReader, bits: u32) -> Result<i64, DecodeError> {
+    let value = input.read(bits)?;
+    let shift = 64 - bits;
+    Ok(((value << shift) as i64) >> shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(rows: &[(i64, Vec<f64>)]) -> Vec<u8> {
+        let columns = rows[0].1.len();
+        let mut encoder = ChunkEncoder::new(columns as u8);
+        for (time, values) in rows {
+            encoder.push(*time, values);
+        }
+        let chunk = encoder.finish();
+        let decoded = decode(&chunk, columns).unwrap();
+        assert_eq!(decoded.len(), rows.len());
+        for ((time, values), (decoded_time, decoded_values)) in rows.iter().zip(&decoded) {
+            assert_eq!(time, decoded_time);
+            let bits = |values: &[f64]| values.iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+            assert_eq!(bits(values), bits(decoded_values));
+        }
+        chunk
+    }
+
+    #[test]
+    fn irregular_series() {
+        let times = [
+            1_700_000_000,
+            1_700_000_060,
+            1_700_000_120,
+            1_700_000_181,
+            1_700_000_300,
+            1_700_000_299,
+            1_700_001_000,
+            1_700_090_000,
+            -5,
+            i64::MAX,
+            i64::MIN,
+            0,
+        ];
+        let values = [
+            0.0,
+            1.0,
+            1.0,
+            -1.5,
+            f64::NAN,
+            f64::INFINITY,
+            f64::MIN_POSITIVE,
+            1e300,
+            -0.0,
+            3.25,
+            u32::MAX as f64,
+            42.0,
+        ];
+        let rows = times
+            .iter()
+            .zip(values)
+            .map(|(&time, value)| (time, vec![value, value * 2.0, (time as f64).sqrt()]))
+            .collect::<Vec<_>>();
+        round_trip(&rows);
+        round_trip(&rows[..1]);
+
+        // every range of the delta of delta, both signs
+        let mut time = 0;
+        let mut rows = vec![(time, vec![0.0])];
+        for dod in [
+            0,
+            1,
+            -1,
+            63,
+            -64,
+            64,
+            255,
+            -256,
+            256,
+            2047,
+            -2048,
+            2048,
+            -1 << 40,
+        ] {
+            time += rows.len() as i64 * 1000 + dod;
+            rows.push((time, vec![dod as f64]));
+        }
+        round_trip(&rows);
+    }
+
+    #[test]
+    fn stable_series() {
+        let rows = (0..60)
+            .map(|minute| {
+                let wobble = [5.0, 5.0, 10.0][minute % 3];
+                (
+                    1_700_000_000 + minute as i64 * 60,
+                    vec![120.0, wobble, 25.0, 50.0, 250.0, 1.0, 1.0, 2.0, 5.0],
+                )
+            })
+            .collect::<Vec<_>>();
+        let chunk = round_trip(&rows);
+        // 10 integers of 8 bytes a row as rows of a table
+        assert!(chunk.len() * 10 < 60 * 10 * 8, "{} bytes", chunk.len());
+    }
+
+    #[test]
+    fn damaged_chunks() {
+        let mut encoder = ChunkEncoder::new(2);
+        encoder.push(1, &[1.0, 2.0]);
+        encoder.push(2, &[3.0, 4.0]);
+        let chunk = encoder.finish();
+        assert_eq!(decode(&chunk, 3), Err(DecodeError::Columns(2, 3)));
+        assert_eq!(
+            decode(&chunk[..chunk.len() - 2], 2),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(decode(&[], 2), Err(DecodeError::Truncated));
+    }
+}
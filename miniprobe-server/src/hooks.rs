@@ -0,0 +1,268 @@
+//! Webhooks fired on session and host state changes and reboots, to drive
+//! external automation like a status page, and optionally on security events.
+//!
+//! Hooks are fired concurrently, so a slow hook does not hold back the
+//! others, and may receive events out of order. `host_down` is not fired for
+//! silenced clients.
+
+use std::{sync::Arc, time::Duration};
+
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::{
+    Semaphore,
+    broadcast::{self, error::RecvError},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::events::{Event, HostState, SessionState};
+
+/// How long a hook may take to answer before it counts as failed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most hooks fired at once, further events wait and are dropped once the
+/// event channel overflows.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// A URL the matching events are POSTed to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookConf {
+    pub url: String,
+    /// Events firing the hook, all but `security` if omitted
+    #[serde(default = "HookEvent::defaults")]
+    pub events: Vec<HookEvent>,
+    /// Body with every `{{field}}` replaced by that field of the event,
+    /// `{{event}}` by the name of the event. The event as JSON if omitted
+    pub template: Option<String>,
+    /// Content type of a templated body
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+}
+
+fn default_content_type() -> String {
+    "application/json".to_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// A client created a session, usually when it starts
+    SessionCreated,
+    /// A client connected again to a session after its websocket closed
+    SessionResumed,
+    /// The websocket of a session closed, see `close_code` and `close_reason`
+    SessionEnded,
+    /// A client sends samples again
+    HostUp,
+    /// A client stopped sending samples
+    HostDown,
+    /// The host of a client booted again since its previous session
+    HostRebooted,
+    /// A security event, see `SecurityEventKind`, the field `kind` tells
+    /// which
+    Security,
+}
+
+impl HookEvent {
+    /// Every event but `Security`, hooks ask for those explicitly.
+    fn defaults() -> Vec<HookEvent> {
+        vec![
+            HookEvent::SessionCreated,
+            HookEvent::SessionResumed,
+            HookEvent::SessionEnded,
+            HookEvent::HostUp,
+            HookEvent::HostDown,
+            HookEvent::HostRebooted,
+        ]
+    }
+
+    /// The hook event of an event, `None` for alerts, they are delivered to
+    /// the alert channels, and for silenced clients going down. Coming up
+    /// again still fires, so nothing is left thinking the host is down.
+    fn of(event: &Event) -> Option<HookEvent> {
+        match event {
+            Event::Alert { .. } => None,
+            Event::Host {
+                state: HostState::Down,
+                silenced: true,
+                ..
+            } => None,
+            Event::Host { state, .. } => Some(match state {
+                HostState::Up => HookEvent::HostUp,
+                HostState::Down => HookEvent::HostDown,
+            }),
+            Event::Session { state, .. } => Some(match state {
+                SessionState::Created => HookEvent::SessionCreated,
+                SessionState::Resumed => HookEvent::SessionResumed,
+                SessionState::Ended => HookEvent::SessionEnded,
+            }),
+            Event::Reboot { .. } => Some(HookEvent::HostRebooted),
+            Event::Security { .. } => Some(HookEvent::Security),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            HookEvent::SessionCreated => "session_created",
+            HookEvent::SessionResumed => "session_resumed",
+            HookEvent::SessionEnded => "session_ended",
+            HookEvent::HostUp => "host_up",
+            HookEvent::HostDown => "host_down",
+            HookEvent::HostRebooted => "host_rebooted",
+            HookEvent::Security => "security",
+        }
+    }
+}
+
+/// Delivers events to the configured hooks.
+pub struct Hooks {
+    hooks: Vec<HookConf>,
+    events: broadcast::Receiver<Event>,
+    http: reqwest::Client,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<HookConf>, events: broadcast::Receiver<Event>) -> Self {
+        Hooks {
+            hooks,
+            events,
+            http: reqwest::Client::new(),
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+
+    /// Deliver events until cancelled, events during shutdown may be missed.
+    pub async fn run(mut self, cancellation_token: CancellationToken) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        loop {
+            tokio::select! {
+                event = self.events.recv() => match event {
+                    Ok(event) => self.deliver(&event).await,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "hooks lagging behind, events dropped");
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = cancellation_token.cancelled() => return,
+            }
+        }
+    }
+
+    async fn deliver(&self, event: &Event) {
+        let Some(hook_event) = HookEvent::of(event) else {
+            return;
+        };
+        let mut fields = serde_json::to_value(event).expect("events are always serializable");
+        fields["event"] = hook_event.as_str().into();
+
+        for hook in self.hooks.iter().filter(|h| h.events.contains(&hook_event)) {
+            let req = self.http.post(&hook.url).timeout(HOOK_TIMEOUT);
+            let req = match &hook.template {
+                Some(template) => req
+                    .header(reqwest::header::CONTENT_TYPE, &hook.content_type)
+                    .body(render(template, &fields)),
+                None => req.json(&fields),
+            };
+            let permit = Arc::clone(&self.in_flight)
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let url = hook.url.clone();
+            tokio::spawn(async move {
+                let res = req.send().await.and_then(|resp| resp.error_for_status());
+                match res {
+                    Ok(_) => debug!(url, event = hook_event.as_str(), "hook fired"),
+                    Err(e) => warn!(url, "failed to fire hook: {e}"),
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// Replace every `{{field}}` of `template` by that field of `fields`. Strings
+/// are JSON escaped without quotes so they can be placed inside a JSON
+/// string, missing fields and `null` are left empty.
+fn render(template: &str, fields: &Value) -> String {
+    let mut body = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        body.push_str(&rest[..start]);
+        match &fields[rest[start + 2..start + len].trim()] {
+            Value::Null => {}
+            Value::String(s) => {
+                let quoted = Value::String(s.clone()).to_string();
+                body.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            value => body.push_str(&value.to_string()),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    body.push_str(rest);
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn render_template() {
+        let fields = json!({
+            "event": "host_down",
+            "client_name": "web \"1\"",
+            "last_sample": 1700000000,
+            "silenced": false,
+            "close_reason": null,
+        });
+        assert_eq!(
+            render(
+                r#"{"text": "{{client_name}} is down since {{ last_sample }}", "silenced": {{silenced}}}"#,
+                &fields
+            ),
+            r#"{"text": "web \"1\" is down since 1700000000", "silenced": false}"#
+        );
+        assert_eq!(render("{{close_reason}}{{missing}}!", &fields), "!");
+        assert_eq!(
+            render("{{event}} {{unclosed", &fields),
+            "host_down {{unclosed"
+        );
+    }
+
+    #[test]
+    fn hook_events() {
+        let mut event = Event::Host {
+            state: HostState::Down,
+            client_id: 1,
+            client_name: "web".to_owned(),
+            last_sample: None,
+            time: 0,
+            silenced: false,
+        };
+        assert_eq!(HookEvent::of(&event), Some(HookEvent::HostDown));
+        let Event::Host { silenced, .. } = &mut event else {
+            unreachable!()
+        };
+        *silenced = true;
+        assert_eq!(HookEvent::of(&event), None);
+
+        let hook: HookConf = toml::from_str(
+            r#"
+            url = "http://localhost/hook"
+            events = ["session_ended", "host_down"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(hook.events, [HookEvent::SessionEnded, HookEvent::HostDown]);
+        assert_eq!(hook.content_type, "application/json");
+    }
+}
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+
+use sqlx::SqliteExecutor;
+
+/// Maps strings to ids of the `strings` dictionary table, caching known ids.
+///
+/// Strings are interned outside of sample transactions, so a cached id can not
+/// refer to a row that was rolled back.
+#[derive(Debug, Default)]
+pub struct Interner {
+    cache: HashMap<String, i64>,
+}
+
+impl Interner {
+    pub async fn intern<'e, E: SqliteExecutor<'e>>(
+        &mut self,
+        executor: E,
+        value: &str,
+    ) -> sqlx::Result<i64> {
+        if let Some(id) = self.cache.get(value) {
+            return Ok(*id);
+        }
+
+        // the no-op update makes `RETURNING` yield the existing row
+        let id = sqlx::query_scalar!(
+            "INSERT INTO strings (value) VALUES (?) \
+                ON CONFLICT (value) DO UPDATE SET value = excluded.value \
+                RETURNING id",
+            value
+        )
+        .fetch_one(executor)
+        .await?;
+
+        self.cache.insert(value.to_owned(), id);
+        Ok(id)
+    }
+}
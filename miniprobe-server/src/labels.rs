@@ -0,0 +1,171 @@
+//! Labels of clients, e.g. `env=prod`, and selectors matching them to query a
+//! group of clients at once.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+};
+
+use serde::Deserialize;
+use sqlx::SqliteExecutor;
+
+pub type Labels = BTreeMap<String, String>;
+
+/// Labels of every client with labels, by client id.
+pub async fn load<'e, E: SqliteExecutor<'e>>(executor: E) -> sqlx::Result<HashMap<i64, Labels>> {
+    let rows = sqlx::query!("SELECT client_id, key, value FROM client_labels")
+        .fetch_all(executor)
+        .await?;
+    let mut labels = HashMap::<i64, Labels>::new();
+    for r in rows {
+        labels
+            .entry(r.client_id)
+            .or_default()
+            .insert(r.key, r.value);
+    }
+    Ok(labels)
+}
+
+/// Parse `key=value` as given to `admin client label`, an empty value removes
+/// the label.
+pub fn parse_label(s: &str) -> Result<(String, Option<String>), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid label '{s}', expected key=value"))?;
+    check_key(key)?;
+    if value.contains(',') {
+        return Err(format!("label values may not contain ',', in '{s}'"));
+    }
+    Ok((
+        key.to_owned(),
+        (!value.is_empty()).then(|| value.to_owned()),
+    ))
+}
+
+fn check_key(key: &str) -> Result<(), String> {
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "invalid label key '{key}', expected letters, digits, '_', '-' or '.'"
+        )),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Matcher {
+    key: String,
+    value: String,
+    negated: bool,
+}
+
+/// Comma separated `key=value` and `key!=value` matchers, all of which must
+/// hold, e.g. `env=prod,role!=db`. A client without the label does not match
+/// `key=value` but matches `key!=value`. The empty selector matches every
+/// client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct LabelSelector(Vec<Matcher>);
+
+impl LabelSelector {
+    pub fn matches(&self, labels: Option<&Labels>) -> bool {
+        self.0.iter().all(|m| {
+            let value = labels.and_then(|labels| labels.get(&m.key));
+            (value == Some(&m.value)) != m.negated
+        })
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut matchers = Vec::new();
+        for matcher in s.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            let (key, value, negated) = match matcher.split_once("!=") {
+                Some((key, value)) => (key, value, true),
+                None => match matcher.split_once('=') {
+                    Some((key, value)) => (key, value, false),
+                    None => {
+                        return Err(format!(
+                            "invalid matcher '{matcher}', expected key=value or key!=value"
+                        ));
+                    }
+                },
+            };
+            let key = key.trim();
+            check_key(key)?;
+            matchers.push(Matcher {
+                key: key.to_owned(),
+                value: value.trim().to_owned(),
+                negated,
+            });
+        }
+        Ok(LabelSelector(matchers))
+    }
+}
+
+impl TryFrom<String> for LabelSelector {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for LabelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, m) in self.0.iter().enumerate() {
+            let op = if m.negated { "!=" } else { "=" };
+            let sep = if i > 0 { "," } else { "" };
+            write!(f, "{sep}{}{op}{}", m.key, m.value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> Labels {
+        labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn selectors() {
+        let selector = "env=prod, role!=db".parse::<LabelSelector>().unwrap();
+        assert_eq!(selector.to_string(), "env=prod,role!=db");
+        assert!(selector.matches(Some(&labels(&[("env", "prod"), ("role", "web")]))));
+        assert!(selector.matches(Some(&labels(&[("env", "prod")]))));
+        assert!(!selector.matches(Some(&labels(&[("env", "prod"), ("role", "db")]))));
+        assert!(!selector.matches(Some(&labels(&[("env", "dev")]))));
+        assert!(!selector.matches(None));
+
+        let any = "".parse::<LabelSelector>().unwrap();
+        assert!(any.matches(None));
+
+        assert!("env".parse::<LabelSelector>().is_err());
+        assert!("1env=prod".parse::<LabelSelector>().is_err());
+    }
+
+    #[test]
+    fn labels_to_set() {
+        assert_eq!(
+            parse_label("env=prod").unwrap(),
+            ("env".to_owned(), Some("prod".to_owned()))
+        );
+        assert_eq!(parse_label("env=").unwrap(), ("env".to_owned(), None));
+        assert!(parse_label("env").is_err());
+        assert!(parse_label("env=a,b").is_err());
+        assert!(parse_label("=prod").is_err());
+    }
+}
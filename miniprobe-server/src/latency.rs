@@ -0,0 +1,539 @@
+//! End-to-end latency of ingested samples, to tell slow or skewed probes from
+//! a slow database.
+//!
+//! Every sample records its transit, from its sample time to its arrival, and
+//! its store latency, from its arrival until the sink wrote it. They are
+//! counted in fixed buckets and rolled up into percentiles once a minute,
+//! one row of `ingest_latency` per minute whatever the number of clients.
+//! Percentiles are the upper bounds of their buckets.
+//!
+//! With `compress_rollups` the rows of every finished hour are compressed
+//! into one row of `ingest_latency_chunks`, see `gorilla`.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use miniprobe_proto::UnixMillis;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::gorilla::{self, ChunkEncoder};
+
+/// Upper bounds of the buckets in milliseconds, slower samples go to a last
+/// bucket bounded by the slowest sample.
+const BUCKETS: [i64; 16] = [
+    1, 2, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000, 300_000,
+];
+/// Window of a rollup.
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(60);
+/// Rollups older than this are deleted.
+const RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+/// Span of the rollups compressed into a chunk.
+const CHUNK_SECS: i64 = 60 * 60;
+/// Values of a chunk row, see `LatencyRollup::values`.
+const CHUNK_COLUMNS: u8 = 9;
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len() + 1],
+    max: i64,
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let ms = latency.as_millis().min(i64::MAX as u128) as i64;
+        let bucket = BUCKETS.partition_point(|&bound| bound < ms);
+        self.counts[bucket] += 1;
+        self.max = self.max.max(ms);
+    }
+
+    fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Upper bound of the bucket of the `q` quantile, at most the maximum.
+    fn quantile(&self, q: f64) -> i64 {
+        let rank = (q * self.count() as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKETS
+                    .get(bucket)
+                    .map_or(self.max, |&bound| bound.min(self.max));
+            }
+        }
+        self.max
+    }
+
+    fn percentiles(&self) -> Percentiles {
+        Percentiles {
+            p50: self.quantile(0.5),
+            p95: self.quantile(0.95),
+            p99: self.quantile(0.99),
+            max: self.max,
+        }
+    }
+}
+
+/// Latency percentiles in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Percentiles {
+    pub p50: i64,
+    pub p95: i64,
+    pub p99: i64,
+    pub max: i64,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    transit: Histogram,
+    store: Histogram,
+}
+
+/// Latencies of the samples of the current minute, shared by the ingress
+/// websockets.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyRecorder(Arc<Mutex<Window>>);
+
+impl LatencyRecorder {
+    /// Record a sample taken at `sample_time` that arrived at `received_at`
+    /// and was written after `store`. Samples from clocks ahead of the server
+    /// count as no transit.
+    pub fn record(&self, sample_time: UnixMillis, received_at: UnixMillis, store: Duration) {
+        let mut window = self.0.lock().unwrap();
+        window
+            .transit
+            .record(received_at.saturating_duration_since(sample_time));
+        window.store.record(store);
+    }
+
+    fn take(&self) -> Window {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Latency of the samples ingested in a minute, a row of `ingest_latency`.
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyRollup {
+    /// End of the minute as unix timestamp in seconds
+    pub time: i64,
+    pub samples: i64,
+    /// From the sample time of the client to the arrival at the server,
+    /// includes the offset of the client clock
+    pub transit: Percentiles,
+    /// From the arrival until the sink wrote the sample
+    pub store: Percentiles,
+}
+
+impl LatencyRollup {
+    /// `samples` and the percentiles, the columns of a chunk.
+    fn values(&self) -> [f64; CHUNK_COLUMNS as usize] {
+        let Self {
+            samples,
+            transit,
+            store,
+            ..
+        } = self;
+        [
+            *samples,
+            transit.p50,
+            transit.p95,
+            transit.p99,
+            transit.max,
+            store.p50,
+            store.p95,
+            store.p99,
+            store.max,
+        ]
+        .map(|value| value as f64)
+    }
+
+    fn from_values(time: i64, values: &[f64]) -> Self {
+        let value = |i: usize| values.get(i).map_or(0, |&value| value as i64);
+        LatencyRollup {
+            time,
+            samples: value(0),
+            transit: Percentiles {
+                p50: value(1),
+                p95: value(2),
+                p99: value(3),
+                max: value(4),
+            },
+            store: Percentiles {
+                p50: value(5),
+                p95: value(6),
+                p99: value(7),
+                max: value(8),
+            },
+        }
+    }
+}
+
+/// A row of `ingest_latency`.
+struct LatencyRow {
+    time: i64,
+    samples: i64,
+    transit_p50: i64,
+    transit_p95: i64,
+    transit_p99: i64,
+    transit_max: i64,
+    store_p50: i64,
+    store_p95: i64,
+    store_p99: i64,
+    store_max: i64,
+}
+
+impl From<LatencyRow> for LatencyRollup {
+    fn from(r: LatencyRow) -> Self {
+        LatencyRollup {
+            time: r.time,
+            samples: r.samples,
+            transit: Percentiles {
+                p50: r.transit_p50,
+                p95: r.transit_p95,
+                p99: r.transit_p99,
+                max: r.transit_max,
+            },
+            store: Percentiles {
+                p50: r.store_p50,
+                p95: r.store_p95,
+                p99: r.store_p99,
+                max: r.store_max,
+            },
+        }
+    }
+}
+
+/// Stores the rollup of the recorded latencies once a minute.
+pub struct LatencyRollups {
+    pool: SqlitePool,
+    recorder: LatencyRecorder,
+    compress: bool,
+}
+
+impl LatencyRollups {
+    pub fn new(pool: SqlitePool, recorder: LatencyRecorder) -> Self {
+        LatencyRollups {
+            pool,
+            recorder,
+            compress: false,
+        }
+    }
+
+    /// Compress the rollups of every finished hour into a chunk.
+    pub fn with_compression(self, compress: bool) -> Self {
+        LatencyRollups { compress, ..self }
+    }
+
+    /// Roll up every minute until cancelled, then once more for the samples
+    /// of the last partial minute.
+    pub async fn run(self, cancellation_token: CancellationToken) {
+        let mut interval = tokio::time::interval(ROLLUP_INTERVAL);
+        // the first tick completes right away
+        interval.tick().await;
+        loop {
+            let cancelled = tokio::select! {
+                _ = interval.tick() => false,
+                _ = cancellation_token.cancelled() => true,
+            };
+            if let Err(e) = self.roll_up().await {
+                warn!("failed to store the ingest latency: {e}");
+            }
+            if cancelled {
+                return;
+            }
+        }
+    }
+
+    async fn roll_up(&self) -> sqlx::Result<()> {
+        let window = self.recorder.take();
+        let samples = window.transit.count() as i64;
+        if samples > 0 {
+            let (transit, store) = (window.transit.percentiles(), window.store.percentiles());
+            debug!(samples, ?transit, ?store, "ingest latency");
+            sqlx::query!(
+                "INSERT INTO ingest_latency (time, samples, transit_p50, transit_p95, \
+                    transit_p99, transit_max, store_p50, store_p95, store_p99, store_max) \
+                    VALUES (unixepoch(), ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                samples,
+                transit.p50,
+                transit.p95,
+                transit.p99,
+                transit.max,
+                store.p50,
+                store.p95,
+                store.p99,
+                store.max
+            )
+            .execute(&self.pool)
+            .await?;
+        }
+        if self.compress {
+            self.compact().await?;
+        }
+        sqlx::query!(
+            "DELETE FROM ingest_latency WHERE time < unixepoch() - ?",
+            RETENTION_SECS
+        )
+        .execute(&self.pool)
+        .await?;
+        // also once compression is turned off again
+        sqlx::query!(
+            "DELETE FROM ingest_latency_chunks WHERE end_time < unixepoch() - ?",
+            RETENTION_SECS
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Replace the rows of every finished hour by a chunk of that hour.
+    async fn compact(&self) -> sqlx::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let hour_start = now / CHUNK_SECS * CHUNK_SECS;
+
+        let mut tx = self.pool.begin().await?;
+        let rollups: Vec<LatencyRollup> = sqlx::query_as!(
+            LatencyRow,
+            "SELECT time, samples, transit_p50, transit_p95, transit_p99, transit_max, \
+                store_p50, store_p95, store_p99, store_max \
+                FROM ingest_latency WHERE time < ? ORDER BY time, id",
+            hour_start
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+        if rollups.is_empty() {
+            return Ok(());
+        }
+
+        for hour in rollups.chunk_by(|a, b| a.time / CHUNK_SECS == b.time / CHUNK_SECS) {
+            let mut encoder = ChunkEncoder::new(CHUNK_COLUMNS);
+            for rollup in hour {
+                encoder.push(rollup.time, &rollup.values());
+            }
+            let (start_time, end_time) = (hour[0].time, hour[hour.len() - 1].time);
+            let rows = encoder.rows();
+            let data = encoder.finish();
+            debug!(
+                start_time,
+                rows,
+                bytes = data.len(),
+                "compressed ingest latency"
+            );
+            sqlx::query!(
+                "INSERT INTO ingest_latency_chunks (start_time, end_time, rows, data) \
+                    VALUES (?, ?, ?, ?)",
+                start_time,
+                end_time,
+                rows,
+                data
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query!("DELETE FROM ingest_latency WHERE time < ?", hour_start)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+}
+
+/// The rollup of the latest minute samples arrived in.
+pub async fn latest(pool: &SqlitePool) -> sqlx::Result<Option<LatencyRollup>> {
+    let row = sqlx::query_as!(
+        LatencyRow,
+        "SELECT time, samples, transit_p50, transit_p95, transit_p99, transit_max, \
+            store_p50, store_p95, store_p99, store_max \
+            FROM ingest_latency ORDER BY time DESC, id DESC LIMIT 1"
+    )
+    .fetch_optional(pool)
+    .await?;
+    if let Some(row) = row {
+        return Ok(Some(row.into()));
+    }
+
+    // the rows of finished hours are compressed, there were no samples since
+    let chunk = sqlx::query_scalar!(
+        "SELECT data FROM ingest_latency_chunks ORDER BY end_time DESC, id DESC LIMIT 1"
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(chunk) = chunk else {
+        return Ok(None);
+    };
+    match gorilla::decode(&chunk, CHUNK_COLUMNS.into()) {
+        Ok(rows) => Ok(rows
+            .last()
+            .map(|(time, values)| LatencyRollup::from_values(*time, values))),
+        Err(e) => {
+            warn!("failed to decode the ingest latency: {e}");
+            Ok(None)
+        }
+    }
+}
+
+/// The rollups of the minutes ending in `start..=end`, oldest first, read
+/// from the rows and the chunks of compressed hours alike.
+pub async fn range(pool: &SqlitePool, start: i64, end: i64) -> sqlx::Result<Vec<LatencyRollup>> {
+    let chunks = sqlx::query_scalar!(
+        "SELECT data FROM ingest_latency_chunks \
+            WHERE end_time >= ? AND start_time <= ? ORDER BY start_time, id",
+        start,
+        end
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut rollups = Vec::new();
+    for chunk in chunks {
+        match gorilla::decode(&chunk, CHUNK_COLUMNS.into()) {
+            Ok(rows) => rollups.extend(
+                rows.into_iter()
+                    .filter(|(time, _)| (start..=end).contains(time))
+                    .map(|(time, values)| LatencyRollup::from_values(time, &values)),
+            ),
+            Err(e) => warn!("failed to decode the ingest latency: {e}"),
+        }
+    }
+
+    let rows = sqlx::query_as!(
+        LatencyRow,
+        "SELECT time, samples, transit_p50, transit_p95, transit_p99, transit_max, \
+            store_p50, store_p95, store_p99, store_max \
+            FROM ingest_latency WHERE time BETWEEN ? AND ? ORDER BY time, id",
+        start,
+        end
+    )
+    .fetch_all(pool)
+    .await?;
+    rollups.extend(rows.into_iter().map(LatencyRollup::from));
+    // rows written while compression was turned off may predate chunks
+    rollups.sort_by_key(|rollup| rollup.time);
+    Ok(rollups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.count(), 0);
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(
+            histogram.percentiles(),
+            Percentiles {
+                p50: 50,
+                p95: 100,
+                p99: 100,
+                max: 100
+            }
+        );
+
+        // beyond the last bucket, bounded by the slowest sample
+        histogram.record(Duration::from_secs(600));
+        assert_eq!(histogram.quantile(1.0), 600_000);
+        // and so is every other bucket
+        let mut fast = Histogram::default();
+        fast.record(Duration::from_millis(12));
+        assert_eq!(fast.percentiles().p50, 12);
+    }
+
+    #[tokio::test]
+    async fn compacted_rollups() {
+        let db = crate::db::Db::connect(
+            "sqlite:file:latency?mode=memory&cache=shared",
+            "sqlite:file:latency-samples?mode=memory&cache=shared",
+            1,
+            None,
+        )
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+
+        // the two hours before and a minute of the current one
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let hour_start = now / CHUNK_SECS * CHUNK_SECS;
+        let times = (0..120)
+            .map(|minute| hour_start - 2 * CHUNK_SECS + minute * 60)
+            .chain([hour_start + 30]);
+        for (i, time) in times.enumerate() {
+            sqlx::query(
+                "INSERT INTO ingest_latency (time, samples, transit_p50, transit_p95, \
+                    transit_p99, transit_max, store_p50, store_p95, store_p99, store_max) \
+                    VALUES (?, ?, 5, 10, 25, 100, 1, 1, 2, ?)",
+            )
+            .bind(time)
+            .bind(120 + i as i64 % 2)
+            .bind(i as i64)
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        }
+
+        let rollups = LatencyRollups::new(db.writer.clone(), LatencyRecorder::default())
+            .with_compression(true);
+        rollups.compact().await.unwrap();
+        let (rows, chunks): (i64, i64) = sqlx::query_as(
+            "SELECT (SELECT COUNT(*) FROM ingest_latency), \
+                (SELECT COUNT(*) FROM ingest_latency_chunks)",
+        )
+        .fetch_one(&db.reader)
+        .await
+        .unwrap();
+        assert_eq!((rows, chunks), (1, 2));
+
+        let hours = range(&db.reader, hour_start - 2 * CHUNK_SECS, now)
+            .await
+            .unwrap();
+        assert_eq!(hours.len(), 121);
+        assert!(hours.is_sorted_by_key(|rollup| rollup.time));
+        assert_eq!(hours[1].store.max, 1);
+        assert_eq!(hours[120].time, hour_start + 30);
+        let minutes = range(&db.reader, hour_start - 90, hour_start - 60)
+            .await
+            .unwrap();
+        assert_eq!(minutes.len(), 1);
+        assert_eq!(minutes[0].store.max, 119);
+
+        sqlx::query("DELETE FROM ingest_latency")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        let latest = latest(&db.reader).await.unwrap().unwrap();
+        assert_eq!(latest.time, hour_start - 60);
+        assert_eq!(latest.samples, 120 + 119 % 2);
+        assert_eq!(latest.transit.p99, 25);
+        assert_eq!(latest.store.max, 119);
+    }
+
+    #[test]
+    fn clock_ahead() {
+        let recorder = LatencyRecorder::default();
+        recorder.record(
+            UnixMillis(2_000),
+            UnixMillis(1_000),
+            Duration::from_millis(3),
+        );
+        let window = recorder.take();
+        assert_eq!(window.transit.max, 0);
+        assert_eq!(window.store.max, 3);
+        assert_eq!(recorder.take().transit.count(), 0);
+    }
+}
@@ -0,0 +1,144 @@
+//! Listening sockets of the server: ones passed by systemd socket activation,
+//! unix sockets or TCP addresses, each serving all or part of the routes.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::{os::unix::fs::FileTypeExt, path::Path};
+
+use listenfd::ListenFd;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tracing::info;
+
+use crate::Conf;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Routes served on a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Routes {
+    #[default]
+    All,
+    /// Everything but the admin API
+    Public,
+    /// Only the admin API
+    Admin,
+}
+
+/// An entry of `listeners`, with either `address` or `unix_socket`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConf {
+    /// TCP address, e.g. `[::]:8000`
+    pub address: Option<SocketAddr>,
+    pub unix_socket: Option<PathBuf>,
+    #[serde(default)]
+    pub routes: Routes,
+}
+
+/// Take the sockets passed by systemd (`LISTEN_FDS`) if there are any, so
+/// restarts do not drop connections waiting to be accepted, the nth socket
+/// serves the routes of the nth entry of `listeners`. Otherwise bind the
+/// configured `listeners`, or the unix socket or TCP address and the admin
+/// address if there are none.
+pub async fn bind(conf: &Conf) -> anyhow::Result<Vec<(Listener, Routes)>> {
+    let mut fds = ListenFd::from_env();
+    if fds.len() > 0 {
+        let mut listeners = Vec::with_capacity(fds.len());
+        for index in 0..fds.len() {
+            let routes = conf
+                .listeners
+                .get(index)
+                .map_or_else(Routes::default, |l| l.routes);
+            listeners.push((take_activated(&mut fds, index, routes)?, routes));
+        }
+        return Ok(listeners);
+    }
+
+    if conf.listeners.is_empty() {
+        let routes = match conf.admin_address {
+            Some(_) => Routes::Public,
+            None => Routes::All,
+        };
+        let listener = match &conf.unix_socket {
+            Some(path) => bind_unix(path, routes)?,
+            None => bind_tcp(SocketAddr::from((conf.address, conf.port)), routes).await?,
+        };
+        let mut listeners = vec![(listener, routes)];
+        if let Some(addr) = conf.admin_address {
+            listeners.push((bind_tcp(addr, Routes::Admin).await?, Routes::Admin));
+        }
+        return Ok(listeners);
+    }
+
+    let mut listeners = Vec::with_capacity(conf.listeners.len());
+    for listener in &conf.listeners {
+        let bound = match (&listener.unix_socket, listener.address) {
+            (Some(path), _) => bind_unix(path, listener.routes)?,
+            (None, Some(addr)) => bind_tcp(addr, listener.routes).await?,
+            (None, None) => unreachable!("checked when loading the config"),
+        };
+        listeners.push((bound, listener.routes));
+    }
+    Ok(listeners)
+}
+
+fn take_activated(fds: &mut ListenFd, index: usize, routes: Routes) -> anyhow::Result<Listener> {
+    if let Some(listener) = fds.take_tcp_listener(index).ok().flatten() {
+        listener.set_nonblocking(true)?;
+        info!(
+            "listening on {} (socket activation, {routes:?} routes)",
+            listener.local_addr()?
+        );
+        return Ok(Listener::Tcp(TcpListener::from_std(listener)?));
+    }
+    #[cfg(unix)]
+    if let Some(listener) = fds.take_unix_listener(index).ok().flatten() {
+        listener.set_nonblocking(true)?;
+        info!(
+            "listening on {:?} (socket activation, {routes:?} routes)",
+            listener.local_addr()?
+        );
+        return Ok(Listener::Unix(UnixListener::from_std(listener)?));
+    }
+    anyhow::bail!("socket {index} passed by systemd is not a stream socket");
+}
+
+async fn bind_tcp(addr: SocketAddr, routes: Routes) -> anyhow::Result<Listener> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("listening on {addr} ({routes:?} routes)");
+    Ok(Listener::Tcp(listener))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, routes: Routes) -> anyhow::Result<Listener> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    info!("listening on {} ({routes:?} routes)", path.display());
+    Ok(Listener::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(path: &std::path::Path, _: Routes) -> anyhow::Result<Listener> {
+    anyhow::bail!(
+        "cannot listen on {}, unix sockets are not supported on this platform",
+        path.display()
+    );
+}
+
+/// Remove the socket file left behind by a previous run, binding fails otherwise.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
@@ -1,12 +1,15 @@
-use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::sync::{Notify, RwLock};
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug)]
 pub struct SharedOwnable<T> {
     data: RwLock<T>,
     owned: AtomicBool,
     owner_notify: Notify,
+    /// Cancelled to ask the current owner to give up ownership
+    preempt: Mutex<CancellationToken>,
 }
 
 pub struct ReadGuard<'a, T> {
@@ -19,6 +22,7 @@ pub struct WriteGuard<'a, T> {
 
 pub struct OwnershipGuard<T> {
     value: Arc<SharedOwnable<T>>,
+    preempted: CancellationToken,
 }
 
 #[allow(dead_code)]
@@ -28,6 +32,7 @@ impl<T> SharedOwnable<T> {
             data: RwLock::new(value),
             owned: AtomicBool::new(false),
             owner_notify: Notify::new(),
+            preempt: Mutex::new(CancellationToken::new()),
         })
     }
 
@@ -44,8 +49,11 @@ impl<T> SharedOwnable<T> {
             .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
+            let preempted = CancellationToken::new();
+            *self.preempt.lock().unwrap() = preempted.clone();
             Some(OwnershipGuard {
                 value: self.clone(),
+                preempted,
             })
         } else {
             None
@@ -62,6 +70,18 @@ impl<T> SharedOwnable<T> {
         }
     }
 
+    /// Get ownership, asking the current owner to give it up and waiting
+    /// until it does
+    pub async fn take_over(self: &Arc<Self>) -> OwnershipGuard<T> {
+        loop {
+            if let Some(guard) = self.try_own() {
+                return guard;
+            }
+            self.preempt.lock().unwrap().cancel();
+            self.owner_notify.notified().await;
+        }
+    }
+
     /// Check if it is owned
     pub fn is_owned(&self) -> bool {
         self.owned.load(Ordering::Acquire)
@@ -102,6 +122,11 @@ impl<T> OwnershipGuard<T> {
     pub async fn write(&self) -> WriteGuard<'_, T> {
         self.value.write().await
     }
+
+    /// Cancelled once another owner wants to take over
+    pub fn preempted(&self) -> CancellationToken {
+        self.preempted.clone()
+    }
 }
 
 impl<T> Drop for OwnershipGuard<T> {
@@ -113,3 +138,29 @@ impl<T> Drop for OwnershipGuard<T> {
 
 unsafe impl<T: Send> Send for SharedOwnable<T> {}
 unsafe impl<T: Send + Sync> Sync for SharedOwnable<T> {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn take_over() {
+        let value = SharedOwnable::new(());
+        let first = value.try_own().unwrap();
+        assert!(value.try_own().is_none());
+
+        let preempted = first.preempted();
+        let owner = tokio::spawn(async move {
+            preempted.cancelled().await;
+            drop(first);
+        });
+        let second = tokio::time::timeout(Duration::from_secs(1), value.take_over())
+            .await
+            .expect("the owner gives up ownership");
+        owner.await.unwrap();
+        assert!(!second.preempted().is_cancelled());
+        assert!(value.is_owned());
+    }
+}
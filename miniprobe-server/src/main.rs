@@ -1,41 +1,72 @@
 use std::{
     net::{IpAddr, SocketAddr},
-    str::FromStr,
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
 
 use anyhow::anyhow;
 use axum::{
-    Router,
+    Router, middleware,
     routing::{get, post},
 };
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use confique::Config;
 use sha2::{Digest, Sha256};
-use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
-use tokio::{net::TcpListener, signal, sync::RwLock};
+use tokio::{
+    signal,
+    sync::{Notify, RwLock, watch},
+};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
-use tracing::{info, trace};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::{info, trace, warn};
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt};
 
-use crate::route::SessionManager;
+use crate::{
+    db::{Db, MIGRATOR, MigrationStatus, SAMPLES_MIGRATOR},
+    listen::{Listener, Routes},
+    route::{LogFilterHandle, SessionManager},
+};
 
+mod addresses;
 mod admin;
+mod alert;
+mod conf;
+mod db;
+mod events;
+mod expr;
+mod gorilla;
+mod hooks;
+mod intern;
+mod labels;
+mod latency;
+mod listen;
 mod lock;
+mod overview;
 mod postcard;
+mod quota;
+mod reboot;
 mod route;
+mod security;
+mod sink;
+#[cfg(test)]
+mod testing;
+mod wol;
 
-const CLINET_TOKEN_LENGTH: usize = 16;
+/// Interval the server asks every client to sample at.
+const SCRAPE_INTERVAL: Duration = miniprobe_proto::limits::DEFAULT_SCRAPE_INTERVAL;
 
 #[derive(Debug, Parser)]
 #[command(name = "miniprobe-server")]
 struct Cli {
     #[arg(short, long, value_name = "FILE", help = "Path to config file")]
     config_path: Option<String>,
+    /// Report pending database migrations without applying them, exits with
+    /// an error if any are pending
+    #[arg(long)]
+    check_migrations: bool,
     #[command(subcommand)]
-    commands: Commands,
+    commands: Option<Commands>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -43,9 +74,16 @@ enum Commands {
     /// Run the server
     Serve,
 
+    /// Apply pending database migrations and exit
+    Migrate,
+
     /// Administrative commands
     #[command(subcommand)]
     Admin(admin::AdminCommands),
+
+    /// Config file tools
+    #[command(subcommand)]
+    Config(conf::ConfigCommands),
 }
 
 #[derive(Config, Debug)]
@@ -58,23 +96,208 @@ struct Conf {
     #[config(default = "127.0.0.1")]
     address: IpAddr,
 
+    /// Listen on this unix socket instead of `address` and `port`. A socket
+    /// passed by systemd socket activation takes precedence over both
+    unix_socket: Option<PathBuf>,
+
+    /// Serve the admin API on this address only, e.g. `127.0.0.1:8001`, the
+    /// address above then serves everything else
+    admin_address: Option<SocketAddr>,
+
+    /// Listen on several sockets instead, each serving `all` routes, the
+    /// `public` ones without the admin API or only the `admin` API, e.g.
+    /// `[{ address = "[::]:8000", routes = "public" }, { address =
+    /// "127.0.0.1:8001", routes = "admin" }]`. Sockets passed by systemd take
+    /// the routes of the entry at their position
+    #[config(default = [])]
+    listeners: Vec<listen::ListenerConf>,
+
     /// Database URL
     #[config(default = "sqlite://db.sqlite")]
     database_url: String,
+
+    /// Database URL of the samples, kept apart from clients and sessions.
+    /// Deleting it while the server is stopped drops every sample
+    #[config(default = "sqlite://samples.sqlite")]
+    samples_database_url: String,
+
+    /// Encrypt both databases with SQLCipher, keyed with the passphrase in
+    /// this file, e.g. a systemd credential. Needs a server built with the
+    /// `sqlcipher` feature. Only databases created encrypted can be opened,
+    /// existing ones have to be exported with `sqlcipher_export` first
+    database_key_file: Option<PathBuf>,
+
+    /// Take the passphrase from this environment variable instead of
+    /// `database_key_file`, e.g. one set by a KMS agent
+    database_key_env: Option<String>,
+
+    /// Number of read-only database connections used by queries
+    #[config(default = 4)]
+    read_connections: u32,
+
+    /// Seconds a request of the query API may take before it is refused with
+    /// 503. Its database statements are interrupted then, and whenever the
+    /// request is dropped before. At least 1, there is no unlimited
+    #[config(default = 30)]
+    query_timeout: u64,
+
+    /// Compress the ingest latency rollups of every finished hour into one
+    /// chunk instead of a row per minute, an experiment with storing long
+    /// term rollups in a fraction of the space
+    #[config(default = false)]
+    compress_rollups: bool,
+
+    /// Apply pending migrations on startup, otherwise refuse to start until
+    /// they are applied with `miniprobe-server migrate`
+    #[config(default = true)]
+    auto_migrate: bool,
+
+    /// CPU report shape enforced on clients (`aggregate` or `per-core`), clients decide if unset
+    cpu_report: Option<miniprobe_proto::CpuReportPolicy>,
+
+    /// Accept JSON encoded metrics in text frames on the ingress websocket,
+    /// handy for testing with `websocat`
+    #[config(default = false)]
+    json_ingress: bool,
+
+    /// What a second connection to the ingress websocket of a session does:
+    /// `takeover` closes the older connection, `reject` refuses the newer one
+    #[config(default = "takeover")]
+    ingress_conflict: route::IngressConflict,
+
+    /// What a session created from another hostname than its client is bound
+    /// to with `admin client hostname` does: `reject` refuses it, `flag` lets
+    /// it in and marks it in the sessions API
+    #[config(default = "reject")]
+    hostname_mismatch: route::HostnameMismatch,
+
+    /// Where ingested samples go: `sqlite` stores them, `jsonl` writes them
+    /// to stdout as JSON lines, `discard` drops them and `forward` sends them
+    /// to the upstream server of `relay`
+    #[config(default = "sqlite")]
+    sink: sink::SinkKind,
+
+    /// Bearer token of the admin API, the admin API is disabled if unset
+    admin_token: Option<String>,
+
+    /// Send logs to the systemd journal with their priority instead of
+    /// writing them to stderr, for servers run as a systemd service. Only
+    /// available on Linux
+    #[config(default = false)]
+    journald: bool,
+
+    /// Webhooks POSTed on session and host events, e.g. `[{ url =
+    /// "https://status.example.com/hook", events = ["host_down", "host_up"],
+    /// template = '{"text": "{{client_name}} is {{state}}"}' }]`, see
+    /// `HookConf`
+    #[config(default = [])]
+    hooks: Vec<hooks::HookConf>,
+
+    /// Alerting
+    #[config(nested)]
+    alerts: alert::AlertConf,
+
+    /// Ingest quotas
+    #[config(nested)]
+    quotas: quota::QuotaConf,
+
+    /// Forwarding to an upstream server
+    #[config(nested)]
+    relay: sink::RelayConf,
+
+    /// Networks clients may connect from
+    #[config(nested)]
+    access: route::AccessConf,
+
+    /// Maintenance mode
+    #[config(nested)]
+    maintenance: route::MaintenanceConf,
+
+    /// Access to the health details and server info
+    #[config(nested)]
+    status: route::StatusConf,
+
+    /// Public status page of picked hosts
+    #[config(nested)]
+    status_page: route::StatusPageConf,
+
+    /// Wake-on-LAN packets sent by `POST /api/v1/clients/{id}/wake`
+    #[config(nested)]
+    wake_on_lan: wol::WakeConf,
+
+    /// Actions clients offer to run on request
+    #[config(nested)]
+    actions: route::ActionsConf,
+
+    /// Metrics for Prometheus to scrape
+    #[config(nested)]
+    prometheus: route::PrometheusConf,
+
+    /// Caching of query responses
+    #[config(nested)]
+    query_cache: route::QueryCacheConf,
+
+    /// Lockouts after failed authentications and the security events kept
+    #[config(nested)]
+    security: security::SecurityConf,
 }
 
 fn config(path: &str) -> anyhow::Result<Conf> {
-    Conf::builder()
-        .env()
-        .file(path)
-        .load()
-        .map_err(|e| e.into())
+    let conf = Conf::builder().env().file(path).load()?;
+    if conf.sink == sink::SinkKind::Forward && conf.relay.upstream.is_none() {
+        anyhow::bail!("`sink = \"forward\"` needs `relay.upstream` to be set");
+    }
+    if conf
+        .listeners
+        .iter()
+        .any(|l| l.address.is_some() == l.unix_socket.is_some())
+    {
+        anyhow::bail!("each of `listeners` needs either `address` or `unix_socket`");
+    }
+    if conf.admin_address.is_some() && !conf.listeners.is_empty() {
+        anyhow::bail!("`admin_address` cannot be combined with `listeners`");
+    }
+    if conf.database_key_file.is_some() && conf.database_key_env.is_some() {
+        anyhow::bail!("`database_key_file` cannot be combined with `database_key_env`");
+    }
+    if (conf.database_key_file.is_some() || conf.database_key_env.is_some())
+        && !cfg!(feature = "sqlcipher")
+    {
+        anyhow::bail!("database encryption needs a server built with the `sqlcipher` feature");
+    }
+    if conf.journald && !cfg!(target_os = "linux") {
+        anyhow::bail!("`journald` is only available on Linux");
+    }
+    // every query would time out at once
+    if conf.query_timeout == 0 {
+        anyhow::bail!("`query_timeout` must be at least 1 second");
+    }
+    Ok(conf)
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct AppState {
+    pub conf: Arc<Conf>,
     pub session_mgr: Arc<RwLock<SessionManager>>,
-    pub pool: SqlitePool,
+    pub db: Db,
+    pub log_filter: LogFilterHandle,
+    pub events: events::EventSender,
+    /// Wakes the alert evaluator when an urgent sample arrives
+    pub alert_trigger: Arc<Notify>,
+    /// Changes whenever a sample was stored, wakes the replication websockets
+    pub samples_stored: watch::Sender<()>,
+    /// Latency of the samples ingested in the current minute
+    pub latency: latency::LatencyRecorder,
+    /// Refuses clients and closes the ingress websockets while enabled
+    pub maintenance: watch::Sender<route::Maintenance>,
+    /// The latest public status page
+    pub status_page: route::StatusPageCache,
+    /// Where to ask connected clients for actions
+    pub actions: route::ActionChannels,
+    /// Recent query responses, dropped by the samples changing them
+    pub query_cache: route::QueryCache,
+    /// Records failed authentications and other security events
+    pub security: security::SecurityLog,
     pub ws_graceful_shutdown: WebsocketGracefule,
 }
 
@@ -84,61 +307,273 @@ struct WebsocketGracefule {
     pub tracker: TaskTracker,
 }
 
-fn app(state: AppState) -> Router {
-    Router::new()
-        .route("/health", get(route::health))
+fn app(state: AppState, routes: Routes) -> Router {
+    match routes {
+        Routes::All => public_router(&state).merge(admin_router(&state)),
+        Routes::Public => public_router(&state),
+        Routes::Admin => admin_router(&state),
+    }
+    .route("/health", get(route::health))
+    .layer((
+        TraceLayer::new_for_http(),
+        // Prevent requests to hang forever
+        TimeoutLayer::new(Duration::from_secs(60)),
+    ))
+    .with_state(state)
+}
+
+/// Routes of clients, authenticated by their tokens, limited to the networks
+/// of `access` and refused while in maintenance. The status page is open to
+/// anyone when enabled.
+fn public_router(state: &AppState) -> Router<AppState> {
+    let access = middleware::from_extractor_with_state::<route::ClientAccess, _>(state.clone());
+    let available = middleware::from_extractor_with_state::<route::Available, _>(state.clone());
+    let status_page = match state.conf.status_page.enabled {
+        true => Router::new()
+            .route("/status", get(route::status_page_html))
+            .route("/status.json", get(route::status_page_json)),
+        false => Router::new(),
+    };
+    status_page
+        .route("/.well-known/miniprobe", get(route::well_known))
         // .route("/auth", post(route::auth))
         .nest(
             "/api/v1",
-            Router::new().route("/sessions", post(route::create_session)),
+            Router::new()
+                .route("/sessions", post(route::create_session))
+                .route_layer(available.clone())
+                .route_layer(access.clone()),
+        )
+        .nest(
+            "/ws/v1",
+            Router::new()
+                .route("/metrics/ingress", get(route::metric_ingress_ws))
+                .route_layer(available)
+                .route_layer(access),
+        )
+}
+
+/// The admin API, every route requires the `admin_token`.
+fn admin_router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .nest(
+            "/api/v1",
+            Router::new()
+                .route(
+                    "/alerts/rules",
+                    get(route::list_alert_rules).post(route::create_alert_rule),
+                )
+                .route(
+                    "/alerts/rules/{id}",
+                    get(route::get_alert_rule)
+                        .put(route::update_alert_rule)
+                        .delete(route::delete_alert_rule),
+                )
+                .route("/clients", get(route::list_clients))
+                .route("/fleet/query", get(route::fleet_query))
+                .route("/fleet/query_range", get(route::fleet_query_range))
+                .route("/clients/{id}/listeners", get(route::list_listeners))
+                .route("/clients/{id}/addresses", get(route::list_addresses))
+                .route("/clients/{id}/reboots", get(route::list_reboots))
+                .route("/clients/{id}/sessions", get(route::list_sessions))
+                .route("/clients/{id}/sparkline", get(route::sparkline))
+                .route("/clients/{id}/wake", post(route::wake_client))
+                .route("/clients/{id}/actions", get(route::list_actions))
+                .route("/clients/{id}/actions/{name}", post(route::run_action))
+                .route("/query", get(route::query))
+                .route("/query_range", get(route::query_range))
+                .route("/security/events", get(route::list_security_events))
+                .route(
+                    "/admin/log-level",
+                    get(route::get_log_level).put(route::set_log_level),
+                )
+                .route(
+                    "/admin/maintenance",
+                    get(route::get_maintenance).put(route::set_maintenance),
+                ),
         )
         .nest(
             "/ws/v1",
-            Router::new().route("/metrics/ingress", get(route::metric_ingress_ws)),
+            Router::new()
+                .route("/events", get(route::events_ws))
+                .route("/metrics/replicate", get(route::metric_replicate_ws)),
+        )
+        .route_layer(middleware::from_extractor_with_state::<route::AdminAuth, _>(state.clone()))
+        .merge(status_router(state))
+}
+
+/// The server info and the Prometheus metrics if enabled, readable with the
+/// `status.token` as well as the `admin_token`.
+fn status_router(state: &AppState) -> Router<AppState> {
+    let prometheus = match state.conf.prometheus.enabled {
+        true => Router::new().route("/metrics", get(route::prometheus_metrics)),
+        false => Router::new(),
+    };
+    prometheus
+        .nest(
+            "/api/v1",
+            Router::new()
+                .route("/server/info", get(route::server_info))
+                .route("/server/ingest-latency", get(route::ingest_latency)),
         )
-        .layer((
-            TraceLayer::new_for_http(),
-            // Prevent requests to hang forever
-            TimeoutLayer::new(Duration::from_secs(60)),
-        ))
-        .with_state(state)
+        .route_layer(middleware::from_extractor_with_state::<route::StatusAuth, _>(state.clone()))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    init_tracing();
-
     let cli = Cli::parse();
-    trace!("using command line arguments {:?}", cli);
+    if cli.check_migrations && cli.commands.is_some() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--check-migrations cannot be used with a subcommand",
+            )
+            .exit();
+    }
 
-    let config = config(&cli.config_path.unwrap_or("config.toml".to_owned()))?;
+    let config_path = cli.config_path.clone().unwrap_or("config.toml".to_owned());
+    if let Some(Commands::Config(command)) = cli.commands {
+        init_tracing(false);
+        return conf::config(command, &config_path);
+    }
+    // logging is set up by the config, errors before go to stderr through main
+    let config = config(&config_path)?;
+    let log_filter = init_tracing(config.journald);
+    trace!("using command line arguments {:?}", cli);
     trace!("using config {:?}", config);
 
-    let db_opts = SqliteConnectOptions::from_str(&config.database_url)?.create_if_missing(true);
-    let pool = SqlitePool::connect_with(db_opts).await?;
-    sqlx::migrate!()
-        .run(&pool)
+    let database_key = db::load_key(
+        config.database_key_file.as_deref(),
+        config.database_key_env.as_deref(),
+    )?;
+    if cli.check_migrations {
+        // checking must not leave an empty database behind
+        let db = Db::connect_existing(
+            &config.database_url,
+            &config.samples_database_url,
+            config.read_connections,
+            database_key.as_deref(),
+        )
         .await
-        .map_err(|e| anyhow!("failed to initialize SQLx database: {e}"))?;
+        .map_err(|e| anyhow!("failed to open the database: {e}"))?;
+        let res = check_migrations(&db).await;
+        db.close().await;
+        return res;
+    }
+    let db = Db::connect(
+        &config.database_url,
+        &config.samples_database_url,
+        config.read_connections,
+        database_key.as_deref(),
+    )
+    .await?;
+
+    let Some(command) = cli.commands else {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a subcommand is required",
+            )
+            .exit();
+    };
 
-    match cli.commands {
-        Commands::Serve => {
-            let addr = SocketAddr::from((config.address, config.port));
-            info!("listening on {addr}");
-            let listener = TcpListener::bind(addr).await?;
+    if config.auto_migrate || matches!(command, Commands::Migrate) {
+        db.migrate()
+            .await
+            .map_err(|e| anyhow!("failed to initialize SQLx database: {e}"))?;
+    } else {
+        let pending = MigrationStatus::check(&db.writer, &MIGRATOR)
+            .await?
+            .pending
+            .len()
+            + MigrationStatus::check(&db.samples, &SAMPLES_MIGRATOR)
+                .await?
+                .pending
+                .len();
+        if pending > 0 {
+            db.close().await;
+            return Err(anyhow!(
+                "database has {pending} pending migrations, apply them with `miniprobe-server migrate`"
+            ));
+        }
+    }
 
+    match command {
+        Commands::Migrate => info!("database migrations applied"),
+        Commands::Serve => {
+            let listeners = listen::bind(&config).await?;
+
+            let maintenance = route::Maintenance::from(&config.maintenance);
+            if maintenance.enabled {
+                warn!("starting in maintenance mode, clients are refused");
+            }
+            let events = events::channel();
+            let (security, security_writer) =
+                security::SecurityLog::new(db.writer.clone(), events.clone(), &config.security);
             let state = AppState {
+                conf: Arc::new(config),
                 session_mgr: Arc::new(RwLock::new(SessionManager::new())),
-                pool: pool.clone(),
+                db: db.clone(),
+                log_filter,
+                events,
+                alert_trigger: Arc::new(Notify::new()),
+                samples_stored: watch::Sender::new(()),
+                latency: latency::LatencyRecorder::default(),
+                maintenance: watch::Sender::new(maintenance),
+                status_page: route::StatusPageCache::default(),
+                actions: route::ActionChannels::default(),
+                query_cache: route::QueryCache::default(),
+                security,
                 ws_graceful_shutdown: WebsocketGracefule {
                     token: CancellationToken::new(),
                     tracker: TaskTracker::new(),
                 },
             };
 
-            axum::serve(listener, app(state.clone()))
-                .with_graceful_shutdown(shutdown_signal(state.ws_graceful_shutdown.token.clone()))
-                .await?;
+            state.ws_graceful_shutdown.tracker.spawn(
+                alert::AlertEvaluator::new(
+                    db.reader.clone(),
+                    &state.conf.alerts,
+                    state.events.clone(),
+                    state.alert_trigger.clone(),
+                )
+                .run(state.ws_graceful_shutdown.token.child_token()),
+            );
+            state.ws_graceful_shutdown.tracker.spawn(
+                events::HostWatcher::new(db.reader.clone(), state.events.clone(), SCRAPE_INTERVAL)
+                    .run(state.ws_graceful_shutdown.token.child_token()),
+            );
+            state.ws_graceful_shutdown.tracker.spawn(
+                hooks::Hooks::new(state.conf.hooks.clone(), state.events.subscribe())
+                    .run(state.ws_graceful_shutdown.token.child_token()),
+            );
+            state.ws_graceful_shutdown.tracker.spawn(
+                security::SecurityNotifier::new(
+                    alert::Notifier::new(state.conf.alerts.channels.clone()),
+                    state.events.subscribe(),
+                )
+                .run(state.ws_graceful_shutdown.token.child_token()),
+            );
+
+            state
+                .ws_graceful_shutdown
+                .tracker
+                .spawn(security_writer.run(state.ws_graceful_shutdown.token.child_token()));
+            state.ws_graceful_shutdown.tracker.spawn(
+                latency::LatencyRollups::new(db.writer.clone(), state.latency.clone())
+                    .with_compression(state.conf.compress_rollups)
+                    .run(state.ws_graceful_shutdown.token.child_token()),
+            );
+
+            tokio::spawn(shutdown_signal(state.ws_graceful_shutdown.token.clone()));
+            futures_util::future::try_join_all(listeners.into_iter().map(|(listener, routes)| {
+                serve(
+                    listener,
+                    app(state.clone(), routes),
+                    state.ws_graceful_shutdown.token.clone(),
+                )
+            }))
+            .await?;
 
             let ws_tracker = state.ws_graceful_shutdown.tracker.clone();
             ws_tracker.close();
@@ -146,38 +581,136 @@ async fn main() -> anyhow::Result<()> {
             trace!("waiting {} websocket connection shutdown", ws_tracker.len());
             ws_tracker.wait().await;
         }
-        Commands::Admin(command) => admin::admin(command, pool.clone()).await?,
+        Commands::Admin(command) => admin::admin(command, db.writer.clone(), &config).await?,
+        Commands::Config(_) => unreachable!("handled before connecting to the database"),
     }
 
     trace!("closing database connection");
-    pool.close().await;
+    db.close().await;
 
     Ok(())
 }
 
-fn init_tracing() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                #[cfg(debug_assertions)]
-                let default_log_level = format!(
-                    "{}=debug,tower_http=debug,axum=trace",
-                    env!("CARGO_CRATE_NAME")
-                )
-                .into();
+/// Serve `app` until `shutdown` is cancelled by [`shutdown_signal`].
+async fn serve(
+    listener: Listener,
+    app: Router,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let shutdown = shutdown.cancelled_owned();
+    match listener {
+        // the peer address is kept as `ConnectInfo` for `route::ClientAccess`
+        Listener::Tcp(listener) => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown)
+            .await
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+    }
+}
 
-                #[cfg(not(debug_assertions))]
-                let default_log_level = format!(
-                    "{}=info,tower_http=info,axum=info",
-                    env!("CARGO_CRATE_NAME")
-                )
-                .into();
+async fn check_migrations(db: &Db) -> anyhow::Result<()> {
+    let mut pending = 0;
+    for (name, pool, migrator) in [
+        ("schema", &db.writer, &MIGRATOR),
+        ("samples schema", &db.samples, &SAMPLES_MIGRATOR),
+    ] {
+        let status = MigrationStatus::check(pool, migrator).await?;
+
+        println!(
+            "{name} version: {}",
+            status
+                .current
+                .map_or_else(|| "none".to_owned(), |v| v.to_string())
+        );
+        if status.pending.is_empty() {
+            println!("no pending migrations");
+            continue;
+        }
 
-                default_log_level
-            }),
-        )
-        .with(tracing_subscriber::fmt::layer().without_time())
+        println!("pending migrations:");
+        for (version, description) in &status.pending {
+            println!("  {version} {description}");
+        }
+        pending += status.pending.len();
+    }
+
+    if pending > 0 {
+        return Err(anyhow!("{pending} pending migrations"));
+    }
+    Ok(())
+}
+
+/// Set up logging to stderr, or to the systemd journal with `journald`. The
+/// returned handle changes the filter at runtime.
+fn init_tracing(journald: bool) -> LogFilterHandle {
+    let (filter, handle) = reload::Layer::new(
+        tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+            #[cfg(debug_assertions)]
+            let default_log_level = format!(
+                "{}=debug,tower_http=debug,axum=trace",
+                env!("CARGO_CRATE_NAME")
+            )
+            .into();
+
+            #[cfg(not(debug_assertions))]
+            let default_log_level = format!(
+                "{}=info,tower_http=info,axum=info",
+                env!("CARGO_CRATE_NAME")
+            )
+            .into();
+
+            default_log_level
+        }),
+    );
+    #[cfg(target_os = "linux")]
+    let (journald, journald_error) = match journald.then(journald_layer).transpose() {
+        Ok(layer) => (layer, None),
+        Err(e) => (None, Some(e)),
+    };
+    // refused when loading the config
+    #[cfg(not(target_os = "linux"))]
+    let (journald, journald_error) = (
+        None::<tracing_subscriber::layer::Identity>,
+        None::<std::io::Error>,
+    );
+    // stdout is left to the `jsonl` sink
+    let stderr = journald.is_none().then(|| {
+        tracing_subscriber::fmt::layer()
+            .without_time()
+            .with_writer(std::io::stderr)
+    });
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(journald)
+        .with(stderr)
         .init();
+    if let Some(e) = journald_error {
+        warn!("failed to connect to the systemd journal, logging to stderr: {e}");
+    }
+    handle
+}
+
+/// The journal layer, if the journal is running. Without the socket the
+/// layer would be created anyway and drop every message.
+#[cfg(target_os = "linux")]
+fn journald_layer() -> std::io::Result<tracing_journald::Layer> {
+    const SOCKET: &str = "/run/systemd/journal/socket";
+    if !std::path::Path::new(SOCKET).exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{SOCKET} not found"),
+        ));
+    }
+    tracing_journald::layer()
 }
 
 async fn shutdown_signal(ws_token: CancellationToken) {
@@ -208,10 +741,27 @@ async fn shutdown_signal(ws_token: CancellationToken) {
 
 #[inline]
 fn index_client_token(token: &str) -> u32 {
-    let token_idx = Sha256::digest(token[..4].as_bytes())
+    Sha256::digest(&token.as_bytes()[..4])
         .into_iter()
         .take(4)
-        .fold(0, |acc, b| (acc << 8) | b as u32);
+        .fold(0, |acc, b| (acc << 8) | b as u32)
+}
 
-    token_idx
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_query_timeout_refused() {
+        let path =
+            std::env::temp_dir().join(format!("miniprobe-config-{}.toml", std::process::id()));
+        let load = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            config(path.to_str().unwrap())
+        };
+        assert_eq!(load("query_timeout = 1\n").unwrap().query_timeout, 1);
+        let err = load("query_timeout = 0\n").unwrap_err();
+        assert!(err.to_string().contains("query_timeout"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+    }
 }
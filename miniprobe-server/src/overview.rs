@@ -0,0 +1,141 @@
+//! Per client health summary shared by the clients API and the admin CLI.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+use sqlx::SqliteExecutor;
+
+use crate::SCRAPE_INTERVAL;
+
+/// Missed scrapes after which a client counts as stale.
+const STALE_AFTER_SCRAPES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientState {
+    /// Sent a sample within the last few scrape intervals
+    Up,
+    /// Sent samples before, but not recently
+    Stale,
+    /// Never sent a sample
+    Never,
+}
+
+impl ClientState {
+    /// State of a client at `now` given the time of its latest sample.
+    pub fn of(now: i64, last_sample: Option<i64>) -> Self {
+        let stale_after = (SCRAPE_INTERVAL * STALE_AFTER_SCRAPES).as_secs() as i64;
+        match last_sample {
+            None => ClientState::Never,
+            Some(time) if now - time > stale_after => ClientState::Stale,
+            Some(_) => ClientState::Up,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientStatus {
+    pub state: ClientState,
+    /// Time of the latest sample as unix timestamp in seconds
+    pub last_sample: Option<i64>,
+    /// Seconds since the latest sample
+    pub staleness: Option<i64>,
+    /// Whether the client has a session active in the last 5 minutes
+    pub session_active: bool,
+    /// Average CPU usage over the last 24 hours in percent
+    pub cpu_avg_24h: Option<f64>,
+    /// Average used over total memory over the last 24 hours, `0..=1`
+    pub memory_avg_24h: Option<f64>,
+}
+
+impl ClientStatus {
+    fn new(
+        now: i64,
+        last_sample: Option<i64>,
+        session_active: bool,
+        cpu_avg_24h: Option<f64>,
+        memory_avg_24h: Option<f64>,
+    ) -> Self {
+        let staleness = last_sample.map(|time| (now - time).max(0));
+        ClientStatus {
+            state: ClientState::of(now, last_sample),
+            last_sample,
+            staleness,
+            session_active,
+            cpu_avg_24h,
+            memory_avg_24h,
+        }
+    }
+}
+
+/// Status of every client by client id.
+pub async fn clients_status<'e, E: SqliteExecutor<'e>>(
+    executor: E,
+) -> sqlx::Result<HashMap<i64, ClientStatus>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT c.id AS "id!", unixepoch() AS "now!: i64",
+            (
+                SELECT MAX(d.sample_time) / 1000 FROM sessions s
+                JOIN session_data d ON d.session_id = s.id
+                WHERE s.client_id = c.id
+            ) AS "last_sample: i64",
+            EXISTS(
+                SELECT 1 FROM non_expired_sessions s WHERE s.client_id = c.id
+            ) AS "session_active!: bool",
+            (
+                SELECT AVG(COALESCE(
+                    a.cpu_usage,
+                    (SELECT AVG(cpu_usage) FROM session_data_cpu WHERE session_data_id = d.id)
+                ))
+                FROM sessions s
+                JOIN session_data d ON d.session_id = s.id
+                LEFT JOIN session_data_cpu_aggregate a ON a.session_data_id = d.id
+                WHERE s.client_id = c.id AND d.sample_time > unixepoch('now', '-1 day') * 1000
+            ) AS "cpu_avg_24h: f64",
+            (
+                SELECT AVG(CAST(m.used AS REAL) / m.total)
+                FROM sessions s
+                JOIN session_data d ON d.session_id = s.id
+                JOIN session_data_memory m ON m.session_data_id = d.id
+                WHERE s.client_id = c.id AND d.sample_time > unixepoch('now', '-1 day') * 1000
+                    AND m.total > 0
+            ) AS "memory_avg_24h: f64"
+        FROM clients c
+        "#
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| {
+            let status = ClientStatus::new(
+                r.now,
+                r.last_sample,
+                r.session_active,
+                r.cpu_avg_24h,
+                r.memory_avg_24h,
+            );
+            (r.id, status)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_state() {
+        let status = |last_sample| ClientStatus::new(1000, last_sample, false, None, None);
+
+        assert_eq!(status(None).state, ClientState::Never);
+        assert_eq!(status(None).staleness, None);
+        assert_eq!(status(Some(995)).state, ClientState::Up);
+        assert_eq!(status(Some(995)).staleness, Some(5));
+        assert_eq!(status(Some(900)).state, ClientState::Stale);
+        // clock skew between client and server
+        assert_eq!(status(Some(1010)).staleness, Some(0));
+    }
+}
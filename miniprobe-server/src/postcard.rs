@@ -5,10 +5,10 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use bytes::BytesMut;
+use miniprobe_proto::limits::POSTCARD_CONTENT_TYPE;
 use serde::{Serialize, de::DeserializeOwned};
 
 const MIME_POSTCARD: &str = "postcard";
-const MIME_APPLICATION_POSTCARD: &str = "application/postcard";
 
 /// Postcard Exractor / Response.
 #[derive(Debug, Clone, Copy, Default)]
@@ -95,7 +95,7 @@ where
 {
     /// Construct a `Postcard<T>` from a byte slice.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, PostcardRejection> {
-        match postcard::from_bytes(&*bytes) {
+        match postcard::from_bytes(bytes) {
             Ok(value) => Ok(Postcard(value)),
             Err(err) => Err(PostcardRejection::PostcardError(err)),
         }
@@ -124,7 +124,7 @@ where
                 Ok(buf) => (
                     [(
                         header::CONTENT_TYPE,
-                        HeaderValue::from_static(MIME_APPLICATION_POSTCARD),
+                        HeaderValue::from_static(POSTCARD_CONTENT_TYPE),
                     )],
                     buf.freeze(),
                 )
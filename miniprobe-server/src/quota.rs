@@ -0,0 +1,147 @@
+use confique::Config;
+use sqlx::SqliteExecutor;
+
+#[derive(Config, Debug)]
+pub struct QuotaConf {
+    /// Samples a client may store per day (UTC), unlimited if unset. Clients
+    /// can override it with `admin client quota`
+    pub samples_per_day: Option<u64>,
+
+    /// Size of the database in MiB after which ingest stops, unlimited if unset
+    pub max_db_size_mb: Option<u64>,
+}
+
+impl QuotaConf {
+    pub fn max_db_size(&self) -> Option<i64> {
+        self.max_db_size_mb.map(|mb| (mb * 1024 * 1024) as i64)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuotaExceeded {
+    #[error("daily quota of {0} samples exceeded")]
+    Samples(i64),
+    #[error("database size cap reached")]
+    DbSize,
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Daily sample quota of a client, counted in memory after loading the
+/// samples already stored today.
+#[derive(Debug)]
+pub struct ClientQuota {
+    limit: Option<i64>,
+    day: i64,
+    samples: i64,
+}
+
+impl ClientQuota {
+    /// Load the quota of a client.
+    pub async fn load<'e, E: SqliteExecutor<'e>>(
+        executor: E,
+        conf: &QuotaConf,
+        client_id: i64,
+    ) -> sqlx::Result<Self> {
+        let record = sqlx::query!(
+            r#"
+            SELECT c.samples_per_day,
+                (
+                    SELECT COUNT(*) FROM session_data d
+                    JOIN sessions s ON s.id = d.session_id
+                    WHERE s.client_id = c.id AND d.sample_time >= unixepoch('now', 'start of day') * 1000
+                ) AS "samples_today!: i64",
+                unixepoch('now') / 86400 AS "day!: i64"
+            FROM clients c
+            WHERE c.id = ?
+            "#,
+            client_id
+        )
+        .fetch_one(executor)
+        .await?;
+
+        Ok(ClientQuota {
+            limit: record
+                .samples_per_day
+                .or(conf.samples_per_day.map(|n| n as i64)),
+            day: record.day,
+            samples: record.samples_today,
+        })
+    }
+
+    /// Check whether one more sample fits and count it.
+    pub fn record(&mut self, now: i64) -> Result<(), QuotaExceeded> {
+        let day = now / SECONDS_PER_DAY;
+        if day != self.day {
+            self.day = day;
+            self.samples = 0;
+        }
+        self.check()?;
+        self.samples += 1;
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), QuotaExceeded> {
+        match self.limit {
+            Some(limit) if self.samples >= limit => Err(QuotaExceeded::Samples(limit)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Size of the main and the attached samples database in bytes.
+pub async fn db_size<'e, E: SqliteExecutor<'e>>(executor: E) -> sqlx::Result<i64> {
+    // not checked at compile time, the samples schema only exists when attached
+    sqlx::query_scalar(
+        "SELECT SUM(page_count * page_size) FROM ( \
+            SELECT * FROM pragma_page_count('main'), pragma_page_size('main') \
+            UNION ALL \
+            SELECT * FROM pragma_page_count('samples'), pragma_page_size('samples') \
+        )",
+    )
+    .fetch_one(executor)
+    .await
+}
+
+/// Whether the database grew beyond the configured cap.
+pub async fn db_size_exceeded<'e, E: SqliteExecutor<'e>>(
+    executor: E,
+    conf: &QuotaConf,
+) -> sqlx::Result<bool> {
+    match conf.max_db_size() {
+        Some(max) => Ok(db_size(executor).await? >= max),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daily_quota() {
+        let mut quota = ClientQuota {
+            limit: Some(2),
+            day: 0,
+            samples: 1,
+        };
+        assert!(quota.record(10).is_ok());
+        assert!(matches!(quota.record(20), Err(QuotaExceeded::Samples(2))));
+        // counting restarts the next day
+        assert!(quota.record(SECONDS_PER_DAY).is_ok());
+        assert!(quota.record(SECONDS_PER_DAY + 1).is_ok());
+        assert!(quota.record(SECONDS_PER_DAY + 2).is_err());
+    }
+
+    #[test]
+    fn unlimited_quota() {
+        let mut quota = ClientQuota {
+            limit: None,
+            day: 0,
+            samples: 0,
+        };
+        for t in 0..1000 {
+            assert!(quota.record(t).is_ok());
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Reboots of the hosts of clients, noticed when a session reports another
+//! boot id, or without boot ids a later boot time, than the session before it
+//! did.
+//!
+//! Counters start over and gaps open across a reboot, so query responses
+//! list the reboots they span next to the values.
+
+use sqlx::{SqliteConnection, SqliteExecutor};
+
+/// Seconds the boot time of a host may move without a reboot. It is derived
+/// from the uptime and moves along when the clock is adjusted.
+const BOOT_TIME_TOLERANCE_SECS: i64 = 60;
+
+/// A reboot between two sessions, boot times are unix timestamps in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reboot {
+    pub previous_boot_time: i64,
+    pub boot_time: i64,
+}
+
+/// The boot a session reported.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Boot {
+    /// Unix timestamp in seconds
+    pub time: Option<i64>,
+    pub id: Option<String>,
+}
+
+/// The reboot between a session reporting `previous` and one reporting
+/// `boot`. Boot ids tell for sure, without them the boot times must be
+/// further apart than the clock could have moved, an earlier boot time is an
+/// adjusted clock.
+fn reboot_between(previous: &Boot, boot: &Boot) -> Option<Reboot> {
+    let (previous_boot_time, boot_time) = (previous.time?, boot.time?);
+    let rebooted = match (&previous.id, &boot.id) {
+        (Some(previous_id), Some(id)) => previous_id != id,
+        _ => boot_time - previous_boot_time > BOOT_TIME_TOLERANCE_SECS,
+    };
+    rebooted.then_some(Reboot {
+        previous_boot_time,
+        boot_time,
+    })
+}
+
+/// The reboot of the host of `client_id` since its latest session reporting a
+/// boot time, if the new session reports `boot`.
+pub async fn detect(
+    conn: &mut SqliteConnection,
+    client_id: i64,
+    boot: &Boot,
+) -> sqlx::Result<Option<Reboot>> {
+    if boot.time.is_none() {
+        return Ok(None);
+    }
+    let previous = sqlx::query_as!(
+        Boot,
+        "SELECT boot_time AS time, boot_id AS id FROM sessions \
+            WHERE client_id = ? AND boot_time IS NOT NULL \
+            ORDER BY sessions.id DESC LIMIT 1",
+        client_id
+    )
+    .fetch_optional(conn)
+    .await?;
+    Ok(previous.and_then(|previous| reboot_between(&previous, boot)))
+}
+
+pub async fn record(
+    conn: &mut SqliteConnection,
+    client_id: i64,
+    session_id: i64,
+    reboot: Reboot,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "INSERT INTO reboots (client_id, session_id, boot_time, previous_boot_time) \
+            VALUES (?, ?, ?, ?)",
+        client_id,
+        session_id,
+        reboot.boot_time,
+        reboot.previous_boot_time
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Boot times of the reboots of `client_id` between `from` and `to`, unix
+/// timestamps in seconds, oldest first.
+pub async fn fetch_reboots<'e, E: SqliteExecutor<'e>>(
+    executor: E,
+    client_id: i64,
+    from: i64,
+    to: i64,
+) -> sqlx::Result<Vec<i64>> {
+    sqlx::query_scalar!(
+        "SELECT boot_time FROM reboots \
+            WHERE client_id = ? AND boot_time BETWEEN ? AND ? \
+            ORDER BY boot_time",
+        client_id,
+        from,
+        to
+    )
+    .fetch_all(executor)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot(time: Option<i64>, id: Option<&str>) -> Boot {
+        Boot {
+            time,
+            id: id.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn reboot_detection() {
+        let t = 1_700_000_000;
+        assert_eq!(
+            reboot_between(&boot(None, None), &boot(Some(t), None)),
+            None
+        );
+        assert_eq!(
+            reboot_between(&boot(Some(t), None), &boot(None, None)),
+            None
+        );
+        // clock adjustments
+        assert_eq!(
+            reboot_between(&boot(Some(t), None), &boot(Some(t + 2), None)),
+            None
+        );
+        assert_eq!(
+            reboot_between(&boot(Some(t), None), &boot(Some(t - 3600), None)),
+            None
+        );
+        assert_eq!(
+            reboot_between(&boot(Some(t), None), &boot(Some(t + 3600), None)),
+            Some(Reboot {
+                previous_boot_time: t,
+                boot_time: t + 3600,
+            })
+        );
+
+        // boot ids decide when both sessions have one
+        let (a, b) = (Some("a"), Some("b"));
+        assert_eq!(
+            reboot_between(&boot(Some(t), a), &boot(Some(t + 3600), a)),
+            None
+        );
+        assert_eq!(
+            reboot_between(&boot(Some(t), a), &boot(Some(t - 5), b)),
+            Some(Reboot {
+                previous_boot_time: t,
+                boot_time: t - 5,
+            })
+        );
+        assert!(reboot_between(&boot(Some(t), None), &boot(Some(t + 3600), b)).is_some());
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, OriginalUri},
+    http::{StatusCode, request::Parts},
+    response::{IntoResponse, Response},
+};
+use confique::Config;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    AppState,
+    security::{SecurityEvent, SecurityEventKind},
+};
+
+/// Networks clients may create sessions and send samples from. Connections
+/// over unix sockets, e.g. from a reverse proxy, are not checked.
+#[derive(Config, Debug)]
+pub struct AccessConf {
+    /// Networks in CIDR notation clients may connect from, e.g.
+    /// `["10.0.0.0/8", "2001:db8::/32"]`, any network if empty
+    #[config(default = [])]
+    pub allow: Vec<IpNet>,
+
+    /// Networks clients may not connect from, even if `allow` lists them
+    #[config(default = [])]
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessConf {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of an IPv6 socket show up as mapped addresses
+        let ip = ip.to_canonical();
+        (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+            && !self.deny.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// A network in CIDR notation, an address without a prefix length is a
+/// network of that address alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    /// Network address, the host bits are cleared
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask(u32::from(ip).into(), 32, self.prefix) == u32::from(net).into()
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(u128::from(ip), 128, self.prefix) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// `bits` with all but the first `prefix` of its `width` bits cleared.
+fn mask(bits: u128, width: u8, prefix: u8) -> u128 {
+    let host_bits = u32::from(width - prefix);
+    bits.checked_shr(host_bits)
+        .and_then(|bits| bits.checked_shl(host_bits))
+        .unwrap_or(0)
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid network '{s}', expected e.g. 10.0.0.0/8"))?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|&prefix| prefix <= width)
+                .ok_or_else(|| format!("invalid prefix length in '{s}'"))?,
+            None => width,
+        };
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                IpAddr::V4((mask(u32::from(addr).into(), 32, prefix) as u32).into())
+            }
+            IpAddr::V6(addr) => IpAddr::V6(mask(u128::from(addr), 128, prefix).into()),
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Address of the peer, `None` on unix sockets. IPv4 peers of an IPv6
+/// socket show up as IPv4 addresses.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddress(pub Option<IpAddr>);
+
+impl<S: Send + Sync> FromRequestParts<S> for PeerAddress {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        // only TCP listeners know the peer address
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>();
+        Ok(PeerAddress(
+            peer.map(|ConnectInfo(peer)| peer.ip().to_canonical()),
+        ))
+    }
+}
+
+/// Method and path of a request for the security events, the path as
+/// requested also under nested routers.
+pub(super) fn request_line(parts: &Parts) -> String {
+    let path = parts
+        .extensions
+        .get::<OriginalUri>()
+        .map_or(parts.uri.path(), |OriginalUri(uri)| uri.path());
+    format!("{} {path}", parts.method)
+}
+
+/// Extractor refusing clients from networks `access` does not permit,
+/// applied as a layer on the session and ingress routes.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAccess;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Clients may not connect from {0}")]
+pub struct AccessDenied(IpAddr);
+
+impl IntoResponse for AccessDenied {
+    fn into_response(self) -> Response {
+        (StatusCode::FORBIDDEN, self.to_string()).into_response()
+    }
+}
+
+impl FromRequestParts<AppState> for ClientAccess {
+    type Rejection = AccessDenied;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Ok(PeerAddress(Some(peer))) = PeerAddress::from_request_parts(parts, state).await
+        else {
+            return Ok(ClientAccess);
+        };
+        if !state.conf.access.permits(peer) {
+            debug!(%peer, "refusing client outside the allowed networks");
+            state.security.record(SecurityEvent::new(
+                SecurityEventKind::AccessDenied,
+                Some(peer),
+                format!("refused {}", request_line(parts)),
+            ));
+            return Err(AccessDenied(peer));
+        }
+        Ok(ClientAccess)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_networks() {
+        let net = "10.1.2.3/8".parse::<IpNet>().unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(net.contains("10.255.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(!net.contains("::a00:1".parse().unwrap()));
+
+        let host = "2001:db8::1".parse::<IpNet>().unwrap();
+        assert_eq!(host.to_string(), "2001:db8::1/128");
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+
+        let any = "0.0.0.0/0".parse::<IpNet>().unwrap();
+        assert!(any.contains("192.0.2.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert!("example.com".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let open = AccessConf {
+            allow: Vec::new(),
+            deny: Vec::new(),
+        };
+        assert!(open.permits("203.0.113.7".parse().unwrap()));
+
+        let conf = AccessConf {
+            allow: nets(&["10.0.0.0/8", "2001:db8::/32"]),
+            deny: nets(&["10.0.5.0/24"]),
+        };
+        assert!(conf.permits("10.0.4.1".parse().unwrap()));
+        assert!(!conf.permits("10.0.5.1".parse().unwrap()));
+        assert!(!conf.permits("192.168.1.1".parse().unwrap()));
+        assert!(conf.permits("2001:db8:1::1".parse().unwrap()));
+        // mapped by a dual stack socket
+        assert!(conf.permits("::ffff:10.0.4.1".parse().unwrap()));
+        assert!(!conf.permits("::ffff:10.0.5.1".parse().unwrap()));
+    }
+}
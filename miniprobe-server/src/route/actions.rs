@@ -0,0 +1,364 @@
+//! Remote actions: the admin API asks a connected client to run one of the
+//! actions it offered with `--action`, see `miniprobe_proto::msg::ACTIONS_PARAM`.
+//! What an action runs is only known to the client. Both sides opt in, and
+//! every request is kept in `client_actions` with its outcome and logged.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use confique::Config;
+use miniprobe_proto::msg::{ActionRequest, ActionResult, is_action_name, truncate_action_output};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+use crate::AppState;
+
+/// Remote actions, disabled by default.
+#[derive(Config, Debug)]
+pub struct ActionsConf {
+    /// Let `POST /api/v1/clients/{id}/actions/{name}` ask clients for the
+    /// actions they offer
+    #[config(default = false)]
+    pub enabled: bool,
+
+    /// Seconds to wait for the result of an action, the client kills it
+    /// after its own `--action-timeout`
+    #[config(default = 60)]
+    pub timeout: u64,
+}
+
+/// The names in an `actions` query parameter, invalid ones left out.
+pub fn parse_action_names(list: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for name in list.split(',').map(str::trim) {
+        if is_action_name(name) && !names.iter().any(|n| n == name) {
+            names.push(name.to_owned());
+        }
+    }
+    names
+}
+
+/// The actions offered by the connected clients, by client.
+#[derive(Debug, Clone, Default)]
+pub struct ActionChannels(Arc<Mutex<HashMap<i64, ActionTarget>>>);
+
+#[derive(Debug, Clone)]
+struct ActionTarget {
+    session_id: i64,
+    names: Vec<String>,
+    calls: mpsc::Sender<ActionCall>,
+}
+
+/// An action to ask the client for, and where its result goes.
+#[derive(Debug)]
+pub struct ActionCall {
+    request: ActionRequest,
+    reply: oneshot::Sender<ActionResult>,
+}
+
+impl ActionChannels {
+    /// Offer the actions `names` of a client until the returned value is
+    /// dropped, in place of those of an older connection.
+    pub fn offer(&self, client_id: i64, session_id: i64, names: Vec<String>) -> ClientActions {
+        let (tx, rx) = mpsc::channel(4);
+        self.lock().insert(
+            client_id,
+            ActionTarget {
+                session_id,
+                names,
+                calls: tx.clone(),
+            },
+        );
+        ClientActions {
+            channels: self.clone(),
+            client_id,
+            own: tx,
+            calls: rx,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn get(&self, client_id: i64) -> Option<ActionTarget> {
+        self.lock().get(&client_id).cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<i64, ActionTarget>> {
+        // the map stays consistent, a panic can not leave it half updated
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The actions offered on an ingress websocket, taken back when dropped.
+#[derive(Debug)]
+pub struct ClientActions {
+    channels: ActionChannels,
+    client_id: i64,
+    /// Tells the calls of this connection apart from those of a newer one
+    own: mpsc::Sender<ActionCall>,
+    /// Actions to ask the client for
+    pub calls: mpsc::Receiver<ActionCall>,
+    /// Replies to the actions asked for, by id
+    pending: HashMap<u64, oneshot::Sender<ActionResult>>,
+}
+
+impl ClientActions {
+    /// The request to send to the client, its result is expected in
+    /// [`finish`](Self::finish).
+    pub fn expect(&mut self, call: ActionCall) -> ActionRequest {
+        // replies given up on would pile up otherwise
+        self.pending.retain(|_, reply| !reply.is_closed());
+        self.pending.insert(call.request.id, call.reply);
+        call.request
+    }
+
+    /// Hand the result to the caller waiting for it, with the output cut to
+    /// `MAX_ACTION_OUTPUT` whatever the client sent.
+    pub fn finish(&mut self, mut result: ActionResult) {
+        truncate_action_output(&mut result.output);
+        match self.pending.remove(&result.id) {
+            Some(reply) => {
+                reply.send(result).ok();
+            }
+            None => debug!(id = result.id, "result of an action not asked for"),
+        }
+    }
+}
+
+impl Drop for ClientActions {
+    fn drop(&mut self) {
+        let mut channels = self.channels.lock();
+        if channels
+            .get(&self.client_id)
+            .is_some_and(|target| target.calls.same_channel(&self.own))
+        {
+            channels.remove(&self.client_id);
+        }
+    }
+}
+
+/// An action asked of a client, as kept in the audit log.
+#[derive(Debug, Serialize)]
+pub struct ActionRun {
+    pub id: i64,
+    /// `None` once the client was removed
+    pub client_id: Option<i64>,
+    pub client_name: String,
+    pub session_id: Option<i64>,
+    pub action: String,
+    /// Unix timestamp in seconds
+    pub requested_at: i64,
+    /// Unix timestamp in seconds the result arrived or was given up on
+    pub finished_at: Option<i64>,
+    /// Exit code of the command, `None` if it did not exit by itself
+    pub exit_code: Option<i64>,
+    /// Output of the command as sent by the client, cut off on the client
+    pub output: Option<String>,
+    /// Why the action did not run or finish
+    pub error: Option<String>,
+}
+
+/// Ask a connected client to run one of the actions it offered and wait for
+/// the result.
+pub async fn run_action(
+    State(state): State<AppState>,
+    Path((client_id, name)): Path<(i64, String)>,
+) -> Result<Json<ActionRun>, ActionError> {
+    if !state.conf.actions.enabled {
+        return Err(ActionError::Disabled);
+    }
+    let client_name = sqlx::query_scalar!("SELECT name FROM clients WHERE id = ?", client_id)
+        .fetch_optional(&state.db.reader)
+        .await?
+        .ok_or(ActionError::ClientNotFound)?;
+    let target = state
+        .actions
+        .get(client_id)
+        .ok_or(ActionError::NotConnected)?;
+    if !target.names.contains(&name) {
+        return Err(ActionError::NotOffered(name));
+    }
+
+    // a caller giving up does not leave the audit log unfinished
+    tokio::spawn(request_action(state, target, client_id, client_name, name))
+        .await
+        .map_err(|e| ActionError::Internal(e.into()))?
+}
+
+async fn request_action(
+    state: AppState,
+    target: ActionTarget,
+    client_id: i64,
+    client_name: String,
+    name: String,
+) -> Result<Json<ActionRun>, ActionError> {
+    let id = sqlx::query_scalar!(
+        "INSERT INTO client_actions (client_id, client_name, session_id, action) \
+            VALUES (?, ?, ?, ?) RETURNING id",
+        client_id,
+        client_name,
+        target.session_id,
+        name
+    )
+    .fetch_one(&state.db.writer)
+    .await?;
+    info!(
+        id,
+        client_id,
+        client_name,
+        action = name,
+        "asking client to run action"
+    );
+
+    let (reply, result) = oneshot::channel();
+    let call = ActionCall {
+        request: ActionRequest {
+            id: id as u64,
+            name: name.clone(),
+        },
+        reply,
+    };
+    let timeout = Duration::from_secs(state.conf.actions.timeout);
+    let outcome = match target.calls.send(call).await {
+        Ok(()) => match tokio::time::timeout(timeout, result).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err("connection lost before the result arrived".to_owned()),
+            Err(_) => Err(format!("no result after {} seconds", timeout.as_secs())),
+        },
+        Err(_) => Err("connection lost".to_owned()),
+    };
+
+    let (exit_code, output, error) = match &outcome {
+        Ok(result) => (
+            result.exit_code,
+            Some(&result.output),
+            result.error.as_ref(),
+        ),
+        Err(error) => (None, None, Some(error)),
+    };
+    match error {
+        None => info!(id, client_id, action = name, exit_code, "action finished"),
+        Some(error) => warn!(id, client_id, action = name, %error, "action failed"),
+    }
+    sqlx::query!(
+        "UPDATE client_actions SET finished_at = unixepoch(), exit_code = ?, output = ?, \
+            error = ? WHERE id = ?",
+        exit_code,
+        output,
+        error,
+        id
+    )
+    .execute(&state.db.writer)
+    .await?;
+
+    if let Err(error) = outcome {
+        return Err(ActionError::NoResult(error));
+    }
+    let run = sqlx::query_as!(ActionRun, "SELECT * FROM client_actions WHERE id = ?", id)
+        .fetch_one(&state.db.writer)
+        .await?;
+    Ok(Json(run))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ActionsParams {
+    /// Number of actions to return, newest first
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+fn default_limit() -> u32 {
+    20
+}
+
+/// The audit log of the actions asked of a client.
+pub async fn list_actions(
+    State(state): State<AppState>,
+    Path(client_id): Path<i64>,
+    Query(params): Query<ActionsParams>,
+) -> Result<Json<Vec<ActionRun>>, ActionError> {
+    let client = sqlx::query_scalar!("SELECT id FROM clients WHERE id = ?", client_id)
+        .fetch_optional(&state.db.reader)
+        .await?;
+    if client.is_none() {
+        return Err(ActionError::ClientNotFound);
+    }
+
+    let runs = sqlx::query_as!(
+        ActionRun,
+        "SELECT * FROM client_actions WHERE client_id = ? ORDER BY id DESC LIMIT ?",
+        client_id,
+        params.limit
+    )
+    .fetch_all(&state.db.reader)
+    .await?;
+    Ok(Json(runs))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ActionError {
+    #[error("Remote actions are disabled, enable them with `actions.enabled`")]
+    Disabled,
+    #[error("Client not found")]
+    ClientNotFound,
+    #[error("Client is not connected or offers no actions")]
+    NotConnected,
+    #[error("Client offers no action `{0}`")]
+    NotOffered(String),
+    #[error("No result: {0}")]
+    NoResult(String),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("Internal error: {0}")]
+    Internal(anyhow::Error),
+}
+
+impl IntoResponse for ActionError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ActionError::Disabled | ActionError::ClientNotFound | ActionError::NotOffered(_) => {
+                StatusCode::NOT_FOUND
+            }
+            ActionError::NotConnected => StatusCode::CONFLICT,
+            ActionError::NoResult(_) => StatusCode::GATEWAY_TIMEOUT,
+            ActionError::DatabaseError(_) | ActionError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn action_names() {
+        assert_eq!(
+            parse_action_names("restart-app, reload,restart-app,Bad Name,"),
+            ["restart-app", "reload"]
+        );
+        assert!(parse_action_names("").is_empty());
+    }
+
+    #[test]
+    fn newer_connection_keeps_its_actions() {
+        let channels = ActionChannels::default();
+        let older = channels.offer(1, 10, vec!["reload".to_owned()]);
+        let newer = channels.offer(1, 11, vec!["reload".to_owned()]);
+        drop(older);
+        assert_eq!(channels.get(1).map(|t| t.session_id), Some(11));
+        drop(newer);
+        assert!(channels.get(1).is_none());
+    }
+}
@@ -0,0 +1,75 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct AddressesParams {
+    /// Number of changes to return, newest first
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+fn default_limit() -> u32 {
+    20
+}
+
+/// Addresses the host of a client had from `changed_at` on.
+#[derive(Debug, Serialize)]
+pub struct AddressChange {
+    pub ipv4: Option<String>,
+    pub ipv6: Option<String>,
+    /// The session reporting the addresses
+    pub session_id: i64,
+    /// Unix timestamp in seconds of the sample reporting the addresses
+    pub changed_at: i64,
+}
+
+pub async fn list_addresses(
+    State(state): State<AppState>,
+    Path(client_id): Path<i64>,
+    Query(params): Query<AddressesParams>,
+) -> Result<Json<Vec<AddressChange>>, AddressesError> {
+    let client = sqlx::query_scalar!("SELECT id FROM clients WHERE id = ?", client_id)
+        .fetch_optional(&state.db.reader)
+        .await?;
+    if client.is_none() {
+        return Err(AddressesError::ClientNotFound);
+    }
+
+    let changes = sqlx::query_as!(
+        AddressChange,
+        "SELECT ipv4, ipv6, session_id, changed_at FROM address_changes \
+            WHERE client_id = ? \
+            ORDER BY id DESC \
+            LIMIT ?",
+        client_id,
+        params.limit
+    )
+    .fetch_all(&state.db.reader)
+    .await?;
+    Ok(Json(changes))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AddressesError {
+    #[error("Client not found")]
+    ClientNotFound,
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for AddressesError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AddressesError::ClientNotFound => StatusCode::NOT_FOUND,
+            AddressesError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,34 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+};
+use miniprobe_proto::msg::PROTOCOL_HEADER;
+
+/// What a client tells about itself in the headers of session creation and
+/// the ingress websocket, logged to tell clients apart behind proxies. Older
+/// clients send neither header.
+#[derive(Clone, Debug, Default)]
+pub struct ClientAgent {
+    /// e.g. `miniprobe-client/0.1.0 (linux; x86_64)`
+    pub user_agent: Option<String>,
+    pub protocol: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientAgent {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let header = |name| {
+            parts
+                .headers
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        Ok(ClientAgent {
+            user_agent: header(header::USER_AGENT.as_str()),
+            protocol: header(PROTOCOL_HEADER),
+        })
+    }
+}
@@ -0,0 +1,66 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use tracing::info;
+
+use crate::{
+    AppState,
+    alert::rules::{self, RuleError, RuleSpec, StoredRule},
+};
+
+/// Rules stored in the database, the rules of the config are not listed.
+pub async fn list_alert_rules(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<StoredRule>>, RuleError> {
+    Ok(Json(rules::list(&state.db.reader).await?))
+}
+
+pub async fn get_alert_rule(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<StoredRule>, RuleError> {
+    Ok(Json(rules::get(&state.db.reader, id).await?))
+}
+
+pub async fn create_alert_rule(
+    State(state): State<AppState>,
+    Json(spec): Json<RuleSpec>,
+) -> Result<(StatusCode, Json<StoredRule>), RuleError> {
+    let rule = rules::create(&state.db.writer, &spec, &state.conf.alerts.rules).await?;
+    info!(id = rule.id, name = rule.spec.name, "alert rule created");
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+pub async fn update_alert_rule(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(spec): Json<RuleSpec>,
+) -> Result<Json<StoredRule>, RuleError> {
+    let rule = rules::update(&state.db.writer, id, &spec, &state.conf.alerts.rules).await?;
+    info!(id, name = rule.spec.name, "alert rule updated");
+    Ok(Json(rule))
+}
+
+pub async fn delete_alert_rule(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, RuleError> {
+    rules::delete(&state.db.writer, id).await?;
+    info!(id, "alert rule deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+impl IntoResponse for RuleError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            RuleError::Invalid(_) | RuleError::InvalidExpr(_) => StatusCode::BAD_REQUEST,
+            RuleError::NameTaken(_) | RuleError::ConfigRule(_) => StatusCode::CONFLICT,
+            RuleError::NotFound(_) => StatusCode::NOT_FOUND,
+            RuleError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
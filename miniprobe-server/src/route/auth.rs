@@ -0,0 +1,160 @@
+use axum::{
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+    response::{IntoResponse, Response},
+};
+use axum_auth::AuthBearer;
+use sha2::{Digest, Sha256};
+
+use super::access::{PeerAddress, request_line};
+use crate::{
+    AppState,
+    security::{SecurityEvent, SecurityEventKind},
+};
+
+/// Extractor guarding the admin API with the configured `admin_token`, applied
+/// as a layer on the admin router.
+#[derive(Clone, Copy, Debug)]
+pub struct AdminAuth;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AdminAuthRejection {
+    #[error("Admin API is disabled")]
+    Disabled,
+    #[error("Too many failed authentications, try again later")]
+    LockedOut,
+    #[error("Invalid admin token")]
+    InvalidToken,
+    #[error("Auth error: {}", .0.1)]
+    BearerRejection(axum_auth::Rejection),
+}
+
+impl IntoResponse for AdminAuthRejection {
+    fn into_response(self) -> Response {
+        match self {
+            AdminAuthRejection::Disabled => {
+                (StatusCode::NOT_FOUND, self.to_string()).into_response()
+            }
+            AdminAuthRejection::LockedOut => {
+                (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response()
+            }
+            AdminAuthRejection::InvalidToken => {
+                (StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+            }
+            AdminAuthRejection::BearerRejection(inner) => inner.into_response(),
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for AdminAuth {
+    type Rejection = AdminAuthRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let expected = state
+            .conf
+            .admin_token
+            .as_deref()
+            .ok_or(AdminAuthRejection::Disabled)?;
+        let Ok(PeerAddress(peer)) = PeerAddress::from_request_parts(parts, state).await;
+        if state.security.is_locked_out(peer) {
+            return Err(AdminAuthRejection::LockedOut);
+        }
+
+        let AuthBearer(token) = AuthBearer::from_request_parts(parts, state)
+            .await
+            .map_err(AdminAuthRejection::BearerRejection)?;
+
+        if !token_matches(&token, expected) {
+            record_failure(state, peer, parts);
+            return Err(AdminAuthRejection::InvalidToken);
+        }
+
+        Ok(AdminAuth)
+    }
+}
+
+/// Extractor guarding the server info with the `status.token` or the
+/// `admin_token`, applied as a layer on the status routes.
+#[derive(Clone, Copy, Debug)]
+pub struct StatusAuth;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StatusAuthRejection {
+    #[error("Server info is disabled")]
+    Disabled,
+    #[error("Too many failed authentications, try again later")]
+    LockedOut,
+    #[error("Invalid token")]
+    InvalidToken,
+    #[error("Auth error: {}", .0.1)]
+    BearerRejection(axum_auth::Rejection),
+}
+
+impl IntoResponse for StatusAuthRejection {
+    fn into_response(self) -> Response {
+        match self {
+            StatusAuthRejection::Disabled => {
+                (StatusCode::NOT_FOUND, self.to_string()).into_response()
+            }
+            StatusAuthRejection::LockedOut => {
+                (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response()
+            }
+            StatusAuthRejection::InvalidToken => {
+                (StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+            }
+            StatusAuthRejection::BearerRejection(inner) => inner.into_response(),
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for StatusAuth {
+    type Rejection = StatusAuthRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let tokens = [
+            state.conf.status.token.as_deref(),
+            state.conf.admin_token.as_deref(),
+        ];
+        if tokens.iter().all(Option::is_none) {
+            return Err(StatusAuthRejection::Disabled);
+        }
+        let Ok(PeerAddress(peer)) = PeerAddress::from_request_parts(parts, state).await;
+        if state.security.is_locked_out(peer) {
+            return Err(StatusAuthRejection::LockedOut);
+        }
+
+        let AuthBearer(token) = AuthBearer::from_request_parts(parts, state)
+            .await
+            .map_err(StatusAuthRejection::BearerRejection)?;
+
+        if !tokens
+            .into_iter()
+            .flatten()
+            .any(|expected| token_matches(&token, expected))
+        {
+            record_failure(state, peer, parts);
+            return Err(StatusAuthRejection::InvalidToken);
+        }
+
+        Ok(StatusAuth)
+    }
+}
+
+fn record_failure(state: &AppState, peer: Option<std::net::IpAddr>, parts: &Parts) {
+    state.security.record(SecurityEvent::new(
+        SecurityEventKind::AdminAuthFailed,
+        peer,
+        format!("invalid token for {}", request_line(parts)),
+    ));
+}
+
+/// Compare digests so the comparison time does not leak the token.
+fn token_matches(token: &str, expected: &str) -> bool {
+    Sha256::digest(token) == Sha256::digest(expected)
+}
@@ -0,0 +1,114 @@
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::{
+    AppState,
+    labels::{self, Labels},
+    overview::{self, ClientStatus},
+};
+
+#[derive(Debug, Serialize)]
+pub struct ClientOverview {
+    pub id: i64,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub timezone: Option<String>,
+    pub location: Option<String>,
+    /// Hostname sessions of the client must come from, any if `None`
+    pub expected_hostname: Option<String>,
+    /// Where Wake-on-LAN packets for the client are sent to
+    pub mac_address: Option<String>,
+    /// Machine id the latest session reported, clients sharing one run on
+    /// the same host
+    pub machine_id: Option<String>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// End of the active silence as unix timestamp in seconds, if silenced
+    pub silenced_until: Option<i64>,
+    /// Samples stored since the start of the day (UTC)
+    pub samples_today: i64,
+    /// Daily sample quota in effect, unlimited if `None`
+    pub samples_per_day: Option<i64>,
+    /// Labels selecting the client in fleet queries, e.g. `env=prod`
+    pub labels: Labels,
+    #[serde(flatten)]
+    pub status: ClientStatus,
+}
+
+pub async fn list_clients(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ClientOverview>>, ClientsError> {
+    let clients = sqlx::query!(
+        r#"
+        SELECT c.id, c.name, c.display_name, c.timezone, c.location, c.expected_hostname,
+            c.mac_address, c.created_at,
+            (
+                SELECT MAX(s.ends_at) FROM silences s
+                WHERE (s.client_id = c.id OR s.client_id IS NULL)
+                    AND s.starts_at <= unixepoch() AND s.ends_at > unixepoch()
+            ) AS "silenced_until: i64",
+            (
+                SELECT COUNT(*) FROM session_data d
+                JOIN sessions s ON s.id = d.session_id
+                WHERE s.client_id = c.id AND d.sample_time >= unixepoch('now', 'start of day') * 1000
+            ) AS "samples_today!: i64",
+            (
+                SELECT s.machine_id FROM sessions s
+                WHERE s.client_id = c.id AND s.machine_id IS NOT NULL
+                ORDER BY s.id DESC LIMIT 1
+            ) AS machine_id,
+            c.samples_per_day
+        FROM clients c
+        ORDER BY c.id
+        "#
+    )
+    .fetch_all(&state.db.reader)
+    .await?;
+    let mut statuses = overview::clients_status(&state.db.reader).await?;
+    let mut labels = labels::load(&state.db.reader).await?;
+
+    let clients = clients
+        .into_iter()
+        // skip clients added in between the queries
+        .filter_map(|r| statuses.remove(&r.id).map(|status| (r, status)))
+        .map(|(r, status)| ClientOverview {
+            id: r.id,
+            name: r.name,
+            display_name: r.display_name,
+            timezone: r.timezone,
+            location: r.location,
+            expected_hostname: r.expected_hostname,
+            mac_address: r.mac_address,
+            machine_id: r.machine_id,
+            created_at: r.created_at.unix_timestamp(),
+            silenced_until: r.silenced_until,
+            samples_today: r.samples_today,
+            samples_per_day: r.samples_per_day.or(state
+                .conf
+                .quotas
+                .samples_per_day
+                .map(|n| n as i64)),
+            labels: labels.remove(&r.id).unwrap_or_default(),
+            status,
+        })
+        .collect();
+
+    Ok(Json(clients))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ClientsError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for ClientsError {
+    fn into_response(self) -> Response {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,71 @@
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderMap, header},
+    response::{IntoResponse, Response},
+};
+use miniprobe_proto::{
+    limits::MAX_BATCH_SIZE,
+    msg::{
+        Compression, Encoding, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, ServerCapabilities,
+        Transport,
+    },
+};
+
+use crate::{AppState, postcard::Postcard};
+
+/// Capabilities of this server, postcard encoded if the client accepts it.
+pub async fn well_known(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let mut encodings = vec![Encoding::Postcard];
+    if state.conf.json_ingress {
+        encodings.push(Encoding::Json);
+    }
+    let capabilities = ServerCapabilities {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        protocol_versions: (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).collect(),
+        transports: vec![Transport::Websocket],
+        encodings,
+        // other compressions are negotiated on the ingress websocket, clients
+        // predating them fail to decode capabilities listing them
+        compression: vec![Compression::Identity],
+        max_batch_size: Some(MAX_BATCH_SIZE),
+    };
+
+    if accepts_postcard(&headers) {
+        Postcard(capabilities).into_response()
+    } else {
+        Json(capabilities).into_response()
+    }
+}
+
+fn accepts_postcard(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .filter_map(|mime| mime.trim().parse::<mime::Mime>().ok())
+        .any(|mime| mime.type_() == mime::APPLICATION && mime.subtype() == "postcard")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn accept_header() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_postcard(&headers));
+
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/json, application/postcard;q=0.9"),
+        );
+        assert!(accepts_postcard(&headers));
+
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+        assert!(!accepts_postcard(&headers));
+    }
+}
@@ -0,0 +1,58 @@
+use axum::{
+    extract::{
+        State, WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    response::Response,
+};
+use futures_util::SinkExt;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{Instrument, debug, debug_span, warn};
+
+use crate::AppState;
+
+/// Stream alert, host and session state changes as JSON text frames.
+pub async fn events_ws(State(state): State<AppState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| handle_socket(socket, state).instrument(debug_span!("events_ws")))
+}
+
+async fn handle_socket(mut socket: WebSocket, state: AppState) {
+    let _tracker_token = state.ws_graceful_shutdown.tracker.token();
+    let cancellation_token = state.ws_graceful_shutdown.token.child_token();
+    let mut events = state.events.subscribe();
+    debug!("events subscriber connected");
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "events subscriber lagging behind, events dropped");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let text = match serde_json::to_string(&event) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        warn!("failed to serialize event: {e}");
+                        continue;
+                    }
+                };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => match msg {
+                // subscribers have nothing to say, only watch for the close
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = cancellation_token.cancelled() => break,
+        }
+    }
+
+    socket.close().await.ok();
+    debug!("events subscriber disconnected");
+}
@@ -0,0 +1,275 @@
+//! Queries aggregating an expression across a group of clients picked by a
+//! label selector, e.g. the total receive rate of `env=prod`.
+//!
+//! The expression is evaluated for every selected client as by `/query` and
+//! `/query_range`, then folded into one value per point in time.
+
+use axum::{
+    extract::{Query, RawQuery, State},
+    response::Response,
+};
+use miniprobe_proto::metrics_math;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    query::{Fill, QueryError, buckets, check_time, fill, now, with_timeout},
+    query_cache::Covers,
+};
+use crate::{
+    AppState,
+    db::InterruptibleConnection,
+    expr::{Expr, fetch_samples},
+    labels::{self, LabelSelector},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    /// Clients with a value
+    Count,
+}
+
+impl Aggregation {
+    /// `None` without values, except for `count`.
+    fn apply(self, values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return (self == Aggregation::Count).then_some(0.0);
+        }
+        let values = values.iter().copied();
+        match self {
+            Aggregation::Sum => Some(metrics_math::sum(values)),
+            Aggregation::Avg => metrics_math::mean(values),
+            Aggregation::Min => metrics_math::min(values),
+            Aggregation::Max => metrics_math::max(values),
+            Aggregation::Count => Some(values.len() as f64),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FleetQueryParams {
+    /// Expression to evaluate for every client, e.g. `rate(rx_bytes[5m])`
+    pub expr: String,
+    /// Clients to aggregate, e.g. `env=prod,role!=db`, every client if unset
+    #[serde(default)]
+    pub selector: LabelSelector,
+    pub agg: Aggregation,
+    /// Unix timestamp in seconds to evaluate at, defaults to now
+    pub time: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FleetQueryResponse {
+    pub time: i64,
+    pub selector: String,
+    pub agg: Aggregation,
+    /// `None` if no selected client has a value, except for `count`
+    pub value: Option<f64>,
+    /// Selected clients with a value
+    pub clients: Vec<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FleetQueryRangeParams {
+    /// Expression to evaluate for every client, e.g. `rate(rx_bytes[5m])`
+    pub expr: String,
+    /// Clients to aggregate, e.g. `env=prod,role!=db`, every client if unset
+    #[serde(default)]
+    pub selector: LabelSelector,
+    pub agg: Aggregation,
+    /// Unix timestamp in seconds the first bucket starts at
+    pub start: i64,
+    /// Unix timestamp in seconds the last bucket reaches, defaults to now
+    pub end: Option<i64>,
+    /// Bucket width in seconds
+    pub step: i64,
+    /// Value of the buckets of a client without samples, before aggregating
+    #[serde(default)]
+    pub fill: Fill,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FleetQueryRangeResponse {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+    pub selector: String,
+    pub agg: Aggregation,
+    /// Start of each bucket `[t, t + step)`
+    pub buckets: Vec<i64>,
+    /// Aggregated value of each bucket
+    pub values: Vec<Option<f64>>,
+    /// Clients with a value in each bucket
+    pub counts: Vec<usize>,
+    /// Selected clients with samples in range
+    pub clients: Vec<i64>,
+}
+
+/// Ids of the clients `selector` matches.
+async fn select_clients(
+    conn: &mut InterruptibleConnection,
+    selector: &LabelSelector,
+) -> sqlx::Result<Vec<i64>> {
+    let ids = sqlx::query_scalar!(r#"SELECT id AS "id!" FROM clients ORDER BY id"#)
+        .fetch_all(&mut **conn)
+        .await?;
+    let labels = labels::load(&mut **conn).await?;
+    Ok(ids
+        .into_iter()
+        .filter(|id| selector.matches(labels.get(id)))
+        .collect())
+}
+
+pub async fn fleet_query(
+    State(state): State<AppState>,
+    RawQuery(raw): RawQuery,
+    Query(params): Query<FleetQueryParams>,
+) -> Result<Response, QueryError> {
+    let expr: Expr = params.expr.parse()?;
+    let time = check_time("time", params.time.unwrap_or_else(now))?;
+    let covers = Covers {
+        client: None,
+        from: time - expr.lookback(),
+        to: time,
+    };
+    state
+        .query_cache
+        .get_or_make(
+            &state.conf.query_cache,
+            "fleet_query",
+            raw,
+            covers,
+            with_timeout(&state, eval_fleet_query(&state, &params, &expr, time)),
+        )
+        .await
+}
+
+async fn eval_fleet_query(
+    state: &AppState,
+    params: &FleetQueryParams,
+    expr: &Expr,
+    time: i64,
+) -> Result<FleetQueryResponse, QueryError> {
+    let mut values = Vec::new();
+    let mut clients = Vec::new();
+    let mut conn = state.db.interruptible_reader().await?;
+    for client_id in select_clients(&mut conn, &params.selector).await? {
+        let samples = fetch_samples(&mut conn, client_id, time - expr.lookback(), time).await?;
+        if let Some(value) = expr.eval(&samples, time) {
+            values.push(value);
+            clients.push(client_id);
+        }
+    }
+
+    Ok(FleetQueryResponse {
+        time,
+        selector: params.selector.to_string(),
+        agg: params.agg,
+        value: params.agg.apply(&values),
+        clients,
+    })
+}
+
+/// Evaluate an expression in regular buckets for every selected client and
+/// aggregate each bucket.
+pub async fn fleet_query_range(
+    State(state): State<AppState>,
+    RawQuery(raw): RawQuery,
+    Query(params): Query<FleetQueryRangeParams>,
+) -> Result<Response, QueryError> {
+    let expr: Expr = params.expr.parse()?;
+    let end = params.end.unwrap_or_else(now);
+    let buckets = buckets(params.start, end, params.step)?;
+    let covers = Covers {
+        client: None,
+        from: params.start - expr.lookback().max(params.step),
+        to: end,
+    };
+    state
+        .query_cache
+        .get_or_make(
+            &state.conf.query_cache,
+            "fleet_query_range",
+            raw,
+            covers,
+            with_timeout(
+                &state,
+                eval_fleet_query_range(&state, &params, &expr, end, buckets),
+            ),
+        )
+        .await
+}
+
+async fn eval_fleet_query_range(
+    state: &AppState,
+    params: &FleetQueryRangeParams,
+    expr: &Expr,
+    end: i64,
+    buckets: Vec<i64>,
+) -> Result<FleetQueryRangeResponse, QueryError> {
+    let lookback = expr.lookback().max(params.step);
+    // values of every bucket across the clients
+    let mut bucket_values = vec![Vec::new(); buckets.len()];
+    let mut clients = Vec::new();
+    let mut conn = state.db.interruptible_reader().await?;
+    for client_id in select_clients(&mut conn, &params.selector).await? {
+        let samples = fetch_samples(&mut conn, client_id, params.start - lookback, end).await?;
+        if samples.is_empty() {
+            continue;
+        }
+
+        let mut values: Vec<_> = buckets
+            .iter()
+            .map(|start| expr.eval_bucket(&samples, start + params.step, params.step))
+            .collect();
+        fill(&mut values, params.fill);
+        for (bucket, value) in bucket_values.iter_mut().zip(values) {
+            bucket.extend(value);
+        }
+        clients.push(client_id);
+    }
+
+    Ok(FleetQueryRangeResponse {
+        start: params.start,
+        end,
+        step: params.step,
+        selector: params.selector.to_string(),
+        agg: params.agg,
+        values: bucket_values
+            .iter()
+            .map(|values| params.agg.apply(values))
+            .collect(),
+        counts: bucket_values.iter().map(Vec::len).collect(),
+        buckets,
+        clients,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregations() {
+        let values = [1.0, 4.0, 2.5];
+        assert_eq!(Aggregation::Sum.apply(&values), Some(7.5));
+        assert_eq!(Aggregation::Avg.apply(&values), Some(2.5));
+        assert_eq!(Aggregation::Min.apply(&values), Some(1.0));
+        assert_eq!(Aggregation::Max.apply(&values), Some(4.0));
+        assert_eq!(Aggregation::Count.apply(&values), Some(3.0));
+
+        for agg in [
+            Aggregation::Sum,
+            Aggregation::Avg,
+            Aggregation::Min,
+            Aggregation::Max,
+        ] {
+            assert_eq!(agg.apply(&[]), None);
+        }
+        assert_eq!(Aggregation::Count.apply(&[]), Some(0.0));
+    }
+}
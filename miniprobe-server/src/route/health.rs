@@ -0,0 +1,129 @@
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use confique::Config;
+use serde::Serialize;
+
+use super::auth::{StatusAuth, StatusAuthRejection};
+use crate::AppState;
+
+/// Who may read the health details and the server info.
+#[derive(Config, Debug)]
+pub struct StatusConf {
+    /// Bearer token granting `/health` details and `/api/v1/server/*` next
+    /// to the admin token, e.g. for monitoring that should not hold the admin
+    /// token
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    /// `ok`, or `unavailable` while the database is unreachable or the server
+    /// is in maintenance
+    pub status: &'static str,
+    pub version: &'static str,
+    /// `ok` or the error reaching the database
+    pub database: String,
+    pub maintenance: bool,
+}
+
+/// `200` if the server takes samples, `503` otherwise.
+///
+/// Only requests bearing the `status.token` or the admin token get the
+/// details, others get the bare status code, so neither the version nor the
+/// database error leaks.
+pub async fn health(
+    State(state): State<AppState>,
+    auth: Result<StatusAuth, StatusAuthRejection>,
+) -> Response {
+    let database = sqlx::query!("SELECT 1 AS ok")
+        .fetch_one(&state.db.reader)
+        .await;
+    let maintenance = state.maintenance.borrow().enabled;
+    let healthy = database.is_ok() && !maintenance;
+    let status = match healthy {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    if auth.is_err() {
+        return status.into_response();
+    }
+
+    let health = Health {
+        status: if healthy { "ok" } else { "unavailable" },
+        version: env!("CARGO_PKG_VERSION"),
+        database: match database {
+            Ok(_) => "ok".to_owned(),
+            Err(e) => e.to_string(),
+        },
+        maintenance,
+    };
+    (status, Json(health)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, header::AUTHORIZATION},
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{app, listen::Routes, testing};
+
+    async fn get(state: &AppState, token: Option<&str>) -> (StatusCode, Option<serde_json::Value>) {
+        let mut req = Request::builder().uri("/health");
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let res = app(state.clone(), Routes::All)
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        (
+            status,
+            (!body.is_empty()).then(|| serde_json::from_slice(&body).unwrap()),
+        )
+    }
+
+    #[tokio::test]
+    async fn details_need_a_token() {
+        let conf = testing::conf("admin_token = \"admin\"\n[status]\ntoken = \"status\"\n");
+        let state = testing::state("health-details", conf).await;
+
+        assert_eq!(get(&state, None).await, (StatusCode::OK, None));
+        assert_eq!(get(&state, Some("wrong")).await, (StatusCode::OK, None));
+        for token in ["status", "admin"] {
+            let (status, health) = get(&state, Some(token)).await;
+            assert_eq!(status, StatusCode::OK);
+            let health = health.expect("no details");
+            assert_eq!(health["status"], "ok");
+            assert_eq!(health["database"], "ok");
+            assert_eq!(health["maintenance"], false);
+        }
+    }
+
+    #[tokio::test]
+    async fn unavailable_in_maintenance() {
+        let conf = testing::conf("[status]\ntoken = \"status\"\n");
+        let state = testing::state("health-maintenance", conf).await;
+        state.maintenance.send_modify(|m| m.enabled = true);
+
+        assert_eq!(
+            get(&state, None).await,
+            (StatusCode::SERVICE_UNAVAILABLE, None)
+        );
+        let (status, health) = get(&state, Some("status")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let health = health.expect("no details");
+        assert_eq!(health["status"], "unavailable");
+        assert_eq!(health["maintenance"], true);
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeSet;
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use miniprobe_proto::ListeningSocket;
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct ListenersParams {
+    /// Number of snapshots to return, newest first
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+fn default_limit() -> u32 {
+    10
+}
+
+/// Listening sockets reported by a client at one point in time.
+#[derive(Debug, Serialize)]
+pub struct ListenerSnapshot {
+    /// Unix timestamp in seconds
+    pub sample_time: i64,
+    pub listeners: Vec<ListeningSocket>,
+    /// Sockets not in the previous snapshot, `None` for the first one
+    pub added: Option<Vec<ListeningSocket>>,
+    /// Sockets of the previous snapshot that are gone
+    pub removed: Option<Vec<ListeningSocket>>,
+}
+
+pub async fn list_listeners(
+    State(state): State<AppState>,
+    Path(client_id): Path<i64>,
+    Query(params): Query<ListenersParams>,
+) -> Result<Json<Vec<ListenerSnapshot>>, ListenersError> {
+    let client = sqlx::query_scalar!("SELECT id FROM clients WHERE id = ?", client_id)
+        .fetch_optional(&state.db.reader)
+        .await?;
+    if client.is_none() {
+        return Err(ListenersError::ClientNotFound);
+    }
+
+    // one more than asked for, to tell what changed in the oldest snapshot
+    let fetch = params.limit as i64 + 1;
+    let rows = sqlx::query!(
+        r#"
+        SELECT d.sample_time, l.listeners
+        FROM session_data_listeners l
+        JOIN session_data d ON d.id = l.session_data_id
+        JOIN sessions s ON s.id = d.session_id
+        WHERE s.client_id = ?
+        ORDER BY d.sample_time DESC, d.id DESC
+        LIMIT ?
+        "#,
+        client_id,
+        fetch
+    )
+    .fetch_all(&state.db.reader)
+    .await?;
+
+    let snapshots = rows
+        .into_iter()
+        .map(|r| {
+            serde_json::from_str::<Vec<ListeningSocket>>(&r.listeners)
+                .map(|listeners| (r.sample_time / 1000, listeners))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut result = Vec::with_capacity(snapshots.len());
+    for (i, (sample_time, listeners)) in snapshots.iter().enumerate().take(params.limit as usize) {
+        let (added, removed) = match snapshots.get(i + 1) {
+            Some((_, previous)) => {
+                let (added, removed) = diff(previous, listeners);
+                (Some(added), Some(removed))
+            }
+            None => (None, None),
+        };
+        result.push(ListenerSnapshot {
+            sample_time: *sample_time,
+            listeners: listeners.clone(),
+            added,
+            removed,
+        });
+    }
+
+    Ok(Json(result))
+}
+
+/// Sockets added to and removed from `previous`.
+fn diff(
+    previous: &[ListeningSocket],
+    current: &[ListeningSocket],
+) -> (Vec<ListeningSocket>, Vec<ListeningSocket>) {
+    let previous = previous.iter().collect::<BTreeSet<_>>();
+    let current = current.iter().collect::<BTreeSet<_>>();
+    (
+        current.difference(&previous).map(|&s| s.clone()).collect(),
+        previous.difference(&current).map(|&s| s.clone()).collect(),
+    )
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ListenersError {
+    #[error("Client not found")]
+    ClientNotFound,
+    #[error("Invalid stored listeners: {0}")]
+    InvalidListeners(#[from] serde_json::Error),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for ListenersError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ListenersError::ClientNotFound => StatusCode::NOT_FOUND,
+            ListenersError::InvalidListeners(_) | ListenersError::DatabaseError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miniprobe_proto::SocketProtocol;
+
+    use super::*;
+
+    fn socket(port: u16) -> ListeningSocket {
+        ListeningSocket {
+            protocol: SocketProtocol::Tcp,
+            address: "0.0.0.0".parse().unwrap(),
+            port,
+            process: None,
+        }
+    }
+
+    #[test]
+    fn listener_diff() {
+        let (added, removed) = diff(&[socket(22), socket(80)], &[socket(22), socket(443)]);
+        assert_eq!(added, [socket(443)]);
+        assert_eq!(removed, [socket(80)]);
+
+        let (added, removed) = diff(&[socket(22)], &[socket(22)]);
+        assert!(added.is_empty() && removed.is_empty());
+    }
+}
@@ -0,0 +1,58 @@
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+use crate::AppState;
+
+/// Handle swapping the tracing filter of the running server.
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogLevel {
+    /// Filter in `RUST_LOG` syntax, e.g. `miniprobe_server=debug,tower_http=info`
+    pub filter: String,
+}
+
+pub async fn get_log_level(State(state): State<AppState>) -> Result<Json<LogLevel>, LogLevelError> {
+    let filter = state.log_filter.with_current(|filter| filter.to_string())?;
+    Ok(Json(LogLevel { filter }))
+}
+
+pub async fn set_log_level(
+    State(state): State<AppState>,
+    Json(LogLevel { filter }): Json<LogLevel>,
+) -> Result<Json<LogLevel>, LogLevelError> {
+    let new_filter = EnvFilter::try_new(&filter)?;
+    state.log_filter.reload(new_filter)?;
+    info!(filter, "log filter changed");
+
+    let filter = state.log_filter.with_current(|filter| filter.to_string())?;
+    Ok(Json(LogLevel { filter }))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum LogLevelError {
+    #[error("Invalid filter: {0}")]
+    InvalidFilter(#[from] tracing_subscriber::filter::ParseError),
+    #[error("Reload error: {0}")]
+    ReloadError(#[from] reload::Error),
+}
+
+impl IntoResponse for LogLevelError {
+    fn into_response(self) -> Response {
+        match self {
+            LogLevelError::InvalidFilter(_) => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            LogLevelError::ReloadError(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use axum::{
+    Json,
+    extract::{FromRequestParts, State},
+    http::{StatusCode, header, request::Parts},
+    response::{IntoResponse, Response},
+};
+use confique::Config;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::AppState;
+
+/// Maintenance mode at startup, toggled at runtime with
+/// `PUT /api/v1/admin/maintenance` until the next restart.
+#[derive(Config, Debug)]
+pub struct MaintenanceConf {
+    /// Refuse new sessions with `503` and close the ingress websockets, e.g.
+    /// while migrating the database
+    #[config(default = false)]
+    pub enabled: bool,
+
+    /// Seconds clients are asked to wait before reconnecting
+    #[config(default = 60)]
+    pub retry_after: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Maintenance {
+    pub enabled: bool,
+    /// Seconds clients are asked to wait before reconnecting
+    pub retry_after: u64,
+}
+
+impl From<&MaintenanceConf> for Maintenance {
+    fn from(conf: &MaintenanceConf) -> Self {
+        Maintenance {
+            enabled: conf.enabled,
+            retry_after: conf.retry_after,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetMaintenance {
+    pub enabled: bool,
+    /// Keeps the current value if unset
+    pub retry_after: Option<u64>,
+}
+
+pub async fn get_maintenance(State(state): State<AppState>) -> Json<Maintenance> {
+    Json(*state.maintenance.borrow())
+}
+
+/// Entering maintenance closes every ingress websocket, leaving it lets
+/// clients back in once their wait is over.
+pub async fn set_maintenance(
+    State(state): State<AppState>,
+    Json(req): Json<SetMaintenance>,
+) -> Json<Maintenance> {
+    state.maintenance.send_modify(|maintenance| {
+        maintenance.enabled = req.enabled;
+        if let Some(retry_after) = req.retry_after {
+            maintenance.retry_after = retry_after;
+        }
+    });
+    let maintenance = *state.maintenance.borrow();
+    info!(
+        enabled = maintenance.enabled,
+        retry_after = maintenance.retry_after,
+        "maintenance mode changed"
+    );
+    Json(maintenance)
+}
+
+/// Extractor refusing clients while in maintenance, applied as a layer on
+/// the session and ingress routes.
+#[derive(Clone, Copy, Debug)]
+pub struct Available;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Server is in maintenance, retry after {0} seconds")]
+pub struct InMaintenance(u64);
+
+impl IntoResponse for InMaintenance {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, self.0.to_string())],
+            self.to_string(),
+        )
+            .into_response()
+    }
+}
+
+impl FromRequestParts<AppState> for Available {
+    type Rejection = InMaintenance;
+
+    async fn from_request_parts(
+        _parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let maintenance = *state.maintenance.borrow();
+        match maintenance.enabled {
+            true => Err(InMaintenance(maintenance.retry_after)),
+            false => Ok(Available),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+/// Slow down factors never exceed this.
+const MAX_FACTOR: f32 = 8.0;
+/// Weight of the latest write duration in the moving average.
+const EWMA_WEIGHT: f64 = 0.3;
+
+/// Compares how long storing a sample takes with the scrape interval and
+/// derives how much the client should stretch its interval.
+///
+/// Writing may take up to half of the interval, beyond that the factor grows
+/// with the moving average of write durations.
+#[derive(Debug)]
+pub struct Backpressure {
+    budget: f64,
+    ewma: Option<f64>,
+    factor: f32,
+}
+
+impl Backpressure {
+    pub fn new(scrape_interval: Duration) -> Self {
+        Backpressure {
+            budget: scrape_interval.as_secs_f64() / 2.0,
+            ewma: None,
+            factor: 1.0,
+        }
+    }
+
+    /// Record how long a write took, returns the new factor if it changed.
+    pub fn record(&mut self, elapsed: Duration) -> Option<f32> {
+        let elapsed = elapsed.as_secs_f64();
+        let ewma = match self.ewma {
+            Some(ewma) => ewma + EWMA_WEIGHT * (elapsed - ewma),
+            None => elapsed,
+        };
+        self.ewma = Some(ewma);
+
+        let factor = ((ewma / self.budget).ceil() as f32).clamp(1.0, MAX_FACTOR);
+        if factor == self.factor {
+            return None;
+        }
+        self.factor = factor;
+        Some(factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fast_writes_keep_interval() {
+        let mut bp = Backpressure::new(Duration::from_secs(4));
+        for _ in 0..10 {
+            assert_eq!(bp.record(Duration::from_millis(50)), None);
+        }
+    }
+
+    #[test]
+    fn slow_writes_slow_down_and_recover() {
+        let mut bp = Backpressure::new(Duration::from_secs(4));
+
+        // writes taking 5s against a 2s budget
+        assert_eq!(bp.record(Duration::from_secs(5)), Some(3.0));
+        assert_eq!(bp.record(Duration::from_secs(5)), None);
+
+        let mut factor = 3.0;
+        for _ in 0..20 {
+            if let Some(f) = bp.record(Duration::from_millis(10)) {
+                assert!(f < factor);
+                factor = f;
+            }
+        }
+        assert_eq!(factor, 1.0);
+    }
+
+    #[test]
+    fn factor_is_capped() {
+        let mut bp = Backpressure::new(Duration::from_secs(1));
+        assert_eq!(bp.record(Duration::from_secs(60)), Some(MAX_FACTOR));
+    }
+}
@@ -1,33 +1,193 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
+use bytes::BytesMut;
 use futures_util::SinkExt;
-use miniprobe_proto::DynamicMetrics;
+use miniprobe_proto::{
+    CpuReport, CpuReportPolicy, DynamicMetrics, MetricsBatch, UnixMillis,
+    codec::Decoder,
+    limits::MAX_BATCH_SIZE,
+    msg::{
+        ActionResult, CLOSE_MAINTENANCE, CLOSE_TAKEN_OVER, Compression, IngressAck, IngressControl,
+        maintenance_close_reason,
+    },
+    validate::Validate,
+};
 use sqlx::SqlitePool;
+use tokio::sync::{Notify, watch};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, trace};
+use tracing::{Instrument, debug, debug_span, info, trace, warn};
+
+use super::{IngressConflict, IngressParams, backpressure::Backpressure};
+use crate::{
+    AppState, Conf, SCRAPE_INTERVAL, addresses,
+    events::{Event, SessionState},
+    latency::LatencyRecorder,
+    quota::{self, ClientQuota, QuotaExceeded},
+    route::{ClientActions, Maintenance, QueryCache, sessions::SessionLock},
+    security::{SecurityEvent, SecurityEventKind, SecurityLog},
+    sink::{Ingested, MetricsSink, Sink},
+};
+
+/// How long a connection taking over waits for the older one to let go.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a connection that was taken over tries to say goodbye, it is
+/// likely half-dead.
+const TAKEN_OVER_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How far a sample may be ahead of the server clock before the ack warns
+/// about it.
+const MAX_CLOCK_AHEAD: i64 = 60;
+/// Most warnings in one ack, the rest are dropped.
+const MAX_ACK_WARNINGS: usize = 16;
+/// How long the size of the database is trusted before it is queried again,
+/// rather than for every sample.
+const DB_SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Sample times below this are in seconds, sent by clients predating
+/// milliseconds: as milliseconds it is in 1973, as seconds in the year 5138.
+const LEGACY_SECONDS_BELOW: u64 = 100_000_000_000;
+/// Longest reason of a close frame, the payload of a control frame is at most
+/// 125 bytes including the code.
+const MAX_CLOSE_REASON: usize = 123;
 
-use crate::{AppState, route::sessions::SessionLock};
-pub async fn handle_socket<'a>(
+pub async fn handle_socket(
     mut socket: WebSocket,
     state: AppState,
     SessionLock(session): SessionLock,
+    params: IngressParams,
+    compression: Compression,
+    network_delta: bool,
+    actions: Vec<String>,
 ) {
     let _tracker_token = state.ws_graceful_shutdown.tracker.token();
     let cancellation_token = state.ws_graceful_shutdown.token.child_token().child_token();
 
-    let session = session.try_own();
+    let session = match state.conf.ingress_conflict {
+        IngressConflict::Reject => session.try_own(),
+        IngressConflict::Takeover => {
+            if session.is_owned() {
+                debug!("taking over the websocket connection of the session");
+            }
+            tokio::time::timeout(TAKEOVER_TIMEOUT, session.take_over())
+                .await
+                .ok()
+        }
+    };
 
     match session {
         Some(session) => {
-            let session_id = session.read().await.id;
-            debug!("websocket connected");
+            let (session_id, client_id) = {
+                let session = session.read().await;
+                (session.id, session.client_id)
+            };
+            let setup = async {
+                let quota =
+                    ClientQuota::load(&state.db.reader, &state.conf.quotas, client_id).await?;
+                let sink = Sink::open(&state, session_id, client_id).await?;
+                let decoder = Decoder::new(compression)?;
+                let session = sqlx::query!(
+                    r#"SELECT s.closed_at IS NOT NULL AS "resumed!: bool", c.name
+                        FROM sessions s JOIN clients c ON c.id = s.client_id
+                        WHERE s.id = ?"#,
+                    session_id
+                )
+                .fetch_one(&state.db.writer)
+                .await?;
+                // the close of an earlier connection no longer applies
+                let (compression, precision) = (compression.as_str(), params.precision());
+                sqlx::query!(
+                    "UPDATE sessions SET closed_at = NULL, closed_by = NULL, close_code = NULL, \
+                        close_reason = NULL, compression = ?, precision = ? WHERE id = ?",
+                    compression,
+                    precision,
+                    session_id
+                )
+                .execute(&state.db.writer)
+                .await?;
+                anyhow::Ok((quota, sink, decoder, session.name, session.resumed))
+            };
+            let (quota, sink, decoder, client_name, resumed) = match setup.await {
+                Ok(setup) => setup,
+                Err(e) => {
+                    let frame = IngressWsError::Internal(e).into_close_frame();
+                    Closed::server(&frame)
+                        .record(&state.db.writer, session_id)
+                        .await;
+                    socket.send(Message::Close(frame)).await.ok();
+                    socket.close().await.ok();
+                    return;
+                }
+            };
+            debug!(
+                resumed,
+                compression = compression.as_str(),
+                network_delta,
+                "websocket connected"
+            );
+            let event = |state, closed: Option<&Closed>| Event::Session {
+                state,
+                session_id,
+                client_id,
+                client_name: client_name.clone(),
+                closed_by: closed.map(|c| c.by.as_str().to_owned()),
+                close_code: closed.map(|c| c.code),
+                close_reason: closed.map(|c| c.reason.clone()),
+                time: UnixMillis::now().as_secs() as i64,
+            };
+            // sending only fails without subscribers
+            if resumed {
+                state.events.send(event(SessionState::Resumed, None)).ok();
+            }
             let mut controller = IngressController {
-                db: state.pool.clone(),
+                db: state.db.writer.clone(),
                 ws: socket,
                 cancellation_token,
+                preempted: session.preempted(),
                 session_id,
+                client_id,
+                conf: state.conf.clone(),
+                sink,
+                decoder,
+                network_delta,
+                unrecorded_bytes: (0, 0),
+                backpressure: Backpressure::new(SCRAPE_INTERVAL),
+                quota,
+                alert_trigger: state.alert_trigger.clone(),
+                samples_stored: state.samples_stored.clone(),
+                query_cache: state.query_cache.clone(),
+                security: state.security.clone(),
+                latency: state.latency.clone(),
+                maintenance: state.maintenance.subscribe(),
+                batch: params.batch,
+                json: false,
+                ack_every: params.ack,
+                unacked: 0,
+                ack: IngressAck::default(),
+                actions: (!actions.is_empty())
+                    .then(|| state.actions.offer(client_id, session_id, actions)),
+                db_size: None,
+                closed: None,
             };
 
             while controller.next().await {}
+            // a connection gone without a close frame, recorded before a
+            // connection taking over starts
+            let closed = controller.closed.take().unwrap_or(Closed {
+                by: ClosedBy::Client,
+                code: close_code::ABNORMAL,
+                reason: "connection lost".to_owned(),
+            });
+            closed.record(&state.db.writer, session_id).await;
+            state
+                .events
+                .send(event(SessionState::Ended, Some(&closed)))
+                .ok();
+            // taken back before a connection taking over offers its own
+            controller.actions.take();
+            // let a connection taking over proceed right away
+            drop(session);
             controller.ws.close().await.ok();
             debug!("websocket disconnected");
         }
@@ -48,7 +208,44 @@ struct IngressController {
     db: SqlitePool,
     ws: WebSocket,
     cancellation_token: CancellationToken,
+    /// A newer connection of the session takes over
+    preempted: CancellationToken,
     session_id: i64,
+    client_id: i64,
+    conf: Arc<Conf>,
+    sink: Sink,
+    /// Decompresses binary messages with the negotiated compression
+    decoder: Decoder,
+    /// The client sends network counters as deltas
+    network_delta: bool,
+    /// Bytes of messages as received and decompressed, not yet added to the
+    /// stats of the session
+    unrecorded_bytes: (i64, i64),
+    backpressure: Backpressure,
+    quota: ClientQuota,
+    alert_trigger: Arc<Notify>,
+    samples_stored: watch::Sender<()>,
+    query_cache: QueryCache,
+    security: SecurityLog,
+    latency: LatencyRecorder,
+    /// Closes the websocket once maintenance starts
+    maintenance: watch::Receiver<Maintenance>,
+    /// Every message carries a `MetricsBatch`
+    batch: bool,
+    /// The client sends JSON text frames, see `Conf::json_ingress`
+    json: bool,
+    /// Send an ack every this many messages, never if 0
+    ack_every: u32,
+    /// Messages since the last ack
+    unacked: u32,
+    /// Outcome of those messages
+    ack: IngressAck,
+    /// Actions the client offered, its text frames carry their results
+    actions: Option<ClientActions>,
+    /// When the database size was last checked and whether it was exceeded
+    db_size: Option<(Instant, bool)>,
+    /// The first close frame sent or received
+    closed: Option<Closed>,
 }
 
 impl IngressController {
@@ -64,17 +261,37 @@ impl IngressController {
             }
             _ => {}
         }
+        self.closed.get_or_insert_with(|| Closed::server(&msg));
         self.ws.send(Message::Close(msg)).await?;
         Ok(())
     }
 
+    /// Send a control message in the encoding the client used last.
+    async fn send_control(&mut self, msg: IngressControl) -> anyhow::Result<()> {
+        let msg = if self.json {
+            Message::Text(serde_json::to_string(&msg)?.into())
+        } else {
+            Message::Binary(postcard::to_extend(&msg, BytesMut::new())?.freeze())
+        };
+        self.ws.send(msg).await?;
+        Ok(())
+    }
+
     async fn next(&mut self) -> bool {
+        let mut maintenance = self.maintenance.clone();
+        let calls = self.actions.as_mut().map(|actions| &mut actions.calls);
         tokio::select! {
             msg = self.ws.recv() => {
                 let msg = match msg {
                     Some(Ok(m)) => m,
                     Some(Err(e)) => {
-                        let reason = IngressWsError::Internal(e.to_string());
+                        // most likely the connection was lost
+                        self.closed.get_or_insert_with(|| Closed {
+                            by: ClosedBy::Client,
+                            code: close_code::ABNORMAL,
+                            reason: e.to_string(),
+                        });
+                        let reason = IngressWsError::Internal(e.into());
                         self.close(reason).await.ok();
                         return false;
                     }
@@ -84,38 +301,116 @@ impl IngressController {
                 };
 
                 if let Err(e) = self.process_msg(msg).await {
+                    if let IngressWsError::QuotaExceeded(quota @ QuotaExceeded::Samples(_)) = &e {
+                        self.security.record(
+                            SecurityEvent::new(SecurityEventKind::QuotaExceeded, None, quota.to_string())
+                                .client(self.client_id, None),
+                        );
+                    }
                     self.close(e).await.ok();
                     return false;
                 }
-                return true;
+                true
             }
             _ = self.cancellation_token.cancelled() => {
                 self.close(IngressWsError::Shutdown).await.ok();
-                return false;
+                false
+            }
+            // the value is copied out, the borrow of the watch is not `Send`
+            Ok(retry_after) = async {
+                maintenance.wait_for(|m| m.enabled).await.map(|m| m.retry_after)
+            } => {
+                self.close(IngressWsError::Maintenance(retry_after)).await.ok();
+                false
+            }
+            _ = self.preempted.cancelled() => {
+                tokio::time::timeout(TAKEN_OVER_CLOSE_TIMEOUT, self.close(IngressWsError::TakenOver))
+                    .await
+                    .ok();
+                false
+            }
+            Some(call) = async {
+                match calls {
+                    Some(calls) => calls.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let Some(actions) = &mut self.actions else {
+                    return true;
+                };
+                let request = actions.expect(call);
+                debug!(id = request.id, action = request.name, "asking client to run action");
+                if let Err(e) = self.send_control(IngressControl::RunAction(request)).await {
+                    self.close(IngressWsError::Internal(e)).await.ok();
+                    return false;
+                }
+                true
             }
         }
     }
 
     async fn process_msg(&mut self, msg: Message) -> Result<(), IngressWsError> {
         match msg {
-            Message::Close(Some(CloseFrame { code, reason })) => {
-                trace!(
-                    code,
-                    %reason,
-                    "websocket closed with frame"
-                );
+            Message::Close(frame) => {
+                if let Some(CloseFrame { code, reason }) = &frame {
+                    trace!(
+                        code,
+                        %reason,
+                        "websocket closed with frame"
+                    );
+                }
+                self.closed.get_or_insert_with(|| Closed {
+                    by: ClosedBy::Client,
+                    code: frame.as_ref().map_or(close_code::STATUS, |f| f.code),
+                    reason: frame.map(|f| f.reason.to_string()).unwrap_or_default(),
+                });
             }
+            // samples borrow their strings from the message, a single sample
+            // is not wrapped into a batch
             Message::Binary(bytes) => {
                 trace!("received binary: {:?}", String::from_utf8_lossy(&bytes));
+                let wire_len = bytes.len();
 
-                let metrics: DynamicMetrics = postcard::from_bytes(&bytes)
-                    .map_err(|e| IngressWsError::Internal(e.to_string()))?;
+                self.json = false;
+                let bytes = self
+                    .decoder
+                    .decode(&bytes)
+                    .map_err(|e| IngressWsError::InvalidMetrics(e.to_string()))?;
+                self.unrecorded_bytes.0 += wire_len as i64;
+                self.unrecorded_bytes.1 += bytes.len() as i64;
+                let batch = decode_binary(&bytes, self.batch).map_err(anyhow::Error::from)?;
+                trace!("decoded into metrics: {:?}", batch);
+                self.ingest_batch(batch).await?;
+                self.acknowledge().await?;
+            }
+            // results of actions take the text frames of connections offering them
+            Message::Text(text) if self.actions.is_some() => {
+                trace!("received text: {text}");
 
-                trace!("decoded into metrics: {:?}", metrics);
+                let result = serde_json::from_str::<ActionResult>(&text)
+                    .map_err(|_| IngressWsError::UnexpectedMessage)?;
+                if let Some(actions) = &mut self.actions {
+                    actions.finish(result);
+                }
+            }
+            Message::Text(text) if self.conf.json_ingress => {
+                trace!("received text: {text}");
 
-                self.write_metrics_to_db(metrics)
-                    .await
-                    .map_err(|e| IngressWsError::Internal(e.to_string()))?;
+                self.json = true;
+                self.unrecorded_bytes.0 += text.len() as i64;
+                self.unrecorded_bytes.1 += text.len() as i64;
+                if self.batch {
+                    let batch = serde_json::from_str::<MetricsBatch>(&text)
+                        .map_err(|e| IngressWsError::InvalidMetrics(e.to_string()))?;
+                    trace!("decoded into metrics: {:?}", batch);
+                    self.ingest_batch(batch).await?;
+                } else {
+                    let metrics = serde_json::from_str::<DynamicMetrics>(&text)
+                        .map_err(|e| IngressWsError::InvalidMetrics(e.to_string()))?;
+                    trace!("decoded into metrics: {:?}", metrics);
+                    self.ingest_batch([metrics]).await?;
+                }
+                self.acknowledge().await?;
             }
             Message::Text(_) => {
                 return Err(IngressWsError::UnexpectedMessage);
@@ -125,88 +420,235 @@ impl IngressController {
         Ok(())
     }
 
-    async fn write_metrics_to_db(&mut self, metrics: DynamicMetrics) -> anyhow::Result<()> {
-        let mut tx = self.db.begin().await?;
-        let sample_time = metrics.sample_time as i64; // will overflow in 2038, but who cares
-
-        let session_data_id = sqlx::query!(
-            r#"
-            INSERT INTO session_data (session_id, sample_time)
-            VALUES (?, ?)
-            RETURNING id
-            "#,
-            self.session_id,
-            sample_time,
+    async fn ingest_batch<'a, I>(&mut self, batch: I) -> Result<(), IngressWsError>
+    where
+        I: IntoIterator<Item = DynamicMetrics<'a>>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let batch = batch.into_iter();
+        if batch.len() > MAX_BATCH_SIZE as usize {
+            return Err(IngressWsError::InvalidMetrics(format!(
+                "batch of {} samples, at most {MAX_BATCH_SIZE} allowed",
+                batch.len()
+            )));
+        }
+        for metrics in batch {
+            self.ingest(metrics).await?;
+        }
+        let (wire_bytes, decoded_bytes) = std::mem::take(&mut self.unrecorded_bytes);
+        sqlx::query!(
+            "UPDATE sessions SET last_active = unixepoch('now'), \
+                wire_bytes = wire_bytes + ?, decoded_bytes = decoded_bytes + ? WHERE id = ?",
+            wire_bytes,
+            decoded_bytes,
+            self.session_id
         )
-        .fetch_one(&mut *tx)
-        .await?
-        .id;
-
-        // cpu metrics
-        for (i, cpu_metric) in metrics.cpu.into_iter().enumerate() {
-            let i = i as i64;
-            sqlx::query!(
-                r#"
-                INSERT INTO session_data_cpu (session_data_id, cpu_id, cpu_usage)
-                VALUES (?, ?, ?)
-                "#,
-                session_data_id,
-                i,
-                cpu_metric.usage,
-            )
-            .execute(&mut *tx)
-            .await?;
+        .execute(&self.db)
+        .await
+        .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Send the ack of the messages so far once `ack_every` are due.
+    async fn acknowledge(&mut self) -> Result<(), IngressWsError> {
+        if self.ack_every == 0 {
+            return Ok(());
+        }
+        self.unacked += 1;
+        if self.unacked < self.ack_every {
+            return Ok(());
         }
+        self.unacked = 0;
+        let ack = std::mem::take(&mut self.ack);
+        Ok(self.send_control(IngressControl::Ack(ack)).await?)
+    }
 
-        // memory metrics
+    /// Report a problem with the samples in the next ack.
+    fn warn(&mut self, warning: String) {
+        if self.ack_every > 0
+            && self.ack.warnings.len() < MAX_ACK_WARNINGS
+            && !self.ack.warnings.contains(&warning)
         {
-            // will someone use that much memory? I doubt it.
-            let (total, used) = (metrics.memory.total as i64, metrics.memory.used as i64);
-            let (swap_total, swap_used) = (
-                metrics.memory.swap_total as i64,
-                metrics.memory.swap_used as i64,
-            );
-            sqlx::query!(
-                r#"
-                INSERT INTO session_data_memory (session_data_id, total, used, swap_total, swap_used)
-                VALUES (?, ?, ?, ?, ?)
-                "#,
-                session_data_id,
-                total,
-                used,
-                swap_total,
-                swap_used,
-            )
-            .execute(&mut *tx)
-            .await?;
+            self.ack.warnings.push(warning);
         }
+    }
 
-        // network metrics
+    /// Whether the database grew beyond its cap, queried at most every
+    /// `DB_SIZE_CHECK_INTERVAL`.
+    async fn db_size_exceeded(&mut self) -> anyhow::Result<bool> {
+        if let Some((checked, exceeded)) = self.db_size
+            && checked.elapsed() < DB_SIZE_CHECK_INTERVAL
         {
-            let (rx_bytes, tx_bytes) = (
-                metrics.network.rx_bytes.map(|i| i as i64),
-                metrics.network.tx_bytes.map(|i| i as i64),
+            return Ok(exceeded);
+        }
+        let exceeded = quota::db_size_exceeded(&self.db, &self.conf.quotas).await?;
+        self.db_size = Some((Instant::now(), exceeded));
+        Ok(exceeded)
+    }
+
+    async fn ingest(&mut self, metrics: DynamicMetrics<'_>) -> Result<(), IngressWsError> {
+        let (arrived, received_at) = (Instant::now(), UnixMillis::now());
+        let now = received_at.as_secs() as i64;
+        if self.db_size_exceeded().await? {
+            return Err(QuotaExceeded::DbSize.into());
+        }
+        // dropped rather than refused, the client would send it again, and
+        // not counted against the quota as nothing is stored
+        if let Err(e) = metrics.validate() {
+            debug!(
+                seq = metrics.seq,
+                field = e.field,
+                value = e.value,
+                range = e.range,
+                "sample dropped, out of range"
             );
+            self.warn(format!("sample dropped: {e}"));
+            self.ack.last_seq = Some(metrics.seq);
+            return Ok(());
+        }
+        self.quota.record(now)?;
 
-            sqlx::query!(
-                r#"
-                INSERT INTO session_data_network (session_data_id, ifname, rx_bytes, tx_bytes)
-                VALUES (?, ?, ?, ?)
-                "#,
-                session_data_id,
-                metrics.network.ifname,
-                rx_bytes,
-                tx_bytes,
-            )
-            .execute(&mut *tx)
+        let sample_time = match metrics.sample_time {
+            UnixMillis(secs) if secs < LEGACY_SECONDS_BELOW => UnixMillis::from_secs(secs),
+            sample_time => sample_time,
+        };
+        let ahead = sample_time.as_secs() as i64 - now;
+        if ahead > MAX_CLOCK_AHEAD {
+            self.warn(format!(
+                "sample time is {ahead} seconds ahead of the server clock"
+            ));
+        }
+        let cpu = match metrics.cpu {
+            // fold per-core reports if the server enforces aggregates
+            CpuReport::PerCore(cores)
+                if self.conf.cpu_report == Some(CpuReportPolicy::Aggregate) =>
+            {
+                self.warn("per-core CPU report folded into an aggregate".to_owned());
+                CpuReport::aggregate(&cores)
+            }
+            cpu => cpu,
+        };
+        let sample = Ingested {
+            client_id: self.client_id,
+            session_id: self.session_id,
+            received_at: received_at.0 as i64,
+            network_delta: self.network_delta,
+            metrics: DynamicMetrics {
+                sample_time,
+                cpu,
+                ..metrics
+            },
+        };
+
+        let started = Instant::now();
+        let urgent = sample.metrics.urgent;
+        let (seq, sample_time) = (sample.metrics.seq, sample.metrics.sample_time);
+        let addresses = sample.metrics.addresses.clone();
+        self.sink
+            .write(sample)
+            .instrument(debug_span!("store", seq))
             .await?;
+        if let Some(addresses) = &addresses {
+            let changed_at = sample_time.as_secs() as i64;
+            let changed = addresses::record(
+                &self.db,
+                self.client_id,
+                self.session_id,
+                changed_at,
+                addresses,
+            )
+            .await
+            .map_err(anyhow::Error::from)?;
+            if changed {
+                info!(ipv4 = ?addresses.ipv4, ipv6 = ?addresses.ipv6, "host addresses changed");
+            }
+        }
+        self.samples_stored.send_replace(());
+        self.query_cache
+            .invalidate(self.client_id, sample_time.as_secs() as i64);
+        self.latency
+            .record(sample_time, received_at, arrived.elapsed());
+        self.ack.stored += 1;
+        self.ack.last_seq = Some(seq);
+        self.ack.sample_time = Some(sample_time);
+        if urgent {
+            self.alert_trigger.notify_one();
         }
 
-        tx.commit().await?;
+        if let Some(factor) = self.backpressure.record(started.elapsed()) {
+            debug!(factor, "asking client to slow down");
+            self.send_control(IngressControl::SlowDown { factor })
+                .await?;
+        }
         Ok(())
     }
 }
 
+/// Samples of a decompressed binary message, a single sample is not wrapped
+/// into a batch. The samples borrow their strings from `bytes`.
+fn decode_binary(bytes: &[u8], batch: bool) -> postcard::Result<MetricsBatch<'_>> {
+    if batch {
+        postcard::from_bytes(bytes)
+    } else {
+        postcard::from_bytes::<DynamicMetrics>(bytes).map(|metrics| vec![metrics])
+    }
+}
+
+/// Which side of an ingress websocket closed it first.
+#[derive(Debug, Clone, Copy)]
+enum ClosedBy {
+    Client,
+    Server,
+}
+
+impl ClosedBy {
+    fn as_str(self) -> &'static str {
+        match self {
+            ClosedBy::Client => "client",
+            ClosedBy::Server => "server",
+        }
+    }
+}
+
+/// How an ingress websocket was closed, kept with its session to tell why a
+/// client disconnected without digging through the logs.
+#[derive(Debug)]
+struct Closed {
+    by: ClosedBy,
+    code: u16,
+    reason: String,
+}
+
+impl Closed {
+    fn server(frame: &Option<CloseFrame>) -> Self {
+        Closed {
+            by: ClosedBy::Server,
+            code: frame.as_ref().map_or(close_code::STATUS, |f| f.code),
+            reason: frame
+                .as_ref()
+                .map(|f| f.reason.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    async fn record(&self, db: &SqlitePool, session_id: i64) {
+        let by = self.by.as_str();
+        let res = sqlx::query!(
+            "UPDATE sessions SET closed_at = unixepoch('now'), closed_by = ?, close_code = ?, \
+                close_reason = ? WHERE id = ?",
+            by,
+            self.code,
+            self.reason,
+            session_id
+        )
+        .execute(db)
+        .await;
+        if let Err(e) = res {
+            warn!(session_id, "failed to record how the websocket closed: {e}");
+        }
+    }
+}
+
 trait IntoCloseFrame {
     fn into_close_frame(self) -> Option<CloseFrame>;
 }
@@ -223,10 +665,18 @@ enum IngressWsError {
     SessionMutexPoisoned,
     #[error("server is shutting down")]
     Shutdown,
+    #[error("session taken over by a newer connection")]
+    TakenOver,
+    #[error("server in maintenance")]
+    Maintenance(u64),
     #[error("unexpected message from client")]
     UnexpectedMessage,
+    #[error("invalid metrics: {0}")]
+    InvalidMetrics(String),
+    #[error(transparent)]
+    QuotaExceeded(#[from] QuotaExceeded),
     #[error("internal error: {0}")]
-    Internal(String),
+    Internal(#[from] anyhow::Error),
 }
 
 impl IntoCloseFrame for IngressWsError {
@@ -240,14 +690,457 @@ impl IntoCloseFrame for IngressWsError {
                 code: close_code::AWAY,
                 reason: "server shutting down".into(),
             },
+            IngressWsError::TakenOver => CloseFrame {
+                code: CLOSE_TAKEN_OVER,
+                reason: "session taken over by a newer connection".into(),
+            },
+            IngressWsError::Maintenance(retry_after) => CloseFrame {
+                code: CLOSE_MAINTENANCE,
+                reason: maintenance_close_reason(retry_after).into(),
+            },
             IngressWsError::UnexpectedMessage => CloseFrame {
                 code: close_code::UNSUPPORTED,
                 reason: "unexpected message from client".into(),
             },
+            IngressWsError::InvalidMetrics(reason) => CloseFrame {
+                code: close_code::INVALID,
+                reason: close_reason(format!("invalid metrics: {}", reason)).into(),
+            },
+            IngressWsError::QuotaExceeded(e @ QuotaExceeded::Samples(_)) => CloseFrame {
+                code: close_code::POLICY,
+                reason: e.to_string().into(),
+            },
+            IngressWsError::QuotaExceeded(e @ QuotaExceeded::DbSize) => CloseFrame {
+                code: close_code::AGAIN,
+                reason: e.to_string().into(),
+            },
             IngressWsError::Internal(reason) => CloseFrame {
                 code: close_code::ERROR,
-                reason: format!("internal error: {}", reason).into(),
+                reason: close_reason(format!("internal error: {}", reason)).into(),
             },
         })
     }
 }
+
+/// Cut `reason` to [`MAX_CLOSE_REASON`] bytes on a character boundary, errors
+/// of serde quote the message they failed on.
+fn close_reason(mut reason: String) -> String {
+    if reason.len() > MAX_CLOSE_REASON {
+        let mut end = MAX_CLOSE_REASON;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+    }
+    reason
+}
+
+/// Messages of clients are attacker-controlled until the token is checked,
+/// and the token is short, so whatever bytes a client sends may fail to
+/// decode or store but never take the server down.
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, net::SocketAddr};
+
+    use futures_util::StreamExt;
+    use miniprobe_proto::msg::SessionToken;
+    use miniprobe_proto::{
+        BatteryMetrics, BatteryState, ClockMetrics, CpuMetrics, DiskMetrics, FdMetrics,
+        MemoryMetrics, NetworkMetrics, ProbeSelfMetrics, ProcessMetrics, Section, SensorMetrics,
+        ServiceMetrics, ServiceState,
+    };
+    use proptest::{collection::vec, option, prelude::*, sample::subsequence};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{
+        MaybeTlsStream, WebSocketStream,
+        tungstenite::{self, client::IntoClientRequest, http::header::AUTHORIZATION},
+    };
+
+    use super::*;
+    use crate::{db::Db, sink::SqliteSink, testing};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// Both databases in memory, `name` keeps tests running at once apart.
+    async fn memory_db(name: &str) -> Db {
+        let db = Db::connect(
+            &format!("sqlite:file:{name}?mode=memory&cache=shared"),
+            &format!("sqlite:file:{name}-samples?mode=memory&cache=shared"),
+            1,
+            None,
+        )
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    fn any_text() -> impl Strategy<Value = Cow<'static, str>> {
+        "\\PC{0,24}".prop_map(Cow::Owned)
+    }
+
+    fn any_cpu() -> impl Strategy<Value = CpuReport> {
+        prop_oneof![
+            vec(any::<f32>(), 0..8).prop_map(|cores| CpuReport::PerCore(
+                cores
+                    .into_iter()
+                    .map(|usage| CpuMetrics { usage })
+                    .collect()
+            )),
+            (any::<f32>(), any::<f32>())
+                .prop_map(|(usage, max_core)| CpuReport::Aggregate { usage, max_core }),
+        ]
+    }
+
+    fn any_network() -> impl Strategy<Value = NetworkMetrics<'static>> {
+        (
+            any_text(),
+            option::of(any::<u64>()),
+            option::of(any::<u64>()),
+        )
+            .prop_map(|(ifname, rx_bytes, tx_bytes)| NetworkMetrics {
+                ifname,
+                rx_bytes,
+                tx_bytes,
+            })
+    }
+
+    fn any_disk() -> impl Strategy<Value = DiskMetrics<'static>> {
+        (
+            any_text(),
+            any::<[u64; 2]>(),
+            option::of(any::<u64>()),
+            option::of(any::<u64>()),
+        )
+            .prop_map(
+                |(mount_point, [total, available], read_bytes, written_bytes)| DiskMetrics {
+                    mount_point,
+                    total,
+                    available,
+                    read_bytes,
+                    written_bytes,
+                },
+            )
+    }
+
+    fn any_service() -> impl Strategy<Value = ServiceMetrics<'static>> {
+        let state = prop_oneof![
+            Just(ServiceState::Active),
+            Just(ServiceState::Failed),
+            Just(ServiceState::Unknown),
+        ];
+        (any_text(), state, option::of(any::<u32>())).prop_map(|(name, state, restarts)| {
+            ServiceMetrics {
+                name,
+                state,
+                restarts,
+            }
+        })
+    }
+
+    /// Samples with values no probe would report: NaN usage, counters near
+    /// `u64::MAX`, odd strings and sections missing at random.
+    fn any_metrics() -> impl Strategy<Value = DynamicMetrics<'static>> {
+        let sections = subsequence(
+            vec![
+                Section::Cpu,
+                Section::Memory,
+                Section::Network,
+                Section::Sensors,
+                Section::Processes,
+                Section::Fds,
+                Section::Clock,
+                Section::Battery,
+                Section::Services,
+                Section::Listeners,
+                Section::Disks,
+            ],
+            0..=3,
+        );
+        (
+            (any::<u64>(), any::<u64>(), any_cpu(), any::<[u64; 4]>()),
+            vec(any_network(), 0..3),
+            (option::of(any::<f32>()), option::of(any::<u64>())),
+            (
+                any::<u64>(),
+                option::of(any::<f32>()),
+                option::of(any::<u64>()),
+            ),
+            option::of((any::<u32>(), option::of(any::<u32>()), any::<u32>())),
+            option::of((
+                any::<[u64; 2]>(),
+                option::of(any::<u32>()),
+                option::of(any::<u32>()),
+            )),
+            option::of((any::<bool>(), option::of(any::<f64>()))),
+            option::of((any::<f32>(), option::of(any::<f32>()))),
+            (
+                vec(any_disk(), 0..3),
+                vec(any_service(), 0..4),
+                any::<bool>(),
+                sections,
+            ),
+        )
+            .prop_map(
+                |(
+                    (seq, sample_time, cpu, [total, used, swap_total, swap_used]),
+                    networks,
+                    (cpu_temperature, cpu_frequency),
+                    (collection_time, probe_cpu, rss),
+                    processes,
+                    fds,
+                    clock,
+                    battery,
+                    (disks, services, urgent, missing_sections),
+                )| DynamicMetrics {
+                    seq,
+                    sample_time: UnixMillis(sample_time),
+                    cpu,
+                    memory: MemoryMetrics {
+                        total,
+                        used,
+                        swap_total,
+                        swap_used,
+                    },
+                    networks,
+                    disks,
+                    sensors: SensorMetrics {
+                        cpu_temperature,
+                        cpu_frequency,
+                    },
+                    probe: ProbeSelfMetrics {
+                        collection_time,
+                        cpu_usage: probe_cpu,
+                        rss,
+                    },
+                    processes: processes.map(|(processes, threads, zombies)| ProcessMetrics {
+                        processes,
+                        threads,
+                        zombies,
+                    }),
+                    fds: fds.map(|([open, max], tcp_established, tcp_time_wait)| FdMetrics {
+                        open,
+                        max,
+                        tcp_established,
+                        tcp_time_wait,
+                    }),
+                    clock: clock.map(|(synchronized, offset)| ClockMetrics {
+                        synchronized,
+                        offset,
+                    }),
+                    battery: battery.map(|(capacity, power)| BatteryMetrics {
+                        capacity,
+                        state: BatteryState::Discharging,
+                        power,
+                    }),
+                    services,
+                    listeners: None,
+                    addresses: None,
+                    urgent,
+                    missing_sections,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_frames(bytes in vec(any::<u8>(), 0..4096), batch in any::<bool>()) {
+            for compression in Compression::supported() {
+                let mut decoder = Decoder::new(compression).unwrap();
+                if let Ok(bytes) = decoder.decode(&bytes) {
+                    decode_binary(&bytes, batch).ok();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutated_frames() {
+        let rt = runtime();
+        let db = rt.block_on(memory_db("ingress-mutated-frames"));
+
+        proptest!(ProptestConfig::with_cases(128), |(
+            batch in vec(any_metrics(), 1..4),
+            flips in vec((any::<prop::sample::Index>(), any::<u8>()), 0..8),
+            truncate in option::of(any::<prop::sample::Index>()),
+        )| {
+            let mut bytes = postcard::to_extend(&batch, Vec::new()).unwrap();
+            for (index, byte) in flips {
+                let index = index.index(bytes.len());
+                bytes[index] ^= byte;
+            }
+            if let Some(index) = truncate {
+                bytes.truncate(index.index(bytes.len()));
+            }
+
+            // storing may fail on a bogus sample, it must not panic
+            if let Ok(samples) = decode_binary(&bytes, true) {
+                let mut sink = SqliteSink::new(db.writer.clone());
+                rt.block_on(async {
+                    for metrics in samples {
+                        sink.write(Ingested {
+                            client_id: 1,
+                            session_id: 1,
+                            received_at: 0,
+                            network_delta: false,
+                            metrics,
+                        })
+                        .await
+                        .ok();
+                    }
+                });
+            }
+        });
+
+        // the database is still usable after whatever was thrown at it
+        let stored = rt.block_on(async {
+            sqlx::query_scalar!("SELECT COUNT(*) FROM session_data")
+                .fetch_one(&db.reader)
+                .await
+        });
+        assert!(stored.is_ok());
+    }
+
+    #[test]
+    fn extreme_values_store() {
+        let rt = runtime();
+        let db = rt.block_on(memory_db("ingress-extreme-values"));
+
+        proptest!(ProptestConfig::with_cases(128), |(metrics in any_metrics())| {
+            let mut sink = SqliteSink::new(db.writer.clone());
+            let bytes = postcard::to_extend(&metrics, Vec::new()).unwrap();
+            let samples = decode_binary(&bytes, false).unwrap();
+            prop_assert_eq!(samples.len(), 1);
+            for metrics in samples {
+                let res = rt.block_on(sink.write(Ingested {
+                    client_id: 1,
+                    session_id: 1,
+                    received_at: 0,
+                    network_delta: false,
+                    metrics,
+                }));
+                prop_assert!(res.is_ok(), "failed to store a valid sample: {:?}", res);
+            }
+        });
+    }
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Connect to the ingress websocket as the session of `token`.
+    async fn connect(addr: SocketAddr, token: &SessionToken, query: &str) -> Client {
+        let mut req = format!("ws://{addr}/ws/v1/metrics/ingress?{query}")
+            .into_client_request()
+            .unwrap();
+        req.headers_mut()
+            .insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        tokio_tungstenite::connect_async(req).await.unwrap().0
+    }
+
+    /// The next text or close frame of the server.
+    async fn next_frame(ws: &mut Client) -> tungstenite::Message {
+        loop {
+            let msg = ws.next().await.unwrap().unwrap();
+            if msg.is_text() || msg.is_close() {
+                return msg;
+            }
+        }
+    }
+
+    async fn stored_samples(db: &Db, session_id: i64) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM samples.session_data WHERE session_id = ?")
+            .bind(session_id)
+            .fetch_one(&db.reader)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn json_text_frames() {
+        let state = testing::state("ingress-json", testing::conf("json_ingress = true")).await;
+        let db = state.db.clone();
+        let (session_id, token) = testing::session(&state, "json").await;
+        let addr = testing::serve(state).await;
+        let mut ws = connect(addr, &token, "ack=1").await;
+
+        let sample = serde_json::to_string(&testing::sample(1)).unwrap();
+        ws.send(tungstenite::Message::text(sample)).await.unwrap();
+        let tungstenite::Message::Text(ack) = next_frame(&mut ws).await else {
+            panic!("expected an ack");
+        };
+        let IngressControl::Ack(ack) = serde_json::from_str(&ack).unwrap() else {
+            panic!("expected an ack, got {ack}");
+        };
+        assert_eq!((ack.stored, ack.last_seq), (1, Some(1)));
+        assert_eq!(stored_samples(&db, session_id).await, 1);
+
+        // serde quotes the offending string, which does not fit into the frame
+        let invalid = format!(r#"{{"seq": "{}"}}"#, "é".repeat(100));
+        ws.send(tungstenite::Message::text(invalid)).await.unwrap();
+        let tungstenite::Message::Close(Some(frame)) = next_frame(&mut ws).await else {
+            panic!("expected a close frame");
+        };
+        assert_eq!(u16::from(frame.code), close_code::INVALID);
+        assert!(frame.reason.starts_with("invalid metrics: "));
+        assert!(frame.reason.len() <= MAX_CLOSE_REASON);
+    }
+
+    #[tokio::test]
+    async fn json_text_frames_refused() {
+        let state = testing::state("ingress-json-refused", testing::conf("")).await;
+        let db = state.db.clone();
+        let (session_id, token) = testing::session(&state, "json").await;
+        let addr = testing::serve(state).await;
+        let mut ws = connect(addr, &token, "ack=1").await;
+
+        let sample = serde_json::to_string(&testing::sample(1)).unwrap();
+        ws.send(tungstenite::Message::text(sample)).await.unwrap();
+        let tungstenite::Message::Close(Some(frame)) = next_frame(&mut ws).await else {
+            panic!("expected a close frame");
+        };
+        assert_eq!(u16::from(frame.code), close_code::UNSUPPORTED);
+        assert_eq!(stored_samples(&db, session_id).await, 0);
+    }
+
+    #[tokio::test]
+    async fn invalid_sample_acked() {
+        let state = testing::state("ingress-invalid-sample", testing::conf("")).await;
+        let db = state.db.clone();
+        let (session_id, token) = testing::session(&state, "invalid").await;
+        let addr = testing::serve(state).await;
+        let mut ws = connect(addr, &token, "ack=1").await;
+
+        let mut sample = testing::sample(1);
+        sample.cpu = CpuReport::Aggregate {
+            usage: 250.0,
+            max_core: 100.0,
+        };
+        let bytes = postcard::to_extend(&sample, Vec::new()).unwrap();
+        ws.send(tungstenite::Message::binary(bytes)).await.unwrap();
+        let ack = loop {
+            match ws.next().await.unwrap().unwrap() {
+                tungstenite::Message::Binary(ack) => break ack,
+                msg => assert!(!msg.is_close(), "closed: {msg:?}"),
+            }
+        };
+        let IngressControl::Ack(ack) = postcard::from_bytes(&ack).unwrap() else {
+            panic!("expected an ack");
+        };
+        // acked so the client moves on, but not stored
+        assert_eq!((ack.stored, ack.last_seq), (0, Some(1)));
+        assert!(ack.warnings[0].starts_with("sample dropped: "));
+        assert_eq!(stored_samples(&db, session_id).await, 0);
+    }
+
+    #[test]
+    fn close_reason_truncated() {
+        assert_eq!(close_reason("short".to_owned()), "short");
+        let reason = close_reason("é".repeat(100));
+        assert_eq!(reason.len(), MAX_CLOSE_REASON - 1);
+        assert!(reason.chars().all(|c| c == 'é'));
+    }
+}
@@ -1,21 +1,142 @@
 use axum::{
-    extract::{State, WebSocketUpgrade},
+    extract::{Query, State, WebSocketUpgrade},
+    http::HeaderValue,
     response::Response,
 };
-use tracing::{Instrument, debug_span};
+use miniprobe_proto::{
+    codec,
+    msg::{
+        ACTIONS_HEADER, COARSE_PRECISION, COMPRESSION_HEADER, COUNTERS_HEADER, DELTA_COUNTERS,
+        FULL_PRECISION, PRECISION_HEADER,
+    },
+};
+use serde::Deserialize;
+use tracing::{Instrument, debug, debug_span};
 
-use crate::{AppState, route::sessions::SessionLock};
+use crate::{
+    AppState,
+    route::{agent::ClientAgent, parse_action_names, schema::SchemaCheck, sessions::SessionLock},
+    sink::SinkKind,
+};
 
+mod backpressure;
 mod ingress;
+mod replicate;
+
+pub use replicate::metric_replicate_ws;
+
+/// What happens when a second connection opens the ingress websocket of a
+/// session that is still connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngressConflict {
+    /// The newer connection closes the older one, which is likely half-dead
+    /// if the client reconnected
+    #[default]
+    Takeover,
+    /// The newer connection is refused
+    Reject,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IngressParams {
+    /// Every message carries a `MetricsBatch` instead of a single sample
+    #[serde(default)]
+    batch: bool,
+    /// Reply with an `IngressControl::Ack` every this many messages, never
+    /// if 0
+    #[serde(default)]
+    ack: u32,
+    /// Compressions the client can send, see `miniprobe_proto::codec`
+    compression: Option<String>,
+    /// `delta` to send network counters as deltas, see
+    /// `miniprobe_proto::msg::DELTA_COUNTERS`
+    counters: Option<String>,
+    /// `coarse` to round the values of the samples, see
+    /// `miniprobe_proto::msg::COARSE_PRECISION`
+    precision: Option<String>,
+    /// Actions the client runs when asked to, see
+    /// `miniprobe_proto::msg::ACTIONS_PARAM`
+    actions: Option<String>,
+}
+
+impl IngressParams {
+    /// Precision of the samples, always accepted since rounded values stay
+    /// valid wherever they end up.
+    fn precision(&self) -> &'static str {
+        match self.precision.as_deref() {
+            Some(COARSE_PRECISION) => COARSE_PRECISION,
+            _ => FULL_PRECISION,
+        }
+    }
+}
 
 pub async fn metric_ingress_ws(
+    _: SchemaCheck,
+    agent: ClientAgent,
     session: SessionLock,
     State(state): State<AppState>,
+    Query(params): Query<IngressParams>,
     ws: WebSocketUpgrade,
 ) -> Response {
-    let session_id = session.0.read().await.id;
-    ws.on_upgrade(move |socket| {
-        ingress::handle_socket(socket, state, session)
-            .instrument(debug_span!("ingress_ws", session_id))
-    })
+    let span = {
+        let session = session.0.read().await;
+        debug_span!(
+            "ingress_ws",
+            session_id = session.id,
+            client_id = session.client_id,
+            host = session.host_name.as_deref()
+        )
+    };
+    let compression = codec::negotiate(params.compression.as_deref().unwrap_or_default());
+    // relayed samples go out as they came in, to a server that was not asked
+    let network_delta =
+        params.counters.as_deref() == Some(DELTA_COUNTERS) && state.conf.sink != SinkKind::Forward;
+    // never asked for unless allowed, the client is told so
+    let actions = match &params.actions {
+        Some(list) if state.conf.actions.enabled => parse_action_names(list),
+        _ => Vec::new(),
+    };
+    let precision = params.precision();
+    debug!(
+        parent: &span,
+        user_agent = agent.user_agent,
+        protocol = agent.protocol,
+        compression = compression.as_str(),
+        network_delta,
+        precision,
+        ?actions,
+        "upgrading to the ingress websocket"
+    );
+    let offers_actions = !actions.is_empty();
+    let mut resp = ws.on_upgrade(move |socket| {
+        ingress::handle_socket(
+            socket,
+            state,
+            session,
+            params,
+            compression,
+            network_delta,
+            actions,
+        )
+        .instrument(span)
+    });
+    // clients predating compression ignore the header, they offer none
+    resp.headers_mut().insert(
+        COMPRESSION_HEADER,
+        HeaderValue::from_static(compression.as_str()),
+    );
+    if network_delta {
+        resp.headers_mut()
+            .insert(COUNTERS_HEADER, HeaderValue::from_static(DELTA_COUNTERS));
+    }
+    if precision == COARSE_PRECISION {
+        resp.headers_mut()
+            .insert(PRECISION_HEADER, HeaderValue::from_static(COARSE_PRECISION));
+    }
+    if offers_actions {
+        resp.headers_mut()
+            .insert(ACTIONS_HEADER, HeaderValue::from_static("1"));
+    }
+    resp
 }
@@ -0,0 +1,484 @@
+//! Replication of stored samples over `/ws/v1/metrics/replicate`.
+//!
+//! Every stored sample is sent as a JSON text frame in the order it was
+//! stored, first those after the `after` cursor, then new ones as they are
+//! ingested. Each sample carries its `cursor`, a replica that reconnects with
+//! the cursor of the last sample it processed continues right after it.
+//! Samples are read back from the samples database, nothing is sent with a
+//! sink other than `sqlite` but samples stored before.
+//!
+//! Cursors are the row ids of `session_data`. They only grow while the newest
+//! samples are kept, purging them lets new samples reuse their cursors.
+
+use std::{collections::HashMap, time::Duration};
+
+use axum::{
+    extract::{
+        Query, State, WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    response::Response,
+};
+use futures_util::SinkExt;
+use miniprobe_proto::ListeningSocket;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tracing::{Instrument, debug, debug_span, warn};
+
+use crate::AppState;
+
+/// Samples read from the database at once.
+const BATCH_SIZE: i64 = 256;
+/// How often a caught up replica looks for samples it was not told about,
+/// e.g. imported by `admin import`.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+pub struct ReplicateParams {
+    /// Cursor of the last sample the replica has, only newly ingested samples
+    /// are sent if unset, every stored one with `0`
+    after: Option<i64>,
+    /// Only replicate the samples of this client
+    client: Option<i64>,
+}
+
+/// A stored sample. Sections the client did not report, or that were not
+/// stored, are `null`.
+#[derive(Debug, Serialize)]
+pub struct ReplicatedSample {
+    /// Position of the sample in the stream, resume after it with `after`
+    pub cursor: i64,
+    /// `None` if the session is gone with its client
+    pub client_id: Option<i64>,
+    pub session_id: i64,
+    pub seq: Option<i64>,
+    /// Client time in unix milliseconds
+    pub sample_time: i64,
+    /// Server time in unix milliseconds the sample arrived at
+    pub received_at: Option<i64>,
+    pub cpu: Option<ReplicatedCpu>,
+    pub memory: Option<ReplicatedMemory>,
+    pub networks: Vec<ReplicatedNetwork>,
+    pub disks: Vec<ReplicatedDisk>,
+    pub sensors: Option<ReplicatedSensors>,
+    pub probe: Option<ReplicatedProbe>,
+    pub processes: Option<ReplicatedProcesses>,
+    pub fds: Option<ReplicatedFds>,
+    pub clock: Option<ReplicatedClock>,
+    pub battery: Option<ReplicatedBattery>,
+    pub services: Vec<ReplicatedService>,
+    /// Only sent by clients when they changed
+    pub listeners: Option<Vec<ListeningSocket>>,
+}
+
+/// Usage in percent, of every core or folded by the client or server.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicatedCpu {
+    PerCore(Vec<f64>),
+    Aggregate { usage: f64, max_core: f64 },
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedMemory {
+    pub total: i64,
+    pub used: i64,
+    pub swap_total: i64,
+    pub swap_used: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedNetwork {
+    pub ifname: String,
+    pub rx_bytes: Option<i64>,
+    pub tx_bytes: Option<i64>,
+    /// The counters are bytes since the previous sample of the session
+    /// rather than cumulative
+    pub delta: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedDisk {
+    pub mount_point: String,
+    /// Bytes
+    pub total: i64,
+    pub available: i64,
+    /// Bytes since boot
+    pub read_bytes: Option<i64>,
+    pub written_bytes: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedSensors {
+    /// Degrees Celsius
+    pub cpu_temperature: Option<f64>,
+    /// MHz
+    pub cpu_frequency: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedProbe {
+    /// Microseconds
+    pub collection_time: i64,
+    /// Percent of one core
+    pub cpu_usage: Option<f64>,
+    /// Bytes
+    pub rss: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedProcesses {
+    pub processes: i64,
+    /// Only counted on Linux
+    pub threads: Option<i64>,
+    pub zombies: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedFds {
+    pub open: i64,
+    pub max: i64,
+    pub tcp_established: Option<i64>,
+    pub tcp_time_wait: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedClock {
+    pub synchronized: bool,
+    /// Seconds ahead of the time source, negative if behind
+    pub offset: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedBattery {
+    /// Percent of the full charge
+    pub capacity: f64,
+    pub state: String,
+    /// Watts
+    pub power: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicatedService {
+    pub unit: String,
+    pub state: String,
+    pub restarts: Option<i64>,
+}
+
+pub async fn metric_replicate_ws(
+    State(state): State<AppState>,
+    Query(params): Query<ReplicateParams>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| {
+        handle_socket(socket, state, params).instrument(debug_span!("replicate_ws"))
+    })
+}
+
+async fn handle_socket(mut socket: WebSocket, state: AppState, params: ReplicateParams) {
+    let _tracker_token = state.ws_graceful_shutdown.tracker.token();
+    let cancellation_token = state.ws_graceful_shutdown.token.child_token();
+    let mut stored = state.samples_stored.subscribe();
+
+    let mut cursor = match params.after {
+        Some(after) => after,
+        None => match latest_cursor(&state.db.reader).await {
+            Ok(cursor) => cursor,
+            Err(e) => {
+                warn!("failed to find the latest sample: {e}");
+                socket.close().await.ok();
+                return;
+            }
+        },
+    };
+    debug!(cursor, client = params.client, "replica connected");
+
+    loop {
+        // samples stored from here on wake the replica once caught up
+        stored.borrow_and_update();
+        let samples = match fetch_after(&state.db.reader, cursor, params.client).await {
+            Ok(samples) => samples,
+            Err(e) => {
+                warn!(cursor, "failed to read samples to replicate: {e}");
+                break;
+            }
+        };
+        let caught_up = (samples.len() as i64) < BATCH_SIZE;
+        if let Some(last) = samples.last() {
+            cursor = last.cursor;
+        }
+        if !send(&mut socket, &samples).await {
+            break;
+        }
+        if !caught_up {
+            continue;
+        }
+
+        tokio::select! {
+            changed = stored.changed() => if changed.is_err() {
+                break;
+            },
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            msg = socket.recv() => match msg {
+                // replicas have nothing to say, only watch for the close
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = cancellation_token.cancelled() => break,
+        }
+    }
+
+    socket.close().await.ok();
+    debug!(cursor, "replica disconnected");
+}
+
+/// Send `samples` one per text frame, `false` once the replica is gone.
+async fn send(socket: &mut WebSocket, samples: &[ReplicatedSample]) -> bool {
+    for sample in samples {
+        let text = match serde_json::to_string(sample) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!(cursor = sample.cursor, "failed to serialize sample: {e}");
+                continue;
+            }
+        };
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+async fn latest_cursor(db: &SqlitePool) -> sqlx::Result<i64> {
+    sqlx::query_scalar!(r#"SELECT COALESCE(MAX(id), 0) AS "cursor!: i64" FROM session_data"#)
+        .fetch_one(db)
+        .await
+}
+
+/// Up to `BATCH_SIZE` samples stored after `cursor`, in the order stored.
+async fn fetch_after(
+    db: &SqlitePool,
+    cursor: i64,
+    client: Option<i64>,
+) -> anyhow::Result<Vec<ReplicatedSample>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT d.id, s.client_id AS "client_id?", d.session_id, d.seq, d.sample_time,
+            d.received_at,
+            a.cpu_usage AS "cpu_usage?", a.max_core_usage AS "max_core_usage?",
+            m.total AS "memory_total?", m.used AS "memory_used?",
+            m.swap_total AS "swap_total?", m.swap_used AS "swap_used?",
+            t.cpu_temperature, t.cpu_frequency,
+            p.collection_time AS "collection_time?", p.cpu_usage AS probe_cpu,
+            p.rss AS probe_rss,
+            y.processes AS "processes?", y.threads, y.zombies AS "zombies?",
+            f.open AS "fds_open?", f.max AS "fds_max?", f.tcp_established, f.tcp_time_wait,
+            k.synchronized AS "clock_synced?: bool", k.offset AS clock_offset,
+            b.capacity AS "battery_capacity?", bs.value AS "battery_state?",
+            b.power AS battery_power,
+            l.listeners AS "listeners?"
+        FROM session_data d
+        LEFT JOIN sessions s ON s.id = d.session_id
+        LEFT JOIN session_data_cpu_aggregate a ON a.session_data_id = d.id
+        LEFT JOIN session_data_memory m ON m.session_data_id = d.id
+        LEFT JOIN session_data_sensors t ON t.session_data_id = d.id
+        LEFT JOIN session_data_probe p ON p.session_data_id = d.id
+        LEFT JOIN session_data_system y ON y.session_data_id = d.id
+        LEFT JOIN session_data_fds f ON f.session_data_id = d.id
+        LEFT JOIN session_data_clock k ON k.session_data_id = d.id
+        LEFT JOIN session_data_battery b ON b.session_data_id = d.id
+        LEFT JOIN strings bs ON bs.id = b.state_id
+        LEFT JOIN session_data_listeners l ON l.session_data_id = d.id
+        WHERE d.id > $1 AND ($2 IS NULL OR s.client_id = $2)
+        ORDER BY d.id
+        LIMIT $3
+        "#,
+        cursor,
+        client,
+        BATCH_SIZE
+    )
+    .fetch_all(db)
+    .await?;
+    let Some(last) = rows.last().map(|r| r.id) else {
+        return Ok(Vec::new());
+    };
+
+    // rows of many per sample, read for the whole range at once
+    let mut cores = HashMap::<i64, Vec<f64>>::new();
+    for r in sqlx::query!(
+        r#"
+        SELECT session_data_id, cpu_usage FROM session_data_cpu
+        WHERE session_data_id > ? AND session_data_id <= ?
+        ORDER BY session_data_id, cpu_id
+        "#,
+        cursor,
+        last
+    )
+    .fetch_all(db)
+    .await?
+    {
+        cores
+            .entry(r.session_data_id)
+            .or_default()
+            .push(r.cpu_usage);
+    }
+    let mut services = HashMap::<i64, Vec<ReplicatedService>>::new();
+    for r in sqlx::query!(
+        r#"
+        SELECT v.session_data_id, u.value AS unit, s.value AS state, v.restarts
+        FROM session_data_service v
+        JOIN strings u ON u.id = v.unit_id
+        JOIN strings s ON s.id = v.state_id
+        WHERE v.session_data_id > ? AND v.session_data_id <= ?
+        ORDER BY v.session_data_id, u.value
+        "#,
+        cursor,
+        last
+    )
+    .fetch_all(db)
+    .await?
+    {
+        services
+            .entry(r.session_data_id)
+            .or_default()
+            .push(ReplicatedService {
+                unit: r.unit,
+                state: r.state,
+                restarts: r.restarts,
+            });
+    }
+    let mut networks = HashMap::<i64, Vec<ReplicatedNetwork>>::new();
+    for r in sqlx::query!(
+        r#"
+        SELECT n.session_data_id, s.value AS ifname, n.rx_bytes, n.tx_bytes,
+            n.delta AS "delta: bool"
+        FROM session_data_network n
+        JOIN strings s ON s.id = n.ifname_id
+        WHERE n.session_data_id > ? AND n.session_data_id <= ?
+        ORDER BY n.session_data_id, s.value
+        "#,
+        cursor,
+        last
+    )
+    .fetch_all(db)
+    .await?
+    {
+        networks
+            .entry(r.session_data_id)
+            .or_default()
+            .push(ReplicatedNetwork {
+                ifname: r.ifname,
+                rx_bytes: r.rx_bytes,
+                tx_bytes: r.tx_bytes,
+                delta: r.delta,
+            });
+    }
+    let mut disks = HashMap::<i64, Vec<ReplicatedDisk>>::new();
+    for r in sqlx::query!(
+        r#"
+        SELECT d.session_data_id, s.value AS mount_point, d.total, d.available,
+            d.read_bytes, d.written_bytes
+        FROM session_data_disk d
+        JOIN strings s ON s.id = d.mount_point_id
+        WHERE d.session_data_id > ? AND d.session_data_id <= ?
+        ORDER BY d.session_data_id, s.value
+        "#,
+        cursor,
+        last
+    )
+    .fetch_all(db)
+    .await?
+    {
+        disks
+            .entry(r.session_data_id)
+            .or_default()
+            .push(ReplicatedDisk {
+                mount_point: r.mount_point,
+                total: r.total,
+                available: r.available,
+                read_bytes: r.read_bytes,
+                written_bytes: r.written_bytes,
+            });
+    }
+
+    let mut samples = Vec::with_capacity(rows.len());
+    for r in rows {
+        let cpu = match (cores.remove(&r.id), r.cpu_usage, r.max_core_usage) {
+            (Some(cores), _, _) => Some(ReplicatedCpu::PerCore(cores)),
+            (None, Some(usage), Some(max_core)) => {
+                Some(ReplicatedCpu::Aggregate { usage, max_core })
+            }
+            _ => None,
+        };
+        let memory = match (r.memory_total, r.memory_used, r.swap_total, r.swap_used) {
+            (Some(total), Some(used), Some(swap_total), Some(swap_used)) => {
+                Some(ReplicatedMemory {
+                    total,
+                    used,
+                    swap_total,
+                    swap_used,
+                })
+            }
+            _ => None,
+        };
+        let listeners = match r.listeners {
+            Some(listeners) => Some(serde_json::from_str(&listeners)?),
+            None => None,
+        };
+        samples.push(ReplicatedSample {
+            cursor: r.id,
+            client_id: r.client_id,
+            session_id: r.session_id,
+            seq: r.seq,
+            sample_time: r.sample_time,
+            received_at: r.received_at,
+            cpu,
+            memory,
+            networks: networks.remove(&r.id).unwrap_or_default(),
+            disks: disks.remove(&r.id).unwrap_or_default(),
+            sensors: (r.cpu_temperature.is_some() || r.cpu_frequency.is_some()).then_some(
+                ReplicatedSensors {
+                    cpu_temperature: r.cpu_temperature,
+                    cpu_frequency: r.cpu_frequency,
+                },
+            ),
+            probe: r.collection_time.map(|collection_time| ReplicatedProbe {
+                collection_time,
+                cpu_usage: r.probe_cpu,
+                rss: r.probe_rss,
+            }),
+            processes: match (r.processes, r.zombies) {
+                (Some(processes), Some(zombies)) => Some(ReplicatedProcesses {
+                    processes,
+                    threads: r.threads,
+                    zombies,
+                }),
+                _ => None,
+            },
+            fds: match (r.fds_open, r.fds_max) {
+                (Some(open), Some(max)) => Some(ReplicatedFds {
+                    open,
+                    max,
+                    tcp_established: r.tcp_established,
+                    tcp_time_wait: r.tcp_time_wait,
+                }),
+                _ => None,
+            },
+            clock: r.clock_synced.map(|synchronized| ReplicatedClock {
+                synchronized,
+                offset: r.clock_offset,
+            }),
+            battery: match (r.battery_capacity, r.battery_state) {
+                (Some(capacity), Some(state)) => Some(ReplicatedBattery {
+                    capacity,
+                    state,
+                    power: r.battery_power,
+                }),
+                _ => None,
+            },
+            services: services.remove(&r.id).unwrap_or_default(),
+            listeners,
+        });
+    }
+    Ok(samples)
+}
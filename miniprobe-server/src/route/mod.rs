@@ -1,13 +1,58 @@
+mod access;
+mod actions;
+mod addresses;
+mod agent;
+mod alert_rules;
+mod auth;
+mod clients;
+mod discovery;
+mod events;
+mod fleet;
+mod health;
+mod listeners;
+mod log_level;
+mod maintenance;
 mod metrics;
+mod prometheus;
+mod query;
+mod query_cache;
+mod reboots;
+mod schema;
+mod security;
+mod server;
 mod sessions;
+mod sparkline;
+mod status_page;
+mod wake;
 
-use axum::Json;
-use serde_json::{Value, json};
-
-pub use metrics::metric_ingress_ws;
+pub use access::{AccessConf, ClientAccess};
+pub use actions::{
+    ActionChannels, ActionsConf, ClientActions, list_actions, parse_action_names, run_action,
+};
+pub use addresses::list_addresses;
+pub use alert_rules::{
+    create_alert_rule, delete_alert_rule, get_alert_rule, list_alert_rules, update_alert_rule,
+};
+pub use auth::{AdminAuth, StatusAuth};
+pub use clients::list_clients;
+pub use discovery::well_known;
+pub use events::events_ws;
+pub use fleet::{fleet_query, fleet_query_range};
+pub use health::{StatusConf, health};
+pub use listeners::list_listeners;
+pub use log_level::{LogFilterHandle, get_log_level, set_log_level};
+pub use maintenance::{Available, Maintenance, MaintenanceConf, get_maintenance, set_maintenance};
+pub use metrics::{IngressConflict, metric_ingress_ws, metric_replicate_ws};
+pub use prometheus::{PrometheusConf, prometheus_metrics};
+pub use query::{query, query_range};
+pub use query_cache::{QueryCache, QueryCacheConf};
+pub use reboots::list_reboots;
+pub use security::list_security_events;
+pub use server::{ingest_latency, server_info};
+#[cfg(test)]
+pub use sessions::Session;
 pub use sessions::SessionManager;
-pub use sessions::create_session;
-
-pub async fn health() -> Json<Value> {
-    Json(json!({"status": "ok"}))
-}
+pub use sessions::{HostnameMismatch, create_session, list_sessions};
+pub use sparkline::sparkline;
+pub use status_page::{StatusPageCache, StatusPageConf, status_page_html, status_page_json};
+pub use wake::wake_client;
@@ -0,0 +1,376 @@
+//! `/metrics`, the latest sample of every client with an active session in
+//! the Prometheus text exposition format, for scraping the server from an
+//! existing Prometheus.
+//!
+//! Only the latest session of a client is exported, so a client reconnecting
+//! does not show up twice. Network counters sent as deltas are left out,
+//! Prometheus expects them cumulative.
+
+use std::fmt::Write;
+
+use axum::{
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use confique::Config;
+
+use crate::AppState;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Config, Debug)]
+pub struct PrometheusConf {
+    /// Serve `/metrics` for Prometheus to scrape, with the `status.token` or
+    /// the admin token as bearer token
+    #[config(default = false)]
+    pub enabled: bool,
+}
+
+/// The latest sample of a client.
+#[derive(Debug, Default)]
+struct LatestSample {
+    client: String,
+    host_name: Option<String>,
+    /// Unix timestamp in milliseconds
+    sample_time: i64,
+    cpu_usage: Option<f64>,
+    memory_total: Option<i64>,
+    memory_used: Option<i64>,
+    swap_total: Option<i64>,
+    swap_used: Option<i64>,
+    cpu_temperature: Option<f64>,
+    cpu_frequency: Option<i64>,
+    processes: Option<i64>,
+    zombies: Option<i64>,
+    open_fds: Option<i64>,
+    tcp_established: Option<i64>,
+}
+
+/// A network interface in the latest sample of a client.
+#[derive(Debug, Default)]
+struct LatestInterface {
+    client: String,
+    host_name: Option<String>,
+    ifname: String,
+    rx_bytes: Option<i64>,
+    tx_bytes: Option<i64>,
+    delta: bool,
+}
+
+impl LatestInterface {
+    /// Cumulative network counters, `None` for deltas.
+    fn counter(&self, bytes: Option<i64>) -> Option<f64> {
+        match self.delta {
+            false => bytes.map(|bytes| bytes as f64),
+            true => None,
+        }
+    }
+}
+
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    value: Value,
+}
+
+enum Value {
+    Sample(fn(&LatestSample) -> Option<f64>),
+    /// Labeled with the interface as well
+    Interface(fn(&LatestInterface) -> Option<f64>),
+}
+
+const FAMILIES: &[Family] = &[
+    Family {
+        name: "miniprobe_sample_timestamp_seconds",
+        help: "Time the latest sample was taken at",
+        kind: "gauge",
+        value: Value::Sample(|s| Some(s.sample_time as f64 / 1000.0)),
+    },
+    Family {
+        name: "miniprobe_cpu_usage_percent",
+        help: "Average CPU usage over all cores",
+        kind: "gauge",
+        value: Value::Sample(|s| s.cpu_usage),
+    },
+    Family {
+        name: "miniprobe_memory_total_bytes",
+        help: "Total memory",
+        kind: "gauge",
+        value: Value::Sample(|s| s.memory_total.map(|v| v as f64)),
+    },
+    Family {
+        name: "miniprobe_memory_used_bytes",
+        help: "Used memory",
+        kind: "gauge",
+        value: Value::Sample(|s| s.memory_used.map(|v| v as f64)),
+    },
+    Family {
+        name: "miniprobe_swap_total_bytes",
+        help: "Total swap",
+        kind: "gauge",
+        value: Value::Sample(|s| s.swap_total.map(|v| v as f64)),
+    },
+    Family {
+        name: "miniprobe_swap_used_bytes",
+        help: "Used swap",
+        kind: "gauge",
+        value: Value::Sample(|s| s.swap_used.map(|v| v as f64)),
+    },
+    Family {
+        name: "miniprobe_network_receive_bytes_total",
+        help: "Bytes received on the interface",
+        kind: "counter",
+        value: Value::Interface(|i| i.counter(i.rx_bytes)),
+    },
+    Family {
+        name: "miniprobe_network_transmit_bytes_total",
+        help: "Bytes sent on the interface",
+        kind: "counter",
+        value: Value::Interface(|i| i.counter(i.tx_bytes)),
+    },
+    Family {
+        name: "miniprobe_cpu_temperature_celsius",
+        help: "Hottest CPU sensor",
+        kind: "gauge",
+        value: Value::Sample(|s| s.cpu_temperature),
+    },
+    Family {
+        name: "miniprobe_cpu_frequency_hertz",
+        help: "Average current frequency over all cores",
+        kind: "gauge",
+        value: Value::Sample(|s| s.cpu_frequency.map(|mhz| mhz as f64 * 1e6)),
+    },
+    Family {
+        name: "miniprobe_processes",
+        help: "Processes running",
+        kind: "gauge",
+        value: Value::Sample(|s| s.processes.map(|v| v as f64)),
+    },
+    Family {
+        name: "miniprobe_zombie_processes",
+        help: "Exited processes not reaped by their parent",
+        kind: "gauge",
+        value: Value::Sample(|s| s.zombies.map(|v| v as f64)),
+    },
+    Family {
+        name: "miniprobe_open_fds",
+        help: "File descriptors open by all processes",
+        kind: "gauge",
+        value: Value::Sample(|s| s.open_fds.map(|v| v as f64)),
+    },
+    Family {
+        name: "miniprobe_tcp_established_connections",
+        help: "TCP connections established or closing on the remote end",
+        kind: "gauge",
+        value: Value::Sample(|s| s.tcp_established.map(|v| v as f64)),
+    },
+];
+
+pub async fn prometheus_metrics(
+    State(state): State<AppState>,
+) -> Result<Response, PrometheusError> {
+    let samples = sqlx::query_as!(
+        LatestSample,
+        r#"
+        SELECT c.name AS client, s.host_name, d.sample_time,
+            COALESCE(
+                a.cpu_usage,
+                (SELECT AVG(cpu_usage) FROM session_data_cpu WHERE session_data_id = d.id)
+            ) AS "cpu_usage?: f64",
+            m.total AS "memory_total?", m.used AS "memory_used?",
+            m.swap_total AS "swap_total?", m.swap_used AS "swap_used?",
+            t.cpu_temperature AS "cpu_temperature?", t.cpu_frequency AS "cpu_frequency?",
+            y.processes AS "processes?", y.zombies AS "zombies?",
+            f.open AS "open_fds?", f.tcp_established AS "tcp_established?"
+        FROM clients c
+        JOIN sessions s ON s.id = (
+            SELECT MAX(id) FROM non_expired_sessions WHERE client_id = c.id
+        )
+        JOIN session_data d ON d.id = (
+            SELECT id FROM session_data WHERE session_id = s.id
+            ORDER BY sample_time DESC LIMIT 1
+        )
+        LEFT JOIN session_data_cpu_aggregate a ON a.session_data_id = d.id
+        LEFT JOIN session_data_memory m ON m.session_data_id = d.id
+        LEFT JOIN session_data_sensors t ON t.session_data_id = d.id
+        LEFT JOIN session_data_system y ON y.session_data_id = d.id
+        LEFT JOIN session_data_fds f ON f.session_data_id = d.id
+        ORDER BY c.name
+        "#
+    )
+    .fetch_all(&state.db.reader)
+    .await?;
+    let interfaces = sqlx::query_as!(
+        LatestInterface,
+        r#"
+        SELECT c.name AS client, s.host_name, i.value AS ifname, n.rx_bytes, n.tx_bytes,
+            n.delta AS "delta: bool"
+        FROM clients c
+        JOIN sessions s ON s.id = (
+            SELECT MAX(id) FROM non_expired_sessions WHERE client_id = c.id
+        )
+        JOIN session_data d ON d.id = (
+            SELECT id FROM session_data WHERE session_id = s.id
+            ORDER BY sample_time DESC LIMIT 1
+        )
+        JOIN session_data_network n ON n.session_data_id = d.id
+        JOIN strings i ON i.id = n.ifname_id
+        ORDER BY c.name, i.value
+        "#
+    )
+    .fetch_all(&state.db.reader)
+    .await?;
+
+    Ok((
+        [(header::CONTENT_TYPE, CONTENT_TYPE)],
+        render(&samples, &interfaces),
+    )
+        .into_response())
+}
+
+/// Every family with a value in any sample, each sample labeled with its
+/// client and host name, and interface counters with the interface.
+fn render(samples: &[LatestSample], interfaces: &[LatestInterface]) -> String {
+    let mut out = String::new();
+    for family in FAMILIES {
+        let values = match family.value {
+            Value::Sample(value) => samples
+                .iter()
+                .filter_map(|s| Some((&s.client, &s.host_name, None, value(s)?)))
+                .collect::<Vec<_>>(),
+            Value::Interface(value) => interfaces
+                .iter()
+                .filter_map(|i| Some((&i.client, &i.host_name, Some(&i.ifname), value(i)?)))
+                .collect(),
+        };
+        if values.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind);
+        for (client, host_name, ifname, value) in values {
+            let _ = write!(
+                out,
+                "{}{{client=\"{}\",host=\"{}\"",
+                family.name,
+                escape(client),
+                escape(host_name.as_deref().unwrap_or_default())
+            );
+            if let Some(ifname) = ifname {
+                let _ = write!(out, ",interface=\"{}\"", escape(ifname));
+            }
+            let _ = writeln!(out, "}} {}", format_value(value));
+        }
+    }
+    out
+}
+
+/// A sample value, with the spelling of the exposition format for infinities,
+/// which `Display` writes as `inf`, and for `NaN`.
+fn format_value(value: f64) -> String {
+    match value {
+        f64::INFINITY => "+Inf".to_owned(),
+        f64::NEG_INFINITY => "-Inf".to_owned(),
+        value if value.is_nan() => "NaN".to_owned(),
+        value => value.to_string(),
+    }
+}
+
+/// A label value, with backslashes, quotes and line feeds escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PrometheusError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for PrometheusError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            PrometheusError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_format() {
+        let samples = [
+            LatestSample {
+                client: "web-1".to_owned(),
+                host_name: Some("web \"one\"".to_owned()),
+                sample_time: 1_760_000_000_500,
+                cpu_usage: Some(12.5),
+                ..Default::default()
+            },
+            LatestSample {
+                client: "db-1".to_owned(),
+                sample_time: 1_760_000_001_000,
+                cpu_frequency: Some(2400),
+                ..Default::default()
+            },
+        ];
+        let interfaces = [
+            LatestInterface {
+                client: "web-1".to_owned(),
+                host_name: Some("web \"one\"".to_owned()),
+                ifname: "eth0".to_owned(),
+                rx_bytes: Some(1024),
+                tx_bytes: Some(2048),
+                delta: false,
+            },
+            LatestInterface {
+                client: "web-1".to_owned(),
+                host_name: Some("web \"one\"".to_owned()),
+                ifname: "wg0".to_owned(),
+                rx_bytes: Some(64),
+                tx_bytes: None,
+                delta: false,
+            },
+            LatestInterface {
+                client: "db-1".to_owned(),
+                ifname: "eth0".to_owned(),
+                rx_bytes: Some(10),
+                delta: true,
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            render(&samples, &interfaces),
+            "# HELP miniprobe_sample_timestamp_seconds Time the latest sample was taken at\n\
+            # TYPE miniprobe_sample_timestamp_seconds gauge\n\
+            miniprobe_sample_timestamp_seconds{client=\"web-1\",host=\"web \\\"one\\\"\"} 1760000000.5\n\
+            miniprobe_sample_timestamp_seconds{client=\"db-1\",host=\"\"} 1760000001\n\
+            # HELP miniprobe_cpu_usage_percent Average CPU usage over all cores\n\
+            # TYPE miniprobe_cpu_usage_percent gauge\n\
+            miniprobe_cpu_usage_percent{client=\"web-1\",host=\"web \\\"one\\\"\"} 12.5\n\
+            # HELP miniprobe_network_receive_bytes_total Bytes received on the interface\n\
+            # TYPE miniprobe_network_receive_bytes_total counter\n\
+            miniprobe_network_receive_bytes_total{client=\"web-1\",host=\"web \\\"one\\\"\",interface=\"eth0\"} 1024\n\
+            miniprobe_network_receive_bytes_total{client=\"web-1\",host=\"web \\\"one\\\"\",interface=\"wg0\"} 64\n\
+            # HELP miniprobe_network_transmit_bytes_total Bytes sent on the interface\n\
+            # TYPE miniprobe_network_transmit_bytes_total counter\n\
+            miniprobe_network_transmit_bytes_total{client=\"web-1\",host=\"web \\\"one\\\"\",interface=\"eth0\"} 2048\n\
+            # HELP miniprobe_cpu_frequency_hertz Average current frequency over all cores\n\
+            # TYPE miniprobe_cpu_frequency_hertz gauge\n\
+            miniprobe_cpu_frequency_hertz{client=\"db-1\",host=\"\"} 2400000000\n"
+        );
+        assert_eq!(escape("a\\b\nc"), "a\\\\b\\nc");
+        assert_eq!(format_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_value(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_value(f64::NAN), "NaN");
+        assert_eq!(format_value(-0.25), "-0.25");
+    }
+}
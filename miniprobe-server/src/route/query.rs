@@ -0,0 +1,522 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Query, RawQuery, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use super::query_cache::Covers;
+use crate::{
+    AppState,
+    expr::{Expr, MAX_RANGE, ParseError, Sample, fetch_samples, parse_range},
+    reboot::fetch_reboots,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct QueryParams {
+    /// Expression to evaluate, e.g. `avg_over_time(cpu[5m])`
+    pub expr: String,
+    /// Only evaluate for this client, otherwise every client with recent samples
+    pub client: Option<i64>,
+    /// Unix timestamp in seconds to evaluate at, defaults to now
+    pub time: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryResponse {
+    pub time: i64,
+    pub results: Vec<QueryResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryResult {
+    pub client_id: i64,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub timezone: Option<String>,
+    pub value: Option<f64>,
+    /// Client time of the latest sample at or before `time`
+    pub sample_time: Option<i64>,
+    /// Server time in unix milliseconds the latest sample arrived at
+    pub received_at: Option<i64>,
+    /// Boot times of the reboots within the lookback of the expression,
+    /// values across a reboot may jump
+    pub reboots: Vec<i64>,
+}
+
+/// Most buckets a range query may ask for.
+const MAX_BUCKETS: i64 = 11_000;
+/// Latest unix timestamp a query may ask for, the end of the year 9999.
+const MAX_TIME: i64 = 253_402_300_799;
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRangeParams {
+    /// Expression to evaluate, e.g. `avg_over_time(cpu[5m])`
+    pub expr: String,
+    /// Only evaluate for this client, otherwise every client with samples in range
+    pub client: Option<i64>,
+    /// Unix timestamp in seconds the first bucket starts at
+    pub start: i64,
+    /// Unix timestamp in seconds the last bucket reaches, defaults to now
+    pub end: Option<i64>,
+    /// Bucket width in seconds
+    pub step: i64,
+    /// Value of buckets without samples
+    #[serde(default)]
+    pub fill: Fill,
+    /// Also evaluate every bucket this much earlier, e.g. `7d` for this week
+    /// against last week, see `compare_values`
+    pub compare_offset: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fill {
+    /// Leave them `null`
+    #[default]
+    Null,
+    /// Repeat the value of the bucket before, `null` until there is one
+    Previous,
+    Zero,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryRangeResponse {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+    /// Start of each bucket `[t, t + step)`, the buckets end at `end` or
+    /// earlier
+    pub buckets: Vec<i64>,
+    /// Seconds the compared buckets are earlier, `null` without
+    /// `compare_offset`
+    pub compare_offset: Option<i64>,
+    pub results: Vec<QueryRangeResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryRangeResult {
+    pub client_id: i64,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub timezone: Option<String>,
+    /// Value of each bucket, evaluated at its end
+    pub values: Vec<Option<f64>>,
+    /// Value of each bucket `compare_offset` earlier, aligned with `values`
+    pub compare_values: Option<Vec<Option<f64>>>,
+    /// Boot times of the reboots between the first and the last bucket
+    pub reboots: Vec<i64>,
+}
+
+pub async fn query(
+    State(state): State<AppState>,
+    RawQuery(raw): RawQuery,
+    Query(params): Query<QueryParams>,
+) -> Result<Response, QueryError> {
+    let expr: Expr = params.expr.parse()?;
+    let time = check_time("time", params.time.unwrap_or_else(now))?;
+    let covers = Covers {
+        client: params.client,
+        from: time - expr.lookback(),
+        to: time,
+    };
+    state
+        .query_cache
+        .get_or_make(
+            &state.conf.query_cache,
+            "query",
+            raw,
+            covers,
+            with_timeout(&state, eval_query(&state, &params, &expr, time)),
+        )
+        .await
+}
+
+async fn eval_query(
+    state: &AppState,
+    params: &QueryParams,
+    expr: &Expr,
+    time: i64,
+) -> Result<QueryResponse, QueryError> {
+    let mut conn = state.db.interruptible_reader().await?;
+    let clients = sqlx::query!(
+        "SELECT id, name, display_name, timezone FROM clients \
+            WHERE $1 IS NULL OR id = $1 ORDER BY id",
+        params.client
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if params.client.is_some() && clients.is_empty() {
+        return Err(QueryError::ClientNotFound);
+    }
+
+    let mut results = Vec::with_capacity(clients.len());
+    for client in clients {
+        let samples = fetch_samples(&mut conn, client.id, time - expr.lookback(), time).await?;
+        if samples.is_empty() && params.client.is_none() {
+            continue;
+        }
+
+        let reboots = fetch_reboots(&mut *conn, client.id, time - expr.lookback(), time).await?;
+        let latest = samples.last();
+        results.push(QueryResult {
+            client_id: client.id,
+            name: client.name,
+            display_name: client.display_name,
+            timezone: client.timezone,
+            value: expr.eval(&samples, time),
+            sample_time: latest.map(|sample| sample.sample_time / 1000),
+            received_at: latest.and_then(|sample| sample.received_at),
+            reboots,
+        });
+    }
+
+    Ok(QueryResponse { time, results })
+}
+
+/// Evaluate an expression in regular buckets, so charts get a value for
+/// every point in time without resampling.
+pub async fn query_range(
+    State(state): State<AppState>,
+    RawQuery(raw): RawQuery,
+    Query(params): Query<QueryRangeParams>,
+) -> Result<Response, QueryError> {
+    let expr: Expr = params.expr.parse()?;
+    let end = params.end.unwrap_or_else(now);
+    let buckets = buckets(params.start, end, params.step)?;
+    let lookback = expr.lookback().max(params.step);
+    let comparison = params
+        .compare_offset
+        .as_deref()
+        .map(|offset| Comparison::new(compare_offset(offset)?, params.start, end, lookback))
+        .transpose()?;
+    let covers = Covers {
+        client: params.client,
+        from: comparison.map_or(params.start - lookback, |c| c.from),
+        to: end,
+    };
+    state
+        .query_cache
+        .get_or_make(
+            &state.conf.query_cache,
+            "query_range",
+            raw,
+            covers,
+            with_timeout(
+                &state,
+                eval_query_range(&state, &params, &expr, end, buckets, comparison),
+            ),
+        )
+        .await
+}
+
+async fn eval_query_range(
+    state: &AppState,
+    params: &QueryRangeParams,
+    expr: &Expr,
+    end: i64,
+    buckets: Vec<i64>,
+    comparison: Option<Comparison>,
+) -> Result<QueryRangeResponse, QueryError> {
+    let mut conn = state.db.interruptible_reader().await?;
+    let clients = sqlx::query!(
+        "SELECT id, name, display_name, timezone FROM clients \
+            WHERE $1 IS NULL OR id = $1 ORDER BY id",
+        params.client
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if params.client.is_some() && clients.is_empty() {
+        return Err(QueryError::ClientNotFound);
+    }
+
+    let lookback = expr.lookback().max(params.step);
+    let mut results = Vec::with_capacity(clients.len());
+    for client in clients {
+        let samples = fetch_samples(&mut conn, client.id, params.start - lookback, end).await?;
+        // the earlier range is fetched on its own, it is usually far enough
+        // back not to overlap
+        let earlier = match comparison {
+            Some(c) => Some(fetch_samples(&mut conn, client.id, c.from, c.to).await?),
+            None => None,
+        };
+        if samples.is_empty()
+            && earlier.as_ref().is_none_or(Vec::is_empty)
+            && params.client.is_none()
+        {
+            continue;
+        }
+
+        let values = bucket_values(expr, &samples, &buckets, params.step, 0, params.fill);
+        let compare_values = earlier.zip(comparison).map(|(earlier, c)| {
+            bucket_values(expr, &earlier, &buckets, params.step, c.offset, params.fill)
+        });
+        let reboots = fetch_reboots(&mut *conn, client.id, params.start, end).await?;
+        results.push(QueryRangeResult {
+            client_id: client.id,
+            name: client.name,
+            display_name: client.display_name,
+            timezone: client.timezone,
+            values,
+            compare_values,
+            reboots,
+        });
+    }
+
+    Ok(QueryRangeResponse {
+        start: params.start,
+        end,
+        step: params.step,
+        buckets,
+        compare_offset: comparison.map(|c| c.offset),
+        results,
+    })
+}
+
+/// Value of each bucket evaluated `offset` seconds before its end, so the
+/// values of an earlier range line up with the buckets of the requested one.
+fn bucket_values(
+    expr: &Expr,
+    samples: &[Sample],
+    buckets: &[i64],
+    step: i64,
+    offset: i64,
+    fill: Fill,
+) -> Vec<Option<f64>> {
+    let mut values: Vec<_> = buckets
+        .iter()
+        .map(|start| expr.eval_bucket(samples, start + step - offset, step))
+        .collect();
+    self::fill(&mut values, fill);
+    values
+}
+
+/// The earlier range a `compare_offset` evaluates, samples of the seconds
+/// `from < t <= to`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Comparison {
+    offset: i64,
+    from: i64,
+    to: i64,
+}
+
+impl Comparison {
+    /// The range `offset` seconds before `start..end`, with the `lookback`
+    /// of its first bucket.
+    fn new(offset: i64, start: i64, end: i64, lookback: i64) -> Result<Self, QueryError> {
+        let from = start
+            .checked_sub(offset)
+            .and_then(|from| from.checked_sub(lookback));
+        match (from, end.checked_sub(offset)) {
+            (Some(from), Some(to)) => Ok(Comparison { offset, from, to }),
+            _ => Err(QueryError::InvalidRange(
+                "compare_offset reaches out of range".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Seconds of a `compare_offset`, e.g. `7d`.
+fn compare_offset(offset: &str) -> Result<i64, QueryError> {
+    parse_range(offset).ok_or_else(|| {
+        QueryError::InvalidRange(format!(
+            "invalid compare_offset '{offset}', expected e.g. 1d or 7d"
+        ))
+    })
+}
+
+/// `eval` refused with `QueryError::Timeout` once it took `query_timeout`,
+/// dropping it interrupts the statements of its interruptible reader.
+pub(super) async fn with_timeout<T>(
+    state: &AppState,
+    eval: impl Future<Output = Result<T, QueryError>>,
+) -> Result<T, QueryError> {
+    let timeout = Duration::from_secs(state.conf.query_timeout);
+    tokio::time::timeout(timeout, eval)
+        .await
+        .map_err(|_| QueryError::Timeout(timeout.as_secs()))?
+}
+
+/// Current unix timestamp in seconds, the default time of queries.
+pub(super) fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// `time` if it is within `0..=MAX_TIME`, so windows around it can be
+/// computed and turned into milliseconds without overflowing.
+pub(super) fn check_time(name: &str, time: i64) -> Result<i64, QueryError> {
+    match (0..=MAX_TIME).contains(&time) {
+        true => Ok(time),
+        false => Err(QueryError::InvalidRange(format!(
+            "{name} must be within 0..={MAX_TIME}"
+        ))),
+    }
+}
+
+/// Starts of the buckets of width `step` from `start` that end by `end`.
+pub(super) fn buckets(start: i64, end: i64, step: i64) -> Result<Vec<i64>, QueryError> {
+    check_time("start", start)?;
+    check_time("end", end)?;
+    if step <= 0 {
+        return Err(QueryError::InvalidRange("step must be positive".to_owned()));
+    }
+    if step > MAX_RANGE {
+        return Err(QueryError::InvalidRange(format!(
+            "step must be at most {MAX_RANGE} seconds"
+        )));
+    }
+    if end < start {
+        return Err(QueryError::InvalidRange(
+            "end must not be before start".to_owned(),
+        ));
+    }
+    let count = (end - start) / step;
+    if count > MAX_BUCKETS {
+        return Err(QueryError::InvalidRange(format!(
+            "{count} buckets requested, at most {MAX_BUCKETS} are allowed"
+        )));
+    }
+    Ok((0..count).map(|i| start + i * step).collect())
+}
+
+pub(super) fn fill(values: &mut [Option<f64>], fill: Fill) {
+    match fill {
+        Fill::Null => {}
+        Fill::Previous => {
+            let mut previous = None;
+            for value in values {
+                match value {
+                    Some(v) => previous = Some(*v),
+                    None => *value = previous,
+                }
+            }
+        }
+        Fill::Zero => {
+            for value in values.iter_mut().filter(|v| v.is_none()) {
+                *value = Some(0.0);
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum QueryError {
+    #[error("Invalid expression: {0}")]
+    InvalidExpr(#[from] ParseError),
+    #[error("Invalid range: {0}")]
+    InvalidRange(String),
+    #[error("Client not found")]
+    ClientNotFound,
+    #[error("Query timed out after {0} seconds")]
+    Timeout(u64),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for QueryError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            QueryError::InvalidExpr(_) | QueryError::InvalidRange(_) => StatusCode::BAD_REQUEST,
+            QueryError::ClientNotFound => StatusCode::NOT_FOUND,
+            QueryError::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            QueryError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_boundaries() {
+        assert_eq!(buckets(100, 130, 10).unwrap(), [100, 110, 120]);
+        // a partial bucket at the end is left out
+        assert_eq!(buckets(100, 135, 10).unwrap(), [100, 110, 120]);
+        assert!(buckets(100, 100, 10).unwrap().is_empty());
+        assert!(buckets(100, 130, 0).is_err());
+        assert!(buckets(130, 100, 10).is_err());
+        assert!(buckets(0, i64::MAX, 1).is_err());
+        assert!(buckets(i64::MIN, 100, 10).is_err());
+        assert!(buckets(100, 130, i64::MAX).is_err());
+        assert!(check_time("time", i64::MAX).is_err());
+        assert_eq!(check_time("time", 1_700_000_000).unwrap(), 1_700_000_000);
+    }
+
+    #[test]
+    fn compared_buckets() {
+        const DAY: i64 = 24 * 60 * 60;
+        // an hourly sample over two days, cpu is the day it was taken on
+        let samples = (0..48)
+            .map(|hour| Sample {
+                sample_time: (hour * 3600 + 1800) * 1000,
+                cpu: Some(100.0 * (hour / 24) as f64),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let expr: Expr = "avg_over_time(cpu[1h])".parse().unwrap();
+        let buckets = buckets(DAY, DAY + 4 * 3600, 3600).unwrap();
+
+        let values = bucket_values(&expr, &samples, &buckets, 3600, 0, Fill::Null);
+        assert_eq!(values, [Some(1.0); 4]);
+        let compared = bucket_values(&expr, &samples, &buckets, 3600, DAY, Fill::Null);
+        assert_eq!(compared, [Some(0.0); 4]);
+        // before the first sample
+        let compared = bucket_values(&expr, &samples, &buckets, 3600, 2 * DAY, Fill::Zero);
+        assert_eq!(compared, [Some(0.0); 4]);
+        let compared = bucket_values(&expr, &samples, &buckets, 3600, 2 * DAY, Fill::Null);
+        assert_eq!(compared, [None; 4]);
+
+        assert_eq!(compare_offset("7d").unwrap(), 7 * DAY);
+        assert!(compare_offset("7").is_err());
+        assert!(compare_offset("-1d").is_err());
+        assert!(compare_offset("999999999999999d").is_err());
+        assert_eq!(
+            Comparison::new(DAY, 2 * DAY, 3 * DAY, 3600).unwrap(),
+            Comparison {
+                offset: DAY,
+                from: DAY - 3600,
+                to: 2 * DAY
+            }
+        );
+        assert!(Comparison::new(MAX_RANGE, i64::MIN + 1, 0, 3600).is_err());
+    }
+
+    #[test]
+    fn gap_filling() {
+        let values = [None, Some(1.0), None, None, Some(2.0), None];
+
+        let mut filled = values;
+        fill(&mut filled, Fill::Null);
+        assert_eq!(filled, values);
+
+        let mut filled = values;
+        fill(&mut filled, Fill::Previous);
+        assert_eq!(
+            filled,
+            [None, Some(1.0), Some(1.0), Some(1.0), Some(2.0), Some(2.0)]
+        );
+
+        let mut filled = values;
+        fill(&mut filled, Fill::Zero);
+        assert_eq!(
+            filled,
+            [
+                Some(0.0),
+                Some(1.0),
+                Some(0.0),
+                Some(0.0),
+                Some(2.0),
+                Some(0.0)
+            ]
+        );
+    }
+}
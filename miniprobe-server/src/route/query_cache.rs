@@ -0,0 +1,265 @@
+//! Responses of `/query`, `/query_range` and the fleet queries kept for a few
+//! seconds, so a dashboard watched by many users evaluates its queries once
+//! instead of once per viewer.
+//!
+//! Responses are kept by endpoint and query string. Each covers a client, or
+//! every client, and a time range. A sample arriving for a covered client
+//! with its time in that range drops the response early, e.g. one buffered
+//! by a client while disconnected. Newer samples wait for the TTL, the
+//! response was right for its time. A response made while any sample
+//! arrived is not kept, it may have read the database before the sample.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json,
+    body::Bytes,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use confique::Config;
+use serde::Serialize;
+
+#[derive(Config, Debug)]
+pub struct QueryCacheConf {
+    /// Seconds a query response is served again to identical queries, 0
+    /// disables the cache
+    #[config(default = 5)]
+    pub ttl: u64,
+
+    /// Most responses kept, the oldest are dropped first
+    #[config(default = 256)]
+    pub max_entries: usize,
+}
+
+/// What a response depends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Covers {
+    /// The client queried, `None` for all of them
+    pub client: Option<i64>,
+    /// Unix timestamps in seconds of the first and last sample time read
+    pub from: i64,
+    pub to: i64,
+}
+
+impl Covers {
+    fn includes(&self, client_id: i64, sample_time: i64) -> bool {
+        self.client.is_none_or(|client| client == client_id)
+            && (self.from..=self.to).contains(&sample_time)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    endpoint: &'static str,
+    query: String,
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    made: Instant,
+    covers: Covers,
+    /// The response serialized as JSON
+    body: Bytes,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    responses: HashMap<CacheKey, CachedResponse>,
+    /// Counts the calls of `invalidate`. A response made while it changed
+    /// may have missed the sample and is not kept
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QueryCache(Arc<Mutex<Entries>>);
+
+impl QueryCache {
+    /// The response to the query `query` of `endpoint` kept from an identical
+    /// query, otherwise that of `make`, kept if it succeeds.
+    pub async fn get_or_make<T, E>(
+        &self,
+        conf: &QueryCacheConf,
+        endpoint: &'static str,
+        query: Option<String>,
+        covers: Covers,
+        make: impl Future<Output = Result<T, E>>,
+    ) -> Result<Response, E>
+    where
+        T: Serialize,
+    {
+        let ttl = Duration::from_secs(conf.ttl);
+        if ttl.is_zero() {
+            return Ok(Json(make.await?).into_response());
+        }
+        let key = CacheKey {
+            endpoint,
+            query: query.unwrap_or_default(),
+        };
+        if let Some(body) = self.get(&key, ttl) {
+            return Ok(json_response(body));
+        }
+
+        // not held while making the response, identical queries arriving
+        // meanwhile are made as well
+        let generation = self.lock().generation;
+        let value = make.await?;
+        let Ok(body) = serde_json::to_vec(&value) else {
+            return Ok(Json(value).into_response());
+        };
+        let body = Bytes::from(body);
+        self.insert(key, covers, body.clone(), generation, ttl, conf.max_entries);
+        Ok(json_response(body))
+    }
+
+    /// Drop the responses a new sample of `client_id` taken at `sample_time`
+    /// (unix timestamp in seconds) would change.
+    pub fn invalidate(&self, client_id: i64, sample_time: i64) {
+        let mut entries = self.lock();
+        entries.generation += 1;
+        if !entries.responses.is_empty() {
+            entries
+                .responses
+                .retain(|_, cached| !cached.covers.includes(client_id, sample_time));
+        }
+    }
+
+    fn get(&self, key: &CacheKey, ttl: Duration) -> Option<Bytes> {
+        let entries = self.lock();
+        let cached = entries.responses.get(key)?;
+        (cached.made.elapsed() < ttl).then(|| cached.body.clone())
+    }
+
+    fn insert(
+        &self,
+        key: CacheKey,
+        covers: Covers,
+        body: Bytes,
+        generation: u64,
+        ttl: Duration,
+        max_entries: usize,
+    ) {
+        let mut entries = self.lock();
+        if entries.generation != generation {
+            return;
+        }
+        let entries = &mut entries.responses;
+        if entries.len() >= max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, cached| cached.made.elapsed() < ttl);
+        }
+        while entries.len() >= max_entries && !entries.contains_key(&key) {
+            let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, cached)| cached.made)
+                .map(|(key, _)| key.clone())
+            else {
+                // `max_entries` is 0
+                return;
+            };
+            entries.remove(&oldest);
+        }
+        entries.insert(
+            key,
+            CachedResponse {
+                made: Instant::now(),
+                covers,
+                body,
+            },
+        );
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        // entries are replaced whole, a panic can not leave one half written
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn json_response(body: Bytes) -> Response {
+    ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn key(query: &str) -> CacheKey {
+        CacheKey {
+            endpoint: "query",
+            query: query.to_owned(),
+        }
+    }
+
+    fn covers(client: Option<i64>) -> Covers {
+        Covers {
+            client,
+            from: 100,
+            to: 200,
+        }
+    }
+
+    #[test]
+    fn invalidated_by_covered_samples() {
+        let cache = QueryCache::default();
+        cache.insert(key("one"), covers(Some(1)), Bytes::new(), 0, TTL, 10);
+        cache.insert(key("all"), covers(None), Bytes::new(), 0, TTL, 10);
+
+        // too new or another client
+        cache.invalidate(1, 201);
+        cache.invalidate(2, 150);
+        assert!(cache.get(&key("one"), TTL).is_some());
+        assert!(cache.get(&key("all"), TTL).is_none());
+
+        cache.invalidate(1, 200);
+        assert!(cache.get(&key("one"), TTL).is_none());
+    }
+
+    #[test]
+    fn expires_and_evicts_the_oldest() {
+        let cache = QueryCache::default();
+        cache.insert(key("a"), covers(None), Bytes::new(), 0, TTL, 2);
+        assert!(cache.get(&key("a"), TTL).is_some());
+        assert!(cache.get(&key("a"), Duration::ZERO).is_none());
+
+        cache.insert(key("b"), covers(None), Bytes::new(), 0, TTL, 2);
+        cache.insert(key("c"), covers(None), Bytes::new(), 0, TTL, 2);
+        assert!(cache.get(&key("a"), TTL).is_none());
+        assert!(cache.get(&key("b"), TTL).is_some());
+        assert!(cache.get(&key("c"), TTL).is_some());
+
+        cache.insert(key("d"), covers(None), Bytes::new(), 0, TTL, 0);
+        assert!(cache.get(&key("d"), TTL).is_none());
+    }
+
+    #[tokio::test]
+    async fn made_while_invalidated_not_kept() {
+        let cache = QueryCache::default();
+        let conf = QueryCacheConf {
+            ttl: 60,
+            max_entries: 10,
+        };
+        let make = async {
+            // a sample stored while the response is being made
+            cache.invalidate(1, 150);
+            Ok::<_, ()>(1)
+        };
+        let res = cache
+            .get_or_make(&conf, "query", None, covers(Some(1)), make)
+            .await;
+        assert!(res.is_ok());
+        assert!(cache.get(&key(""), TTL).is_none());
+
+        let res = cache
+            .get_or_make(&conf, "query", None, covers(Some(1)), async {
+                Ok::<_, ()>(1)
+            })
+            .await;
+        assert!(res.is_ok());
+        assert!(cache.get(&key(""), TTL).is_some());
+    }
+}
@@ -0,0 +1,76 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct RebootsParams {
+    /// Number of reboots to return, newest first
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+fn default_limit() -> u32 {
+    20
+}
+
+#[derive(Debug, Serialize)]
+pub struct RebootOverview {
+    /// Unix timestamp in seconds the host booted at
+    pub boot_time: i64,
+    /// Unix timestamp in seconds the host booted at before
+    pub previous_boot_time: i64,
+    /// The first session after the reboot
+    pub session_id: i64,
+    /// Unix timestamp in seconds the session noticing the reboot was created
+    pub detected_at: i64,
+}
+
+pub async fn list_reboots(
+    State(state): State<AppState>,
+    Path(client_id): Path<i64>,
+    Query(params): Query<RebootsParams>,
+) -> Result<Json<Vec<RebootOverview>>, RebootsError> {
+    let client = sqlx::query_scalar!("SELECT id FROM clients WHERE id = ?", client_id)
+        .fetch_optional(&state.db.reader)
+        .await?;
+    if client.is_none() {
+        return Err(RebootsError::ClientNotFound);
+    }
+
+    let reboots = sqlx::query_as!(
+        RebootOverview,
+        "SELECT boot_time, previous_boot_time, session_id, detected_at FROM reboots \
+            WHERE client_id = ? \
+            ORDER BY boot_time DESC \
+            LIMIT ?",
+        client_id,
+        params.limit
+    )
+    .fetch_all(&state.db.reader)
+    .await?;
+    Ok(Json(reboots))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RebootsError {
+    #[error("Client not found")]
+    ClientNotFound,
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for RebootsError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            RebootsError::ClientNotFound => StatusCode::NOT_FOUND,
+            RebootsError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
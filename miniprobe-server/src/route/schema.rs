@@ -0,0 +1,71 @@
+use axum::{
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+    response::{IntoResponse, Response},
+};
+use miniprobe_proto::{METRICS_SCHEMA_HASH, msg::SCHEMA_HEADER};
+
+/// Extractor refusing clients whose `miniprobe-schema` header differs from
+/// our [`METRICS_SCHEMA_HASH`], before their samples fail to decode. Clients
+/// predating the header are let through.
+#[derive(Clone, Copy, Debug)]
+pub struct SchemaCheck;
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "client/server proto mismatch: client metrics schema {client}, server {METRICS_SCHEMA_HASH:016x}, \
+    update the older one"
+)]
+pub struct SchemaMismatch {
+    client: String,
+}
+
+impl IntoResponse for SchemaMismatch {
+    fn into_response(self) -> Response {
+        (StatusCode::CONFLICT, self.to_string()).into_response()
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for SchemaCheck {
+    type Rejection = SchemaMismatch;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(SCHEMA_HEADER) else {
+            return Ok(SchemaCheck);
+        };
+        let client = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        match u64::from_str_radix(&client, 16) {
+            Ok(hash) if hash == METRICS_SCHEMA_HASH => Ok(SchemaCheck),
+            _ => Err(SchemaMismatch { client }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::*;
+
+    async fn check(value: Option<&str>) -> Result<SchemaCheck, SchemaMismatch> {
+        let mut req = Request::builder();
+        if let Some(value) = value {
+            req = req.header(SCHEMA_HEADER, value);
+        }
+        let (mut parts, _) = req.body(()).unwrap().into_parts();
+        SchemaCheck::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn schema_check() {
+        assert!(check(None).await.is_ok());
+        assert!(
+            check(Some(&format!("{METRICS_SCHEMA_HASH:016x}")))
+                .await
+                .is_ok()
+        );
+        let err = check(Some("0123456789abcdef")).await.unwrap_err();
+        assert!(err.to_string().starts_with("client/server proto mismatch"));
+        assert!(check(Some("garbage")).await.is_err());
+    }
+}
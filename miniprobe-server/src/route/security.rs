@@ -0,0 +1,74 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppState, security::SecurityEventKind};
+
+#[derive(Debug, Deserialize)]
+pub struct SecurityEventsParams {
+    /// Number of events to return, newest first
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    /// Only events of this kind
+    pub kind: Option<SecurityEventKind>,
+    /// Only events of this client
+    pub client: Option<i64>,
+}
+
+fn default_limit() -> u32 {
+    50
+}
+
+#[derive(Debug, Serialize)]
+pub struct SecurityEventRow {
+    pub id: i64,
+    /// Unix timestamp in seconds
+    pub time: i64,
+    /// See `SecurityEventKind`
+    pub kind: String,
+    pub client_id: Option<i64>,
+    /// Name of the client at the time, kept when it is removed
+    pub client_name: Option<String>,
+    /// Peer address of the request, `None` on unix sockets
+    pub address: Option<String>,
+    pub detail: String,
+}
+
+/// Failed authentications, lockouts and other security events.
+pub async fn list_security_events(
+    State(state): State<AppState>,
+    Query(params): Query<SecurityEventsParams>,
+) -> Result<Json<Vec<SecurityEventRow>>, SecurityEventsError> {
+    let kind = params.kind.map(SecurityEventKind::as_str);
+    let events = sqlx::query_as!(
+        SecurityEventRow,
+        "SELECT id, time, kind, client_id, client_name, address, detail FROM security_events \
+            WHERE ($1 IS NULL OR kind = $1) AND ($2 IS NULL OR client_id = $2) \
+            ORDER BY id DESC LIMIT $3",
+        kind,
+        params.client,
+        params.limit
+    )
+    .fetch_all(&state.db.reader)
+    .await?;
+    Ok(Json(events))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SecurityEventsError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for SecurityEventsError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            SecurityEventsError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,99 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use miniprobe_proto::UnixMillis;
+use serde::{Deserialize, Serialize};
+use sqlx::migrate::MigrateError;
+
+use crate::{
+    AppState,
+    db::{MIGRATOR, MigrationStatus, SAMPLES_MIGRATOR},
+    latency::{self, LatencyRollup},
+    quota,
+};
+
+#[derive(Debug, Serialize)]
+pub struct ServerInfo {
+    pub version: &'static str,
+    /// Latest applied migration, `None` for a fresh database
+    pub schema_version: Option<i64>,
+    /// Latest migration known to this server
+    pub latest_schema_version: Option<i64>,
+    /// Latest applied migration of the samples database
+    pub samples_schema_version: Option<i64>,
+    /// Latest samples migration known to this server
+    pub latest_samples_schema_version: Option<i64>,
+    /// Versions of migrations not applied yet, of both databases
+    pub pending_migrations: Vec<i64>,
+    /// Size of both databases in bytes
+    pub db_size: i64,
+    /// Size cap of the database in bytes, ingest stops once reached
+    pub max_db_size: Option<i64>,
+    /// Latency of the samples of the latest minute samples arrived in, kept
+    /// for a week in `ingest_latency`
+    pub ingest_latency: Option<LatencyRollup>,
+}
+
+pub async fn server_info(
+    State(state): State<AppState>,
+) -> Result<Json<ServerInfo>, ServerInfoError> {
+    let status = MigrationStatus::check(&state.db.reader, &MIGRATOR).await?;
+    let samples_status = MigrationStatus::check(&state.db.samples, &SAMPLES_MIGRATOR).await?;
+    let db_size = quota::db_size(&state.db.reader).await?;
+    let ingest_latency = latency::latest(&state.db.reader).await?;
+
+    Ok(Json(ServerInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        schema_version: status.current,
+        latest_schema_version: status.latest,
+        samples_schema_version: samples_status.current,
+        latest_samples_schema_version: samples_status.latest,
+        pending_migrations: status
+            .pending
+            .into_iter()
+            .chain(samples_status.pending)
+            .map(|(v, _)| v)
+            .collect(),
+        db_size,
+        max_db_size: state.conf.quotas.max_db_size(),
+        ingest_latency,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IngestLatencyParams {
+    /// Unix timestamp in seconds of the first minute to return
+    pub start: i64,
+    /// Unix timestamp in seconds of the last minute to return, defaults to now
+    pub end: Option<i64>,
+}
+
+/// The ingest latency rollups of a range of minutes, oldest first.
+pub async fn ingest_latency(
+    State(state): State<AppState>,
+    Query(params): Query<IngestLatencyParams>,
+) -> Result<Json<Vec<LatencyRollup>>, ServerInfoError> {
+    let end = params
+        .end
+        .unwrap_or_else(|| UnixMillis::now().as_secs() as i64);
+    Ok(Json(
+        latency::range(&state.db.reader, params.start, end).await?,
+    ))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ServerInfoError {
+    #[error("Migration error: {0}")]
+    MigrateError(#[from] MigrateError),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for ServerInfoError {
+    fn into_response(self) -> Response {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+    }
+}
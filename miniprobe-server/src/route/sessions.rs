@@ -1,79 +1,275 @@
 use axum::{
-    extract::{FromRequestParts, State},
+    Json,
+    extract::{FromRequestParts, Path, Query, State},
     http::{StatusCode, request::Parts},
     response::{IntoResponse, Response},
 };
 use axum_auth::AuthBearer;
-use miniprobe_proto::msg::{CreateSessionReq, CreateSessionResp, SessionToken};
-use std::{collections::HashMap, sync::Arc};
-use tracing::debug;
+use hmac::{Hmac, Mac};
+use miniprobe_proto::{
+    UnixMillis,
+    limits::{CLIENT_TOKEN_LENGTH, HEARTBEAT_INTERVAL, MAX_BATCH_SIZE, MAX_DECODED_SIZE},
+    msg::{CreateSessionReq, CreateSessionResp, SessionToken},
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+use tracing::{Instrument, Span, debug, debug_span, field, info, warn};
 
 use crate::{
-    AppState, CLINET_TOKEN_LENGTH, index_client_token, lock::SharedOwnable, postcard::Postcard,
+    AppState, SCRAPE_INTERVAL,
+    events::{Event, SessionState},
+    index_client_token,
+    lock::SharedOwnable,
+    postcard::Postcard,
+    quota::{self, ClientQuota, QuotaExceeded},
+    reboot,
+    route::{access::PeerAddress, agent::ClientAgent, schema::SchemaCheck},
+    security::{SecurityEvent, SecurityEventKind},
 };
 
 pub async fn create_session(
+    _: SchemaCheck,
+    agent: ClientAgent,
+    PeerAddress(peer): PeerAddress,
     State(state): State<AppState>,
-    Postcard(CreateSessionReq { token, system_info }): Postcard<CreateSessionReq>,
+    Postcard(request): Postcard<CreateSessionReq>,
 ) -> Result<Postcard<CreateSessionResp>, CreateSessionError> {
-    let system_status = system_info.system;
-    let mut tx = state.pool.begin().await?;
+    // the client is recorded once the token is checked
+    let span = debug_span!(
+        "create_session",
+        client_id = field::Empty,
+        host = request.system_info.system.host_name.as_deref()
+    );
+    open_session(agent, peer, state, request)
+        .instrument(span)
+        .await
+}
 
-    if token.len() != CLINET_TOKEN_LENGTH {
-        return Err(CreateSessionError::InvalidToken(token));
+async fn open_session(
+    agent: ClientAgent,
+    peer: Option<IpAddr>,
+    state: AppState,
+    CreateSessionReq { token, system_info }: CreateSessionReq,
+) -> Result<Postcard<CreateSessionResp>, CreateSessionError> {
+    if state.security.is_locked_out(peer) {
+        return Err(CreateSessionError::LockedOut);
     }
-
-    let token_idx = index_client_token(&token);
+    let system_status = system_info.system;
+    let capabilities = serde_json::to_string(&system_info.capabilities)
+        .expect("capabilities are always serializable");
+    let mut tx = state.db.writer.begin().await?;
 
     // check if token exists in the database
-    let record = sqlx::query!(
-        "SELECT id, token_hash FROM clients WHERE token_idx = $1",
-        token_idx
-    )
-    .fetch_all(&mut *tx)
-    .await?
-    .into_iter()
-    .find(|r| password_auth::verify_password(&token, &r.token_hash).is_ok());
+    let record = if token.len() == CLIENT_TOKEN_LENGTH {
+        let token_idx = index_client_token(&token);
+        sqlx::query!(
+            "SELECT id, name, token_hash, expected_hostname FROM clients WHERE token_idx = $1",
+            token_idx
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .find(|r| password_auth::verify_password(&token, &r.token_hash).is_ok())
+    } else {
+        None
+    };
 
-    let client_id = if let Some(record) = record {
-        record.id
+    let (client_id, client_name, expected_hostname) = if let Some(record) = record {
+        (record.id, record.name, record.expected_hostname)
     } else {
+        state.security.record(SecurityEvent::new(
+            SecurityEventKind::ClientAuthFailed,
+            peer,
+            "unknown client token",
+        ));
         return Err(CreateSessionError::InvalidToken(token));
     };
+    Span::current().record("client_id", client_id);
+    let security_event = |kind, detail: String| {
+        SecurityEvent::new(kind, peer, detail).client(client_id, Some(client_name.clone()))
+    };
+
+    // a token copied to another machine would merge the data of both
+    let hostname_mismatch = expected_hostname
+        .filter(|expected| !hostname_matches(expected, system_status.host_name.as_deref()));
+    if let Some(expected) = &hostname_mismatch {
+        let host_name = system_status.host_name.as_deref().unwrap_or_default();
+        state.security.record(security_event(
+            SecurityEventKind::HostnameMismatch,
+            format!("bound to hostname '{expected}', not '{host_name}'"),
+        ));
+        match state.conf.hostname_mismatch {
+            HostnameMismatch::Reject => {
+                return Err(CreateSessionError::HostnameMismatch {
+                    expected: expected.clone(),
+                    actual: host_name.to_owned(),
+                });
+            }
+            HostnameMismatch::Flag => {
+                warn!(expected, "session created from an unexpected hostname")
+            }
+        }
+    }
+    let hostname_mismatch = hostname_mismatch.is_some();
+
+    // refuse early so the client backs off instead of being cut off right after connecting
+    if quota::db_size_exceeded(&mut *tx, &state.conf.quotas).await? {
+        return Err(QuotaExceeded::DbSize.into());
+    }
+    if let Err(e) = ClientQuota::load(&mut *tx, &state.conf.quotas, client_id)
+        .await?
+        .check()
+    {
+        state.security.record(security_event(
+            SecurityEventKind::QuotaExceeded,
+            e.to_string(),
+        ));
+        return Err(e.into());
+    }
+
+    // a token copied elsewhere, or a client that moved
+    let peer_address = peer.map(|peer| peer.to_string());
+    if peer_address.is_some() {
+        let seen = sqlx::query!(
+            r#"SELECT
+                EXISTS(SELECT 1 FROM sessions WHERE client_id = $1 AND peer_address IS NOT NULL)
+                    AS "known!: bool",
+                EXISTS(SELECT 1 FROM sessions WHERE client_id = $1 AND peer_address = $2)
+                    AS "seen!: bool""#,
+            client_id,
+            peer_address
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if seen.known && !seen.seen {
+            state.security.record(security_event(
+                SecurityEventKind::NewAddress,
+                "token used from an address new to the client".to_owned(),
+            ));
+        }
+    }
+
+    let boot = reboot::Boot {
+        time: system_status.boot_time.map(|time| time.as_secs() as i64),
+        id: system_status.boot_id,
+    };
+    let reboot = reboot::detect(&mut tx, client_id, &boot).await?;
 
     // create a new session
     let session = sqlx::query_as!(
         Session,
         "INSERT INTO sessions \
-            (client_id, system_name, kernel_version, os_version, host_name, cpu_arch) \
-            VALUES ($1, $2, $3, $4, $5, $6) \
-            RETURNING id",
+            (client_id, system_name, kernel_version, os_version, host_name, cpu_arch, capabilities, \
+            hostname_mismatch, peer_address, boot_time, boot_id, machine_id) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) \
+            RETURNING id, client_id, host_name",
         client_id,
         system_status.system_name,
         system_status.kernel_version,
         system_status.os_version,
         system_status.host_name,
-        system_status.cpu_arch
+        system_status.cpu_arch,
+        capabilities,
+        hostname_mismatch,
+        peer_address,
+        boot.time,
+        boot.id,
+        system_status.machine_id
     )
     .fetch_one(&mut *tx)
     .await?;
 
+    let session_id = session.id;
+    if let Some(reboot) = reboot {
+        reboot::record(&mut tx, client_id, session_id, reboot).await?;
+    }
     let token = state.session_mgr.write().await.add_session(session);
 
     tx.commit().await?;
 
-    debug!(client_id, ?token, "session created");
+    debug!(
+        session_id,
+        ?token,
+        ?system_info.capabilities,
+        user_agent = agent.user_agent,
+        protocol = agent.protocol,
+        "session created"
+    );
+    // sending only fails without subscribers
+    let time = UnixMillis::now().as_secs() as i64;
+    state
+        .events
+        .send(Event::Session {
+            state: SessionState::Created,
+            session_id,
+            client_id,
+            client_name: client_name.clone(),
+            closed_by: None,
+            close_code: None,
+            close_reason: None,
+            time,
+        })
+        .ok();
+    if let Some(reboot) = reboot {
+        info!(
+            session_id,
+            boot_time = reboot.boot_time,
+            previous_boot_time = reboot.previous_boot_time,
+            "host rebooted"
+        );
+        state
+            .events
+            .send(Event::Reboot {
+                client_id,
+                client_name,
+                session_id,
+                boot_time: reboot.boot_time,
+                previous_boot_time: reboot.previous_boot_time,
+                time,
+            })
+            .ok();
+    }
 
     Ok(Postcard(CreateSessionResp {
         session_token: token,
-        scrape_interval: 5,
+        scrape_interval: SCRAPE_INTERVAL.as_secs(),
+        cpu_report: state.conf.cpu_report,
+        server_time: UnixMillis::now(),
+        max_message_size: MAX_DECODED_SIZE as u64,
+        max_batch_size: MAX_BATCH_SIZE,
+        heartbeat_interval: HEARTBEAT_INTERVAL.as_secs(),
     }))
 }
 
+/// Whether the hostname of a session matches the expected one of its
+/// client, case-insensitively as hostnames are.
+fn hostname_matches(expected: &str, host_name: Option<&str>) -> bool {
+    host_name.is_some_and(|host_name| host_name.eq_ignore_ascii_case(expected))
+}
+
+/// What happens when a client creates a session from another hostname than
+/// the one its token is bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostnameMismatch {
+    /// The session is created and flagged, and a warning logged
+    Flag,
+    /// The session is refused
+    #[default]
+    Reject,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum CreateSessionError {
     #[error("Invalid token: {0}")]
     InvalidToken(String),
+    #[error("Too many failed authentications, try again later")]
+    LockedOut,
+    #[error("Token is bound to hostname '{expected}', not '{actual}'")]
+    HostnameMismatch { expected: String, actual: String },
+    #[error(transparent)]
+    QuotaExceeded(#[from] QuotaExceeded),
     #[error("Database error: {0}")]
     DatabaseError(#[from] sqlx::Error),
 }
@@ -84,46 +280,216 @@ impl IntoResponse for CreateSessionError {
             CreateSessionError::InvalidToken(_) => {
                 (StatusCode::UNAUTHORIZED, self.to_string()).into_response()
             }
+            CreateSessionError::HostnameMismatch { .. } => {
+                (StatusCode::FORBIDDEN, self.to_string()).into_response()
+            }
+            CreateSessionError::LockedOut => {
+                (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response()
+            }
+            CreateSessionError::QuotaExceeded(QuotaExceeded::Samples(_)) => {
+                (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response()
+            }
+            CreateSessionError::QuotaExceeded(QuotaExceeded::DbSize) => {
+                (StatusCode::INSUFFICIENT_STORAGE, self.to_string()).into_response()
+            }
             _ => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response(),
         }
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SessionsParams {
+    /// Number of sessions to return, newest first
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+fn default_limit() -> u32 {
+    20
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionOverview {
+    pub id: i64,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+    /// Unix timestamp in seconds of the latest message of the client
+    pub last_active: i64,
+    pub host_name: Option<String>,
+    pub os_version: Option<String>,
+    /// Id of the installation of the host, kept across hostname changes
+    pub machine_id: Option<String>,
+    /// Id of the boot the session started in
+    pub boot_id: Option<String>,
+    /// Created from another hostname than the client is bound to
+    pub hostname_mismatch: bool,
+    /// How the latest ingress websocket was closed, `None` while it is open
+    /// or if the client never connected
+    pub close: Option<SessionClose>,
+    pub codec: SessionCodec,
+}
+
+/// How much the compression of the ingress websocket saved.
+#[derive(Debug, Serialize)]
+pub struct SessionCodec {
+    /// Compression of the latest connection, `None` if the client never
+    /// connected
+    pub compression: Option<String>,
+    /// `coarse` if the latest connection sent rounded values, `full`
+    /// otherwise, see `miniprobe_proto::msg::COARSE_PRECISION`
+    pub precision: Option<String>,
+    /// Bytes of all messages of the session as received
+    pub wire_bytes: i64,
+    /// Bytes of those messages after decompression
+    pub decoded_bytes: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionClose {
+    /// Unix timestamp in seconds
+    pub closed_at: i64,
+    /// `client` or `server`, whichever side closed first
+    pub closed_by: String,
+    /// Websocket close code, 1006 if the connection was lost without one
+    pub code: u16,
+    pub reason: String,
+}
+
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    Path(client_id): Path<i64>,
+    Query(params): Query<SessionsParams>,
+) -> Result<Json<Vec<SessionOverview>>, SessionsError> {
+    let client = sqlx::query_scalar!("SELECT id FROM clients WHERE id = ?", client_id)
+        .fetch_optional(&state.db.reader)
+        .await?;
+    if client.is_none() {
+        return Err(SessionsError::ClientNotFound);
+    }
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT id, unixepoch(created_at) AS "created_at!: i64", last_active, host_name,
+            os_version, machine_id, boot_id, hostname_mismatch, closed_at, closed_by, close_code AS "close_code: u16", close_reason,
+            compression, precision, wire_bytes, decoded_bytes
+        FROM sessions
+        WHERE client_id = ?
+        ORDER BY id DESC
+        LIMIT ?
+        "#,
+        client_id,
+        params.limit
+    )
+    .fetch_all(&state.db.reader)
+    .await?;
+
+    let sessions = rows
+        .into_iter()
+        .map(|r| SessionOverview {
+            id: r.id,
+            created_at: r.created_at,
+            last_active: r.last_active,
+            host_name: r.host_name,
+            os_version: r.os_version,
+            machine_id: r.machine_id,
+            boot_id: r.boot_id,
+            hostname_mismatch: r.hostname_mismatch,
+            close: match (r.closed_at, r.closed_by, r.close_code) {
+                (Some(closed_at), Some(closed_by), Some(code)) => Some(SessionClose {
+                    closed_at,
+                    closed_by,
+                    code,
+                    reason: r.close_reason.unwrap_or_default(),
+                }),
+                _ => None,
+            },
+            codec: SessionCodec {
+                compression: r.compression,
+                precision: r.precision,
+                wire_bytes: r.wire_bytes,
+                decoded_bytes: r.decoded_bytes,
+            },
+        })
+        .collect();
+    Ok(Json(sessions))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SessionsError {
+    #[error("Client not found")]
+    ClientNotFound,
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for SessionsError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            SessionsError::ClientNotFound => StatusCode::NOT_FOUND,
+            SessionsError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Sessions by a keyed hash of their token. The key is random per process so
+/// hashing the presented token leaks nothing usable, the token itself is then
+/// compared in constant time.
 #[derive(Clone, Debug)]
 pub struct SessionManager {
-    authed_sessions: HashMap<SessionToken, Arc<SharedOwnable<Session>>>,
+    key: [u8; 32],
+    authed_sessions: HashMap<[u8; 32], (SessionToken, Arc<SharedOwnable<Session>>)>,
 }
 
 impl SessionManager {
     pub fn new() -> Self {
         SessionManager {
+            key: rand::random(),
             authed_sessions: HashMap::new(),
         }
     }
 
+    fn lookup_key(&self, token: &SessionToken) -> [u8; 32] {
+        HmacSha256::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length")
+            .chain_update(token.as_bytes())
+            .finalize()
+            .into_bytes()
+            .into()
+    }
+
     pub fn add_session(&mut self, session: Session) -> SessionToken {
         // ensure the token is unique
-        let token = loop {
+        let (token, lookup_key) = loop {
             let token = SessionToken::random();
-            if !self.authed_sessions.contains_key(&token) {
-                break token;
+            let lookup_key = self.lookup_key(&token);
+            if !self.authed_sessions.contains_key(&lookup_key) {
+                break (token, lookup_key);
             }
         };
 
         self.authed_sessions
-            .insert(token.clone(), SharedOwnable::new(session));
+            .insert(lookup_key, (token.clone(), SharedOwnable::new(session)));
 
         token
     }
 
     pub fn get_session(&self, token: &SessionToken) -> Option<Arc<SharedOwnable<Session>>> {
-        self.authed_sessions.get(token).cloned()
+        self.authed_sessions
+            .get(&self.lookup_key(token))
+            .filter(|(stored, _)| stored == token)
+            .map(|(_, session)| session.clone())
     }
 }
 
 #[derive(Clone, Debug, sqlx::FromRow)]
 pub struct Session {
     pub id: i64,
+    pub client_id: i64,
+    /// Host name the client reported, for the spans of the session
+    pub host_name: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -157,7 +523,7 @@ impl FromRequestParts<AppState> for SessionLock {
     ) -> Result<Self, Self::Rejection> {
         let AuthBearer(token) = AuthBearer::from_request_parts(parts, state)
             .await
-            .map_err(|e| SessionMutexRejection::BearerRejection(e))?;
+            .map_err(SessionMutexRejection::BearerRejection)?;
 
         let session = state
             .session_mgr
@@ -173,3 +539,30 @@ impl FromRequestParts<AppState> for SessionLock {
         Ok(SessionLock(session))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_lookup() {
+        let mut mgr = SessionManager::new();
+        let token = mgr.add_session(Session {
+            id: 1,
+            client_id: 2,
+            host_name: None,
+        });
+
+        let parsed = token.to_string().parse::<SessionToken>().unwrap();
+        assert!(mgr.get_session(&parsed).is_some());
+        assert!(mgr.get_session(&SessionToken::random()).is_none());
+    }
+
+    #[test]
+    fn expected_hostname() {
+        assert!(hostname_matches("web-1", Some("web-1")));
+        assert!(hostname_matches("web-1", Some("WEB-1")));
+        assert!(!hostname_matches("web-1", Some("web-2")));
+        assert!(!hostname_matches("web-1", None));
+    }
+}
@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use super::query::now;
+use crate::{AppState, expr::MAX_RANGE};
+
+/// Most points a sparkline may have.
+const MAX_POINTS: u32 = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SparklineMetric {
+    /// Average usage over all cores, `0..=1`
+    Cpu,
+    /// Used memory over total memory, `0..=1`
+    Memory,
+    /// Used swap over total swap, `0..=1`
+    Swap,
+    /// Hottest CPU sensor in degrees Celsius
+    CpuTemperature,
+}
+
+impl SparklineMetric {
+    fn as_str(self) -> &'static str {
+        match self {
+            SparklineMetric::Cpu => "cpu",
+            SparklineMetric::Memory => "memory",
+            SparklineMetric::Swap => "swap",
+            SparklineMetric::CpuTemperature => "cpu_temperature",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SparklineParams {
+    pub metric: SparklineMetric,
+    /// Number of points
+    #[serde(default = "default_points")]
+    pub points: u32,
+    /// Seconds the points span up to now
+    #[serde(default = "default_range")]
+    pub range: i64,
+}
+
+fn default_points() -> u32 {
+    60
+}
+
+fn default_range() -> i64 {
+    60 * 60
+}
+
+/// Averages of a metric in equal buckets, oldest first, for charts too small
+/// for axes.
+#[derive(Debug, Serialize)]
+pub struct Sparkline {
+    /// Unix timestamp in seconds the first bucket starts at
+    pub start: i64,
+    /// Bucket width in seconds
+    pub step: i64,
+    /// Average of each bucket rounded to 3 decimals, `null` without samples
+    pub values: Vec<Option<f64>>,
+}
+
+/// Buckets of a whole number of seconds covering at least `range` and
+/// ending at `now`, returns the start and width.
+fn span(now: i64, range: i64, points: u32) -> Result<(i64, i64), SparklineError> {
+    if !(1..=MAX_POINTS).contains(&points) {
+        return Err(SparklineError::InvalidParams(format!(
+            "points must be within 1..={MAX_POINTS}"
+        )));
+    }
+    if !(1..=MAX_RANGE).contains(&range) {
+        return Err(SparklineError::InvalidParams(format!(
+            "range must be within 1..={MAX_RANGE}"
+        )));
+    }
+    let overflow = || SparklineError::InvalidParams("range is out of bounds".to_owned());
+    let step = range.checked_add(points as i64 - 1).ok_or_else(overflow)? / points as i64;
+    let start = step
+        .checked_mul(points as i64)
+        .and_then(|span| now.checked_sub(span))
+        .ok_or_else(overflow)?;
+    Ok((start, step))
+}
+
+pub async fn sparkline(
+    State(state): State<AppState>,
+    Path(client_id): Path<i64>,
+    Query(params): Query<SparklineParams>,
+) -> Result<Json<Sparkline>, SparklineError> {
+    let timeout = Duration::from_secs(state.conf.query_timeout);
+    tokio::time::timeout(timeout, eval_sparkline(&state, client_id, &params))
+        .await
+        .map_err(|_| SparklineError::Timeout(timeout.as_secs()))?
+        .map(Json)
+}
+
+async fn eval_sparkline(
+    state: &AppState,
+    client_id: i64,
+    params: &SparklineParams,
+) -> Result<Sparkline, SparklineError> {
+    let (start, step) = span(now(), params.range, params.points)?;
+
+    let mut conn = state.db.interruptible_reader().await?;
+    let client = sqlx::query_scalar!("SELECT id FROM clients WHERE id = ?", client_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    if client.is_none() {
+        return Err(SparklineError::ClientNotFound);
+    }
+
+    let metric = params.metric.as_str();
+    let end = start + step * params.points as i64;
+    // bucketed in the database, one row per bucket with samples
+    let rows = sqlx::query!(
+        r#"
+        SELECT (d.sample_time / 1000 - $2) / $3 AS "bucket!: i64",
+            AVG(CASE $4
+                WHEN 'cpu' THEN COALESCE(
+                    a.cpu_usage,
+                    (SELECT AVG(cpu_usage) FROM session_data_cpu WHERE session_data_id = d.id)
+                ) / 100.0
+                WHEN 'memory' THEN CAST(m.used AS REAL) / NULLIF(m.total, 0)
+                WHEN 'swap' THEN CAST(m.swap_used AS REAL) / NULLIF(m.swap_total, 0)
+                WHEN 'cpu_temperature' THEN t.cpu_temperature
+            END) AS "value: f64"
+        FROM session_data d
+        JOIN sessions s ON s.id = d.session_id
+        LEFT JOIN session_data_cpu_aggregate a ON a.session_data_id = d.id
+        LEFT JOIN session_data_memory m ON m.session_data_id = d.id
+        LEFT JOIN session_data_sensors t ON t.session_data_id = d.id
+        WHERE s.client_id = $1 AND d.sample_time >= $2 * 1000 AND d.sample_time < $5 * 1000
+        GROUP BY 1
+        "#,
+        client_id,
+        start,
+        step,
+        metric,
+        end
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut values = vec![None; params.points as usize];
+    for row in rows {
+        if let Some(value) = values.get_mut(row.bucket as usize) {
+            *value = row.value.map(|v| (v * 1000.0).round() / 1000.0);
+        }
+    }
+    Ok(Sparkline {
+        start,
+        step,
+        values,
+    })
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SparklineError {
+    #[error("Invalid parameters: {0}")]
+    InvalidParams(String),
+    #[error("Client not found")]
+    ClientNotFound,
+    #[error("Query timed out after {0} seconds")]
+    Timeout(u64),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for SparklineError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            SparklineError::InvalidParams(_) => StatusCode::BAD_REQUEST,
+            SparklineError::ClientNotFound => StatusCode::NOT_FOUND,
+            SparklineError::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            SparklineError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_range() {
+        assert_eq!(span(3600, 3600, 60).unwrap(), (0, 60));
+        // rounded up to whole seconds, reaching further back
+        assert_eq!(span(1000, 100, 30).unwrap(), (880, 4));
+        assert!(span(1000, 100, 0).is_err());
+        assert!(span(1000, 100, MAX_POINTS + 1).is_err());
+        assert!(span(1000, 0, 10).is_err());
+        assert!(span(1000, MAX_RANGE + 1, 10).is_err());
+        assert!(span(1000, i64::MAX, MAX_POINTS).is_err());
+        assert!(span(i64::MIN, MAX_RANGE, MAX_POINTS).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Public status page: up or down and the uptime of the last 24 hours of the
+//! hosts picked in `status_page.hosts`, nothing else. Served without
+//! authentication, so clients not listed never show up and no metric is
+//! published.
+
+use std::{
+    fmt::Write,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderValue, StatusCode, header},
+    response::{Html, IntoResponse, Response},
+};
+use confique::Config;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{AppState, overview::ClientState};
+
+/// Width of the buckets uptime is counted in, a host is up for a bucket if it
+/// sent a sample within it.
+const UPTIME_BUCKET_SECS: i64 = 5 * 60;
+const UPTIME_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+/// The status page, disabled by default.
+#[derive(Config, Debug)]
+pub struct StatusPageConf {
+    /// Serve `/status` and `/status.json` on the public routes, without
+    /// authentication
+    #[config(default = false)]
+    pub enabled: bool,
+
+    /// Heading of the page
+    #[config(default = "Status")]
+    pub title: String,
+
+    /// Hosts shown in this order, e.g. `[{ client = "web-1", name =
+    /// "Website" }]`, see `StatusHost`. Other clients are never shown
+    #[config(default = [])]
+    pub hosts: Vec<StatusHost>,
+
+    /// Seconds the page is cached by the server, browsers and proxies
+    #[config(default = 60)]
+    pub cache_ttl: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusHost {
+    /// Name of the client
+    pub client: String,
+    /// Name shown instead of the client name
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusPage {
+    pub title: String,
+    /// Unix timestamp in seconds the page was made at
+    pub updated: i64,
+    pub hosts: Vec<HostStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostStatus {
+    pub name: String,
+    pub status: HostState,
+    /// Percentage of the last 24 hours the host sent samples in, counted
+    /// since its first sample if younger, `None` if it never sent one
+    pub uptime_24h: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostState {
+    Up,
+    Down,
+}
+
+/// The latest page, made again once older than `cache_ttl`.
+#[derive(Debug, Clone, Default)]
+pub struct StatusPageCache(Arc<Mutex<Option<CachedPage>>>);
+
+#[derive(Debug)]
+struct CachedPage {
+    made: Instant,
+    page: Arc<StatusPage>,
+}
+
+impl StatusPageCache {
+    async fn get(&self, state: &AppState) -> sqlx::Result<Arc<StatusPage>> {
+        let ttl = Duration::from_secs(state.conf.status_page.cache_ttl);
+        // held while making the page, concurrent requests wait for it
+        let mut cached = self.0.lock().await;
+        if let Some(cached) = &*cached
+            && cached.made.elapsed() < ttl
+        {
+            return Ok(cached.page.clone());
+        }
+        let page = Arc::new(status_page(state).await?);
+        *cached = Some(CachedPage {
+            made: Instant::now(),
+            page: page.clone(),
+        });
+        Ok(page)
+    }
+}
+
+async fn status_page(state: &AppState) -> sqlx::Result<StatusPage> {
+    let conf = &state.conf.status_page;
+    let mut hosts = Vec::with_capacity(conf.hosts.len());
+    for host in &conf.hosts {
+        let row = sqlx::query!(
+            r#"
+            SELECT unixepoch() AS "now!: i64",
+                (
+                    SELECT MIN(d.sample_time) / 1000 FROM sessions s
+                    JOIN session_data d ON d.session_id = s.id
+                    WHERE s.client_id = c.id
+                ) AS "first_sample: i64",
+                (
+                    SELECT MAX(d.sample_time) / 1000 FROM sessions s
+                    JOIN session_data d ON d.session_id = s.id
+                    WHERE s.client_id = c.id
+                ) AS "last_sample: i64",
+                (
+                    SELECT COUNT(DISTINCT d.sample_time / 1000 / $2) FROM sessions s
+                    JOIN session_data d ON d.session_id = s.id
+                    WHERE s.client_id = c.id
+                        AND d.sample_time > (unixepoch() - $3) * 1000
+                ) AS "buckets!: i64"
+            FROM clients c WHERE c.name = $1
+            "#,
+            host.client,
+            UPTIME_BUCKET_SECS,
+            UPTIME_WINDOW_SECS,
+        )
+        .fetch_optional(&state.db.reader)
+        .await?;
+
+        let name = host.name.clone().unwrap_or_else(|| host.client.clone());
+        let Some(row) = row else {
+            warn!(client = host.client, "no such client on the status page");
+            hosts.push(HostStatus {
+                name,
+                status: HostState::Down,
+                uptime_24h: None,
+            });
+            continue;
+        };
+        hosts.push(HostStatus {
+            name,
+            status: match ClientState::of(row.now, row.last_sample) {
+                ClientState::Up => HostState::Up,
+                ClientState::Stale | ClientState::Never => HostState::Down,
+            },
+            uptime_24h: uptime(row.now, row.first_sample, row.buckets),
+        });
+    }
+
+    Ok(StatusPage {
+        title: conf.title.clone(),
+        updated: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64),
+        hosts,
+    })
+}
+
+/// Percentage of the buckets of the last 24 hours with samples, of those
+/// since the first sample if later.
+fn uptime(now: i64, first_sample: Option<i64>, buckets: i64) -> Option<f64> {
+    let start = first_sample?.max(now - UPTIME_WINDOW_SECS);
+    let total = now / UPTIME_BUCKET_SECS - start / UPTIME_BUCKET_SECS + 1;
+    Some((buckets as f64 / total.max(1) as f64 * 100.0).min(100.0))
+}
+
+pub async fn status_page_html(State(state): State<AppState>) -> Result<Response, StatusPageError> {
+    let page = state.status_page.get(&state).await?;
+    Ok(cached(&state, Html(render(&page))))
+}
+
+pub async fn status_page_json(State(state): State<AppState>) -> Result<Response, StatusPageError> {
+    let page = state.status_page.get(&state).await?;
+    Ok(cached(&state, Json(page)))
+}
+
+fn cached(state: &AppState, body: impl IntoResponse) -> Response {
+    let cache_control = format!("public, max-age={}", state.conf.status_page.cache_ttl);
+    let mut response = body.into_response();
+    if let Ok(value) = HeaderValue::from_str(&cache_control) {
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    response
+}
+
+fn render(page: &StatusPage) -> String {
+    let title = escape(&page.title);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+        <title>{title}</title>\n<style>\n\
+        body {{ font-family: sans-serif; max-width: 40em; margin: 2em auto; padding: 0 1em; }}\n\
+        table {{ width: 100%; border-collapse: collapse; }}\n\
+        td {{ padding: 0.5em 0; border-bottom: 1px solid #ddd; }}\n\
+        .up {{ color: #1a7f37; }}\n.down {{ color: #cf222e; }}\n\
+        </style>\n</head>\n<body>\n<h1>{title}</h1>\n<table>\n"
+    );
+    for host in &page.hosts {
+        let (class, status) = match host.status {
+            HostState::Up => ("up", "Up"),
+            HostState::Down => ("down", "Down"),
+        };
+        let uptime = host
+            .uptime_24h
+            .map_or("-".to_owned(), |uptime| format!("{uptime:.2}%"));
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td class=\"{class}\">{status}</td><td>{uptime} (24h)</td></tr>",
+            escape(&host.name)
+        );
+    }
+    let _ = write!(
+        html,
+        "</table>\n<p><small>Updated <time>{}</time></small></p>\n</body>\n</html>\n",
+        humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(page.updated as u64))
+    );
+    html
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StatusPageError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for StatusPageError {
+    fn into_response(self) -> Response {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uptime_24h() {
+        let now = 1_000_000 * UPTIME_BUCKET_SECS;
+        let day = UPTIME_WINDOW_SECS / UPTIME_BUCKET_SECS;
+
+        assert_eq!(uptime(now, None, 0), None);
+        // the window spans one more bucket than a day, started one ago
+        assert_eq!(uptime(now, Some(0), day + 1), Some(100.0));
+        assert_eq!(uptime(now, Some(0), 0), Some(0.0));
+        // younger hosts count from their first sample
+        assert_eq!(
+            uptime(now, Some(now - 2 * UPTIME_BUCKET_SECS), 3),
+            Some(100.0)
+        );
+        assert_eq!(
+            uptime(now, Some(now - 3 * UPTIME_BUCKET_SECS), 2),
+            Some(50.0)
+        );
+    }
+
+    #[test]
+    fn rendered_names_are_escaped() {
+        let page = StatusPage {
+            title: "<Status>".to_owned(),
+            updated: 0,
+            hosts: vec![HostStatus {
+                name: "a & \"b\"".to_owned(),
+                status: HostState::Up,
+                uptime_24h: Some(99.5),
+            }],
+        };
+        let html = render(&page);
+        assert!(html.contains("<h1>&lt;Status&gt;</h1>"));
+        assert!(html.contains("a &amp; &quot;b&quot;"));
+        assert!(html.contains("99.50%"));
+        assert!(!html.contains("<Status>"));
+    }
+}
@@ -0,0 +1,51 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use tracing::info;
+
+use crate::{AppState, wol};
+
+/// Send a Wake-on-LAN packet to the MAC address of a client, set with
+/// `admin client mac`.
+pub async fn wake_client(
+    State(state): State<AppState>,
+    Path(client_id): Path<i64>,
+) -> Result<StatusCode, WakeError> {
+    let client = sqlx::query!("SELECT mac_address FROM clients WHERE id = ?", client_id)
+        .fetch_optional(&state.db.reader)
+        .await?
+        .ok_or(WakeError::ClientNotFound)?;
+    let mac_address = client.mac_address.ok_or(WakeError::NoMacAddress)?;
+    let mac = wol::parse_mac(&mac_address).ok_or(WakeError::NoMacAddress)?;
+
+    wol::wake(&state.conf.wake_on_lan, mac).await?;
+    info!(client_id, mac_address, "wake-on-LAN packet sent");
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WakeError {
+    #[error("Client not found")]
+    ClientNotFound,
+    #[error("Client has no MAC address, set it with `admin client mac`")]
+    NoMacAddress,
+    #[error("Failed to send the packet: {0}")]
+    SendError(#[from] std::io::Error),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for WakeError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            WakeError::ClientNotFound => StatusCode::NOT_FOUND,
+            WakeError::NoMacAddress => StatusCode::CONFLICT,
+            WakeError::SendError(_) | WakeError::DatabaseError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
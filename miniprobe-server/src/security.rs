@@ -0,0 +1,527 @@
+//! Security relevant events: failed authentication, lockouts, clients
+//! refused for their network or quota, and client tokens turning up at new
+//! addresses.
+//!
+//! Events are kept in `security_events` for the admin API and published as
+//! `Event::Security`, hooks and alert channels deliver them when they ask for
+//! them. Addresses failing to authenticate too often are locked out for a
+//! while once `security.max_auth_failures` is set.
+//!
+//! A single [`SecurityWriter`] stores the events from a bounded queue, so a
+//! flood of failed authentications can neither pile up tasks nor hold the
+//! writer from ingest. Events not fitting the queue are only logged.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use confique::Config;
+use miniprobe_proto::UnixMillis;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{field, info, warn};
+
+use crate::{
+    alert::Notifier,
+    events::{Event, EventSender},
+};
+
+/// Most addresses whose failures are counted at once, so a flood from many
+/// addresses can not exhaust memory. Addresses beyond are not locked out.
+const MAX_TRACKED_ADDRESSES: usize = 10_000;
+/// Events waiting to be stored, further ones are dropped.
+const QUEUE_SIZE: usize = 1024;
+/// Most events stored in one transaction.
+const MAX_WRITE_BATCH: usize = 64;
+/// How often events past the retention are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Config, Debug)]
+pub struct SecurityConf {
+    /// Failed authentications of clients and the admin API from one address
+    /// after which it is refused for `lockout` seconds, never if 0. Clients
+    /// behind a shared address are locked out together
+    #[config(default = 0)]
+    pub max_auth_failures: u32,
+
+    /// Seconds failed authentications are counted over, and an address stays
+    /// locked out
+    #[config(default = 600)]
+    pub lockout: u64,
+
+    /// Days security events are kept
+    #[config(default = 90)]
+    pub retention_days: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventKind {
+    /// A client presented an unknown token
+    ClientAuthFailed,
+    /// A request of the admin API or the server info presented a wrong token
+    AdminAuthFailed,
+    /// An address was locked out after too many failed authentications
+    Lockout,
+    /// A client was refused for its network, see `access`
+    AccessDenied,
+    /// A session was created, or refused, from another hostname than the
+    /// client is bound to
+    HostnameMismatch,
+    /// A client ran out of its daily sample quota
+    QuotaExceeded,
+    /// A client token was used from an address none of the earlier sessions
+    /// of its client came from
+    NewAddress,
+}
+
+impl SecurityEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SecurityEventKind::ClientAuthFailed => "client_auth_failed",
+            SecurityEventKind::AdminAuthFailed => "admin_auth_failed",
+            SecurityEventKind::Lockout => "lockout",
+            SecurityEventKind::AccessDenied => "access_denied",
+            SecurityEventKind::HostnameMismatch => "hostname_mismatch",
+            SecurityEventKind::QuotaExceeded => "quota_exceeded",
+            SecurityEventKind::NewAddress => "new_address",
+        }
+    }
+
+    fn is_auth_failure(self) -> bool {
+        matches!(
+            self,
+            SecurityEventKind::ClientAuthFailed | SecurityEventKind::AdminAuthFailed
+        )
+    }
+}
+
+/// A security event to record.
+#[derive(Debug, Clone)]
+pub struct SecurityEvent {
+    pub kind: SecurityEventKind,
+    pub client_id: Option<i64>,
+    pub client_name: Option<String>,
+    /// Peer address of the request, unknown on unix sockets
+    pub address: Option<IpAddr>,
+    pub detail: String,
+}
+
+impl SecurityEvent {
+    pub fn new(
+        kind: SecurityEventKind,
+        address: Option<IpAddr>,
+        detail: impl Into<String>,
+    ) -> Self {
+        SecurityEvent {
+            kind,
+            client_id: None,
+            client_name: None,
+            address,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn client(self, client_id: i64, client_name: Option<String>) -> Self {
+        SecurityEvent {
+            client_id: Some(client_id),
+            client_name,
+            ..self
+        }
+    }
+}
+
+/// Failed authentications of an address.
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    /// Start of the window they are counted in
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failed authentications by address.
+#[derive(Debug)]
+struct Lockouts {
+    max_failures: u32,
+    lockout: Duration,
+    failures: HashMap<IpAddr, Failures>,
+}
+
+impl Lockouts {
+    fn is_locked_out(&self, address: IpAddr, now: Instant) -> bool {
+        self.failures
+            .get(&address)
+            .and_then(|failures| failures.locked_until)
+            .is_some_and(|until| now < until)
+    }
+
+    fn expired(&self, failures: &Failures, now: Instant) -> bool {
+        failures
+            .locked_until
+            .unwrap_or(failures.since + self.lockout)
+            <= now
+    }
+
+    /// Count a failed authentication of `address`, true if that locked it
+    /// out.
+    fn failed(&mut self, address: IpAddr, now: Instant) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+        if self.failures.len() >= MAX_TRACKED_ADDRESSES && !self.failures.contains_key(&address) {
+            let failures = std::mem::take(&mut self.failures);
+            self.failures = failures
+                .into_iter()
+                .filter(|(_, failures)| !self.expired(failures, now))
+                .collect();
+            if self.failures.len() >= MAX_TRACKED_ADDRESSES {
+                return false;
+            }
+        }
+
+        let fresh = Failures {
+            count: 0,
+            since: now,
+            locked_until: None,
+        };
+        let mut failures = *self.failures.get(&address).unwrap_or(&fresh);
+        if self.expired(&failures, now) {
+            failures = fresh;
+        }
+        failures.count += 1;
+        let locked = failures.locked_until.is_none() && failures.count >= self.max_failures;
+        if locked {
+            failures.locked_until = Some(now + self.lockout);
+        }
+        self.failures.insert(address, failures);
+        locked
+    }
+}
+
+/// Records security events and keeps track of lockouts.
+#[derive(Debug, Clone)]
+pub struct SecurityLog {
+    events: EventSender,
+    lockouts: Arc<Mutex<Lockouts>>,
+    /// Events for the [`SecurityWriter`], with their time
+    queue: mpsc::Sender<(SecurityEvent, i64)>,
+    /// Events dropped since the writer last reported them
+    dropped: Arc<AtomicU64>,
+}
+
+impl SecurityLog {
+    /// The log and the writer storing its events, which must be run.
+    pub fn new(
+        pool: SqlitePool,
+        events: EventSender,
+        conf: &SecurityConf,
+    ) -> (Self, SecurityWriter) {
+        let (queue, queued) = mpsc::channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let log = SecurityLog {
+            events,
+            lockouts: Arc::new(Mutex::new(Lockouts {
+                max_failures: conf.max_auth_failures,
+                lockout: Duration::from_secs(conf.lockout),
+                failures: HashMap::new(),
+            })),
+            queue,
+            dropped: dropped.clone(),
+        };
+        let writer = SecurityWriter {
+            pool,
+            queued,
+            dropped,
+            retention_secs: i64::from(conf.retention_days) * 24 * 60 * 60,
+        };
+        (log, writer)
+    }
+
+    /// Whether requests from `address` are refused before their token is
+    /// checked.
+    pub fn is_locked_out(&self, address: Option<IpAddr>) -> bool {
+        address.is_some_and(|address| {
+            self.lock()
+                .is_locked_out(address.to_canonical(), Instant::now())
+        })
+    }
+
+    /// Store and publish `event`, and lock out its address if it failed to
+    /// authenticate too often. Stored by the [`SecurityWriter`], the writer
+    /// may be held by the request recording it.
+    pub fn record(&self, event: SecurityEvent) {
+        let lockout = match event.address {
+            Some(address) if event.kind.is_auth_failure() => {
+                let mut lockouts = self.lock();
+                lockouts
+                    .failed(address.to_canonical(), Instant::now())
+                    .then(|| (address, lockouts.max_failures, lockouts.lockout))
+            }
+            _ => None,
+        };
+        let lockout = lockout.map(|(address, max_failures, lockout)| {
+            SecurityEvent::new(
+                SecurityEventKind::Lockout,
+                Some(address),
+                format!(
+                    "{max_failures} failed authentications, locked out for {} seconds",
+                    lockout.as_secs()
+                ),
+            )
+        });
+        let events = [Some(event), lockout]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        let time = UnixMillis::now().as_secs() as i64;
+        for event in events {
+            self.publish(&event, time);
+            // logged and published all the same
+            if self.queue.try_send((event, time)).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Log `event` and send it to the subscribers of events.
+    fn publish(&self, event: &SecurityEvent, time: i64) {
+        let kind = event.kind.as_str();
+        let address = event.address.map(field::display);
+        match event.kind {
+            SecurityEventKind::Lockout => warn!(kind, address, "{}", event.detail),
+            _ => info!(
+                kind,
+                client_id = event.client_id,
+                address,
+                "{}",
+                event.detail
+            ),
+        }
+
+        // sending only fails without subscribers
+        self.events
+            .send(Event::Security {
+                kind: event.kind,
+                client_id: event.client_id,
+                client_name: event.client_name.clone(),
+                address: event.address,
+                detail: event.detail.clone(),
+                time,
+            })
+            .ok();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lockouts> {
+        // counters only, a panic can not leave them inconsistent
+        self.lockouts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Stores the events of a [`SecurityLog`] and deletes those past the
+/// retention.
+pub struct SecurityWriter {
+    pool: SqlitePool,
+    queued: mpsc::Receiver<(SecurityEvent, i64)>,
+    dropped: Arc<AtomicU64>,
+    retention_secs: i64,
+}
+
+impl SecurityWriter {
+    /// Store events as they are recorded until cancelled, then the ones
+    /// still queued.
+    pub async fn run(mut self, cancellation_token: CancellationToken) {
+        let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+        let mut batch = Vec::with_capacity(MAX_WRITE_BATCH);
+        loop {
+            tokio::select! {
+                received = self.queued.recv_many(&mut batch, MAX_WRITE_BATCH) => {
+                    if received == 0 {
+                        return;
+                    }
+                    self.store(&mut batch).await;
+                }
+                _ = prune.tick() => {
+                    if let Err(e) = self.prune().await {
+                        warn!("failed to delete old security events: {e}");
+                    }
+                }
+                _ = cancellation_token.cancelled() => {
+                    self.queued.close();
+                    while self.queued.recv_many(&mut batch, MAX_WRITE_BATCH).await > 0 {
+                        self.store(&mut batch).await;
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Store and clear `batch` in one transaction.
+    async fn store(&self, batch: &mut Vec<(SecurityEvent, i64)>) {
+        if let Err(e) = insert(&self.pool, batch).await {
+            warn!(events = batch.len(), "failed to store security events: {e}");
+        }
+        batch.clear();
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(
+                dropped,
+                "security events recorded faster than stored, not stored"
+            );
+        }
+    }
+
+    async fn prune(&self) -> sqlx::Result<()> {
+        let cutoff = UnixMillis::now().as_secs() as i64 - self.retention_secs;
+        sqlx::query!("DELETE FROM security_events WHERE time < ?", cutoff)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+async fn insert(pool: &SqlitePool, events: &[(SecurityEvent, i64)]) -> sqlx::Result<()> {
+    let mut tx = pool.begin().await?;
+    for (event, time) in events {
+        let kind = event.kind.as_str();
+        let address = event.address.map(|address| address.to_string());
+        sqlx::query!(
+            "INSERT INTO security_events (time, kind, client_id, client_name, address, detail) \
+                VALUES (?, ?, ?, ?, ?, ?)",
+            time,
+            kind,
+            event.client_id,
+            event.client_name,
+            address,
+            event.detail
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+/// Delivers security events to the alert channels asking for them.
+pub struct SecurityNotifier {
+    notifier: Notifier,
+    events: broadcast::Receiver<Event>,
+}
+
+impl SecurityNotifier {
+    pub fn new(notifier: Notifier, events: broadcast::Receiver<Event>) -> Self {
+        SecurityNotifier { notifier, events }
+    }
+
+    /// Deliver events until cancelled, events during shutdown may be missed.
+    pub async fn run(mut self, cancellation_token: CancellationToken) {
+        if !self.notifier.wants_security_events() {
+            return;
+        }
+        loop {
+            tokio::select! {
+                event = self.events.recv() => match event {
+                    Ok(event @ Event::Security { .. }) => self.notifier.notify_security(&event).await,
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "security notifications lagging behind, events dropped");
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = cancellation_token.cancelled() => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn lockout_after_failures() {
+        let mut lockouts = Lockouts {
+            max_failures: 3,
+            lockout: 10 * MINUTE,
+            failures: HashMap::new(),
+        };
+        let (a, b) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(!lockouts.failed(a, start));
+        assert!(!lockouts.failed(a, start + MINUTE));
+        assert!(!lockouts.failed(b, start + MINUTE));
+        assert!(!lockouts.is_locked_out(a, start + MINUTE));
+        assert!(lockouts.failed(a, start + 2 * MINUTE));
+        assert!(lockouts.is_locked_out(a, start + 2 * MINUTE));
+        assert!(!lockouts.is_locked_out(b, start + 2 * MINUTE));
+        // locked out once, not again for every failure meanwhile
+        assert!(!lockouts.failed(a, start + 3 * MINUTE));
+        assert!(lockouts.is_locked_out(a, start + 11 * MINUTE));
+        assert!(!lockouts.is_locked_out(a, start + 12 * MINUTE));
+
+        // failures further apart than the window never add up
+        for minute in [20, 31, 42, 53] {
+            assert!(!lockouts.failed(b, start + minute * MINUTE));
+        }
+
+        lockouts.max_failures = 0;
+        for _ in 0..10 {
+            assert!(!lockouts.failed(b, start));
+        }
+    }
+
+    #[tokio::test]
+    async fn events_beyond_queue_dropped() {
+        let db = crate::db::Db::connect(
+            "sqlite:file:security?mode=memory&cache=shared",
+            "sqlite:file:security-samples?mode=memory&cache=shared",
+            1,
+            None,
+        )
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        let conf = SecurityConf {
+            max_auth_failures: 0,
+            lockout: 600,
+            retention_days: 90,
+        };
+        let (events, _) = broadcast::channel(16);
+        let (log, writer) = SecurityLog::new(db.writer.clone(), events, &conf);
+
+        for i in 0..QUEUE_SIZE + 10 {
+            log.record(SecurityEvent::new(
+                SecurityEventKind::NewAddress,
+                None,
+                format!("event {i}"),
+            ));
+        }
+        assert_eq!(log.dropped.load(Ordering::Relaxed), 10);
+
+        // stores what is still queued once cancelled
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        writer.run(cancelled).await;
+        let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM security_events")
+            .fetch_one(&db.writer)
+            .await
+            .unwrap();
+        assert_eq!(stored, QUEUE_SIZE as i64);
+        assert_eq!(log.dropped.load(Ordering::Relaxed), 0);
+    }
+}
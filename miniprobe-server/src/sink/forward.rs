@@ -0,0 +1,382 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use confique::Config;
+use miniprobe_api::{IngressControls, IngressSender};
+use miniprobe_proto::{
+    Capabilities, DynamicMetrics, StaticMetrics, SystemInfo, UnixMillis, limits::MAX_BATCH_SIZE,
+    msg::CreateSessionReq,
+};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{Instrument, debug, debug_span, warn};
+
+use super::{Ingested, MetricsSink};
+
+const RETRY_MINIMUM_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_MAXIMUM_INTERVAL: Duration = Duration::from_secs(300);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Config, Debug)]
+pub struct RelayConf {
+    /// Address of the upstream server samples are forwarded to with
+    /// `sink = "forward"`, e.g. `central.example.com:8000`
+    pub upstream: Option<String>,
+
+    /// Connect to the upstream server over TLS
+    #[config(default = false)]
+    pub tls: bool,
+
+    /// Seconds samples are collected before they are forwarded as a batch
+    #[config(default = 30)]
+    pub send_interval: u64,
+
+    /// Samples kept per session while the upstream server is unreachable,
+    /// the oldest are dropped first
+    #[config(default = 10000)]
+    pub buffer_size: usize,
+
+    /// Upstream client tokens of the forwarded clients, samples of other
+    /// clients are dropped
+    #[config(default = [])]
+    pub clients: Vec<ForwardedClient>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForwardedClient {
+    /// Name of the client on this server
+    pub name: String,
+    /// Token of the client on the upstream server
+    pub token: String,
+}
+
+/// Samples waiting to be forwarded, shared between the sink and its forwarder.
+#[derive(Debug)]
+struct Buffer {
+    samples: Mutex<VecDeque<DynamicMetrics<'static>>>,
+    capacity: usize,
+    /// The sink is gone, the forwarder sends what is left and stops
+    closed: CancellationToken,
+}
+
+impl Buffer {
+    /// Take up to `max` of the oldest samples.
+    fn take(&self, max: usize) -> Vec<DynamicMetrics<'static>> {
+        let mut samples = self.samples.lock().unwrap();
+        let n = samples.len().min(max);
+        samples.drain(..n).collect()
+    }
+
+    /// The sink is gone and every sample was sent.
+    fn is_drained(&self) -> bool {
+        self.closed.is_cancelled() && self.samples.lock().unwrap().is_empty()
+    }
+
+    /// Put samples that could not be sent back in front, as far as they fit.
+    fn put_back(&self, batch: Vec<DynamicMetrics<'static>>) {
+        let mut samples = self.samples.lock().unwrap();
+        let room = self.capacity.saturating_sub(samples.len());
+        // the newest samples are kept if not all fit
+        for sample in batch.into_iter().rev().take(room) {
+            samples.push_front(sample);
+        }
+    }
+}
+
+/// Forwards samples to an upstream miniprobe server as a client would, in
+/// batches over the ingress websocket.
+///
+/// Every local session gets its own upstream session. Samples are buffered in
+/// memory while the upstream server is unreachable and are lost when the
+/// server stops before they could be sent.
+#[derive(Debug)]
+pub struct ForwardSink {
+    buffer: Option<Arc<Buffer>>,
+}
+
+impl ForwardSink {
+    /// Start forwarding samples of a session, the forwarder runs on `tracker`
+    /// and `shutdown` stops it after a last attempt to send the buffer.
+    pub async fn open(
+        conf: &RelayConf,
+        db: &SqlitePool,
+        session_id: i64,
+        client_id: i64,
+        tracker: &TaskTracker,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<Self> {
+        let Some(upstream) = conf.upstream.clone() else {
+            anyhow::bail!("no upstream server configured for the forward sink");
+        };
+        let session = sqlx::query!(
+            "SELECT c.name, s.system_name, s.kernel_version, s.os_version, s.host_name, \
+                s.cpu_arch, s.capabilities, s.boot_time, s.boot_id, s.machine_id \
+                FROM sessions s JOIN clients c ON c.id = s.client_id \
+                WHERE s.id = ? AND s.client_id = ?",
+            session_id,
+            client_id
+        )
+        .fetch_one(db)
+        .await?;
+
+        let Some(token) = conf
+            .clients
+            .iter()
+            .find(|client| client.name == session.name)
+            .map(|client| client.token.clone())
+        else {
+            warn!(
+                client = session.name,
+                "no upstream token configured, samples are dropped"
+            );
+            return Ok(ForwardSink { buffer: None });
+        };
+
+        let system_info = StaticMetrics {
+            system: SystemInfo {
+                system_name: session.system_name,
+                kernel_version: session.kernel_version,
+                os_version: session.os_version,
+                host_name: session.host_name,
+                cpu_arch: session.cpu_arch,
+                boot_time: session.boot_time.map(|secs| UnixMillis(secs as u64 * 1000)),
+                boot_id: session.boot_id,
+                machine_id: session.machine_id,
+            },
+            capabilities: session
+                .capabilities
+                .and_then(|capabilities| serde_json::from_str::<Capabilities>(&capabilities).ok())
+                .unwrap_or_default(),
+        };
+
+        let buffer = Arc::new(Buffer {
+            samples: Mutex::new(VecDeque::new()),
+            capacity: conf.buffer_size.max(1),
+            closed: shutdown.child_token(),
+        });
+        let scheme = if conf.tls { "https" } else { "http" };
+        let forwarder = Forwarder {
+            send_interval: Duration::from_secs(conf.send_interval),
+            request: CreateSessionReq { token, system_info },
+            api: miniprobe_api::Client::new(&format!("{scheme}://{upstream}"))?.with_http_client(
+                reqwest::Client::builder()
+                    .timeout(REQUEST_TIMEOUT)
+                    .build()?,
+            ),
+            batch_size: MAX_BATCH_SIZE as usize,
+            buffer: buffer.clone(),
+            shutdown,
+        };
+        tracker.spawn(forwarder.run().instrument(debug_span!(
+            "forward",
+            client = session.name,
+            client_id,
+            session_id
+        )));
+
+        Ok(ForwardSink {
+            buffer: Some(buffer),
+        })
+    }
+}
+
+impl MetricsSink for ForwardSink {
+    async fn write(&mut self, sample: Ingested<'_>) -> anyhow::Result<()> {
+        let Some(buffer) = &self.buffer else {
+            return Ok(());
+        };
+        let mut samples = buffer.samples.lock().unwrap();
+        if samples.len() >= buffer.capacity {
+            samples.pop_front();
+            debug!("forward buffer full, dropping the oldest sample");
+        }
+        samples.push_back(sample.metrics.into_owned());
+        Ok(())
+    }
+}
+
+impl Drop for ForwardSink {
+    fn drop(&mut self) {
+        if let Some(buffer) = &self.buffer {
+            buffer.closed.cancel();
+        }
+    }
+}
+
+type Upstream = (IngressSender, IngressControls);
+
+struct Forwarder {
+    send_interval: Duration,
+    request: CreateSessionReq,
+    api: miniprobe_api::Client,
+    /// Most samples in one batch, lowered to what the upstream server accepts
+    batch_size: usize,
+    buffer: Arc<Buffer>,
+    /// The server is shutting down
+    shutdown: CancellationToken,
+}
+
+impl Forwarder {
+    async fn run(mut self) {
+        let shutdown = self.shutdown.clone();
+        let mut retry_interval = RETRY_MINIMUM_INTERVAL;
+        loop {
+            let connected = tokio::select! {
+                connected = self.connect() => connected,
+                _ = shutdown.cancelled() => return,
+            };
+            match connected {
+                Ok(ws) => {
+                    retry_interval = RETRY_MINIMUM_INTERVAL;
+                    match self.forward(ws).await {
+                        Ok(()) => return,
+                        Err(e) => warn!("forwarding to upstream failed: {e}"),
+                    }
+                }
+                Err(e) => warn!("connecting to upstream failed: {e}"),
+            }
+
+            if shutdown.is_cancelled() || self.buffer.is_drained() {
+                return;
+            }
+            debug!(?retry_interval, "reconnecting to upstream");
+            tokio::select! {
+                _ = tokio::time::sleep(retry_interval) => {}
+                _ = shutdown.cancelled() => return,
+            }
+            retry_interval = (retry_interval * 2).min(RETRY_MAXIMUM_INTERVAL);
+        }
+    }
+
+    /// Open an upstream session and its ingress websocket.
+    async fn connect(&mut self) -> anyhow::Result<Upstream> {
+        if let Some(capabilities) = self.api.capabilities().await?
+            && let Some(max) = capabilities.max_batch_size
+        {
+            self.batch_size = self.batch_size.min(max.max(1) as usize);
+        }
+
+        let session = self.api.create_session(&self.request).await?;
+        let upstream = self.api.ingress(&session.session_token, true).await?;
+        debug!("connected to upstream");
+        Ok(upstream)
+    }
+
+    /// Send batches until the sink is closed and the buffer is drained.
+    async fn forward(&self, (mut write, mut controls): Upstream) -> anyhow::Result<()> {
+        // control messages are not used, reading keeps pings answered
+        let mut read_task = tokio::spawn(async move {
+            while let Some(Ok(_)) = controls.next().await {}
+            debug!("upstream closed the websocket");
+        });
+
+        let mut interval = tokio::time::interval(self.send_interval);
+        loop {
+            let last = tokio::select! {
+                _ = interval.tick() => false,
+                _ = self.buffer.closed.cancelled() => true,
+                _ = &mut read_task => anyhow::bail!("upstream closed the websocket"),
+            };
+
+            loop {
+                let batch = self.buffer.take(self.batch_size);
+                if batch.is_empty() {
+                    break;
+                }
+                if let Err(e) = write.send_batch(&batch).await {
+                    self.buffer.put_back(batch);
+                    return Err(e.into());
+                }
+                debug!(samples = batch.len(), "forwarded batch");
+            }
+
+            if last {
+                write.close().await.ok();
+                read_task.abort();
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miniprobe_proto::{CpuReport, MemoryMetrics, NetworkMetrics, UnixMillis};
+
+    use super::*;
+
+    fn sample(seq: u64) -> DynamicMetrics<'static> {
+        DynamicMetrics {
+            seq,
+            sample_time: UnixMillis(seq),
+            cpu: CpuReport::Aggregate {
+                usage: 0.0,
+                max_core: 0.0,
+            },
+            memory: MemoryMetrics {
+                total: 0,
+                used: 0,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: "eth0".into(),
+                rx_bytes: None,
+                tx_bytes: None,
+            }],
+            disks: Vec::new(),
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: None,
+            services: Vec::new(),
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        }
+    }
+
+    fn seqs(buffer: &Buffer) -> Vec<u64> {
+        buffer
+            .samples
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| s.seq)
+            .collect()
+    }
+
+    #[test]
+    fn buffer_put_back() {
+        let buffer = Buffer {
+            samples: Mutex::new((0..4).map(sample).collect()),
+            capacity: 5,
+            closed: CancellationToken::new(),
+        };
+
+        let batch = buffer.take(3);
+        assert_eq!(batch.len(), 3);
+        assert_eq!(seqs(&buffer), [3]);
+
+        // new samples arrived while sending
+        buffer
+            .samples
+            .lock()
+            .unwrap()
+            .extend([sample(4), sample(5)]);
+        buffer.put_back(batch);
+        assert_eq!(seqs(&buffer), [1, 2, 3, 4, 5]);
+
+        assert!(!buffer.is_drained());
+        buffer.take(5);
+        buffer.closed.cancel();
+        assert!(buffer.is_drained());
+    }
+}
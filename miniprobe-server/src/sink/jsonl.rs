@@ -0,0 +1,27 @@
+use std::sync::LazyLock;
+
+use tokio::{
+    io::{AsyncWriteExt, Stdout},
+    sync::Mutex,
+};
+
+use super::{Ingested, MetricsSink};
+
+/// Every connection writes whole lines, so lines of concurrent sinks do not interleave.
+static STDOUT: LazyLock<Mutex<Stdout>> = LazyLock::new(|| Mutex::new(tokio::io::stdout()));
+
+/// Writes every sample as a line of JSON to stdout.
+#[derive(Debug, Default)]
+pub struct JsonlSink;
+
+impl MetricsSink for JsonlSink {
+    async fn write(&mut self, sample: Ingested<'_>) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&sample)?;
+        line.push(b'\n');
+
+        let mut stdout = STDOUT.lock().await;
+        stdout.write_all(&line).await?;
+        stdout.flush().await?;
+        Ok(())
+    }
+}
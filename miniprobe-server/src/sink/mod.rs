@@ -0,0 +1,102 @@
+//! Destinations of ingested samples.
+//!
+//! Every ingress websocket writes through its own sink, picked by the `sink`
+//! config. Sessions and clients stay in the database whatever the sink, so
+//! the server can run as a pure relay with `jsonl` or `forward`.
+
+use std::future::Future;
+
+use miniprobe_proto::DynamicMetrics;
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+mod forward;
+mod jsonl;
+mod sqlite;
+
+pub use forward::{ForwardSink, RelayConf};
+pub use jsonl::JsonlSink;
+pub use sqlite::SqliteSink;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkKind {
+    /// Store samples in the samples database
+    #[default]
+    Sqlite,
+    /// Write samples to stdout as JSON lines, e.g. to pipe them elsewhere
+    Jsonl,
+    /// Drop samples
+    Discard,
+    /// Forward samples to another miniprobe server, see `RelayConf`
+    Forward,
+}
+
+/// A sample as it arrived from a client, borrowing from the message.
+#[derive(Debug, Serialize)]
+pub struct Ingested<'a> {
+    pub client_id: i64,
+    pub session_id: i64,
+    /// Server time in unix milliseconds the sample arrived at
+    pub received_at: i64,
+    /// The network counters are deltas since the previous sample of the
+    /// connection, see `miniprobe_proto::msg::DELTA_COUNTERS`
+    pub network_delta: bool,
+    pub metrics: DynamicMetrics<'a>,
+}
+
+pub trait MetricsSink: Send {
+    fn write(&mut self, sample: Ingested<'_>) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// The sink picked by the config.
+#[derive(Debug)]
+pub enum Sink {
+    Sqlite(SqliteSink),
+    Jsonl(JsonlSink),
+    Discard(DiscardSink),
+    Forward(ForwardSink),
+}
+
+impl Sink {
+    /// Open the configured sink for the samples of a session.
+    pub async fn open(state: &AppState, session_id: i64, client_id: i64) -> anyhow::Result<Self> {
+        Ok(match state.conf.sink {
+            SinkKind::Sqlite => Sink::Sqlite(SqliteSink::new(state.db.writer.clone())),
+            SinkKind::Jsonl => Sink::Jsonl(JsonlSink),
+            SinkKind::Discard => Sink::Discard(DiscardSink),
+            SinkKind::Forward => Sink::Forward(
+                ForwardSink::open(
+                    &state.conf.relay,
+                    &state.db.reader,
+                    session_id,
+                    client_id,
+                    &state.ws_graceful_shutdown.tracker,
+                    state.ws_graceful_shutdown.token.child_token(),
+                )
+                .await?,
+            ),
+        })
+    }
+}
+
+impl MetricsSink for Sink {
+    async fn write(&mut self, sample: Ingested<'_>) -> anyhow::Result<()> {
+        match self {
+            Sink::Sqlite(sink) => sink.write(sample).await,
+            Sink::Jsonl(sink) => sink.write(sample).await,
+            Sink::Discard(sink) => sink.write(sample).await,
+            Sink::Forward(sink) => sink.write(sample).await,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DiscardSink;
+
+impl MetricsSink for DiscardSink {
+    async fn write(&mut self, _: Ingested<'_>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+use miniprobe_proto::{CpuReport, Section, metrics_math};
+use sqlx::SqlitePool;
+use tracing::debug;
+
+use super::{Ingested, MetricsSink};
+use crate::intern::Interner;
+
+/// Stores samples in the samples database, the default sink.
+#[derive(Debug)]
+pub struct SqliteSink {
+    db: SqlitePool,
+    interner: Interner,
+    /// Interned interface names of the sample being written, kept for the
+    /// next one
+    ifname_ids: Vec<i64>,
+    /// Interned unit names and states of the sample being written, kept for
+    /// the next one
+    unit_ids: Vec<(i64, i64)>,
+    /// Interned mount points of the sample being written, likewise
+    mount_point_ids: Vec<i64>,
+}
+
+impl SqliteSink {
+    pub fn new(db: SqlitePool) -> Self {
+        SqliteSink {
+            db,
+            interner: Interner::default(),
+            ifname_ids: Vec::new(),
+            unit_ids: Vec::new(),
+            mount_point_ids: Vec::new(),
+        }
+    }
+}
+
+impl MetricsSink for SqliteSink {
+    async fn write(&mut self, sample: Ingested<'_>) -> anyhow::Result<()> {
+        let Ingested {
+            client_id,
+            session_id,
+            received_at,
+            network_delta,
+            metrics,
+        } = sample;
+        // sections the client could not collect have no rows
+        self.ifname_ids.clear();
+        if !metrics.is_missing(Section::Network) {
+            for network in &metrics.networks {
+                let ifname_id = self.interner.intern(&self.db, &network.ifname).await?;
+                self.ifname_ids.push(ifname_id);
+            }
+        }
+        self.unit_ids.clear();
+        for service in &metrics.services {
+            let unit_id = self.interner.intern(&self.db, &service.name).await?;
+            let state_id = self
+                .interner
+                .intern(&self.db, service.state.as_str())
+                .await?;
+            self.unit_ids.push((unit_id, state_id));
+        }
+        self.mount_point_ids.clear();
+        if !metrics.is_missing(Section::Disks) {
+            for disk in &metrics.disks {
+                let mount_point_id = self.interner.intern(&self.db, &disk.mount_point).await?;
+                self.mount_point_ids.push(mount_point_id);
+            }
+        }
+
+        let battery_state_id = match &metrics.battery {
+            Some(battery) => Some(
+                self.interner
+                    .intern(&self.db, battery.state.as_str())
+                    .await?,
+            ),
+            None => None,
+        };
+
+        let mut tx = self.db.begin().await?;
+        let sample_time = metrics.sample_time.0 as i64;
+
+        let seq = metrics.seq as i64;
+
+        let Some(session_data_id) = sqlx::query_scalar!(
+            r#"
+            INSERT INTO session_data (session_id, client_id, sample_time, seq, received_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (client_id, seq) DO NOTHING
+            RETURNING id
+            "#,
+            session_id,
+            client_id,
+            sample_time,
+            seq,
+            received_at,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            debug!(seq, "dropping duplicate sample");
+            return Ok(());
+        };
+
+        // cpu metrics
+        match &metrics.cpu {
+            _ if metrics.is_missing(Section::Cpu) => {}
+            CpuReport::PerCore(cores) => {
+                for (i, cpu_metric) in cores.iter().enumerate() {
+                    let (i, usage) = (i as i64, metrics_math::widen(cpu_metric.usage));
+                    sqlx::query!(
+                        r#"
+                        INSERT INTO session_data_cpu (session_data_id, cpu_id, cpu_usage)
+                        VALUES (?, ?, ?)
+                        "#,
+                        session_data_id,
+                        i,
+                        usage,
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+            }
+            CpuReport::Aggregate { usage, max_core } => {
+                let (usage, max_core) =
+                    (metrics_math::widen(*usage), metrics_math::widen(*max_core));
+                sqlx::query!(
+                    r#"
+                    INSERT INTO session_data_cpu_aggregate (session_data_id, cpu_usage, max_core_usage)
+                    VALUES (?, ?, ?)
+                    "#,
+                    session_data_id,
+                    usage,
+                    max_core,
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        // memory metrics
+        if !metrics.is_missing(Section::Memory) {
+            // will someone use that much memory? I doubt it.
+            let (total, used) = (metrics.memory.total as i64, metrics.memory.used as i64);
+            let (swap_total, swap_used) = (
+                metrics.memory.swap_total as i64,
+                metrics.memory.swap_used as i64,
+            );
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_memory (session_data_id, total, used, swap_total, swap_used)
+                VALUES (?, ?, ?, ?, ?)
+                "#,
+                session_data_id,
+                total,
+                used,
+                swap_total,
+                swap_used,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // network metrics, an interface listed twice keeps its first entry
+        for (network, ifname_id) in metrics.networks.iter().zip(&self.ifname_ids) {
+            let (rx_bytes, tx_bytes) = (
+                network.rx_bytes.map(|i| i as i64),
+                network.tx_bytes.map(|i| i as i64),
+            );
+
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_network
+                    (session_data_id, ifname_id, rx_bytes, tx_bytes, delta)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT DO NOTHING
+                "#,
+                session_data_id,
+                ifname_id,
+                rx_bytes,
+                tx_bytes,
+                network_delta,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // disk metrics, a mount point listed twice keeps its first entry
+        for (disk, mount_point_id) in metrics.disks.iter().zip(&self.mount_point_ids) {
+            let (total, available) = (disk.total as i64, disk.available as i64);
+            let (read_bytes, written_bytes) = (
+                disk.read_bytes.map(|i| i as i64),
+                disk.written_bytes.map(|i| i as i64),
+            );
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_disk
+                    (session_data_id, mount_point_id, total, available, read_bytes, written_bytes)
+                VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT DO NOTHING
+                "#,
+                session_data_id,
+                mount_point_id,
+                total,
+                available,
+                read_bytes,
+                written_bytes,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // probe metrics
+        {
+            let collection_time = metrics.probe.collection_time as i64;
+            let rss = metrics.probe.rss.map(|bytes| bytes as i64);
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_probe (session_data_id, collection_time, cpu_usage, rss)
+                VALUES (?, ?, ?, ?)
+                "#,
+                session_data_id,
+                collection_time,
+                metrics.probe.cpu_usage,
+                rss,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // process counts, unless the client can not list processes
+        if let Some(processes) = &metrics.processes
+            && !metrics.is_missing(Section::Processes)
+        {
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_system (session_data_id, processes, threads, zombies)
+                VALUES (?, ?, ?, ?)
+                "#,
+                session_data_id,
+                processes.processes,
+                processes.threads,
+                processes.zombies,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // file descriptors, only reported by Linux clients
+        if let Some(fds) = &metrics.fds
+            && !metrics.is_missing(Section::Fds)
+        {
+            // a limit past i64::MAX is as good as none
+            let (open, max) = (
+                i64::try_from(fds.open).unwrap_or(i64::MAX),
+                i64::try_from(fds.max).unwrap_or(i64::MAX),
+            );
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_fds
+                    (session_data_id, open, max, tcp_established, tcp_time_wait)
+                VALUES (?, ?, ?, ?, ?)
+                "#,
+                session_data_id,
+                open,
+                max,
+                fds.tcp_established,
+                fds.tcp_time_wait,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // clock synchronization, if the client could tell
+        if let Some(clock) = &metrics.clock
+            && !metrics.is_missing(Section::Clock)
+        {
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_clock (session_data_id, synchronized, offset)
+                VALUES (?, ?, ?)
+                "#,
+                session_data_id,
+                clock.synchronized,
+                clock.offset,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // battery metrics, only reported by clients with a battery
+        if let (Some(battery), Some(state_id)) = (&metrics.battery, battery_state_id) {
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_battery (session_data_id, capacity, state_id, power)
+                VALUES (?, ?, ?, ?)
+                "#,
+                session_data_id,
+                battery.capacity,
+                state_id,
+                battery.power,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // systemd units
+        for (service, (unit_id, state_id)) in metrics.services.iter().zip(&self.unit_ids) {
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_service (session_data_id, unit_id, state_id, restarts)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT DO NOTHING
+                "#,
+                session_data_id,
+                unit_id,
+                state_id,
+                service.restarts,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // listening sockets, only sent when they changed
+        if let Some(listeners) = &metrics.listeners {
+            let listeners = serde_json::to_string(listeners)?;
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_listeners (session_data_id, listeners)
+                VALUES (?, ?)
+                "#,
+                session_data_id,
+                listeners,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // sensor metrics, skipped if the client has no sensors at all
+        if metrics.sensors.cpu_temperature.is_some() || metrics.sensors.cpu_frequency.is_some() {
+            let cpu_frequency = metrics.sensors.cpu_frequency.map(|mhz| mhz as i64);
+            sqlx::query!(
+                r#"
+                INSERT INTO session_data_sensors (session_data_id, cpu_temperature, cpu_frequency)
+                VALUES (?, ?, ?)
+                "#,
+                session_data_id,
+                metrics.sensors.cpu_temperature,
+                cpu_frequency,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miniprobe_proto::{DynamicMetrics, MemoryMetrics, NetworkMetrics, UnixMillis};
+
+    use super::*;
+    use crate::db::Db;
+
+    fn sample(seq: u64) -> DynamicMetrics<'static> {
+        DynamicMetrics {
+            seq,
+            sample_time: UnixMillis(seq),
+            cpu: CpuReport::Aggregate {
+                usage: 0.0,
+                max_core: 0.0,
+            },
+            memory: MemoryMetrics {
+                total: 0,
+                used: 0,
+                swap_total: 0,
+                swap_used: 0,
+            },
+            networks: vec![NetworkMetrics {
+                ifname: "eth0".into(),
+                rx_bytes: None,
+                tx_bytes: None,
+            }],
+            disks: Vec::new(),
+            sensors: Default::default(),
+            probe: Default::default(),
+            processes: None,
+            fds: None,
+            clock: None,
+            battery: None,
+            services: Vec::new(),
+            listeners: None,
+            addresses: None,
+            urgent: false,
+            missing_sections: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn resent_after_reconnect_stored_once() {
+        let db = Db::connect(
+            "sqlite:file:sink-resend?mode=memory&cache=shared",
+            "sqlite:file:sink-resend-samples?mode=memory&cache=shared",
+            1,
+            None,
+        )
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        let mut sink = SqliteSink::new(db.writer.clone());
+        let mut write = async |client_id, session_id, seq| {
+            sink.write(Ingested {
+                client_id,
+                session_id,
+                received_at: 0,
+                network_delta: false,
+                metrics: sample(seq),
+            })
+            .await
+            .unwrap();
+        };
+
+        // the batch went out before the connection was lost, and again in
+        // the session after reconnecting
+        for seq in [1000, 2000] {
+            write(1, 1, seq).await;
+        }
+        for seq in [1000, 2000, 3000] {
+            write(1, 2, seq).await;
+        }
+        // numbers of other clients are their own
+        write(2, 3, 1000).await;
+
+        let stored = sqlx::query_as::<_, (i64, i64, i64)>(
+            "SELECT client_id, session_id, seq FROM samples.session_data ORDER BY id",
+        )
+        .fetch_all(&db.writer)
+        .await
+        .unwrap();
+        assert_eq!(
+            stored,
+            [(1, 1, 1000), (1, 1, 2000), (1, 2, 3000), (2, 3, 1000)]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! A server on in-memory databases for the tests of its routes, without the
+//! background tasks of `serve`.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use confique::Config;
+use miniprobe_proto::{CpuReport, DynamicMetrics, MemoryMetrics, UnixMillis, msg::SessionToken};
+use tokio::sync::{Notify, RwLock, watch};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing_subscriber::{EnvFilter, reload};
+
+use crate::{
+    AppState, Conf, WebsocketGracefule, app,
+    db::Db,
+    events, latency,
+    listen::Routes,
+    route::{self, Session, SessionManager},
+    security,
+};
+
+/// The config of `toml` on top of the defaults.
+pub fn conf(toml: &str) -> Conf {
+    let partial = toml::from_str::<<Conf as Config>::Partial>(toml).unwrap();
+    Conf::builder().preloaded(partial).load().unwrap()
+}
+
+/// The state of a server with `conf`, `name` keeps the databases of tests
+/// running at once apart.
+pub async fn state(name: &str, conf: Conf) -> AppState {
+    let db = Db::connect(
+        &format!("sqlite:file:{name}?mode=memory&cache=shared"),
+        &format!("sqlite:file:{name}-samples?mode=memory&cache=shared"),
+        1,
+        None,
+    )
+    .await
+    .unwrap();
+    db.migrate().await.unwrap();
+    let events = events::channel();
+    let (security, _) =
+        security::SecurityLog::new(db.writer.clone(), events.clone(), &conf.security);
+    let maintenance = route::Maintenance::from(&conf.maintenance);
+    AppState {
+        conf: Arc::new(conf),
+        session_mgr: Arc::new(RwLock::new(SessionManager::new())),
+        db,
+        log_filter: reload::Layer::new(EnvFilter::default()).1,
+        events,
+        alert_trigger: Arc::new(Notify::new()),
+        samples_stored: watch::Sender::new(()),
+        latency: latency::LatencyRecorder::default(),
+        maintenance: watch::Sender::new(maintenance),
+        status_page: route::StatusPageCache::default(),
+        actions: route::ActionChannels::default(),
+        query_cache: route::QueryCache::default(),
+        security,
+        ws_graceful_shutdown: WebsocketGracefule {
+            token: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+        },
+    }
+}
+
+/// A new client with a session, returns the id of the session and its token.
+pub async fn session(state: &AppState, name: &str) -> (i64, SessionToken) {
+    let client_id: i64 = sqlx::query_scalar(
+        "INSERT INTO clients (name, token_idx, token_hash) VALUES (?, ?, ?) RETURNING id",
+    )
+    .bind(name)
+    .bind(name)
+    .bind(name)
+    .fetch_one(&state.db.writer)
+    .await
+    .unwrap();
+    let id: i64 = sqlx::query_scalar(
+        "INSERT INTO sessions (client_id, cpu_arch) VALUES (?, 'x86_64') RETURNING id",
+    )
+    .bind(client_id)
+    .fetch_one(&state.db.writer)
+    .await
+    .unwrap();
+    let token = state.session_mgr.write().await.add_session(Session {
+        id,
+        client_id,
+        host_name: None,
+    });
+    (id, token)
+}
+
+/// Serve all routes of `state` on a free port of localhost.
+pub async fn serve(state: AppState) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app(state, Routes::All).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
+
+/// A sample of `seq` taken now.
+pub fn sample(seq: u64) -> DynamicMetrics<'static> {
+    DynamicMetrics {
+        seq,
+        sample_time: UnixMillis::now(),
+        cpu: CpuReport::Aggregate {
+            usage: 0.0,
+            max_core: 0.0,
+        },
+        memory: MemoryMetrics {
+            total: 0,
+            used: 0,
+            swap_total: 0,
+            swap_used: 0,
+        },
+        networks: Vec::new(),
+        disks: Vec::new(),
+        sensors: Default::default(),
+        probe: Default::default(),
+        processes: None,
+        fds: None,
+        clock: None,
+        battery: None,
+        services: Vec::new(),
+        listeners: None,
+        addresses: None,
+        urgent: false,
+        missing_sections: Vec::new(),
+    }
+}
@@ -0,0 +1,65 @@
+//! Wake-on-LAN magic packets, sent from the server to wake clients on a
+//! network it can broadcast to.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use confique::Config;
+use tokio::net::UdpSocket;
+
+#[derive(Config, Debug)]
+pub struct WakeConf {
+    /// Where magic packets are sent, the broadcast address of the network of
+    /// the clients, e.g. `192.168.1.255:9`
+    #[config(default = "255.255.255.255:9")]
+    pub broadcast: SocketAddr,
+}
+
+/// Parse a MAC address separated by `:` or `-`, e.g. `00:11:22:AA:BB:CC`.
+pub fn parse_mac(text: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let mut parts = text.split([':', '-']);
+    for byte in &mut mac {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+/// Six `0xff` followed by the MAC address sixteen times.
+fn magic_packet(mac: [u8; 6]) -> [u8; 102] {
+    let mut packet = [0xff; 102];
+    for chunk in packet[6..].chunks_exact_mut(6) {
+        chunk.copy_from_slice(&mac);
+    }
+    packet
+}
+
+pub async fn wake(conf: &WakeConf, mac: [u8; 6]) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&magic_packet(mac), conf.broadcast).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_addresses() {
+        let mac = [0x00, 0x11, 0x22, 0xaa, 0xbb, 0xcc];
+        assert_eq!(parse_mac("00:11:22:aa:bb:cc"), Some(mac));
+        assert_eq!(parse_mac("00-11-22-AA-BB-CC"), Some(mac));
+        assert_eq!(parse_mac("00:11:22:aa:bb"), None);
+        assert_eq!(parse_mac("00:11:22:aa:bb:cc:dd"), None);
+        assert_eq!(parse_mac("0:11:22:aa:bb:cc"), None);
+        assert_eq!(parse_mac("00:11:22:aa:bb:zz"), None);
+
+        let packet = magic_packet(mac);
+        assert_eq!(packet[..6], [0xff; 6]);
+        assert!(packet[6..].chunks(6).all(|chunk| chunk == mac));
+    }
+}